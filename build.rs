@@ -0,0 +1,83 @@
+//! Embed build metadata (git commit, rustc version, build time, features)
+//!
+//! Every value falls back to "unknown" so building from a source tarball
+//! without git works.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Format Unix seconds as an RFC 3339 UTC timestamp
+fn rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil-from-days (Howard Hinnant)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+fn main() {
+    let commit = command_output("git", &["rev-parse", "HEAD"]);
+    let dirty = match commit {
+        Some(_) => command_output("git", &["status", "--porcelain", "--untracked-files=no"])
+            .map_or("false", |_| "true"),
+        None => "unknown",
+    };
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]);
+
+    // Reproducible builds may pin the timestamp
+    let build_secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+
+    let features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+
+    let unknown = || "unknown".to_string();
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit.unwrap_or_else(unknown));
+    println!("cargo:rustc-env=BUILD_GIT_DIRTY={}", dirty);
+    println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", rustc_version.unwrap_or_else(unknown));
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", rfc3339(build_secs));
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+
+    // Re-run when the sources, the checked-out commit or the index change
+    println!("cargo:rerun-if-changed=src");
+    for path in [".git/HEAD", ".git/index"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
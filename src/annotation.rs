@@ -0,0 +1,186 @@
+//! Detection annotation settings
+//!
+//! Display names for model classes (a custom model's product codes, or
+//! translated COCO names) and how the labels are drawn. The label map is
+//! applied when a detection result is ingested: `Detection::class` keeps
+//! the model's class for API consumers and `Detection::label` carries the
+//! display name the annotated frames use. Classes without an entry are
+//! drawn under their own name.
+//!
+//! Persisted under the `annotation` key of the settings state file.
+
+use crate::detector::DetectionResult;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Key in the settings state file
+pub const STATE_KEY: &str = "annotation";
+
+/// Font scales accepted (1 = 5x7 pixel glyphs)
+pub const FONT_SCALES: std::ops::RangeInclusive<u32> = 1..=6;
+
+/// Longest display name
+const MAX_LABEL_CHARS: usize = 64;
+
+/// Where the label goes relative to its box
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum LabelPosition {
+    /// Above the box (below it when the box touches the top edge)
+    Top,
+    /// Below the box (above it when the box touches the bottom edge)
+    Bottom,
+}
+
+/// How boxes are labelled
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default)]
+pub struct AnnotationStyle {
+    /// Append the confidence ("person 87%")
+    pub show_confidence: bool,
+    pub label_position: LabelPosition,
+    pub font_scale: u32,
+}
+
+impl Default for AnnotationStyle {
+    fn default() -> Self {
+        Self { show_confidence: true, label_position: LabelPosition::Top, font_scale: 2 }
+    }
+}
+
+impl AnnotationStyle {
+    pub fn validate(&self) -> Result<(), String> {
+        if !FONT_SCALES.contains(&self.font_scale) {
+            return Err(format!(
+                "font_scale must be {}-{}",
+                FONT_SCALES.start(),
+                FONT_SCALES.end()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Label map and drawing style
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationSettings {
+    /// Model class -> display name
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub style: AnnotationStyle,
+}
+
+impl AnnotationSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_labels(&self.labels)?;
+        self.style.validate()
+    }
+
+    /// Set the display name of every detection (None when unmapped)
+    pub fn apply_labels(&self, result: &mut DetectionResult) {
+        for det in &mut result.detections {
+            det.label = self.labels.get(&det.class).cloned();
+        }
+    }
+}
+
+pub fn validate_labels(labels: &BTreeMap<String, String>) -> Result<(), String> {
+    for (class, label) in labels {
+        if class.is_empty() {
+            return Err("Empty class name in label map".to_string());
+        }
+        if label.trim().is_empty() || label.chars().count() > MAX_LABEL_CHARS {
+            return Err(format!(
+                "Label for '{}' must be 1-{} characters",
+                class, MAX_LABEL_CHARS
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{self, BBox, Detection};
+
+    fn detection(class: &str) -> Detection {
+        Detection {
+            class: class.to_string(),
+            confidence: 0.9,
+            bbox: BBox { x1: 40, y1: 60, x2: 200, y2: 180 },
+            label: None,
+            landmarks: Vec::new(),
+            zones: Vec::new(),
+        }
+    }
+
+    fn settings(labels: &[(&str, &str)]) -> AnnotationSettings {
+        AnnotationSettings {
+            labels: labels.iter().map(|(c, l)| (c.to_string(), l.to_string())).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn mapped_classes_keep_their_raw_class() {
+        let mut result = DetectionResult { detections: vec![detection("sku_4711"), detection("person")], ..Default::default() };
+        settings(&[("sku_4711", "kaffee"), ("dog", "hund")]).apply_labels(&mut result);
+
+        let mapped = &result.detections[0];
+        assert_eq!((mapped.class.as_str(), mapped.label.as_deref()), ("sku_4711", Some("kaffee")));
+        assert_eq!(mapped.display_name(), "kaffee");
+        // Unknown classes fall through under their own name
+        let unmapped = &result.detections[1];
+        assert_eq!((unmapped.class.as_str(), unmapped.label.as_deref()), ("person", None));
+        assert_eq!(unmapped.display_name(), "person");
+
+        // API consumers see both fields; the label only when there is one
+        let json = serde_json::to_value(&result.detections).unwrap();
+        assert_eq!(json[0]["class"], "sku_4711");
+        assert_eq!(json[0]["label"], "kaffee");
+        assert_eq!(json[1]["class"], "person");
+        assert!(json[1].get("label").is_none());
+        let back: Vec<Detection> = serde_json::from_value(json).unwrap();
+        assert_eq!(back[0].label.as_deref(), Some("kaffee"));
+    }
+
+    #[test]
+    fn relabelling_replaces_stale_labels() {
+        let mut result = DetectionResult { detections: vec![detection("person")], ..Default::default() };
+        settings(&[("person", "mensch")]).apply_labels(&mut result);
+        settings(&[("person", "person_de")]).apply_labels(&mut result);
+        assert_eq!(result.detections[0].label.as_deref(), Some("person_de"));
+        AnnotationSettings::default().apply_labels(&mut result);
+        assert_eq!(result.detections[0].label, None);
+        assert_eq!(result.detections[0].class, "person");
+    }
+
+    #[test]
+    fn frames_are_drawn_with_the_label() {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(320, 240, image::Rgb([90, 90, 90])));
+        let style = AnnotationStyle::default();
+        let mut result = DetectionResult { detections: vec![detection("person")], ..Default::default() };
+        settings(&[("person", "mensch")]).apply_labels(&mut result);
+
+        let drawn = detector::annotate_image(&image, &result.detections, &style).unwrap();
+        // Same pixels as a detection whose class is the display name
+        let as_named = detector::annotate_image(&image, &[detection("mensch")], &style).unwrap();
+        let as_raw = detector::annotate_image(&image, &[detection("person")], &style).unwrap();
+        assert_eq!(drawn, as_named);
+        assert_ne!(drawn, as_raw);
+    }
+
+    #[test]
+    fn label_maps_are_validated() {
+        assert!(validate_labels(&settings(&[("person", "Fußgänger")]).labels).is_ok());
+        assert!(validate_labels(&settings(&[("", "x")]).labels).is_err());
+        assert!(validate_labels(&settings(&[("person", "  ")]).labels).is_err());
+        assert!(validate_labels(&settings(&[("person", &"x".repeat(MAX_LABEL_CHARS + 1))]).labels).is_err());
+        let style = AnnotationStyle { font_scale: FONT_SCALES.end() + 1, ..Default::default() };
+        assert!(AnnotationSettings { style, ..Default::default() }.validate().is_err());
+    }
+}
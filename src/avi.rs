@@ -0,0 +1,236 @@
+//! MJPEG-in-AVI muxing
+//!
+//! An AVI 1.0 file with one `MJPG` video stream:
+//!
+//! ```text
+//! RIFF 'AVI ' [ LIST 'hdrl' [ avih, LIST 'strl' [ strh, strf ] ],
+//!               LIST 'movi' [ 00dc, 00dc, ... ],
+//!               idx1 ]
+//! ```
+//!
+//! The headers need the frame count and rate, which are only known at the
+//! end, so a writer puts [`HEADER_LEN`] placeholder bytes first, appends
+//! [`frame_chunk`]s, then the [`index`], and finally overwrites the start
+//! with the real [`header`]. All sizes are 32-bit; files are kept under
+//! [`MAX_FILE_BYTES`], the size older players handle.
+
+/// Largest AVI written (1 GiB, the AVI 1.0 limit many players keep to)
+pub const MAX_FILE_BYTES: u64 = 1 << 30;
+
+/// Bytes before the first frame: the RIFF, `hdrl` and `movi` headers
+pub const HEADER_LEN: usize = 12 + 8 + 4 + (8 + 56) + 8 + 4 + (8 + 56) + (8 + 40) + 12;
+
+/// Bytes of index added per frame
+pub const INDEX_ENTRY_LEN: u64 = 16;
+
+/// `avih` flag: the file has an `idx1`
+const AVIF_HASINDEX: u32 = 0x10;
+
+/// `idx1` flag: a keyframe (every JPEG is)
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// What the headers describe
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+    /// Average frame interval
+    pub micros_per_frame: u32,
+    pub largest_frame: u32,
+    /// Bytes of frame chunks after the `movi` header
+    pub movi_bytes: u32,
+    /// Bytes of the `idx1` chunk (header included)
+    pub index_bytes: u32,
+}
+
+/// Where a frame chunk went, for the index
+#[derive(Debug, Clone, Copy)]
+pub struct IndexEntry {
+    /// From the `movi` list type, as `idx1` counts
+    pub offset: u32,
+    pub size: u32,
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    out.extend_from_slice(id);
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    body(out);
+    let size = (out.len() - start - 4) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_le_bytes());
+}
+
+fn write_list(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    write_chunk(out, b"LIST", |out| {
+        out.extend_from_slice(kind);
+        body(out);
+    });
+}
+
+/// The [`HEADER_LEN`] bytes at the start of the file
+pub fn header(summary: &Summary) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN);
+    let rate = 1_000_000 / summary.micros_per_frame.max(1);
+    out.extend_from_slice(b"RIFF");
+    // Everything after the size field
+    let riff_size = HEADER_LEN as u32 - 8 + summary.movi_bytes + summary.index_bytes;
+    put_u32(&mut out, riff_size);
+    out.extend_from_slice(b"AVI ");
+    write_list(&mut out, b"hdrl", |out| {
+        write_chunk(out, b"avih", |out| {
+            put_u32(out, summary.micros_per_frame);
+            put_u32(out, summary.largest_frame.saturating_mul(rate)); // max bytes per second
+            put_u32(out, 0); // padding granularity
+            put_u32(out, AVIF_HASINDEX);
+            put_u32(out, summary.frames);
+            put_u32(out, 0); // initial frames
+            put_u32(out, 1); // streams
+            put_u32(out, summary.largest_frame); // suggested buffer size
+            put_u32(out, summary.width);
+            put_u32(out, summary.height);
+            out.extend_from_slice(&[0; 16]);
+        });
+        write_list(out, b"strl", |out| {
+            write_chunk(out, b"strh", |out| {
+                out.extend_from_slice(b"vids");
+                out.extend_from_slice(b"MJPG");
+                put_u32(out, 0); // flags
+                put_u16(out, 0); // priority
+                put_u16(out, 0); // language
+                put_u32(out, 0); // initial frames
+                // Rate as microseconds per frame over a 1 MHz clock
+                put_u32(out, summary.micros_per_frame.max(1)); // scale
+                put_u32(out, 1_000_000); // rate
+                put_u32(out, 0); // start
+                put_u32(out, summary.frames); // length
+                put_u32(out, summary.largest_frame);
+                put_u32(out, u32::MAX); // default quality
+                put_u32(out, 0); // sample size (varies)
+                put_u16(out, 0);
+                put_u16(out, 0);
+                put_u16(out, summary.width as u16);
+                put_u16(out, summary.height as u16);
+            });
+            // BITMAPINFOHEADER
+            write_chunk(out, b"strf", |out| {
+                put_u32(out, 40);
+                put_u32(out, summary.width);
+                put_u32(out, summary.height);
+                put_u16(out, 1); // planes
+                put_u16(out, 24); // bits per pixel
+                out.extend_from_slice(b"MJPG");
+                put_u32(out, summary.width * summary.height * 3);
+                out.extend_from_slice(&[0; 16]);
+            });
+        });
+    });
+    // LIST 'movi' header; its frames follow
+    out.extend_from_slice(b"LIST");
+    put_u32(&mut out, 4 + summary.movi_bytes);
+    out.extend_from_slice(b"movi");
+    debug_assert_eq!(out.len(), HEADER_LEN);
+    out
+}
+
+/// Chunk header and padding to put around a JPEG in the `movi` list
+pub fn frame_chunk(jpeg_len: usize) -> ([u8; 8], &'static [u8]) {
+    let mut header = [0u8; 8];
+    header[0..4].copy_from_slice(b"00dc");
+    header[4..8].copy_from_slice(&(jpeg_len as u32).to_le_bytes());
+    // Chunks are word aligned
+    let padding: &[u8] = if jpeg_len % 2 == 1 { &[0] } else { &[] };
+    (header, padding)
+}
+
+/// The `idx1` chunk
+pub fn index(entries: &[IndexEntry]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + entries.len() * INDEX_ENTRY_LEN as usize);
+    write_chunk(&mut out, b"idx1", |out| {
+        for entry in entries {
+            out.extend_from_slice(b"00dc");
+            put_u32(out, AVIIF_KEYFRAME);
+            put_u32(out, entry.offset);
+            put_u32(out, entry.size);
+        }
+    });
+    out
+}
+
+/// Width and height from a JPEG's frame header
+pub fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+    while i + 4 <= jpeg.len() {
+        if jpeg[i] != 0xff {
+            return None;
+        }
+        let marker = jpeg[i + 1];
+        let len = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        // SOF0-SOF15, except DHT (c4), JPG (c8) and DAC (cc)
+        if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+            let sof = jpeg.get(i + 5..i + 9)?;
+            let height = u16::from_be_bytes([sof[0], sof[1]]) as u32;
+            let width = u16::from_be_bytes([sof[2], sof[3]]) as u32;
+            return Some((width, height));
+        }
+        i += 2 + len;
+    }
+    None
+}
+
+/// Finish a segment cut short by a crash (its header still the zero
+/// placeholder): drop a partial last frame, then add the index and the
+/// header. Returns the frames kept, None if the file isn't such a segment.
+pub fn repair(path: &std::path::Path) -> std::io::Result<Option<u32>> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let mut placeholder = [0u8; HEADER_LEN];
+    if file.read_exact(&mut placeholder).is_err() || placeholder.iter().any(|&b| b != 0) {
+        return Ok(None);
+    }
+    let mut summary = Summary::default();
+    let mut index = Vec::new();
+    let mut offset = HEADER_LEN as u64;
+    let mut chunk = [0u8; 8];
+    while offset + 8 <= len {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut chunk)?;
+        let size = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+        let end = offset + 8 + size as u64 + size as u64 % 2;
+        if &chunk[0..4] != b"00dc" || end > len {
+            break;
+        }
+        if index.is_empty() {
+            // The frame header is near the start of the JPEG
+            let mut start = vec![0u8; (size as usize).min(64 * 1024)];
+            file.read_exact(&mut start)?;
+            (summary.width, summary.height) = jpeg_dimensions(&start).unwrap_or_default();
+        }
+        index.push(IndexEntry { offset: 4 + summary.movi_bytes, size });
+        summary.movi_bytes += (end - offset) as u32;
+        summary.largest_frame = summary.largest_frame.max(size);
+        offset = end;
+    }
+    // Frame times weren't kept: assume the nominal rate
+    summary.frames = index.len() as u32;
+    let index = self::index(&index);
+    summary.index_bytes = index.len() as u32;
+    summary.micros_per_frame = 1_000_000 / crate::h264::FPS;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&index)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header(&summary))?;
+    file.sync_all()?;
+    Ok(Some(summary.frames))
+}
@@ -0,0 +1,450 @@
+//! Outbound bandwidth accounting and the daily budget
+//!
+//! Every HTTP response body is wrapped in a [`MeteredBody`] that counts
+//! bytes as hyper polls them out of the body, i.e. as they are handed to
+//! the connection. Long-running chunked streams are counted as they go and
+//! a client that disconnects is only charged for what it was sent. Totals
+//! are kept per route (the matched path, e.g. `/stream`) and, for responses
+//! without a known length (MJPEG, SSE, clips), per open connection. Only
+//! body bytes are counted, not HTTP headers or TCP/IP overhead.
+//!
+//! With a daily budget (`--bandwidth-budget-mb`), service degrades in the
+//! configured order once the budget is used up, one more step for every
+//! further `ESCALATION_FRACTION` of the budget: lower stream quality, lower
+//! stream frame rate, no new stream clients (single frames are then served
+//! at low resolution). The window restarts every day at
+//! `--bandwidth-reset-hour` local time.
+
+use crate::ratelimit::{self, IpRange};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveDate, TimeZone};
+use http_body::{Body as _, Frame, SizeHint};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// Budget used beyond 100% before each further degradation step
+pub const ESCALATION_FRACTION: f64 = 0.1;
+
+/// Highest stream JPEG quality while `quality` is engaged
+pub const DEGRADED_QUALITY: u8 = 50;
+
+/// Highest stream frame rate while `fps` is engaged
+pub const DEGRADED_FPS: u32 = 2;
+
+/// Route name for responses that matched no route
+const UNMATCHED: &str = "other";
+
+/// One step of service degradation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Degradation {
+    /// Cap stream JPEG quality at `DEGRADED_QUALITY`
+    Quality,
+    /// Cap stream frame rate at `DEGRADED_FPS`
+    Fps,
+    /// Refuse new stream clients; single frames at low resolution
+    Streams,
+}
+
+impl Degradation {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "quality" => Some(Degradation::Quality),
+            "fps" => Some(Degradation::Fps),
+            "streams" => Some(Degradation::Streams),
+            _ => None,
+        }
+    }
+}
+
+/// Daily outbound budget
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BudgetConfig {
+    pub bytes_per_day: u64,
+    /// Local hour (0-23) the window restarts at
+    pub reset_hour: u32,
+    /// Degradation steps, in the order they engage
+    pub degrade: Vec<Degradation>,
+}
+
+impl BudgetConfig {
+    /// Parse a comma-separated degradation order (`quality,fps,streams`)
+    pub fn parse_order(value: &str) -> Result<Vec<Degradation>, String> {
+        let mut order = Vec::new();
+        for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let step = Degradation::parse(name)
+                .ok_or_else(|| format!("Unknown degradation '{}'. Use quality, fps or streams", name))?;
+            if order.contains(&step) {
+                return Err(format!("Degradation '{}' listed twice", name));
+            }
+            order.push(step);
+        }
+        Ok(order)
+    }
+}
+
+#[derive(Debug, Default)]
+struct EndpointCounters {
+    bytes: AtomicU64,
+    responses: AtomicU64,
+}
+
+/// An open response without a known length
+#[derive(Debug)]
+struct Connection {
+    endpoint: String,
+    client: Option<IpAddr>,
+    started_ms: u64,
+    started: Instant,
+    bytes: AtomicU64,
+}
+
+/// Current budget window
+#[derive(Debug)]
+struct Window {
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    /// Bytes sent in the window before this one
+    previous_bytes: Option<u64>,
+}
+
+pub struct Bandwidth {
+    budget: Option<BudgetConfig>,
+    trusted_proxies: Vec<IpRange>,
+    total: AtomicU64,
+    window_bytes: AtomicU64,
+    window: Mutex<Window>,
+    /// Degradation steps engaged at the last check (for logging changes)
+    stage: AtomicUsize,
+    rejected_streams: AtomicU64,
+    endpoints: Mutex<BTreeMap<String, Arc<EndpointCounters>>>,
+    connections: Mutex<BTreeMap<u64, Arc<Connection>>>,
+    next_connection: AtomicU64,
+}
+
+/// Per-route totals in /stats/bandwidth
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct EndpointStats {
+    pub bytes: u64,
+    pub responses: u64,
+}
+
+/// An open stream in /stats/bandwidth
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ConnectionStats {
+    pub id: u64,
+    pub endpoint: String,
+    #[schema(value_type = Option<String>)]
+    pub client: Option<IpAddr>,
+    pub started_ms: u64,
+    pub bytes: u64,
+    /// Average since the connection opened
+    pub bytes_per_sec: f64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BudgetStats {
+    #[serde(flatten)]
+    pub config: BudgetConfig,
+    pub used_bytes: u64,
+    pub used_fraction: f64,
+    pub window_start: String,
+    pub resets_at: String,
+    pub previous_window_bytes: Option<u64>,
+    /// Degradation steps currently in effect
+    pub engaged: Vec<Degradation>,
+    /// Stream requests refused with 503 since startup
+    pub rejected_streams: u64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BandwidthStats {
+    pub total_bytes: u64,
+    pub endpoints: BTreeMap<String, EndpointStats>,
+    pub connections: Vec<ConnectionStats>,
+    pub budget: Option<BudgetStats>,
+}
+
+/// `hour`:00 local time on `date` (an hour later if a DST change skips it)
+fn reset_time(date: NaiveDate, hour: u32) -> DateTime<Local> {
+    let at = date.and_hms_opt(hour, 0, 0).unwrap_or_default();
+    Local
+        .from_local_datetime(&at)
+        .earliest()
+        .or_else(|| Local.from_local_datetime(&(at + ChronoDuration::hours(1))).earliest())
+        .unwrap_or_else(|| Local.from_utc_datetime(&at))
+}
+
+/// Start and end of the window containing `now`
+fn window_bounds(now: DateTime<Local>, reset_hour: u32) -> (DateTime<Local>, DateTime<Local>) {
+    let today = now.date_naive();
+    let start = if reset_time(today, reset_hour) <= now {
+        today
+    } else {
+        today.pred_opt().unwrap_or(today)
+    };
+    (
+        reset_time(start, reset_hour),
+        reset_time(start.succ_opt().unwrap_or(start), reset_hour),
+    )
+}
+
+impl Bandwidth {
+    pub fn new(budget: Option<BudgetConfig>, trusted_proxies: Vec<IpRange>) -> Self {
+        let (start, end) = window_bounds(Local::now(), budget.as_ref().map_or(0, |b| b.reset_hour));
+        Self {
+            budget,
+            trusted_proxies,
+            total: AtomicU64::new(0),
+            window_bytes: AtomicU64::new(0),
+            window: Mutex::new(Window { start, end, previous_bytes: None }),
+            stage: AtomicUsize::new(0),
+            rejected_streams: AtomicU64::new(0),
+            endpoints: Mutex::new(BTreeMap::new()),
+            connections: Mutex::new(BTreeMap::new()),
+            next_connection: AtomicU64::new(1),
+        }
+    }
+
+    /// Start a new window if the reset time has passed
+    fn roll(&self) {
+        let now = Local::now();
+        let mut window = self.window.lock();
+        if now < window.end {
+            return;
+        }
+        let previous = self.window_bytes.swap(0, Ordering::Relaxed);
+        (window.start, window.end) = window_bounds(now, self.budget.as_ref().map_or(0, |b| b.reset_hour));
+        window.previous_bytes = Some(previous);
+        tracing::info!("Bandwidth window reset ({} bytes sent in the last one)", previous);
+    }
+
+    /// Degradation steps in effect for the bytes sent this window
+    fn engaged(&self) -> &[Degradation] {
+        let Some(ref budget) = self.budget else {
+            return &[];
+        };
+        self.roll();
+        let used = self.window_bytes.load(Ordering::Relaxed);
+        let stage = if used < budget.bytes_per_day {
+            0
+        } else {
+            let over = (used - budget.bytes_per_day) as f64 / budget.bytes_per_day as f64;
+            1 + (over / ESCALATION_FRACTION) as usize
+        }
+        .min(budget.degrade.len());
+        let previous = self.stage.swap(stage, Ordering::Relaxed);
+        if stage != previous {
+            tracing::warn!(
+                "Bandwidth budget: {} of {} bytes used, degradation now {:?}",
+                used,
+                budget.bytes_per_day,
+                &budget.degrade[..stage]
+            );
+        }
+        &budget.degrade[..stage]
+    }
+
+    pub fn is_engaged(&self, step: Degradation) -> bool {
+        self.engaged().contains(&step)
+    }
+
+    /// Stream JPEG quality cap currently in effect
+    pub fn quality_cap(&self) -> Option<u8> {
+        self.is_engaged(Degradation::Quality).then_some(DEGRADED_QUALITY)
+    }
+
+    /// Whether a new stream may start; counts refusals
+    pub fn admit_stream(&self) -> bool {
+        if self.is_engaged(Degradation::Streams) {
+            self.rejected_streams.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Time until the budget window restarts
+    pub fn until_reset(&self) -> Duration {
+        self.roll();
+        (self.window.lock().end - Local::now()).to_std().unwrap_or_default()
+    }
+
+    fn meter(self: &Arc<Self>, endpoint: &str, connection: Option<Option<IpAddr>>) -> Meter {
+        let counters = self.endpoints.lock().entry(endpoint.to_string()).or_default().clone();
+        counters.responses.fetch_add(1, Ordering::Relaxed);
+        let connection = connection.map(|client| {
+            let id = self.next_connection.fetch_add(1, Ordering::Relaxed);
+            let connection = Arc::new(Connection {
+                endpoint: endpoint.to_string(),
+                client,
+                started_ms: crate::unix_millis(),
+                started: Instant::now(),
+                bytes: AtomicU64::new(0),
+            });
+            self.connections.lock().insert(id, connection.clone());
+            (id, connection)
+        });
+        Meter { bandwidth: self.clone(), counters, connection }
+    }
+
+    pub fn stats(&self) -> BandwidthStats {
+        let endpoints = self
+            .endpoints
+            .lock()
+            .iter()
+            .map(|(name, c)| {
+                let stats = EndpointStats {
+                    bytes: c.bytes.load(Ordering::Relaxed),
+                    responses: c.responses.load(Ordering::Relaxed),
+                };
+                (name.clone(), stats)
+            })
+            .collect();
+        let connections = self
+            .connections
+            .lock()
+            .iter()
+            .map(|(&id, c)| {
+                let bytes = c.bytes.load(Ordering::Relaxed);
+                ConnectionStats {
+                    id,
+                    endpoint: c.endpoint.clone(),
+                    client: c.client,
+                    started_ms: c.started_ms,
+                    bytes,
+                    bytes_per_sec: bytes as f64 / c.started.elapsed().as_secs_f64().max(1e-3),
+                }
+            })
+            .collect();
+        let budget = self.budget.as_ref().map(|config| {
+            let engaged = self.engaged().to_vec();
+            let used_bytes = self.window_bytes.load(Ordering::Relaxed);
+            let window = self.window.lock();
+            BudgetStats {
+                config: config.clone(),
+                used_bytes,
+                used_fraction: used_bytes as f64 / config.bytes_per_day as f64,
+                window_start: window.start.to_rfc3339(),
+                resets_at: window.end.to_rfc3339(),
+                previous_window_bytes: window.previous_bytes,
+                engaged,
+                rejected_streams: self.rejected_streams.load(Ordering::Relaxed),
+            }
+        });
+        BandwidthStats {
+            total_bytes: self.total.load(Ordering::Relaxed),
+            endpoints,
+            connections,
+            budget,
+        }
+    }
+}
+
+/// Charges one response's bytes; unregisters its connection when dropped
+struct Meter {
+    bandwidth: Arc<Bandwidth>,
+    counters: Arc<EndpointCounters>,
+    connection: Option<(u64, Arc<Connection>)>,
+}
+
+impl Meter {
+    fn add(&self, bytes: u64) {
+        self.counters.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.bandwidth.total.fetch_add(bytes, Ordering::Relaxed);
+        self.bandwidth.window_bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some((_, ref connection)) = self.connection {
+            connection.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        if let Some((id, _)) = self.connection {
+            self.bandwidth.connections.lock().remove(&id);
+        }
+    }
+}
+
+/// Response body that counts the data frames polled out of it
+struct MeteredBody {
+    inner: Body,
+    meter: Meter,
+}
+
+impl http_body::Body for MeteredBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(ref frame))) = poll {
+            if let Some(data) = frame.data_ref() {
+                self.meter.add(data.len() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Middleware counting every response body against its route
+pub async fn account(State(bandwidth): State<Arc<Bandwidth>>, request: Request, next: Next) -> Response {
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED, MatchedPath::as_str)
+        .to_string();
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| ratelimit::client_ip(peer.ip(), request.headers(), &bandwidth.trusted_proxies));
+
+    let (parts, body) = next.run(request).await.into_parts();
+    // Only responses without a known length stay open long enough to list
+    let streaming = body.size_hint().exact().is_none();
+    let meter = bandwidth.meter(&endpoint, streaming.then_some(client));
+    Response::from_parts(parts, Body::new(MeteredBody { inner: body, meter }))
+}
+
+/// Drops stream frames to hold `DEGRADED_FPS` while `fps` is engaged
+#[derive(Debug, Default)]
+pub struct FrameThrottle {
+    last_sent: Option<Instant>,
+}
+
+impl FrameThrottle {
+    /// Whether to send the next frame
+    pub fn admit(&mut self, bandwidth: &Bandwidth) -> bool {
+        let now = Instant::now();
+        if bandwidth.is_engaged(Degradation::Fps) {
+            let period = Duration::from_millis(1000 / DEGRADED_FPS as u64);
+            if self.last_sent.is_some_and(|last| now.duration_since(last) < period) {
+                return false;
+            }
+        }
+        self.last_sent = Some(now);
+        true
+    }
+}
@@ -1,41 +1,744 @@
 //! V4L2 capture module for IMX415 sensor
 //!
-//! Supports both grayscale (byte-4 method) and color (10-bit Bayer demosaic) modes
+//! Supports grayscale (byte-4 method), grayscale exposure fusion (short/long
+//! pairs, see `hdr`) and color (10-bit Bayer demosaic) modes
 
+use crate::ccm::{CcmTables, ColorCorrection};
+use crate::contrast::ContrastMode;
+use crate::denoise::TemporalDenoiser;
+use crate::greenbalance::GreenBalancer;
+use crate::hdr;
+use crate::masks::MaskRaster;
+use crate::metering::MeteringMode;
+use crate::orientation::{Flips, Orientation};
+use crate::pipeline::{PipelineSettings, SettingsCell, SettingsSnapshot};
+use crate::rawformat::{Cfa, Packing, RawFormat};
+use crate::scale::BilinearScaler;
+use crate::source::{FileSource, FrameSource, SourceConfig, SyntheticSource, V4l2Source};
+use crate::whitebalance::{self, WbSmoother, WhiteBalanceMode};
 use anyhow::{Context, Result};
-use image::{GrayImage, RgbImage};
+use image::{DynamicImage, GrayImage, RgbImage};
 use image::codecs::jpeg::JpegEncoder;
-use std::process::{Command, Stdio};
-use std::fs;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::process::Command;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-static FRAME_COUNTER: AtomicU64 = AtomicU64::new(0);
+// Geometry of the full (stock) sensor mode; other modes are detected or
+// configured at runtime (`FrameSize`)
+pub const WIDTH: usize = 3840;
+pub const HEIGHT: usize = 2160;
+// 3840 px * 10 bit = 4800 bytes of pixel data; the remaining 64 bytes are
+// DMA alignment padding (4864 = 76 * 64), not optical-black columns
+pub const STRIDE: usize = 4864;
+// Smallest frame accepted (the synthetic pattern's square must fit)
+const MIN_WIDTH: usize = 640;
+const MIN_HEIGHT: usize = 480;
+// Rows on each side used for the local median in row-noise correction
+const ROW_NOISE_RADIUS: usize = 4;
+// Tone mapping: histogram bins are capped at this multiple of the mean bin
+// count (contrast limiting) and the curve moves this fraction per frame
+const TONEMAP_CLIP_LIMIT: f32 = 4.0;
+const TONEMAP_SMOOTHING: f32 = 0.2;
+// Frames a sensor control change takes to show in the stream
+pub const SETTLE_FRAMES: u64 = 2;
+// A frame wait gives up (and the stream is restarted) after this unless
+// the config sets a capture timeout
+const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+// Accepted output gamma range
+pub const MIN_GAMMA: f32 = 0.5;
+pub const MAX_GAMMA: f32 = 4.0;
+// Frame histograms (and so auto-exposure) sample every this many output
+// pixels in each direction
+pub const HISTOGRAM_STEP: usize = 8;
 
-const WIDTH: usize = 3840;
-const HEIGHT: usize = 2160;
-const STRIDE: usize = 4864;
-const GROUPS_PER_ROW: usize = 960;
+/// Histogram count of a sample at full metering weight
+const METERING_WEIGHT: f32 = 64.0;
+// Frames auto-exposure waits before retrying after a sensor control error
+const AE_RETRY_FRAMES: u64 = 30;
 
 /// Capture mode
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CaptureMode {
     /// 4K Grayscale using byte-4 + row averaging (artifact-free)
     Grayscale,
+    /// Grayscale fused from a short and a long exposure (reduced frame rate)
+    GrayscaleHdr,
     /// 4K Color using 10-bit Bayer demosaicing
     Color,
+    /// Color fused from a short and a long exposure (reduced frame rate)
+    ColorHdr,
 }
 
-/// Frame capture configuration
+impl CaptureMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "grayscale" | "gray" | "g" => Some(CaptureMode::Grayscale),
+            "grayscale-hdr" | "gray-hdr" | "hdr" => Some(CaptureMode::GrayscaleHdr),
+            "color" | "c" => Some(CaptureMode::Color),
+            "color-hdr" | "hdr-color" => Some(CaptureMode::ColorHdr),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CaptureMode::Grayscale => "grayscale",
+            CaptureMode::GrayscaleHdr => "grayscale-hdr",
+            CaptureMode::Color => "color",
+            CaptureMode::ColorHdr => "color-hdr",
+        }
+    }
+
+    /// Gamma of the mode's output given the gamma setting: grayscale output
+    /// is linear in the raw values, gamma only shapes color
+    pub fn output_gamma(self, gamma: f32) -> f32 {
+        match self {
+            CaptureMode::Color | CaptureMode::ColorHdr => gamma,
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => 1.0,
+        }
+    }
+
+    /// Whether an output frame is fused from an exposure pair
+    pub fn is_hdr(self) -> bool {
+        matches!(self, CaptureMode::GrayscaleHdr | CaptureMode::ColorHdr)
+    }
+}
+
+/// Raw frames of one exposure-fusion pair
+pub struct HdrPair {
+    /// At the sensor's exposure setting
+    pub long: Vec<u8>,
+    /// At `1/ratio` of it
+    pub short: Vec<u8>,
+    /// Actual long/short exposure ratio (after rounding to whole lines)
+    pub ratio: f32,
+}
+
+/// Raw data for one output frame
+pub enum RawCapture {
+    Frame(Vec<u8>),
+    Pair(HdrPair),
+}
+
+/// Proportional JPEG quality controller
+///
+/// Nudges quality each frame so the encoded size and/or encode time settle
+/// near their targets. Error is measured as a log ratio so over- and
+/// undershoot are symmetric, a deadband ignores small deviations and the
+/// per-frame step is clamped to keep the stream from visibly pulsing.
+#[derive(Debug, Clone)]
+pub struct AdaptiveQuality {
+    pub target_bytes: Option<usize>,
+    pub target_encode_ms: Option<f32>,
+    pub min_quality: u8,
+    pub max_quality: u8,
+    quality: f32,
+}
+
+impl AdaptiveQuality {
+    /// Quality points per unit of log error
+    const GAIN: f32 = 20.0;
+    /// Largest change applied in one frame
+    const MAX_STEP: f32 = 3.0;
+    /// Relative error below which quality is left alone
+    const DEADBAND: f32 = 0.05;
+
+    pub fn new(target_bytes: Option<usize>, target_encode_ms: Option<f32>, min_quality: u8, max_quality: u8) -> Self {
+        let min_quality = min_quality.clamp(1, 100);
+        let max_quality = max_quality.clamp(min_quality, 100);
+        Self {
+            target_bytes,
+            target_encode_ms,
+            min_quality,
+            max_quality,
+            quality: max_quality as f32,
+        }
+    }
+
+    /// Same targets and range (the controller state aside)
+    pub fn same_limits(&self, other: &AdaptiveQuality) -> bool {
+        self.target_bytes == other.target_bytes
+            && self.target_encode_ms == other.target_encode_ms
+            && (self.min_quality, self.max_quality) == (other.min_quality, other.max_quality)
+    }
+
+    /// Quality to use for the next frame
+    pub fn quality(&self) -> u8 {
+        self.quality.round() as u8
+    }
+
+    /// Feed back the last frame's size and encode time, returns the new quality
+    pub fn update(&mut self, size_bytes: usize, encode_ms: f32) -> u8 {
+        // The most violated target drives the controller
+        let size_error = self
+            .target_bytes
+            .map(|target| (size_bytes.max(1) as f32 / target.max(1) as f32).ln());
+        let time_error = self
+            .target_encode_ms
+            .map(|target| (encode_ms.max(0.01) / target.max(0.01)).ln());
+        let error = match (size_error, time_error) {
+            (Some(a), Some(b)) => a.max(b),
+            (Some(e), None) | (None, Some(e)) => e,
+            (None, None) => return self.quality(),
+        };
+
+        if error.abs() > Self::DEADBAND.ln_1p() {
+            let step = (-error * Self::GAIN).clamp(-Self::MAX_STEP, Self::MAX_STEP);
+            self.quality = (self.quality + step).clamp(self.min_quality as f32, self.max_quality as f32);
+        }
+        self.quality()
+    }
+}
+
+/// Software auto-exposure controller
+///
+/// Meters the mean of each output frame's luminance histogram and moves
+/// the sensor's total exposure (exposure lines times analogue gain) towards
+/// the target: exposure time first, up to the frame length, then gain up to
+/// `max_gain_db`; when darkening, gain goes down before exposure time. The
+/// error is a log ratio taken back through the output gamma, `speed` of it
+/// is corrected per adjustment, and the frames still in flight after a
+/// change are not metered. Too many clipped highlights count as
+/// overexposure whatever the mean, so a bright sky is not blown out to
+/// lift a dark foreground.
+///
+/// With anti-flicker on, the exposure time is a whole number of mains
+/// half-periods (10 ms at 50 Hz, 8.3 ms at 60 Hz) and gain makes up the
+/// rest, so every row of the rolling shutter collects the same share of a
+/// flickering light and no bands roll through the frame. Light too bright
+/// for one half-period at 0 dB gets a shorter exposure anyway, where
+/// banding can return (the alternative being blown highlights).
+#[derive(Debug, Clone)]
+pub struct AutoExposure {
+    /// Target mean output luminance (0-255)
+    pub target: f32,
+    /// Highest analogue gain used, in dB
+    pub max_gain_db: f32,
+    /// Fraction of the error corrected per adjustment (0-1)
+    pub speed: f32,
+    pub anti_flicker: AntiFlicker,
+    sensor: Option<SensorExposure>,
+    settle: u64,
+    mean: Option<f32>,
+    converged: bool,
+}
+
+/// Mains frequency whose lighting flicker auto-exposure avoids
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AntiFlicker {
+    #[default]
+    Off,
+    #[serde(rename = "50hz")]
+    Hz50,
+    #[serde(rename = "60hz")]
+    Hz60,
+}
+
+impl AntiFlicker {
+    /// `off`, `50` or `60` (`50hz` and `60hz` too)
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().trim_end_matches("hz") {
+            "off" => Some(AntiFlicker::Off),
+            "50" => Some(AntiFlicker::Hz50),
+            "60" => Some(AntiFlicker::Hz60),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AntiFlicker::Off => "off",
+            AntiFlicker::Hz50 => "50hz",
+            AntiFlicker::Hz60 => "60hz",
+        }
+    }
+
+    /// Flicker period: lights peak twice per mains cycle
+    fn half_period_s(self) -> Option<f64> {
+        match self {
+            AntiFlicker::Off => None,
+            AntiFlicker::Hz50 => Some(1.0 / 100.0),
+            AntiFlicker::Hz60 => Some(1.0 / 120.0),
+        }
+    }
+}
+
+/// Exposure controls as last read from or written to the sensor
+#[derive(Debug, Clone, Copy)]
+struct SensorExposure {
+    lines: i64,
+    min_lines: i64,
+    max_lines: i64,
+    gain: i64,
+    max_gain: i64,
+    /// Seconds per exposure line (None if the sensor doesn't tell)
+    line_s: Option<f64>,
+}
+
+/// Luminance histogram of a processed frame (before contrast enhancement
+/// and the output stages), and for color frames the histograms of its red,
+/// green and blue channels, sampled every `HISTOGRAM_STEP` pixels in each
+/// direction
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    pub luma: [u32; 256],
+    pub rgb: Option<Box<[[u32; 256]; 3]>>,
+}
+
+impl Histogram {
+    /// Pixels sampled
+    pub fn samples(&self) -> u64 {
+        self.luma.iter().map(|&n| n as u64).sum()
+    }
+
+    /// Mean luminance (0-255); None for an empty histogram
+    pub fn mean(&self) -> Option<f32> {
+        let total = self.samples();
+        let sum: u64 = self.luma.iter().enumerate().map(|(i, &n)| i as u64 * n as u64).sum();
+        (total > 0).then(|| sum as f32 / total as f32)
+    }
+
+    /// Fraction of the samples whose luminance is in `bins`
+    pub fn fraction(&self, bins: impl std::ops::RangeBounds<usize>) -> f32 {
+        let total = self.samples().max(1);
+        let count: u64 = self.luma[(bins.start_bound().cloned(), bins.end_bound().cloned())]
+            .iter()
+            .map(|&n| n as u64)
+            .sum();
+        count as f32 / total as f32
+    }
+}
+
+/// Per-channel sums of a color frame, and for white-patch the histograms
+/// of its unclipped pixels
+struct ChannelStats {
+    sums: [u64; 3],
+    histograms: Option<Box<[[u32; 256]; 3]>>,
+}
+
+impl ChannelStats {
+    fn new(histograms: bool) -> Self {
+        Self { sums: [0; 3], histograms: histograms.then(|| Box::new([[0; 256]; 3])) }
+    }
+
+    fn add(&mut self, pixel: &[u8]) {
+        for (sum, &v) in self.sums.iter_mut().zip(pixel) {
+            *sum += v as u64;
+        }
+        if let Some(ref mut histograms) = self.histograms {
+            // A clipped channel says nothing about the light's color
+            if pixel.iter().all(|&v| v < 255) {
+                for (histogram, &v) in histograms.iter_mut().zip(pixel) {
+                    histogram[v as usize] += 1;
+                }
+            }
+        }
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        for (sum, other) in self.sums.iter_mut().zip(other.sums) {
+            *sum += other;
+        }
+        if let (Some(histograms), Some(other)) = (self.histograms.as_mut(), other.histograms) {
+            for (histogram, other) in histograms.iter_mut().zip(other.iter()) {
+                for (n, m) in histogram.iter_mut().zip(other) {
+                    *n += m;
+                }
+            }
+        }
+        self
+    }
+}
+
+/// Auto-exposure state for status reports
+#[derive(Debug, Clone, Serialize)]
+pub struct AutoExposureStatus {
+    pub target: f32,
+    pub max_gain_db: f32,
+    pub speed: f32,
+    pub anti_flicker: AntiFlicker,
+    /// Mean luminance of the last metered frame
+    pub mean: Option<f32>,
+    /// Exposure (lines) and analogue gain (control units) last set
+    pub exposure: Option<i64>,
+    pub analogue_gain: Option<i64>,
+    /// Within the deadband of the target, or at an exposure/gain limit
+    pub converged: bool,
+}
+
+/// Milliseconds spent in each stage of the last frame (None: the stage
+/// didn't run for it), for /status
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StageTimings {
+    /// Waiting for and reading the raw frame (filled in by the capture loop)
+    pub capture: Option<f32>,
+    /// 10-bit Bayer unpack
+    pub unpack: Option<f32>,
+    /// Black level, tone curve (or exposure fusion) and green balance
+    pub levels: Option<f32>,
+    pub demosaic: Option<f32>,
+    /// Grayscale extraction (or exposure fusion) and row-noise correction
+    pub extract: Option<f32>,
+    /// Spatial noise reduction (median or bilateral)
+    pub spatial_denoise: Option<f32>,
+    /// Temporal noise reduction
+    pub denoise: Option<f32>,
+    pub upscale: Option<f32>,
+    pub white_balance: Option<f32>,
+    pub color_matrix: Option<f32>,
+    /// Gamma, unless the demosaic applied it
+    pub gamma: Option<f32>,
+    /// Unsharp mask
+    pub sharpen: Option<f32>,
+    /// Luminance and channel histograms
+    pub histogram: Option<f32>,
+    /// Grayscale histogram equalization
+    pub contrast: Option<f32>,
+    /// Brightness, contrast and saturation
+    pub tone: Option<f32>,
+    /// Flips and rotation left to software
+    pub orientation: Option<f32>,
+    /// Privacy masks
+    pub masks: Option<f32>,
+    /// Digital zoom crop
+    pub zoom: Option<f32>,
+    /// Text overlay
+    pub overlay: Option<f32>,
+    pub encode: Option<f32>,
+    /// Auto-exposure metering and sensor update
+    pub auto_exposure: Option<f32>,
+    /// Detection boxes drawn on the frame (filled in by the capture loop)
+    pub draw: Option<f32>,
+    /// Focus score of the frame (filled in by the capture loop)
+    pub focus: Option<f32>,
+}
+
+/// Milliseconds since `start`
+pub fn elapsed_ms(start: Instant) -> Option<f32> {
+    Some(start.elapsed().as_secs_f32() * 1000.0)
+}
+
+impl AutoExposure {
+    pub const DEFAULT_TARGET: f32 = 110.0;
+    pub const DEFAULT_MAX_GAIN_DB: f32 = 30.0;
+    pub const DEFAULT_SPEED: f32 = 0.5;
+    /// Log error (linear domain) below which exposure is left alone
+    const DEADBAND: f32 = 0.1;
+    /// Largest log change applied in one adjustment
+    const MAX_STEP: f32 = 1.0;
+    /// Histogram bins counted as clipped, and the fraction of samples
+    /// allowed there
+    pub const CLIP_BIN: usize = 250;
+    const MAX_CLIPPED: f32 = 0.02;
+
+    pub fn new(target: f32, max_gain_db: f32, speed: f32, anti_flicker: AntiFlicker) -> Self {
+        Self {
+            target: target.clamp(1.0, 254.0),
+            max_gain_db: max_gain_db.max(0.0),
+            speed: speed.clamp(0.01, 1.0),
+            anti_flicker,
+            sensor: None,
+            settle: 0,
+            mean: None,
+            converged: false,
+        }
+    }
+
+    /// Same target, gain limit, speed and anti-flicker (the controller
+    /// state aside)
+    pub fn same_limits(&self, other: &AutoExposure) -> bool {
+        (self.target, self.max_gain_db, self.speed, self.anti_flicker)
+            == (other.target, other.max_gain_db, other.speed, other.anti_flicker)
+    }
+
+    /// Start metering afresh (after the metered region changed); the
+    /// sensor exposure as last set is kept
+    fn restart(&mut self) {
+        self.settle = 0;
+        self.mean = None;
+        self.converged = false;
+    }
+
+    pub fn status(&self) -> AutoExposureStatus {
+        AutoExposureStatus {
+            target: self.target,
+            max_gain_db: self.max_gain_db,
+            speed: self.speed,
+            anti_flicker: self.anti_flicker,
+            mean: self.mean,
+            exposure: self.sensor.map(|s| s.lines),
+            analogue_gain: self.sensor.map(|s| s.gain),
+            converged: self.converged,
+        }
+    }
+
+    /// Meter a frame; returns the factor to scale the total exposure by,
+    /// if it needs changing. `gamma` is the output gamma the histogram was
+    /// taken after.
+    fn correction(&mut self, histogram: &[u32; 256], gamma: f32) -> Option<f32> {
+        if self.settle > 0 {
+            self.settle -= 1;
+            return None;
+        }
+        let total: u64 = histogram.iter().map(|&n| n as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let sum: u64 = histogram.iter().enumerate().map(|(i, &n)| i as u64 * n as u64).sum();
+        let clipped: u64 = histogram[Self::CLIP_BIN..].iter().map(|&n| n as u64).sum();
+        let mean = sum as f32 / total as f32;
+        let clipped = clipped as f32 / total as f32;
+        self.mean = Some(mean);
+
+        let mut error = (self.target / mean.max(1.0)).ln() * gamma;
+        if clipped > Self::MAX_CLIPPED {
+            error = error.min(-(clipped / Self::MAX_CLIPPED).ln());
+        }
+        if error.abs() < Self::DEADBAND {
+            self.converged = true;
+            // An exposure set before anti-flicker was turned on still has
+            // to be moved onto the flicker period
+            let off_period = self.anti_flicker != AntiFlicker::Off
+                && self.sensor.is_none_or(|sensor| self.next_exposure(sensor, 1.0).is_some());
+            return off_period.then_some(1.0);
+        }
+        self.converged = false;
+        Some((error * self.speed).clamp(-Self::MAX_STEP, Self::MAX_STEP).exp())
+    }
+
+    /// The anti-flicker period in exposure lines (None while off or
+    /// without a line time)
+    fn flicker_period_lines(&self, sensor: SensorExposure) -> Option<f32> {
+        let period = self.anti_flicker.half_period_s()? / sensor.line_s?;
+        Some(period as f32)
+    }
+
+    /// Split `factor` times the current total exposure into exposure lines
+    /// and gain steps within the limits; None if the result is unchanged
+    fn next_exposure(&self, sensor: SensorExposure, factor: f32) -> Option<(i64, i64)> {
+        let gain_step_db = crate::exif::GAIN_STEP_DB as f32;
+        let max_gain = ((self.max_gain_db / gain_step_db) as i64).min(sensor.max_gain).max(0);
+        let gain_linear = |steps: i64| 10f32.powf(steps as f32 * gain_step_db / 20.0);
+        let total = sensor.lines as f32 * gain_linear(sensor.gain) * factor;
+        let mut lines = (total.round() as i64).clamp(sensor.min_lines, sensor.max_lines);
+        if let Some(period) = self.flicker_period_lines(sensor) {
+            // Whole periods (with half a line's slack, as lines are
+            // rounded), down from the total and no longer than the frame
+            let periods = ((total + 0.5) / period).floor().min((sensor.max_lines as f32 / period).floor());
+            if periods >= 1.0 {
+                lines = ((periods * period).round() as i64).clamp(sensor.min_lines, sensor.max_lines);
+            }
+        }
+        let gain_db = 20.0 * (total / lines as f32).max(1.0).log10();
+        let gain = ((gain_db / gain_step_db).round() as i64).clamp(0, max_gain);
+        ((lines, gain) != (sensor.lines, sensor.gain)).then_some((lines, gain))
+    }
+}
+
+/// JPEG encoder for streamed frames
+pub trait FrameEncoder: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Encode `image` into `out`, replacing its contents
+    fn encode(&mut self, image: &DynamicImage, quality: u8, out: &mut Vec<u8>) -> Result<()>;
+}
+
+/// The `image` crate's JPEG encoder on the CPU
+pub struct SoftwareEncoder;
+
+impl FrameEncoder for SoftwareEncoder {
+    fn name(&self) -> &'static str {
+        "software"
+    }
+
+    fn encode(&mut self, image: &DynamicImage, quality: u8, out: &mut Vec<u8>) -> Result<()> {
+        out.clear();
+        let mut encoder = JpegEncoder::new_with_quality(out, quality);
+        encoder.encode_image(image).context("Failed to encode JPEG")
+    }
+}
+
+/// Frame geometry of a sensor mode, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FrameSize {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FrameSize {
+    /// The full 3840x2160 mode
+    pub const FULL: FrameSize = FrameSize { width: WIDTH, height: HEIGHT };
+
+    /// `full` or `<width>x<height>`, e.g. `1920x1080`
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim().to_lowercase();
+        if text == "full" {
+            return Some(Self::FULL);
+        }
+        let (width, height) = text.split_once('x')?;
+        Some(Self { width: width.trim().parse().ok()?, height: height.trim().parse().ok()? })
+    }
+
+    /// From the `Width/Height` field of v4l2-ctl's format (`3840/2160`)
+    pub fn from_v4l2(text: &str) -> Option<Self> {
+        let (width, height) = text.split_once('/')?;
+        Some(Self { width: width.trim().parse().ok()?, height: height.trim().parse().ok()? })
+    }
+
+    /// Error message if the pipeline can't process frames of this size:
+    /// whole packed groups and 4x2 grayscale blocks, no larger than the
+    /// full mode
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.width.is_multiple_of(4) || !self.height.is_multiple_of(2) {
+            return Err(format!("{} is not a multiple of 4 pixels wide and 2 rows high", self));
+        }
+        if !(MIN_WIDTH..=WIDTH).contains(&self.width) || !(MIN_HEIGHT..=HEIGHT).contains(&self.height) {
+            return Err(format!("{} is outside {}x{} to {}", self, MIN_WIDTH, MIN_HEIGHT, Self::FULL));
+        }
+        Ok(())
+    }
+
+    /// This size turned by `orientation` (width and height swap on a
+    /// quarter turn)
+    pub fn oriented(self, orientation: &Orientation) -> FrameSize {
+        if orientation.quarter_turn() {
+            FrameSize { width: self.height, height: self.width }
+        } else {
+            self
+        }
+    }
+
+    /// Size of the encoded frames for frames of this size processed with
+    /// `settings`: turned by the orientation, cropped by the zoom
+    pub fn output(self, settings: &PipelineSettings) -> FrameSize {
+        settings.zoom.crop(self.oriented(&settings.orientation)).size()
+    }
+}
+
+impl std::fmt::Display for FrameSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// Which encoder streamed frames use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderKind {
+    /// Rockchip VPU through MPP (`mpp` feature), software if it fails
+    Mpp,
+    Software,
+}
+
+impl EncoderKind {
+    /// MPP when built with it
+    pub const DEFAULT: EncoderKind = if cfg!(feature = "mpp") { EncoderKind::Mpp } else { EncoderKind::Software };
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "mpp" | "hardware" => Some(Self::Mpp),
+            "software" | "cpu" => Some(Self::Software),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mpp => "mpp",
+            Self::Software => "software",
+        }
+    }
+}
+
+/// Open the encoder for `kind` and frames of `size`, falling back to
+/// software
+fn start_encoder(kind: EncoderKind, size: FrameSize) -> Box<dyn FrameEncoder> {
+    let hardware: Result<Box<dyn FrameEncoder>> = match kind {
+        EncoderKind::Software => return Box::new(SoftwareEncoder),
+        #[cfg(feature = "mpp")]
+        EncoderKind::Mpp => crate::mpp::MppEncoder::new(size.width, size.height).map(|e| Box::new(e) as Box<dyn FrameEncoder>),
+        #[cfg(not(feature = "mpp"))]
+        EncoderKind::Mpp => {
+            let _ = size;
+            Err(anyhow::anyhow!("Built without the `mpp` feature"))
+        }
+    };
+    hardware.unwrap_or_else(|e| {
+        tracing::warn!("{} JPEG encoder unavailable ({:#}), using software encoding", kind.name(), e);
+        Box::new(SoftwareEncoder)
+    })
+}
+
+/// What scales frames: the grayscale upscale and the downscaled streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalerKind {
+    /// Rockchip RGA through librga (`rga` feature), the CPU if it fails
+    Rga,
+    Software,
+}
+
+impl ScalerKind {
+    /// RGA when built with it
+    pub const DEFAULT: ScalerKind = if cfg!(feature = "rga") { ScalerKind::Rga } else { ScalerKind::Software };
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "rga" | "hardware" => Some(Self::Rga),
+            "software" | "cpu" => Some(Self::Software),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rga => "rga",
+            Self::Software => "software",
+        }
+    }
+}
+
+/// Scale packed 8-bit pixels on the RGA (see `rga::resize`)
+fn rga_resize(src: &[u8], src_size: (usize, usize), dst: &mut [u8], dst_size: (usize, usize), channels: usize) -> Result<()> {
+    #[cfg(feature = "rga")]
+    {
+        crate::rga::resize(src, src_size, dst, dst_size, channels)
+    }
+    #[cfg(not(feature = "rga"))]
+    {
+        let _ = (src, src_size, dst, dst_size, channels);
+        anyhow::bail!("Built without the `rga` feature")
+    }
+}
+
+/// Frame capture configuration (devices; the processing settings are in
+/// the `pipeline` settings cell)
+#[derive(Debug, Clone)]
 pub struct CaptureConfig {
     pub device_path: String,
     pub sensor_subdev: String,
-    pub mode: CaptureMode,
     pub link_frequency: u32,
-    pub jpeg_quality: u8,
-    pub gamma: f32,
-    pub enable_white_balance: bool,
-    pub temp_dir: PathBuf,
+    /// JPEG encoder for streamed frames (stills always use software)
+    pub encoder: EncoderKind,
+    /// Scaler for the grayscale upscale and the downscaled streams
+    pub scaler: ScalerKind,
+    /// Give up on a frame (and restart the stream) if none arrives within
+    /// this; 5 s when unset
+    pub capture_timeout: Option<Duration>,
+    /// Worker threads for the color passes (demosaic, white balance,
+    /// gamma); one per core when unset
+    pub threads: Option<usize>,
+    /// Sensor mode to ask the driver for (e.g. 1920x1080 binned); the
+    /// device's current mode when unset. File and synthetic sources carry
+    /// their own size.
+    pub frame_size: Option<FrameSize>,
+    /// Bayer order to demosaic with instead of the one the format reports,
+    /// for drivers that keep the fourcc when the sensor is flipped
+    pub bayer_order: Option<Cfa>,
+    /// Where frames come from: the sensor, or raw dumps or a test pattern
+    /// when there is none
+    pub source: SourceConfig,
 }
 
 impl Default for CaptureConfig {
@@ -43,12 +746,14 @@ impl Default for CaptureConfig {
         Self {
             device_path: "/dev/video9".to_string(),
             sensor_subdev: "/dev/v4l-subdev3".to_string(),
-            mode: CaptureMode::Color,
             link_frequency: 0,
-            jpeg_quality: 90,
-            gamma: 2.2,
-            enable_white_balance: true,
-            temp_dir: PathBuf::from("/tmp/imx415_capture"),
+            encoder: EncoderKind::DEFAULT,
+            scaler: ScalerKind::DEFAULT,
+            capture_timeout: None,
+            threads: None,
+            frame_size: None,
+            bayer_order: None,
+            source: SourceConfig::V4l2,
         }
     }
 }
@@ -56,46 +761,179 @@ impl Default for CaptureConfig {
 /// Frame capture instance
 pub struct FrameCapture {
     config: CaptureConfig,
+    // Published processing settings, and the version the caches below
+    // (LUTs, quality controller, scene state) were last brought up to date
+    // with; one snapshot is used for a whole frame
+    settings: Arc<SettingsCell>,
+    applied: Arc<SettingsSnapshot>,
+    // Adaptive JPEG quality controller, following `applied.adaptive_quality`
+    quality: Option<AdaptiveQuality>,
+    // Auto-exposure controller, following `applied.auto_exposure`
+    ae: Option<AutoExposure>,
+    // Negotiated raw format, line length and frame size (detected in
+    // start_streaming); the buffers below are sized for `size`
+    format: RawFormat,
+    stride: usize,
+    size: FrameSize,
+    // Bytes per raw frame as the source delivers it: the device's
+    // `sizeimage`, which may pad the frame past `stride * size.height`
+    frame_bytes: usize,
+    // Frame source (opened by start_streaming or given to set_source) and
+    // whether it is a sensor with exposure controls
+    source: Mutex<Option<Box<dyn FrameSource>>>,
+    sensor: bool,
     // 10-bit Bayer buffer (for color mode)
     bayer10: Vec<u16>,
     // RGB output buffer (for color mode)
     rgb_buffer: Vec<u8>,
+    // Workers for the color passes, which split the frame by rows
+    pool: rayon::ThreadPool,
     // Grayscale buffers
-    gray_native: Vec<u8>,   // 960x1080
-    gray_output: Vec<u8>,   // 3840x2160
-    // JPEG output
+    gray_native: Vec<u8>,   // width/4 x height/2 (960x1080 at full size)
+    gray_native_wanted: bool, // fill gray_native in color mode too
+    gray_output: Vec<u8>,   // frame size
+    upscaler: BilinearScaler, // gray_native -> gray_output
+    scaler: ScalerKind,     // software once the RGA has failed
+    row_means: Vec<f32>,    // per-row scratch for row-noise correction
+    // Exposure fusion: linear 16-bit luma (native size) or Bayer samples of
+    // the pair, fused in place into `hdr_long`, and the 8-bit (grayscale)
+    // and 10-bit (color) tone curves for the last ratio
+    hdr_long: Vec<u16>,
+    hdr_short: Vec<u16>,
+    hdr_curve: Option<(f32, Vec<u8>)>,
+    hdr_curve10: Option<(f32, Vec<u16>)>,
+    // JPEG output (the encoder is opened for the first streamed frame)
+    encoder: Option<Box<dyn FrameEncoder>>,
     jpeg_buffer: Vec<u8>,
+    // Pre-encode pixels of the last frame (shared with HTTP handlers)
+    last_image: Option<Arc<DynamicImage>>,
+    // Histogram of the last processed frame
+    histogram: Option<Arc<Histogram>>,
+    // Its luma weighted by the metering region, while auto-exposure meters
+    // less than the whole frame
+    metered_luma: Option<Box<[u32; 256]>>,
+    // Sensor test pattern active: scene statistics (gray-world WB) are meaningless
+    test_pattern_active: bool,
+    // Smoothed white balance gains and when they were last updated
+    wb: WbSmoother,
+    wb_updated: Option<Instant>,
+    // Color correction tables, while correction is on
+    ccm: Option<CcmTables>,
+    // Flips the sensor readout has (the rest of the orientation is done in
+    // software)
+    sensor_flips: Flips,
+    // Privacy masks rasterized for the oriented frame (None: rebuild)
+    mask_raster: Option<MaskRaster>,
+    // Running average of the frames for temporal denoise
+    denoiser: TemporalDenoiser,
+    // Gr/Gb ratio estimate and the gain last applied
+    green: GreenBalancer,
     // Gamma LUT
     gamma_lut: [u8; 1024],  // 10-bit input -> 8-bit output
+    // Black level LUTs (subtract pedestal, rescale to full range)
+    black_lut10: Vec<u16>,  // 10-bit Bayer
+    black_lut8: [u8; 256],  // 8-bit byte-4 grayscale
+    // Tone mapping: smoothed output curve (8-bit domain, per raw 10-bit code)
+    // and the black level + tone LUT applied to the Bayer data
+    tone_curve: Vec<f32>,
+    tone_lut10: Vec<u16>,
+    // Stage timings of the frame in progress (reset by begin_frame)
+    stages: StageTimings,
 }
 
 impl FrameCapture {
-    pub fn new() -> Result<Self> {
-        Self::with_config(CaptureConfig::default())
-    }
-
-    pub fn with_config(config: CaptureConfig) -> Result<Self> {
-        fs::create_dir_all(&config.temp_dir)?;
-        
-        // Build gamma LUT (10-bit to 8-bit with gamma)
-        let mut gamma_lut = [0u8; 1024];
-        let inv_gamma = 1.0 / config.gamma;
-        for i in 0..1024 {
-            gamma_lut[i] = ((i as f32 / 1023.0).powf(inv_gamma) * 255.0) as u8;
-        }
+    /// Capture with its processing settings taken from `settings`
+    pub fn with_config(config: CaptureConfig, settings: Arc<SettingsCell>) -> Result<Self> {
+        let applied = settings.load();
+        let gamma_lut = build_gamma_lut(applied.gamma);
+        let (black_lut10, black_lut8) = build_black_level_luts(applied.black_level);
+        let ccm = build_ccm(&applied.color_correction);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads.unwrap_or(0))
+            .thread_name(|i| format!("pipeline-{}", i))
+            .build()
+            .context("Failed to start the pipeline threads")?;
+        let scaler = config.scaler;
         
-        Ok(Self {
+        let mut capture = Self {
             config,
-            bayer10: vec![0u16; WIDTH * HEIGHT],
-            rgb_buffer: vec![0u8; WIDTH * HEIGHT * 3],
-            gray_native: vec![0u8; GROUPS_PER_ROW * (HEIGHT / 2)],
-            gray_output: vec![0u8; WIDTH * HEIGHT],
+            quality: applied.adaptive_quality.clone(),
+            ae: applied.auto_exposure.clone(),
+            settings,
+            applied,
+            format: RawFormat::default(),
+            stride: STRIDE,
+            size: FrameSize::FULL,
+            frame_bytes: STRIDE * FrameSize::FULL.height,
+            source: Mutex::new(None),
+            sensor: false,
+            bayer10: Vec::new(),
+            rgb_buffer: Vec::new(),
+            pool,
+            gray_native: Vec::new(),
+            gray_native_wanted: false,
+            gray_output: Vec::new(),
+            upscaler: BilinearScaler::new(0, 0, 0, 0),
+            scaler,
+            row_means: Vec::new(),
+            hdr_long: Vec::new(),
+            hdr_short: Vec::new(),
+            hdr_curve: None,
+            hdr_curve10: None,
+            encoder: None,
             jpeg_buffer: Vec::with_capacity(3 * 1024 * 1024),
+            last_image: None,
+            histogram: None,
+            metered_luma: None,
+            test_pattern_active: false,
+            wb: WbSmoother::default(),
+            wb_updated: None,
+            ccm,
+            sensor_flips: Flips::default(),
+            mask_raster: None,
+            denoiser: TemporalDenoiser::default(),
+            green: GreenBalancer::default(),
             gamma_lut,
-        })
+            black_lut10,
+            black_lut8,
+            tone_curve: Vec::new(),
+            tone_lut10: vec![0u16; 1024],
+            stages: StageTimings::default(),
+        };
+        capture.resize_buffers();
+        Ok(capture)
+    }
+
+    /// Reallocate the frame buffers for `size`
+    fn resize_buffers(&mut self) {
+        let FrameSize { width, height } = self.size;
+        let (native_width, native_height) = self.native_size();
+        self.bayer10 = vec![0u16; width * height];
+        self.rgb_buffer = vec![0u8; width * height * 3];
+        self.gray_native = vec![0u8; native_width * native_height];
+        self.gray_output = vec![0u8; width * height];
+        self.upscaler = BilinearScaler::new(native_width, native_height, width, height);
+        self.row_means = Vec::with_capacity(native_height);
+        // The hardware encoder is opened for one size
+        self.encoder = None;
+        self.last_image = None;
+    }
+
+    /// Size of the encoded frames: the frame size, turned by the
+    /// orientation and cropped by the zoom
+    pub fn output_size(&self) -> FrameSize {
+        self.size.output(&self.applied)
+    }
+
+    /// Size of the native grayscale image: one pixel per 4x2 block
+    fn native_size(&self) -> (usize, usize) {
+        (self.size.width / 4, self.size.height / 2)
     }
 
     pub fn setup_sensor(&self) -> Result<()> {
+        if !self.config.source.has_sensor() {
+            return Ok(());
+        }
         let output = Command::new("v4l2-ctl")
             .args([
                 "-d", &self.config.sensor_subdev,
@@ -118,310 +956,1865 @@ impl FrameCapture {
         Ok(())
     }
 
+    /// Open the configured source: detect the raw format and start the
+    /// capture stream, or open the raw dumps or the test pattern
     pub fn start_streaming(&mut self) -> Result<()> {
-        tracing::info!("Capture ready: {}x{} {:?}", WIDTH, HEIGHT, self.config.mode);
+        // The old stream must release the device first
+        *self.source.get_mut() = None;
+        let source: Box<dyn FrameSource> = match self.config.source.clone() {
+            SourceConfig::V4l2 => {
+                if let Some(size) = self.config.frame_size {
+                    self.request_frame_size(size);
+                }
+                // Before the format is read: a flip can change the Bayer order
+                self.update_sensor_flips(true);
+                // Frames are cut from the stream by the buffer size, so a
+                // wrong guess would misalign every frame after the first
+                self.detect_format().context("Could not read the raw format from the video device")?;
+                Box::new(V4l2Source::start(&self.config.device_path, self.frame_bytes)?)
+            }
+            SourceConfig::Files { path, format, size, stride } => {
+                self.set_raw_format(format, size, stride)?;
+                let files = FileSource::open(&path, self.frame_bytes)?;
+                tracing::info!("Playing {} raw frame(s) from {}", files.len(), path.display());
+                Box::new(files)
+            }
+            SourceConfig::Synthetic { format, size, stride } => {
+                self.set_raw_format(format.clone(), size, stride)?;
+                Box::new(SyntheticSource::new(format, size, stride)?)
+            }
+        };
+        let name = source.name();
+        self.set_source(source);
+        tracing::info!(
+            "Capture ready: {} {:?} from {}, {} ({:?}, {}-bit {:?}, {} bytes per line)",
+            self.size, self.settings.load().mode, name, self.format.fourcc,
+            self.format.packing, self.format.bits, self.format.cfa, self.stride
+        );
         Ok(())
     }
-    
-    pub fn set_mode(&mut self, mode: CaptureMode) {
-        self.config.mode = mode;
-        tracing::info!("Mode changed to {:?}", mode);
-    }
-    
-    pub fn mode(&self) -> CaptureMode {
-        self.config.mode
+
+    /// Take frames from `source` (in the current raw format) instead of
+    /// the configured one
+    pub fn set_source(&mut self, source: Box<dyn FrameSource>) {
+        self.sensor = source.has_sensor();
+        *self.source.get_mut() = Some(source);
     }
 
-    fn capture_raw_frame(&self) -> Result<Vec<u8>> {
-        let frame_num = FRAME_COUNTER.fetch_add(1, Ordering::Relaxed);
-        let raw_path = self.config.temp_dir.join(format!("frame_{}.raw", frame_num % 4));
-        
-        let output = Command::new("v4l2-ctl")
-            .args([
-                "-d", &self.config.device_path,
-                "--stream-mmap=4",
-                "--stream-skip=1",
-                "--stream-count=1",
-                &format!("--stream-to={}", raw_path.display()),
-            ])
-            .stderr(Stdio::null())
-            .output()
-            .context("Failed to run v4l2-ctl capture")?;
-        
-        if !output.status.success() {
-            anyhow::bail!("v4l2-ctl capture failed");
+    /// Name of the frame source (None before one is opened)
+    pub fn source_name(&self) -> Option<&'static str> {
+        self.source.lock().as_ref().map(|s| s.name())
+    }
+    
+    /// Ask the sensor and the video device for a mode of `size`; what the
+    /// driver settles on is read back by `detect_format`
+    fn request_frame_size(&self, size: FrameSize) {
+        let (width, height) = (size.width, size.height);
+        let requests = [
+            (&self.config.sensor_subdev, format!("--set-subdev-fmt=pad=0,width={},height={}", width, height)),
+            (&self.config.device_path, format!("--set-fmt-video=width={},height={}", width, height)),
+        ];
+        for (device, request) in requests {
+            match Command::new("v4l2-ctl").args(["-d", device, &request]).output() {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => tracing::warn!(
+                    "Could not request {} from {}: {}",
+                    size,
+                    device,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => tracing::warn!("Could not request {} from {}: {}", size, device, e),
+            }
         }
-        
-        let raw_data = fs::read(&raw_path).context("Failed to read raw frame")?;
-        let _ = fs::remove_file(&raw_path);
-        
-        Ok(raw_data)
     }
 
-    /// Unpack CSI-2 packed SGBRG10 (5 bytes → 4 pixels at 10-bit)
-    fn unpack_bayer10(&mut self, raw: &[u8]) {
-        for y in 0..HEIGHT {
-            let raw_row = y * STRIDE;
-            let out_row = y * WIDTH;
-
-            for x in 0..(WIDTH / 4) {
-                let i = raw_row + x * 5;
-                if i + 4 >= raw.len() {
-                    break;
+    /// Ask the sensor for the flips of the applied orientation, leaving to
+    /// software those it has no control for; `force` sets both controls
+    /// even if they look unchanged (the sensor may keep them from an
+    /// earlier run). True if the readout changed.
+    fn update_sensor_flips(&mut self, force: bool) -> bool {
+        if !self.config.source.has_sensor() {
+            return false;
+        }
+        let wanted = self.applied.orientation.flips();
+        let mut changed = false;
+        let axes = [
+            ("horizontal_flip", wanted.horizontal, &mut self.sensor_flips.horizontal),
+            ("vertical_flip", wanted.vertical, &mut self.sensor_flips.vertical),
+        ];
+        for (control, on, current) in axes {
+            if !force && on == *current {
+                continue;
+            }
+            match crate::controls::set_control(&self.config.sensor_subdev, control, on as i64) {
+                Ok(()) => {
+                    changed |= on != *current;
+                    *current = on;
                 }
-
-                let b0 = raw[i + 0] as u16;
-                let b1 = raw[i + 1] as u16;
-                let b2 = raw[i + 2] as u16;
-                let b3 = raw[i + 3] as u16;
-                let b4 = raw[i + 4] as u16;
-
-                self.bayer10[out_row + x * 4 + 0] = (b0 << 2) | ((b4 >> 0) & 0x3);
-                self.bayer10[out_row + x * 4 + 1] = (b1 << 2) | ((b4 >> 2) & 0x3);
-                self.bayer10[out_row + x * 4 + 2] = (b2 << 2) | ((b4 >> 4) & 0x3);
-                self.bayer10[out_row + x * 4 + 3] = (b3 << 2) | ((b4 >> 6) & 0x3);
+                // Unflipped is what a sensor without the control delivers
+                Err(e) if on => tracing::warn!("Sensor {} unavailable ({:#}), flipping in software", control, e),
+                Err(e) => tracing::debug!("Sensor {}: {:#}", control, e),
             }
         }
+        changed
     }
 
-    /// Safe Bayer access with clamping
-    #[inline]
-    fn bayer(&self, x: isize, y: isize) -> u16 {
-        let x = x.clamp(0, (WIDTH - 1) as isize) as usize;
-        let y = y.clamp(0, (HEIGHT - 1) as isize) as usize;
-        self.bayer10[y * WIDTH + x]
+    /// Flips the sensor readout currently has
+    pub fn sensor_flips(&self) -> Flips {
+        self.sensor_flips
     }
 
-    /// GBRG bilinear demosaic (10-bit precision)
-    fn demosaic_bayer(&mut self) {
-        for y in 0..HEIGHT as isize {
-            for x in 0..WIDTH as isize {
-                let idx = (y as usize * WIDTH + x as usize) * 3;
-
-                let (r, g, b) = match ((y & 1), (x & 1)) {
-                    // G (row 0, col 0) - Green in GB row
-                    (0, 0) => (
-                        (self.bayer(x, y - 1) + self.bayer(x, y + 1)) / 2,
-                        self.bayer(x, y),
-                        (self.bayer(x - 1, y) + self.bayer(x + 1, y)) / 2,
-                    ),
-                    // B (row 0, col 1) - Blue in GB row
-                    (0, 1) => (
-                        (self.bayer(x - 1, y - 1)
-                            + self.bayer(x + 1, y - 1)
-                            + self.bayer(x - 1, y + 1)
-                            + self.bayer(x + 1, y + 1)) / 4,
-                        (self.bayer(x - 1, y)
-                            + self.bayer(x + 1, y)
-                            + self.bayer(x, y - 1)
-                            + self.bayer(x, y + 1)) / 4,
-                        self.bayer(x, y),
-                    ),
-                    // R (row 1, col 0) - Red in RG row
-                    (1, 0) => (
-                        self.bayer(x, y),
-                        (self.bayer(x - 1, y)
-                            + self.bayer(x + 1, y)
-                            + self.bayer(x, y - 1)
-                            + self.bayer(x, y + 1)) / 4,
-                        (self.bayer(x - 1, y - 1)
-                            + self.bayer(x + 1, y - 1)
-                            + self.bayer(x - 1, y + 1)
-                            + self.bayer(x + 1, y + 1)) / 4,
-                    ),
-                    // G (row 1, col 1) - Green in RG row
-                    _ => (
-                        (self.bayer(x - 1, y) + self.bayer(x + 1, y)) / 2,
-                        self.bayer(x, y),
-                        (self.bayer(x, y - 1) + self.bayer(x, y + 1)) / 2,
-                    ),
-                };
-
-                // Store as 10-bit values (will apply gamma later)
-                self.rgb_buffer[idx + 0] = (r.min(1023) >> 2) as u8;
-                self.rgb_buffer[idx + 1] = (g.min(1023) >> 2) as u8;
-                self.rgb_buffer[idx + 2] = (b.min(1023) >> 2) as u8;
-            }
-        }
-    }
-
-    /// Apply gray-world white balance
-    fn apply_white_balance(&mut self) {
-        if !self.config.enable_white_balance {
-            return;
+    /// TIFF/EXIF Orientation value of raw frames: the part of the
+    /// orientation the sensor doesn't do
+    pub fn raw_orientation(&self) -> u16 {
+        self.applied.orientation.exif_orientation(self.sensor_flips)
+    }
+
+    /// Read the negotiated pixel format, frame size, line length and
+    /// buffer size from the video device
+    pub fn detect_format(&mut self) -> Result<()> {
+        let output = Command::new("v4l2-ctl")
+            .args(["-d", &self.config.device_path, "--get-fmt-video"])
+            .output()
+            .context("Failed to run v4l2-ctl")?;
+        let device = DeviceFormat::parse(&String::from_utf8_lossy(&output.stdout))?;
+        if let Some(wanted) = self.config.frame_size.filter(|&wanted| wanted != device.size) {
+            tracing::warn!("Asked for {} but the device delivers {}", wanted, device.size);
         }
-        
-        let pixels = WIDTH * HEIGHT;
-        let mut r_sum = 0u64;
-        let mut g_sum = 0u64;
-        let mut b_sum = 0u64;
-        
-        for i in 0..pixels {
-            r_sum += self.rgb_buffer[i * 3 + 0] as u64;
-            g_sum += self.rgb_buffer[i * 3 + 1] as u64;
-            b_sum += self.rgb_buffer[i * 3 + 2] as u64;
+        self.set_raw_format(device.format, device.size, device.stride)?;
+        self.frame_bytes = device.frame_bytes;
+        Ok(())
+    }
+    
+    /// Use a raw format, frame size and line length without asking the
+    /// device (frames are then exactly `stride * size.height` bytes); the
+    /// configured Bayer order, if any, replaces the format's
+    pub fn set_raw_format(&mut self, mut format: RawFormat, size: FrameSize, stride: usize) -> Result<()> {
+        size.validate().map_err(anyhow::Error::msg)?;
+        if stride < format.min_stride(size.width) {
+            anyhow::bail!("{} bytes per line is too short for {} at {}", stride, format.fourcc, size);
         }
-        
-        let r_avg = r_sum as f32 / pixels as f32;
-        let g_avg = g_sum as f32 / pixels as f32;
-        let b_avg = b_sum as f32 / pixels as f32;
-        let avg = (r_avg + g_avg + b_avg) / 3.0;
-        
-        // Limit gains to prevent extreme correction
-        let r_gain = (avg / r_avg).clamp(0.5, 2.0);
-        let g_gain = (avg / g_avg).clamp(0.5, 2.0);
-        let b_gain = (avg / b_avg).clamp(0.5, 2.0);
-        
-        for i in 0..pixels {
-            self.rgb_buffer[i * 3 + 0] = (self.rgb_buffer[i * 3 + 0] as f32 * r_gain).min(255.0) as u8;
-            self.rgb_buffer[i * 3 + 1] = (self.rgb_buffer[i * 3 + 1] as f32 * g_gain).min(255.0) as u8;
-            self.rgb_buffer[i * 3 + 2] = (self.rgb_buffer[i * 3 + 2] as f32 * b_gain).min(255.0) as u8;
+        if let Some(cfa) = self.config.bayer_order.filter(|&cfa| cfa != format.cfa) {
+            tracing::info!("Bayer order {} overrides {} reported by {}", cfa.name(), format.cfa.name(), format.fourcc);
+            format.cfa = cfa;
         }
-    }
-
-    /// Apply gamma correction
-    fn apply_gamma(&mut self) {
-        let inv_gamma = 1.0 / self.config.gamma;
-        for byte in self.rgb_buffer.iter_mut() {
-            let normalized = *byte as f32 / 255.0;
-            *byte = (normalized.powf(inv_gamma) * 255.0) as u8;
+        self.format = format;
+        self.stride = stride;
+        self.frame_bytes = stride * size.height;
+        if size != self.size {
+            self.size = size;
+            self.resize_buffers();
         }
+        Ok(())
+    }
+    
+    /// Negotiated raw format
+    pub fn raw_format(&self) -> &RawFormat {
+        &self.format
+    }
+    
+    /// Raw line length in bytes
+    pub fn stride(&self) -> usize {
+        self.stride
     }
 
-    // ==================== GRAYSCALE MODE ====================
-
-    /// Extract byte-4 with row averaging → 960x1080
-    fn extract_grayscale(&mut self, raw: &[u8]) {
-        for out_y in 0..(HEIGHT / 2) {
-            let row0 = out_y * 2;
-            let row1 = row0 + 1;
-            let row0_start = row0 * STRIDE;
-            let row1_start = row1 * STRIDE;
-            let out_row_start = out_y * GROUPS_PER_ROW;
-            
-            for g in 0..GROUPS_PER_ROW {
-                let idx0 = row0_start + g * 5 + 4;
-                let idx1 = row1_start + g * 5 + 4;
-                
-                let v0 = raw.get(idx0).copied().unwrap_or(0) as u16;
-                let v1 = raw.get(idx1).copied().unwrap_or(0) as u16;
-                
-                self.gray_native[out_row_start + g] = ((v0 + v1) / 2) as u8;
-            }
-        }
+    /// Size of the raw and processed frames
+    pub fn frame_size(&self) -> FrameSize {
+        self.size
+    }
+    
+    /// Also extract the native grayscale in color mode (for motion detection)
+    pub fn set_native_gray(&mut self, wanted: bool) {
+        self.gray_native_wanted = wanted;
     }
 
-    /// Upscale 960x1080 → 3840x2160 using bilinear interpolation
-    fn upscale_grayscale(&mut self) {
-        let src_w = GROUPS_PER_ROW;
-        let src_h = HEIGHT / 2;
-        let dst_w = WIDTH;
-        let dst_h = HEIGHT;
-        
-        let x_ratio = ((src_w - 1) << 16) / (dst_w - 1);
-        let y_ratio = ((src_h - 1) << 16) / (dst_h - 1);
-        
-        for dst_y in 0..dst_h {
-            let src_y_fp = dst_y * y_ratio;
-            let src_y0 = src_y_fp >> 16;
-            let src_y1 = (src_y0 + 1).min(src_h - 1);
-            let y_frac = (src_y_fp & 0xFFFF) as u32;
-            
-            let dst_row = dst_y * dst_w;
-            
-            for dst_x in 0..dst_w {
-                let src_x_fp = dst_x * x_ratio;
-                let src_x0 = src_x_fp >> 16;
-                let src_x1 = (src_x0 + 1).min(src_w - 1);
-                let x_frac = (src_x_fp & 0xFFFF) as u32;
-                
-                let p00 = self.gray_native[src_y0 * src_w + src_x0] as u32;
-                let p01 = self.gray_native[src_y0 * src_w + src_x1] as u32;
-                let p10 = self.gray_native[src_y1 * src_w + src_x0] as u32;
-                let p11 = self.gray_native[src_y1 * src_w + src_x1] as u32;
-                
-                let x_inv = 0x10000 - x_frac;
-                let y_inv = 0x10000 - y_frac;
-                
-                let top = (p00 * x_inv + p01 * x_frac) >> 16;
-                let bot = (p10 * x_inv + p11 * x_frac) >> 16;
-                let val = (top * y_inv + bot * y_frac) >> 16;
-                
-                self.gray_output[dst_row + dst_x] = val as u8;
-            }
+    /// Native grayscale of the last processed frame (width, height,
+    /// pixels; 960x1080 at full size); None in color mode unless asked for
+    /// with `set_native_gray`
+    pub fn native_gray(&self) -> Option<(usize, usize, &[u8])> {
+        if matches!(self.applied.mode, CaptureMode::Color | CaptureMode::ColorHdr) && !self.gray_native_wanted {
+            return None;
         }
+        let (width, height) = self.native_size();
+        Some((width, height, &self.gray_native))
     }
 
-    // ==================== JPEG ENCODING ====================
-
-    fn encode_jpeg(&mut self) -> Result<Vec<u8>> {
-        self.jpeg_buffer.clear();
-        
-        match self.config.mode {
-            CaptureMode::Color => {
-                let image = RgbImage::from_raw(
-                    WIDTH as u32,
-                    HEIGHT as u32,
-                    self.rgb_buffer.clone(),
-                ).context("Failed to create RGB image")?;
-                
-                let mut encoder = JpegEncoder::new_with_quality(&mut self.jpeg_buffer, self.config.jpeg_quality);
-                encoder.encode(
-                    image.as_raw(),
-                    image.width(),
-                    image.height(),
-                    image::ExtendedColorType::Rgb8,
-                ).context("Failed to encode JPEG")?;
-            }
-            CaptureMode::Grayscale => {
-                let image = GrayImage::from_raw(
-                    WIDTH as u32,
-                    HEIGHT as u32,
-                    self.gray_output.clone(),
-                ).context("Failed to create grayscale image")?;
+    /// Settings version the last frame was processed with
+    pub fn applied_version(&self) -> u64 {
+        self.applied.version
+    }
+    
+    /// Start a frame: take the current settings snapshot and bring the
+    /// derived state up to date if it is a new version
+    fn begin_frame(&mut self) {
+        self.stages = StageTimings::default();
+        let next = self.settings.load();
+        if next.version == self.applied.version {
+            return;
+        }
+        let previous = std::mem::replace(&mut self.applied, next.clone());
+        if next.gamma != previous.gamma {
+            self.gamma_lut = build_gamma_lut(next.gamma);
+            // The tone curve is built in the gamma domain
+            self.tone_curve.clear();
+        }
+        if next.black_level != previous.black_level {
+            (self.black_lut10, self.black_lut8) = build_black_level_luts(next.black_level);
+        }
+        if next.tonemap_strength == 0.0 && previous.tonemap_strength > 0.0 {
+            // Start from plain gamma next time instead of a stale curve
+            self.tone_curve.clear();
+        }
+        if next.mode != previous.mode || next.enable_white_balance != previous.enable_white_balance {
+            self.wb.reset();
+        }
+        if next.color_correction != previous.color_correction {
+            self.ccm = build_ccm(&next.color_correction);
+        }
+        if next.masks != previous.masks {
+            self.mask_raster = None;
+        }
+        if next.green_balance.mode != previous.green_balance.mode {
+            self.green.reset();
+        }
+        if self.size.output(&next) != self.size.output(&previous) {
+            // Opened for the other output size
+            self.encoder = None;
+        }
+        if next.orientation != previous.orientation && self.update_sensor_flips(false) {
+            // Frames read out before the flip are in flight, and the driver
+            // may report another Bayer order now
+            self.skip_frames(SETTLE_FRAMES);
+            if let Err(e) = self.detect_format() {
+                tracing::warn!("Could not read the raw format back after flipping: {:#}", e);
+            }
+        }
+        let same_quality = match (&next.adaptive_quality, &self.quality) {
+            (Some(next), Some(current)) => next.same_limits(current),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same_quality {
+            self.quality = next.adaptive_quality.clone();
+        }
+        let same_ae = match (&next.auto_exposure, &self.ae) {
+            (Some(next), Some(current)) => next.same_limits(current),
+            (None, None) => true,
+            _ => false,
+        };
+        if !same_ae {
+            self.ae = next.auto_exposure.clone();
+        } else if !next.metering.same_region(&previous.metering) {
+            if let Some(ae) = self.ae.as_mut() {
+                ae.restart();
+            }
+        }
+        tracing::debug!("Pipeline settings version {}", next.version);
+    }
+    
+    /// Estimate the black level from a dark frame (lens covered) and apply it
+    pub fn calibrate_black_level(&mut self) -> Result<u16> {
+        let raw_data = self.capture_raw_frame()?;
+        // Unpack without subtracting the current pedestal
+        self.unpack_bayer10(&raw_data);
+        
+        let mut histogram = [0u32; 1024];
+        for &v in &self.bayer10 {
+            histogram[v as usize & 0x3FF] += 1;
+        }
+        let half = self.bayer10.len() as u32 / 2;
+        let mut cumulative = 0;
+        let median = histogram
+            .iter()
+            .position(|&count| {
+                cumulative += count;
+                cumulative > half
+            })
+            .unwrap_or(0) as u16;
+        
+        self.settings.update(|s| s.set_black_level(median));
+        Ok(median)
+    }
+    
+    /// Quality used for the next streamed frame
+    pub fn effective_quality(&self) -> u8 {
+        match &self.quality {
+            Some(adaptive) => adaptive.quality(),
+            None => self.applied.jpeg_quality,
+        }
+    }
+    
+    /// Quality for stills of the last frame: the configured maximum, never the adaptive value
+    pub fn snapshot_quality(&self) -> u8 {
+        self.applied.snapshot_quality()
+    }
+    
+    /// Auto-exposure state, while it is on
+    pub fn auto_exposure_status(&self) -> Option<AutoExposureStatus> {
+        self.ae.as_ref().map(AutoExposure::status)
+    }
+    
+    /// White balance gains (R, G, B) applied to the last color frame
+    pub fn white_balance_gains(&self) -> Option<[f32; 3]> {
+        self.wb.gains()
+    }
+    
+    /// Gr/Gb estimate and the gain applied to the last color frame
+    pub fn green_balance_state(&self) -> &GreenBalancer {
+        &self.green
+    }
+    
+    /// Forget the state carried between frames (white balance and Gr/Gb
+    /// estimates, tone curve), so the next frame is processed on its own
+    pub fn reset_scene_state(&mut self) {
+        self.wb.reset();
+        self.wb_updated = None;
+        self.green.reset();
+        self.tone_curve.clear();
+    }
+    
+    /// Suspend scene-adaptive processing while a sensor test pattern is shown
+    pub fn set_test_pattern_active(&mut self, active: bool) {
+        if active != self.test_pattern_active {
+            self.wb.reset();
+        }
+        self.test_pattern_active = active;
+    }
+    
+    /// Unpack a raw frame into the 10-bit Bayer buffer (no corrections) and return it
+    pub fn unpack_raw(&mut self, raw: &[u8]) -> &[u16] {
+        self.unpack_bayer10(raw);
+        &self.bayer10
+    }
+    
+    /// Mode of the last processed frame
+    pub fn mode(&self) -> CaptureMode {
+        self.applied.mode
+    }
+
+    /// Capture what the current mode needs for one output frame: a single
+    /// raw frame, or an exposure pair in the HDR modes
+    pub fn capture_next(&self) -> Result<RawCapture> {
+        let settings = self.settings.load();
+        // Without a sensor there is no exposure to vary: a single frame is
+        // processed as in the plain mode
+        if settings.mode.is_hdr() && self.sensor {
+            Ok(RawCapture::Pair(self.capture_hdr_pair(settings.hdr_ratio)?))
+        } else {
+            Ok(RawCapture::Frame(self.capture_raw_frame()?))
+        }
+    }
+
+    /// Capture an exposure-fusion pair: a frame at the current exposure,
+    /// then one at `1/ratio` of it. The exposure setting is restored
+    /// afterwards, also when a capture fails.
+    pub fn capture_hdr_pair(&self, ratio: f32) -> Result<HdrPair> {
+        let subdev = &self.config.sensor_subdev;
+        let exposure = crate::controls::find_control(subdev, "exposure")?;
+        let long_lines = exposure.value.context("Exposure control has no value")?;
+        let short_lines = ((long_lines as f32 / ratio).round() as i64).max(exposure.min.unwrap_or(1)).max(1);
+        
+        let long = self.capture_raw_frame()?;
+        crate::controls::set_control(subdev, "exposure", short_lines)?;
+        self.skip_frames(SETTLE_FRAMES);
+        let short = self.capture_raw_frame();
+        let restored = crate::controls::set_control(subdev, "exposure", long_lines);
+        self.skip_frames(SETTLE_FRAMES);
+        let short = short?;
+        restored?;
+        Ok(HdrPair { long, short, ratio: long_lines as f32 / short_lines as f32 })
+    }
+
+    /// Next packed raw frame from the source, without the buffer's padding
+    /// past the last line (the capture stream is restarted if it ended or
+    /// stalled)
+    pub fn capture_raw_frame(&self) -> Result<Vec<u8>> {
+        let mut source = self.source.lock();
+        let source = source.as_mut().context("Capture not started")?;
+        let mut frame = source.next_frame(self.config.capture_timeout.unwrap_or(DEFAULT_FRAME_TIMEOUT))?;
+        frame.truncate(self.stride * self.size.height);
+        Ok(frame)
+    }
+
+    /// Drop the frame in flight and `frames` more, so the next frame
+    /// returned was exposed after now (e.g. after a control change)
+    pub fn skip_frames(&self, frames: u64) {
+        if let Some(source) = self.source.lock().as_mut() {
+            source.skip(frames);
+        }
+    }
+
+    /// Unpack the raw frame into the 10-bit Bayer buffer
+    fn unpack_bayer10(&mut self, raw: &[u8]) {
+        self.format.unpack(raw, self.stride, self.size.width, &mut self.bayer10);
+    }
+
+    /// Subtract the black level from the Bayer buffer, plus the tone curve if enabled
+    fn apply_levels(&mut self) {
+        if self.applied.tonemap_strength > 0.0 && !self.test_pattern_active {
+            self.update_tone_curve();
+            for v in self.bayer10.iter_mut() {
+                *v = self.tone_lut10[*v as usize & 0x3FF];
+            }
+            return;
+        }
+        if self.applied.black_level == 0 {
+            return;
+        }
+        for v in self.bayer10.iter_mut() {
+            *v = self.black_lut10[*v as usize & 0x3FF];
+        }
+    }
+
+    /// Even out the Gr/Gb response before demosaic
+    fn apply_green_balance(&mut self) {
+        self.green.process(
+            &self.applied.green_balance,
+            &mut self.bayer10,
+            self.size.width,
+            self.size.height,
+            self.format.cfa.gbrg_offset(),
+            !self.test_pattern_active,
+        );
+    }
+
+    /// Demosaic with the selected algorithm (see `demosaic`), rows split
+    /// across the pipeline threads
+    fn demosaic_bayer(&mut self) {
+        let algorithm = self.applied.demosaic;
+        let offset = self.format.cfa.gbrg_offset();
+        let FrameSize { width, height } = self.size;
+        let curve = self.gamma_in_demosaic().then_some(&self.gamma_lut);
+        let (bayer, rgb) = (&self.bayer10, &mut self.rgb_buffer);
+        self.pool.install(|| {
+            rgb.par_chunks_exact_mut(width * 3)
+                .enumerate()
+                .for_each(|(y, row)| algorithm.row(bayer, (width, height), y, offset, curve, row));
+        });
+    }
+
+    /// Whether the demosaic applies the gamma: nothing after it needs
+    /// linear values (no white balance or color matrix), so it can go
+    /// straight from 10 bits and skip the 8-bit pass
+    fn gamma_in_demosaic(&self) -> bool {
+        !self.applied.enable_white_balance && self.ccm.is_none()
+    }
+
+    /// Rebuild the tone LUT from this frame's luma histogram
+    ///
+    /// The target curve blends the gamma curve with a contrast-limited
+    /// histogram equalization (evaluated in the gamma domain, so a uniform
+    /// histogram maps to plain gamma), smoothed against the previous frame's
+    /// curve. It is converted back to linear 10-bit so the rest of the
+    /// pipeline (demosaic, white balance, gamma) is unchanged.
+    fn update_tone_curve(&mut self) {
+        // Green sites on the GB rows as luma
+        let (dy, dx) = self.format.cfa.gbrg_offset();
+        let mut histogram = [0u32; 256];
+        for row in self.bayer10.chunks_exact(self.size.width).skip(dy).step_by(2) {
+            for &v in row.iter().skip(dx).step_by(2) {
+                let black = self.black_lut10[v as usize & 0x3FF];
+                histogram[self.gamma_lut[black as usize] as usize] += 1;
+            }
+        }
+        let equalized = equalization_curve(&histogram);
+        
+        let strength = self.applied.tonemap_strength;
+        let gamma = self.applied.gamma;
+        let first = self.tone_curve.len() != 1024;
+        self.tone_curve.resize(1024, 0.0);
+        for v in 0..1024 {
+            let linear = self.black_lut10[v] as f32 / 1023.0;
+            let plain = linear.powf(1.0 / gamma) * 255.0;
+            let target = plain + strength * (sample_curve(&equalized, plain) - plain);
+            
+            let curve = &mut self.tone_curve[v];
+            *curve = if first { target } else { *curve + TONEMAP_SMOOTHING * (target - *curve) };
+            self.tone_lut10[v] = ((*curve / 255.0).clamp(0.0, 1.0).powf(gamma) * 1023.0).round() as u16;
+        }
+    }
+
+    /// Apply the fixed white balance gains, or the scene estimate smoothed
+    /// over time
+    fn apply_white_balance(&mut self) {
+        if !self.applied.enable_white_balance {
+            return;
+        }
+        let gains = match self.applied.wb_mode.fixed_gains() {
+            Some(gains) => {
+                self.wb.hold(gains);
+                gains
+            }
+            None if self.test_pattern_active => return,
+            None => self.estimate_wb_gains(self.applied.wb_mode == WhiteBalanceMode::WhitePatch),
+        };
+        
+        let (rgb, width) = (&mut self.rgb_buffer, self.size.width);
+        self.pool.install(|| {
+            rgb.par_chunks_exact_mut(width * 3).for_each(|row| {
+                for pixel in row.chunks_exact_mut(3) {
+                    for (v, gain) in pixel.iter_mut().zip(gains) {
+                        *v = (*v as f32 * gain).min(255.0) as u8;
+                    }
+                }
+            });
+        });
+    }
+
+    /// Gray-world or white-patch gains for this frame, after smoothing
+    /// (white-patch falls back to gray-world in a scene too dark for it)
+    fn estimate_wb_gains(&mut self, white_patch: bool) -> [f32; 3] {
+        let FrameSize { width, height } = self.size;
+        let pixels = width * height;
+        let rgb = &self.rgb_buffer;
+        let stats = self.pool.install(|| {
+            rgb.par_chunks_exact(width * 3)
+                .fold(
+                    || ChannelStats::new(white_patch),
+                    |mut stats, row| {
+                        for pixel in row.chunks_exact(3) {
+                            stats.add(pixel);
+                        }
+                        stats
+                    },
+                )
+                .reduce(|| ChannelStats::new(white_patch), ChannelStats::merge)
+        });
+        
+        let [r_avg, g_avg, b_avg] = stats.sums.map(|sum| sum as f32 / pixels as f32);
+        let avg = (r_avg + g_avg + b_avg) / 3.0;
+        
+        let now = Instant::now();
+        let dt_s = self.wb_updated.map_or(0.0, |t| now.duration_since(t).as_secs_f32());
+        self.wb_updated = Some(now);
+        let target = stats
+            .histograms
+            .as_deref()
+            .and_then(whitebalance::white_patch_target)
+            .unwrap_or([avg / r_avg.max(1e-3), avg / g_avg.max(1e-3), avg / b_avg.max(1e-3)]);
+        self.wb.update(&self.applied.wb_smoothing, target, avg, dt_s)
+    }
+
+    /// Apply gamma correction to the linear 8-bit frame, through the 10-bit
+    /// LUT
+    fn apply_gamma(&mut self) {
+        let lut: [u8; 256] = std::array::from_fn(|v| self.gamma_lut[(v * 1023 + 127) / 255]);
+        let (rgb, width) = (&mut self.rgb_buffer, self.size.width);
+        self.pool.install(|| {
+            rgb.par_chunks_exact_mut(width * 3).for_each(|row| {
+                for byte in row {
+                    *byte = lut[*byte as usize];
+                }
+            });
+        });
+    }
+
+    // ==================== GRAYSCALE MODE ====================
+
+    /// Extract byte-4 with row averaging → native size (960x1080 at full
+    /// size)
+    ///
+    /// Byte-4 only exists in 10-bit packed data; other formats average
+    /// each 4x2 block of the unpacked Bayer samples instead.
+    fn extract_grayscale(&mut self, raw: &[u8]) {
+        let width = self.size.width;
+        let (groups_per_row, native_height) = self.native_size();
+        if self.format.packing != Packing::Packed10 {
+            self.unpack_bayer10(raw);
+            for out_y in 0..native_height {
+                let rows = &self.bayer10[out_y * 2 * width..(out_y * 2 + 2) * width];
+                let (row0, row1) = rows.split_at(width);
+                for g in 0..groups_per_row {
+                    let sum: u32 = row0[g * 4..g * 4 + 4]
+                        .iter()
+                        .chain(&row1[g * 4..g * 4 + 4])
+                        .map(|&v| v as u32)
+                        .sum();
+                    // Mean of 8 10-bit samples, scaled to 8 bits
+                    self.gray_native[out_y * groups_per_row + g] = self.black_lut8[(sum / 32).min(255) as usize];
+                }
+            }
+            return;
+        }
+        for out_y in 0..native_height {
+            let row0 = out_y * 2;
+            let row1 = row0 + 1;
+            let row0_start = row0 * self.stride;
+            let row1_start = row1 * self.stride;
+            let out_row_start = out_y * groups_per_row;
+            
+            for g in 0..groups_per_row {
+                let idx0 = row0_start + g * 5 + 4;
+                let idx1 = row1_start + g * 5 + 4;
+                
+                let v0 = raw.get(idx0).copied().unwrap_or(0) as u16;
+                let v1 = raw.get(idx1).copied().unwrap_or(0) as u16;
                 
-                let mut encoder = JpegEncoder::new_with_quality(&mut self.jpeg_buffer, self.config.jpeg_quality);
-                encoder.encode(
-                    image.as_raw(),
-                    image.width(),
-                    image.height(),
-                    image::ExtendedColorType::L8,
-                ).context("Failed to encode JPEG")?;
+                self.gray_native[out_row_start + g] = self.black_lut8[((v0 + v1) / 2) as usize];
+            }
+        }
+    }
+
+    /// Extract linear 16-bit luma (native size, black level removed)
+    /// through the same byte-4 or full-luma path as `extract_grayscale`
+    fn extract_luma16(&mut self, raw: &[u8], out: &mut Vec<u16>) {
+        let width = self.size.width;
+        let (groups_per_row, native_height) = self.native_size();
+        out.resize(groups_per_row * native_height, 0);
+        let black10 = self.applied.black_level.min(1022) as u32;
+        if self.format.packing != Packing::Packed10 {
+            self.unpack_bayer10(raw);
+            // Sum of 8 10-bit samples
+            let black = black10 * 8;
+            let range = 1023 * 8 - black;
+            for out_y in 0..native_height {
+                let rows = &self.bayer10[out_y * 2 * width..(out_y * 2 + 2) * width];
+                let (row0, row1) = rows.split_at(width);
+                for g in 0..groups_per_row {
+                    let sum: u32 = row0[g * 4..g * 4 + 4]
+                        .iter()
+                        .chain(&row1[g * 4..g * 4 + 4])
+                        .map(|&v| v as u32)
+                        .sum();
+                    out[out_y * groups_per_row + g] = (sum.saturating_sub(black) * 65535 / range).min(65535) as u16;
+                }
+            }
+            return;
+        }
+        // Sum of two 8-bit byte-4 values, black capped as in `black_lut8`
+        let black = (black10 >> 2).min(254) * 2;
+        let range = 510 - black;
+        for out_y in 0..native_height {
+            let row0_start = out_y * 2 * self.stride;
+            let row1_start = row0_start + self.stride;
+            for g in 0..groups_per_row {
+                let v0 = raw.get(row0_start + g * 5 + 4).copied().unwrap_or(0) as u32;
+                let v1 = raw.get(row1_start + g * 5 + 4).copied().unwrap_or(0) as u32;
+                out[out_y * groups_per_row + g] = ((v0 + v1).saturating_sub(black) * 65535 / range) as u16;
+            }
+        }
+    }
+
+    /// Fuse an exposure pair into the native grayscale image
+    fn fuse_grayscale(&mut self, pair: &HdrPair) {
+        let mut long = std::mem::take(&mut self.hdr_long);
+        let mut short = std::mem::take(&mut self.hdr_short);
+        self.extract_luma16(&pair.long, &mut long);
+        self.extract_luma16(&pair.short, &mut short);
+        hdr::fuse(&mut long, &short, pair.ratio);
+        if self.hdr_curve.as_ref().is_none_or(|(ratio, _)| *ratio != pair.ratio) {
+            self.hdr_curve = Some((pair.ratio, hdr::tone_curve(pair.ratio)));
+        }
+        if let Some((_, ref curve)) = self.hdr_curve {
+            hdr::tone_map(&long, curve, &mut self.gray_native);
+        }
+        self.hdr_long = long;
+        self.hdr_short = short;
+    }
+
+    /// Fuse an exposure pair into the Bayer buffer: both frames with the
+    /// black level removed, merged per sample and tone-mapped back to 10
+    /// bits (the tone-mapping curve of plain color mode is not applied)
+    fn fuse_bayer(&mut self, pair: &HdrPair) {
+        let mut long = std::mem::take(&mut self.hdr_long);
+        let mut short = std::mem::take(&mut self.hdr_short);
+        for (raw, out) in [(&pair.short, &mut short), (&pair.long, &mut long)] {
+            self.unpack_bayer10(raw);
+            let black_lut10 = &self.black_lut10;
+            out.clear();
+            out.extend(self.bayer10.iter().map(|&v| (black_lut10[v as usize & 0x3FF] as u32 * 65535 / 1023) as u16));
+        }
+        let (width, ratio) = (self.size.width, pair.ratio);
+        self.pool.install(|| {
+            long.par_chunks_mut(width)
+                .zip(short.par_chunks(width))
+                .for_each(|(long, short)| hdr::fuse(long, short, ratio));
+        });
+        if self.hdr_curve10.as_ref().is_none_or(|(ratio, _)| *ratio != pair.ratio) {
+            self.hdr_curve10 = Some((pair.ratio, hdr::tone_curve10(pair.ratio)));
+        }
+        if let Some((_, ref curve)) = self.hdr_curve10 {
+            hdr::tone_map(&long, curve, &mut self.bayer10);
+        }
+        self.hdr_long = long;
+        self.hdr_short = short;
+    }
+
+    /// Remove per-row offset (banding) noise from the native grayscale image
+    fn suppress_row_noise(&mut self) {
+        if !self.applied.row_noise_correction || self.applied.row_noise_strength <= 0.0 {
+            return;
+        }
+        let (native_width, native_height) = self.native_size();
+        correct_row_offsets(
+            &mut self.gray_native,
+            native_width,
+            native_height,
+            self.applied.row_noise_strength,
+            &mut self.row_means,
+        );
+    }
+
+    /// Upscale the native grayscale to the frame size (960x1080 → 3840x2160
+    /// at full size) using bilinear interpolation
+    fn upscale_grayscale(&mut self) {
+        if self.scaler == ScalerKind::Rga {
+            let native = self.native_size();
+            let output = (self.size.width, self.size.height);
+            match rga_resize(&self.gray_native, native, &mut self.gray_output, output, 1) {
+                Ok(()) => return,
+                Err(e) => {
+                    tracing::warn!("RGA scaling unavailable ({:#}), upscaling on the CPU", e);
+                    self.scaler = ScalerKind::Software;
+                }
+            }
+        }
+        self.upscaler.scale(&self.gray_native, &mut self.gray_output, 1);
+    }
+
+    /// Blend the frame into the running average when temporal denoise is
+    /// on: the native grayscale, or the color frame after gamma
+    fn reduce_noise(&mut self) {
+        let denoise = self.applied.denoise;
+        if !denoise.is_enabled() {
+            self.denoiser.reset();
+            return;
+        }
+        let start = Instant::now();
+        let (native_width, _) = self.native_size();
+        let (frame, width, channels) = match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => (&mut self.rgb_buffer, self.size.width, 3),
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => (&mut self.gray_native, native_width, 1),
+        };
+        let denoiser = &mut self.denoiser;
+        self.pool.install(|| denoiser.apply(frame, width, channels, &denoise));
+        self.stages.denoise = elapsed_ms(start);
+    }
+
+    /// Smooth the frame with the spatial denoise filter when it's on: the
+    /// color frame after demosaic, or the native grayscale
+    fn spatial_denoise(&mut self) {
+        let spatial = self.applied.spatial_denoise;
+        if !spatial.is_enabled() {
+            return;
+        }
+        let start = Instant::now();
+        let (native_width, _) = self.native_size();
+        let (frame, width, channels) = match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => (&mut self.rgb_buffer, self.size.width, 3),
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => (&mut self.gray_native, native_width, 1),
+        };
+        self.pool.install(|| spatial.apply(frame, width, channels));
+        self.stages.spatial_denoise = elapsed_ms(start);
+    }
+
+    /// Sharpen the processed frame when sharpening is on: the color frame
+    /// after gamma, or the upscaled grayscale
+    fn sharpen(&mut self) {
+        let sharpen = self.applied.sharpen;
+        if !sharpen.is_enabled() {
+            return;
+        }
+        let start = Instant::now();
+        let (frame, channels) = match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => (&mut self.rgb_buffer, 3),
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => (&mut self.gray_output, 1),
+        };
+        let width = self.size.width;
+        self.pool.install(|| sharpen.apply(frame, width, channels));
+        self.stages.sharpen = elapsed_ms(start);
+    }
+
+    /// Take the histogram of the processed frame (before contrast
+    /// enhancement, so auto-exposure meters the scene)
+    fn update_histogram(&mut self) {
+        let start = Instant::now();
+        self.histogram = Some(Arc::new(self.frame_histogram()));
+        let metered = self.ae.is_some() && self.applied.metering.mode != MeteringMode::Average;
+        self.metered_luma = metered.then(|| self.metered_histogram());
+        self.stages.histogram = elapsed_ms(start);
+    }
+
+    /// Equalize the grayscale output when contrast enhancement is on
+    fn enhance_contrast(&mut self) {
+        let contrast = self.applied.contrast;
+        if contrast.mode == ContrastMode::Off {
+            return;
+        }
+        let start = Instant::now();
+        let (gray, FrameSize { width, height }) = (&mut self.gray_output, self.size);
+        self.pool.install(|| contrast.apply(gray, width, height));
+        self.stages.contrast = elapsed_ms(start);
+    }
+
+    /// Brightness, contrast and saturation (after the histogram, like the
+    /// contrast enhancement)
+    fn adjust_tone(&mut self) {
+        let tone = self.applied.tone;
+        let (frame, channels) = match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => (&mut self.rgb_buffer, 3),
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => (&mut self.gray_output, 1),
+        };
+        if !tone.is_enabled(channels) {
+            return;
+        }
+        let start = Instant::now();
+        self.pool.install(|| tone.apply(frame, channels));
+        self.stages.tone = elapsed_ms(start);
+    }
+
+    // ==================== JPEG ENCODING ====================
+
+    /// Pixels of the processed frame, turned by the orientation, masked,
+    /// cropped by the zoom and with the overlay drawn on
+    fn output_image(&mut self) -> Result<DynamicImage> {
+        let image = match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => DynamicImage::ImageRgb8(
+                RgbImage::from_raw(
+                    self.size.width as u32,
+                    self.size.height as u32,
+                    self.rgb_buffer.clone(),
+                ).context("Failed to create RGB image")?,
+            ),
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => DynamicImage::ImageLuma8(
+                GrayImage::from_raw(
+                    self.size.width as u32,
+                    self.size.height as u32,
+                    self.gray_output.clone(),
+                ).context("Failed to create grayscale image")?,
+            ),
+        };
+        let orientation = self.applied.orientation;
+        let mut image = if orientation.needs_software(self.sensor_flips) {
+            let start = Instant::now();
+            let image = orientation.apply(image, self.sensor_flips);
+            self.stages.orientation = elapsed_ms(start);
+            image
+        } else {
+            image
+        };
+        if !self.applied.masks.is_empty() {
+            let start = Instant::now();
+            let oriented = self.size.oriented(&orientation);
+            let raster = match self.mask_raster {
+                Some(ref raster) if raster.size() == oriented => raster,
+                _ => self.mask_raster.insert(MaskRaster::new(&self.applied.masks, oriented)),
+            };
+            raster.apply(&mut image);
+            self.stages.masks = elapsed_ms(start);
+        }
+        let zoom = self.applied.zoom;
+        let mut image = if zoom.is_full() {
+            image
+        } else {
+            let start = Instant::now();
+            let crop = zoom.crop(self.size.oriented(&orientation));
+            let image = image.crop_imm(crop.x as u32, crop.y as u32, crop.width as u32, crop.height as u32);
+            self.stages.zoom = elapsed_ms(start);
+            image
+        };
+        if self.applied.overlay.is_enabled() {
+            let start = Instant::now();
+            self.applied.overlay.draw(&mut image, chrono::Local::now());
+            self.stages.overlay = elapsed_ms(start);
+        }
+        Ok(image)
+    }
+
+    fn encode_jpeg(&mut self, image: DynamicImage) -> Result<Vec<u8>> {
+        let quality = self.effective_quality();
+        let encode_start = Instant::now();
+        let (kind, size) = (self.config.encoder, self.output_size());
+        let encoder = self.encoder.get_or_insert_with(|| start_encoder(kind, size));
+        if let Err(e) = encoder.encode(&image, quality, &mut self.jpeg_buffer) {
+            if encoder.name() == SoftwareEncoder.name() {
+                return Err(e);
             }
+            tracing::warn!("{} JPEG encoder failed ({:#}), switching to software", encoder.name(), e);
+            *encoder = Box::new(SoftwareEncoder);
+            encoder.encode(&image, quality, &mut self.jpeg_buffer)?;
+        }
+        
+        let encode_ms = encode_start.elapsed().as_secs_f32() * 1000.0;
+        self.stages.encode = Some(encode_ms);
+        if let Some(ref mut adaptive) = self.quality {
+            adaptive.update(self.jpeg_buffer.len(), encode_ms);
         }
         
+        // Keep the pre-encode pixels so crops don't need a JPEG decode
+        self.last_image = Some(Arc::new(image));
+        
         Ok(self.jpeg_buffer.clone())
     }
 
+    /// Full-resolution pixels of the most recently encoded frame
+    pub fn last_image(&self) -> Option<Arc<DynamicImage>> {
+        self.last_image.clone()
+    }
+
+    /// Stage timings of the most recently processed frame
+    pub fn stage_timings(&self) -> StageTimings {
+        self.stages
+    }
+
     /// Capture and return JPEG-encoded frame
     pub fn capture_jpeg_frame(&mut self) -> Result<Vec<u8>> {
-        let raw_data = self.capture_raw_frame()?;
-        
-        match self.config.mode {
-            CaptureMode::Color => {
-                self.unpack_bayer10(&raw_data);
-                self.demosaic_bayer();
-                self.apply_white_balance();
-                self.apply_gamma();
-            }
-            CaptureMode::Grayscale => {
-                self.extract_grayscale(&raw_data);
+        let raw = self.capture_next()?;
+        self.process_capture(&raw)
+    }
+
+    /// Process and encode the result of `capture_next`, then let
+    /// auto-exposure (if on) adjust the sensor for the next frames
+    pub fn process_capture(&mut self, raw: &RawCapture) -> Result<Vec<u8>> {
+        let jpeg = match raw {
+            RawCapture::Frame(raw_data) => self.process_raw_frame(raw_data),
+            RawCapture::Pair(pair) => self.process_hdr_pair(pair),
+        }?;
+        let start = Instant::now();
+        self.run_auto_exposure();
+        if self.ae.is_some() {
+            self.stages.auto_exposure = elapsed_ms(start);
+        }
+        Ok(jpeg)
+    }
+
+    /// Histogram of the processed frame, sampled every `HISTOGRAM_STEP`
+    /// pixels in each direction
+    fn frame_histogram(&self) -> Histogram {
+        let mut histogram = Histogram { luma: [0; 256], rgb: None };
+        let FrameSize { width, height } = self.size;
+        match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => {
+                let mut rgb = Box::new([[0u32; 256]; 3]);
+                for y in (0..height).step_by(HISTOGRAM_STEP) {
+                    for x in (0..width).step_by(HISTOGRAM_STEP) {
+                        let i = (y * width + x) * 3;
+                        for (channel, &v) in rgb.iter_mut().zip(&self.rgb_buffer[i..i + 3]) {
+                            channel[v as usize] += 1;
+                        }
+                        histogram.luma[self.sample_luma(x, y) as usize] += 1;
+                    }
+                }
+                histogram.rgb = Some(rgb);
+            }
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => {
+                for y in (0..height).step_by(HISTOGRAM_STEP) {
+                    for x in (0..width).step_by(HISTOGRAM_STEP) {
+                        histogram.luma[self.sample_luma(x, y) as usize] += 1;
+                    }
+                }
+            }
+        }
+        histogram
+    }
+
+    /// Luma of the processed (not yet oriented) frame at `(x, y)`
+    fn sample_luma(&self, x: usize, y: usize) -> u8 {
+        let i = y * self.size.width + x;
+        match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => {
+                let [r, g, b] = [0, 1, 2].map(|c| self.rgb_buffer[i * 3 + c] as u32);
+                ((77 * r + 150 * g + 29 * b) >> 8) as u8
+            }
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => self.gray_output[i],
+        }
+    }
+
+    /// Luma histogram of the processed frame with each sample weighted by
+    /// the metering region, sampled as in `frame_histogram`. The region is
+    /// given on the streamed frame, so samples are weighted where they end
+    /// up after the orientation and zoom; zoomed-out samples don't count.
+    fn metered_histogram(&self) -> Box<[u32; 256]> {
+        let metering = &self.applied.metering;
+        let orientation = self.applied.orientation;
+        let crop = self.applied.zoom.crop(self.size.oriented(&orientation));
+        let FrameSize { width, height } = self.size;
+        let mut histogram = Box::new([0u32; 256]);
+        for y in (0..height).step_by(HISTOGRAM_STEP) {
+            for x in (0..width).step_by(HISTOGRAM_STEP) {
+                let (ox, oy) = orientation.map_point(self.sensor_flips, (x, y), (width, height));
+                let (Some(cx), Some(cy)) = (ox.checked_sub(crop.x), oy.checked_sub(crop.y)) else { continue };
+                if cx >= crop.width || cy >= crop.height {
+                    continue;
+                }
+                let (fx, fy) = ((cx as f32 + 0.5) / crop.width as f32, (cy as f32 + 0.5) / crop.height as f32);
+                let weight = (metering.weight(fx, fy) * METERING_WEIGHT).round() as u32;
+                histogram[self.sample_luma(x, y) as usize] += weight;
+            }
+        }
+        histogram
+    }
+
+    /// Histogram of the most recently processed frame
+    pub fn histogram(&self) -> Option<Arc<Histogram>> {
+        self.histogram.clone()
+    }
+
+    /// Meter the last processed frame and move the sensor exposure and
+    /// gain towards the auto-exposure target
+    fn run_auto_exposure(&mut self) {
+        if self.ae.is_none() || self.test_pattern_active || !self.sensor {
+            return;
+        }
+        let Some(histogram) = self.histogram.clone() else { return };
+        let gamma = self.applied.mode.output_gamma(self.applied.gamma);
+        let subdev = self.config.sensor_subdev.clone();
+        let width = self.size.width;
+        let Some(ae) = self.ae.as_mut() else { return };
+        let luma = self.metered_luma.as_deref().unwrap_or(&histogram.luma);
+        let Some(factor) = ae.correction(luma, gamma) else { return };
+
+        let sensor = match ae.sensor {
+            Some(sensor) => sensor,
+            None => match read_sensor_exposure(&subdev, width) {
+                Ok(sensor) => {
+                    if ae.anti_flicker != AntiFlicker::Off && sensor.line_s.is_none() {
+                        tracing::warn!(
+                            "Auto-exposure: the sensor reports no line time (horizontal_blanking, pixel_rate), \
+                             anti-flicker has no effect"
+                        );
+                    }
+                    *ae.sensor.insert(sensor)
+                }
+                Err(e) => {
+                    tracing::warn!("Auto-exposure: {:#}", e);
+                    ae.settle = AE_RETRY_FRAMES;
+                    return;
+                }
+            },
+        };
+        let Some((lines, gain)) = ae.next_exposure(sensor, factor) else {
+            // Already at the exposure or gain limit in that direction
+            ae.converged = true;
+            return;
+        };
+        let result = crate::controls::set_control(&subdev, "exposure", lines)
+            .and_then(|()| crate::controls::set_control(&subdev, "analogue_gain", gain));
+        match result {
+            Ok(()) => {
+                tracing::debug!(
+                    "Auto-exposure: mean {:.0}, exposure {} -> {}, gain {} -> {}",
+                    ae.mean.unwrap_or_default(), sensor.lines, lines, sensor.gain, gain
+                );
+                ae.sensor = Some(SensorExposure { lines, gain, ..sensor });
+                ae.settle = SETTLE_FRAMES;
+            }
+            Err(e) => {
+                tracing::warn!("Auto-exposure: {:#}", e);
+                // Read back what the sensor has on the next attempt
+                ae.sensor = None;
+                ae.settle = AE_RETRY_FRAMES;
+            }
+        }
+    }
+
+    /// Run the processing pipeline on a raw frame and encode it
+    pub fn process_raw_frame(&mut self, raw_data: &[u8]) -> Result<Vec<u8>> {
+        let image = self.process_raw_image(raw_data)?;
+        self.encode_jpeg(image)
+    }
+
+    /// Run the processing pipeline on a raw frame, without encoding. A
+    /// single frame in `grayscale-hdr` mode is processed as plain grayscale.
+    pub fn process_raw_image(&mut self, raw_data: &[u8]) -> Result<DynamicImage> {
+        self.begin_frame();
+        self.run_pipeline(raw_data)
+    }
+
+    /// Fuse an exposure pair and encode the result. If the mode changed
+    /// while the pair was captured, the long frame (taken at the normal
+    /// exposure) is processed in the new mode and the short one dropped.
+    pub fn process_hdr_pair(&mut self, pair: &HdrPair) -> Result<Vec<u8>> {
+        self.begin_frame();
+        let image = match self.applied.mode {
+            CaptureMode::GrayscaleHdr => {
+                let start = Instant::now();
+                self.fuse_grayscale(pair);
+                self.suppress_row_noise();
+                self.stages.extract = elapsed_ms(start);
+                self.spatial_denoise();
+                self.reduce_noise();
+                let start = Instant::now();
+                self.upscale_grayscale();
+                self.stages.upscale = elapsed_ms(start);
+                self.sharpen();
+                self.update_histogram();
+                self.enhance_contrast();
+                self.adjust_tone();
+                self.output_image()?
+            }
+            CaptureMode::ColorHdr => {
+                if self.gray_native_wanted {
+                    let start = Instant::now();
+                    self.extract_grayscale(&pair.long);
+                    self.stages.extract = elapsed_ms(start);
+                }
+                let start = Instant::now();
+                self.fuse_bayer(pair);
+                self.apply_green_balance();
+                self.stages.levels = elapsed_ms(start);
+                self.develop_color();
+                self.output_image()?
+            }
+            CaptureMode::Grayscale | CaptureMode::Color => self.run_pipeline(&pair.long)?,
+        };
+        self.encode_jpeg(image)
+    }
+
+    /// Process one raw frame with the applied settings
+    fn run_pipeline(&mut self, raw_data: &[u8]) -> Result<DynamicImage> {
+        match self.applied.mode {
+            CaptureMode::Color | CaptureMode::ColorHdr => {
+                if self.gray_native_wanted {
+                    let start = Instant::now();
+                    self.extract_grayscale(raw_data);
+                    self.stages.extract = elapsed_ms(start);
+                }
+                let start = Instant::now();
+                self.unpack_bayer10(raw_data);
+                self.stages.unpack = elapsed_ms(start);
+                let start = Instant::now();
+                self.apply_levels();
+                self.apply_green_balance();
+                self.stages.levels = elapsed_ms(start);
+                self.develop_color();
+            }
+            CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => {
+                let start = Instant::now();
+                self.extract_grayscale(raw_data);
+                self.suppress_row_noise();
+                self.stages.extract = elapsed_ms(start);
+                self.spatial_denoise();
+                self.reduce_noise();
+                let start = Instant::now();
                 self.upscale_grayscale();
+                self.stages.upscale = elapsed_ms(start);
+                self.sharpen();
+                self.update_histogram();
+                self.enhance_contrast();
+                self.adjust_tone();
             }
         }
         
-        self.encode_jpeg()
+        self.output_image()
+    }
+
+    /// The color stages from the leveled Bayer buffer on: demosaic to the
+    /// finished RGB frame
+    fn develop_color(&mut self) {
+        let start = Instant::now();
+        self.demosaic_bayer();
+        self.stages.demosaic = elapsed_ms(start);
+        self.spatial_denoise();
+        let start = Instant::now();
+        self.apply_white_balance();
+        self.stages.white_balance = elapsed_ms(start);
+        if let Some(ref ccm) = self.ccm {
+            let start = Instant::now();
+            let (rgb, width) = (&mut self.rgb_buffer, self.size.width);
+            self.pool.install(|| ccm.apply(rgb, width));
+            self.stages.color_matrix = elapsed_ms(start);
+        }
+        if !self.gamma_in_demosaic() {
+            let start = Instant::now();
+            self.apply_gamma();
+            self.stages.gamma = elapsed_ms(start);
+        }
+        self.reduce_noise();
+        self.sharpen();
+        self.update_histogram();
+        self.adjust_tone();
+    }
+
+    /// Encoder used for streamed frames (None before the first one)
+    pub fn encoder_name(&self) -> Option<&'static str> {
+        self.encoder.as_ref().map(|e| e.name())
+    }
+
+    /// Scaler used for the grayscale upscale
+    pub fn scaler_name(&self) -> &'static str {
+        self.scaler.name()
     }
 
-    #[allow(dead_code)]
     pub fn config(&self) -> &CaptureConfig {
         &self.config
     }
 }
 
+/// Current exposure and analogue gain of the sensor, with their limits,
+/// and the line time at frame width `width`
+fn read_sensor_exposure(subdev: &str, width: usize) -> Result<SensorExposure> {
+    let controls = crate::controls::list_controls(subdev)?;
+    let find = |name: &str| {
+        controls
+            .iter()
+            .find(|c| c.name == name)
+            .with_context(|| format!("Control '{}' not available on {}", name, subdev))
+    };
+    let (exposure, gain) = (find("exposure")?, find("analogue_gain")?);
+    Ok(SensorExposure {
+        lines: exposure.value.context("Exposure control has no value")?,
+        min_lines: exposure.min.unwrap_or(1).max(1),
+        max_lines: exposure.max.context("Exposure control has no maximum")?,
+        gain: gain.value.context("Analogue gain control has no value")?,
+        max_gain: gain.max.context("Analogue gain control has no maximum")?,
+        line_s: crate::exif::line_time_s(&controls, width),
+    })
+}
+
+/// Color correction tables for the setting (None while it is off)
+fn build_ccm(correction: &ColorCorrection) -> Option<CcmTables> {
+    correction.enabled.then(|| CcmTables::new(&correction.matrix))
+}
+
+/// Build the gamma LUT (10-bit linear to 8-bit with gamma)
+fn build_gamma_lut(gamma: f32) -> [u8; 1024] {
+    let mut gamma_lut = [0u8; 1024];
+    let inv_gamma = 1.0 / gamma;
+    for (i, v) in gamma_lut.iter_mut().enumerate() {
+        *v = ((i as f32 / 1023.0).powf(inv_gamma) * 255.0) as u8;
+    }
+    gamma_lut
+}
+
+/// Build LUTs that subtract the black level and stretch the remaining range
+/// back to full scale, for 10-bit Bayer data and 8-bit grayscale data
+fn build_black_level_luts(black_level: u16) -> (Vec<u16>, [u8; 256]) {
+    let black10 = black_level.min(1022) as u32;
+    let lut10 = (0..1024u32)
+        .map(|v| (v.saturating_sub(black10) * 1023 / (1023 - black10)) as u16)
+        .collect();
+    
+    // 1020-1022 would leave no 8-bit range above black
+    let black8 = (black10 >> 2).min(254);
+    let mut lut8 = [0u8; 256];
+    for (v, out) in lut8.iter_mut().enumerate() {
+        *out = ((v as u32).saturating_sub(black8) * 255 / (255 - black8)) as u8;
+    }
+    
+    (lut10, lut8)
+}
+
+/// Contrast-limited equalization of a 256-bin histogram
+///
+/// Bins are clipped at `TONEMAP_CLIP_LIMIT` times the mean and the excess is
+/// spread evenly. Each bin maps to the midpoint of its CDF step (0-256, at
+/// the bin centre), so a uniform histogram gives the identity and a
+/// single-spike histogram at mid-gray stays at mid-gray.
+fn equalization_curve(histogram: &[u32; 256]) -> [f32; 256] {
+    let total: u32 = histogram.iter().sum();
+    let mut curve = [0f32; 256];
+    if total == 0 {
+        for (i, c) in curve.iter_mut().enumerate() {
+            *c = i as f32 + 0.5;
+        }
+        return curve;
+    }
+    
+    let limit = TONEMAP_CLIP_LIMIT * total as f32 / 256.0;
+    let excess: f32 = histogram.iter().map(|&n| (n as f32 - limit).max(0.0)).sum();
+    let spread = excess / 256.0;
+    
+    let mut cumulative = 0.0;
+    for (c, &n) in curve.iter_mut().zip(histogram) {
+        let count = (n as f32).min(limit) + spread;
+        *c = (cumulative + count / 2.0) / total as f32 * 256.0;
+        cumulative += count;
+    }
+    curve
+}
+
+/// Evaluate a 256-bin curve at a fractional 8-bit position (bin centres at i + 0.5)
+fn sample_curve(curve: &[f32; 256], x: f32) -> f32 {
+    let x = (x - 0.5).clamp(0.0, 255.0);
+    let i = (x as usize).min(254);
+    let frac = x - i as f32;
+    curve[i] + (curve[i + 1] - curve[i]) * frac
+}
+
+/// Subtract each row's deviation from the median of its neighbouring rows
+///
+/// Real image content varies smoothly across a few rows, while readout
+/// offset noise is independent per row, so the difference between a row's
+/// mean and the local median of row means estimates the offset. The window
+/// is always 2R+1 rows, clamped at the frame edges, so edge rows of a
+/// gradient are compared against themselves rather than a one-sided median.
+fn correct_row_offsets(buf: &mut [u8], width: usize, height: usize, strength: f32, row_means: &mut Vec<f32>) {
+    row_means.clear();
+    row_means.extend(buf.chunks_exact(width).take(height).map(|row| {
+        row.iter().map(|&v| v as u32).sum::<u32>() as f32 / width as f32
+    }));
+    
+    let mut window = [0f32; 2 * ROW_NOISE_RADIUS + 1];
+    for y in 0..height {
+        for (i, slot) in window.iter_mut().enumerate() {
+            let row = (y + i).saturating_sub(ROW_NOISE_RADIUS).min(height - 1);
+            *slot = row_means[row];
+        }
+        window.sort_unstable_by(|a, b| a.total_cmp(b));
+        let median = window[ROW_NOISE_RADIUS];
+        
+        let offset = ((row_means[y] - median) * strength).round() as i32;
+        if offset == 0 {
+            continue;
+        }
+        for v in &mut buf[y * width..(y + 1) * width] {
+            *v = (*v as i32 - offset).clamp(0, 255) as u8;
+        }
+    }
+}
+
+/// Encode an image as JPEG at `quality`, downscaled to `max_width` if
+/// wider (by `scaler`, the CPU if the RGA fails)
+pub fn scaled_jpeg(image: &DynamicImage, max_width: Option<u32>, quality: u8, scaler: ScalerKind) -> Result<Vec<u8>> {
+    match max_width {
+        Some(max_width) if image.width() > max_width => {
+            let height = (image.height() as u64 * max_width as u64 / image.width() as u64).max(1) as u32;
+            let scaled = match scaler {
+                ScalerKind::Rga => rga_scale_image(image, max_width, height).unwrap_or_else(|e| {
+                    if !RGA_FALLBACK_WARNED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                        tracing::warn!("RGA scaling failed ({:#}), scaling streams on the CPU", e);
+                    }
+                    None
+                }),
+                ScalerKind::Software => None,
+            };
+            let scaled = scaled
+                .unwrap_or_else(|| image.resize_exact(max_width, height, image::imageops::FilterType::Triangle));
+            encode_image_jpeg(&scaled, quality)
+        }
+        _ => encode_image_jpeg(image, quality),
+    }
+}
+
+/// `scaled_jpeg` has reported an RGA failure
+static RGA_FALLBACK_WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// `image` scaled on the RGA; None for pixel layouts it isn't given
+fn rga_scale_image(image: &DynamicImage, width: u32, height: u32) -> Result<Option<DynamicImage>> {
+    let src_size = (image.width() as usize, image.height() as usize);
+    let dst_size = (width as usize, height as usize);
+    Ok(Some(match image {
+        DynamicImage::ImageLuma8(gray) => {
+            let mut scaled = GrayImage::new(width, height);
+            rga_resize(gray.as_raw(), src_size, &mut scaled, dst_size, 1)?;
+            DynamicImage::ImageLuma8(scaled)
+        }
+        DynamicImage::ImageRgb8(rgb) => {
+            let mut scaled = RgbImage::new(width, height);
+            rga_resize(rgb.as_raw(), src_size, &mut scaled, dst_size, 3)?;
+            DynamicImage::ImageRgb8(scaled)
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/// Encode an image as JPEG
+pub fn encode_image_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    JpegEncoder::new_with_quality(&mut output, quality.clamp(1, 100))
+        .encode_image(image)
+        .context("Failed to encode JPEG")?;
+    Ok(output)
+}
+
+/// Crop a rectangle from an image at native resolution and encode it as JPEG
+pub fn crop_jpeg(image: &DynamicImage, x: u32, y: u32, width: u32, height: u32, quality: u8) -> Result<Vec<u8>> {
+    encode_image_jpeg(&image.crop_imm(x, y, width, height), quality)
+}
+
 impl Drop for FrameCapture {
     fn drop(&mut self) {
-        let _ = fs::remove_dir_all(&self.config.temp_dir);
-        tracing::info!("Capture stopped");
+        if self.source.get_mut().take().is_some() {
+            tracing::info!("Capture stopped");
+        }
+    }
+}
+
+/// The video device's format as `v4l2-ctl --get-fmt-video` reports it
+/// (single- or multi-planar; raw Bayer has one plane)
+#[derive(Debug, Clone, PartialEq)]
+struct DeviceFormat {
+    format: RawFormat,
+    size: FrameSize,
+    stride: usize,
+    /// Bytes per buffer (`sizeimage`)
+    frame_bytes: usize,
+}
+
+impl DeviceFormat {
+    fn parse(output: &str) -> Result<Self> {
+        let field = |key: &str| v4l2_field(output, key).with_context(|| format!("No {} reported", key));
+        let number = |key: &str| -> Result<usize> {
+            let value = field(key)?;
+            value.parse().with_context(|| format!("Invalid {} '{}'", key, value))
+        };
+        let pixel_format = field("Pixel Format")?;
+        let format = RawFormat::from_v4l2(pixel_format)
+            .with_context(|| format!("Unsupported pixel format {}", pixel_format))?;
+        let size = FrameSize::from_v4l2(field("Width/Height")?).context("Invalid Width/Height")?;
+        let stride = number("Bytes per Line")?;
+        let frame_bytes = number("Size Image")?;
+        if frame_bytes < stride * size.height {
+            anyhow::bail!(
+                "Size Image of {} bytes is less than {} lines of {} bytes",
+                frame_bytes,
+                size.height,
+                stride
+            );
+        }
+        Ok(Self { format, size, stride, frame_bytes })
+    }
+}
+
+/// Value of a `Key : value` line in v4l2-ctl output
+pub fn v4l2_field<'a>(output: &'a str, key: &str) -> Option<&'a str> {
+    output
+        .lines()
+        .find(|line| line.trim_start().starts_with(key))
+        .and_then(|line| line.split_once(':'))
+        .map(|(_, value)| value.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metering::{MeteringConfig, Roi};
+    use crate::zoom::Zoom;
+
+    #[test]
+    fn black_level_luts_map_black_to_zero_and_keep_white() {
+        for black in [0u16, 1, 64, 200, 1022] {
+            let (lut10, lut8) = build_black_level_luts(black);
+            assert_eq!(lut10.len(), 1024);
+            assert_eq!(lut10[black as usize], 0, "black {}", black);
+            assert_eq!(lut10[1023], 1023, "black {}", black);
+            assert!(lut10[..=black as usize].iter().all(|&v| v == 0), "black {}", black);
+            assert!(lut10.windows(2).all(|w| w[0] <= w[1]), "black {}", black);
+            assert_eq!(lut8[(black >> 2).min(254) as usize], 0, "black {}", black);
+            assert_eq!(lut8[255], 255, "black {}", black);
+            assert!(lut8.windows(2).all(|w| w[0] <= w[1]), "black {}", black);
+        }
+    }
+
+    /// Encoded size of a frame at `quality`: 4% more per quality point
+    fn modelled_size(quality: u8) -> usize {
+        (20_000.0 * 1.04f32.powi(quality as i32)) as usize
+    }
+
+    #[test]
+    fn adaptive_quality_converges_on_the_size_target() {
+        let mut controller = AdaptiveQuality::new(Some(modelled_size(70)), None, 30, 95);
+        assert_eq!(controller.quality(), 95);
+        let mut history = Vec::new();
+        for _ in 0..50 {
+            let quality = controller.quality();
+            let next = controller.update(modelled_size(quality), 5.0);
+            assert!(quality.abs_diff(next) <= AdaptiveQuality::MAX_STEP as u8, "{} -> {}", quality, next);
+            history.push(next);
+        }
+        let settled = &history[history.len() - 10..];
+        assert!(settled.iter().all(|&q| q == settled[0]), "still moving: {:?}", history);
+        assert!(settled[0].abs_diff(70) <= 2, "settled at {}: {:?}", settled[0], history);
+    }
+
+    #[test]
+    fn adaptive_quality_follows_the_most_violated_target() {
+        // The size allows 80, the encode time (1 ms per point) only 50
+        let mut controller = AdaptiveQuality::new(Some(modelled_size(80)), Some(50.0), 10, 100);
+        for _ in 0..100 {
+            let quality = controller.quality();
+            controller.update(modelled_size(quality), quality as f32);
+        }
+        assert!(controller.quality().abs_diff(50) <= 3, "settled at {}", controller.quality());
+    }
+
+    #[test]
+    fn adaptive_quality_stays_in_its_range() {
+        let mut controller = AdaptiveQuality::new(Some(1000), None, 40, 90);
+        for _ in 0..100 {
+            controller.update(1_000_000, 5.0);
+        }
+        assert_eq!(controller.quality(), 40);
+        for _ in 0..100 {
+            controller.update(1, 5.0);
+        }
+        assert_eq!(controller.quality(), 90);
+    }
+
+    /// A capture with `settings` for `size` frames in `format` (no sensor)
+    fn raw_capture(settings: PipelineSettings, format: &str, size: FrameSize) -> FrameCapture {
+        let mut capture = FrameCapture::with_config(CaptureConfig::default(), Arc::new(SettingsCell::new(settings))).unwrap();
+        let format = RawFormat::from_fourcc(format, "").unwrap();
+        let stride = format.min_stride(size.width);
+        capture.set_raw_format(format, size, stride).unwrap();
+        capture
+    }
+
+    /// A raw frame in the capture's format whose samples are `sample(x, y)`
+    fn raw_frame(capture: &FrameCapture, sample: impl Fn(usize, usize) -> u16) -> Vec<u8> {
+        let FrameSize { width, height } = capture.frame_size();
+        let stride = capture.stride();
+        let mut raw = vec![0u8; stride * height];
+        for (y, line) in raw.chunks_exact_mut(stride).enumerate() {
+            let samples: Vec<u16> = (0..width).map(|x| sample(x, y)).collect();
+            capture.raw_format().pack_line(&samples, line);
+        }
+        raw
+    }
+
+    #[test]
+    fn frames_at_the_black_level_come_out_black() {
+        let size = FrameSize { width: 640, height: 480 };
+        // Saturated patch on whole 4x2 pixel groups
+        let patch = |x: usize, y: usize| (320..480).contains(&x) && (240..360).contains(&y);
+        for black in [64u16, 200, 1016] {
+            let settings = PipelineSettings { mode: CaptureMode::Grayscale, black_level: black, ..Default::default() };
+            let mut capture = raw_capture(settings, "pGAA", size);
+            capture.begin_frame();
+            let raw = raw_frame(&capture, |x, y| if patch(x, y) { 1023 } else { black });
+
+            capture.extract_grayscale(&raw);
+            let (groups_per_row, _) = capture.native_size();
+            for (i, &v) in capture.gray_native.iter().enumerate() {
+                let (x, y) = (i % groups_per_row * 4, i / groups_per_row * 2);
+                assert_eq!(v, if patch(x, y) { 255 } else { 0 }, "black {} at {},{}", black, x, y);
+            }
+
+            capture.unpack_bayer10(&raw);
+            capture.apply_levels();
+            for (i, &v) in capture.bayer10.iter().enumerate() {
+                let (x, y) = (i % size.width, i / size.width);
+                assert_eq!(v, if patch(x, y) { 1023 } else { 0 }, "black {} at {},{}", black, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn hdr_luma_maps_black_to_zero_and_white_to_full_scale() {
+        let size = FrameSize { width: 640, height: 480 };
+        for format in ["pGAA", "GB10"] {
+            for black in [0u16, 200, 1016, 1022] {
+                let settings = PipelineSettings { mode: CaptureMode::GrayscaleHdr, black_level: black, ..Default::default() };
+                let mut capture = raw_capture(settings, format, size);
+                capture.begin_frame();
+                // Black on the left half, saturated on the right
+                let raw = raw_frame(&capture, |x, _| if x < size.width / 2 { black } else { 1023 });
+                let mut luma = Vec::new();
+                capture.extract_luma16(&raw, &mut luma);
+                let (groups_per_row, _) = capture.native_size();
+                for row in luma.chunks_exact(groups_per_row) {
+                    let (dark, bright) = row.split_at(groups_per_row / 2);
+                    assert!(dark.iter().all(|&v| v == 0), "{} black {}: {:?}", format, black, &dark[..4]);
+                    assert!(bright.iter().all(|&v| v == 65535), "{} black {}: {:?}", format, black, &bright[..4]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hdr_pairs_are_fused_at_the_highest_black_level() {
+        // 1020-1022 leave no 8-bit range above black without the cap
+        let size = FrameSize { width: 640, height: 480 };
+        let settings = PipelineSettings { mode: CaptureMode::GrayscaleHdr, black_level: 1022, ..Default::default() };
+        let mut capture = raw_capture(settings, "pGAA", size);
+        let long = raw_frame(&capture, |x, _| if x < size.width / 2 { 1000 } else { 1023 });
+        let short = raw_frame(&capture, |_, _| 1000);
+        let jpeg = capture.process_hdr_pair(&HdrPair { long, short, ratio: 4.0 }).unwrap();
+        let image = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(image.width() as usize, size.width);
+    }
+
+    /// A grayscale capture with auto-exposure on and `metering`, after a
+    /// dark frame with a bright patch in the middle (a lamp in a dark room)
+    fn metered_capture(metering: MeteringConfig, zoom: Zoom) -> FrameCapture {
+        let size = FrameSize { width: 640, height: 480 };
+        let settings = PipelineSettings {
+            mode: CaptureMode::Grayscale,
+            black_level: 0,
+            auto_exposure: Some(AutoExposure::new(AutoExposure::DEFAULT_TARGET, 30.0, 1.0, AntiFlicker::Off)),
+            metering,
+            zoom,
+            ..Default::default()
+        };
+        let mut capture = raw_capture(settings, "GB10", size);
+        // Under the default spot: too little of the frame to count as clipped
+        let lamp = |x: usize, y: usize| (288..352).contains(&x) && (216..264).contains(&y);
+        let raw = raw_frame(&capture, |x, y| if lamp(x, y) { 1000 } else { 30 });
+        capture.process_raw_image(&raw).unwrap();
+        capture
+    }
+
+    fn histogram_mean(histogram: &[u32; 256]) -> f32 {
+        Histogram { luma: *histogram, rgb: None }.mean().unwrap()
+    }
+
+    #[test]
+    fn auto_exposure_meters_the_selected_region() {
+        let spot = MeteringConfig { mode: MeteringMode::Spot, roi: Some(Roi { x: 0.45, y: 0.45, w: 0.1, h: 0.1 }), overlay: false };
+        let mut capture = metered_capture(spot, Zoom::FULL);
+        let full_frame = capture.histogram().unwrap().luma;
+        let metered = *capture.metered_luma.clone().expect("spot metering keeps a metered histogram");
+        assert!(histogram_mean(&full_frame) < 60.0, "{}", histogram_mean(&full_frame));
+        assert!(histogram_mean(&metered) > 200.0, "{}", histogram_mean(&metered));
+
+        // The full frame would brighten, the lamp darkens
+        let gamma = capture.applied.mode.output_gamma(capture.applied.gamma);
+        let ae = capture.ae.as_mut().unwrap();
+        assert!(ae.clone().correction(&full_frame, gamma).unwrap() > 1.0);
+        assert!(ae.correction(&metered, gamma).unwrap() < 1.0);
+
+        // Center-weighted falls in between; average meters the whole frame
+        let center = MeteringConfig { mode: MeteringMode::CenterWeighted, ..spot };
+        let centered = histogram_mean(metered_capture(center, Zoom::FULL).metered_luma.as_deref().unwrap());
+        assert!(centered > histogram_mean(&full_frame) && centered < histogram_mean(&metered), "{}", centered);
+        assert!(metered_capture(MeteringConfig::default(), Zoom::FULL).metered_luma.is_none());
+    }
+
+    #[test]
+    fn metering_regions_are_on_the_zoomed_frame() {
+        // Zoomed onto the left half of the sensor frame, the lamp (0.45-0.55
+        // across it) is on the right edge of the streamed frame
+        let zoom = Zoom::from_factor(2.0, 0.25, 0.5).unwrap();
+        let right = MeteringConfig { mode: MeteringMode::Spot, roi: Some(Roi { x: 0.9, y: 0.45, w: 0.1, h: 0.1 }), overlay: false };
+        let capture = metered_capture(right, zoom);
+        assert!(histogram_mean(capture.metered_luma.as_deref().unwrap()) > 200.0);
+        let left = MeteringConfig { roi: Some(Roi { x: 0.0, y: 0.45, w: 0.1, h: 0.1 }), ..right };
+        let capture = metered_capture(left, zoom);
+        assert!(histogram_mean(capture.metered_luma.as_deref().unwrap()) < 60.0);
+    }
+
+    #[test]
+    fn changing_the_metered_region_restarts_auto_exposure() {
+        let spot = MeteringConfig { mode: MeteringMode::Spot, roi: None, overlay: false };
+        let mut capture = metered_capture(spot, Zoom::FULL);
+        let metered = *capture.metered_luma.clone().unwrap();
+        let ae = capture.ae.as_mut().unwrap();
+        ae.correction(&metered, 2.2);
+        ae.settle = 3;
+        assert!(capture.auto_exposure_status().unwrap().mean.is_some());
+
+        // The overlay alone doesn't change what is metered
+        capture.settings.update(|s| s.set_metering(MeteringConfig { overlay: true, ..spot }));
+        capture.begin_frame();
+        assert!(capture.auto_exposure_status().unwrap().mean.is_some());
+
+        let moved = MeteringConfig { roi: Some(Roi { x: 0.0, y: 0.0, w: 0.2, h: 0.2 }), ..spot };
+        capture.settings.update(|s| s.set_metering(moved));
+        capture.begin_frame();
+        let ae = capture.ae.as_ref().unwrap();
+        assert_eq!((ae.mean, ae.settle, ae.converged), (None, 0, false));
+    }
+
+    /// Smooth content (a horizontal gradient, equal row means) and the same
+    /// with ±1 LSB offsets on every third row
+    fn row_noise_image(width: usize, height: usize) -> (Vec<u8>, Vec<u8>) {
+        let clean: Vec<u8> = (0..width * height).map(|i| (20 + (i % width) * 200 / width) as u8).collect();
+        let mut noisy = clean.clone();
+        for (y, row) in noisy.chunks_exact_mut(width).enumerate() {
+            let offset: i32 = match (y % 3, (y / 3) % 2) {
+                (1, 0) => 1,
+                (1, _) => -1,
+                _ => 0,
+            };
+            for v in row {
+                *v = (*v as i32 + offset) as u8;
+            }
+        }
+        (clean, noisy)
+    }
+
+    #[test]
+    fn row_offsets_of_one_lsb_are_removed() {
+        let (width, height) = (64, 48);
+        let (clean, mut noisy) = row_noise_image(width, height);
+        assert_ne!(noisy, clean);
+        correct_row_offsets(&mut noisy, width, height, 1.0, &mut Vec::new());
+        assert_eq!(noisy, clean);
+    }
+
+    #[test]
+    fn row_offsets_leave_vertical_gradients_alone() {
+        let (width, height) = (64, 48);
+        let ramp: Vec<u8> = (0..width * height).map(|i| (20 + (i / width) * 4) as u8).collect();
+        let mut corrected = ramp.clone();
+        correct_row_offsets(&mut corrected, width, height, 1.0, &mut Vec::new());
+        for (y, (row, clean)) in corrected.chunks_exact(width).zip(ramp.chunks_exact(width)).enumerate() {
+            for (&v, &c) in row.iter().zip(clean) {
+                assert!(v.abs_diff(c) <= 1, "row {}: {} instead of {}", y, v, c);
+            }
+        }
+    }
+
+    #[test]
+    fn row_offsets_leave_clean_rows_alone() {
+        let (width, height) = (64, 48);
+        let (clean, _) = row_noise_image(width, height);
+        let mut corrected = clean.clone();
+        correct_row_offsets(&mut corrected, width, height, 1.0, &mut Vec::new());
+        assert_eq!(corrected, clean);
+    }
+
+    #[test]
+    fn row_offsets_scale_with_strength() {
+        let (width, height) = (64, 48);
+        let (_, noisy) = row_noise_image(width, height);
+        let mut corrected = noisy.clone();
+        // Corrections under half a level round to nothing
+        correct_row_offsets(&mut corrected, width, height, 0.3, &mut Vec::new());
+        assert_eq!(corrected, noisy);
+    }
+
+    #[test]
+    fn black_level_luts_are_identity_at_zero() {
+        let (lut10, lut8) = build_black_level_luts(0);
+        assert!(lut10.iter().enumerate().all(|(i, &v)| v as usize == i));
+        assert!(lut8.iter().enumerate().all(|(i, &v)| v as usize == i));
+    }
+
+    #[test]
+    fn black_level_luts_clamp_out_of_range_levels() {
+        // 1023 and above would divide by zero; they act as 1022
+        assert_eq!(build_black_level_luts(1023), build_black_level_luts(1022));
+        assert_eq!(build_black_level_luts(u16::MAX), build_black_level_luts(1022));
+    }
+
+    /// Histogram with `count` pixels in each of `bins`
+    fn histogram_of(bins: impl IntoIterator<Item = usize>, count: u32) -> [u32; 256] {
+        let mut histogram = [0u32; 256];
+        for bin in bins {
+            histogram[bin] += count;
+        }
+        histogram
+    }
+
+    #[test]
+    fn equalization_is_identity_for_a_uniform_histogram() {
+        let curve = equalization_curve(&histogram_of(0..256, 100));
+        for (i, &c) in curve.iter().enumerate() {
+            assert!((c - (i as f32 + 0.5)).abs() < 1e-3, "bin {}: {}", i, c);
+        }
+    }
+
+    #[test]
+    fn equalization_keeps_flat_mid_gray() {
+        // The clip limit spreads most of the spike, so the curve stays
+        // close to the identity rather than jumping at the spike
+        let curve = equalization_curve(&histogram_of([128], 10_000));
+        assert!((curve[128] - 128.5).abs() < 0.5, "mid-gray -> {}", curve[128]);
+        for (i, &c) in curve.iter().enumerate() {
+            assert!((c - (i as f32 + 0.5)).abs() < 2.5, "bin {}: {}", i, c);
+        }
+    }
+
+    #[test]
+    fn equalization_stretches_a_bimodal_histogram() {
+        // Two lobes below the clip limit, nothing between them
+        let curve = equalization_curve(&histogram_of((64..112).chain(160..208), 100));
+        assert!(curve.windows(2).all(|w| w[0] <= w[1]));
+        // Each 48-bin lobe is spread over half the range
+        assert!(curve[64] < 2.0, "{}", curve[64]);
+        assert!(curve[207] > 254.0, "{}", curve[207]);
+        assert!((curve[111] - curve[64] - 47.0 * 128.0 / 48.0).abs() < 0.5);
+        // and the empty gap between them collapses to mid-gray
+        assert!(curve[112..160].iter().all(|&c| (c - 128.0).abs() < 1e-3));
+    }
+
+    /// A capture with full-strength tone mapping (no black level) and a
+    /// 96x64 Bayer frame whose 2x2 cells cycle through the 10-bit codes of
+    /// the 8-bit gamma outputs `levels` (1536 cells, so 96 levels come up
+    /// equally often)
+    fn tone_mapped(levels: &[u8]) -> FrameCapture {
+        let settings = PipelineSettings { tonemap_strength: 1.0, black_level: 0, ..Default::default() };
+        let mut capture = FrameCapture::with_config(CaptureConfig::default(), Arc::new(SettingsCell::new(settings))).unwrap();
+        let (width, height) = (96, 64);
+        capture.size = FrameSize { width, height };
+        let codes: Vec<u16> = levels.iter().map(|&level| code_for(&capture.gamma_lut, level)).collect();
+        capture.bayer10 = (0..width * height)
+            .map(|i| codes[(i / (2 * width) * width / 2 + i % width / 2) % codes.len()])
+            .collect();
+        capture.update_tone_curve();
+        capture
+    }
+
+    /// Lowest 10-bit code the gamma maps to `level`
+    fn code_for(gamma_lut: &[u8; 1024], level: u8) -> u16 {
+        gamma_lut.iter().position(|&v| v == level).unwrap_or_else(|| panic!("{} unreachable", level)) as u16
+    }
+
+    /// 8-bit output of a 10-bit code after the tone LUT and gamma
+    fn tone_output(capture: &FrameCapture, level: u8) -> u8 {
+        capture.gamma_lut[capture.tone_lut10[code_for(&capture.gamma_lut, level) as usize] as usize]
+    }
+
+    #[test]
+    fn tone_curve_leaves_flat_mid_gray_alone() {
+        let capture = tone_mapped(&[128]);
+        assert!(tone_output(&capture, 128).abs_diff(128) <= 1, "{}", tone_output(&capture, 128));
+        for level in (48..=240).step_by(8) {
+            let out = tone_output(&capture, level);
+            assert!(out.abs_diff(level) <= 3, "{} -> {}", level, out);
+        }
+    }
+
+    #[test]
+    fn tone_curve_separates_a_bimodal_scene() {
+        let levels: Vec<u8> = (64..112).chain(160..208).collect();
+        let capture = tone_mapped(&levels);
+        assert!(tone_output(&capture, 64) < 8, "{}", tone_output(&capture, 64));
+        assert!(tone_output(&capture, 207) > 245, "{}", tone_output(&capture, 207));
+        assert!(tone_output(&capture, 136).abs_diff(128) <= 2, "{}", tone_output(&capture, 136));
+        let codes = (0..1024).map(|v| capture.tone_lut10[v]).collect::<Vec<_>>();
+        assert!(codes.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn device_formats_carry_the_buffer_size() {
+        let single = "Format Video Capture:
+	Width/Height      : 3864/2192
+	Pixel Format      : 'RG10' (10-bit Bayer RGRG/GBGB)
+	Field             : None
+	Bytes per Line    : 7744
+	Size Image        : 16990336
+	Colorspace        : Raw
+";
+        let device = DeviceFormat::parse(single).unwrap();
+        assert_eq!(device.size, FrameSize { width: 3864, height: 2192 });
+        assert_eq!(device.format.fourcc, "RG10");
+        assert_eq!(device.stride, 7744);
+        assert_eq!(device.frame_bytes, 16990336);
+        assert!(device.frame_bytes > device.stride * device.size.height);
+
+        let mplane = "Format Video Capture Multiplanar:
+	Width/Height      : 1920/1080
+	Pixel Format      : 'pRAA' (10-bit Bayer RGRG/GBGB Packed)
+	Field             : None
+	Number of planes  : 1
+	Flags             :
+	Colorspace        : Default
+	Plane 0           :
+	   Bytes per Line : 2400
+	   Size Image     : 2592000
+";
+        let device = DeviceFormat::parse(mplane).unwrap();
+        assert_eq!(device.size, FrameSize { width: 1920, height: 1080 });
+        assert_eq!(device.format.fourcc, "pRAA");
+        assert_eq!((device.stride, device.frame_bytes), (2400, 2592000));
+    }
+
+    #[test]
+    fn device_formats_are_not_guessed() {
+        let format = "\tWidth/Height      : 1920/1080\n\tPixel Format      : 'RG10' (10-bit Bayer RGRG/GBGB)\n";
+        let missing = DeviceFormat::parse(&format!("{}\tBytes per Line    : 3840\n", format)).unwrap_err();
+        assert!(format!("{:#}", missing).contains("No Size Image"), "{:#}", missing);
+        let missing = DeviceFormat::parse(&format!("{}\tSize Image        : 4147200\n", format)).unwrap_err();
+        assert!(format!("{:#}", missing).contains("No Bytes per Line"), "{:#}", missing);
+        let short = DeviceFormat::parse(&format!(
+            "{}\tBytes per Line    : 3840\n\tSize Image        : 4140000\n",
+            format
+        ))
+        .unwrap_err();
+        assert!(format!("{:#}", short).contains("less than 1080 lines"), "{:#}", short);
+        assert!(DeviceFormat::parse("").is_err());
     }
 }
@@ -0,0 +1,126 @@
+//! Color correction matrix
+//!
+//! The sensor's color filters overlap far more than sRGB primaries do, so
+//! white-balanced camera RGB taken as sRGB looks washed out. The color
+//! pipeline multiplies every pixel (linear, after white balance, before
+//! gamma) by a 3x3 matrix that maps camera RGB to linear sRGB. Rows that
+//! sum to 1 leave neutrals neutral, so the matrix only moves saturation and
+//! hue and white balance stays in charge of the white point.
+//!
+//! The default is a moderate daylight matrix typical of Sony sensors of
+//! the IMX415's generation, not a calibration; a matrix measured against a
+//! color chart with the optics in use can replace it
+//! (`capture.color_matrix`, `/control/ccm`).
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Largest coefficient magnitude accepted; real matrices stay well below
+pub const MAX_COEFFICIENT: f32 = 8.0;
+
+/// Fraction bits of the fixed-point tables
+const FRACTION_BITS: u32 = 12;
+
+/// A 3x3 matrix, rows R, G, B of the output
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct ColorMatrix(pub [[f32; 3]; 3]);
+
+impl ColorMatrix {
+    pub const IDENTITY: ColorMatrix = ColorMatrix([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+
+    /// Default for the IMX415 in daylight
+    pub const IMX415: ColorMatrix = ColorMatrix([[1.62, -0.45, -0.17], [-0.28, 1.52, -0.24], [-0.05, -0.52, 1.57]]);
+
+    /// Error message if a coefficient isn't usable
+    pub fn validate(&self) -> Result<(), String> {
+        if self.0.iter().flatten().any(|c| !c.is_finite() || c.abs() > MAX_COEFFICIENT) {
+            return Err(format!("Coefficients must be finite and within ±{}", MAX_COEFFICIENT));
+        }
+        if self.inverse().is_none() {
+            return Err("The matrix is singular".to_string());
+        }
+        Ok(())
+    }
+
+    /// The inverse, None for a singular matrix
+    pub fn inverse(&self) -> Option<ColorMatrix> {
+        let m = self.0.map(|row| row.map(|c| c as f64));
+        let cofactor = |r: usize, c: usize| {
+            let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+            let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+            m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+        };
+        let det: f64 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+        if det.abs() < 1e-9 {
+            return None;
+        }
+        // Inverse = adjugate / det, the adjugate being the transposed cofactors
+        Some(ColorMatrix(std::array::from_fn(|r| std::array::from_fn(|c| (cofactor(c, r) / det) as f32))))
+    }
+
+    /// `self * other`
+    pub fn multiply(&self, other: &ColorMatrix) -> ColorMatrix {
+        ColorMatrix(std::array::from_fn(|r| {
+            std::array::from_fn(|c| (0..3).map(|k| self.0[r][k] * other.0[k][c]).sum())
+        }))
+    }
+}
+
+/// Color correction setting (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct ColorCorrection {
+    pub enabled: bool,
+    pub matrix: ColorMatrix,
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        Self { enabled: true, matrix: ColorMatrix::IMX415 }
+    }
+}
+
+impl ColorCorrection {
+    /// The matrix applied, identity when correction is off
+    pub fn effective(&self) -> ColorMatrix {
+        if self.enabled {
+            self.matrix
+        } else {
+            ColorMatrix::IDENTITY
+        }
+    }
+}
+
+/// A matrix as per-coefficient lookup tables over 8-bit input, in fixed
+/// point, so a pixel costs nine lookups and six additions
+pub struct CcmTables {
+    tables: Box<[[i32; 256]; 9]>,
+}
+
+impl CcmTables {
+    pub fn new(matrix: &ColorMatrix) -> Self {
+        let mut tables = Box::new([[0i32; 256]; 9]);
+        for (i, table) in tables.iter_mut().enumerate() {
+            let coefficient = matrix.0[i / 3][i % 3] * (1 << FRACTION_BITS) as f32;
+            for (v, entry) in table.iter_mut().enumerate() {
+                *entry = (coefficient * v as f32).round() as i32;
+            }
+        }
+        Self { tables }
+    }
+
+    /// Correct interleaved RGB rows of `width` pixels in place
+    pub fn apply(&self, rgb: &mut [u8], width: usize) {
+        let t = &self.tables;
+        rgb.par_chunks_exact_mut(width * 3).for_each(|row| {
+            for pixel in row.chunks_exact_mut(3) {
+                let [r, g, b] = [pixel[0] as usize, pixel[1] as usize, pixel[2] as usize];
+                for (channel, out) in pixel.iter_mut().enumerate() {
+                    let sum = t[channel * 3][r] + t[channel * 3 + 1][g] + t[channel * 3 + 2][b];
+                    *out = ((sum + (1 << (FRACTION_BITS - 1))) >> FRACTION_BITS).clamp(0, 255) as u8;
+                }
+            }
+        });
+    }
+}
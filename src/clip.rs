@@ -0,0 +1,184 @@
+//! Short animated clips (GIF / WebP) from the history ring
+//!
+//! Frames are picked by capture timestamp so the clip spans the requested
+//! wall-clock duration whatever the capture rate was; a frame that covers
+//! several output ticks is emitted once with a longer delay.
+
+use crate::history::HistoryFrame;
+use anyhow::{Context, Result};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::webp::WebPEncoder;
+use image::{Delay, DynamicImage, ExtendedColorType, Frame, RgbImage};
+use serde::Deserialize;
+use std::io::Write;
+use utoipa::IntoParams;
+
+/// Longest clip served
+pub const MAX_CLIP_SECONDS: f32 = 10.0;
+/// Highest clip frame rate
+pub const MAX_CLIP_FPS: u32 = 10;
+/// Widest clip served (pixels)
+pub const MAX_CLIP_WIDTH: u32 = 640;
+
+/// GIF quantizer speed (1 = best quality, 30 = fastest)
+const GIF_SPEED: i32 = 10;
+
+/// `?seconds=&fps=&width=`, clamped to the caps above
+#[derive(Debug, Clone, Copy, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ClipParams {
+    /// Length of the clip, ending at the latest frame
+    #[serde(default = "ClipParams::default_seconds")]
+    pub seconds: f32,
+    #[serde(default = "ClipParams::default_fps")]
+    pub fps: u32,
+    /// Output width in pixels
+    #[serde(default = "ClipParams::default_width")]
+    pub width: u32,
+}
+
+impl ClipParams {
+    fn default_seconds() -> f32 { 3.0 }
+    fn default_fps() -> u32 { 5 }
+    fn default_width() -> u32 { 480 }
+
+    pub fn clamped(self) -> Self {
+        Self {
+            seconds: if self.seconds.is_finite() { self.seconds.clamp(0.1, MAX_CLIP_SECONDS) } else { 3.0 },
+            fps: self.fps.clamp(1, MAX_CLIP_FPS),
+            width: self.width.clamp(16, MAX_CLIP_WIDTH),
+        }
+    }
+}
+
+/// A history frame and how long it is shown in the clip
+pub struct ClipFrame {
+    pub frame: HistoryFrame,
+    pub duration_ms: u32,
+}
+
+/// Pick the frame shown at each output tick over the last `seconds`
+/// (ending at the newest frame), merging consecutive repeats
+pub fn select_frames(frames: &[HistoryFrame], seconds: f32, fps: u32) -> Vec<ClipFrame> {
+    let Some(newest) = frames.last() else {
+        return Vec::new();
+    };
+    let tick_ms = 1000 / fps.max(1);
+    let ticks = ((seconds * fps as f32).round() as u64).max(1);
+    let start = newest.timestamp_ms.saturating_sub((ticks - 1) * tick_ms as u64);
+
+    let mut clip: Vec<ClipFrame> = Vec::new();
+    for tick in 0..ticks {
+        let at = start + tick * tick_ms as u64;
+        // Latest frame captured at or before this tick; ticks before the
+        // oldest retained frame are skipped
+        let index = frames.partition_point(|f| f.timestamp_ms <= at);
+        let Some(frame) = index.checked_sub(1).map(|i| &frames[i]) else {
+            continue;
+        };
+        match clip.last_mut() {
+            Some(last) if last.frame.seq == frame.seq => last.duration_ms += tick_ms,
+            _ => clip.push(ClipFrame { frame: frame.clone(), duration_ms: tick_ms }),
+        }
+    }
+    clip
+}
+
+/// Decode and downscale one clip frame
+fn scaled_frame(frame: &HistoryFrame, width: u32) -> Result<RgbImage> {
+    let image = image::load_from_memory(&frame.jpeg)
+        .with_context(|| format!("Failed to decode frame {}", frame.seq))?;
+    let height = (image.height() as u64 * width as u64 / image.width().max(1) as u64).max(1) as u32;
+    Ok(image.thumbnail(width, height).to_rgb8())
+}
+
+/// Encode an infinitely looping GIF, frame by frame, into `writer`
+pub fn encode_gif<W: Write>(clip: &[ClipFrame], width: u32, writer: W) -> Result<()> {
+    let mut encoder = GifEncoder::new_with_speed(writer, GIF_SPEED);
+    encoder.set_repeat(Repeat::Infinite)?;
+    for item in clip {
+        let rgba = DynamicImage::ImageRgb8(scaled_frame(&item.frame, width)?).to_rgba8();
+        let delay = Delay::from_numer_denom_ms(item.duration_ms, 1);
+        encoder.encode_frame(Frame::from_parts(rgba, 0, 0, delay))?;
+    }
+    Ok(())
+}
+
+fn push_u24(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// The image data chunks (ALPH / VP8 / VP8L) of a still WebP
+fn still_webp_chunks(image: &RgbImage) -> Result<Vec<u8>> {
+    let mut still = Vec::new();
+    WebPEncoder::new_lossless(&mut still).encode(
+        image.as_raw(),
+        image.width(),
+        image.height(),
+        ExtendedColorType::Rgb8,
+    )?;
+    anyhow::ensure!(still.len() > 12 && &still[8..12] == b"WEBP", "Unexpected WebP encoder output");
+
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= still.len() {
+        let fourcc = &still[pos..pos + 4];
+        let size = u32::from_le_bytes(still[pos + 4..pos + 8].try_into()?) as usize;
+        let end = (pos + 8 + size + size % 2).min(still.len());
+        if matches!(fourcc, b"ALPH" | b"VP8 " | b"VP8L") {
+            chunks.extend_from_slice(&still[pos..end]);
+        }
+        pos = end;
+    }
+    Ok(chunks)
+}
+
+/// Encode a looping animated WebP (lossless frames)
+///
+/// The image crate only writes still WebP images, so each frame is encoded
+/// on its own and its VP8L chunk is wrapped in an ANMF frame chunk.
+pub fn encode_webp(clip: &[ClipFrame], width: u32) -> Result<Vec<u8>> {
+    let mut frames = Vec::new();
+    let (mut canvas_width, mut canvas_height) = (1, 1);
+    for item in clip {
+        let image = scaled_frame(&item.frame, width)?;
+        canvas_width = canvas_width.max(image.width());
+        canvas_height = canvas_height.max(image.height());
+
+        let mut anmf = Vec::new();
+        push_u24(&mut anmf, 0); // X offset / 2
+        push_u24(&mut anmf, 0); // Y offset / 2
+        push_u24(&mut anmf, image.width() - 1);
+        push_u24(&mut anmf, image.height() - 1);
+        push_u24(&mut anmf, item.duration_ms.min(0xFF_FFFF));
+        anmf.push(0b10); // no blending, no disposal
+        anmf.extend_from_slice(&still_webp_chunks(&image)?);
+        push_chunk(&mut frames, b"ANMF", &anmf);
+    }
+
+    let mut vp8x = vec![0b10, 0, 0, 0]; // animation flag
+    push_u24(&mut vp8x, canvas_width - 1);
+    push_u24(&mut vp8x, canvas_height - 1);
+    let mut anim = vec![0, 0, 0, 0xFF]; // background (BGRA)
+    anim.extend_from_slice(&0u16.to_le_bytes()); // loop forever
+
+    let mut body = b"WEBP".to_vec();
+    push_chunk(&mut body, b"VP8X", &vp8x);
+    push_chunk(&mut body, b"ANIM", &anim);
+    body.extend_from_slice(&frames);
+
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
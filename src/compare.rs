@@ -0,0 +1,154 @@
+//! A/B frame comparison against a stored reference frame
+//!
+//! Used when tuning the processing pipeline: store the current output as
+//! a reference, change settings, then compare side by side, as a
+//! difference heatmap, or numerically.
+
+use crate::capture::CaptureMode;
+use image::{imageops, DynamicImage, GenericImageView, RgbImage};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Default width of rendered comparison images
+pub const DEFAULT_COMPARE_WIDTH: u32 = 1920;
+
+/// Heatmap gain: a difference of 1/GAIN of full scale saturates
+const HEATMAP_GAIN: u32 = 4;
+
+/// A stored full-resolution reference frame.
+///
+/// Kept until replaced or cleared; it is not part of the frame history
+/// and does not count against its memory budget.
+pub struct Reference {
+    pub image: Arc<DynamicImage>,
+    pub mode: CaptureMode,
+    pub seq: u64,
+    pub timestamp_ms: u64,
+}
+
+impl Reference {
+    /// Check that `current` (captured in `mode`) can be compared with this reference
+    pub fn check(&self, mode: CaptureMode, current: &DynamicImage) -> Result<(), String> {
+        if self.mode != mode {
+            return Err(format!(
+                "Reference was captured in {:?} mode but the camera is in {:?} mode",
+                self.mode, mode
+            ));
+        }
+        check_compatible(&self.image, current)
+    }
+}
+
+/// Per-channel difference statistics
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelStats {
+    pub channel: &'static str,
+    /// Mean absolute difference (0-255)
+    pub mean_abs_diff: f64,
+    /// Peak signal-to-noise ratio in dB (None if the channels are identical)
+    pub psnr_db: Option<f64>,
+}
+
+/// Check that two frames can be compared pixel by pixel
+fn check_compatible(reference: &DynamicImage, current: &DynamicImage) -> Result<(), String> {
+    if reference.color() != current.color() {
+        return Err(format!(
+            "Reference is {:?} but the current frame is {:?}; switch modes or set a new reference",
+            reference.color(),
+            current.color()
+        ));
+    }
+    if reference.dimensions() != current.dimensions() {
+        return Err(format!(
+            "Reference is {:?} but the current frame is {:?}",
+            reference.dimensions(),
+            current.dimensions()
+        ));
+    }
+    Ok(())
+}
+
+fn channel_names(image: &DynamicImage) -> &'static [&'static str] {
+    match image.color().channel_count() {
+        1 => &["luma"],
+        _ => &["r", "g", "b"],
+    }
+}
+
+/// Mean absolute difference and PSNR for each channel
+pub fn channel_stats(reference: &DynamicImage, current: &DynamicImage) -> Vec<ChannelStats> {
+    let names = channel_names(reference);
+    let channels = names.len();
+    let mut abs_sum = vec![0u64; channels];
+    let mut sq_sum = vec![0u64; channels];
+    for (i, (&a, &b)) in reference.as_bytes().iter().zip(current.as_bytes()).enumerate() {
+        let d = (a as i32 - b as i32).unsigned_abs() as u64;
+        abs_sum[i % channels] += d;
+        sq_sum[i % channels] += d * d;
+    }
+
+    let samples = (reference.as_bytes().len() / channels).max(1) as f64;
+    names
+        .iter()
+        .enumerate()
+        .map(|(c, &channel)| {
+            let mse = sq_sum[c] as f64 / samples;
+            ChannelStats {
+                channel,
+                mean_abs_diff: abs_sum[c] as f64 / samples,
+                psnr_db: (mse > 0.0).then(|| 10.0 * (255.0 * 255.0 / mse).log10()),
+            }
+        })
+        .collect()
+}
+
+/// Reference (left) and current (right), each scaled to half of `width`
+pub fn side_by_side(reference: &DynamicImage, current: &DynamicImage, width: u32) -> DynamicImage {
+    let half = (width / 2).max(1);
+    let (w, h) = reference.dimensions();
+    let half_height = (h as u64 * half as u64 / w.max(1) as u64).max(1) as u32;
+    let left = reference.thumbnail_exact(half, half_height).to_rgb8();
+    let right = current.thumbnail_exact(half, half_height).to_rgb8();
+
+    let mut canvas = RgbImage::new(half * 2, half_height);
+    imageops::replace(&mut canvas, &left, 0, 0);
+    imageops::replace(&mut canvas, &right, half as i64, 0);
+    DynamicImage::ImageRgb8(canvas)
+}
+
+/// "Hot" colour map: black -> red -> yellow -> white
+fn heat_color(value: u8) -> [u8; 3] {
+    let v = value as u32 * 3;
+    [
+        v.min(255) as u8,
+        v.saturating_sub(255).min(255) as u8,
+        v.saturating_sub(510).min(255) as u8,
+    ]
+}
+
+/// Per-pixel largest channel difference, amplified and colour-mapped
+pub fn heatmap(reference: &DynamicImage, current: &DynamicImage, width: u32) -> DynamicImage {
+    let channels = reference.color().channel_count() as usize;
+    let (w, h) = reference.dimensions();
+    let mut heat = RgbImage::new(w, h);
+    let pixels = reference
+        .as_bytes()
+        .chunks_exact(channels)
+        .zip(current.as_bytes().chunks_exact(channels));
+    for (out, (a, b)) in heat.pixels_mut().zip(pixels) {
+        let diff = a
+            .iter()
+            .zip(b)
+            .map(|(&x, &y)| (x as i32 - y as i32).unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        out.0 = heat_color((diff * HEATMAP_GAIN).min(255) as u8);
+    }
+
+    let heat = DynamicImage::ImageRgb8(heat);
+    if width < w {
+        heat.thumbnail(width, h)
+    } else {
+        heat
+    }
+}
@@ -0,0 +1,447 @@
+//! TOML configuration file (`--config`)
+//!
+//! ```toml
+//! [server]
+//! bind = "0.0.0.0"
+//! port = 8080
+//!
+//! [capture]
+//! device = "/dev/video9"
+//! subdev = "/dev/v4l-subdev3"
+//! bayer_order = "GBRG"
+//! frame_size = "1920x1080"
+//! mode = "color"
+//! demosaic = "malvar"
+//! jpeg_quality = 85
+//! gamma = 2.2
+//! white_balance = true
+//! white_balance_mode = "white_patch"
+//! color_matrix = [[1.62, -0.45, -0.17], [-0.28, 1.52, -0.24], [-0.05, -0.52, 1.57]]
+//! contrast = { mode = "clahe", clip_limit = 2.0, tiles = 8 }
+//! denoise = { strength = 0.8, threshold = 24 }
+//! spatial_denoise = { method = "bilateral", strength = 0.5 }
+//! sharpen = { amount = 0.8, radius = 1 }
+//! tone = { brightness = 10, contrast = 1.1, saturation = 1.2 }
+//! orientation = { rotation = 180 }
+//! controls = { exposure = 1200 }
+//!
+//! [detector]
+//! backends = ["rknn", "subprocess", "onnx-cpu"]
+//! enabled = true
+//! interval = 3
+//!
+//! [overlay]
+//! timestamp = true
+//! camera_name = "driveway"
+//! position = "bottom_right"
+//! font_scale = 4
+//! color = [255, 255, 0]
+//!
+//! [[zones]]
+//! name = "driveway"
+//! points = [[0.1, 0.6], [0.5, 0.55], [0.6, 1.0], [0.05, 1.0]]
+//! alert_classes = ["person", "car"]
+//!
+//! [[masks]]
+//! name = "neighbor_window"
+//! rect = [0.72, 0.1, 0.12, 0.2]
+//! style = "pixelate"
+//! ```
+//!
+//! Every key is optional. At startup the file provides defaults that the
+//! command line flags override. `POST /config/reload` reads it again and
+//! applies the processing settings, sensor controls and detection cadence
+//! (the file wins over flags given at startup); devices, the listen address
+//! and the detector setup only change on restart and are reported instead.
+//! Zones and masks also change live; the `/zones` and `/masks` APIs rewrite
+//! their `[[zones]]` and `[[masks]]` tables in place, leaving the rest of
+//! the file (comments included) as it was.
+
+use crate::capture::{CaptureMode, EncoderKind, FrameSize, ScalerKind, MAX_GAMMA, MIN_GAMMA};
+use crate::ccm::{ColorCorrection, ColorMatrix};
+use crate::contrast::Contrast;
+use crate::denoise::{Denoise, SpatialDenoise};
+use crate::demosaic::DemosaicAlgorithm;
+use crate::detector::{BackendKind, DetectionTask};
+use crate::hdr;
+use crate::masks::{self, Mask};
+use crate::orientation::Orientation;
+use crate::overlay::Overlay;
+use crate::pipeline::PipelineSettings;
+use crate::profiles::RowNoiseSetting;
+use crate::rawformat::Cfa;
+use crate::sharpen::Sharpen;
+use crate::tone::Tone;
+use crate::whitebalance::WhiteBalanceMode;
+use crate::zones::{self, Zone};
+use crate::zoom::Zoom;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Loaded at startup if present and no `--config` is given
+pub const DEFAULT_CONFIG_FILE: &str = "/home/angelo/imx415_streamer/imx415_streamer.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub server: ServerConfig,
+    pub capture: CaptureFileConfig,
+    pub detector: DetectorFileConfig,
+    /// `[overlay]`: text drawn on the frames (applied live)
+    pub overlay: Option<Overlay>,
+    /// `[[zones]]` (applied live)
+    pub zones: Vec<Zone>,
+    /// `[[masks]]`: privacy masks (applied live)
+    pub masks: Vec<Mask>,
+}
+
+/// `[server]` (restart only)
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+}
+
+/// `[capture]`: devices (restart only) and processing settings
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureFileConfig {
+    pub device: Option<String>,
+    pub subdev: Option<String>,
+    pub link_frequency: Option<u32>,
+    /// `mpp` or `software`
+    pub encoder: Option<String>,
+    /// `rga` or `software`
+    pub scaler: Option<String>,
+    /// Color pipeline worker threads (default: one per core)
+    pub threads: Option<usize>,
+    /// `RGGB`, `GRBG`, `GBRG` or `BGGR`, over the order the format reports
+    pub bayer_order: Option<String>,
+    /// Sensor mode as `<width>x<height>` (e.g. `1920x1080`) or `full`
+    pub frame_size: Option<String>,
+    /// `grayscale`, `grayscale-hdr`, `color` or `color-hdr`
+    pub mode: Option<String>,
+    /// `bilinear` or `malvar`
+    pub demosaic: Option<String>,
+    pub jpeg_quality: Option<u8>,
+    pub gamma: Option<f32>,
+    pub white_balance: Option<bool>,
+    /// `gray_world`, `white_patch` or a preset (`daylight`, `tungsten`, ...)
+    pub white_balance_mode: Option<String>,
+    /// Apply the color correction matrix (default on)
+    pub color_correction: Option<bool>,
+    /// Color correction matrix, rows R, G, B of the output
+    pub color_matrix: Option<ColorMatrix>,
+    /// `{ rotation = 0|90|180|270, hflip = bool, vflip = bool }`
+    pub orientation: Option<Orientation>,
+    /// Digital zoom window, `{ x, y, width, height }` in fractions of the
+    /// frame
+    pub zoom: Option<Zoom>,
+    pub tonemap_strength: Option<f32>,
+    /// Grayscale equalization, `{ mode = "off"|"global"|"clahe", clip_limit,
+    /// tiles }`
+    pub contrast: Option<Contrast>,
+    /// Temporal noise reduction, `{ strength = 0.0-0.95, threshold = 1-128 }`
+    pub denoise: Option<Denoise>,
+    /// Spatial noise reduction, `{ method = "off"|"median"|"bilateral",
+    /// strength = 0.0-1.0 }`
+    pub spatial_denoise: Option<SpatialDenoise>,
+    /// Unsharp mask, `{ amount = 0.0-4.0, radius = 1-4, threshold }`
+    pub sharpen: Option<Sharpen>,
+    /// Output adjustment, `{ brightness = -128 to 128, contrast = 0.0-4.0,
+    /// saturation = 0.0-4.0 }`
+    pub tone: Option<Tone>,
+    pub black_level: Option<u16>,
+    pub hdr_ratio: Option<f32>,
+    pub row_noise: Option<RowNoiseSetting>,
+    /// Sensor subdevice controls by name
+    pub controls: BTreeMap<String, i64>,
+}
+
+/// `[detector]`: setup (restart only) and cadence
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectorFileConfig {
+    /// Backends in the order they are tried
+    pub backends: Option<Vec<String>>,
+    /// `objects` or `faces`
+    pub task: Option<String>,
+    pub models_dir: Option<PathBuf>,
+    pub onnx_model: Option<String>,
+    pub rknn_model: Option<String>,
+    /// Detection on when the server starts / after a reload
+    pub enabled: Option<bool>,
+    /// Feed every Nth frame (0 = on demand)
+    pub interval: Option<u32>,
+}
+
+impl ConfigFile {
+    /// Read and validate a config file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let config: Self = toml::from_str(&text).with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.validate().with_context(|| format!("Invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let capture = &self.capture;
+        if let Some(ref encoder) = capture.encoder {
+            anyhow::ensure!(EncoderKind::parse(encoder).is_some(), "capture.encoder must be mpp or software");
+        }
+        if let Some(ref scaler) = capture.scaler {
+            anyhow::ensure!(ScalerKind::parse(scaler).is_some(), "capture.scaler must be rga or software");
+        }
+        if let Some(ref size) = capture.frame_size {
+            let parsed = FrameSize::parse(size).with_context(|| format!("capture.frame_size: invalid size '{}'", size))?;
+            parsed.validate().map_err(|e| anyhow::anyhow!("capture.frame_size: {}", e))?;
+        }
+        if let Some(ref order) = capture.bayer_order {
+            anyhow::ensure!(Cfa::parse(order).is_some(), "capture.bayer_order must be RGGB, GRBG, GBRG or BGGR");
+        }
+        if let Some(ref mode) = capture.mode {
+            anyhow::ensure!(CaptureMode::parse(mode).is_some(), "capture.mode: unknown mode '{}'", mode);
+        }
+        if let Some(ref demosaic) = capture.demosaic {
+            anyhow::ensure!(DemosaicAlgorithm::parse(demosaic).is_some(), "capture.demosaic must be bilinear or malvar");
+        }
+        if let Some(ref mode) = capture.white_balance_mode {
+            anyhow::ensure!(
+                WhiteBalanceMode::parse(mode).is_some(),
+                "capture.white_balance_mode must be one of {}",
+                WhiteBalanceMode::names().join(", ")
+            );
+        }
+        if let Some(ref matrix) = capture.color_matrix {
+            matrix.validate().map_err(|e| anyhow::anyhow!("capture.color_matrix: {}", e))?;
+        }
+        if let Some(ref orientation) = capture.orientation {
+            orientation.validate().map_err(|e| anyhow::anyhow!("capture.orientation: {}", e))?;
+        }
+        if let Some(ref zoom) = capture.zoom {
+            zoom.validate().map_err(|e| anyhow::anyhow!("capture.zoom: {}", e))?;
+        }
+        if let Some(quality) = capture.jpeg_quality {
+            anyhow::ensure!((1..=100).contains(&quality), "capture.jpeg_quality must be between 1 and 100");
+        }
+        if let Some(gamma) = capture.gamma {
+            anyhow::ensure!(
+                (MIN_GAMMA..=MAX_GAMMA).contains(&gamma),
+                "capture.gamma must be between {} and {}",
+                MIN_GAMMA,
+                MAX_GAMMA
+            );
+        }
+        if let Some(strength) = capture.tonemap_strength {
+            anyhow::ensure!((0.0..=1.0).contains(&strength), "capture.tonemap_strength must be between 0.0 and 1.0");
+        }
+        if let Some(ref contrast) = capture.contrast {
+            contrast.validate().map_err(|e| anyhow::anyhow!("capture.contrast: {}", e))?;
+        }
+        if let Some(ref denoise) = capture.denoise {
+            denoise.validate().map_err(|e| anyhow::anyhow!("capture.denoise: {}", e))?;
+        }
+        if let Some(ref spatial) = capture.spatial_denoise {
+            spatial.validate().map_err(|e| anyhow::anyhow!("capture.spatial_denoise: {}", e))?;
+        }
+        if let Some(ref sharpen) = capture.sharpen {
+            sharpen.validate().map_err(|e| anyhow::anyhow!("capture.sharpen: {}", e))?;
+        }
+        if let Some(ref tone) = capture.tone {
+            tone.validate().map_err(|e| anyhow::anyhow!("capture.tone: {}", e))?;
+        }
+        if let Some(black_level) = capture.black_level {
+            anyhow::ensure!(black_level <= 1022, "capture.black_level must be at most 1022");
+        }
+        if let Some(ratio) = capture.hdr_ratio {
+            anyhow::ensure!(
+                (hdr::MIN_RATIO..=hdr::MAX_RATIO).contains(&ratio),
+                "capture.hdr_ratio must be between {} and {}",
+                hdr::MIN_RATIO,
+                hdr::MAX_RATIO
+            );
+        }
+        if let Some(row_noise) = capture.row_noise {
+            anyhow::ensure!(
+                (0.0..=1.0).contains(&row_noise.strength),
+                "capture.row_noise.strength must be between 0.0 and 1.0"
+            );
+        }
+        for name in self.detector.backends.iter().flatten() {
+            anyhow::ensure!(
+                BackendKind::parse(name).is_some() || name == "none",
+                "detector.backends: unknown backend '{}'",
+                name
+            );
+        }
+        if let Some(ref task) = self.detector.task {
+            anyhow::ensure!(DetectionTask::parse(task).is_some(), "detector.task must be objects or faces");
+        }
+        if let Some(ref overlay) = self.overlay {
+            overlay.validate().map_err(|e| anyhow::anyhow!("overlay: {}", e))?;
+        }
+        zones::validate(&self.zones).map_err(|e| anyhow::anyhow!("zones: {}", e))?;
+        masks::validate(&self.masks).map_err(|e| anyhow::anyhow!("masks: {}", e))?;
+        Ok(())
+    }
+
+    /// Apply the `[capture]` processing settings, the `[overlay]` and the
+    /// `[[masks]]`; returns their names
+    pub fn apply_pipeline(&self, settings: &mut PipelineSettings) -> Vec<&'static str> {
+        let capture = &self.capture;
+        let mut applied = Vec::new();
+        if let Some(mode) = capture.mode.as_deref().and_then(CaptureMode::parse) {
+            settings.set_mode(mode);
+            applied.push("mode");
+        }
+        if let Some(algorithm) = capture.demosaic.as_deref().and_then(DemosaicAlgorithm::parse) {
+            settings.set_demosaic(algorithm);
+            applied.push("demosaic");
+        }
+        if let Some(quality) = capture.jpeg_quality {
+            settings.set_jpeg_quality(quality);
+            applied.push("jpeg_quality");
+        }
+        if let Some(gamma) = capture.gamma {
+            settings.set_gamma(gamma);
+            applied.push("gamma");
+        }
+        if let Some(enabled) = capture.white_balance {
+            settings.set_white_balance(enabled);
+            applied.push("white_balance");
+        }
+        if let Some(mode) = capture.white_balance_mode.as_deref().and_then(WhiteBalanceMode::parse) {
+            settings.set_wb_mode(mode);
+            applied.push("white_balance_mode");
+        }
+        if capture.color_correction.is_some() || capture.color_matrix.is_some() {
+            let current = settings.color_correction;
+            settings.set_color_correction(ColorCorrection {
+                enabled: capture.color_correction.unwrap_or(current.enabled),
+                matrix: capture.color_matrix.unwrap_or(current.matrix),
+            });
+            applied.push("color_correction");
+        }
+        if let Some(orientation) = capture.orientation {
+            settings.set_orientation(orientation);
+            applied.push("orientation");
+        }
+        if let Some(zoom) = capture.zoom {
+            settings.set_zoom(zoom);
+            applied.push("zoom");
+        }
+        if let Some(strength) = capture.tonemap_strength {
+            settings.set_tonemap_strength(strength);
+            applied.push("tonemap_strength");
+        }
+        if let Some(contrast) = capture.contrast {
+            settings.set_contrast(contrast);
+            applied.push("contrast");
+        }
+        if let Some(denoise) = capture.denoise {
+            settings.set_denoise(denoise);
+            applied.push("denoise");
+        }
+        if let Some(spatial) = capture.spatial_denoise {
+            settings.set_spatial_denoise(spatial);
+            applied.push("spatial_denoise");
+        }
+        if let Some(sharpen) = capture.sharpen {
+            settings.set_sharpen(sharpen);
+            applied.push("sharpen");
+        }
+        if let Some(tone) = capture.tone {
+            settings.set_tone(tone);
+            applied.push("tone");
+        }
+        if let Some(black_level) = capture.black_level {
+            settings.set_black_level(black_level);
+            applied.push("black_level");
+        }
+        if let Some(ratio) = capture.hdr_ratio {
+            settings.set_hdr_ratio(ratio);
+            applied.push("hdr_ratio");
+        }
+        if let Some(row_noise) = capture.row_noise {
+            settings.set_row_noise_correction(row_noise.enabled, row_noise.strength);
+            applied.push("row_noise");
+        }
+        if let Some(ref overlay) = self.overlay {
+            settings.set_overlay(overlay.clone());
+            applied.push("overlay");
+        }
+        if self.masks != settings.masks {
+            settings.set_masks(self.masks.clone());
+            applied.push("masks");
+        }
+        applied
+    }
+
+    /// Keys that differ from `running` but only take effect on restart
+    pub fn restart_required(&self, running: &ConfigFile) -> Vec<&'static str> {
+        let (capture, was) = (&self.capture, &running.capture);
+        let (detector, was_detector) = (&self.detector, &running.detector);
+        [
+            ("server.bind", self.server.bind != running.server.bind),
+            ("server.port", self.server.port != running.server.port),
+            ("capture.device", capture.device != was.device),
+            ("capture.subdev", capture.subdev != was.subdev),
+            ("capture.link_frequency", capture.link_frequency != was.link_frequency),
+            ("capture.encoder", capture.encoder != was.encoder),
+            ("capture.scaler", capture.scaler != was.scaler),
+            ("capture.threads", capture.threads != was.threads),
+            ("capture.bayer_order", capture.bayer_order != was.bayer_order),
+            ("capture.frame_size", capture.frame_size != was.frame_size),
+            ("detector.backends", detector.backends != was_detector.backends),
+            ("detector.task", detector.task != was_detector.task),
+            ("detector.models_dir", detector.models_dir != was_detector.models_dir),
+            ("detector.onnx_model", detector.onnx_model != was_detector.onnx_model),
+            ("detector.rknn_model", detector.rknn_model != was_detector.rknn_model),
+        ]
+        .into_iter()
+        .filter_map(|(key, changed)| changed.then_some(key))
+        .collect()
+    }
+}
+
+/// Replace the `[[zones]]` tables of the config file at `path` (created if
+/// missing); everything else in the file is kept as written
+pub fn write_zones(path: &Path, zones: &[Zone]) -> Result<()> {
+    write_tables(path, "zones", zones)
+}
+
+/// Replace the `[[masks]]` tables of the config file at `path`, like
+/// [`write_zones`]
+pub fn write_masks(path: &Path, masks: &[Mask]) -> Result<()> {
+    write_tables(path, "masks", masks)
+}
+
+/// Replace the array of tables `key` of the config file at `path` with
+/// `items` (removed when empty)
+fn write_tables<T: Serialize>(path: &Path, key: &str, items: &[T]) -> Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let mut document: toml_edit::DocumentMut =
+        text.parse().with_context(|| format!("Invalid config file {}", path.display()))?;
+    if items.is_empty() {
+        document.remove(key);
+    } else {
+        let tables: toml_edit::DocumentMut = toml::to_string(&BTreeMap::from([(key, items)]))?.parse()?;
+        document.insert(key, tables[key].clone());
+    }
+
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let temp = path.with_extension("toml.tmp");
+    std::fs::write(&temp, document.to_string()).with_context(|| format!("Failed to write {}", temp.display()))?;
+    std::fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
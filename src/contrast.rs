@@ -0,0 +1,172 @@
+//! Contrast enhancement (grayscale modes)
+//!
+//! Grayscale output is linear in the raw values, so a low-contrast scene
+//! (fog, night, a dim room) fills a narrow band of gray levels and looks
+//! flat. The enhancement remaps the upscaled grayscale frame through a
+//! histogram equalization:
+//!
+//! - global equalization builds one curve from the whole frame's histogram,
+//!   spreading the levels in use over the full range
+//! - CLAHE (contrast-limited adaptive histogram equalization) builds a
+//!   curve per tile of a grid and interpolates bilinearly between the
+//!   curves of the four nearest tile centres, so each part of the frame is
+//!   stretched by its own content. Each tile's histogram is clipped at
+//!   `clip_limit` times its mean bin count and the excess spread over all
+//!   bins, which keeps flat areas (sky, walls) from turning into amplified
+//!   noise.
+//!
+//! The frame histogram (`/histogram`, auto-exposure) is taken before this
+//! stage, so exposure still follows the scene and not the enhanced output.
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Clip limits accepted, in multiples of the mean bin count
+pub const CLIP_LIMITS: std::ops::RangeInclusive<f32> = 1.0..=16.0;
+
+/// Tile counts across the frame width accepted (rows follow the aspect ratio)
+pub const TILES: std::ops::RangeInclusive<u32> = 2..=32;
+
+/// Which equalization is applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ContrastMode {
+    #[default]
+    Off,
+    /// One curve for the whole frame
+    Global,
+    /// Contrast-limited curves per tile
+    Clahe,
+}
+
+impl ContrastMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "off" | "none" => Some(ContrastMode::Off),
+            "global" | "equalize" => Some(ContrastMode::Global),
+            "clahe" | "adaptive" => Some(ContrastMode::Clahe),
+            _ => None,
+        }
+    }
+}
+
+/// Contrast enhancement settings (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct Contrast {
+    pub mode: ContrastMode,
+    /// CLAHE histogram clip, in multiples of the mean bin count (lower is
+    /// gentler)
+    pub clip_limit: f32,
+    /// CLAHE tiles across the frame width
+    pub tiles: u32,
+}
+
+impl Default for Contrast {
+    fn default() -> Self {
+        Self { mode: ContrastMode::Off, clip_limit: 2.0, tiles: 8 }
+    }
+}
+
+impl Contrast {
+    pub fn validate(&self) -> Result<(), String> {
+        if !CLIP_LIMITS.contains(&self.clip_limit) {
+            return Err(format!("clip_limit must be {}-{}", CLIP_LIMITS.start(), CLIP_LIMITS.end()));
+        }
+        if !TILES.contains(&self.tiles) {
+            return Err(format!("tiles must be {}-{}", TILES.start(), TILES.end()));
+        }
+        Ok(())
+    }
+
+    /// Enhance a `width` x `height` grayscale frame in place (rows are
+    /// processed in parallel on the current rayon pool)
+    pub fn apply(&self, gray: &mut [u8], width: usize, height: usize) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        match self.mode {
+            ContrastMode::Off => {}
+            ContrastMode::Global => {
+                let histogram = histogram(gray, width, 0..width, 0..height);
+                let lut = equalization_lut(&histogram, None);
+                gray.par_iter_mut().for_each(|v| *v = lut[*v as usize]);
+            }
+            ContrastMode::Clahe => self.clahe(gray, width, height),
+        }
+    }
+
+    fn clahe(&self, gray: &mut [u8], width: usize, height: usize) {
+        let tiles_x = (self.tiles as usize).min(width);
+        let tiles_y = ((self.tiles as usize * height + width / 2) / width).clamp(1, height);
+        let (tile_width, tile_height) = (width.div_ceil(tiles_x), height.div_ceil(tiles_y));
+        let luts: Vec<[u8; 256]> = (0..tiles_x * tiles_y)
+            .into_par_iter()
+            .map(|i| {
+                let (tx, ty) = (i % tiles_x, i / tiles_x);
+                let columns = tx * tile_width..((tx + 1) * tile_width).min(width);
+                let rows = ty * tile_height..((ty + 1) * tile_height).min(height);
+                equalization_lut(&histogram(gray, width, columns, rows), Some(self.clip_limit))
+            })
+            .collect();
+
+        // The two tiles whose centres bracket a position, and the weight of
+        // the second
+        let neighbours = |position: usize, tile: usize, tiles: usize| {
+            let t = ((position as f32 + 0.5) / tile as f32 - 0.5).clamp(0.0, (tiles - 1) as f32);
+            let first = (t as usize).min(tiles - 1);
+            (first, (first + 1).min(tiles - 1), t - first as f32)
+        };
+        let columns: Vec<_> = (0..width).map(|x| neighbours(x, tile_width, tiles_x)).collect();
+        gray.par_chunks_exact_mut(width).enumerate().for_each(|(y, row)| {
+            let (ty0, ty1, wy) = neighbours(y, tile_height, tiles_y);
+            let (top, bottom) = (&luts[ty0 * tiles_x..(ty0 + 1) * tiles_x], &luts[ty1 * tiles_x..(ty1 + 1) * tiles_x]);
+            for (v, &(tx0, tx1, wx)) in row.iter_mut().zip(&columns) {
+                let i = *v as usize;
+                let upper = top[tx0][i] as f32 + wx * (top[tx1][i] as f32 - top[tx0][i] as f32);
+                let lower = bottom[tx0][i] as f32 + wx * (bottom[tx1][i] as f32 - bottom[tx0][i] as f32);
+                *v = (upper + wy * (lower - upper)).round() as u8;
+            }
+        });
+    }
+}
+
+/// Histogram of a rectangle of a grayscale frame
+fn histogram(gray: &[u8], width: usize, columns: std::ops::Range<usize>, rows: std::ops::Range<usize>) -> [u32; 256] {
+    let mut histogram = [0u32; 256];
+    for y in rows {
+        for &v in &gray[y * width + columns.start..y * width + columns.end] {
+            histogram[v as usize] += 1;
+        }
+    }
+    histogram
+}
+
+/// Equalization curve of a histogram, clipped at `clip_limit` times the
+/// mean bin count if given (the excess spread evenly over the bins)
+fn equalization_lut(histogram: &[u32; 256], clip_limit: Option<f32>) -> [u8; 256] {
+    let total: u32 = histogram.iter().sum();
+    let mut lut = [0u8; 256];
+    if total == 0 {
+        return lut;
+    }
+    let mut counts = histogram.map(|n| n as f32);
+    if let Some(clip_limit) = clip_limit {
+        let limit = clip_limit * total as f32 / 256.0;
+        let excess: f32 = counts.iter().map(|&n| (n - limit).max(0.0)).sum();
+        for n in counts.iter_mut() {
+            *n = n.min(limit) + excess / 256.0;
+        }
+    }
+    // Levels below the darkest one in use stay black
+    let first = counts.iter().position(|&n| n > 0.0).unwrap_or(0);
+    let mut cumulative = 0.0;
+    let range = (total as f32 - counts[first]).max(1.0);
+    for (v, &n) in lut.iter_mut().zip(&counts) {
+        cumulative += n;
+        *v = ((cumulative - counts[first]).max(0.0) / range * 255.0).round() as u8;
+    }
+    lut
+}
@@ -0,0 +1,201 @@
+//! V4L2 control discovery and access
+//!
+//! Wraps `v4l2-ctl` to enumerate the controls exposed by a (sub)device,
+//! including menu entries, and to read/write individual controls.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::process::Command;
+
+/// A single V4L2 control as reported by `v4l2-ctl --list-ctrls-menus`
+#[derive(Debug, Clone, Serialize)]
+pub struct Control {
+    pub name: String,
+    pub id: u32,
+    /// Control type (int, bool, menu, intmenu, button, ...)
+    pub kind: String,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub step: Option<i64>,
+    pub default: Option<i64>,
+    pub value: Option<i64>,
+    /// Flags reported by the driver (read-only, inactive, volatile, ...)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+    /// Menu entries (index, label) for menu/intmenu controls
+    pub menu: Vec<MenuEntry>,
+}
+
+/// One entry of a menu control
+#[derive(Debug, Clone, Serialize)]
+pub struct MenuEntry {
+    pub index: i64,
+    pub label: String,
+}
+
+impl Control {
+    /// Find a menu entry by index or case-insensitive label
+    /// (spaces and underscores/dashes are treated alike)
+    pub fn find_menu_entry(&self, query: &str) -> Option<&MenuEntry> {
+        if let Ok(index) = query.parse::<i64>() {
+            return self.menu.iter().find(|e| e.index == index);
+        }
+        let wanted = normalize_label(query);
+        self.menu.iter().find(|e| normalize_label(&e.label) == wanted)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.flags.iter().any(|f| f == "read-only")
+    }
+
+    /// Parse a value to set: a number, a menu entry (index or label) for
+    /// menu controls, or `on`/`off`/`true`/`false` for booleans; checked
+    /// against the range and step
+    pub fn parse_value(&self, query: &str) -> Result<i64, String> {
+        let query = query.trim();
+        let value = if matches!(self.kind.as_str(), "menu" | "intmenu") {
+            let entry = self.find_menu_entry(query).ok_or_else(|| format!("No menu entry '{}' in {}", query, self.name))?;
+            entry.index
+        } else if self.kind == "bool" && !query.starts_with(|c: char| c.is_ascii_digit()) {
+            match query.to_ascii_lowercase().as_str() {
+                "on" | "true" => 1,
+                "off" | "false" => 0,
+                _ => return Err(format!("Invalid value '{}' for {} (use on/off)", query, self.name)),
+            }
+        } else {
+            query.parse().map_err(|_| format!("Invalid value '{}' for {}", query, self.name))?
+        };
+        let (min, max) = (self.min.unwrap_or(i64::MIN), self.max.unwrap_or(i64::MAX));
+        if !(min..=max).contains(&value) {
+            return Err(format!("{} must be within {}..={}", self.name, min, max));
+        }
+        if let (Some(min), Some(step)) = (self.min, self.step.filter(|&s| s > 1)) {
+            if (value - min) % step != 0 {
+                return Err(format!("{} must be {} plus a multiple of {}", self.name, min, step));
+            }
+        }
+        Ok(value)
+    }
+}
+
+fn normalize_label(label: &str) -> String {
+    label
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c == '_' || c == '-' { ' ' } else { c })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse the `key=value` fields after the colon of a control line
+fn parse_field(fields: &str, key: &str) -> Option<i64> {
+    fields
+        .split_whitespace()
+        .find_map(|f| f.strip_prefix(key)?.strip_prefix('='))
+        .and_then(|v| v.parse().ok())
+}
+
+/// The comma-separated `flags=` field of a control line
+fn parse_flags(fields: &str) -> Vec<String> {
+    fields
+        .split_whitespace()
+        .find_map(|f| f.strip_prefix("flags="))
+        .map(|flags| flags.split(',').filter(|f| !f.is_empty()).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Parse `v4l2-ctl --list-ctrls-menus` output
+pub fn parse_controls(output: &str) -> Vec<Control> {
+    let mut controls: Vec<Control> = Vec::new();
+
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        // Menu entry: "0: Disabled"
+        if let Some((index, label)) = trimmed.split_once(": ") {
+            if let (Ok(index), Some(control)) = (index.parse::<i64>(), controls.last_mut()) {
+                control.menu.push(MenuEntry {
+                    index,
+                    label: label.trim().to_string(),
+                });
+                continue;
+            }
+        }
+
+        // Control: "name 0x009f0903 (menu)   : min=0 max=4 default=0 value=0"
+        let Some((head, fields)) = trimmed.split_once(':') else {
+            continue;
+        };
+        let mut parts = head.split_whitespace();
+        let (Some(name), Some(id), Some(kind)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        let Some(id) = id
+            .strip_prefix("0x")
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        else {
+            continue;
+        };
+
+        controls.push(Control {
+            name: name.to_string(),
+            id,
+            kind: kind.trim_matches(|c| c == '(' || c == ')').to_string(),
+            min: parse_field(fields, "min"),
+            max: parse_field(fields, "max"),
+            step: parse_field(fields, "step"),
+            default: parse_field(fields, "default"),
+            value: parse_field(fields, "value"),
+            flags: parse_flags(fields),
+            menu: Vec::new(),
+        });
+    }
+
+    controls
+}
+
+/// Enumerate all controls on a device
+pub fn list_controls(device: &str) -> Result<Vec<Control>> {
+    let output = Command::new("v4l2-ctl")
+        .args(["-d", device, "--list-ctrls-menus"])
+        .output()
+        .context("Failed to run v4l2-ctl")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "v4l2-ctl --list-ctrls-menus failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_controls(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Look up a single control by name
+pub fn find_control(device: &str, name: &str) -> Result<Control> {
+    list_controls(device)?
+        .into_iter()
+        .find(|c| c.name == name)
+        .with_context(|| format!("Control '{}' not available on {}", name, device))
+}
+
+/// Set a control value
+pub fn set_control(device: &str, name: &str, value: i64) -> Result<()> {
+    let output = Command::new("v4l2-ctl")
+        .args(["-d", device, "--set-ctrl", &format!("{}={}", name, value)])
+        .output()
+        .context("Failed to run v4l2-ctl")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Setting {}={} failed: {}",
+            name,
+            value,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
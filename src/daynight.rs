@@ -0,0 +1,182 @@
+//! Day/night capture mode switching
+//!
+//! `/mode/auto` lets the measured scene brightness pick the capture mode:
+//! the color pipeline while there is light to work with, and the
+//! artifact-free grayscale path when it gets dark. Brightness is taken out
+//! of the exposure settings, so auto exposure chasing the light doesn't
+//! hide it: the log2 of the frame's linear mean luminance (the 8-bit mean
+//! taken back through the output gamma, 0-1) per second of exposure at
+//! unity gain. An 18% gray frame at 10 ms and 0 dB is about 4.2 EV; the
+//! same frame at 33 ms and 24 dB about -1.5 EV. Without sensor controls
+//! (a synthetic source) the luminance alone is used.
+//!
+//! Night starts below `night_below` and day above `day_above`, which must
+//! be higher; between the two the mode stays. A level has to stay past a
+//! threshold for `hold_secs` before the mode switches, so headlights or a
+//! passing cloud don't flip it. The first measurement after enabling picks
+//! the mode straight away (by the nearer threshold when between them).
+
+use crate::capture::CaptureMode;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Settings state file key for the day/night config
+pub const STATE_KEY: &str = "day_night";
+
+/// Seconds between brightness measurements
+pub const POLL_SECS: u64 = 2;
+
+/// Longest hold time
+const MAX_HOLD_SECS: f32 = 3600.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct DayNightConfig {
+    /// Switch modes automatically (`/mode/auto`)
+    pub enabled: bool,
+    /// Scene brightness (EV) below which night starts
+    pub night_below: f32,
+    /// Scene brightness (EV) above which day starts
+    pub day_above: f32,
+    /// How long the brightness must stay past a threshold
+    pub hold_secs: f32,
+}
+
+impl Default for DayNightConfig {
+    fn default() -> Self {
+        Self { enabled: false, night_below: -4.0, day_above: -1.0, hold_secs: 30.0 }
+    }
+}
+
+impl DayNightConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.night_below.is_finite() || !self.day_above.is_finite() {
+            return Err("night_below and day_above must be numbers".to_string());
+        }
+        if self.day_above <= self.night_below {
+            return Err("day_above must be higher than night_below".to_string());
+        }
+        if !(0.0..=MAX_HOLD_SECS).contains(&self.hold_secs) {
+            return Err(format!("hold_secs must be between 0 and {}", MAX_HOLD_SECS));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Night,
+}
+
+impl Period {
+    pub fn name(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Night => "night",
+        }
+    }
+
+    /// Capture mode used during the period
+    pub fn mode(self) -> CaptureMode {
+        match self {
+            Period::Day => CaptureMode::Color,
+            Period::Night => CaptureMode::Grayscale,
+        }
+    }
+}
+
+/// Day/night state for /status
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DayNightStatus {
+    #[serde(flatten)]
+    pub config: DayNightConfig,
+    /// Current period (None until the first measurement)
+    pub period: Option<Period>,
+    /// Last measured scene brightness (EV)
+    pub brightness: Option<f32>,
+    /// Since when the brightness has been past the other period's
+    /// threshold (Unix ms)
+    pub pending_since_ms: Option<u64>,
+}
+
+/// Scene brightness in EV from an 8-bit mean luminance and the output
+/// `gamma`, exposure time and linear analogue gain it was taken with
+pub fn scene_brightness(mean: f32, gamma: f32, exposure_s: f64, gain: f64) -> Option<f32> {
+    let linear = (mean as f64 / 255.0).powf(gamma as f64);
+    let brightness = (linear / (exposure_s * gain)).log2();
+    (linear > 0.0 && exposure_s > 0.0 && gain > 0.0).then_some(brightness as f32)
+}
+
+/// Hysteresis between the two periods
+pub struct DayNight {
+    config: DayNightConfig,
+    period: Option<Period>,
+    brightness: Option<f32>,
+    pending_since_ms: Option<u64>,
+}
+
+impl DayNight {
+    pub fn new(config: DayNightConfig) -> Self {
+        Self { config, period: None, brightness: None, pending_since_ms: None }
+    }
+
+    pub fn config(&self) -> &DayNightConfig {
+        &self.config
+    }
+
+    /// Replace the config; the next measurement decides the period afresh
+    pub fn set_config(&mut self, config: DayNightConfig) {
+        *self = Self::new(config);
+    }
+
+    /// Take a brightness measurement; returns the period to switch to, if
+    /// it changes
+    pub fn update(&mut self, brightness: f32, now_ms: u64) -> Option<Period> {
+        self.brightness = Some(brightness);
+        if !self.config.enabled {
+            return None;
+        }
+        let target = if brightness < self.config.night_below {
+            Period::Night
+        } else if brightness > self.config.day_above {
+            Period::Day
+        } else if self.period.is_none() {
+            // Nearer threshold
+            if brightness < (self.config.night_below + self.config.day_above) / 2.0 {
+                Period::Night
+            } else {
+                Period::Day
+            }
+        } else {
+            self.pending_since_ms = None;
+            return None;
+        };
+        match self.period {
+            None => {}
+            Some(period) if period == target => {
+                self.pending_since_ms = None;
+                return None;
+            }
+            Some(_) => {
+                let since = *self.pending_since_ms.get_or_insert(now_ms);
+                if now_ms.saturating_sub(since) < (self.config.hold_secs * 1000.0) as u64 {
+                    return None;
+                }
+            }
+        }
+        self.period = Some(target);
+        self.pending_since_ms = None;
+        Some(target)
+    }
+
+    pub fn status(&self) -> DayNightStatus {
+        DayNightStatus {
+            config: self.config.clone(),
+            period: self.period,
+            brightness: self.brightness,
+            pending_since_ms: self.pending_since_ms,
+        }
+    }
+}
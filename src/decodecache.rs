@@ -0,0 +1,191 @@
+//! Decoded pixels of published frames
+//!
+//! Endpoints that need pixels rather than JPEG bytes (scaled or
+//! re-encoded frames, annotated history frames) share one decode per frame
+//! content, keyed by the frame hash. The capture loop seeds the cache with
+//! the pipeline's pre-encode pixels of every new frame, so the current
+//! clean frame (and the annotated one while nothing is drawn on it) is
+//! never decoded. Other JPEGs (frames with overlays, history frames) are
+//! decoded once, in a blocking task, however many requests want them at
+//! the same time; the rest wait for that decode.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use image::{DynamicImage, ImageFormat};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// Decoded frames kept besides the pipeline's own (a 4K RGB frame is ~25 MB)
+pub const DECODED_ENTRIES: usize = 2;
+
+/// A decode, finished or in flight
+type Slot = Arc<OnceCell<Arc<DynamicImage>>>;
+
+#[derive(Default)]
+struct Entries {
+    /// The latest pipeline frame's pixels and the hash of their JPEG
+    pipeline: Option<(u64, Arc<DynamicImage>)>,
+    /// Decoded frames by hash, oldest first
+    decoded: VecDeque<(u64, Slot)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DecodeCacheStats {
+    pub entries: usize,
+    /// Pixels held by decoded entries (the pipeline's are counted with the
+    /// current frame)
+    pub bytes: usize,
+    /// JPEG decodes started
+    pub decodes: u64,
+    /// Requests served by an earlier or in-flight decode
+    pub hits: u64,
+    /// Requests served from the pipeline's pre-encode pixels
+    pub pipeline_hits: u64,
+}
+
+#[derive(Default)]
+pub struct DecodeCache {
+    entries: Mutex<Entries>,
+    decodes: AtomicU64,
+    hits: AtomicU64,
+    pipeline_hits: AtomicU64,
+}
+
+impl DecodeCache {
+    /// Offer the pipeline's pixels of a new frame whose JPEG hashes to `hash`
+    pub fn seed(&self, hash: u64, image: Arc<DynamicImage>) {
+        self.entries.lock().pipeline = Some((hash, image));
+    }
+
+    /// Pixels of `jpeg`, whose hash is `hash`
+    pub async fn get(&self, hash: u64, jpeg: Bytes) -> Result<Arc<DynamicImage>> {
+        let slot = {
+            let mut entries = self.entries.lock();
+            if let Some((_, ref image)) = entries.pipeline.as_ref().filter(|(h, _)| *h == hash) {
+                self.pipeline_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(image.clone());
+            }
+            match entries.decoded.iter().find(|(h, _)| *h == hash) {
+                Some((_, slot)) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    slot.clone()
+                }
+                None => {
+                    if entries.decoded.len() >= DECODED_ENTRIES {
+                        entries.decoded.pop_front();
+                    }
+                    let slot = Slot::default();
+                    entries.decoded.push_back((hash, slot.clone()));
+                    slot
+                }
+            }
+        };
+        // If the decoding request goes away, a waiting one takes over
+        let image = slot
+            .get_or_try_init(|| async {
+                self.decodes.fetch_add(1, Ordering::Relaxed);
+                let image = tokio::task::spawn_blocking(move || {
+                    image::load_from_memory_with_format(&jpeg, ImageFormat::Jpeg)
+                })
+                .await
+                .context("Decode task failed")?
+                .context("Failed to decode JPEG")?;
+                Ok::<_, anyhow::Error>(Arc::new(image))
+            })
+            .await?;
+        Ok(image.clone())
+    }
+
+    /// Bytes held by decoded entries
+    pub fn bytes(&self) -> usize {
+        self.entries
+            .lock()
+            .decoded
+            .iter()
+            .filter_map(|(_, slot)| slot.get())
+            .map(|image| image.as_bytes().len())
+            .sum()
+    }
+
+    /// Drop the decoded entries (the pipeline's pixels stay)
+    pub fn clear(&self) {
+        self.entries.lock().decoded.clear();
+    }
+
+    pub fn stats(&self) -> DecodeCacheStats {
+        let entries = self.entries.lock().decoded.len();
+        DecodeCacheStats {
+            entries,
+            bytes: self.bytes(),
+            decodes: self.decodes.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            pipeline_hits: self.pipeline_hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg(width: u32, height: u32, value: u8) -> Bytes {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(width, height, image::Luma([value])));
+        let mut out = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut out, ImageFormat::Jpeg).unwrap();
+        out.into_inner().into()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_share_one_decode() {
+        let cache = Arc::new(DecodeCache::default());
+        let frame = jpeg(640, 480, 128);
+        let requests: Vec<_> = (0..16)
+            .map(|_| {
+                let (cache, frame) = (cache.clone(), frame.clone());
+                tokio::spawn(async move { cache.get(42, frame).await.unwrap() })
+            })
+            .collect();
+        let images = futures::future::try_join_all(requests).await.unwrap();
+        assert!(images.iter().all(|image| Arc::ptr_eq(image, &images[0])));
+        assert_eq!((images[0].width(), images[0].height()), (640, 480));
+        let stats = cache.stats();
+        assert_eq!((stats.decodes, stats.hits, stats.entries), (1, 15, 1));
+    }
+
+    #[tokio::test]
+    async fn seeded_pixels_are_never_decoded() {
+        let cache = DecodeCache::default();
+        let pixels = Arc::new(DynamicImage::new_luma8(4, 4));
+        cache.seed(7, pixels.clone());
+        // Not a JPEG: a decode would fail
+        let image = cache.get(7, Bytes::from_static(b"not a jpeg")).await.unwrap();
+        assert!(Arc::ptr_eq(&image, &pixels));
+        let stats = cache.stats();
+        assert_eq!((stats.decodes, stats.pipeline_hits, stats.entries), (0, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn the_oldest_decode_is_evicted() {
+        let cache = DecodeCache::default();
+        for hash in 0..=DECODED_ENTRIES as u64 {
+            cache.get(hash, jpeg(8, 8, hash as u8)).await.unwrap();
+        }
+        assert_eq!(cache.stats().entries, DECODED_ENTRIES);
+        // Hash 0 went first: decoded again
+        cache.get(0, jpeg(8, 8, 0)).await.unwrap();
+        assert_eq!(cache.stats().decodes, DECODED_ENTRIES as u64 + 2);
+    }
+
+    #[tokio::test]
+    async fn a_failed_decode_is_retried() {
+        let cache = DecodeCache::default();
+        assert!(cache.get(1, Bytes::from_static(b"broken")).await.is_err());
+        let image = cache.get(1, jpeg(8, 8, 10)).await.unwrap();
+        assert_eq!(image.width(), 8);
+        assert_eq!(cache.stats().decodes, 2);
+    }
+}
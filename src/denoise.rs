@@ -0,0 +1,245 @@
+//! Noise reduction: a temporal running average and spatial filters
+//!
+//! Sensor noise changes from frame to frame while a still scene doesn't,
+//! so averaging each pixel over time removes noise without the blur of a
+//! spatial filter. Every pixel keeps a running average (8.8 fixed point, so
+//! small steps aren't lost to rounding) that each new frame is blended into:
+//! with `strength` 0.8 a still pixel takes 20% of the new value, about a
+//! five-frame average. Where the new value differs from the average by
+//! more than noise would (`threshold` levels) the pixel is moving, and the
+//! blend falls off linearly to taking the new value outright, so moving
+//! objects don't leave trails.
+//!
+//! Grayscale frames are filtered at the native resolution before the
+//! upscale (a quarter of the pixels), color frames after gamma; a color
+//! pixel moves when any of its channels does.
+//!
+//! A spatial filter ([`SpatialDenoise`]) smooths each frame on its own,
+//! for noise the running average can't reach (moving areas, or a first
+//! frame): a 3x3 median, which removes hot pixels and speckle, or a 3x3
+//! bilateral filter, which averages neighbours weighted by how close their
+//! values are, so edges survive. It runs on color frames right after
+//! demosaic (linear, before white balance) and on grayscale frames at the
+//! native resolution, before the temporal filter.
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Strengths accepted (the weight of the running average for still pixels)
+pub const STRENGTHS: std::ops::RangeInclusive<f32> = 0.0..=0.95;
+
+/// Motion thresholds accepted, in 8-bit levels
+pub const THRESHOLDS: std::ops::RangeInclusive<u8> = 1..=128;
+
+/// Temporal denoise settings (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct Denoise {
+    /// Weight of the running average for still pixels (0 disables)
+    pub strength: f32,
+    /// Difference from the average, in 8-bit levels, above which a pixel
+    /// counts as moving and isn't averaged
+    pub threshold: u8,
+}
+
+impl Default for Denoise {
+    fn default() -> Self {
+        Self { strength: 0.0, threshold: 24 }
+    }
+}
+
+impl Denoise {
+    pub fn validate(&self) -> Result<(), String> {
+        if !STRENGTHS.contains(&self.strength) {
+            return Err(format!("strength must be {}-{}", STRENGTHS.start(), STRENGTHS.end()));
+        }
+        if !THRESHOLDS.contains(&self.threshold) {
+            return Err(format!("threshold must be {}-{}", THRESHOLDS.start(), THRESHOLDS.end()));
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.strength > 0.0
+    }
+}
+
+/// Running average of the frames so far
+#[derive(Debug, Default)]
+pub struct TemporalDenoiser {
+    history: Vec<u16>,
+    channels: usize,
+}
+
+impl TemporalDenoiser {
+    /// Forget the history (the next frame starts a new average)
+    pub fn reset(&mut self) {
+        self.history = Vec::new();
+    }
+
+    /// Blend a frame of interleaved `channels`-channel pixels, `width`
+    /// pixels to a row, into the running average and replace it with the
+    /// result (rows are processed in parallel on the current rayon pool).
+    /// A frame of another size or layout than the last starts over.
+    pub fn apply(&mut self, frame: &mut [u8], width: usize, channels: usize, settings: &Denoise) {
+        if self.history.len() != frame.len() || self.channels != channels {
+            self.history = frame.iter().map(|&v| (v as u16) << 8).collect();
+            self.channels = channels;
+            return;
+        }
+        // Weight of the average for each difference (in levels) from it
+        let threshold = settings.threshold as f32;
+        let weights: [u16; 256] = std::array::from_fn(|d| {
+            let still = (1.0 - d as f32 / threshold).max(0.0);
+            (settings.strength * still * 256.0).round() as u16
+        });
+        let stride = width * channels;
+        frame
+            .par_chunks_exact_mut(stride)
+            .zip(self.history.par_chunks_exact_mut(stride))
+            .for_each(|(row, history)| {
+                for (pixel, average) in row.chunks_exact_mut(channels).zip(history.chunks_exact_mut(channels)) {
+                    let moved = pixel
+                        .iter()
+                        .zip(average.iter())
+                        .map(|(&v, &a)| ((v as i32) - ((a as i32 + 128) >> 8)).unsigned_abs())
+                        .max()
+                        .unwrap_or(0);
+                    let weight = weights[moved.min(255) as usize] as u32;
+                    for (v, a) in pixel.iter_mut().zip(average.iter_mut()) {
+                        let blended = (*a as u32 * weight + ((*v as u32) << 8) * (256 - weight)) >> 8;
+                        *a = blended as u16;
+                        *v = ((blended + 128) >> 8).min(255) as u8;
+                    }
+                }
+            });
+    }
+}
+
+/// Spatial filter of the [`SpatialDenoise`] stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SpatialMethod {
+    #[default]
+    Off,
+    /// 3x3 median per channel
+    Median,
+    /// 3x3 edge-preserving weighted average
+    Bilateral,
+}
+
+impl SpatialMethod {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "off" | "none" => Some(SpatialMethod::Off),
+            "median" => Some(SpatialMethod::Median),
+            "bilateral" => Some(SpatialMethod::Bilateral),
+            _ => None,
+        }
+    }
+}
+
+/// Spatial denoise settings (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct SpatialDenoise {
+    pub method: SpatialMethod,
+    /// 0.0-1.0: for median the share of the filtered value blended in, for
+    /// bilateral how large a difference still counts as noise
+    pub strength: f32,
+}
+
+impl Default for SpatialDenoise {
+    fn default() -> Self {
+        Self { method: SpatialMethod::Off, strength: 0.5 }
+    }
+}
+
+/// Range sigma of the bilateral filter at strength 0 and 1, in 8-bit levels
+/// (summed over the channels of a color pixel)
+const BILATERAL_SIGMA: (f32, f32) = (2.0, 40.0);
+
+impl SpatialDenoise {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.strength) {
+            return Err("strength must be 0-1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.method != SpatialMethod::Off && self.strength > 0.0
+    }
+
+    /// Filter a frame of interleaved `channels`-channel pixels, `width`
+    /// pixels to a row, in place (rows in parallel on the current rayon
+    /// pool)
+    pub fn apply(&self, frame: &mut [u8], width: usize, channels: usize) {
+        let stride = width * channels;
+        if !self.is_enabled() || stride == 0 || frame.len() < stride {
+            return;
+        }
+        let height = frame.len() / stride;
+        let source = frame.to_vec();
+        // Offset of the neighbour at (dx, dy) of column x in row y, clamped
+        // to the frame
+        let at = |x: usize, y: usize, dx: isize, dy: isize| {
+            let nx = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+            let ny = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+            ny * stride + nx * channels
+        };
+        match self.method {
+            SpatialMethod::Off => {}
+            SpatialMethod::Median => {
+                let blend = (self.strength * 256.0).round() as i32;
+                frame.par_chunks_exact_mut(stride).enumerate().for_each(|(y, row)| {
+                    for x in 0..width {
+                        for c in 0..channels {
+                            let mut window = [0u8; 9];
+                            for (i, v) in window.iter_mut().enumerate() {
+                                *v = source[at(x, y, i as isize % 3 - 1, i as isize / 3 - 1) + c];
+                            }
+                            window.sort_unstable();
+                            let v = row[x * channels + c] as i32;
+                            row[x * channels + c] = (v + ((window[4] as i32 - v) * blend + 128) / 256) as u8;
+                        }
+                    }
+                });
+            }
+            SpatialMethod::Bilateral => {
+                let (low, high) = BILATERAL_SIGMA;
+                let sigma = (low + self.strength * (high - low)) * channels as f32;
+                // Weights by summed absolute difference, times the spatial
+                // weight of edge (1 px) and corner (1.4 px) neighbours
+                let range: Vec<f32> =
+                    (0..=255 * channels).map(|d| (-(d as f32 * d as f32) / (2.0 * sigma * sigma)).exp()).collect();
+                let spatial: [f32; 3] = std::array::from_fn(|d| (-(d as f32) / 2.0).exp());
+                frame.par_chunks_exact_mut(stride).enumerate().for_each(|(y, row)| {
+                    for x in 0..width {
+                        let centre = &source[y * stride + x * channels..][..channels];
+                        let mut sums = [0f32; 3];
+                        let mut total = 0.0;
+                        for dy in -1..=1 {
+                            for dx in -1..=1 {
+                                let neighbour = &source[at(x, y, dx, dy)..][..channels];
+                                let d: usize =
+                                    centre.iter().zip(neighbour).map(|(&a, &b)| a.abs_diff(b) as usize).sum();
+                                let weight = range[d] * spatial[(dx * dx + dy * dy) as usize];
+                                for (sum, &v) in sums.iter_mut().zip(neighbour) {
+                                    *sum += weight * v as f32;
+                                }
+                                total += weight;
+                            }
+                        }
+                        for (out, sum) in row[x * channels..(x + 1) * channels].iter_mut().zip(sums) {
+                            *out = (sum / total).round() as u8;
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
@@ -1,17 +1,214 @@
 //! YOLO Object Detection Module
 //!
-//! Communicates with Python RKNN-Lite subprocess for NPU inference
+//! Runs inference on a dedicated thread through one of several backends:
+//! an in-process RKNN model on the NPU (`rknn` feature), the Python
+//! RKNN-Lite subprocess, or an in-process ONNX model on the CPU (`onnx`
+//! feature). Each runs an object (YOLO) model or, for doorbell-style use, a
+//! face (SCRFD) model whose detections carry the class `face` and five
+//! landmarks. In-process backends take the frame's pixels when the caller
+//! has them; the subprocess is sent the JPEG.
 
+use crate::annotation::{AnnotationStyle, LabelPosition};
+use crate::zones::Zone;
 use anyhow::{Context, Result};
+use image::DynamicImage;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::io::{BufRead, BufReader, Write};
-use std::process::{Command, Stdio};
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Python detector script run as the inference subprocess
+pub const DETECTOR_SCRIPT: &str = "/home/angelo/imx415_streamer/yolo_detector.py";
+
+/// Environment variable telling the detector script which .rknn model to load
+/// (unset: the script's built-in default)
+pub const DETECTOR_MODEL_ENV: &str = "YOLO_MODEL";
+
+/// Environment variable telling the detector script which kind of model it
+/// runs (`objects` or `faces`)
+pub const DETECTOR_TASK_ENV: &str = "DETECTOR_TASK";
+
+/// Default RKNN models for the in-process NPU backend (the detector
+/// script's defaults)
+#[cfg(feature = "rknn")]
+pub const DEFAULT_RKNN_MODEL: &str = "/home/angelo/imx415_streamer/models/yolov5s-640-640.rknn";
+#[cfg(feature = "rknn")]
+pub const DEFAULT_FACE_RKNN_MODEL: &str = "/home/angelo/imx415_streamer/models/scrfd_500m-640-640.rknn";
+
+/// Default ONNX model for the CPU backend
+pub const DEFAULT_ONNX_MODEL: &str = "/home/angelo/imx415_streamer/yolov8n.onnx";
+
+/// Default ONNX model for the CPU backend in face mode
+pub const DEFAULT_FACE_ONNX_MODEL: &str = "/home/angelo/imx415_streamer/models/scrfd_500m.onnx";
+
+/// Default (square) model input size for the CPU backend; kept low for speed
+pub const DEFAULT_ONNX_INPUT_SIZE: u32 = 320;
+
+/// Default model directory, next to the detector script
+pub const DEFAULT_MODELS_DIR: &str = "/home/angelo/imx415_streamer/models";
+
+/// Default model upload size limit (MiB)
+pub const DEFAULT_MAX_MODEL_MB: u64 = 256;
+
+/// How long a backend may take to load its model before we give up on it
+const BACKEND_STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the warm-up inference may take; below the API request timeout
+/// so /detect/on can report it
+pub const WARM_UP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Size of the generated warm-up image
+const WARM_UP_IMAGE_SIZE: (u32, u32) = (640, 480);
+
+/// Available inference backends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// In-process RKNN runtime on the NPU (`rknn` feature)
+    Rknn,
+    /// Python RKNN-Lite script on the NPU
+    Subprocess,
+    /// In-process ONNX model on the CPU (`onnx` feature)
+    OnnxCpu,
+}
+
+impl BackendKind {
+    /// Default selection order
+    pub const DEFAULT_ORDER: [BackendKind; 3] = [BackendKind::Rknn, BackendKind::Subprocess, BackendKind::OnnxCpu];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "rknn" | "npu" => Some(Self::Rknn),
+            "subprocess" => Some(Self::Subprocess),
+            "onnx-cpu" | "onnx" => Some(Self::OnnxCpu),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rknn => "rknn",
+            Self::Subprocess => "subprocess",
+            Self::OnnxCpu => "onnx-cpu",
+        }
+    }
+
+    /// Whether inference runs on the (shared) CPU rather than the NPU
+    pub fn is_cpu(&self) -> bool {
+        matches!(self, Self::OnnxCpu)
+    }
+}
+
+/// What the detector model finds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionTask {
+    /// YOLO object classes
+    #[default]
+    Objects,
+    /// SCRFD faces with landmarks only (lighter, for doorbell use)
+    Faces,
+}
+
+impl DetectionTask {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "objects" => Some(Self::Objects),
+            "faces" => Some(Self::Faces),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Objects => "objects",
+            Self::Faces => "faces",
+        }
+    }
+}
+
+/// Backend selection and model settings
+#[derive(Debug, Clone)]
+pub struct DetectorConfig {
+    /// Backends tried in order; the first that starts is used
+    pub backends: Vec<BackendKind>,
+    /// Model kind, which decides the ONNX post-processing
+    pub task: DetectionTask,
+    pub onnx_model: String,
+    pub onnx_input_size: u32,
+    /// RKNN model for the NPU backends (None: the per-task default)
+    pub rknn_model: Option<String>,
+    pub filter: DetectionFilter,
+    pub zones: Vec<Zone>,
+    /// Where uploaded models are stored
+    pub models_dir: PathBuf,
+    pub max_model_bytes: u64,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        Self {
+            backends: BackendKind::DEFAULT_ORDER.to_vec(),
+            task: DetectionTask::default(),
+            onnx_model: DEFAULT_ONNX_MODEL.to_string(),
+            onnx_input_size: DEFAULT_ONNX_INPUT_SIZE,
+            rknn_model: None,
+            filter: DetectionFilter::default(),
+            zones: Vec::new(),
+            models_dir: PathBuf::from(DEFAULT_MODELS_DIR),
+            max_model_bytes: DEFAULT_MAX_MODEL_MB * 1024 * 1024,
+        }
+    }
+}
+
+impl DetectorConfig {
+    /// Names of the configured models that live in the models directory
+    pub fn active_models(&self) -> Vec<String> {
+        [Some(self.onnx_model.as_str()), self.rknn_model.as_deref()]
+            .into_iter()
+            .flatten()
+            .map(Path::new)
+            .filter(|path| path.parent() == Some(self.models_dir.as_path()))
+            .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()))
+            .collect()
+    }
+}
+
+/// A frame submitted for detection: its JPEG, and its pixels if the
+/// caller has them
+pub struct FrameInput {
+    pub jpeg: Vec<u8>,
+    /// Only read by the in-process backends
+    #[cfg_attr(not(any(feature = "onnx", feature = "rknn")), allow(dead_code))]
+    pub pixels: Option<Arc<DynamicImage>>,
+}
+
+impl FrameInput {
+    /// The frame's pixels, decoded from the JPEG if they weren't given
+    #[cfg(any(feature = "onnx", feature = "rknn"))]
+    pub fn image(&self) -> Result<std::borrow::Cow<'_, DynamicImage>> {
+        Ok(match self.pixels {
+            Some(ref pixels) => std::borrow::Cow::Borrowed(pixels),
+            None => std::borrow::Cow::Owned(image::load_from_memory(&self.jpeg).context("Failed to decode frame")?),
+        })
+    }
+}
+
+/// One inference engine, owned by the detector thread
+pub(crate) trait Backend {
+    /// Run detection on a frame
+    fn infer(&mut self, frame: &FrameInput) -> Result<DetectionResult>;
+}
 
 /// Bounding box coordinates
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
 pub struct BBox {
     pub x1: i32,
     pub y1: i32,
@@ -19,185 +216,779 @@ pub struct BBox {
     pub y2: i32,
 }
 
+/// Point in frame pixels
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+}
+
 /// Single detection result
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
 pub struct Detection {
+    /// Class name as reported by the model
     pub class: String,
     pub confidence: f32,
     pub bbox: BBox,
+    /// Display name from the annotation label map, if the class has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Keypoints, for models that predict them (face models: eyes, nose
+    /// tip, mouth corners)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub landmarks: Vec<Point>,
+    /// Zones the bbox centre lies in (see `zones`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub zones: Vec<String>,
+}
+
+impl Detection {
+    /// Name drawn on annotated frames
+    pub fn display_name(&self) -> &str {
+        self.label.as_deref().unwrap_or(&self.class)
+    }
 }
 
 /// Detection result for a frame
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
 pub struct DetectionResult {
     pub width: Option<u32>,
     pub height: Option<u32>,
     pub detections: Vec<Detection>,
     #[serde(default)]
     pub error: Option<String>,
+    /// Capture sequence number of the frame these detections belong to
+    #[serde(default)]
+    pub frame_seq: Option<u64>,
+}
+
+/// Key of the detection filter in the settings state file
+pub const FILTER_STATE_KEY: &str = "detection_filter";
+
+/// Default filter thresholds: the backends' own object thresholds, so the
+/// default filter changes nothing
+pub const DEFAULT_CONFIDENCE_THRESHOLD: f32 = 0.25;
+pub const DEFAULT_IOU_THRESHOLD: f32 = 0.45;
+
+/// Filtering applied to every backend result before it is stored. The
+/// backends already drop candidates below their built-in thresholds, so
+/// these can only make detection stricter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct DetectionFilter {
+    /// Minimum confidence (0-1)
+    pub confidence_threshold: f32,
+    /// Same-class boxes overlapping more than this (IoU, 0-1) are suppressed
+    pub iou_threshold: f32,
+    /// Model classes to keep, e.g. `["person", "car"]` (empty: all)
+    pub classes: Vec<String>,
 }
 
-impl Default for DetectionResult {
+impl Default for DetectionFilter {
     fn default() -> Self {
         Self {
-            width: None,
-            height: None,
-            detections: Vec::new(),
-            error: None,
+            confidence_threshold: DEFAULT_CONFIDENCE_THRESHOLD,
+            iou_threshold: DEFAULT_IOU_THRESHOLD,
+            classes: Vec::new(),
         }
     }
 }
 
-/// Request to detector thread
-enum DetectorRequest {
-    Detect(Vec<u8>),
-    Shutdown,
+impl DetectionFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.confidence_threshold) {
+            return Err("confidence_threshold must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.iou_threshold) {
+            return Err("iou_threshold must be between 0 and 1".to_string());
+        }
+        if self.classes.iter().any(|class| class.trim().is_empty()) {
+            return Err("Empty class name in classes".to_string());
+        }
+        Ok(())
+    }
+
+    /// Drop detections below the threshold or outside the class list, then
+    /// suppress overlaps
+    pub fn apply(&self, result: &mut DetectionResult) {
+        let detections = std::mem::take(&mut result.detections)
+            .into_iter()
+            .filter(|d| d.confidence >= self.confidence_threshold)
+            .filter(|d| self.classes.is_empty() || self.classes.contains(&d.class))
+            .collect();
+        result.detections = nms(detections, self.iou_threshold);
+    }
+}
+
+fn iou(a: &BBox, b: &BBox) -> f32 {
+    let area = |r: &BBox| ((r.x2 - r.x1).max(0) * (r.y2 - r.y1).max(0)) as f32;
+    let inter = BBox {
+        x1: a.x1.max(b.x1),
+        y1: a.y1.max(b.y1),
+        x2: a.x2.min(b.x2),
+        y2: a.y2.min(b.y2),
+    };
+    let inter_area = area(&inter);
+    let union = area(a) + area(b) - inter_area;
+    if union <= 0.0 { 0.0 } else { inter_area / union }
+}
+
+/// Greedy per-class non-maximum suppression, highest confidence first
+pub fn nms(mut detections: Vec<Detection>, iou_threshold: f32) -> Vec<Detection> {
+    detections.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    let mut kept: Vec<Detection> = Vec::new();
+    for det in detections {
+        let suppressed = kept
+            .iter()
+            .any(|k| k.class == det.class && iou(&k.bbox, &det.bbox) > iou_threshold);
+        if !suppressed {
+            kept.push(det);
+        }
+    }
+    kept
+}
+
+/// Latest-wins submission slot shared with the detector thread.
+///
+/// Holds at most one pending frame: submitting while a frame is still
+/// waiting replaces it, so the detector always works on the newest frame
+/// and queue depth (and therefore latency) is bounded by construction.
+#[derive(Default)]
+struct FrameSlot {
+    pending: Option<(u64, FrameInput)>,
+    /// Warm-up image and where to send its result; runs before any frame
+    warm_up: Option<(FrameInput, mpsc::Sender<Result<DetectionResult>>)>,
+    shutdown: bool,
+}
+
+/// Work for the detector thread
+enum Work {
+    Frame(u64, FrameInput),
+    WarmUp(FrameInput, mpsc::Sender<Result<DetectionResult>>),
+}
+
+#[derive(Default)]
+struct SubmitQueue {
+    slot: Mutex<FrameSlot>,
+    ready: Condvar,
+}
+
+/// Window over which the achieved detection rate is measured
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Detector throughput counters
+#[derive(Default)]
+struct DetectorCounters {
+    submitted: AtomicU64,
+    dropped: AtomicU64,
+    processed: AtomicU64,
+    last_submitted_seq: AtomicU64,
+    last_processed_seq: AtomicU64,
+    /// Duration of the most recent inference, microseconds
+    last_inference_us: AtomicU64,
+    /// Size of the frame the backend is currently working on
+    in_flight_bytes: AtomicUsize,
+    /// Completion times within `RATE_WINDOW` (the newest is always kept)
+    completions: Mutex<VecDeque<Instant>>,
+    /// Why the detector thread stopped, if the backend failed
+    exit_error: Mutex<Option<String>>,
+}
+
+impl DetectorCounters {
+    fn record_completion(&self) {
+        if let Ok(mut completions) = self.completions.lock() {
+            let now = Instant::now();
+            completions.push_back(now);
+            while completions
+                .front()
+                .is_some_and(|t| now.duration_since(*t) > RATE_WINDOW)
+            {
+                completions.pop_front();
+            }
+        }
+    }
+
+    /// Detections completed per second over the last `RATE_WINDOW`
+    fn rate_per_sec(&self) -> f64 {
+        let Ok(completions) = self.completions.lock() else {
+            return 0.0;
+        };
+        let now = Instant::now();
+        let recent = completions
+            .iter()
+            .filter(|t| now.duration_since(**t) <= RATE_WINDOW)
+            .count();
+        recent as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn last_completion(&self) -> Option<Instant> {
+        self.completions.lock().ok()?.back().copied()
+    }
+
+    fn set_exit_error(&self, error: &anyhow::Error) {
+        if let Ok(mut exit_error) = self.exit_error.lock() {
+            *exit_error = Some(format!("{:#}", error));
+        }
+    }
+}
+
+/// Snapshot of detector queue statistics
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DetectorStats {
+    pub submitted: u64,
+    pub dropped: u64,
+    pub processed: u64,
+    pub last_submitted_seq: u64,
+    pub last_processed_seq: u64,
+    /// Achieved detections per second (recent window)
+    pub rate_per_sec: f64,
+    /// Duration of the most recent inference
+    pub last_inference_ms: f64,
+}
+
+/// Latest detection result plus a wakeup for callers waiting on a frame
+#[derive(Default)]
+struct ResultSlot {
+    result: Mutex<DetectionResult>,
+    updated: Condvar,
+    /// Applied to each result before it is stored
+    filter: Mutex<DetectionFilter>,
+    /// Tagged onto each result after filtering
+    zones: Mutex<Vec<Zone>>,
+}
+
+/// The detector's submission queue and results, shared with the detector
+/// thread; cloned out of the detector so a caller can wait for a result
+/// without holding the lock the detector lives behind
+#[derive(Clone)]
+pub struct DetectorHandle {
+    queue: Arc<SubmitQueue>,
+    counters: Arc<DetectorCounters>,
+    last_result: Arc<ResultSlot>,
+}
+
+impl DetectorHandle {
+    /// Submit frame for detection (non-blocking), with its pixels if they
+    /// are at hand
+    ///
+    /// If the previous submission has not been picked up yet it is
+    /// replaced and counted as dropped.
+    pub fn detect(&self, frame_seq: u64, jpeg_data: Vec<u8>, pixels: Option<Arc<DynamicImage>>) -> Result<()> {
+        let mut slot = self
+            .queue
+            .slot
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Detector queue poisoned"))?;
+        if slot.shutdown {
+            anyhow::bail!("Detector is shut down");
+        }
+        if slot.pending.replace((frame_seq, FrameInput { jpeg: jpeg_data, pixels })).is_some() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counters.submitted.fetch_add(1, Ordering::Relaxed);
+        self.counters.last_submitted_seq.store(frame_seq, Ordering::Relaxed);
+        self.queue.ready.notify_one();
+        Ok(())
+    }
+
+    /// Submit a frame and block until its result (or a newer one) is available
+    pub fn detect_blocking(&self, frame_seq: u64, jpeg_data: Vec<u8>, timeout: Duration) -> Result<DetectionResult> {
+        self.detect(frame_seq, jpeg_data, None)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut result = self
+            .last_result
+            .result
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Detector result poisoned"))?;
+        // A later submission may replace ours in the slot; its result is newer, so accept it
+        while result.frame_seq.is_none_or(|seq| seq < frame_seq) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                anyhow::bail!("Detection timed out after {:?}", timeout);
+            }
+            result = self
+                .last_result
+                .updated
+                .wait_timeout(result, remaining)
+                .map_err(|_| anyhow::anyhow!("Detector result poisoned"))?
+                .0;
+        }
+        Ok(result.clone())
+    }
+
+    /// Run a generated test image through the backend and check that a
+    /// well-formed result comes back within `timeout`; returns the inference
+    /// time, which also becomes the inference-time estimate. Frames
+    /// submitted meanwhile wait until the warm-up is done.
+    pub fn warm_up(&self, timeout: Duration) -> Result<Duration> {
+        let image = FrameInput { jpeg: warm_up_image()?, pixels: None };
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut slot = self
+                .queue
+                .slot
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Detector queue poisoned"))?;
+            if slot.shutdown {
+                anyhow::bail!("Detector is shut down");
+            }
+            slot.warm_up = Some((image, tx));
+        }
+        self.queue.ready.notify_one();
+
+        let started = Instant::now();
+        let result = match rx.recv_timeout(timeout) {
+            Ok(result) => result?,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                anyhow::bail!("Detector did not answer the warm-up within {:?}", timeout)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("Detector stopped during warm-up"),
+        };
+        let elapsed = started.elapsed();
+        if let Some(error) = result.error {
+            anyhow::bail!("{}", error);
+        }
+        let (width, height) = WARM_UP_IMAGE_SIZE;
+        if result.width.is_some_and(|w| w != width) || result.height.is_some_and(|h| h != height) {
+            anyhow::bail!(
+                "Detector reported a {}x{} image for the {}x{} warm-up image",
+                result.width.unwrap_or(0),
+                result.height.unwrap_or(0),
+                width,
+                height
+            );
+        }
+        Ok(elapsed)
+    }
 }
 
 /// YOLO Detector interface (thread-safe)
 pub struct YoloDetector {
-    request_tx: Sender<DetectorRequest>,
-    last_result: Arc<Mutex<DetectionResult>>,
-    _handle: thread::JoinHandle<()>,
+    backend: BackendKind,
+    submit: DetectorHandle,
+    thread: thread::JoinHandle<()>,
 }
 
-// Implement Send + Sync for YoloDetector
-unsafe impl Send for YoloDetector {}
-unsafe impl Sync for YoloDetector {}
-
 impl YoloDetector {
-    /// Create and start the detector
-    pub fn new() -> Result<Self> {
-        let (request_tx, request_rx) = mpsc::channel::<DetectorRequest>();
-        let last_result = Arc::new(Mutex::new(DetectionResult::default()));
-        let result_clone = last_result.clone();
-
-        // Spawn detector thread
-        let handle = thread::spawn(move || {
-            if let Err(e) = detector_thread(request_rx, result_clone) {
-                tracing::error!("Detector thread error: {}", e);
+    /// Start the first backend in `config.backends` that comes up
+    pub fn new(config: &DetectorConfig) -> Result<Self> {
+        let mut errors = Vec::new();
+        for &kind in &config.backends {
+            match Self::with_backend(kind, config) {
+                Ok(detector) => return Ok(detector),
+                Err(e) => {
+                    tracing::info!("Detector backend {} unavailable: {:#}", kind.name(), e);
+                    errors.push(format!("{}: {:#}", kind.name(), e));
+                }
             }
+        }
+        if errors.is_empty() {
+            anyhow::bail!("No detector backends configured");
+        }
+        anyhow::bail!("No detector backend available ({})", errors.join("; "))
+    }
+
+    /// Start a specific backend, waiting until its model is loaded
+    pub fn with_backend(kind: BackendKind, config: &DetectorConfig) -> Result<Self> {
+        let start_config = config.clone();
+        Self::spawn(kind, config, move || start_backend(kind, &start_config))
+    }
+
+    /// Run the detector thread on the backend `start` creates (on that
+    /// thread), waiting until it is up
+    fn spawn(
+        kind: BackendKind,
+        config: &DetectorConfig,
+        start: impl FnOnce() -> Result<Box<dyn Backend>> + Send + 'static,
+    ) -> Result<Self> {
+        let queue = Arc::new(SubmitQueue::default());
+        let counters = Arc::new(DetectorCounters::default());
+        let last_result = Arc::new(ResultSlot {
+            filter: Mutex::new(config.filter.clone()),
+            zones: Mutex::new(config.zones.clone()),
+            ..Default::default()
         });
+        let (started_tx, started_rx) = mpsc::channel();
 
-        Ok(Self {
-            request_tx,
-            last_result,
-            _handle: handle,
-        })
+        // Spawn detector thread; the backend is created on it
+        let thread = {
+            let queue = queue.clone();
+            let counters = counters.clone();
+            let last_result = last_result.clone();
+            thread::spawn(move || {
+                let backend = match start() {
+                    Ok(backend) => {
+                        let _ = started_tx.send(Ok(()));
+                        backend
+                    }
+                    Err(e) => {
+                        let _ = started_tx.send(Err(e));
+                        return;
+                    }
+                };
+                detector_thread(backend, queue, counters, last_result);
+            })
+        };
+
+        match started_rx.recv_timeout(BACKEND_STARTUP_TIMEOUT) {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                // The thread keeps loading; mark shutdown so it exits once it's done
+                if let Ok(mut slot) = queue.slot.lock() {
+                    slot.shutdown = true;
+                }
+                anyhow::bail!("Backend did not start within {:?}", BACKEND_STARTUP_TIMEOUT);
+            }
+        }
+        tracing::info!("Detector backend: {}", kind.name());
+
+        Ok(Self { backend: kind, submit: DetectorHandle { queue, counters, last_result }, thread })
     }
 
-    /// Submit frame for detection (non-blocking)
-    pub fn detect(&self, jpeg_data: Vec<u8>) -> Result<()> {
-        self.request_tx
-            .send(DetectorRequest::Detect(jpeg_data))
-            .context("Failed to send detection request")?;
-        Ok(())
+    /// Active inference backend
+    pub fn backend(&self) -> BackendKind {
+        self.backend
+    }
+
+    /// Smallest detection interval (in frames) this backend can sustain
+    ///
+    /// The NPU backends keep up with the configured cadence. A CPU backend
+    /// competes with capture for cores, so if inference takes longer than
+    /// the configured number of frame periods we back off accordingly.
+    pub fn min_interval(&self, frame_period: Duration) -> u32 {
+        if !self.backend.is_cpu() {
+            return 1;
+        }
+        let inference_us = self.submit.counters.last_inference_us.load(Ordering::Relaxed);
+        let period_us = frame_period.as_micros().max(1) as u64;
+        inference_us.div_ceil(period_us).max(1) as u32
+    }
+
+    /// Submit frame for detection (non-blocking), with its pixels if they
+    /// are at hand (see `DetectorHandle::detect`)
+    pub fn detect(&self, frame_seq: u64, jpeg_data: Vec<u8>, pixels: Option<Arc<DynamicImage>>) -> Result<()> {
+        self.submit.detect(frame_seq, jpeg_data, pixels)
+    }
+
+    /// A handle to submit frames and wait for results with, for callers
+    /// that mustn't hold on to the detector while they wait
+    pub fn handle(&self) -> DetectorHandle {
+        self.submit.clone()
+    }
+
+    /// Bytes of frame data held for the detector (pending and in-flight
+    /// frames; their pixels are shared with the pipeline's)
+    pub fn input_bytes(&self) -> usize {
+        let pending = self
+            .submit
+            .queue
+            .slot
+            .lock()
+            .map(|slot| slot.pending.as_ref().map_or(0, |(_, frame)| frame.jpeg.len()))
+            .unwrap_or(0);
+        pending + self.submit.counters.in_flight_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Whether the detector thread (and its subprocess) is still alive
+    pub fn is_running(&self) -> bool {
+        !self.thread.is_finished()
+    }
+
+    /// The backend error that stopped the detector thread
+    pub fn exit_error(&self) -> Option<String> {
+        self.submit.counters.exit_error.lock().ok()?.clone()
+    }
+
+    /// Time since the last completed detection
+    pub fn since_last_inference(&self) -> Option<Duration> {
+        Some(self.submit.counters.last_completion()?.elapsed())
+    }
+
+    /// Replace the filter applied to new results
+    pub fn set_filter(&self, filter: DetectionFilter) {
+        if let Ok(mut current) = self.submit.last_result.filter.lock() {
+            *current = filter;
+        }
+    }
+
+    /// Replace the zones tagged onto new results
+    pub fn set_zones(&self, zones: Vec<Zone>) {
+        if let Ok(mut current) = self.submit.last_result.zones.lock() {
+            *current = zones;
+        }
     }
 
     /// Get latest detection result (non-blocking)
     pub fn get_last_result(&self) -> DetectionResult {
-        self.last_result.lock().unwrap().clone()
+        self.submit.last_result.result.lock().unwrap().clone()
+    }
+
+    /// Submit a frame and block until its result (or a newer one) is available
+    pub fn detect_blocking(&self, frame_seq: u64, jpeg_data: Vec<u8>, timeout: Duration) -> Result<DetectionResult> {
+        self.submit.detect_blocking(frame_seq, jpeg_data, timeout)
+    }
+
+    /// Check the backend with a test image (see `DetectorHandle::warm_up`)
+    pub fn warm_up(&self, timeout: Duration) -> Result<Duration> {
+        self.submit.warm_up(timeout)
+    }
+
+    /// Queue statistics (submitted, dropped, processed, sequence numbers)
+    pub fn stats(&self) -> DetectorStats {
+        DetectorStats {
+            submitted: self.submit.counters.submitted.load(Ordering::Relaxed),
+            dropped: self.submit.counters.dropped.load(Ordering::Relaxed),
+            processed: self.submit.counters.processed.load(Ordering::Relaxed),
+            last_submitted_seq: self.submit.counters.last_submitted_seq.load(Ordering::Relaxed),
+            last_processed_seq: self.submit.counters.last_processed_seq.load(Ordering::Relaxed),
+            rate_per_sec: self.submit.counters.rate_per_sec(),
+            last_inference_ms: self.submit.counters.last_inference_us.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
     }
 }
 
 impl Drop for YoloDetector {
     fn drop(&mut self) {
-        let _ = self.request_tx.send(DetectorRequest::Shutdown);
+        if let Ok(mut slot) = self.submit.queue.slot.lock() {
+            slot.shutdown = true;
+            slot.pending = None;
+        }
+        self.submit.queue.ready.notify_all();
     }
 }
 
-/// Detector thread - manages Python subprocess
-fn detector_thread(
-    request_rx: Receiver<DetectorRequest>,
-    last_result: Arc<Mutex<DetectionResult>>,
-) -> Result<()> {
-    tracing::info!("Starting YOLO detector subprocess...");
-
-    // Spawn Python process
-    let mut child = Command::new("python3")
-        .arg("/home/angelo/imx415_streamer/yolo_detector.py")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .context("Failed to spawn YOLO detector")?;
-
-    let mut stdin = child.stdin.take().context("No stdin")?;
-    let stdout = child.stdout.take().context("No stdout")?;
-    let mut reader = BufReader::new(stdout);
-
-    // Wait for READY signal
-    let mut ready_line = String::new();
-    reader.read_line(&mut ready_line)?;
-    if !ready_line.trim().eq("READY") {
-        anyhow::bail!("Detector did not signal READY: {}", ready_line);
-    }
-    tracing::info!("YOLO detector ready!");
-
-    // Process requests
-    for request in request_rx {
-        match request {
-            DetectorRequest::Detect(jpeg_data) => {
-                // Send length prefix + data
-                let len = jpeg_data.len() as u32;
-                if stdin.write_all(&len.to_le_bytes()).is_err() {
-                    tracing::error!("Failed to write length to detector");
-                    break;
-                }
-                if stdin.write_all(&jpeg_data).is_err() {
-                    tracing::error!("Failed to write data to detector");
-                    break;
-                }
-                if stdin.flush().is_err() {
-                    tracing::error!("Failed to flush detector stdin");
-                    break;
-                }
+/// Block until a warm-up or frame is pending or shutdown is requested
+fn next_work(queue: &SubmitQueue) -> Option<Work> {
+    let mut slot = queue.slot.lock().ok()?;
+    loop {
+        if slot.shutdown {
+            return None;
+        }
+        if let Some((image, reply)) = slot.warm_up.take() {
+            return Some(Work::WarmUp(image, reply));
+        }
+        if let Some((frame_seq, frame)) = slot.pending.take() {
+            return Some(Work::Frame(frame_seq, frame));
+        }
+        slot = queue.ready.wait(slot).ok()?;
+    }
+}
 
-                // Read JSON response
-                let mut response_line = String::new();
-                if reader.read_line(&mut response_line).is_err() {
-                    tracing::error!("Failed to read detector response");
-                    break;
-                }
+/// Python RKNN-Lite script speaking the length-prefixed JPEG / JSON-line protocol
+struct SubprocessBackend {
+    child: Child,
+    stdin: ChildStdin,
+    reader: BufReader<ChildStdout>,
+}
 
-                // Parse JSON and update shared result
-                match serde_json::from_str::<DetectionResult>(&response_line) {
-                    Ok(result) => {
-                        if let Ok(mut guard) = last_result.lock() {
-                            *guard = result;
-                        }
-                    }
-                    Err(e) => {
-                        tracing::warn!("Failed to parse detection result: {}", e);
-                        if let Ok(mut guard) = last_result.lock() {
-                            *guard = DetectionResult {
-                                error: Some(format!("Parse error: {}", e)),
-                                ..Default::default()
-                            };
-                        }
+impl SubprocessBackend {
+    fn start(model: Option<&str>, task: DetectionTask) -> Result<Self> {
+        tracing::info!(
+            "Starting YOLO detector subprocess ({}, {})...",
+            model.unwrap_or("default model"),
+            task.name()
+        );
+
+        // Spawn Python process
+        let mut command = Command::new("python3");
+        command
+            .arg(DETECTOR_SCRIPT)
+            .envs(model.map(|m| (DETECTOR_MODEL_ENV, m)))
+            .env(DETECTOR_TASK_ENV, task.name());
+        let backend = Self::spawn(command)?;
+        tracing::info!("YOLO detector ready!");
+        Ok(backend)
+    }
+
+    /// Run `command` as the detector and wait for its READY line
+    fn spawn(mut command: Command) -> Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .context("Failed to spawn YOLO detector")?;
+
+        let stdin = child.stdin.take().context("No stdin")?;
+        let stdout = child.stdout.take().context("No stdout")?;
+        let mut backend = Self {
+            child,
+            stdin,
+            reader: BufReader::new(stdout),
+        };
+
+        // Wait for READY signal
+        let mut ready_line = String::new();
+        backend.reader.read_line(&mut ready_line)?;
+        if !ready_line.trim().eq("READY") {
+            anyhow::bail!("Detector did not signal READY: {}", ready_line.trim());
+        }
+        Ok(backend)
+    }
+}
+
+impl Backend for SubprocessBackend {
+    fn infer(&mut self, frame: &FrameInput) -> Result<DetectionResult> {
+        let jpeg_data = &frame.jpeg;
+        // Send length prefix + data
+        let len = jpeg_data.len() as u32;
+        self.stdin
+            .write_all(&len.to_le_bytes())
+            .context("Failed to write length to detector")?;
+        self.stdin
+            .write_all(jpeg_data)
+            .context("Failed to write data to detector")?;
+        self.stdin.flush().context("Failed to flush detector stdin")?;
+
+        // Read JSON response
+        let mut response_line = String::new();
+        if self.reader.read_line(&mut response_line)? == 0 {
+            anyhow::bail!("Detector subprocess closed its output");
+        }
+
+        // A malformed line is reported in the result rather than ending the backend
+        Ok(serde_json::from_str::<DetectionResult>(&response_line).unwrap_or_else(|e| {
+            tracing::warn!("Failed to parse detection result: {}", e);
+            DetectionResult {
+                error: Some(format!("Parse error: {}", e)),
+                ..Default::default()
+            }
+        }))
+    }
+}
+
+impl Drop for SubprocessBackend {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn start_backend(kind: BackendKind, config: &DetectorConfig) -> Result<Box<dyn Backend>> {
+    match kind {
+        #[cfg(feature = "rknn")]
+        BackendKind::Rknn => {
+            let model = config.rknn_model.as_deref().unwrap_or(match config.task {
+                DetectionTask::Objects => DEFAULT_RKNN_MODEL,
+                DetectionTask::Faces => DEFAULT_FACE_RKNN_MODEL,
+            });
+            Ok(Box::new(crate::rknn_backend::RknnBackend::load(model, config.task)?))
+        }
+        #[cfg(not(feature = "rknn"))]
+        BackendKind::Rknn => anyhow::bail!("Built without the `rknn` feature"),
+        BackendKind::Subprocess => Ok(Box::new(SubprocessBackend::start(config.rknn_model.as_deref(), config.task)?)),
+        #[cfg(feature = "onnx")]
+        BackendKind::OnnxCpu => Ok(Box::new(crate::onnx_backend::OnnxBackend::load(
+            &config.onnx_model,
+            config.onnx_input_size,
+            config.task,
+        )?)),
+        #[cfg(not(feature = "onnx"))]
+        BackendKind::OnnxCpu => {
+            let _ = config;
+            anyhow::bail!("Built without the `onnx` feature")
+        }
+    }
+}
+
+/// Detector thread - feeds the most recent frame to the backend
+fn detector_thread(
+    mut backend: Box<dyn Backend>,
+    queue: Arc<SubmitQueue>,
+    counters: Arc<DetectorCounters>,
+    last_result: Arc<ResultSlot>,
+) {
+    // Process the most recent frame each time one is available
+    while let Some(work) = next_work(&queue) {
+        let (frame_seq, frame) = match work {
+            Work::Frame(frame_seq, frame) => (frame_seq, frame),
+            Work::WarmUp(image, reply) => {
+                let started = Instant::now();
+                let result = backend.infer(&image);
+                counters
+                    .last_inference_us
+                    .store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+                let failed = match result {
+                    Ok(_) => false,
+                    Err(ref e) => {
+                        tracing::error!("Detector backend failed during warm-up: {:#}", e);
+                        counters.set_exit_error(e);
+                        true
                     }
+                };
+                let _ = reply.send(result);
+                if failed {
+                    break;
                 }
+                continue;
             }
-            DetectorRequest::Shutdown => {
-                tracing::info!("Detector shutdown requested");
+        };
+        counters.in_flight_bytes.store(frame.jpeg.len(), Ordering::Relaxed);
+        let started = Instant::now();
+        let mut result = match backend.infer(&frame) {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::error!("Detector backend failed: {:#}", e);
+                counters.set_exit_error(&e);
                 break;
             }
+        };
+        counters
+            .last_inference_us
+            .store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        counters.in_flight_bytes.store(0, Ordering::Relaxed);
+
+        if let Ok(filter) = last_result.filter.lock() {
+            filter.apply(&mut result);
         }
+        if let Ok(zones) = last_result.zones.lock() {
+            crate::zones::tag(&zones, &mut result);
+        }
+        if let Ok(mut guard) = last_result.result.lock() {
+            *guard = DetectionResult {
+                frame_seq: Some(frame_seq),
+                ..result
+            };
+        }
+        counters.processed.fetch_add(1, Ordering::Relaxed);
+        counters.last_processed_seq.store(frame_seq, Ordering::Relaxed);
+        counters.record_completion();
+        last_result.updated.notify_all();
     }
 
-    // Cleanup
-    let _ = child.kill();
-    let _ = child.wait();
+    // Later submissions and warm-ups fail straight away instead of waiting
+    // for a thread that is gone
+    if let Ok(mut slot) = queue.slot.lock() {
+        slot.shutdown = true;
+        slot.pending = None;
+        slot.warm_up = None;
+    }
+    // Dropping the backend stops its subprocess, if any
+    drop(backend);
     tracing::info!("YOLO detector stopped");
+}
 
-    Ok(())
+/// Mid-grey gradient JPEG used to warm up backends
+fn warm_up_image() -> Result<Vec<u8>> {
+    let (width, height) = WARM_UP_IMAGE_SIZE;
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        let v = (64 + (x + y) * 128 / (width + height)) as u8;
+        image::Rgb([v, v, v])
+    });
+    crate::capture::encode_image_jpeg(&image::DynamicImage::ImageRgb8(image), 90)
 }
 
 /// Draw detection boxes on an image (modifies JPEG in-place would require re-encoding)
 /// Returns a new JPEG with boxes drawn
-pub fn draw_detections(jpeg_data: &[u8], detections: &[Detection]) -> Result<Vec<u8>> {
-    use image::codecs::jpeg::{JpegDecoder, JpegEncoder};
-    use image::{DynamicImage, Rgb};
+pub fn draw_detections(jpeg_data: &[u8], detections: &[Detection], style: &AnnotationStyle) -> Result<Vec<u8>> {
+    use image::codecs::jpeg::JpegDecoder;
+    use image::DynamicImage;
     use std::io::Cursor;
 
     if detections.is_empty() {
@@ -207,10 +998,19 @@ pub fn draw_detections(jpeg_data: &[u8], detections: &[Detection]) -> Result<Vec
     // Decode JPEG
     let decoder = JpegDecoder::new(Cursor::new(jpeg_data))?;
     let img = DynamicImage::from_decoder(decoder)?;
+    annotate_image(&img, detections, style)
+}
+
+/// `draw_detections` on already decoded pixels
+pub fn annotate_image(img: &image::DynamicImage, detections: &[Detection], style: &AnnotationStyle) -> Result<Vec<u8>> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::Rgb;
+
     let mut rgb_img = img.to_rgb8();
 
     // Colors
     let box_color = Rgb([255u8, 50u8, 50u8]); // Red box
+    let landmark_color = Rgb([50u8, 255u8, 50u8]); // Green landmark dots
     let label_bg = Rgb([255u8, 255u8, 255u8]); // White background
     let label_text = Rgb([200u8, 0u8, 0u8]); // Dark red text
     let thickness = 4;
@@ -267,13 +1067,35 @@ pub fn draw_detections(jpeg_data: &[u8], detections: &[Detection]) -> Result<Vec
             }
         }
 
-        // Draw label with white background above the box
-        let label = format!("{} {:.0}%", det.class, det.confidence * 100.0);
-        let char_width = 12u32;  // Bigger characters
-        let char_height = 24u32;
-        let label_width = (label.len() as u32 * char_width).min(rgb_img.width() - x1);
+        for point in &det.landmarks {
+            let (px, py) = (point.x.max(0) as u32, point.y.max(0) as u32);
+            for y in py.saturating_sub(thickness)..=py + thickness {
+                for x in px.saturating_sub(thickness)..=px + thickness {
+                    if x < rgb_img.width() && y < rgb_img.height() {
+                        rgb_img.put_pixel(x, y, landmark_color);
+                    }
+                }
+            }
+        }
+
+        // Draw label with white background above or below the box
+        let label = if style.show_confidence {
+            format!("{} {:.0}%", det.display_name(), det.confidence * 100.0)
+        } else {
+            det.display_name().to_string()
+        };
+        let scale = style.font_scale;
+        let char_width = 6 * scale;
+        let char_height = 12 * scale;
+        let label_width = (label.chars().count() as u32 * char_width).min(rgb_img.width() - x1);
         let label_height = char_height + 8; // Padding
-        let label_y = if y1 >= label_height { y1 - label_height } else { y2 + 4 };
+        let above = y1.checked_sub(label_height);
+        let below = Some(y2 + 4).filter(|y| y + label_height <= rgb_img.height());
+        let label_y = match style.label_position {
+            LabelPosition::Top => above.or(below),
+            LabelPosition::Bottom => below.or(above),
+        }
+        .unwrap_or(y2 + 4);
 
         // Draw white background for label
         for ly in 0..label_height {
@@ -289,7 +1111,7 @@ pub fn draw_detections(jpeg_data: &[u8], detections: &[Detection]) -> Result<Vec
         // Draw simple block letters for the label
         let text_y = label_y + 4;
         let text_x = x1 + 4;
-        draw_text(&mut rgb_img, &label, text_x, text_y, label_text, 2);
+        draw_text(&mut rgb_img, &label, text_x, text_y, label_text, scale);
     }
 
     // Re-encode to JPEG
@@ -305,8 +1127,10 @@ pub fn draw_detections(jpeg_data: &[u8], detections: &[Detection]) -> Result<Vec
     Ok(output)
 }
 
-/// Draw simple blocky text on image
-fn draw_text(img: &mut image::RgbImage, text: &str, x: u32, y: u32, color: image::Rgb<u8>, scale: u32) {
+/// Draw simple blocky text on image: 5x7 glyphs `scale` times enlarged, a
+/// character every `6 * scale` pixels (lower case; characters without a
+/// glyph are left blank)
+pub(crate) fn draw_text<I: image::GenericImage>(img: &mut I, text: &str, x: u32, y: u32, color: I::Pixel, scale: u32) {
     // Simple 5x7 font bitmaps for common characters
     let font: std::collections::HashMap<char, [[u8; 5]; 7]> = [
         ('0', [[0,1,1,1,0],[1,0,0,0,1],[1,0,0,1,1],[1,0,1,0,1],[1,1,0,0,1],[1,0,0,0,1],[0,1,1,1,0]]),
@@ -328,24 +1152,41 @@ fn draw_text(img: &mut image::RgbImage, text: &str, x: u32, y: u32, color: image
         ('g', [[0,0,0,0,0],[0,1,1,1,1],[1,0,0,0,1],[1,0,0,0,1],[0,1,1,1,1],[0,0,0,0,1],[0,1,1,1,0]]),
         ('h', [[1,0,0,0,0],[1,0,0,0,0],[1,1,1,1,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1]]),
         ('i', [[0,0,1,0,0],[0,0,0,0,0],[0,1,1,0,0],[0,0,1,0,0],[0,0,1,0,0],[0,0,1,0,0],[0,1,1,1,0]]),
+        ('j', [[0,0,0,1,0],[0,0,0,0,0],[0,0,1,1,0],[0,0,0,1,0],[0,0,0,1,0],[1,0,0,1,0],[0,1,1,0,0]]),
         ('k', [[1,0,0,0,0],[1,0,0,0,0],[1,0,0,1,0],[1,0,1,0,0],[1,1,0,0,0],[1,0,1,0,0],[1,0,0,1,0]]),
         ('l', [[0,1,1,0,0],[0,0,1,0,0],[0,0,1,0,0],[0,0,1,0,0],[0,0,1,0,0],[0,0,1,0,0],[0,1,1,1,0]]),
         ('m', [[0,0,0,0,0],[0,0,0,0,0],[1,1,0,1,0],[1,0,1,0,1],[1,0,1,0,1],[1,0,1,0,1],[1,0,1,0,1]]),
         ('n', [[0,0,0,0,0],[0,0,0,0,0],[1,1,1,1,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1]]),
         ('o', [[0,0,0,0,0],[0,0,0,0,0],[0,1,1,1,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[0,1,1,1,0]]),
         ('p', [[0,0,0,0,0],[1,1,1,1,0],[1,0,0,0,1],[1,0,0,0,1],[1,1,1,1,0],[1,0,0,0,0],[1,0,0,0,0]]),
+        ('q', [[0,0,0,0,0],[0,1,1,1,1],[1,0,0,0,1],[1,0,0,0,1],[0,1,1,1,1],[0,0,0,0,1],[0,0,0,0,1]]),
         ('r', [[0,0,0,0,0],[0,0,0,0,0],[1,0,1,1,0],[1,1,0,0,1],[1,0,0,0,0],[1,0,0,0,0],[1,0,0,0,0]]),
         ('s', [[0,0,0,0,0],[0,0,0,0,0],[0,1,1,1,1],[1,0,0,0,0],[0,1,1,1,0],[0,0,0,0,1],[1,1,1,1,0]]),
         ('t', [[0,0,1,0,0],[0,0,1,0,0],[0,1,1,1,0],[0,0,1,0,0],[0,0,1,0,0],[0,0,1,0,0],[0,0,0,1,0]]),
         ('u', [[0,0,0,0,0],[0,0,0,0,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[0,1,1,1,1]]),
         ('v', [[0,0,0,0,0],[0,0,0,0,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[0,1,0,1,0],[0,0,1,0,0]]),
         ('w', [[0,0,0,0,0],[0,0,0,0,0],[1,0,0,0,1],[1,0,1,0,1],[1,0,1,0,1],[1,0,1,0,1],[0,1,0,1,0]]),
+        ('x', [[0,0,0,0,0],[0,0,0,0,0],[1,0,0,0,1],[0,1,0,1,0],[0,0,1,0,0],[0,1,0,1,0],[1,0,0,0,1]]),
         ('y', [[0,0,0,0,0],[1,0,0,0,1],[1,0,0,0,1],[0,1,0,1,0],[0,0,1,0,0],[0,1,0,0,0],[1,0,0,0,0]]),
+        ('z', [[0,0,0,0,0],[0,0,0,0,0],[1,1,1,1,1],[0,0,0,1,0],[0,0,1,0,0],[0,1,0,0,0],[1,1,1,1,1]]),
+        // German letters for translated labels
+        ('ä', [[0,1,0,1,0],[0,0,0,0,0],[0,1,1,1,0],[0,0,0,0,1],[0,1,1,1,1],[1,0,0,0,1],[0,1,1,1,1]]),
+        ('ö', [[0,1,0,1,0],[0,0,0,0,0],[0,1,1,1,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[0,1,1,1,0]]),
+        ('ü', [[0,1,0,1,0],[0,0,0,0,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[1,0,0,0,1],[0,1,1,1,1]]),
+        ('ß', [[0,1,1,0,0],[1,0,0,1,0],[1,0,0,1,0],[1,0,1,1,0],[1,0,0,0,1],[1,0,0,0,1],[1,0,1,1,0]]),
         (' ', [[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0]]),
         ('%', [[1,1,0,0,1],[1,1,0,0,1],[0,0,0,1,0],[0,0,1,0,0],[0,1,0,0,0],[1,0,0,1,1],[1,0,0,1,1]]),
         ('.', [[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,1,1,0,0],[0,1,1,0,0]]),
         ('-', [[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[1,1,1,1,1],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0]]),
         ('_', [[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[1,1,1,1,1]]),
+        // Timestamps and overlay text
+        (':', [[0,0,0,0,0],[0,1,1,0,0],[0,1,1,0,0],[0,0,0,0,0],[0,1,1,0,0],[0,1,1,0,0],[0,0,0,0,0]]),
+        ('/', [[0,0,0,0,1],[0,0,0,0,1],[0,0,0,1,0],[0,0,1,0,0],[0,1,0,0,0],[1,0,0,0,0],[1,0,0,0,0]]),
+        (',', [[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,0,0,0,0],[0,1,1,0,0],[0,0,1,0,0],[0,1,0,0,0]]),
+        ('+', [[0,0,0,0,0],[0,0,1,0,0],[0,0,1,0,0],[1,1,1,1,1],[0,0,1,0,0],[0,0,1,0,0],[0,0,0,0,0]]),
+        ('#', [[0,1,0,1,0],[0,1,0,1,0],[1,1,1,1,1],[0,1,0,1,0],[1,1,1,1,1],[0,1,0,1,0],[0,1,0,1,0]]),
+        ('(', [[0,0,0,1,0],[0,0,1,0,0],[0,1,0,0,0],[0,1,0,0,0],[0,1,0,0,0],[0,0,1,0,0],[0,0,0,1,0]]),
+        (')', [[0,1,0,0,0],[0,0,1,0,0],[0,0,0,1,0],[0,0,0,1,0],[0,0,0,1,0],[0,0,1,0,0],[0,1,0,0,0]]),
     ].iter().cloned().collect();
 
     let mut cursor_x = x;
@@ -370,3 +1211,106 @@ fn draw_text(img: &mut image::RgbImage, text: &str, x: u32, y: u32, color: image
         cursor_x += 6 * scale; // Character width + spacing
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD_REPLY: &str = r#"{"width": 640, "height": 480, "detections": []}"#;
+
+    /// A detector running `script` under sh
+    fn fake_detector(script: String) -> Result<YoloDetector> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        YoloDetector::spawn(BackendKind::Subprocess, &DetectorConfig::default(), move || {
+            Ok(Box::new(SubprocessBackend::spawn(command)?) as Box<dyn Backend>)
+        })
+    }
+
+    /// A detector subprocess that answers its requests with `replies` in
+    /// turn, then reads on without answering
+    fn scripted_detector(replies: &[&str]) -> Result<YoloDetector> {
+        let quoted: Vec<String> = replies.iter().map(|r| format!("'{}'", r.replace('\'', r"'\''"))).collect();
+        fake_detector(format!(
+            "set -- {}\n\
+             echo READY\n\
+             for reply in \"$@\"; do\n\
+               n=$(head -c 4 | od -An -tu4)\n\
+               [ -n \"$n\" ] || exit 0\n\
+               head -c $n >/dev/null\n\
+               printf '%s\\n' \"$reply\"\n\
+             done\n\
+             cat >/dev/null\n",
+            quoted.join(" ")
+        ))
+    }
+
+    #[test]
+    fn warm_up_reports_the_inference_time() {
+        let detector = scripted_detector(&[GOOD_REPLY]).unwrap();
+        let latency = detector.warm_up(WARM_UP_TIMEOUT).unwrap();
+        assert!(latency < WARM_UP_TIMEOUT);
+        assert!(detector.stats().last_inference_ms > 0.0);
+        // Not counted as a processed frame
+        assert_eq!(detector.stats().processed, 0);
+    }
+
+    #[test]
+    fn warm_up_times_out_on_a_silent_detector() {
+        let detector = scripted_detector(&[]).unwrap();
+        let timeout = Duration::from_millis(300);
+        let started = Instant::now();
+        let error = detector.warm_up(timeout).unwrap_err().to_string();
+        assert!(error.contains("did not answer the warm-up"), "{}", error);
+        assert!(started.elapsed() >= timeout && started.elapsed() < WARM_UP_TIMEOUT);
+    }
+
+    #[test]
+    fn malformed_warm_up_responses_fail_without_stopping_the_detector() {
+        let detector =
+            scripted_detector(&["{not json", r#"{"width": 320, "height": 240, "detections": []}"#, GOOD_REPLY]).unwrap();
+        let error = detector.warm_up(WARM_UP_TIMEOUT).unwrap_err().to_string();
+        assert!(error.starts_with("Parse error"), "{}", error);
+        let error = detector.warm_up(WARM_UP_TIMEOUT).unwrap_err().to_string();
+        assert!(error.contains("320x240") && error.contains("640x480"), "{}", error);
+        // The backend is still up and answers the next one
+        assert!(detector.is_running());
+        detector.warm_up(WARM_UP_TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn a_detector_exiting_during_warm_up_is_reported() {
+        // Closes its output as soon as the first request arrives
+        let detector = fake_detector("echo READY; head -c 4 >/dev/null".to_string()).unwrap();
+        assert!(detector.warm_up(WARM_UP_TIMEOUT).is_err());
+        let deadline = Instant::now() + WARM_UP_TIMEOUT;
+        while detector.is_running() {
+            assert!(Instant::now() < deadline, "detector thread still running");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(detector.exit_error().is_some());
+        // Later warm-ups fail at once rather than timing out
+        let error = detector.warm_up(WARM_UP_TIMEOUT).unwrap_err().to_string();
+        assert_eq!(error, "Detector is shut down");
+    }
+
+    #[test]
+    fn handles_outlive_the_detector() {
+        let detector = scripted_detector(&[GOOD_REPLY]).unwrap();
+        let handle = detector.handle();
+        let result = handle.detect_blocking(7, b"frame".to_vec(), WARM_UP_TIMEOUT).unwrap();
+        assert_eq!(result.frame_seq, Some(7));
+        assert!(result.error.is_none(), "{:?}", result.error);
+        assert_eq!(detector.stats().processed, 1);
+
+        drop(detector);
+        let error = handle.detect_blocking(8, b"frame".to_vec(), WARM_UP_TIMEOUT).unwrap_err();
+        assert!(error.to_string().contains("shut down"), "{:#}", error);
+    }
+
+    #[test]
+    fn a_detector_that_never_gets_ready_does_not_start() {
+        let error = fake_detector("echo 'Traceback: no NPU'".to_string()).err().unwrap().to_string();
+        assert!(error.contains("did not signal READY: Traceback: no NPU"), "{}", error);
+    }
+}
@@ -0,0 +1,172 @@
+//! DNG writer for raw stills
+//!
+//! `/frame.dng` wraps a raw frame's Bayer samples in a DNG so it can be
+//! developed in RawTherapee, darktable and the like instead of with the
+//! built-in demosaic: one uncompressed strip of 16-bit samples holding the
+//! 10-bit values, with the CFA pattern, black and white levels, and the
+//! white balance of the last color frame as the as-shot neutral. Frame
+//! stacks (see [`crate::stack`]) are written the same way at a 16-bit
+//! white level.
+//!
+//! ColorMatrix1 is derived from the pipeline's color correction matrix
+//! (camera RGB to linear sRGB, see [`crate::ccm`]), so a raw developer's
+//! default rendering matches the stream's colors; it is only as accurate as
+//! that matrix.
+
+use crate::ccm::ColorMatrix;
+use crate::exif::{self, Entry, ExifFields};
+use crate::rawformat::Cfa;
+use anyhow::{Context, Result};
+
+/// Largest sample value of the unpacked 10-bit data
+pub const WHITE_LEVEL: u16 = 1023;
+
+// TIFF/EP and DNG tags
+const NEW_SUBFILE_TYPE: u16 = 0x00FE;
+const IMAGE_WIDTH: u16 = 0x0100;
+const IMAGE_LENGTH: u16 = 0x0101;
+const BITS_PER_SAMPLE: u16 = 0x0102;
+const COMPRESSION: u16 = 0x0103;
+const PHOTOMETRIC_INTERPRETATION: u16 = 0x0106;
+const STRIP_OFFSETS: u16 = 0x0111;
+const SAMPLES_PER_PIXEL: u16 = 0x0115;
+const ROWS_PER_STRIP: u16 = 0x0116;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const PLANAR_CONFIGURATION: u16 = 0x011C;
+const CFA_REPEAT_PATTERN_DIM: u16 = 0x828D;
+const CFA_PATTERN: u16 = 0x828E;
+const DNG_VERSION: u16 = 0xC612;
+const DNG_BACKWARD_VERSION: u16 = 0xC613;
+const UNIQUE_CAMERA_MODEL: u16 = 0xC614;
+const BLACK_LEVEL: u16 = 0xC61A;
+const WHITE_LEVEL_TAG: u16 = 0xC61D;
+const COLOR_MATRIX_1: u16 = 0xC621;
+const AS_SHOT_NEUTRAL: u16 = 0xC628;
+const CALIBRATION_ILLUMINANT_1: u16 = 0xC65A;
+
+const COMPRESSION_NONE: u16 = 1;
+const PHOTOMETRIC_CFA: u16 = 32803;
+const PLANAR_CHUNKY: u16 = 1;
+const ILLUMINANT_D65: u16 = 21;
+
+/// Denominator of the matrix and neutral rationals
+const SCALE: i32 = 10_000;
+
+/// XYZ (D65) to linear sRGB
+const XYZ_TO_SRGB: ColorMatrix = ColorMatrix([
+    [3.2406, -1.5372, -0.4986],
+    [-0.9689, 1.8758, 0.0415],
+    [0.0557, -0.2040, 1.0570],
+]);
+
+/// A raw frame to write
+pub struct BayerImage<'a> {
+    /// Samples up to `white_level`, `width` per row
+    pub samples: &'a [u16],
+    pub width: usize,
+    pub height: usize,
+    pub cfa: Cfa,
+    pub black_level: u16,
+    /// Largest sample value ([`WHITE_LEVEL`] for a single frame)
+    pub white_level: u16,
+    /// White balance gains (R, G, B) of the scene, if known
+    pub white_balance: Option<[f32; 3]>,
+    /// Camera RGB to linear sRGB, as the pipeline corrects colors
+    pub color_matrix: ColorMatrix,
+    /// TIFF Orientation: how a viewer should turn the samples
+    pub orientation: u16,
+}
+
+/// CFAPattern values (0 red, 1 green, 2 blue) of the top-left 2x2 block
+pub fn cfa_pattern(cfa: Cfa) -> [u8; 4] {
+    match cfa {
+        Cfa::Rggb => [0, 1, 1, 2],
+        Cfa::Grbg => [1, 0, 2, 1],
+        Cfa::Gbrg => [1, 2, 0, 1],
+        Cfa::Bggr => [2, 1, 1, 0],
+    }
+}
+
+/// Camera response to a neutral, the inverse of the gains relative to green
+fn as_shot_neutral(gains: [f32; 3]) -> Option<[(u32, u32); 3]> {
+    if !gains.iter().all(|g| g.is_finite() && *g > 0.0) {
+        return None;
+    }
+    Some(gains.map(|g| (((gains[1] / g) * SCALE as f32).round() as u32, SCALE as u32)))
+}
+
+/// Encode `image` as a DNG; capture time, exposure and ISO come from
+/// `fields`
+pub fn encode(image: &BayerImage, fields: &ExifFields) -> Result<Vec<u8>> {
+    let pixels = image.width * image.height;
+    anyhow::ensure!(image.samples.len() >= pixels, "Bayer buffer shorter than {}x{}", image.width, image.height);
+    let strip_bytes = pixels * 2;
+
+    let stamp = exif::tiff_date_time(&exif::local_time(fields.timestamp_ms));
+    let software = exif::software();
+    let unique_model = format!("{} {}", exif::MAKE, exif::MODEL);
+    // XYZ to camera RGB: to sRGB, then back through the correction
+    let srgb_to_camera = image.color_matrix.inverse().context("Singular color matrix")?;
+    let matrix: Vec<(i32, i32)> = srgb_to_camera
+        .multiply(&XYZ_TO_SRGB)
+        .0
+        .iter()
+        .flatten()
+        .map(|&v| ((v * SCALE as f32).round() as i32, SCALE))
+        .collect();
+    let entries = |strip_offset: usize| {
+        let mut entries = vec![
+            Entry::long(NEW_SUBFILE_TYPE, 0),
+            Entry::long(IMAGE_WIDTH, image.width as u32),
+            Entry::long(IMAGE_LENGTH, image.height as u32),
+            Entry::short(BITS_PER_SAMPLE, 16),
+            Entry::short(COMPRESSION, COMPRESSION_NONE),
+            Entry::short(PHOTOMETRIC_INTERPRETATION, PHOTOMETRIC_CFA),
+            Entry::ascii(exif::TAG_MAKE, exif::MAKE),
+            Entry::ascii(exif::TAG_MODEL, exif::MODEL),
+            Entry::long(STRIP_OFFSETS, strip_offset as u32),
+            Entry::short(exif::ORIENTATION, image.orientation),
+            Entry::short(SAMPLES_PER_PIXEL, 1),
+            Entry::long(ROWS_PER_STRIP, image.height as u32),
+            Entry::long(STRIP_BYTE_COUNTS, strip_bytes as u32),
+            Entry::short(PLANAR_CONFIGURATION, PLANAR_CHUNKY),
+            Entry::ascii(exif::SOFTWARE, &software),
+            Entry::ascii(exif::DATE_TIME, &stamp),
+            Entry::shorts(CFA_REPEAT_PATTERN_DIM, &[2, 2]),
+            Entry::bytes(CFA_PATTERN, &cfa_pattern(image.cfa)),
+            Entry::bytes(DNG_VERSION, &[1, 4, 0, 0]),
+            Entry::bytes(DNG_BACKWARD_VERSION, &[1, 1, 0, 0]),
+            Entry::ascii(UNIQUE_CAMERA_MODEL, &unique_model),
+            Entry::short(BLACK_LEVEL, image.black_level),
+            Entry::short(WHITE_LEVEL_TAG, image.white_level),
+            Entry::srationals(COLOR_MATRIX_1, &matrix),
+            Entry::short(CALIBRATION_ILLUMINANT_1, ILLUMINANT_D65),
+        ];
+        if let Some(neutral) = image.white_balance.and_then(as_shot_neutral) {
+            entries.push(Entry::rationals(AS_SHOT_NEUTRAL, &neutral));
+        }
+        // TIFF/EP allows these in IFD0, where raw developers look for them
+        if let Some(seconds) = fields.exposure_s {
+            let (numerator, denominator) = exif::exposure_rational(seconds);
+            entries.push(Entry::rational(exif::EXPOSURE_TIME, numerator, denominator));
+        }
+        if let Some(iso) = fields.iso {
+            entries.push(Entry::short(exif::ISO_SPEED_RATINGS, iso));
+        }
+        entries
+    };
+
+    // The strip offset is inline, so the IFD's size doesn't depend on it
+    const IFD0_OFFSET: usize = 8;
+    let strip_offset = IFD0_OFFSET + exif::encode_ifd(entries(0), IFD0_OFFSET).len();
+    anyhow::ensure!(strip_offset + strip_bytes <= u32::MAX as usize, "Image too large for a DNG");
+
+    let mut dng = Vec::with_capacity(strip_offset + strip_bytes);
+    dng.extend_from_slice(b"II*\0");
+    dng.extend_from_slice(&(IFD0_OFFSET as u32).to_le_bytes());
+    dng.extend_from_slice(&exif::encode_ifd(entries(strip_offset), IFD0_OFFSET));
+    for &sample in &image.samples[..pixels] {
+        dng.extend_from_slice(&sample.to_le_bytes());
+    }
+    Ok(dng)
+}
@@ -0,0 +1,189 @@
+//! API error responses
+//!
+//! Every handler failure is an `ApiError`: a category that fixes the HTTP
+//! status, a human-readable message and optional structured details, sent
+//! as `{"code": "...", "message": "...", "details": ...}` (`details` is
+//! `null` when there are none). Clients branch on the status or `code`,
+//! never on the message text.
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Error category; decides the HTTP status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Malformed or out-of-range input (400)
+    BadRequest,
+    /// Unknown frame, job, model, profile, route, ... (404)
+    NotFound,
+    /// Route exists, method doesn't (405)
+    MethodNotAllowed,
+    /// Request timed out (408)
+    Timeout,
+    /// Valid request that conflicts with the current state (409)
+    Conflict,
+    /// Request body over the limit (413)
+    PayloadTooLarge,
+    /// Rate limit or a busy single-slot resource (429)
+    RateLimited,
+    /// Something went wrong on our side (500)
+    Internal,
+    /// Camera, detector or frames not available (503)
+    Unavailable,
+}
+
+impl ErrorCode {
+    /// Category for a status produced outside our handlers (extractor
+    /// rejections, tower layers, routing)
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::REQUEST_TIMEOUT => ErrorCode::Timeout,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            s if s.is_client_error() => ErrorCode::BadRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Timeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// A failed API request
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[schema(examples(
+    json!({"code": "unavailable", "message": "No frame available", "details": null}),
+    json!({
+        "code": "bad_request",
+        "message": "Unknown test pattern 'bars'",
+        "details": {"available": ["Disabled", "Vertical Color Bars"]}
+    })
+))]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+    /// Sent as `Retry-After` (seconds)
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), details: None, retry_after: None }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::BadRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Conflict, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unavailable, message)
+    }
+
+    pub fn camera_unavailable() -> Self {
+        Self::unavailable("Camera not available")
+    }
+
+    pub fn no_frame() -> Self {
+        Self::unavailable("No frame available")
+    }
+
+    /// Attach structured details (serialized to JSON)
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Unexpected failures from helpers (v4l2-ctl, file I/O, ...)
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::internal(format!("{:#}", e))
+    }
+}
+
+/// A blocking task that panicked or was cancelled
+impl From<tokio::task::JoinError> for ApiError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Self::internal(format!("Background task failed: {}", e))
+    }
+}
+
+/// Rejected JSON bodies: malformed (400), or cut off by the body size
+/// limit (413) when they arrive without a `Content-Length`
+impl From<axum::extract::rejection::JsonRejection> for ApiError {
+    fn from(e: axum::extract::rejection::JsonRejection) -> Self {
+        Self::new(ErrorCode::from_status(e.status()), e.body_text())
+    }
+}
+
+/// Failed model uploads: the size limit, a bad checksum or the disk
+impl From<crate::models::UploadError> for ApiError {
+    fn from(e: crate::models::UploadError) -> Self {
+        use crate::models::UploadError;
+        let code = match e {
+            UploadError::TooLarge { .. } => ErrorCode::PayloadTooLarge,
+            UploadError::ChecksumMismatch { .. } => ErrorCode::BadRequest,
+            UploadError::Io(_) => ErrorCode::Internal,
+        };
+        ApiError::new(code, e.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), axum::Json(&self)).into_response();
+        if let Some(secs) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response
+    }
+}
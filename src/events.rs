@@ -0,0 +1,451 @@
+//! Internal event bus
+//!
+//! Subsystems publish [`DomainEvent`]s (a detection confirmed, the camera
+//! failing, a recording finished, ...) on one broadcast channel instead of
+//! calling each integration from wherever the state changes. Every consumer
+//! (the SSE stream, the event log, the counters) is an independent task
+//! with its own [`Subscription`].
+//!
+//! Publishing never waits: the channel keeps the last `EVENT_CAPACITY`
+//! events and a subscriber that falls further behind skips ahead, with the
+//! skipped events counted per subscriber name (visible in /stats).
+
+use crate::detector::DetectionResult;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events buffered for subscribers that are behind
+const EVENT_CAPACITY: usize = 256;
+
+/// Events kept by the event log (GET /events)
+pub const EVENT_LOG_CAPACITY: usize = 200;
+
+/// Something that happened in a subsystem
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DomainEvent {
+    /// Objects detected after a result without any
+    DetectionConfirmed { frame_seq: Option<u64>, count: usize, classes: Vec<String> },
+    /// A result without objects after one with
+    DetectionCleared { frame_seq: Option<u64> },
+    /// Frame capture started failing
+    CameraDegraded { error: String },
+    /// Frames are being captured again
+    CameraRecovered,
+    RecordingStarted { file: PathBuf },
+    /// The recording file is complete
+    RecordingStopped { file: PathBuf, frames: u64, bytes: u64 },
+    ProfileApplied { name: String, applied: Vec<String> },
+    /// Faces detected after a result without any; the crop of the most
+    /// confident one is at /faces/last.jpg when the event is published
+    FaceAppeared { frame_seq: u64, count: usize, confidence: f32 },
+    /// An alerting class came into a zone (`count` detections of it)
+    ZoneEntered { zone: String, class: String, frame_seq: Option<u64>, count: usize },
+    /// The class is no longer detected in the zone
+    ZoneCleared { zone: String, class: String, frame_seq: Option<u64> },
+    /// Frame differencing found motion (`score`: percent of the frame changed)
+    MotionStarted { frame_seq: u64, score: f32 },
+    /// No motion for the configured hold time
+    MotionStopped { frame_seq: u64, duration_ms: u64 },
+    /// Day/night switching changed the capture mode (`brightness`: scene
+    /// brightness in EV)
+    DayNightSwitched { period: String, mode: String, brightness: f32 },
+}
+
+impl DomainEvent {
+    pub const KINDS: [&'static str; 13] = [
+        "detection_confirmed",
+        "detection_cleared",
+        "camera_degraded",
+        "camera_recovered",
+        "recording_started",
+        "recording_stopped",
+        "profile_applied",
+        "face_appeared",
+        "zone_entered",
+        "zone_cleared",
+        "motion_started",
+        "motion_stopped",
+        "day_night_switched",
+    ];
+
+    /// Event type name (the `type` field)
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DomainEvent::DetectionConfirmed { .. } => "detection_confirmed",
+            DomainEvent::DetectionCleared { .. } => "detection_cleared",
+            DomainEvent::CameraDegraded { .. } => "camera_degraded",
+            DomainEvent::CameraRecovered => "camera_recovered",
+            DomainEvent::RecordingStarted { .. } => "recording_started",
+            DomainEvent::RecordingStopped { .. } => "recording_stopped",
+            DomainEvent::ProfileApplied { .. } => "profile_applied",
+            DomainEvent::FaceAppeared { .. } => "face_appeared",
+            DomainEvent::ZoneEntered { .. } => "zone_entered",
+            DomainEvent::ZoneCleared { .. } => "zone_cleared",
+            DomainEvent::MotionStarted { .. } => "motion_started",
+            DomainEvent::MotionStopped { .. } => "motion_stopped",
+            DomainEvent::DayNightSwitched { .. } => "day_night_switched",
+        }
+    }
+}
+
+/// A published event
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// Increasing from 1 per process
+    pub id: u64,
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub event: DomainEvent,
+}
+
+/// Delivery counters shared by all subscriptions with the same name
+#[derive(Debug, Default)]
+struct SubscriberCounters {
+    active: AtomicUsize,
+    received: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Per-subscriber section of /stats
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriberStats {
+    pub name: String,
+    /// Open subscriptions (SSE clients come and go)
+    pub active: usize,
+    pub received: u64,
+    /// Events skipped because the subscriber fell behind
+    pub dropped: u64,
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<Arc<Event>>,
+    next_id: AtomicU64,
+    published: AtomicU64,
+    subscribers: Mutex<BTreeMap<String, Arc<SubscriberCounters>>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast::Sender::new(EVENT_CAPACITY),
+            next_id: AtomicU64::new(1),
+            published: AtomicU64::new(0),
+            subscribers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Publish an event to every current subscriber (never blocks)
+    pub fn publish(&self, event: DomainEvent) {
+        let event = Event {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp_ms: crate::unix_millis(),
+            event,
+        };
+        tracing::debug!("Event {}: {:?}", event.id, event.event);
+        self.published.fetch_add(1, Ordering::Relaxed);
+        // No subscribers is fine
+        let _ = self.tx.send(Arc::new(event));
+    }
+
+    /// Receive events published from now on; `name` groups the counters
+    pub fn subscribe(&self, name: &str) -> Subscription {
+        let counters = self.subscribers.lock().entry(name.to_string()).or_default().clone();
+        counters.active.fetch_add(1, Ordering::Relaxed);
+        Subscription { rx: self.tx.subscribe(), counters }
+    }
+
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    pub fn subscriber_stats(&self) -> Vec<SubscriberStats> {
+        self.subscribers
+            .lock()
+            .iter()
+            .map(|(name, c)| SubscriberStats {
+                name: name.clone(),
+                active: c.active.load(Ordering::Relaxed),
+                received: c.received.load(Ordering::Relaxed),
+                dropped: c.dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// One consumer's view of the bus
+pub struct Subscription {
+    rx: broadcast::Receiver<Arc<Event>>,
+    counters: Arc<SubscriberCounters>,
+}
+
+impl Subscription {
+    /// Next event; skips (and counts) events lost to lag
+    pub async fn recv(&mut self) -> Option<Arc<Event>> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => {
+                    self.counters.received.fetch_add(1, Ordering::Relaxed);
+                    return Some(event);
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Event subscriber fell behind, skipped {} events", skipped);
+                    self.counters.dropped.fetch_add(skipped, Ordering::Relaxed);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.counters.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Turns successive detection results into confirmed/cleared events
+#[derive(Debug, Default)]
+pub struct DetectionTracker {
+    active: bool,
+    last_seq: Option<u64>,
+}
+
+impl DetectionTracker {
+    /// Event for a detection result, if it changes the state; repeated
+    /// results for the same frame and failed runs are ignored
+    pub fn update(&mut self, result: &DetectionResult) -> Option<DomainEvent> {
+        if result.error.is_some() || (result.frame_seq.is_some() && result.frame_seq == self.last_seq) {
+            return None;
+        }
+        self.last_seq = result.frame_seq;
+        let active = !result.detections.is_empty();
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        Some(if active {
+            let mut classes: Vec<String> = result.detections.iter().map(|d| d.class.clone()).collect();
+            classes.sort();
+            classes.dedup();
+            DomainEvent::DetectionConfirmed { frame_seq: result.frame_seq, count: result.detections.len(), classes }
+        } else {
+            DomainEvent::DetectionCleared { frame_seq: result.frame_seq }
+        })
+    }
+
+    /// Forget the state (detector restarted); clears if objects were seen
+    pub fn reset(&mut self) -> Option<DomainEvent> {
+        let was_active = std::mem::take(&mut self.active);
+        self.last_seq = None;
+        was_active.then_some(DomainEvent::DetectionCleared { frame_seq: None })
+    }
+}
+
+/// Recent events, newest last
+#[derive(Debug, Default)]
+pub struct EventLog {
+    events: Mutex<VecDeque<Arc<Event>>>,
+}
+
+impl EventLog {
+    /// The last `limit` events, oldest first
+    pub fn recent(&self, limit: usize) -> Vec<Event> {
+        let events = self.events.lock();
+        events.iter().skip(events.len().saturating_sub(limit)).map(|e| Event::clone(e)).collect()
+    }
+}
+
+/// Event log subscriber
+pub async fn run_event_log(mut events: Subscription, log: Arc<EventLog>) {
+    while let Some(event) = events.recv().await {
+        let mut log = log.events.lock();
+        if log.len() == EVENT_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(event);
+    }
+}
+
+/// Events received per type
+#[derive(Debug, Default)]
+pub struct EventCounters {
+    counts: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl EventCounters {
+    /// Counts for every event type, zeros included
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        let counts = self.counts.lock();
+        DomainEvent::KINDS
+            .iter()
+            .map(|&kind| (kind, counts.get(kind).copied().unwrap_or(0)))
+            .collect()
+    }
+}
+
+/// Counter subscriber
+pub async fn run_counters(mut events: Subscription, counters: Arc<EventCounters>) {
+    while let Some(event) = events.recv().await {
+        *counters.counts.lock().entry(event.event.kind()).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{BBox, Detection};
+    use std::time::Duration;
+
+    fn detection(class: &str) -> Detection {
+        Detection {
+            class: class.to_string(),
+            confidence: 0.9,
+            bbox: BBox { x1: 0, y1: 0, x2: 10, y2: 10 },
+            label: None,
+            landmarks: Vec::new(),
+            zones: Vec::new(),
+        }
+    }
+
+    fn result(frame_seq: u64, classes: &[&str]) -> DetectionResult {
+        DetectionResult {
+            frame_seq: Some(frame_seq),
+            detections: classes.iter().map(|c| detection(c)).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Wait (briefly) for a subscriber task to catch up
+    async fn eventually(mut condition: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("subscriber did not react");
+    }
+
+    fn stats(bus: &EventBus, name: &str) -> SubscriberStats {
+        bus.subscriber_stats().into_iter().find(|s| s.name == name).unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_events_in_order() {
+        let bus = EventBus::new();
+        // Nobody listening is fine
+        bus.publish(DomainEvent::CameraRecovered);
+        let mut first = bus.subscribe("a");
+        let mut second = bus.subscribe("b");
+        bus.publish(DomainEvent::CameraDegraded { error: "timeout".into() });
+        bus.publish(DomainEvent::CameraRecovered);
+
+        for subscription in [&mut first, &mut second] {
+            let degraded = subscription.recv().await.unwrap();
+            assert_eq!((degraded.id, degraded.event.kind()), (2, "camera_degraded"));
+            let recovered = subscription.recv().await.unwrap();
+            assert_eq!((recovered.id, recovered.event.kind()), (3, "camera_recovered"));
+        }
+        assert_eq!(bus.published(), 3);
+        assert_eq!(stats(&bus, "a").received, 2);
+        assert_eq!(stats(&bus, "a").active, 1);
+        drop(first);
+        assert_eq!(stats(&bus, "a").active, 0);
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_skip_ahead_and_count_drops() {
+        let bus = EventBus::new();
+        let mut slow = bus.subscribe("slow");
+        // Publishing never waits for the subscriber
+        for frame_seq in 0..EVENT_CAPACITY as u64 + 10 {
+            bus.publish(DomainEvent::MotionStarted { frame_seq, score: 1.0 });
+        }
+        let next = slow.recv().await.unwrap();
+        assert!(matches!(next.event, DomainEvent::MotionStarted { frame_seq: 10, .. }), "{:?}", next.event);
+        let stats = stats(&bus, "slow");
+        assert_eq!((stats.dropped, stats.received), (10, 1));
+    }
+
+    #[tokio::test]
+    async fn event_log_keeps_the_recent_events() {
+        let bus = EventBus::new();
+        let log = Arc::new(EventLog::default());
+        let task = tokio::spawn(run_event_log(bus.subscribe("event_log"), log.clone()));
+        for frame_seq in 0..EVENT_LOG_CAPACITY as u64 + 5 {
+            bus.publish(DomainEvent::MotionStopped { frame_seq, duration_ms: 1000 });
+            // Keep within the channel's buffer
+            eventually(|| stats(&bus, "event_log").received == frame_seq + 1).await;
+        }
+        let recent = log.recent(usize::MAX);
+        assert_eq!(recent.len(), EVENT_LOG_CAPACITY);
+        assert_eq!(recent[0].id, 6);
+        let last_two: Vec<u64> = log.recent(2).iter().map(|e| e.id).collect();
+        assert_eq!(last_two, [EVENT_LOG_CAPACITY as u64 + 4, EVENT_LOG_CAPACITY as u64 + 5]);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn counters_count_each_type() {
+        let bus = EventBus::new();
+        let counters = Arc::new(EventCounters::default());
+        let task = tokio::spawn(run_counters(bus.subscribe("counters"), counters.clone()));
+        bus.publish(DomainEvent::RecordingStarted { file: "a.avi".into() });
+        bus.publish(DomainEvent::RecordingStopped { file: "a.avi".into(), frames: 10, bytes: 1000 });
+        bus.publish(DomainEvent::RecordingStarted { file: "b.avi".into() });
+        eventually(|| stats(&bus, "counters").received == 3).await;
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.len(), DomainEvent::KINDS.len());
+        assert_eq!(snapshot["recording_started"], 2);
+        assert_eq!(snapshot["recording_stopped"], 1);
+        assert_eq!(snapshot["camera_degraded"], 0);
+        task.abort();
+    }
+
+    #[test]
+    fn detection_tracker_reports_changes_only() {
+        let mut tracker = DetectionTracker::default();
+        assert!(tracker.update(&result(1, &[])).is_none());
+        match tracker.update(&result(2, &["person", "car", "person"])) {
+            Some(DomainEvent::DetectionConfirmed { frame_seq, count, classes }) => {
+                assert_eq!((frame_seq, count, classes), (Some(2), 3, vec!["car".to_string(), "person".to_string()]));
+            }
+            other => panic!("{:?}", other),
+        }
+        // Still detecting, a repeat of frame 3, a failed run
+        assert!(tracker.update(&result(3, &["car"])).is_none());
+        assert!(tracker.update(&result(3, &[])).is_none());
+        let failed = DetectionResult { error: Some("timeout".into()), ..result(4, &[]) };
+        assert!(tracker.update(&failed).is_none());
+        assert!(matches!(tracker.update(&result(5, &[])), Some(DomainEvent::DetectionCleared { frame_seq: Some(5) })));
+        
+        tracker.update(&result(6, &["dog"]));
+        assert!(matches!(tracker.reset(), Some(DomainEvent::DetectionCleared { frame_seq: None })));
+        assert!(tracker.reset().is_none());
+    }
+
+    #[test]
+    fn events_serialize_with_their_type() {
+        let event = DomainEvent::ZoneCleared { zone: "door".into(), class: "person".into(), frame_seq: Some(3) };
+        let event = Event { id: 7, timestamp_ms: 1000, event };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"id": 7, "timestamp_ms": 1000, "type": "zone_cleared", "zone": "door", "class": "person", "frame_seq": 3})
+        );
+        assert_eq!(json["type"], event.event.kind());
+    }
+}
@@ -0,0 +1,398 @@
+//! Minimal EXIF (APP1) writer for saved stills
+//!
+//! Stills written to disk or downloaded (snapshots, job stills, the
+//! `capture` command) carry when they were taken and how: capture time,
+//! exposure time and ISO derived from the sensor controls, make/model and,
+//! when detection is running, the detections as JSON in ImageDescription.
+//! Streamed frames never get EXIF.
+//!
+//! Only what those fields need is implemented: a little-endian TIFF
+//! structure with IFD0 and the Exif sub-IFD, inserted after the JFIF
+//! segment of an encoded JPEG. The DNG writer ([`crate::dng`]) builds its
+//! IFD with the same entries.
+
+use crate::controls::Control;
+use anyhow::Result;
+use chrono::{DateTime, Local, TimeZone};
+
+pub const MAKE: &str = "Sony";
+pub const MODEL: &str = "IMX415";
+
+/// ISO at unity analogue gain
+pub const BASE_ISO: f64 = 100.0;
+
+/// EXIF orientation "as stored": sensor flips are applied to the pixel
+/// data itself, so stills never need rotating on display
+pub(crate) const ORIENTATION_UPRIGHT: u16 = 1;
+
+/// Analogue gain control step (dB)
+pub const GAIN_STEP_DB: f64 = 0.3;
+
+/// Largest ImageDescription written; longer descriptions are dropped
+pub const MAX_DESCRIPTION_BYTES: usize = 32 * 1024;
+
+// TIFF field types
+const BYTE: u16 = 1;
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const UNDEFINED: u16 = 7;
+const SRATIONAL: u16 = 10;
+
+// Tags
+const IMAGE_DESCRIPTION: u16 = 0x010E;
+pub(crate) const TAG_MAKE: u16 = 0x010F;
+pub(crate) const TAG_MODEL: u16 = 0x0110;
+pub(crate) const ORIENTATION: u16 = 0x0112;
+pub(crate) const SOFTWARE: u16 = 0x0131;
+pub(crate) const DATE_TIME: u16 = 0x0132;
+const EXIF_IFD_POINTER: u16 = 0x8769;
+pub(crate) const EXPOSURE_TIME: u16 = 0x829A;
+pub(crate) const ISO_SPEED_RATINGS: u16 = 0x8827;
+const EXIF_VERSION: u16 = 0x9000;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+
+/// What a still's EXIF records
+#[derive(Debug, Clone, Default)]
+pub struct ExifFields {
+    /// Capture time, milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub exposure_s: Option<f64>,
+    pub iso: Option<u16>,
+    pub description: Option<String>,
+}
+
+/// Seconds per exposure line, `(width + hblank) / pixel_rate`, from the
+/// sensor's controls (None without them)
+pub fn line_time_s(controls: &[Control], width: usize) -> Option<f64> {
+    let value = |name: &str| controls.iter().find(|c| c.name == name).and_then(|c| c.value);
+    let hblank = value("horizontal_blanking").or_else(|| value("hblank"))?;
+    let pixel_rate = value("pixel_rate").filter(|&r| r > 0)?;
+    Some((width as i64 + hblank) as f64 / pixel_rate as f64)
+}
+
+impl ExifFields {
+    pub fn new(timestamp_ms: u64) -> Self {
+        Self { timestamp_ms, ..Default::default() }
+    }
+
+    /// Fill exposure time and ISO from the sensor's current controls:
+    /// exposure is in lines of `(width + hblank) / pixel_rate` seconds,
+    /// analogue gain in 0.3 dB steps
+    pub fn with_sensor_controls(mut self, controls: &[Control], width: usize) -> Self {
+        let value = |name: &str| controls.iter().find(|c| c.name == name).and_then(|c| c.value);
+        if let (Some(lines), Some(line_s)) = (value("exposure"), line_time_s(controls, width)) {
+            if lines > 0 {
+                self.exposure_s = Some(lines as f64 * line_s);
+            }
+        }
+        if let Some(gain) = value("analogue_gain") {
+            let iso = BASE_ISO * 10f64.powf(gain as f64 * GAIN_STEP_DB / 20.0);
+            self.iso = Some(iso.round().clamp(1.0, u16::MAX as f64) as u16);
+        }
+        self
+    }
+}
+
+/// One IFD entry; `data` is the encoded value
+pub(crate) struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    pub(crate) fn ascii(tag: u16, text: &str) -> Self {
+        let mut data = text.as_bytes().to_vec();
+        data.push(0);
+        Self { tag, kind: ASCII, count: data.len() as u32, data }
+    }
+
+    pub(crate) fn short(tag: u16, value: u16) -> Self {
+        Self { tag, kind: SHORT, count: 1, data: value.to_le_bytes().to_vec() }
+    }
+
+    pub(crate) fn long(tag: u16, value: u32) -> Self {
+        Self { tag, kind: LONG, count: 1, data: value.to_le_bytes().to_vec() }
+    }
+
+    pub(crate) fn rational(tag: u16, numerator: u32, denominator: u32) -> Self {
+        let mut data = numerator.to_le_bytes().to_vec();
+        data.extend_from_slice(&denominator.to_le_bytes());
+        Self { tag, kind: RATIONAL, count: 1, data }
+    }
+
+    pub(crate) fn undefined(tag: u16, bytes: &[u8]) -> Self {
+        Self { tag, kind: UNDEFINED, count: bytes.len() as u32, data: bytes.to_vec() }
+    }
+
+    pub(crate) fn bytes(tag: u16, bytes: &[u8]) -> Self {
+        Self { tag, kind: BYTE, count: bytes.len() as u32, data: bytes.to_vec() }
+    }
+
+    pub(crate) fn shorts(tag: u16, values: &[u16]) -> Self {
+        let data = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        Self { tag, kind: SHORT, count: values.len() as u32, data }
+    }
+
+    pub(crate) fn rationals(tag: u16, values: &[(u32, u32)]) -> Self {
+        let data = values.iter().flat_map(|&(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat()).collect();
+        Self { tag, kind: RATIONAL, count: values.len() as u32, data }
+    }
+
+    pub(crate) fn srationals(tag: u16, values: &[(i32, i32)]) -> Self {
+        let data = values.iter().flat_map(|&(n, d)| [n.to_le_bytes(), d.to_le_bytes()].concat()).collect();
+        Self { tag, kind: SRATIONAL, count: values.len() as u32, data }
+    }
+}
+
+/// Encode an IFD placed at `offset` in the TIFF structure: the entry table
+/// followed by the values that don't fit in an entry
+pub(crate) fn encode_ifd(mut entries: Vec<Entry>, offset: usize) -> Vec<u8> {
+    entries.sort_by_key(|e| e.tag);
+    let table_len = 2 + 12 * entries.len() + 4;
+    let mut table = Vec::with_capacity(table_len);
+    let mut values = Vec::new();
+    table.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for entry in &entries {
+        table.extend_from_slice(&entry.tag.to_le_bytes());
+        table.extend_from_slice(&entry.kind.to_le_bytes());
+        table.extend_from_slice(&entry.count.to_le_bytes());
+        if entry.data.len() <= 4 {
+            let mut inline = [0u8; 4];
+            inline[..entry.data.len()].copy_from_slice(&entry.data);
+            table.extend_from_slice(&inline);
+        } else {
+            let at = offset + table_len + values.len();
+            table.extend_from_slice(&(at as u32).to_le_bytes());
+            values.extend_from_slice(&entry.data);
+            // Values start on word boundaries
+            if values.len() % 2 == 1 {
+                values.push(0);
+            }
+        }
+    }
+    table.extend_from_slice(&0u32.to_le_bytes()); // no next IFD
+    table.extend_from_slice(&values);
+    table
+}
+
+/// Exposure time as a rational, `1/n` below one second
+pub(crate) fn exposure_rational(seconds: f64) -> (u32, u32) {
+    if seconds < 1.0 {
+        (1, (1.0 / seconds).round().clamp(1.0, u32::MAX as f64) as u32)
+    } else {
+        ((seconds * 1000.0).round().min(u32::MAX as f64) as u32, 1000)
+    }
+}
+
+/// Capture time in local time
+pub(crate) fn local_time(timestamp_ms: u64) -> DateTime<Local> {
+    Local.timestamp_millis_opt(timestamp_ms as i64).single().unwrap_or_else(Local::now)
+}
+
+/// A time as TIFF DateTime values are written
+pub(crate) fn tiff_date_time(time: &DateTime<Local>) -> String {
+    time.format("%Y:%m:%d %H:%M:%S").to_string()
+}
+
+/// Software tag value
+pub(crate) fn software() -> String {
+    format!("imx415_streamer {}", env!("CARGO_PKG_VERSION"))
+}
+
+/// The TIFF structure (after the `Exif\0\0` header) for `fields`
+fn encode_tiff(fields: &ExifFields) -> Vec<u8> {
+    let time = local_time(fields.timestamp_ms);
+    let stamp = tiff_date_time(&time);
+
+    let software = software();
+    let ifd0 = |exif_offset: usize| {
+        let mut entries = vec![
+            Entry::ascii(TAG_MAKE, MAKE),
+            Entry::ascii(TAG_MODEL, MODEL),
+            Entry::short(ORIENTATION, ORIENTATION_UPRIGHT),
+            Entry::ascii(SOFTWARE, &software),
+            Entry::ascii(DATE_TIME, &stamp),
+            Entry::long(EXIF_IFD_POINTER, exif_offset as u32),
+        ];
+        if let Some(ref description) = fields.description {
+            entries.push(Entry::ascii(IMAGE_DESCRIPTION, description));
+        }
+        entries
+    };
+
+    let mut exif = vec![
+        Entry::undefined(EXIF_VERSION, b"0232"),
+        Entry::ascii(DATE_TIME_ORIGINAL, &stamp),
+        Entry::ascii(OFFSET_TIME_ORIGINAL, &time.format("%:z").to_string()),
+        Entry::ascii(SUB_SEC_TIME_ORIGINAL, &format!("{:03}", fields.timestamp_ms % 1000)),
+    ];
+    if let Some(seconds) = fields.exposure_s {
+        let (numerator, denominator) = exposure_rational(seconds);
+        exif.push(Entry::rational(EXPOSURE_TIME, numerator, denominator));
+    }
+    if let Some(iso) = fields.iso {
+        exif.push(Entry::short(ISO_SPEED_RATINGS, iso));
+    }
+
+    // The pointer is inline, so IFD0's size doesn't depend on its value
+    const IFD0_OFFSET: usize = 8;
+    let exif_offset = IFD0_OFFSET + encode_ifd(ifd0(0), IFD0_OFFSET).len();
+
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&(IFD0_OFFSET as u32).to_le_bytes());
+    tiff.extend_from_slice(&encode_ifd(ifd0(exif_offset), IFD0_OFFSET));
+    tiff.extend_from_slice(&encode_ifd(exif, exif_offset));
+    tiff
+}
+
+/// Insert an EXIF segment for `fields` into an encoded JPEG (after SOI and
+/// any JFIF segment)
+pub fn insert(jpeg: &[u8], fields: &ExifFields) -> Result<Vec<u8>> {
+    anyhow::ensure!(jpeg.starts_with(&[0xFF, 0xD8]), "Not a JPEG");
+    let mut fields = fields.clone();
+    if fields.description.as_ref().is_some_and(|d| d.len() > MAX_DESCRIPTION_BYTES) {
+        fields.description = None;
+    }
+    let mut payload = b"Exif\0\0".to_vec();
+    payload.extend_from_slice(&encode_tiff(&fields));
+    let segment_len = payload.len() + 2;
+    anyhow::ensure!(segment_len <= u16::MAX as usize, "EXIF segment too large");
+
+    // Keep JFIF (APP0) first, as readers expect
+    let mut at = 2;
+    if jpeg.len() >= 6 && jpeg[2..4] == [0xFF, 0xE0] {
+        at += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+        anyhow::ensure!(at <= jpeg.len(), "Truncated JPEG");
+    }
+    let mut out = Vec::with_capacity(jpeg.len() + segment_len + 2);
+    out.extend_from_slice(&jpeg[..at]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&jpeg[at..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::exif::{In, Reader, Tag, Value};
+
+    fn control(name: &str, value: i64) -> Control {
+        Control {
+            name: name.to_string(),
+            id: 0,
+            kind: "int".to_string(),
+            min: None,
+            max: None,
+            step: None,
+            default: None,
+            value: Some(value),
+            flags: Vec::new(),
+            menu: Vec::new(),
+        }
+    }
+
+    /// A small encoded JPEG, as the snapshot encoder writes them
+    fn jpeg() -> Vec<u8> {
+        let image = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 128]));
+        crate::capture::encode_image_jpeg(&image::DynamicImage::ImageRgb8(image), 90).unwrap()
+    }
+
+    fn read(jpeg: &[u8]) -> ::exif::Exif {
+        Reader::new().read_from_container(&mut std::io::Cursor::new(jpeg)).unwrap()
+    }
+
+    fn ascii(exif: &::exif::Exif, tag: Tag, ifd: In) -> String {
+        match exif.get_field(tag, ifd).unwrap_or_else(|| panic!("{} missing", tag)).value {
+            Value::Ascii(ref values) => String::from_utf8(values[0].clone()).unwrap(),
+            ref other => panic!("{}: {:?}", tag, other),
+        }
+    }
+
+    fn uint(exif: &::exif::Exif, tag: Tag) -> u32 {
+        exif.get_field(tag, In::PRIMARY).unwrap_or_else(|| panic!("{} missing", tag)).value.get_uint(0).unwrap()
+    }
+
+    #[test]
+    fn a_reader_recovers_the_written_fields() {
+        // 3864 + 1136 pixels per line at 500 MP/s: 10 us lines
+        let controls = [
+            control("exposure", 1000),
+            control("horizontal_blanking", 1136),
+            control("pixel_rate", 500_000_000),
+            control("analogue_gain", 100),
+        ];
+        let timestamp_ms = 1_760_000_000_123;
+        let description = r#"{"detections":[{"class":"person","confidence":0.9}]}"#;
+        let fields = ExifFields { description: Some(description.to_string()), ..ExifFields::new(timestamp_ms) }
+            .with_sensor_controls(&controls, 3864);
+        let original = jpeg();
+        let tagged = insert(&original, &fields).unwrap();
+        let exif = read(&tagged);
+
+        assert_eq!(ascii(&exif, Tag::Make, In::PRIMARY), MAKE);
+        assert_eq!(ascii(&exif, Tag::Model, In::PRIMARY), MODEL);
+        assert_eq!(ascii(&exif, Tag::Software, In::PRIMARY), software());
+        assert_eq!(uint(&exif, Tag::Orientation), ORIENTATION_UPRIGHT as u32);
+        let time = local_time(timestamp_ms);
+        assert_eq!(ascii(&exif, Tag::DateTime, In::PRIMARY), tiff_date_time(&time));
+        assert_eq!(ascii(&exif, Tag::DateTimeOriginal, In::PRIMARY), tiff_date_time(&time));
+        assert_eq!(ascii(&exif, Tag::SubSecTimeOriginal, In::PRIMARY), "123");
+        assert_eq!(ascii(&exif, Tag::OffsetTimeOriginal, In::PRIMARY), time.format("%:z").to_string());
+
+        // 1000 lines of 10 us; 100 steps of 0.3 dB is 30 dB
+        match exif.get_field(Tag::ExposureTime, In::PRIMARY).unwrap().value {
+            Value::Rational(ref values) => assert_eq!((values[0].num, values[0].denom), (1, 100)),
+            ref other => panic!("ExposureTime: {:?}", other),
+        }
+        assert_eq!(uint(&exif, Tag::PhotographicSensitivity), 3162);
+
+        let written: serde_json::Value = serde_json::from_str(&ascii(&exif, Tag::ImageDescription, In::PRIMARY)).unwrap();
+        assert_eq!(written, serde_json::from_str::<serde_json::Value>(description).unwrap());
+
+        // JFIF stays the first segment and the pixels are untouched
+        assert_eq!(tagged[2..4], [0xFF, 0xE0]);
+        let app1 = 2 + 2 + u16::from_be_bytes([original[4], original[5]]) as usize;
+        assert_eq!(tagged[app1..app1 + 2], [0xFF, 0xE1]);
+        assert_eq!(
+            image::load_from_memory(&tagged).unwrap().to_rgb8(),
+            image::load_from_memory(&original).unwrap().to_rgb8()
+        );
+    }
+
+    #[test]
+    fn fields_without_a_source_are_left_out() {
+        let exif = read(&insert(&jpeg(), &ExifFields::new(1_760_000_000_000)).unwrap());
+        assert!(exif.get_field(Tag::ExposureTime, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::PhotographicSensitivity, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::ImageDescription, In::PRIMARY).is_none());
+
+        // Without a line time there is no exposure time, only the ISO
+        let fields = ExifFields::new(0).with_sensor_controls(&[control("exposure", 1000), control("analogue_gain", 0)], 3864);
+        assert_eq!((fields.exposure_s, fields.iso), (None, Some(100)));
+
+        let fields = ExifFields { description: Some("x".repeat(MAX_DESCRIPTION_BYTES + 1)), ..ExifFields::new(0) };
+        let exif = read(&insert(&jpeg(), &fields).unwrap());
+        assert!(exif.get_field(Tag::ImageDescription, In::PRIMARY).is_none());
+    }
+
+    #[test]
+    fn exposure_times_are_written_as_photographers_read_them() {
+        assert_eq!(exposure_rational(0.01), (1, 100));
+        assert_eq!(exposure_rational(1.0 / 30.0), (1, 30));
+        assert_eq!(exposure_rational(2.5), (2500, 1000));
+    }
+
+    #[test]
+    fn only_jpegs_are_tagged() {
+        assert!(insert(b"\x89PNG\r\n", &ExifFields::new(0)).is_err());
+        assert!(insert(b"\xFF\xD8\xFF\xE0\xFF\xFF", &ExifFields::new(0)).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Face sightings and crops (doorbell mode)
+//!
+//! Every new detection result containing `face` detections is logged with
+//! its frame's sequence number and capture time, and the most confident
+//! face is cut out as a square crop: the box grown by `expand` around its
+//! centre, moved inside the frame and scaled to `size`. Crops are cut from
+//! decoded pixels (the pipeline's pre-encode buffer while the frame is
+//! still current), never from an annotated frame.
+//!
+//! Crops are taken whenever faces appear and at most every
+//! `CROP_INTERVAL_MS` while they stay, so a face in front of the door
+//! doesn't cost a crop per inference.
+
+use crate::detector::{BBox, DetectionResult, Point};
+use anyhow::Result;
+use bytes::Bytes;
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use serde::Serialize;
+use std::collections::VecDeque;
+use utoipa::ToSchema;
+
+/// Class name face models report
+pub const FACE_CLASS: &str = "face";
+
+/// Default crop edge length (pixels)
+pub const DEFAULT_CROP_SIZE: u32 = 256;
+
+/// Default crop size relative to the face box
+pub const DEFAULT_CROP_EXPAND: f32 = 1.5;
+
+/// Sightings kept for GET /faces
+pub const RECENT_FACES: usize = 50;
+
+/// Minimum time between crops while faces stay in view
+pub const CROP_INTERVAL_MS: u64 = 1000;
+
+/// JPEG quality of crops
+const CROP_QUALITY: u8 = 90;
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct FaceCropConfig {
+    /// Edge length of the (square) crop
+    pub size: u32,
+    /// Crop edge relative to the longer side of the face box
+    pub expand: f32,
+}
+
+impl Default for FaceCropConfig {
+    fn default() -> Self {
+        Self { size: DEFAULT_CROP_SIZE, expand: DEFAULT_CROP_EXPAND }
+    }
+}
+
+/// One face in one detection result
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FaceRecord {
+    pub frame_seq: u64,
+    /// Capture time of the frame, milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub confidence: f32,
+    pub bbox: BBox,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub landmarks: Vec<Point>,
+}
+
+/// The latest face crop
+#[derive(Debug, Clone)]
+pub struct FaceCrop {
+    pub face: FaceRecord,
+    pub jpeg: Bytes,
+}
+
+/// A crop to take, from a new detection result
+#[derive(Debug, Clone)]
+pub struct CropRequest {
+    /// The most confident face
+    pub face: FaceRecord,
+    /// Faces in the result
+    pub count: usize,
+    /// No faces were in the previous result
+    pub appeared: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct FaceStore {
+    config: FaceCropConfig,
+    /// Newest last
+    recent: VecDeque<FaceRecord>,
+    last_crop: Option<FaceCrop>,
+    last_seq: Option<u64>,
+    present: bool,
+    last_crop_request_ms: u64,
+    sightings: u64,
+}
+
+impl FaceStore {
+    pub fn new(config: FaceCropConfig) -> Self {
+        Self { config, ..Default::default() }
+    }
+
+    pub fn config(&self) -> FaceCropConfig {
+        self.config
+    }
+
+    /// Log the faces of a detection result for the frame captured at
+    /// `timestamp_ms`; returns the crop to take, if one is due. Repeated
+    /// results for the same frame and failed runs are ignored.
+    pub fn observe(&mut self, result: &DetectionResult, timestamp_ms: u64) -> Option<CropRequest> {
+        let frame_seq = result.frame_seq?;
+        if result.error.is_some() || self.last_seq == Some(frame_seq) {
+            return None;
+        }
+        self.last_seq = Some(frame_seq);
+
+        let faces: Vec<FaceRecord> = result
+            .detections
+            .iter()
+            .filter(|d| d.class == FACE_CLASS)
+            .map(|d| FaceRecord {
+                frame_seq,
+                timestamp_ms,
+                confidence: d.confidence,
+                bbox: d.bbox.clone(),
+                landmarks: d.landmarks.clone(),
+            })
+            .collect();
+        let appeared = !faces.is_empty() && !self.present;
+        self.present = !faces.is_empty();
+        let best = faces.iter().max_by(|a, b| a.confidence.total_cmp(&b.confidence)).cloned()?;
+
+        let count = faces.len();
+        self.sightings += count as u64;
+        for face in faces {
+            if self.recent.len() == RECENT_FACES {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(face);
+        }
+
+        if !appeared && timestamp_ms < self.last_crop_request_ms + CROP_INTERVAL_MS {
+            return None;
+        }
+        self.last_crop_request_ms = timestamp_ms;
+        Some(CropRequest { face: best, count, appeared })
+    }
+
+    /// Keep a finished crop, unless a newer one is already stored
+    pub fn store_crop(&mut self, crop: FaceCrop) {
+        if self.last_crop.as_ref().is_none_or(|c| c.face.frame_seq <= crop.face.frame_seq) {
+            self.last_crop = Some(crop);
+        }
+    }
+
+    pub fn last_crop(&self) -> Option<&FaceCrop> {
+        self.last_crop.as_ref()
+    }
+
+    /// Logged faces, newest first
+    pub fn recent(&self) -> Vec<FaceRecord> {
+        self.recent.iter().rev().cloned().collect()
+    }
+
+    /// Faces in view as of the latest result
+    pub fn present(&self) -> bool {
+        self.present
+    }
+
+    /// Faces logged since startup
+    pub fn sightings(&self) -> u64 {
+        self.sightings
+    }
+
+    /// Forget presence (detector restarted); the log and crop stay
+    pub fn reset(&mut self) {
+        self.present = false;
+        self.last_seq = None;
+    }
+}
+
+/// Square crop rectangle `(x, y, side)` around a face box, grown by
+/// `expand`, moved inside a `width` x `height` frame and no larger than it
+pub fn crop_rect(bbox: &BBox, expand: f32, width: u32, height: u32) -> (u32, u32, u32) {
+    let longest = (bbox.x2 - bbox.x1).max(bbox.y2 - bbox.y1).max(1) as f32;
+    let side = ((longest * expand).round() as u32).clamp(1, width.min(height).max(1));
+    let centre = |a: i32, b: i32| (a + b) as f32 / 2.0;
+    let start = |centre: f32, extent: u32| {
+        (centre - side as f32 / 2.0).round().clamp(0.0, extent.saturating_sub(side) as f32) as u32
+    };
+    (start(centre(bbox.x1, bbox.x2), width), start(centre(bbox.y1, bbox.y2), height), side)
+}
+
+/// Cut and encode the crop of `bbox` from a frame's pixels
+pub fn crop_face(image: &DynamicImage, bbox: &BBox, config: FaceCropConfig) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let (x, y, side) = crop_rect(bbox, config.expand, width, height);
+    let crop = image.crop_imm(x, y, side, side);
+    let crop = if side == config.size {
+        crop
+    } else {
+        crop.resize_exact(config.size, config.size, FilterType::Triangle)
+    };
+    crate::capture::encode_image_jpeg(&crop, CROP_QUALITY)
+}
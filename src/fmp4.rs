@@ -0,0 +1,281 @@
+//! Fragmented MP4 muxing of an H.264 elementary stream
+//!
+//! A live stream is one init segment (`ftyp` + `moov` with the SPS/PPS in
+//! `avcC` and an empty sample table) followed by one `moof` + `mdat`
+//! fragment per access unit, so a browser can start playing from the
+//! first keyframe it receives and never waits for the end of the file.
+//! Samples are length-prefixed NAL units (AVCC); the encoder's Annex B
+//! output is converted with [`annexb_to_avcc`].
+
+/// Media timescale (the usual 90 kHz video clock)
+pub const TIMESCALE: u32 = 90_000;
+
+const TRACK_ID: u32 = 1;
+
+/// NAL unit types
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+/// `sample_flags`: a sync sample depending on no other
+const SAMPLE_FLAGS_KEY: u32 = 0x0200_0000;
+/// `sample_flags`: depends on others, not a sync sample
+const SAMPLE_FLAGS_DELTA: u32 = 0x0101_0000;
+
+/// NAL units of an Annex B byte stream, start codes removed
+pub fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+            // A four-byte start code leaves its leading zero on the unit before
+            let mut nal = &data[start..end];
+            while nal.last() == Some(&0) {
+                nal = &nal[..nal.len() - 1];
+            }
+            nal
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+fn nal_type(nal: &[u8]) -> u8 {
+    nal[0] & 0x1f
+}
+
+/// SPS and PPS from an Annex B header
+pub fn parameter_sets(header: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let nals = split_annexb(header);
+    let find = |kind| nals.iter().find(|nal| nal_type(nal) == kind).map(|nal| nal.to_vec());
+    Some((find(NAL_SPS)?, find(NAL_PPS)?))
+}
+
+/// An access unit as an MP4 sample; parameter sets and delimiters are
+/// dropped (they live in `avcC`). Returns the sample and whether it is an
+/// IDR frame.
+pub fn annexb_to_avcc(data: &[u8]) -> (Vec<u8>, bool) {
+    let mut sample = Vec::with_capacity(data.len());
+    let mut keyframe = false;
+    for nal in split_annexb(data) {
+        match nal_type(nal) {
+            NAL_SPS | NAL_PPS | NAL_AUD => continue,
+            NAL_IDR => keyframe = true,
+            _ => {}
+        }
+        sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        sample.extend_from_slice(nal);
+    }
+    (sample, keyframe)
+}
+
+/// An MP4 sample back as Annex B, with `prefix` NAL units (parameter
+/// sets) in front
+#[cfg(feature = "webrtc")]
+pub fn avcc_to_annexb(sample: &[u8], prefix: &[&[u8]]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(sample.len() + 64);
+    for nal in prefix {
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(nal);
+    }
+    let mut rest = sample;
+    while rest.len() >= 4 {
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let Some(nal) = rest.get(4..4 + length) else {
+            break;
+        };
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(nal);
+        rest = &rest[4 + length..];
+    }
+    data
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(out: &mut Vec<u8>, kind: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&((version as u32) << 24 | flags).to_be_bytes());
+        body(out);
+    });
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Identity transformation matrix (16.16 and 2.30 fixed point)
+fn put_matrix(out: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        put_u32(out, value);
+    }
+}
+
+/// `ftyp` + `moov` for one H.264 track of `width` x `height`
+pub fn init_segment(width: u32, height: u32, sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1024);
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        put_u32(out, 0x200);
+        for brand in [b"isom", b"iso6", b"avc1", b"mp41"] {
+            out.extend_from_slice(brand);
+        }
+    });
+    write_box(&mut out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            // Creation and modification time, timescale (ms), duration
+            for value in [0, 0, 1000, 0] {
+                put_u32(out, value);
+            }
+            put_u32(out, 0x0001_0000); // rate 1.0
+            put_u16(out, 0x0100); // volume 1.0
+            out.extend_from_slice(&[0; 10]);
+            put_matrix(out);
+            out.extend_from_slice(&[0; 24]);
+            put_u32(out, TRACK_ID + 1); // next track ID
+        });
+        write_box(out, b"trak", |out| {
+            // Enabled, in movie
+            write_full_box(out, b"tkhd", 0, 0x3, |out| {
+                for value in [0, 0, TRACK_ID, 0, 0] {
+                    put_u32(out, value);
+                }
+                // Reserved, layer, alternate group, volume, reserved
+                out.extend_from_slice(&[0; 16]);
+                put_matrix(out);
+                put_u32(out, width << 16);
+                put_u32(out, height << 16);
+            });
+            write_box(out, b"mdia", |out| {
+                write_full_box(out, b"mdhd", 0, 0, |out| {
+                    for value in [0, 0, TIMESCALE, 0] {
+                        put_u32(out, value);
+                    }
+                    put_u16(out, 0x55c4); // "und"
+                    put_u16(out, 0);
+                });
+                write_full_box(out, b"hdlr", 0, 0, |out| {
+                    put_u32(out, 0);
+                    out.extend_from_slice(b"vide");
+                    out.extend_from_slice(&[0; 12]);
+                    out.extend_from_slice(b"VideoHandler\0");
+                });
+                write_box(out, b"minf", |out| {
+                    write_full_box(out, b"vmhd", 0, 0x1, |out| out.extend_from_slice(&[0; 8]));
+                    write_box(out, b"dinf", |out| {
+                        write_full_box(out, b"dref", 0, 0, |out| {
+                            put_u32(out, 1);
+                            // Media data in this file
+                            write_full_box(out, b"url ", 0, 0x1, |_| {});
+                        });
+                    });
+                    write_box(out, b"stbl", |out| {
+                        write_full_box(out, b"stsd", 0, 0, |out| {
+                            put_u32(out, 1);
+                            write_avc1(out, width, height, sps, pps);
+                        });
+                        // Samples are all in the fragments
+                        for kind in [b"stts", b"stsc", b"stco"] {
+                            write_full_box(out, kind, 0, 0, |out| put_u32(out, 0));
+                        }
+                        write_full_box(out, b"stsz", 0, 0, |out| out.extend_from_slice(&[0; 8]));
+                    });
+                });
+            });
+        });
+        write_box(out, b"mvex", |out| {
+            write_full_box(out, b"trex", 0, 0, |out| {
+                // Track, sample description 1, no defaults
+                for value in [TRACK_ID, 1, 0, 0, 0] {
+                    put_u32(out, value);
+                }
+            });
+        });
+    });
+    out
+}
+
+fn write_avc1(out: &mut Vec<u8>, width: u32, height: u32, sps: &[u8], pps: &[u8]) {
+    write_box(out, b"avc1", |out| {
+        out.extend_from_slice(&[0; 6]);
+        put_u16(out, 1); // data reference index
+        out.extend_from_slice(&[0; 16]);
+        put_u16(out, width as u16);
+        put_u16(out, height as u16);
+        put_u32(out, 0x0048_0000); // 72 dpi
+        put_u32(out, 0x0048_0000);
+        put_u32(out, 0);
+        put_u16(out, 1); // frames per sample
+        out.extend_from_slice(&[0; 32]); // compressor name
+        put_u16(out, 0x0018); // depth
+        put_u16(out, 0xffff);
+        write_box(out, b"avcC", |out| {
+            out.push(1);
+            out.extend_from_slice(&sps[1..4]); // profile, compatibility, level
+            out.push(0xff); // 4-byte NAL lengths
+            out.push(0xe1); // one SPS
+            put_u16(out, sps.len() as u16);
+            out.extend_from_slice(sps);
+            out.push(1);
+            put_u16(out, pps.len() as u16);
+            out.extend_from_slice(pps);
+            // High profiles also carry the chroma format and bit depths
+            if matches!(sps[1], 100 | 110 | 122 | 144) {
+                out.extend_from_slice(&[0xfc | 1, 0xf8, 0xf8, 0]);
+            }
+        });
+    });
+}
+
+/// One `moof` + `mdat` holding a single sample
+///
+/// `sequence` counts fragments from 1; `decode_time` and `duration` are in
+/// [`TIMESCALE`] units.
+pub fn fragment(sequence: u32, decode_time: u64, duration: u32, sample: &[u8], keyframe: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(sample.len() + 128);
+    let mut data_offset_at = 0;
+    write_box(&mut out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| put_u32(out, sequence));
+        write_box(out, b"traf", |out| {
+            // Offsets are relative to the moof
+            write_full_box(out, b"tfhd", 0, 0x02_0000, |out| put_u32(out, TRACK_ID));
+            write_full_box(out, b"tfdt", 1, 0, |out| out.extend_from_slice(&decode_time.to_be_bytes()));
+            // Data offset, sample duration, size and flags present
+            write_full_box(out, b"trun", 0, 0x0701, |out| {
+                put_u32(out, 1);
+                data_offset_at = out.len();
+                put_u32(out, 0);
+                put_u32(out, duration);
+                put_u32(out, sample.len() as u32);
+                put_u32(out, if keyframe { SAMPLE_FLAGS_KEY } else { SAMPLE_FLAGS_DELTA });
+            });
+        });
+    });
+    // The sample follows the mdat header
+    let data_offset = (out.len() + 8) as u32;
+    out[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+    write_box(&mut out, b"mdat", |out| out.extend_from_slice(sample));
+    out
+}
@@ -0,0 +1,120 @@
+//! Focus metric for manual focusing
+//!
+//! Runs in the capture loop on the pixels of each published frame (after
+//! the orientation, masks and digital zoom, so zooming in on a detail
+//! focuses on that detail). The score is the variance of the 4-neighbour
+//! Laplacian of the luma over a region of interest: a sharper image has
+//! stronger edges and so a larger variance. Scores only compare between
+//! frames of the same scene and region; the peak since the region last
+//! changed is kept so the lens can be turned past the best point and back.
+//!
+//! The region is read at full resolution, so it's kept small by default:
+//! the central quarter of each edge (a sixteenth of the frame).
+
+use image::DynamicImage;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::metering::Roi;
+
+/// Region scored when none is set
+pub const DEFAULT_ROI: Roi = Roi { x: 0.375, y: 0.375, w: 0.25, h: 0.25 };
+
+/// Latest score of the focus meter
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct FocusReading {
+    /// Frame the score is from
+    pub seq: u64,
+    /// Variance of the Laplacian over the region (higher is sharper)
+    pub score: f32,
+    /// Highest score since the region was set or the peak reset
+    pub peak: f32,
+    pub roi: Roi,
+}
+
+/// Focus meter state, updated by the capture loop
+#[derive(Debug)]
+pub struct FocusMeter {
+    roi: Roi,
+    peak: f32,
+    latest: Option<(u64, f32)>,
+}
+
+impl Default for FocusMeter {
+    fn default() -> Self {
+        Self { roi: DEFAULT_ROI, peak: 0.0, latest: None }
+    }
+}
+
+impl FocusMeter {
+    pub fn roi(&self) -> Roi {
+        self.roi
+    }
+
+    /// Score from now on over `roi` (a new region starts a new peak)
+    pub fn set_roi(&mut self, roi: Roi) {
+        if roi != self.roi {
+            self.roi = roi;
+            self.peak = 0.0;
+            self.latest = None;
+        }
+    }
+
+    /// Start a new peak from the latest score
+    pub fn reset_peak(&mut self) {
+        self.peak = self.latest.map_or(0.0, |(_, score)| score);
+    }
+
+    /// Score frame `seq` and keep the result
+    pub fn update(&mut self, seq: u64, image: &DynamicImage) {
+        let score = sharpness(image, self.roi);
+        self.peak = self.peak.max(score);
+        self.latest = Some((seq, score));
+    }
+
+    /// The latest score, if a frame has been scored since the region was set
+    pub fn reading(&self) -> Option<FocusReading> {
+        self.latest.map(|(seq, score)| FocusReading { seq, score, peak: self.peak, roi: self.roi })
+    }
+}
+
+/// Variance of the Laplacian of the luma over `roi` of an 8-bit frame
+/// (0 for other formats and regions under 3x3 pixels)
+pub fn sharpness(image: &DynamicImage, roi: Roi) -> f32 {
+    let (x1, y1, x2, y2) = roi.to_pixels(image.width(), image.height());
+    let (width, height) = ((x2 - x1) as usize, (y2 - y1) as usize);
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let mut luma = Vec::with_capacity(width * height);
+    match image {
+        DynamicImage::ImageLuma8(gray) => {
+            for y in y1..y2 {
+                let row = (y * gray.width()) as usize;
+                luma.extend_from_slice(&gray.as_raw()[row + x1 as usize..row + x2 as usize]);
+            }
+        }
+        DynamicImage::ImageRgb8(rgb) => {
+            for y in y1..y2 {
+                let row = (y * rgb.width()) as usize * 3;
+                let pixels = &rgb.as_raw()[row + x1 as usize * 3..row + x2 as usize * 3];
+                luma.extend(pixels.chunks_exact(3).map(|p| {
+                    ((77 * p[0] as u32 + 150 * p[1] as u32 + 29 * p[2] as u32) >> 8) as u8
+                }));
+            }
+        }
+        _ => return 0.0,
+    }
+    let (mut sum, mut sum_sq) = (0i64, 0i64);
+    for y in 1..height - 1 {
+        let (above, row, below) = (&luma[(y - 1) * width..], &luma[y * width..], &luma[(y + 1) * width..]);
+        for x in 1..width - 1 {
+            let laplacian = 4 * row[x] as i64 - row[x - 1] as i64 - row[x + 1] as i64 - above[x] as i64 - below[x] as i64;
+            sum += laplacian;
+            sum_sq += laplacian * laplacian;
+        }
+    }
+    let count = ((width - 2) * (height - 2)) as f64;
+    let mean = sum as f64 / count;
+    (sum_sq as f64 / count - mean * mean) as f32
+}
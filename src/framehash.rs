@@ -0,0 +1,70 @@
+//! Content hashes of published frames
+//!
+//! Every published JPEG (annotated and clean) is hashed with xxHash64 in
+//! the capture loop, so archivers polling /frame.jpg can skip byte-identical
+//! frames (`X-Frame-Hash`, /frame/changed). The clean frame's hash also
+//! drives the static-scene indicator: a run of identical frames means the
+//! scene (or the camera) is frozen, which frame age alone doesn't show
+//! while frames keep being published.
+
+use serde::Serialize;
+use xxhash_rust::xxh64::xxh64;
+
+/// Response header carrying the frame hash
+pub const HEADER: &str = "x-frame-hash";
+
+/// Identical consecutive frames before the scene counts as static, unless
+/// `--static-scene-frames` is given
+pub const DEFAULT_STATIC_SCENE_FRAMES: u64 = 30;
+
+/// xxHash64 of a frame (seed 0)
+pub fn hash(data: &[u8]) -> u64 {
+    xxh64(data, 0)
+}
+
+/// Hash as sent to clients: 16 lowercase hex digits
+pub fn to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value.trim(), 16).ok()
+}
+
+/// Hashes of the latest published frame
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FrameHashes {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    #[serde(serialize_with = "serialize_hex")]
+    pub annotated: u64,
+    #[serde(serialize_with = "serialize_hex")]
+    pub clean: u64,
+    /// Frames in a row whose clean frame was identical to this one
+    pub unchanged_frames: u64,
+    /// Pipeline settings version the frame was processed with
+    pub settings_version: u64,
+}
+
+impl FrameHashes {
+    /// Hashes for a new frame, continuing the unchanged run of `previous`
+    pub fn next(
+        previous: Option<&FrameHashes>,
+        seq: u64,
+        timestamp_ms: u64,
+        settings_version: u64,
+        annotated: &[u8],
+        clean: &[u8],
+    ) -> Self {
+        let clean = hash(clean);
+        let unchanged_frames = match previous {
+            Some(p) if p.clean == clean => p.unchanged_frames + 1,
+            _ => 0,
+        };
+        Self { seq, timestamp_ms, annotated: hash(annotated), clean, unchanged_frames, settings_version }
+    }
+}
+
+fn serialize_hex<S: serde::Serializer>(hash: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(*hash))
+}
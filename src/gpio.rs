@@ -0,0 +1,276 @@
+//! Frame-synchronized GPIO via the sysfs interface
+//!
+//! An optional strobe output is asserted just before each raw capture and
+//! deasserted after it (e.g. to fire an IR illuminator only during
+//! exposure), and an optional trigger input lets an external edge start
+//! each capture instead of the interval timer.
+//!
+//! Everything here blocks (sysfs writes, `poll(2)`, lead/lag sleeps) and is
+//! only called from the capture loop's blocking section. Pins exported by
+//! us are unexported again on drop.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+const GPIO_ROOT: &str = "/sys/class/gpio";
+
+/// How long to wait for udev to fix permissions on a freshly exported pin
+const EXPORT_SETTLE: Duration = Duration::from_millis(100);
+const EXPORT_RETRIES: u32 = 10;
+
+/// Default wait for a trigger edge before giving up on a capture cycle
+pub const DEFAULT_TRIGGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Trigger edge
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+impl Edge {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "rising" => Some(Edge::Rising),
+            "falling" => Some(Edge::Falling),
+            "both" => Some(Edge::Both),
+            _ => None,
+        }
+    }
+
+    fn sysfs_name(self) -> &'static str {
+        match self {
+            Edge::Rising => "rising",
+            Edge::Falling => "falling",
+            Edge::Both => "both",
+        }
+    }
+}
+
+/// An exported sysfs GPIO line
+struct SysfsPin {
+    number: u32,
+    value: File,
+    exported_by_us: bool,
+}
+
+impl SysfsPin {
+    fn dir(number: u32) -> PathBuf {
+        PathBuf::from(GPIO_ROOT).join(format!("gpio{}", number))
+    }
+
+    fn write_attr(number: u32, attr: &str, value: &str) -> Result<()> {
+        let path = Self::dir(number).join(attr);
+        // Attributes may not be writable until udev has run
+        let mut last_err = None;
+        for _ in 0..EXPORT_RETRIES {
+            match std::fs::write(&path, value) {
+                Ok(()) => return Ok(()),
+                Err(e) => last_err = Some(e),
+            }
+            std::thread::sleep(EXPORT_SETTLE);
+        }
+        Err(last_err.unwrap()).with_context(|| format!("Failed to write {} to {}", value, path.display()))
+    }
+
+    fn open(number: u32, direction: &str, edge: Option<Edge>) -> Result<Self> {
+        let exported_by_us = !Self::dir(number).exists();
+        if exported_by_us {
+            std::fs::write(format!("{}/export", GPIO_ROOT), number.to_string())
+                .with_context(|| format!("Failed to export GPIO {}", number))?;
+        }
+        Self::write_attr(number, "direction", direction)?;
+        if let Some(edge) = edge {
+            Self::write_attr(number, "edge", edge.sysfs_name())?;
+        }
+        let value = OpenOptions::new()
+            .read(true)
+            .write(direction != "in")
+            .open(Self::dir(number).join("value"))
+            .with_context(|| format!("Failed to open GPIO {} value", number))?;
+        Ok(Self { number, value, exported_by_us })
+    }
+
+    fn set(&mut self, high: bool) -> Result<()> {
+        self.value.seek(SeekFrom::Start(0))?;
+        self.value.write_all(if high { b"1" } else { b"0" })?;
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<bool> {
+        let mut buf = [0u8; 2];
+        self.value.seek(SeekFrom::Start(0))?;
+        let n = self.value.read(&mut buf)?;
+        Ok(n > 0 && buf[0] == b'1')
+    }
+
+    /// Wait for an edge interrupt (POLLPRI) on the value file
+    fn wait_edge(&mut self, timeout: Duration) -> Result<bool> {
+        // Reading clears any pending edge so only new edges count
+        self.read()?;
+        let mut fd = libc::pollfd {
+            fd: self.value.as_raw_fd(),
+            events: libc::POLLPRI | libc::POLLERR,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        // SAFETY: `fd` is a valid pollfd for the lifetime of the call
+        let ready = unsafe { libc::poll(&mut fd, 1, timeout_ms) };
+        if ready < 0 {
+            return Err(std::io::Error::last_os_error()).context("poll on trigger GPIO failed");
+        }
+        if ready > 0 {
+            self.read()?;
+        }
+        Ok(ready > 0)
+    }
+}
+
+impl Drop for SysfsPin {
+    fn drop(&mut self) {
+        if self.exported_by_us {
+            let _ = std::fs::write(format!("{}/unexport", GPIO_ROOT), self.number.to_string());
+        }
+    }
+}
+
+/// GPIO settings from the command line
+#[derive(Debug, Clone, Serialize)]
+pub struct GpioConfig {
+    pub strobe_pin: Option<u32>,
+    /// Strobe is asserted low instead of high
+    pub strobe_active_low: bool,
+    /// Assert this long before the capture starts
+    pub strobe_lead_us: u64,
+    /// Keep asserted this long after the capture ends
+    pub strobe_lag_us: u64,
+    /// External trigger input; when set, captures wait for an edge
+    pub trigger_pin: Option<u32>,
+    pub trigger_edge: Edge,
+    pub trigger_timeout_ms: u64,
+}
+
+impl Default for GpioConfig {
+    fn default() -> Self {
+        Self {
+            strobe_pin: None,
+            strobe_active_low: false,
+            strobe_lead_us: 0,
+            strobe_lag_us: 0,
+            trigger_pin: None,
+            trigger_edge: Edge::Rising,
+            trigger_timeout_ms: DEFAULT_TRIGGER_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+impl GpioConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.strobe_pin.is_some() || self.trigger_pin.is_some()
+    }
+}
+
+/// Strobe/trigger counters, readable from any thread
+#[derive(Debug, Default)]
+pub struct GpioStats {
+    strobe_asserted: AtomicBool,
+    strobes: AtomicU64,
+    triggers: AtomicU64,
+    trigger_timeouts: AtomicU64,
+}
+
+/// Snapshot of GPIO counters for /status
+#[derive(Debug, Clone, Serialize)]
+pub struct GpioStatsSnapshot {
+    pub strobe_asserted: bool,
+    pub strobes: u64,
+    pub triggers: u64,
+    pub trigger_timeouts: u64,
+}
+
+impl GpioStats {
+    pub fn snapshot(&self) -> GpioStatsSnapshot {
+        GpioStatsSnapshot {
+            strobe_asserted: self.strobe_asserted.load(Ordering::Relaxed),
+            strobes: self.strobes.load(Ordering::Relaxed),
+            triggers: self.triggers.load(Ordering::Relaxed),
+            trigger_timeouts: self.trigger_timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The strobe and trigger lines, only driven from the capture loop
+pub struct FrameGpio {
+    config: GpioConfig,
+    strobe: Option<SysfsPin>,
+    trigger: Option<SysfsPin>,
+}
+
+impl FrameGpio {
+    pub fn open(config: &GpioConfig) -> Result<Self> {
+        let mut strobe = config.strobe_pin.map(|pin| SysfsPin::open(pin, "out", None)).transpose()?;
+        if let Some(ref mut pin) = strobe {
+            pin.set(config.strobe_active_low)?;
+        }
+        let trigger = config
+            .trigger_pin
+            .map(|pin| SysfsPin::open(pin, "in", Some(config.trigger_edge)))
+            .transpose()?;
+        Ok(Self { config: config.clone(), strobe, trigger })
+    }
+
+    pub fn trigger_mode(&self) -> bool {
+        self.trigger.is_some()
+    }
+
+    /// Block until the trigger edge; false on timeout
+    pub fn wait_trigger(&mut self, stats: &GpioStats) -> Result<bool> {
+        let Some(ref mut trigger) = self.trigger else {
+            return Ok(true);
+        };
+        let fired = trigger.wait_edge(Duration::from_millis(self.config.trigger_timeout_ms))?;
+        let counter = if fired { &stats.triggers } else { &stats.trigger_timeouts };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(fired)
+    }
+
+    /// Assert the strobe, then wait the lead time
+    pub fn strobe_on(&mut self, stats: &GpioStats) -> Result<()> {
+        let Some(ref mut strobe) = self.strobe else {
+            return Ok(());
+        };
+        strobe.set(!self.config.strobe_active_low)?;
+        stats.strobe_asserted.store(true, Ordering::Relaxed);
+        stats.strobes.fetch_add(1, Ordering::Relaxed);
+        std::thread::sleep(Duration::from_micros(self.config.strobe_lead_us));
+        Ok(())
+    }
+
+    /// Wait the lag time, then deassert the strobe
+    pub fn strobe_off(&mut self, stats: &GpioStats) -> Result<()> {
+        let Some(ref mut strobe) = self.strobe else {
+            return Ok(());
+        };
+        std::thread::sleep(Duration::from_micros(self.config.strobe_lag_us));
+        strobe.set(self.config.strobe_active_low)?;
+        stats.strobe_asserted.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for FrameGpio {
+    fn drop(&mut self) {
+        // Never leave the illuminator on
+        if let Some(ref mut strobe) = self.strobe {
+            let _ = strobe.set(self.config.strobe_active_low);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Gr/Gb green imbalance correction
+//!
+//! The two green sites of each Bayer quad (Gb on the blue rows, Gr on the
+//! red rows) don't respond quite equally on the IMX415. Bilinear demosaic
+//! copies the native green at green sites and averages the other phase
+//! everywhere else, so a mismatch of a percent or two turns flat areas into
+//! a checkerboard "maze". The correction scales the Gb sites in the 10-bit
+//! Bayer buffer by the Gr/Gb ratio before demosaic.
+//!
+//! The ratio is either fixed (from a calibration) or estimated per frame
+//! from flat tiles: a sampled grid of tiles is scanned, tiles whose green
+//! samples vary more than noise would explain (texture, edges) are ignored,
+//! and the median of the remaining per-tile ratios is smoothed over frames.
+
+use serde::{Deserialize, Serialize};
+
+/// Tile size in pixels (even, so tiles hold whole quads)
+const TILE: usize = 32;
+
+/// Every Nth tile in each direction is examined
+const TILE_STEP: usize = 4;
+
+/// Tiles outside this mean green level (10-bit) are too dark (noise) or
+/// too close to clipping to estimate from
+const MIN_LEVEL: f32 = 48.0;
+const MAX_LEVEL: f32 = 900.0;
+
+/// Variance gate: largest standard deviation of either green phase in a
+/// flat tile, as a fraction of its mean, with a floor for dark tiles
+const FLAT_REL_STDDEV: f32 = 0.04;
+const FLAT_MIN_STDDEV: f32 = 3.0;
+
+/// Fewest flat tiles for an estimate
+const MIN_FLAT_TILES: usize = 8;
+
+/// Ratios further from 1 than this are scene content, not sensor mismatch
+pub const MAX_IMBALANCE: f32 = 0.1;
+
+/// Fraction of each frame's estimate blended into the running ratio
+const ESTIMATE_SMOOTHING: f32 = 0.2;
+
+/// Where the Gr/Gb ratio comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GreenBalanceMode {
+    Off,
+    /// Estimated from flat regions of each frame
+    Auto,
+    /// Fixed Gr/Gb ratio
+    Fixed(f32),
+}
+
+impl GreenBalanceMode {
+    /// `off`, `auto` or a Gr/Gb ratio within `MAX_IMBALANCE` of 1
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(GreenBalanceMode::Off),
+            "auto" => Some(GreenBalanceMode::Auto),
+            ratio => match ratio.parse::<f32>() {
+                Ok(r) if (r - 1.0).abs() <= MAX_IMBALANCE => Some(GreenBalanceMode::Fixed(r)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Green balance settings
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GreenBalance {
+    pub mode: GreenBalanceMode,
+    /// Fraction of the imbalance to remove (0.0 - 1.0)
+    pub strength: f32,
+}
+
+impl Default for GreenBalance {
+    fn default() -> Self {
+        Self { mode: GreenBalanceMode::Off, strength: 1.0 }
+    }
+}
+
+/// One frame's estimate
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    /// Mean Gr / mean Gb over the flat tiles (median of per-tile ratios)
+    pub ratio: f32,
+    pub flat_tiles: usize,
+}
+
+/// Running estimate and the gain applied to the last frame
+#[derive(Debug, Clone, Default)]
+pub struct GreenBalancer {
+    /// Smoothed Gr/Gb ratio (auto mode)
+    ratio: Option<f32>,
+    /// Flat tiles behind the last estimate
+    flat_tiles: usize,
+    /// Gain applied to the Gb sites of the last frame
+    gain: Option<f32>,
+}
+
+impl GreenBalancer {
+    pub fn ratio(&self) -> Option<f32> {
+        self.ratio
+    }
+
+    pub fn gain(&self) -> Option<f32> {
+        self.gain
+    }
+
+    pub fn flat_tiles(&self) -> usize {
+        self.flat_tiles
+    }
+
+    /// Forget the running estimate
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Estimate (auto mode) and correct one Bayer frame; `gbrg_offset` is
+    /// the CFA's row/column shift onto GBRG. `estimate` = false keeps the
+    /// previous ratio (scene statistics not meaningful, e.g. test patterns).
+    pub fn process(
+        &mut self,
+        config: &GreenBalance,
+        bayer: &mut [u16],
+        width: usize,
+        height: usize,
+        gbrg_offset: (usize, usize),
+        estimate: bool,
+    ) {
+        let ratio = match config.mode {
+            GreenBalanceMode::Off => None,
+            GreenBalanceMode::Fixed(ratio) => Some(ratio),
+            GreenBalanceMode::Auto => {
+                if estimate {
+                    if let Some(e) = estimate_ratio(bayer, width, height, gbrg_offset) {
+                        self.flat_tiles = e.flat_tiles;
+                        self.ratio = Some(match self.ratio {
+                            Some(r) => r + ESTIMATE_SMOOTHING * (e.ratio - r),
+                            None => e.ratio,
+                        });
+                    }
+                }
+                self.ratio
+            }
+        };
+        self.gain = ratio.map(|r| 1.0 + config.strength.clamp(0.0, 1.0) * (r - 1.0));
+        if let Some(gain) = self.gain {
+            apply_gain(bayer, width, height, gbrg_offset, gain);
+        }
+    }
+}
+
+/// Gr/Gb ratio from the flat tiles of a Bayer frame
+pub fn estimate_ratio(bayer: &[u16], width: usize, height: usize, gbrg_offset: (usize, usize)) -> Option<Estimate> {
+    let (dy, dx) = gbrg_offset;
+    let mut ratios = Vec::new();
+    for ty in (0..height / TILE).step_by(TILE_STEP) {
+        for tx in (0..width / TILE).step_by(TILE_STEP) {
+            // Sums and squared sums of the Gb and Gr sites
+            let mut sum = [0u64; 2];
+            let mut sum_sq = [0u64; 2];
+            let mut count = [0u64; 2];
+            for y in ty * TILE..(ty + 1) * TILE {
+                let row = &bayer[y * width..(y + 1) * width];
+                let phase = (y + dy) & 1;
+                // Gb at even (GBRG) columns of even rows, Gr at odd columns of odd rows
+                let start = tx * TILE + ((phase + dx) & 1);
+                for &v in row[start..(tx + 1) * TILE].iter().step_by(2) {
+                    let v = v as u64;
+                    sum[phase] += v;
+                    sum_sq[phase] += v * v;
+                    count[phase] += 1;
+                }
+            }
+            let stats = [0, 1].map(|p| {
+                let mean = sum[p] as f32 / count[p] as f32;
+                let variance = sum_sq[p] as f32 / count[p] as f32 - mean * mean;
+                (mean, variance.max(0.0).sqrt())
+            });
+            let flat = stats.iter().all(|&(mean, stddev)| {
+                (MIN_LEVEL..=MAX_LEVEL).contains(&mean) && stddev <= (mean * FLAT_REL_STDDEV).max(FLAT_MIN_STDDEV)
+            });
+            if flat {
+                let (gb, gr) = (stats[0].0, stats[1].0);
+                ratios.push(gr / gb);
+            }
+        }
+    }
+    if ratios.len() < MIN_FLAT_TILES {
+        return None;
+    }
+    ratios.sort_unstable_by(|a, b| a.total_cmp(b));
+    let ratio = ratios[ratios.len() / 2];
+    ((ratio - 1.0).abs() <= MAX_IMBALANCE).then_some(Estimate { ratio, flat_tiles: ratios.len() })
+}
+
+/// Scale the Gb sites by `gain`
+pub fn apply_gain(bayer: &mut [u16], width: usize, height: usize, gbrg_offset: (usize, usize), gain: f32) {
+    let (dy, dx) = gbrg_offset;
+    // 12-bit fixed point
+    let gain_q = (gain * 4096.0).round() as u32;
+    if gain_q == 4096 {
+        return;
+    }
+    for row in bayer.chunks_exact_mut(width).take(height).skip(dy).step_by(2) {
+        for v in row.iter_mut().skip(dx).step_by(2) {
+            *v = ((*v as u32 * gain_q + 2048) >> 12).min(1023) as u16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::DemosaicAlgorithm;
+
+    /// 24x16 tiles, 6x4 of them sampled
+    const WIDTH: usize = 768;
+    const HEIGHT: usize = 512;
+
+    /// A flat GBRG field: R and B at 400, Gb at `gb`, Gr at `gr`
+    fn flat_field(gb: u16, gr: u16) -> Vec<u16> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| match ((i / WIDTH) & 1, (i % WIDTH) & 1) {
+                (0, 0) => gb,
+                (1, 1) => gr,
+                _ => 400,
+            })
+            .collect()
+    }
+
+    /// High-frequency energy of the bilinear-demosaiced green channel: the
+    /// mean absolute step between neighbouring pixels (0 for a flat field)
+    fn maze_amplitude(bayer: &[u16]) -> f32 {
+        let mut rgb = vec![0u8; WIDTH * HEIGHT * 3];
+        for (y, row) in rgb.chunks_exact_mut(WIDTH * 3).enumerate() {
+            DemosaicAlgorithm::Bilinear.row(bayer, (WIDTH, HEIGHT), y, (0, 0), None, row);
+        }
+        let green = |x: usize, y: usize| rgb[(y * WIDTH + x) * 3 + 1] as f32;
+        let (mut total, mut steps) = (0.0, 0);
+        for y in 1..HEIGHT - 1 {
+            for x in 1..WIDTH - 1 {
+                total += (green(x, y) - green(x + 1, y)).abs() + (green(x, y) - green(x, y + 1)).abs();
+                steps += 2;
+            }
+        }
+        total / steps as f32
+    }
+
+    fn auto(strength: f32) -> GreenBalance {
+        GreenBalance { mode: GreenBalanceMode::Auto, strength }
+    }
+
+    #[test]
+    fn imbalanced_flat_field_loses_its_maze() {
+        // A 3% mismatch: 150 vs 154 at 8 bits
+        let mut bayer = flat_field(600, 618);
+        let before = maze_amplitude(&bayer);
+        assert!(before > 1.0, "{}", before);
+
+        let mut balancer = GreenBalancer::default();
+        balancer.process(&auto(1.0), &mut bayer, WIDTH, HEIGHT, (0, 0), true);
+        assert!((balancer.ratio().unwrap() - 1.03).abs() < 1e-4);
+        assert_eq!(balancer.flat_tiles(), 24);
+        let after = maze_amplitude(&bayer);
+        assert!(after < 0.05, "maze {} -> {}", before, after);
+    }
+
+    #[test]
+    fn strength_scales_the_correction() {
+        // 6%, half of it removed
+        let mut bayer = flat_field(600, 636);
+        let before = maze_amplitude(&bayer);
+        let mut balancer = GreenBalancer::default();
+        balancer.process(&auto(0.5), &mut bayer, WIDTH, HEIGHT, (0, 0), true);
+        assert!((balancer.gain().unwrap() - 1.03).abs() < 1e-4);
+        let after = maze_amplitude(&bayer);
+        assert!((after / before - 0.5).abs() < 0.15, "maze {} -> {}", before, after);
+    }
+
+    #[test]
+    fn textured_tiles_are_ignored() {
+        // Flat at a 2% mismatch, except the top half: stripes whose green
+        // phases differ by 8%, enough to move the median if counted
+        let mut bayer = flat_field(500, 510);
+        for y in 0..HEIGHT / 2 {
+            for x in (0..WIDTH).filter(|x| (x & 1) == (y & 1)) {
+                let stripe = if (x / 2) % 2 == 0 { 200.0 } else { 800.0 };
+                let gain = if y & 1 == 1 { 1.08 } else { 1.0 };
+                bayer[y * WIDTH + x] = (stripe * gain) as u16;
+            }
+        }
+        let estimate = estimate_ratio(&bayer, WIDTH, HEIGHT, (0, 0)).unwrap();
+        assert_eq!(estimate.flat_tiles, 12);
+        assert!((estimate.ratio - 1.02).abs() < 1e-4, "{}", estimate.ratio);
+    }
+
+    #[test]
+    fn dark_or_strongly_imbalanced_fields_give_no_estimate() {
+        // Under MIN_LEVEL: too dark to trust
+        assert!(estimate_ratio(&flat_field(30, 31), WIDTH, HEIGHT, (0, 0)).is_none());
+        // 15% is scene content, not sensor mismatch
+        assert!(estimate_ratio(&flat_field(500, 575), WIDTH, HEIGHT, (0, 0)).is_none());
+        let mut balancer = GreenBalancer::default();
+        let mut bayer = flat_field(500, 575);
+        balancer.process(&auto(1.0), &mut bayer, WIDTH, HEIGHT, (0, 0), true);
+        assert_eq!(balancer.gain(), None);
+        assert_eq!(bayer, flat_field(500, 575));
+    }
+
+    #[test]
+    fn fixed_ratios_parse_within_the_imbalance_limit() {
+        assert_eq!(GreenBalanceMode::parse("auto"), Some(GreenBalanceMode::Auto));
+        assert_eq!(GreenBalanceMode::parse("1.02"), Some(GreenBalanceMode::Fixed(1.02)));
+        assert_eq!(GreenBalanceMode::parse("1.2"), None);
+        assert_eq!(GreenBalanceMode::parse("on"), None);
+    }
+}
@@ -0,0 +1,399 @@
+//! H.264 live stream (`/video.mp4`)
+//!
+//! One encoder serves every client: it is opened when the first client
+//! connects, encodes the published frames at up to [`FPS`] and stops once
+//! the last client has gone. Access units go out on a broadcast channel;
+//! each client muxes them into its own fragmented MP4 ([`crate::fmp4`])
+//! starting at an IDR frame, which a new client asks for rather than
+//! waiting out the GOP. A client that falls behind skips to the next IDR
+//! frame instead of buffering.
+
+use crate::fmp4;
+use crate::latency::FrameTiming;
+use anyhow::Result;
+use bytes::Bytes;
+use image::DynamicImage;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+
+/// Encoder rate (the sensor's): frames beyond it are skipped
+pub const FPS: u32 = crate::source::FPS;
+
+/// Default target bitrate (kbit/s)
+pub const DEFAULT_BITRATE_KBPS: u32 = 8000;
+
+/// Frames between IDR frames when no client asks for one
+const GOP: u32 = 2 * FPS;
+
+/// Access units a client may fall behind before it skips ahead
+const BACKLOG: usize = 32;
+
+/// How long a new client waits for the encoder to start
+pub const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// H.264 encoder for the live stream
+pub trait VideoEncoder: Send {
+    /// SPS and PPS (Annex B)
+    fn header(&mut self) -> Result<Vec<u8>>;
+    /// Make the next frame an IDR frame
+    fn request_idr(&mut self) -> Result<()>;
+    /// Encode `image` into an access unit (Annex B) in `out`
+    fn encode(&mut self, image: &DynamicImage, out: &mut Vec<u8>) -> Result<()>;
+}
+
+#[cfg(feature = "mpp")]
+impl VideoEncoder for crate::mpp::H264Encoder {
+    fn header(&mut self) -> Result<Vec<u8>> {
+        self.header()
+    }
+
+    fn request_idr(&mut self) -> Result<()> {
+        self.request_idr()
+    }
+
+    fn encode(&mut self, image: &DynamicImage, out: &mut Vec<u8>) -> Result<()> {
+        self.encode(image, out)
+    }
+}
+
+/// Open the hardware encoder for `width` x `height` frames
+pub fn open_encoder(width: u32, height: u32, bitrate_kbps: u32) -> Result<Box<dyn VideoEncoder>> {
+    #[cfg(feature = "mpp")]
+    {
+        let encoder =
+            crate::mpp::H264Encoder::new(width as usize, height as usize, bitrate_kbps.saturating_mul(1000), FPS, GOP)?;
+        Ok(Box::new(encoder))
+    }
+    #[cfg(not(feature = "mpp"))]
+    {
+        let _ = (width, height, bitrate_kbps, GOP);
+        anyhow::bail!("Built without the `mpp` feature")
+    }
+}
+
+/// Frame size and parameter sets of the running encoder
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub width: u32,
+    pub height: u32,
+    pub sps: Bytes,
+    pub pps: Bytes,
+}
+
+impl Track {
+    fn new(width: u32, height: u32, header: &[u8]) -> Result<Self> {
+        let (sps, pps) =
+            fmp4::parameter_sets(header).ok_or_else(|| anyhow::anyhow!("Encoder header has no SPS/PPS"))?;
+        anyhow::ensure!(sps.len() >= 4, "Encoder SPS is truncated");
+        Ok(Self { width, height, sps: sps.into(), pps: pps.into() })
+    }
+
+    pub fn init_segment(&self) -> Bytes {
+        fmp4::init_segment(self.width, self.height, &self.sps, &self.pps).into()
+    }
+}
+
+/// One encoded frame as an MP4 sample
+pub struct AccessUnit {
+    pub timestamp_ms: u64,
+    pub keyframe: bool,
+    /// Length-prefixed NAL units
+    pub sample: Bytes,
+    pub timing: FrameTiming,
+}
+
+#[derive(Default)]
+struct Run {
+    running: bool,
+    /// Why the encoder last stopped with an error
+    error: Option<String>,
+}
+
+/// The shared encoder's output and state
+pub struct H264Stream {
+    pub bitrate_kbps: u32,
+    units: broadcast::Sender<Arc<AccessUnit>>,
+    track: watch::Sender<Option<Track>>,
+    idr_requested: AtomicBool,
+    run: parking_lot::Mutex<Run>,
+}
+
+impl H264Stream {
+    pub fn new(bitrate_kbps: u32) -> Self {
+        Self {
+            bitrate_kbps,
+            units: broadcast::Sender::new(BACKLOG),
+            track: watch::Sender::new(None),
+            idr_requested: AtomicBool::new(false),
+            run: parking_lot::Mutex::new(Run::default()),
+        }
+    }
+
+    /// Subscribe a client; returns whether the caller has to start the
+    /// encoder
+    pub fn subscribe(&self) -> (Subscription, bool) {
+        let mut run = self.run.lock();
+        let subscription = Subscription {
+            units: self.units.subscribe(),
+            track: self.track.subscribe(),
+        };
+        self.request_idr();
+        let start = !run.running;
+        if start {
+            run.running = true;
+            run.error = None;
+            self.track.send_replace(None);
+        }
+        (subscription, start)
+    }
+
+    /// Make the next frame an IDR frame (a client joined or lost frames)
+    pub fn request_idr(&self) {
+        self.idr_requested.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.run.lock().running
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.run.lock().error.clone()
+    }
+
+    pub fn status(&self) -> H264Status {
+        let run = self.run.lock();
+        H264Status {
+            available: cfg!(feature = "mpp"),
+            running: run.running,
+            clients: self.units.receiver_count(),
+            bitrate_kbps: self.bitrate_kbps,
+            error: run.error.clone(),
+        }
+    }
+
+    /// Mark the encoder stopped if no client is left; a client that
+    /// subscribes afterwards starts it again
+    fn stop_if_idle(&self) -> bool {
+        let mut run = self.run.lock();
+        if self.units.receiver_count() > 0 {
+            return false;
+        }
+        run.running = false;
+        true
+    }
+
+    fn stop_with_error(&self, error: String) {
+        let mut run = self.run.lock();
+        run.running = false;
+        run.error = Some(error);
+        // Wakes clients waiting for the track
+        self.track.send_replace(None);
+    }
+}
+
+/// The stream as reported by /status
+#[derive(Debug, Clone, Serialize)]
+pub struct H264Status {
+    /// Built with a hardware encoder
+    pub available: bool,
+    pub running: bool,
+    pub clients: usize,
+    pub bitrate_kbps: u32,
+    /// Why the encoder last stopped, if it failed
+    pub error: Option<String>,
+}
+
+/// A client's view of the stream
+pub struct Subscription {
+    units: broadcast::Receiver<Arc<AccessUnit>>,
+    track: watch::Receiver<Option<Track>>,
+}
+
+impl Subscription {
+    /// Wait for the encoder's track; None if it failed to start
+    pub async fn track(&mut self, stream: &H264Stream) -> Option<Track> {
+        loop {
+            if let Some(track) = self.track.borrow_and_update().clone() {
+                return Some(track);
+            }
+            if !stream.is_running() {
+                return None;
+            }
+            self.track.changed().await.ok()?;
+        }
+    }
+
+    /// Access units from the next IDR frame on; ends if the encoder stops
+    /// or changes the track
+    pub fn access_units(
+        self,
+        stream: Arc<H264Stream>,
+        track: Track,
+    ) -> impl futures::Stream<Item = Arc<AccessUnit>> + Send {
+        let reader = UnitReader { subscription: self, stream, track, synced: false };
+        futures::stream::unfold(reader, |mut reader| async move {
+            let unit = reader.next_unit().await?;
+            Some((unit, reader))
+        })
+    }
+
+    /// [`Subscription::access_units`] as MP4 fragments, each with its
+    /// access unit
+    pub fn fragments(
+        self,
+        stream: Arc<H264Stream>,
+        track: Track,
+    ) -> impl futures::Stream<Item = (Bytes, Arc<AccessUnit>)> + Send {
+        use futures::StreamExt;
+
+        let mut muxer = Muxer::default();
+        self.access_units(stream, track).map(move |unit| (muxer.mux(&unit), unit))
+    }
+}
+
+struct UnitReader {
+    subscription: Subscription,
+    stream: Arc<H264Stream>,
+    track: Track,
+    /// An IDR frame has been returned
+    synced: bool,
+}
+
+impl UnitReader {
+    async fn next_unit(&mut self) -> Option<Arc<AccessUnit>> {
+        loop {
+            let unit = match self.subscription.units.recv().await {
+                Ok(unit) => unit,
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    // Dropped units break the reference chain: start over at an IDR frame
+                    self.stream.request_idr();
+                    self.resync().await?
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            if self.subscription.track.borrow().as_ref() != Some(&self.track) {
+                return None;
+            }
+            if !self.synced && !unit.keyframe {
+                continue;
+            }
+            self.synced = true;
+            return Some(unit);
+        }
+    }
+
+    /// Skip to the next IDR frame
+    async fn resync(&mut self) -> Option<Arc<AccessUnit>> {
+        loop {
+            match self.subscription.units.recv().await {
+                Ok(unit) if unit.keyframe => return Some(unit),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Access units into MP4 fragments, timed from the first one
+#[derive(Default)]
+pub struct Muxer {
+    /// Timestamp of the first fragment (decode time 0)
+    first_ms: Option<u64>,
+    last_decode_time: Option<u64>,
+    sequence: u32,
+}
+
+impl Muxer {
+    pub fn mux(&mut self, unit: &AccessUnit) -> Bytes {
+        let first_ms = *self.first_ms.get_or_insert(unit.timestamp_ms);
+        let mut decode_time = unit.timestamp_ms.saturating_sub(first_ms) * fmp4::TIMESCALE as u64 / 1000;
+        // Decode times must increase even if the clock doesn't
+        if let Some(last) = self.last_decode_time {
+            decode_time = decode_time.max(last + 1);
+        }
+        self.last_decode_time = Some(decode_time);
+        self.sequence += 1;
+        // The next frame's time isn't known yet: assume the nominal rate
+        let duration = fmp4::TIMESCALE / FPS;
+        fmp4::fragment(self.sequence, decode_time, duration, &unit.sample, unit.keyframe).into()
+    }
+}
+
+/// A frame to encode
+pub struct SourceFrame {
+    pub image: Arc<DynamicImage>,
+    pub timestamp_ms: u64,
+    pub timing: FrameTiming,
+}
+
+/// Encode frames from `frames` until no client is left or the encoder
+/// fails
+pub async fn run_encoder(stream: Arc<H264Stream>, frames: impl futures::Stream<Item = SourceFrame> + Send) {
+    use futures::StreamExt;
+
+    let mut frames = std::pin::pin!(frames);
+    let mut encoder: Option<(Box<dyn VideoEncoder>, (u32, u32))> = None;
+    let mut buffer = Vec::new();
+    while let Some(frame) = frames.next().await {
+        if stream.stop_if_idle() {
+            tracing::info!("H.264 stream: last client left, encoder stopped");
+            return;
+        }
+        let size = (frame.image.width(), frame.image.height());
+        let opened = encoder.take().filter(|(_, opened_size)| *opened_size == size);
+        let idr = stream.idr_requested.swap(false, Ordering::Relaxed);
+        let bitrate_kbps = stream.bitrate_kbps;
+        let image = frame.image.clone();
+        let mut out = std::mem::take(&mut buffer);
+        let encoded = tokio::task::spawn_blocking(move || {
+            let result = (|| {
+                let (mut video, track) = match opened {
+                    Some((video, _)) => (video, None),
+                    None => {
+                        let mut video = open_encoder(size.0, size.1, bitrate_kbps)?;
+                        let header = video.header()?;
+                        (video, Some(Track::new(size.0, size.1, &header)?))
+                    }
+                };
+                if idr {
+                    // A new client just waits for the next GOP if this fails
+                    if let Err(e) = video.request_idr() {
+                        tracing::debug!("H.264 IDR request failed: {:#}", e);
+                    }
+                }
+                video.encode(&image, &mut out)?;
+                Ok((video, track))
+            })();
+            (result, out)
+        })
+        .await;
+        let result = match encoded {
+            Ok((result, returned)) => {
+                buffer = returned;
+                result
+            }
+            Err(e) => Err(anyhow::anyhow!("Encoder task failed: {}", e)),
+        };
+        match result {
+            Ok((video, track)) => {
+                if let Some(track) = track {
+                    tracing::info!("H.264 stream: encoding {}x{} at {} kbit/s", size.0, size.1, bitrate_kbps);
+                    stream.track.send_replace(Some(track));
+                }
+                encoder = Some((video, size));
+            }
+            Err(e) => {
+                tracing::warn!("H.264 stream stopped: {:#}", e);
+                stream.stop_with_error(format!("{:#}", e));
+                return;
+            }
+        }
+        let (sample, keyframe) = fmp4::annexb_to_avcc(&buffer);
+        let unit = AccessUnit { timestamp_ms: frame.timestamp_ms, keyframe, sample: sample.into(), timing: frame.timing };
+        let _ = stream.units.send(Arc::new(unit));
+    }
+    stream.stop_with_error("Frame source ended".to_string());
+}
@@ -0,0 +1,180 @@
+//! Exposure fusion (`grayscale-hdr` and `color-hdr` modes)
+//!
+//! Each output frame is built from two captures: a long exposure at the
+//! sensor's current exposure setting and a short one at `1/ratio` of it.
+//! Both are extracted to linear 16-bit values, merged per pixel into one
+//! radiance estimate (in units of the long exposure), and only then mapped
+//! down by a global tone curve, so highlights the long frame clips are
+//! taken from the short frame instead of saturating.
+//!
+//! Grayscale fuses the native luma and maps it straight to 8-bit output.
+//! Color fuses the Bayer samples, each against the same sample of the
+//! other frame, and maps them to 10 bits for the rest of the color
+//! pipeline (demosaic, white balance, color matrix, gamma).
+//!
+//! The IMX415's own DOL-HDR is not used: the Rockchip driver configures it
+//! through a private ioctl and leaves the merge to the ISP, which this raw
+//! pipeline bypasses, so the pair is bracketed through the exposure
+//! control instead.
+
+/// Default long/short exposure ratio
+pub const DEFAULT_RATIO: f32 = 4.0;
+/// Accepted exposure ratios
+pub const MIN_RATIO: f32 = 2.0;
+pub const MAX_RATIO: f32 = 16.0;
+
+/// Long-exposure values at or above this (of full scale) count as clipped
+/// and get no weight
+const CLIP_LEVEL: f32 = 0.95;
+/// Width of the well-exposedness weight around mid-scale
+const SIGMA: f32 = 0.2;
+/// Entries in the tone curve (indexed by the top 12 bits of a fused value)
+const TONE_CURVE_LEN: usize = 4096;
+
+/// How well exposed a normalized value is: 1 at mid-scale, falling off
+/// towards black and white
+fn well_exposed(v: f32) -> f32 {
+    let d = v - 0.5;
+    (-d * d / (2.0 * SIGMA * SIGMA)).exp()
+}
+
+/// Merge a long and a short exposure (linear 16-bit luma) of the same
+/// scene, replacing `long` with the radiance in long-exposure units,
+/// scaled so that `ratio` maps to full scale.
+///
+/// Each frame is weighted by how well exposed it is; the short frame's
+/// weight is further scaled by its exposure time relative to the long one
+/// (its shadows are `ratio` times noisier), and clipped long values are
+/// ignored so their highlights come from the short frame alone.
+pub fn fuse(long: &mut [u16], short: &[u16], ratio: f32) {
+    let ratio = ratio.max(1.0);
+    for (out, &s) in long.iter_mut().zip(short) {
+        let l = *out as f32 / 65535.0;
+        let s = s as f32 / 65535.0;
+        let w_long = if l >= CLIP_LEVEL { 0.0 } else { well_exposed(l) };
+        // Never zero, so a pixel clipped in the long frame always has a value
+        let w_short = well_exposed(s).max(f32::EPSILON) / ratio;
+        let radiance = (w_long * l + w_short * s * ratio) / (w_long + w_short);
+        *out = (radiance / ratio * 65535.0).round().clamp(0.0, 65535.0) as u16;
+    }
+}
+
+/// Tone curve from 0 to 1 for fused values at `ratio`, indexed by
+/// `value >> 4`
+///
+/// Extended Reinhard with the white point at the short frame's clip level:
+/// slope 1 at black, so the darkest tones match the plain modes, and
+/// rolling off to reach white only where the short exposure clips.
+fn reinhard(ratio: f32) -> impl Iterator<Item = f32> {
+    let ratio = ratio.max(1.0);
+    (0..TONE_CURVE_LEN).map(move |i| {
+        let x = i as f32 / (TONE_CURVE_LEN - 1) as f32 * ratio;
+        (x * (1.0 + x / (ratio * ratio)) / (1.0 + x)).clamp(0.0, 1.0)
+    })
+}
+
+/// 8-bit tone curve for fused values at `ratio` (grayscale output)
+pub fn tone_curve(ratio: f32) -> Vec<u8> {
+    reinhard(ratio).map(|y| (y * 255.0).round() as u8).collect()
+}
+
+/// 10-bit tone curve for fused values at `ratio` (linear Bayer samples for
+/// the color pipeline)
+pub fn tone_curve10(ratio: f32) -> Vec<u16> {
+    reinhard(ratio).map(|y| (y * 1023.0).round() as u16).collect()
+}
+
+/// Apply a curve from [`tone_curve`] or [`tone_curve10`] to fused values
+pub fn tone_map<T: Copy>(fused: &[u16], curve: &[T], out: &mut [T]) {
+    for (&v, out) in fused.iter().zip(out.iter_mut()) {
+        *out = curve[(v >> 4) as usize];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: f32 = 65535.0;
+
+    /// A long and short exposure of scene radiances (in units of the long
+    /// exposure's full scale), each clipped at full scale
+    fn bracket(radiance: &[f32], ratio: f32) -> (Vec<u16>, Vec<u16>) {
+        let expose = |scale: f32| radiance.iter().map(|&r| (r * scale * FULL).round().min(FULL) as u16).collect();
+        (expose(1.0), expose(1.0 / ratio))
+    }
+
+    /// Fused value back in radiance units
+    fn radiance_of(fused: u16, ratio: f32) -> f32 {
+        fused as f32 / FULL * ratio
+    }
+
+    #[test]
+    fn clipped_highlights_come_from_the_short_exposure() {
+        let ratio = 4.0;
+        // A streetlight: everything from 1.2 up is white in the long frame
+        let scene = [0.05, 0.2, 0.5, 0.8, 1.2, 1.6, 2.4, 3.2, 3.9];
+        let (mut fused, short) = bracket(&scene, ratio);
+        assert!(fused[4..].iter().all(|&v| v == u16::MAX));
+        fuse(&mut fused, &short, ratio);
+
+        for (&r, &v) in scene.iter().zip(&fused) {
+            let recovered = radiance_of(v, ratio);
+            assert!((recovered - r).abs() < 0.01 * r.max(0.1), "{} -> {}", r, recovered);
+        }
+        assert!(fused.windows(2).all(|w| w[0] < w[1]), "{:?}", fused);
+    }
+
+    #[test]
+    fn shadows_come_from_the_long_exposure() {
+        let ratio = 8.0;
+        let scene = [0.1, 0.3, 0.6];
+        let (mut fused, mut short) = bracket(&scene, ratio);
+        // Noise in the short frame's shadows, amplified `ratio` times
+        for s in &mut short {
+            *s += 200;
+        }
+        fuse(&mut fused, &short, ratio);
+        for ((&s, &v), &r) in short.iter().zip(&fused).zip(&scene) {
+            let recovered = radiance_of(v, ratio);
+            assert!((recovered - r).abs() < 0.02 * r, "{} -> {}", r, recovered);
+            // where the short frame alone would be well off
+            assert!((s as f32 / FULL * ratio - r).abs() > 0.02, "{}", r);
+        }
+    }
+
+    #[test]
+    fn fused_highlights_stay_distinct_in_8_bit() {
+        let ratio = 4.0;
+        let scene = [0.5, 1.2, 1.6, 2.4, 3.2];
+        let (mut fused, short) = bracket(&scene, ratio);
+        fuse(&mut fused, &short, ratio);
+        let mut out = vec![0u8; scene.len()];
+        tone_map(&fused, &tone_curve(ratio), &mut out);
+        assert!(out.windows(2).all(|w| w[0] < w[1]), "{:?}", out);
+        assert!(out[4] < 255, "{:?}", out);
+
+        // The plain grayscale mode renders all but the first as white
+        let (long, _) = bracket(&scene, ratio);
+        assert!(long[1..].iter().all(|&v| v == u16::MAX));
+    }
+
+    #[test]
+    fn tone_curves_span_the_output_range() {
+        for ratio in [MIN_RATIO, DEFAULT_RATIO, MAX_RATIO] {
+            let curve = tone_curve(ratio);
+            let curve10 = tone_curve10(ratio);
+            assert_eq!((curve.len(), curve10.len()), (TONE_CURVE_LEN, TONE_CURVE_LEN));
+            assert_eq!((curve[0], curve[TONE_CURVE_LEN - 1]), (0, 255));
+            assert_eq!((curve10[0], curve10[TONE_CURVE_LEN - 1]), (0, 1023));
+            assert!(curve.windows(2).all(|w| w[0] <= w[1]));
+            assert!(curve10.windows(2).all(|w| w[0] <= w[1]));
+        }
+        // Slope 1 at black: dark tones match the plain modes
+        let ratio = DEFAULT_RATIO;
+        let curve10 = tone_curve10(ratio);
+        let long_level = 0.02;
+        let index = (long_level / ratio * (TONE_CURVE_LEN - 1) as f32).round() as usize;
+        assert!((curve10[index] as f32 - long_level * 1023.0).abs() <= 1.0, "{}", curve10[index]);
+    }
+}
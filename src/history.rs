@@ -0,0 +1,275 @@
+//! Frame history ring buffer
+//!
+//! Keeps the most recent encoded frames, keyed by capture sequence number,
+//! together with detection results for those sequence numbers. Detection
+//! records are retained longer than frames, so results can still be
+//! reviewed after the frame itself has been evicted.
+//!
+//! The frame ring is also bounded in bytes: under memory pressure it is the
+//! first structure to shrink (see `memory`).
+
+use crate::detector::DetectionResult;
+use bytes::Bytes;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Number of frames kept in the ring
+pub const HISTORY_FRAMES: usize = 60;
+
+/// Number of detection records kept (outlives the frames they refer to)
+pub const HISTORY_DETECTIONS: usize = 1000;
+
+/// One captured frame
+#[derive(Clone)]
+pub struct HistoryFrame {
+    pub seq: u64,
+    /// Capture time, milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Unannotated JPEG
+    pub jpeg: Bytes,
+}
+
+/// Listing entry for a history frame
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub size: usize,
+    pub has_detections: bool,
+}
+
+/// Ring buffer of recent frames and their detection results
+pub struct FrameHistory {
+    frames: VecDeque<HistoryFrame>,
+    detections: VecDeque<DetectionResult>,
+    /// Total JPEG bytes in `frames`
+    bytes: usize,
+    /// Upper bound on `bytes`, set by the memory accountant
+    byte_limit: usize,
+    /// The last push evicted frames to stay under `byte_limit`
+    memory_capped: bool,
+}
+
+impl Default for FrameHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameHistory {
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::with_capacity(HISTORY_FRAMES),
+            detections: VecDeque::with_capacity(HISTORY_DETECTIONS),
+            bytes: 0,
+            byte_limit: usize::MAX,
+            memory_capped: false,
+        }
+    }
+
+    /// Append a frame, evicting the oldest when full
+    pub fn push(&mut self, seq: u64, timestamp_ms: u64, jpeg: Bytes) {
+        if self.frames.len() == HISTORY_FRAMES {
+            self.pop_oldest();
+        }
+        self.bytes += jpeg.len();
+        self.frames.push_back(HistoryFrame { seq, timestamp_ms, jpeg });
+        self.memory_capped = self.enforce_byte_limit();
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some(frame) = self.frames.pop_front() {
+            self.bytes -= frame.jpeg.len();
+        }
+    }
+
+    /// Evict oldest frames until under the byte limit; true if any were evicted
+    fn enforce_byte_limit(&mut self) -> bool {
+        let mut evicted = false;
+        while self.bytes > self.byte_limit {
+            self.pop_oldest();
+            evicted = true;
+        }
+        evicted
+    }
+
+    /// Bound the frame ring to `limit` bytes, evicting oldest frames first.
+    ///
+    /// Returns whether the limit evicted frames since the last push, i.e.
+    /// the ring is being held below `HISTORY_FRAMES` by memory pressure.
+    pub fn set_byte_limit(&mut self, limit: usize) -> bool {
+        self.byte_limit = limit;
+        self.memory_capped |= self.enforce_byte_limit();
+        self.memory_capped
+    }
+
+    /// JPEG bytes held by the frame ring
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Record the detector's result for the frame it was run on
+    ///
+    /// Results without a sequence number, or for a sequence already
+    /// recorded, are ignored.
+    pub fn record_detections(&mut self, result: &DetectionResult) {
+        let Some(seq) = result.frame_seq else {
+            return;
+        };
+        if self
+            .detections
+            .back()
+            .and_then(|r| r.frame_seq)
+            .is_some_and(|last| last >= seq)
+        {
+            return;
+        }
+        if self.detections.len() == HISTORY_DETECTIONS {
+            self.detections.pop_front();
+        }
+        self.detections.push_back(result.clone());
+    }
+
+    pub fn frame(&self, seq: u64) -> Option<&HistoryFrame> {
+        // Sequence numbers are increasing, so binary search by seq
+        self.frames
+            .binary_search_by_key(&seq, |f| f.seq)
+            .ok()
+            .map(|i| &self.frames[i])
+    }
+
+    pub fn detections(&self, seq: u64) -> Option<&DetectionResult> {
+        self.detections
+            .binary_search_by_key(&seq, |r| r.frame_seq.unwrap_or(0))
+            .ok()
+            .map(|i| &self.detections[i])
+    }
+
+    /// Frames captured at or after `timestamp_ms`, oldest first
+    pub fn frames_since(&self, timestamp_ms: u64) -> Vec<HistoryFrame> {
+        self.frames
+            .iter()
+            .filter(|f| f.timestamp_ms >= timestamp_ms)
+            .cloned()
+            .collect()
+    }
+
+    /// Frames with `from_seq <= seq <= to_seq` (either bound optional)
+    /// still in the ring, oldest first
+    pub fn frames_between(&self, from_seq: Option<u64>, to_seq: Option<u64>) -> Vec<HistoryFrame> {
+        self.frames
+            .iter()
+            .filter(|f| from_seq.is_none_or(|from| f.seq >= from) && to_seq.is_none_or(|to| f.seq <= to))
+            .cloned()
+            .collect()
+    }
+
+    /// Sequence numbers of the oldest and newest frame in the ring
+    pub fn seq_range(&self) -> Option<(u64, u64)> {
+        Some((self.frames.front()?.seq, self.frames.back()?.seq))
+    }
+
+    /// Frames currently in the ring, oldest first
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.frames
+            .iter()
+            .map(|f| HistoryEntry {
+                seq: f.seq,
+                timestamp_ms: f.timestamp_ms,
+                size: f.jpeg.len(),
+                has_detections: self.detections(f.seq).is_some(),
+            })
+            .collect()
+    }
+
+    /// Sequence numbers with a detection record (including evicted frames)
+    pub fn detection_seqs(&self) -> Vec<u64> {
+        self.detections.iter().filter_map(|r| r.frame_seq).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A history of frames 1..=n, frame `seq` being `seq * 100` bytes
+    fn history_of(n: u64) -> FrameHistory {
+        let mut history = FrameHistory::new();
+        for seq in 1..=n {
+            history.push(seq, seq * 1000, Bytes::from(vec![0u8; seq as usize * 100]));
+        }
+        history
+    }
+
+    fn seqs(history: &FrameHistory) -> Vec<u64> {
+        history.entries().iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn byte_limit_evicts_oldest_frames_first() {
+        let mut history = history_of(5);
+        assert_eq!(history.bytes(), 1500);
+        assert!(!history.set_byte_limit(1500));
+        assert_eq!(seqs(&history), [1, 2, 3, 4, 5]);
+        
+        // 1400 drops frame 1 only; 1000 then drops 2 and 3, not the larger 4
+        assert!(history.set_byte_limit(1400));
+        assert_eq!(seqs(&history), [2, 3, 4, 5]);
+        assert!(history.set_byte_limit(1000));
+        assert_eq!(seqs(&history), [4, 5]);
+        assert_eq!(history.bytes(), 900);
+        
+        // Down to nothing when even the newest frame doesn't fit
+        assert!(history.set_byte_limit(400));
+        assert_eq!(seqs(&history), Vec::<u64>::new());
+        assert_eq!(history.bytes(), 0);
+    }
+
+    #[test]
+    fn pushes_stay_under_the_byte_limit() {
+        let mut history = history_of(3);
+        history.set_byte_limit(1000);
+        history.push(4, 4000, Bytes::from(vec![0u8; 500]));
+        // Frames 2-4 exactly fit
+        assert_eq!(seqs(&history), [2, 3, 4]);
+        assert_eq!(history.bytes(), 1000);
+        history.push(5, 5000, Bytes::from(vec![0u8; 500]));
+        assert_eq!(seqs(&history), [4, 5]);
+    }
+
+    #[test]
+    fn memory_capped_lasts_until_a_push_fits() {
+        let mut history = history_of(3);
+        assert!(history.set_byte_limit(500));
+        // Raising the limit evicts nothing, but the ring is still short
+        assert!(history.set_byte_limit(10_000));
+        history.push(4, 4000, Bytes::from(vec![0u8; 100]));
+        assert!(!history.set_byte_limit(10_000));
+    }
+
+    #[test]
+    fn frame_count_limit_evicts_oldest() {
+        let mut history = FrameHistory::new();
+        for seq in 1..=HISTORY_FRAMES as u64 + 5 {
+            history.push(seq, seq, Bytes::from_static(b"jpeg"));
+        }
+        assert_eq!(history.seq_range(), Some((6, HISTORY_FRAMES as u64 + 5)));
+        assert_eq!(history.bytes(), HISTORY_FRAMES * 4);
+        assert!(history.frame(5).is_none());
+        assert_eq!(history.frame(6).unwrap().timestamp_ms, 6);
+    }
+
+    #[test]
+    fn detections_outlive_evicted_frames() {
+        let mut history = history_of(3);
+        for seq in [1, 3, 2] {
+            history.record_detections(&DetectionResult { frame_seq: Some(seq), ..Default::default() });
+        }
+        // Out of order (2 after 3) is dropped
+        assert_eq!(history.detection_seqs(), [1, 3]);
+        history.set_byte_limit(300);
+        assert_eq!(seqs(&history), [3]);
+        assert!(history.detections(1).is_some());
+        assert!(history.entries()[0].has_detections);
+    }
+}
@@ -0,0 +1,356 @@
+//! Home Assistant integration over MQTT
+//!
+//! Publishes MQTT discovery configs so the streamer shows up in Home
+//! Assistant as one device with:
+//!
+//! - a camera, fed a downscaled JPEG every `camera_interval` (HA's MQTT
+//!   camera takes still images; the MJPEG stream stays on HTTP and is the
+//!   device's configuration URL when one is given),
+//! - a "Detection" switch mirroring and setting detection on/off,
+//! - an occupancy binary sensor per configured class, on while the latest
+//!   detection result has the class.
+//!
+//! Everything is retained, with an availability topic the broker flips to
+//! `offline` through the last will. Discovery is sent again whenever HA
+//! announces itself on `<prefix>/status`. A lost connection is retried with
+//! backoff.
+
+use crate::mqtt::{Connection, Incoming, LastWill, MqttOptions};
+use crate::supervisor::Backoff;
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+pub const DEFAULT_NODE_ID: &str = "imx415";
+
+/// Root of the state and command topics
+const TOPIC_ROOT: &str = "imx415_streamer";
+
+/// MQTT keep-alive; a broker silent for 1.5x this is considered gone
+pub const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+const ON: &str = "ON";
+const OFF: &str = "OFF";
+
+#[derive(Debug, Clone)]
+pub struct HaConfig {
+    pub mqtt: MqttOptions,
+    pub discovery_prefix: String,
+    /// Device id, unique per streamer on the broker (letters, digits, '_', '-')
+    pub node_id: String,
+    /// Classes that get an occupancy sensor
+    pub classes: Vec<String>,
+    /// Camera image period (None: no camera entity)
+    pub camera_interval: Option<Duration>,
+    /// Live view URL shown on the device page
+    pub url: Option<String>,
+}
+
+impl HaConfig {
+    fn topic(&self, suffix: &str) -> String {
+        format!("{}/{}/{}", TOPIC_ROOT, self.node_id, suffix)
+    }
+
+    fn availability_topic(&self) -> String {
+        self.topic("availability")
+    }
+
+    fn detection_state_topic(&self) -> String {
+        self.topic("detection/state")
+    }
+
+    fn detection_command_topic(&self) -> String {
+        self.topic("detection/set")
+    }
+
+    fn image_topic(&self) -> String {
+        self.topic("camera/image")
+    }
+
+    fn occupancy_topic(&self, class: &str) -> String {
+        self.topic(&format!("occupancy/{}", object_id(class)))
+    }
+
+    /// Where HA announces itself after starting
+    fn ha_status_topic(&self) -> String {
+        format!("{}/status", self.discovery_prefix)
+    }
+
+    fn config_topic(&self, component: &str, object: &str) -> String {
+        format!("{}/{}/{}/{}/config", self.discovery_prefix, component, self.node_id, object)
+    }
+
+    /// Discovery topic and payload for every entity
+    fn discovery(&self) -> Vec<(String, serde_json::Value)> {
+        let mut device = serde_json::json!({
+            "identifiers": [format!("imx415_streamer_{}", self.node_id)],
+            "name": format!("IMX415 camera ({})", self.node_id),
+            "manufacturer": "imx415_streamer",
+            "model": "IMX415",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        if let Some(ref url) = self.url {
+            device["configuration_url"] = serde_json::json!(url);
+        }
+        let entity = |object: &str, name: &str| {
+            serde_json::json!({
+                "name": name,
+                "unique_id": format!("{}_{}", self.node_id, object),
+                "object_id": format!("{}_{}", self.node_id, object),
+                "availability_topic": self.availability_topic(),
+                "device": device,
+            })
+        };
+
+        let mut entities = Vec::new();
+        if self.camera_interval.is_some() {
+            let mut camera = entity("camera", "Camera");
+            camera["topic"] = serde_json::json!(self.image_topic());
+            entities.push((self.config_topic("camera", "camera"), camera));
+        }
+        let mut switch = entity("detection", "Detection");
+        switch["state_topic"] = serde_json::json!(self.detection_state_topic());
+        switch["command_topic"] = serde_json::json!(self.detection_command_topic());
+        switch["icon"] = serde_json::json!("mdi:motion-sensor");
+        entities.push((self.config_topic("switch", "detection"), switch));
+        for class in &self.classes {
+            let object = format!("{}_occupancy", object_id(class));
+            let mut sensor = entity(&object, &format!("{} detected", capitalize(class)));
+            sensor["state_topic"] = serde_json::json!(self.occupancy_topic(class));
+            sensor["device_class"] = serde_json::json!("occupancy");
+            entities.push((self.config_topic("binary_sensor", &object), sensor));
+        }
+        entities
+    }
+}
+
+/// Class name as a topic level / entity id ("traffic light" -> "traffic_light")
+fn object_id(class: &str) -> String {
+    class
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    chars.next().map_or_else(String::new, |first| first.to_uppercase().chain(chars).collect())
+}
+
+/// What the bridge mirrors
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HaState {
+    pub detection_enabled: bool,
+    /// Classes in the latest detection result
+    pub classes: BTreeSet<String>,
+}
+
+/// A command from Home Assistant
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaCommand {
+    SetDetection(bool),
+}
+
+#[derive(Debug, Default)]
+pub struct HaStats {
+    connected: AtomicBool,
+    connects: AtomicU64,
+    published: AtomicU64,
+    commands: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HaStatsSnapshot {
+    pub connected: bool,
+    /// Successful connections (1 + reconnects)
+    pub connects: u64,
+    pub published: u64,
+    pub commands: u64,
+    pub last_error: Option<String>,
+}
+
+impl HaStats {
+    pub fn snapshot(&self) -> HaStatsSnapshot {
+        HaStatsSnapshot {
+            connected: self.connected.load(Ordering::Relaxed),
+            connects: self.connects.load(Ordering::Relaxed),
+            published: self.published.load(Ordering::Relaxed),
+            commands: self.commands.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().clone(),
+        }
+    }
+}
+
+/// Inputs and outputs of the bridge
+pub struct HaChannels {
+    pub state: watch::Receiver<HaState>,
+    /// Latest camera image (JPEG)
+    pub images: watch::Receiver<Option<Bytes>>,
+    pub commands: mpsc::Sender<HaCommand>,
+}
+
+/// Keep a broker session up, reconnecting with backoff; runs until the
+/// input channels close
+pub async fn run(config: HaConfig, mut channels: HaChannels, stats: Arc<HaStats>) {
+    let mut options = config.mqtt.clone();
+    options.last_will = Some(LastWill { topic: config.availability_topic(), payload: "offline".to_string(), retain: true });
+    let mut backoff = Backoff::new(RECONNECT_MIN_DELAY, RECONNECT_MAX_DELAY);
+    loop {
+        let started = Instant::now();
+        let result = session(&config, &options, &mut channels, &stats).await;
+        stats.connected.store(false, Ordering::Relaxed);
+        match result {
+            Ok(()) => return,
+            Err(e) => {
+                tracing::warn!("Home Assistant MQTT: {:#}", e);
+                *stats.last_error.lock() = Some(format!("{:#}", e));
+            }
+        }
+        // A session that lasted resets the backoff
+        if started.elapsed() > RECONNECT_MAX_DELAY {
+            backoff.reset();
+        }
+        tokio::time::sleep(backoff.next_delay()).await;
+    }
+}
+
+/// One connection; `Ok` when the inputs close
+async fn session(
+    config: &HaConfig,
+    options: &MqttOptions,
+    channels: &mut HaChannels,
+    stats: &HaStats,
+) -> Result<()> {
+    let mut connection = Connection::connect(options).await?;
+    connection.subscribe(&[&config.detection_command_topic(), &config.ha_status_topic()]).await?;
+    stats.connected.store(true, Ordering::Relaxed);
+    stats.connects.fetch_add(1, Ordering::Relaxed);
+    tracing::info!("Home Assistant MQTT: connected to {}", options.broker);
+
+    let mut publisher = Publisher { connection: &mut connection, config, stats, sent: None };
+    publisher.announce().await?;
+    let state = channels.state.borrow_and_update().clone();
+    publisher.state(&state).await?;
+    let image = channels.images.borrow_and_update().clone();
+    if let Some(image) = image {
+        publisher.image(&image).await?;
+    }
+
+    let mut ping = tokio::time::interval(KEEP_ALIVE / 2);
+    let mut last_heard = Instant::now();
+    loop {
+        tokio::select! {
+            changed = channels.state.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let state = channels.state.borrow_and_update().clone();
+                publisher.state(&state).await?;
+            }
+            changed = channels.images.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let image = channels.images.borrow_and_update().clone();
+                if let Some(image) = image {
+                    publisher.image(&image).await?;
+                }
+            }
+            packet = publisher.connection.incoming.recv() => {
+                let packet = packet.ok_or_else(|| anyhow::anyhow!("Connection closed"))??;
+                last_heard = Instant::now();
+                let Incoming::Publish { topic, payload } = packet else {
+                    continue;
+                };
+                if topic == config.ha_status_topic() && payload.as_ref() == b"online" {
+                    // HA restarted: it may have lost the retained configs
+                    tracing::info!("Home Assistant MQTT: Home Assistant came online, re-sending discovery");
+                    publisher.announce().await?;
+                    let state = channels.state.borrow().clone();
+                    publisher.sent = None;
+                    publisher.state(&state).await?;
+                } else if topic == config.detection_command_topic() {
+                    let command = match payload.as_ref() {
+                        b"ON" => HaCommand::SetDetection(true),
+                        b"OFF" => HaCommand::SetDetection(false),
+                        other => {
+                            tracing::warn!("Home Assistant MQTT: ignoring detection command {:?}", String::from_utf8_lossy(other));
+                            continue;
+                        }
+                    };
+                    stats.commands.fetch_add(1, Ordering::Relaxed);
+                    if channels.commands.send(command).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            _ = ping.tick() => {
+                anyhow::ensure!(last_heard.elapsed() < KEEP_ALIVE * 3 / 2, "Broker stopped answering");
+                publisher.connection.ping().await?;
+            }
+        }
+    }
+    publisher.offline().await?;
+    connection.disconnect().await
+}
+
+/// Publishes on one connection, skipping states that didn't change
+struct Publisher<'a> {
+    connection: &'a mut Connection,
+    config: &'a HaConfig,
+    stats: &'a HaStats,
+    /// Last state sent on this connection
+    sent: Option<HaState>,
+}
+
+impl Publisher<'_> {
+    async fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        self.connection.publish(topic, payload, true).await?;
+        self.stats.published.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Discovery configs, then availability
+    async fn announce(&mut self) -> Result<()> {
+        for (topic, payload) in self.config.discovery() {
+            self.publish(&topic, payload.to_string().as_bytes()).await?;
+        }
+        self.publish(&self.config.availability_topic(), b"online").await
+    }
+
+    async fn offline(&mut self) -> Result<()> {
+        self.publish(&self.config.availability_topic(), b"offline").await
+    }
+
+    async fn state(&mut self, state: &HaState) -> Result<()> {
+        let sent = self.sent.take();
+        if sent.as_ref().map(|s| s.detection_enabled) != Some(state.detection_enabled) {
+            let payload = if state.detection_enabled { ON } else { OFF };
+            self.publish(&self.config.detection_state_topic(), payload.as_bytes()).await?;
+        }
+        for class in &self.config.classes {
+            let present = state.classes.contains(class);
+            if sent.as_ref().map(|s| s.classes.contains(class)) != Some(present) {
+                let payload = if present { ON } else { OFF };
+                self.publish(&self.config.occupancy_topic(class), payload.as_bytes()).await?;
+            }
+        }
+        self.sent = Some(state.clone());
+        Ok(())
+    }
+
+    async fn image(&mut self, jpeg: &[u8]) -> Result<()> {
+        self.publish(&self.config.image_topic(), jpeg).await
+    }
+}
@@ -0,0 +1,236 @@
+//! Scripted capture sequences
+//!
+//! A job is a list of steps ("set exposure, wait 5 frames, snapshot, ...")
+//! run server-side, so frame waits count real captured frames instead of
+//! racing the pipeline with sleeps. The whole job is validated before the
+//! first step runs; only one job runs at a time, and a failed or cancelled
+//! job puts back any controls and mode it changed.
+
+use crate::controls::Control;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use tokio::sync::watch;
+use utoipa::ToSchema;
+
+/// Most steps accepted in one job
+pub const MAX_STEPS: usize = 100;
+/// Longest single `wait_frames`
+pub const MAX_WAIT_FRAMES: u64 = 10_000;
+/// Longest single `wait_ms`
+pub const MAX_WAIT_MS: u64 = 10 * 60 * 1000;
+/// Finished jobs kept for GET /jobs/:id
+const KEPT_JOBS: usize = 32;
+
+/// One step of a job (`{"op": "set_control", "name": "exposure", "value": 1000}`)
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Step {
+    /// Set a sensor subdevice control
+    SetControl { name: String, value: i64 },
+    /// Wait until this many new frames have been captured
+    WaitFrames { frames: u64 },
+    WaitMs { ms: u64 },
+    /// Save the current frame at snapshot quality
+    Snapshot { name: Option<String> },
+    /// Save the next frame captured after this step starts, so it reflects
+    /// every earlier step
+    StillCapture { name: Option<String> },
+    /// `grayscale` or `color`
+    SetMode { mode: String },
+}
+
+impl Step {
+    /// Output file stem for snapshot steps
+    pub fn output_name(&self, job_id: u64, index: usize) -> Option<String> {
+        match self {
+            Step::Snapshot { name } | Step::StillCapture { name } => {
+                Some(name.clone().unwrap_or_else(|| format!("job{}_step{}", job_id, index)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Check every step up front; `controls` are the sensor's controls and
+/// `parse_mode` accepts capture mode names
+pub fn validate(
+    steps: &[Step],
+    controls: &[Control],
+    parse_mode: impl Fn(&str) -> bool,
+) -> Result<(), String> {
+    if steps.is_empty() {
+        return Err("A job needs at least one step".into());
+    }
+    if steps.len() > MAX_STEPS {
+        return Err(format!("A job may have at most {} steps", MAX_STEPS));
+    }
+    let mut names = HashSet::new();
+    for (index, step) in steps.iter().enumerate() {
+        let fail = |message: String| Err(format!("Step {}: {}", index, message));
+        match step {
+            Step::SetControl { name, value } => {
+                let Some(control) = controls.iter().find(|c| &c.name == name) else {
+                    return fail(format!("Unknown control '{}'", name));
+                };
+                let (min, max) = (control.min.unwrap_or(i64::MIN), control.max.unwrap_or(i64::MAX));
+                if !(min..=max).contains(value) {
+                    return fail(format!("{} must be between {} and {}", name, min, max));
+                }
+            }
+            Step::WaitFrames { frames } if *frames == 0 || *frames > MAX_WAIT_FRAMES => {
+                return fail(format!("frames must be between 1 and {}", MAX_WAIT_FRAMES));
+            }
+            Step::WaitMs { ms } if *ms > MAX_WAIT_MS => {
+                return fail(format!("ms must be at most {}", MAX_WAIT_MS));
+            }
+            Step::Snapshot { name: Some(name) } | Step::StillCapture { name: Some(name) } => {
+                if !valid_name(name) {
+                    return fail("Snapshot names may only use letters, digits, '_' and '-'".into());
+                }
+                if !names.insert(name.as_str()) {
+                    return fail(format!("Duplicate snapshot name '{}'", name));
+                }
+            }
+            Step::SetMode { mode } if !parse_mode(mode) => {
+                return fail(format!("Invalid mode '{}'. Use 'grayscale' or 'color'", mode));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Job lifecycle state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Per-step progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StepState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct StepStatus {
+    pub step: Step,
+    pub state: StepState,
+    /// Snapshot file written by the step
+    pub output: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Job report for GET /jobs/:id
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Job {
+    pub id: u64,
+    pub state: JobState,
+    pub created_ms: u64,
+    pub finished_ms: Option<u64>,
+    pub steps: Vec<StepStatus>,
+    /// Controls (and "mode") put back after a failure or cancel
+    pub restored: Vec<String>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    cancel: watch::Sender<bool>,
+}
+
+impl Job {
+    pub fn is_finished(&self) -> bool {
+        self.state != JobState::Running
+    }
+
+    /// Mark every step not yet run as skipped
+    pub fn skip_remaining(&mut self) {
+        for step in &mut self.steps {
+            if matches!(step.state, StepState::Pending | StepState::Running) {
+                step.state = StepState::Skipped;
+            }
+        }
+    }
+}
+
+/// The running job and recently finished ones
+#[derive(Default)]
+pub struct JobTable {
+    next_id: u64,
+    jobs: VecDeque<Job>,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn running(&self) -> Option<u64> {
+        self.jobs.iter().find(|j| !j.is_finished()).map(|j| j.id)
+    }
+
+    /// Register a new running job; returns its id and cancel receiver, or
+    /// the id of the job already running
+    pub fn start(&mut self, steps: Vec<Step>, now_ms: u64) -> Result<(u64, watch::Receiver<bool>), u64> {
+        if let Some(running) = self.running() {
+            return Err(running);
+        }
+        self.next_id += 1;
+        let (cancel, cancelled) = watch::channel(false);
+        self.jobs.push_back(Job {
+            id: self.next_id,
+            state: JobState::Running,
+            created_ms: now_ms,
+            finished_ms: None,
+            steps: steps
+                .into_iter()
+                .map(|step| StepStatus { step, state: StepState::Pending, output: None, error: None })
+                .collect(),
+            restored: Vec::new(),
+            error: None,
+            cancel,
+        });
+        while self.jobs.len() > KEPT_JOBS {
+            match self.jobs.iter().position(Job::is_finished) {
+                Some(oldest) => self.jobs.remove(oldest),
+                None => break,
+            };
+        }
+        Ok((self.next_id, cancelled))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|j| j.id == id)
+    }
+
+    pub fn list(&self) -> Vec<&Job> {
+        self.jobs.iter().collect()
+    }
+
+    /// Ask a running job to stop; false if it has already finished
+    pub fn cancel(&self, id: u64) -> Option<bool> {
+        let job = self.get(id)?;
+        if job.is_finished() {
+            return Some(false);
+        }
+        job.cancel.send_replace(true);
+        Some(true)
+    }
+}
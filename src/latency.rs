@@ -0,0 +1,256 @@
+//! Glass-to-stream latency bookkeeping
+//!
+//! Every frame carries monotonic timestamps for capture start, capture
+//! complete (raw buffer dequeued), processing complete (encoded and
+//! annotated) and publish; each MJPEG client adds the time its copy was
+//! handed to the connection. Samples go into fixed-size rings allocated up
+//! front (per client at connect time), so the capture loop and the stream
+//! bodies only copy a few integers under an uncontended lock.
+//!
+//! Sensor exposure and readout before the buffer is dequeued, and anything
+//! after the socket write (network, client decode, display), are invisible
+//! here; the blink marker exists so an external camera filming the client's
+//! screen can measure the full path against a known frame sequence.
+
+use image::DynamicImage;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use utoipa::ToSchema;
+
+/// Samples kept per ring (frames, and sends per client)
+pub const WINDOW: usize = 256;
+
+/// Blink marker edge length, as a fraction of the frame height
+const MARKER_FRACTION: u32 = 8;
+
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Monotonic microseconds since the first call
+pub fn now_us() -> u64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// Pipeline timestamps of one frame (`now_us` values, 0 = not reached)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameTiming {
+    pub capture_start_us: u64,
+    pub capture_done_us: u64,
+    pub processed_us: u64,
+    pub published_us: u64,
+}
+
+/// A client's copy of a frame
+#[derive(Debug, Clone, Copy, Default)]
+struct SendSample {
+    /// Publish to send
+    send_us: u64,
+    /// Capture start to send
+    total_us: u64,
+}
+
+/// Fixed-capacity ring; never reallocates after construction
+struct Ring<T> {
+    samples: Vec<T>,
+    next: usize,
+}
+
+impl<T> Default for Ring<T> {
+    fn default() -> Self {
+        Self { samples: Vec::with_capacity(WINDOW), next: 0 }
+    }
+}
+
+impl<T: Copy> Ring<T> {
+    fn push(&mut self, sample: T) {
+        if self.samples.len() < WINDOW {
+            self.samples.push(sample);
+        } else {
+            self.samples[self.next] = sample;
+        }
+        self.next = (self.next + 1) % WINDOW;
+    }
+
+    /// Up to `count` most recent samples, oldest first
+    fn recent(&self, count: usize) -> Vec<T> {
+        let len = self.samples.len();
+        let count = count.min(len);
+        let start = if len < WINDOW { len - count } else { (self.next + WINDOW - count) % WINDOW };
+        (0..count).map(|i| self.samples[(start + i) % len]).collect()
+    }
+}
+
+/// Summary of one latency segment, in milliseconds
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Distribution {
+    pub count: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Distribution {
+    fn from_us(values: impl Iterator<Item = u64>) -> Option<Self> {
+        let mut values: Vec<u64> = values.collect();
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        let ms = |us: u64| us as f64 / 1000.0;
+        let percentile = |p: usize| ms(values[((values.len() - 1) * p).div_ceil(100)]);
+        Some(Self {
+            count: values.len(),
+            min_ms: ms(values[0]),
+            mean_ms: ms(values.iter().sum::<u64>() / values.len() as u64),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: ms(values[values.len() - 1]),
+        })
+    }
+}
+
+/// One open stream and its recent sends
+pub struct ClientLatency {
+    id: u64,
+    path: &'static str,
+    connected_ms: u64,
+    frames_sent: AtomicU64,
+    sends: Mutex<Ring<SendSample>>,
+}
+
+/// Registration of a stream client; unregisters on drop
+pub struct ClientHandle {
+    client: Arc<ClientLatency>,
+    tracker: Arc<LatencyTracker>,
+}
+
+impl ClientHandle {
+    /// Record that this client's copy of a frame is being sent now
+    pub fn record_send(&self, timing: &FrameTiming) {
+        let now = now_us();
+        self.client.frames_sent.fetch_add(1, Ordering::Relaxed);
+        if timing.published_us == 0 {
+            return;
+        }
+        self.client.sends.lock().push(SendSample {
+            send_us: now.saturating_sub(timing.published_us),
+            total_us: now.saturating_sub(timing.capture_start_us),
+        });
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        self.tracker.clients.lock().retain(|c| c.id != self.client.id);
+    }
+}
+
+/// Frame timings and per-client send timings
+#[derive(Default)]
+pub struct LatencyTracker {
+    frames: Mutex<Ring<FrameTiming>>,
+    clients: Mutex<Vec<Arc<ClientLatency>>>,
+    next_client: AtomicU64,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a published frame
+    pub fn record_frame(&self, timing: &FrameTiming) {
+        self.frames.lock().push(*timing);
+    }
+
+    /// Start tracking a stream client (`path` names the endpoint)
+    pub fn register(self: &Arc<Self>, path: &'static str, connected_ms: u64) -> ClientHandle {
+        let client = Arc::new(ClientLatency {
+            id: self.next_client.fetch_add(1, Ordering::Relaxed) + 1,
+            path,
+            connected_ms,
+            frames_sent: AtomicU64::new(0),
+            sends: Mutex::new(Ring::default()),
+        });
+        self.clients.lock().push(client.clone());
+        ClientHandle { client, tracker: self.clone() }
+    }
+
+    /// Distributions over the last `frames` frames (and sends per client)
+    pub fn report(&self, frames: usize) -> LatencyReport {
+        let timings = self.frames.lock().recent(frames);
+        let segment = |f: fn(&FrameTiming) -> u64| Distribution::from_us(timings.iter().map(f));
+        let clients = self.clients.lock().clone();
+        LatencyReport {
+            frames: timings.len(),
+            capture: segment(|t| t.capture_done_us.saturating_sub(t.capture_start_us)),
+            processing: segment(|t| t.processed_us.saturating_sub(t.capture_done_us)),
+            publish: segment(|t| t.published_us.saturating_sub(t.processed_us)),
+            pipeline: segment(|t| t.published_us.saturating_sub(t.capture_start_us)),
+            clients: clients
+                .iter()
+                .map(|client| {
+                    let sends = client.sends.lock().recent(frames);
+                    ClientReport {
+                        id: client.id,
+                        path: client.path,
+                        connected_ms: client.connected_ms,
+                        frames_sent: client.frames_sent.load(Ordering::Relaxed),
+                        send: Distribution::from_us(sends.iter().map(|s| s.send_us)),
+                        total: Distribution::from_us(sends.iter().map(|s| s.total_us)),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Per-client section of /stats/latency
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ClientReport {
+    pub id: u64,
+    pub path: &'static str,
+    pub connected_ms: u64,
+    pub frames_sent: u64,
+    /// Publish to send
+    pub send: Option<Distribution>,
+    /// Capture start to send
+    pub total: Option<Distribution>,
+}
+
+/// GET /stats/latency
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LatencyReport {
+    pub frames: usize,
+    /// Capture start to raw buffer dequeued
+    pub capture: Option<Distribution>,
+    /// Unpack, demosaic, encode and overlays
+    pub processing: Option<Distribution>,
+    /// Storing the frame for handlers, up to the publish
+    pub publish: Option<Distribution>,
+    /// Capture start to publish
+    pub pipeline: Option<Distribution>,
+    pub clients: Vec<ClientReport>,
+}
+
+/// Draw the blink marker (a white square with a black border) in the
+/// top-left corner of a JPEG frame
+pub fn draw_marker(jpeg_data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut rgb = image::load_from_memory(jpeg_data)?.to_rgb8();
+    let size = (rgb.height() / MARKER_FRACTION).max(8).min(rgb.width()).min(rgb.height());
+    let border = (size / 16).max(1);
+    for y in 0..size {
+        for x in 0..size {
+            let edge = x >= size - border || y >= size - border;
+            let value = if edge { 0 } else { 255 };
+            rgb.put_pixel(x, y, image::Rgb([value; 3]));
+        }
+    }
+    crate::capture::encode_image_jpeg(&DynamicImage::ImageRgb8(rgb), 90)
+}
@@ -0,0 +1,183 @@
+//! IMX415 capture and processing for the Rock 5C
+//!
+//! The streamer binary is built on this library, and its capture and
+//! detection parts can be used without the web server:
+//!
+//! - [`FrameCapture`] sets up the sensor, runs the V4L2 capture stream
+//!   ([`stream`], formats in [`rawformat`]) and turns raw frames into JPEGs
+//!   or pixels, configured by [`CaptureConfig`] and the processing settings
+//!   in [`pipeline`]; raw dumps or a test pattern can stand in for the
+//!   sensor ([`source`])
+//! - the pipeline stages it runs are modules of their own: [`demosaic`],
+//!   [`greenbalance`], [`whitebalance`], [`ccm`] (color correction),
+//!   [`hdr`] (exposure fusion), [`denoise`] (noise reduction), [`sharpen`]
+//!   (unsharp mask), [`contrast`] (grayscale equalization), [`tone`]
+//!   (brightness, contrast and saturation), [`orientation`] (flips and
+//!   rotation), [`masks`] (privacy masks), [`zoom`] (digital zoom),
+//!   [`overlay`] (burnt-in text) and [`scale`]; [`StageTimings`] reports
+//!   what each took for a frame
+//! - [`exif`] tags the JPEGs, [`stack`] averages raw frames into long
+//!   exposures and [`dng`] writes raw images
+//! - [`controls`] reads and sets the sensor's V4L2 controls
+//! - [`YoloDetector`] runs object or face detection on a thread of its own
+//!   with one of the [`detector`] backends, tagging detections with
+//!   [`zones`] and drawing them as [`annotation`] says
+//!
+//! The `server` feature (on by default) adds the streamer itself as the
+//! `server` module: its HTTP API, CLI, recording, events and so on, and
+//! the OpenAPI schemas of the types above. Build with
+//! `default-features = false` for the library alone.
+
+// The GET /status example in server/openapi.rs is one large json! literal
+#![recursion_limit = "256"]
+
+pub mod annotation;
+pub mod capture;
+pub mod ccm;
+pub mod contrast;
+pub mod controls;
+pub mod demosaic;
+pub mod denoise;
+pub mod detector;
+pub mod dng;
+pub mod exif;
+pub mod greenbalance;
+pub mod hdr;
+pub mod masks;
+pub mod metering;
+pub mod orientation;
+pub mod overlay;
+pub mod pipeline;
+pub mod rawformat;
+pub mod scale;
+pub mod sharpen;
+pub mod source;
+pub mod stack;
+pub mod stream;
+pub mod tone;
+pub mod whitebalance;
+pub mod zones;
+pub mod zoom;
+#[cfg(feature = "server")]
+pub mod server;
+
+#[cfg(feature = "mpp")]
+mod mpp;
+#[cfg(target_arch = "aarch64")]
+mod neon;
+#[cfg(feature = "onnx")]
+mod onnx_backend;
+#[cfg(feature = "rga")]
+mod rga;
+#[cfg(feature = "rknn")]
+mod rknn_backend;
+#[cfg(any(feature = "onnx", feature = "rknn"))]
+mod scrfd;
+#[cfg(any(feature = "onnx", feature = "rknn"))]
+mod yolo;
+
+// The streamer's own, used by `server`
+#[cfg(feature = "server")]
+mod avi;
+#[cfg(feature = "server")]
+mod bandwidth;
+#[cfg(feature = "server")]
+mod clip;
+#[cfg(feature = "server")]
+mod compare;
+#[cfg(feature = "server")]
+mod config;
+#[cfg(feature = "server")]
+mod daynight;
+#[cfg(feature = "server")]
+mod decodecache;
+#[cfg(feature = "server")]
+mod error;
+#[cfg(feature = "server")]
+mod events;
+#[cfg(feature = "server")]
+mod faces;
+#[cfg(feature = "server")]
+mod fmp4;
+#[cfg(feature = "server")]
+mod focus;
+#[cfg(feature = "server")]
+mod framehash;
+#[cfg(feature = "server")]
+mod gpio;
+#[cfg(feature = "server")]
+mod h264;
+#[cfg(feature = "server")]
+mod history;
+#[cfg(feature = "server")]
+mod homeassistant;
+#[cfg(feature = "server")]
+mod jobs;
+#[cfg(feature = "server")]
+mod latency;
+#[cfg(feature = "server")]
+mod memory;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod models;
+#[cfg(feature = "server")]
+mod motion;
+#[cfg(feature = "server")]
+mod mqtt;
+#[cfg(feature = "server")]
+mod prebuffer;
+#[cfg(feature = "server")]
+mod profiles;
+#[cfg(feature = "server")]
+mod push;
+#[cfg(feature = "server")]
+mod ratelimit;
+#[cfg(feature = "server")]
+mod recorder;
+#[cfg(feature = "server")]
+mod reprocess;
+#[cfg(feature = "server")]
+mod scaledframes;
+#[cfg(feature = "server")]
+mod schedule;
+#[cfg(feature = "server")]
+mod selftest;
+#[cfg(feature = "server")]
+mod snapshots;
+#[cfg(feature = "server")]
+mod spool;
+#[cfg(feature = "server")]
+mod storage;
+#[cfg(feature = "server")]
+mod supervisor;
+#[cfg(feature = "server")]
+mod thermal;
+#[cfg(feature = "server")]
+mod version;
+#[cfg(feature = "server")]
+mod watchdog;
+#[cfg(feature = "server")]
+mod webrtc_peer;
+
+pub use capture::{CaptureConfig, CaptureMode, FrameCapture, RawCapture, StageTimings};
+pub use detector::{Detection, DetectionResult, DetectorConfig, YoloDetector};
+pub use pipeline::{PipelineSettings, SettingsCell};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Names of profiles, zones, masks and the like: letters, digits, '_' and
+/// '-', at most 64
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Milliseconds since the Unix epoch
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,236 @@
+//! Privacy masks
+//!
+//! Masks are named rectangles or polygons in fractions of the oriented frame
+//! (before the digital zoom, so they stay on the same part of the scene
+//! while zooming), kept as `[[masks]]` in the config file. They are blacked
+//! out or pixelated in the processed frame right after the orientation
+//! stage, so the streams, stills, recordings and the detector never see
+//! what's under them. Raw frames (DNG downloads, raw push) are the sensor's
+//! data and are not masked.
+//!
+//! Changing the orientation moves the frame under the masks: they are
+//! meant to be drawn on the frame as it's finally oriented.
+
+use crate::capture::FrameSize;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// Vertex limit per mask
+pub const MAX_POINTS: usize = 64;
+
+/// Masks per config file
+pub const MAX_MASKS: usize = 32;
+
+// Slack for rectangles whose edges are sums of rounded fractions
+const EPSILON: f64 = 1e-9;
+
+/// Pixelation blocks across the frame width
+pub const PIXELATE_BLOCKS: usize = 96;
+
+/// How a mask hides what's under it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum MaskStyle {
+    /// Solid black
+    #[default]
+    Black,
+    /// Coarse blocks of the average color ([`PIXELATE_BLOCKS`] across the
+    /// frame)
+    Pixelate,
+}
+
+/// A named rectangle or polygon
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Mask {
+    /// Letters, digits, '_' and '-' (taken from the path in `PUT /masks/{name}`)
+    #[serde(default)]
+    pub name: String,
+    /// Rectangle as `[x, y, width, height]` fractions of the frame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rect: Option<[f64; 4]>,
+    /// Polygon vertices as `[x, y]` fractions of the frame, in order around
+    /// the polygon (at least 3; instead of `rect`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<[f64; 2]>,
+    #[serde(default)]
+    pub style: MaskStyle,
+}
+
+impl Mask {
+    pub fn validate(&self) -> Result<(), String> {
+        if !crate::valid_name(&self.name) {
+            return Err(format!(
+                "Invalid mask name '{}' (letters, digits, '_' and '-', at most 64)",
+                self.name
+            ));
+        }
+        match (self.rect, self.points.is_empty()) {
+            (Some([x, y, width, height]), true) => {
+                let fractions = [x, y, width, height].iter().all(|v| (0.0..=1.0).contains(v));
+                if !fractions || x + width > 1.0 + EPSILON || y + height > 1.0 + EPSILON {
+                    return Err(format!("Mask '{}': rect must lie within the frame (fractions 0-1)", self.name));
+                }
+                if width <= 0.0 || height <= 0.0 {
+                    return Err(format!("Mask '{}': rect needs a width and height", self.name));
+                }
+            }
+            (None, false) => {
+                if !(3..=MAX_POINTS).contains(&self.points.len()) {
+                    return Err(format!("Mask '{}' needs 3-{} points", self.name, MAX_POINTS));
+                }
+                if self.points.iter().flatten().any(|v| !(0.0..=1.0).contains(v)) {
+                    return Err(format!("Mask '{}': points must be fractions of the frame (0-1)", self.name));
+                }
+            }
+            _ => return Err(format!("Mask '{}' needs either rect or points", self.name)),
+        }
+        Ok(())
+    }
+
+    /// The outline as polygon vertices
+    fn polygon(&self) -> Vec<[f64; 2]> {
+        match self.rect {
+            Some([x, y, width, height]) => vec![[x, y], [x + width, y], [x + width, y + height], [x, y + height]],
+            None => self.points.clone(),
+        }
+    }
+}
+
+/// Check a whole mask list (names must be unique)
+pub fn validate(masks: &[Mask]) -> Result<(), String> {
+    if masks.len() > MAX_MASKS {
+        return Err(format!("At most {} masks", MAX_MASKS));
+    }
+    let mut names = BTreeSet::new();
+    for mask in masks {
+        mask.validate()?;
+        if !names.insert(mask.name.as_str()) {
+            return Err(format!("Duplicate mask '{}'", mask.name));
+        }
+    }
+    Ok(())
+}
+
+/// A run of masked pixels on one row, `x0..x1`
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    y: usize,
+    x0: usize,
+    x1: usize,
+    style: MaskStyle,
+}
+
+/// Masks rasterized for one frame size: the pixel runs to hide
+#[derive(Debug, Clone)]
+pub struct MaskRaster {
+    size: FrameSize,
+    spans: Vec<Span>,
+}
+
+impl MaskRaster {
+    /// Rasterize `masks` for frames of `size`: pixels whose centres fall
+    /// inside a mask (even-odd rule, as for zones)
+    pub fn new(masks: &[Mask], size: FrameSize) -> Self {
+        let mut spans = Vec::new();
+        let mut crossings = Vec::new();
+        for mask in masks {
+            let polygon = mask.polygon();
+            for y in 0..size.height {
+                let fy = (y as f64 + 0.5) / size.height as f64;
+                crossings.clear();
+                let mut previous = polygon[polygon.len() - 1];
+                for &point in &polygon {
+                    let ([x1, y1], [x2, y2]) = (previous, point);
+                    if (y1 > fy) != (y2 > fy) {
+                        crossings.push(x1 + (fy - y1) * (x2 - x1) / (y2 - y1));
+                    }
+                    previous = point;
+                }
+                crossings.sort_by(f64::total_cmp);
+                for pair in crossings.chunks_exact(2) {
+                    // First and one past the last pixel centre inside
+                    let column = |fx: f64| ((fx * size.width as f64 - 0.5).ceil().max(0.0) as usize).min(size.width);
+                    let (x0, x1) = (column(pair[0]), column(pair[1]));
+                    if x0 < x1 {
+                        spans.push(Span { y, x0, x1, style: mask.style });
+                    }
+                }
+            }
+        }
+        Self { size, spans }
+    }
+
+    /// Frame size the masks were rasterized for
+    pub fn size(&self) -> FrameSize {
+        self.size
+    }
+
+    /// Hide the masked pixels of an 8-bit RGB or grayscale frame of the
+    /// raster's size
+    pub fn apply(&self, image: &mut DynamicImage) {
+        if (image.width() as usize, image.height() as usize) != (self.size.width, self.size.height) {
+            return;
+        }
+        match image {
+            DynamicImage::ImageRgb8(img) => self.apply_raw(img.as_mut(), 3),
+            DynamicImage::ImageLuma8(img) => self.apply_raw(img.as_mut(), 1),
+            _ => {}
+        }
+    }
+
+    fn apply_raw(&self, pixels: &mut [u8], channels: usize) {
+        let FrameSize { width, height } = self.size;
+        let stride = width * channels;
+        // Black first, so pixelated blocks don't average in what a black
+        // mask hides; block averages are all taken before any is written
+        self.fill_black(pixels, stride, channels);
+        let block = (width / PIXELATE_BLOCKS).max(1);
+        let mut averages: HashMap<(usize, usize), [u8; 3]> = HashMap::new();
+        for span in self.spans.iter().filter(|s| s.style == MaskStyle::Pixelate) {
+            let by = span.y / block;
+            for bx in span.x0 / block..=(span.x1 - 1) / block {
+                averages.entry((bx, by)).or_insert_with(|| {
+                    let (x_end, y_end) = (((bx + 1) * block).min(width), ((by + 1) * block).min(height));
+                    let mut sums = [0u64; 3];
+                    for y in by * block..y_end {
+                        let row = &pixels[y * stride + bx * block * channels..y * stride + x_end * channels];
+                        for pixel in row.chunks_exact(channels) {
+                            for (sum, &value) in sums.iter_mut().zip(pixel) {
+                                *sum += value as u64;
+                            }
+                        }
+                    }
+                    let count = ((x_end - bx * block) * (y_end - by * block)).max(1) as u64;
+                    sums.map(|sum| (sum / count) as u8)
+                });
+            }
+        }
+        if averages.is_empty() {
+            return;
+        }
+        for span in self.spans.iter().filter(|s| s.style == MaskStyle::Pixelate) {
+            let row = &mut pixels[span.y * stride..(span.y + 1) * stride];
+            let mut x = span.x0;
+            while x < span.x1 {
+                let end = ((x / block + 1) * block).min(span.x1);
+                let average = &averages[&(x / block, span.y / block)][..channels];
+                for pixel in row[x * channels..end * channels].chunks_exact_mut(channels) {
+                    pixel.copy_from_slice(average);
+                }
+                x = end;
+            }
+        }
+        // Where masks overlap, black wins
+        self.fill_black(pixels, stride, channels);
+    }
+
+    fn fill_black(&self, pixels: &mut [u8], stride: usize, channels: usize) {
+        for span in self.spans.iter().filter(|s| s.style == MaskStyle::Black) {
+            pixels[span.y * stride + span.x0 * channels..span.y * stride + span.x1 * channels].fill(0);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Memory accounting for frame-holding structures
+//!
+//! Every structure that retains frame data reports its size to a shared
+//! `MemoryTracker`, which checks the total against one global budget
+//! (`--memory-budget-mb`). As usage approaches the budget, features degrade
+//! in a fixed priority order rather than letting the board get OOM-killed:
+//!
+//! 1. The history ring shrinks, oldest frames first, to keep the total
+//!    under the soft limit (`SOFT_LIMIT_PERCENT` of the budget).
+//! 2. If the total is still over the budget, non-essential retention is
+//!    dropped: the A/B compare reference, the decoded-frame cache and the
+//!    recording pre-event buffer are cleared, a frame stack is dropped,
+//!    and new references and stacks are refused while they would not fit.
+//! 3. Essential buffers (the current frame and detector input) are never
+//!    dropped; the pressure is reported as critical.
+//!
+//! Nothing here panics or fails a capture when the budget is exceeded.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Default budget for retained frame data
+pub const DEFAULT_MEMORY_BUDGET_MB: usize = 512;
+
+/// Share of the budget the history ring may grow into
+pub const SOFT_LIMIT_PERCENT: usize = 80;
+
+/// A structure that retains frame data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    /// Current annotated and clean JPEGs, the pre-encode pixels and the
+    /// downscaled stream encodings
+    CurrentFrame,
+    /// Pending and in-flight detector frames
+    DetectorInput,
+    /// Frame history ring
+    History,
+    /// A/B compare reference frame
+    CompareReference,
+    /// Decoded pixels of published JPEGs
+    DecodeCache,
+    /// Frames kept for the start of the next recording
+    PreBuffer,
+    /// Sums of a raw frame stack
+    Stack,
+}
+
+impl Component {
+    pub const ALL: [Component; 7] = [
+        Component::CurrentFrame,
+        Component::DetectorInput,
+        Component::History,
+        Component::CompareReference,
+        Component::DecodeCache,
+        Component::PreBuffer,
+        Component::Stack,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Component::CurrentFrame => "current_frame",
+            Component::DetectorInput => "detector_input",
+            Component::History => "history",
+            Component::CompareReference => "compare_reference",
+            Component::DecodeCache => "decode_cache",
+            Component::PreBuffer => "pre_buffer",
+            Component::Stack => "stack",
+        }
+    }
+
+    /// Essential components are never dropped to save memory
+    pub fn is_essential(self) -> bool {
+        matches!(self, Component::CurrentFrame | Component::DetectorInput)
+    }
+}
+
+/// How far degradation has progressed, in priority order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryPressure {
+    /// Everything fits
+    Normal,
+    /// The history ring is being held below its frame count
+    Shrinking,
+    /// Non-essential features were dropped or refused
+    Shedding,
+    /// Essential buffers alone exceed the budget
+    Critical,
+}
+
+impl MemoryPressure {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => MemoryPressure::Normal,
+            1 => MemoryPressure::Shrinking,
+            2 => MemoryPressure::Shedding,
+            _ => MemoryPressure::Critical,
+        }
+    }
+}
+
+/// Snapshot of memory usage for /status and /stats
+#[derive(Debug, Clone, Serialize)]
+pub struct MemorySnapshot {
+    pub budget_bytes: usize,
+    pub used_bytes: usize,
+    pub pressure: MemoryPressure,
+    pub components: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Per-component byte counts against a global budget
+pub struct MemoryTracker {
+    budget: usize,
+    usage: [AtomicUsize; Component::ALL.len()],
+    pressure: AtomicU8,
+}
+
+impl MemoryTracker {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            usage: Default::default(),
+            pressure: AtomicU8::new(MemoryPressure::Normal as u8),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Usage the history ring may grow into before it starts shrinking
+    pub fn soft_limit(&self) -> usize {
+        self.budget / 100 * SOFT_LIMIT_PERCENT
+    }
+
+    /// Record the current size of a component
+    pub fn set(&self, component: Component, bytes: usize) {
+        self.usage[component as usize].store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn usage(&self, component: Component) -> usize {
+        self.usage[component as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> usize {
+        Component::ALL.iter().map(|&c| self.usage(c)).sum()
+    }
+
+    /// Bytes `component` may use while keeping the total under the soft limit
+    pub fn allowance(&self, component: Component) -> usize {
+        let others = self.total() - self.usage(component);
+        self.soft_limit().saturating_sub(others)
+    }
+
+    /// Whether `component` could grow to `bytes` without exceeding the budget
+    pub fn fits(&self, component: Component, bytes: usize) -> bool {
+        self.total() - self.usage(component) + bytes <= self.budget
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.total() > self.budget
+    }
+
+    /// Usage of the essential components only
+    pub fn essential_total(&self) -> usize {
+        Component::ALL
+            .iter()
+            .filter(|c| c.is_essential())
+            .map(|&c| self.usage(c))
+            .sum()
+    }
+
+    pub fn pressure(&self) -> MemoryPressure {
+        MemoryPressure::from_u8(self.pressure.load(Ordering::Relaxed))
+    }
+
+    /// Record the pressure level; returns the previous level
+    pub fn set_pressure(&self, pressure: MemoryPressure) -> MemoryPressure {
+        MemoryPressure::from_u8(self.pressure.swap(pressure as u8, Ordering::Relaxed))
+    }
+
+    pub fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            budget_bytes: self.budget,
+            used_bytes: self.total(),
+            pressure: self.pressure(),
+            components: Component::ALL
+                .iter()
+                .map(|&c| (c.name().to_string(), self.usage(c).into()))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Exposure metering: which part of the frame brightness is measured over
+//!
+//! Average weighs every pixel equally, center-weighted applies a gaussian
+//! falloff from the frame center, and spot measures only an explicit
+//! rectangle. Regions are given as fractions of the frame so they survive
+//! resolution changes. Luminance is sampled on a sparse grid; a 4K frame is
+//! far more pixels than a brightness estimate needs.
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+/// Samples taken along the longer frame edge
+const SAMPLES_ACROSS: u32 = 256;
+
+/// Gaussian sigma for center-weighted metering, as a fraction of each edge
+const CENTER_SIGMA: f32 = 0.25;
+
+/// Spot region used when none is given: the central 10% of each edge
+pub const DEFAULT_SPOT: Roi = Roi { x: 0.45, y: 0.45, w: 0.1, h: 0.1 };
+
+/// Metering mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MeteringMode {
+    #[default]
+    Average,
+    CenterWeighted,
+    Spot,
+}
+
+impl MeteringMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "average" | "avg" => Some(MeteringMode::Average),
+            "center" | "center_weighted" | "center-weighted" => Some(MeteringMode::CenterWeighted),
+            "spot" | "region" | "roi" => Some(MeteringMode::Spot),
+            _ => None,
+        }
+    }
+}
+
+/// Metering rectangle, in fractions (0.0-1.0) of the frame size
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct Roi {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Roi {
+    pub fn new(x: f32, y: f32, w: f32, h: f32) -> Result<Self, String> {
+        let roi = Self { x, y, w, h };
+        let in_unit = |v: f32| (0.0..=1.0).contains(&v);
+        if ![x, y, w, h].iter().all(|&v| in_unit(v)) || w <= 0.0 || h <= 0.0 {
+            return Err("Region values must be fractions between 0.0 and 1.0 with non-zero size".into());
+        }
+        if x + w > 1.0 + f32::EPSILON || y + h > 1.0 + f32::EPSILON {
+            return Err("Region extends past the frame edge".into());
+        }
+        Ok(roi)
+    }
+
+    /// Pixel bounds `(x1, y1, x2, y2)` (exclusive end) in a `width`x`height` frame
+    pub fn to_pixels(self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let scale = |v: f32, size: u32| ((v * size as f32).round() as u32).min(size);
+        let (x1, y1) = (scale(self.x, width), scale(self.y, height));
+        let x2 = scale(self.x + self.w, width).max(x1 + 1).min(width);
+        let y2 = scale(self.y + self.h, height).max(y1 + 1).min(height);
+        (x1, y1, x2, y2)
+    }
+}
+
+/// Current metering selection (a pipeline setting)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct MeteringConfig {
+    pub mode: MeteringMode,
+    /// Spot region (only used in spot mode)
+    pub roi: Option<Roi>,
+    /// Draw the metered region on the annotated stream
+    pub overlay: bool,
+}
+
+impl MeteringConfig {
+    /// Same mode and region (the overlay aside)
+    pub fn same_region(&self, other: &MeteringConfig) -> bool {
+        (self.mode, self.roi) == (other.mode, other.roi)
+    }
+
+    /// Weight of the point at `(fx, fy)`, in fractions of the frame, in
+    /// the metered mean
+    pub fn weight(&self, fx: f32, fy: f32) -> f32 {
+        match self.mode {
+            MeteringMode::Average => 1.0,
+            MeteringMode::CenterWeighted => {
+                let (dx, dy) = ((fx - 0.5) / CENTER_SIGMA, (fy - 0.5) / CENTER_SIGMA);
+                (-0.5 * (dx * dx + dy * dy)).exp()
+            }
+            MeteringMode::Spot => {
+                let roi = self.roi.unwrap_or(DEFAULT_SPOT);
+                let inside = (roi.x..roi.x + roi.w).contains(&fx) && (roi.y..roi.y + roi.h).contains(&fy);
+                if inside { 1.0 } else { 0.0 }
+            }
+        }
+    }
+
+    /// Region outlined by the overlay: the spot, or the center-weighted
+    /// ±1 sigma box; average metering has nothing to outline
+    pub fn overlay_region(&self) -> Option<Roi> {
+        match self.mode {
+            MeteringMode::Average => None,
+            MeteringMode::CenterWeighted => Some(Roi {
+                x: 0.5 - CENTER_SIGMA,
+                y: 0.5 - CENTER_SIGMA,
+                w: 2.0 * CENTER_SIGMA,
+                h: 2.0 * CENTER_SIGMA,
+            }),
+            MeteringMode::Spot => Some(self.roi.unwrap_or(DEFAULT_SPOT)),
+        }
+    }
+}
+
+/// Full-frame and metered mean luminance (0-255)
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Luminance {
+    pub full_frame: f32,
+    pub metered: f32,
+}
+
+/// Rec. 601 luma of one pixel
+fn luma(image: &DynamicImage, x: u32, y: u32) -> f32 {
+    match image {
+        DynamicImage::ImageLuma8(gray) => gray.get_pixel(x, y)[0] as f32,
+        DynamicImage::ImageRgb8(rgb) => {
+            let [r, g, b] = rgb.get_pixel(x, y).0;
+            0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32
+        }
+        other => {
+            let [r, g, b, _] = image::GenericImageView::get_pixel(other, x, y).0;
+            0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32
+        }
+    }
+}
+
+/// Weighted mean luminance over `(x1, y1)..(x2, y2)`
+fn weighted_mean(
+    image: &DynamicImage,
+    (x1, y1, x2, y2): (u32, u32, u32, u32),
+    weight: impl Fn(u32, u32) -> f32,
+) -> f32 {
+    let step = ((x2 - x1).max(y2 - y1) / SAMPLES_ACROSS).max(1) as usize;
+    let (mut sum, mut total) = (0.0f64, 0.0f64);
+    for y in (y1..y2).step_by(step) {
+        for x in (x1..x2).step_by(step) {
+            let w = weight(x, y) as f64;
+            sum += w * luma(image, x, y) as f64;
+            total += w;
+        }
+    }
+    if total > 0.0 { (sum / total) as f32 } else { 0.0 }
+}
+
+/// Measure the frame's mean luminance and the luminance under `config`
+pub fn measure(image: &DynamicImage, config: &MeteringConfig) -> Luminance {
+    let (width, height) = (image.width(), image.height());
+    if width == 0 || height == 0 {
+        return Luminance { full_frame: 0.0, metered: 0.0 };
+    }
+    let frame = (0, 0, width, height);
+    let full_frame = weighted_mean(image, frame, |_, _| 1.0);
+    let metered = match config.mode {
+        MeteringMode::Average => full_frame,
+        MeteringMode::CenterWeighted => weighted_mean(image, frame, |x, y| {
+            config.weight(x as f32 / width as f32, y as f32 / height as f32)
+        }),
+        MeteringMode::Spot => {
+            let roi = config.roi.unwrap_or(DEFAULT_SPOT);
+            weighted_mean(image, roi.to_pixels(width, height), |_, _| 1.0)
+        }
+    };
+    Luminance { full_frame, metered }
+}
+
+/// Outline `roi` on a JPEG frame
+pub fn draw_region(jpeg_data: &[u8], roi: Roi) -> anyhow::Result<Vec<u8>> {
+    let mut rgb = image::load_from_memory(jpeg_data)?.to_rgb8();
+    let (x1, y1, x2, y2) = roi.to_pixels(rgb.width(), rgb.height());
+    let color = image::Rgb([255u8, 210, 0]);
+    let thickness = 4;
+    for y in y1..y2 {
+        for x in x1..x2 {
+            let edge = x < x1 + thickness || x + thickness >= x2 || y < y1 + thickness || y + thickness >= y2;
+            if edge {
+                rgb.put_pixel(x, y, color);
+            }
+        }
+    }
+    crate::capture::encode_image_jpeg(&DynamicImage::ImageRgb8(rgb), 85)
+}
@@ -0,0 +1,71 @@
+//! Prometheus text exposition for /metrics
+
+use std::fmt::Write;
+
+/// Prefix of every exported metric name
+pub const PREFIX: &str = "imx415_streamer";
+
+/// Builds a Prometheus text-format (0.0.4) response body
+#[derive(Default)]
+pub struct MetricsWriter {
+    body: String,
+}
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.body, "# HELP {}_{} {}", PREFIX, name, help);
+        let _ = writeln!(self.body, "# TYPE {}_{} {}", PREFIX, name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let _ = write!(self.body, "{}_{}", PREFIX, name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+                .collect();
+            let _ = write!(self.body, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.body, " {}", value);
+    }
+
+    /// A single unlabelled gauge
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    /// A single unlabelled counter
+    pub fn counter(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+
+    /// A gauge with one sample per label set
+    pub fn labelled_gauge(&mut self, name: &str, help: &str, samples: &[(Vec<(&str, &str)>, f64)]) {
+        self.header(name, "gauge", help);
+        for (labels, value) in samples {
+            self.sample(name, labels, *value);
+        }
+    }
+
+    /// A counter with one sample per label set
+    pub fn labelled_counter(&mut self, name: &str, help: &str, samples: &[(Vec<(&str, &str)>, f64)]) {
+        self.header(name, "counter", help);
+        for (labels, value) in samples {
+            self.sample(name, labels, *value);
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.body
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
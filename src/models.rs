@@ -0,0 +1,176 @@
+//! Detector model files: listing, streamed upload and removal
+//!
+//! Models live in one directory (`--models-dir`). Uploads are streamed to a
+//! hidden temp file next to their destination, hashed on the way, and only
+//! renamed into place once complete and verified, so a half-written or
+//! corrupt upload never replaces a working model. The temp file is removed
+//! if the upload is abandoned (client disconnect, size limit, bad checksum).
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Model file types the backends can load
+pub const MODEL_EXTENSIONS: [&str; 2] = ["rknn", "onnx"];
+
+/// Plain file names with a known model extension: no separators, no
+/// leading dot, nothing that could escape the models directory
+pub fn valid_model_name(name: &str) -> bool {
+    let Some((stem, extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    name.len() <= 128
+        && !stem.is_empty()
+        && !name.starts_with('.')
+        && MODEL_EXTENSIONS.contains(&extension)
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// One model file
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub size: u64,
+    pub modified_ms: Option<u64>,
+    pub active: bool,
+}
+
+/// Model files in `dir`, sorted by name; `active` names are flagged
+pub fn list_models(dir: &Path, active: &[String]) -> Result<Vec<ModelInfo>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut models: Vec<ModelInfo> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+            valid_model_name(&name).then(|| ModelInfo {
+                active: active.contains(&name),
+                size: metadata.len(),
+                modified_ms: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_millis() as u64),
+                name,
+            })
+        })
+        .collect();
+    models.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(models)
+}
+
+/// Why an upload was rejected
+#[derive(Debug)]
+pub enum UploadError {
+    TooLarge { limit: u64 },
+    ChecksumMismatch { expected: String, actual: String },
+    Io(anyhow::Error),
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::TooLarge { limit } => write!(f, "Model exceeds the {} byte upload limit", limit),
+            UploadError::ChecksumMismatch { expected, actual } => {
+                write!(f, "SHA-256 mismatch: expected {}, received {}", expected, actual)
+            }
+            UploadError::Io(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        UploadError::Io(e.into())
+    }
+}
+
+/// Parse an `X-Content-SHA256` value (64 hex digits)
+pub fn parse_sha256(value: &str) -> Option<String> {
+    let value = value.trim().to_ascii_lowercase();
+    (value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())).then_some(value)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A model upload in progress
+pub struct ModelUpload {
+    file: tokio::fs::File,
+    temp_path: PathBuf,
+    final_path: PathBuf,
+    hasher: Sha256,
+    written: u64,
+    limit: u64,
+    finished: bool,
+}
+
+impl ModelUpload {
+    /// Start writing `name` into `dir` (created if missing)
+    pub async fn create(dir: &Path, name: &str, limit: u64) -> Result<Self, UploadError> {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))
+            .map_err(UploadError::Io)?;
+        // Unique per upload, so concurrent uploads of one name can't interleave
+        let nonce = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let temp_path = dir.join(format!(".{}.{}.partial", name, nonce));
+        let file = tokio::fs::File::create(&temp_path)
+            .await
+            .with_context(|| format!("Failed to create {}", temp_path.display()))
+            .map_err(UploadError::Io)?;
+        Ok(Self {
+            file,
+            temp_path,
+            final_path: dir.join(name),
+            hasher: Sha256::new(),
+            written: 0,
+            limit,
+            finished: false,
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), UploadError> {
+        self.written += chunk.len() as u64;
+        if self.written > self.limit {
+            return Err(UploadError::TooLarge { limit: self.limit });
+        }
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    /// Verify the checksum (if given), flush to disk and move the file into
+    /// place; returns the size and SHA-256
+    pub async fn finish(mut self, expected_sha256: Option<&str>) -> Result<(u64, String), UploadError> {
+        let actual = hex(&std::mem::take(&mut self.hasher).finalize());
+        if let Some(expected) = expected_sha256 {
+            if expected != actual {
+                return Err(UploadError::ChecksumMismatch { expected: expected.to_string(), actual });
+            }
+        }
+        self.file.sync_all().await?;
+        tokio::fs::rename(&self.temp_path, &self.final_path).await?;
+        self.finished = true;
+        Ok((self.written, actual))
+    }
+}
+
+impl Drop for ModelUpload {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
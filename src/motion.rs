@@ -0,0 +1,290 @@
+//! Frame-differencing motion detection
+//!
+//! Runs in the capture loop on the native 960x1080 grayscale frame
+//! ([`FrameCapture::native_gray`](crate::capture::FrameCapture::native_gray)),
+//! so it works without the detector and costs about a millisecond a frame.
+//! Each frame is reduced to a grid of [`BLOCK`]x[`BLOCK`] block means and
+//! compared with the previous one; a block has changed when its difference,
+//! less the median difference of the frame (an exposure change moves every
+//! block alike), is above the threshold the sensitivity sets. Blocks
+//! whose centre falls in a mask polygon are left out.
+//!
+//! Motion starts after [`TRIGGER_FRAMES`] frames in a row with at least
+//! `min_area_percent` of the blocks changed, and stops once there has been
+//! none for `hold_secs`; both edges become `motion_started` /
+//! `motion_stopped` events.
+
+use crate::zones;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Settings state file key for the motion config
+pub const STATE_KEY: &str = "motion";
+
+/// Block size (native pixels) of the compared grid
+pub const BLOCK: usize = 8;
+
+/// Consecutive changed frames that start motion (one is often noise)
+pub const TRIGGER_FRAMES: u32 = 2;
+
+/// Mask polygons per config
+pub const MAX_MASKS: usize = 16;
+
+/// Longest hold time
+const MAX_HOLD_SECS: f32 = 3600.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotionConfig {
+    pub enabled: bool,
+    /// 1-100: higher reacts to smaller brightness changes
+    pub sensitivity: u8,
+    /// Changed share of the unmasked frame that counts as motion (percent)
+    pub min_area_percent: f32,
+    /// Motion stops after this long without any
+    pub hold_secs: f32,
+    /// Record while there is motion (if the recorder is idle when it starts)
+    pub record: bool,
+    /// Areas to ignore (trees, a busy road), each a polygon of `[x, y]`
+    /// fractions of the frame width and height
+    pub masks: Vec<Vec<[f64; 2]>>,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sensitivity: 50,
+            min_area_percent: 0.5,
+            hold_secs: 5.0,
+            record: false,
+            masks: Vec::new(),
+        }
+    }
+}
+
+impl MotionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.sensitivity) {
+            return Err("sensitivity must be between 1 and 100".to_string());
+        }
+        if !(self.min_area_percent > 0.0 && self.min_area_percent <= 100.0) {
+            return Err("min_area_percent must be above 0 and at most 100".to_string());
+        }
+        if !(0.0..=MAX_HOLD_SECS).contains(&self.hold_secs) {
+            return Err(format!("hold_secs must be between 0 and {}", MAX_HOLD_SECS));
+        }
+        if self.masks.len() > MAX_MASKS {
+            return Err(format!("At most {} masks", MAX_MASKS));
+        }
+        for (i, mask) in self.masks.iter().enumerate() {
+            if !(3..=zones::MAX_POINTS).contains(&mask.len()) {
+                return Err(format!("Mask {} needs 3-{} points", i, zones::MAX_POINTS));
+            }
+            if mask.iter().flatten().any(|v| !(0.0..=1.0).contains(v)) {
+                return Err(format!("Mask {}: points must be fractions of the frame (0-1)", i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Block-mean difference that counts as a change: 33 levels at
+    /// sensitivity 1 down to 3 at 100
+    pub fn threshold(&self) -> i32 {
+        3 + (100 - self.sensitivity.clamp(1, 100) as i32) * 30 / 99
+    }
+}
+
+/// GET /motion
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct MotionStatus {
+    pub enabled: bool,
+    pub active: bool,
+    /// Changed share of the unmasked frame in the last comparison (percent)
+    pub score: f32,
+    /// Frames compared since the config last changed
+    pub frames: u64,
+    /// Motion periods since the config last changed
+    pub events: u64,
+    /// When the current motion started (Unix ms)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_ms: Option<u64>,
+    /// Last frame with motion (Unix ms)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_motion_ms: Option<u64>,
+    /// Share of the frame the masks leave out (percent)
+    pub masked_percent: f32,
+}
+
+/// A motion edge, for the caller to publish
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    Started { score: f32 },
+    Stopped { duration_ms: u64 },
+}
+
+pub struct MotionDetector {
+    config: MotionConfig,
+    /// Block means of this frame and the previous one
+    current: Vec<u8>,
+    previous: Vec<u8>,
+    /// Per block: left out by a mask; computed for `mask_size`
+    masked: Vec<bool>,
+    mask_size: (usize, usize),
+    /// Changed frames in a row
+    streak: u32,
+    status: MotionStatus,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        let status = MotionStatus { enabled: config.enabled, ..Default::default() };
+        Self {
+            config,
+            current: Vec::new(),
+            previous: Vec::new(),
+            masked: Vec::new(),
+            mask_size: (0, 0),
+            streak: 0,
+            status,
+        }
+    }
+
+    pub fn config(&self) -> &MotionConfig {
+        &self.config
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn status(&self) -> MotionStatus {
+        self.status.clone()
+    }
+
+    /// Start over with a new config; ends current motion
+    pub fn set_config(&mut self, config: MotionConfig, now_ms: u64) -> Option<Transition> {
+        let stopped = self.stop(now_ms);
+        self.status = MotionStatus { enabled: config.enabled, ..Default::default() };
+        self.config = config;
+        self.previous.clear();
+        self.mask_size = (0, 0);
+        self.streak = 0;
+        stopped
+    }
+
+    /// Compare a `width`x`height` grayscale frame with the previous one
+    pub fn update(&mut self, width: usize, height: usize, pixels: &[u8], now_ms: u64) -> Option<Transition> {
+        let (columns, rows) = (width / BLOCK, height / BLOCK);
+        if !self.config.enabled || columns == 0 || rows == 0 || pixels.len() < width * height {
+            return None;
+        }
+        if self.mask_size != (columns, rows) {
+            self.build_mask(columns, rows);
+            self.previous.clear();
+        }
+        block_means(pixels, width, columns, rows, &mut self.current);
+        let first = self.previous.is_empty();
+        let score = if first { 0.0 } else { self.changed_percent() };
+        std::mem::swap(&mut self.current, &mut self.previous);
+        if first {
+            // Nothing to compare with yet
+            return None;
+        }
+        self.status.frames += 1;
+        self.status.score = score;
+
+        if self.status.score >= self.config.min_area_percent {
+            self.streak += 1;
+            self.status.last_motion_ms = Some(now_ms);
+            if !self.status.active && self.streak >= TRIGGER_FRAMES {
+                self.status.active = true;
+                self.status.since_ms = Some(now_ms);
+                self.status.events += 1;
+                return Some(Transition::Started { score: self.status.score });
+            }
+            return None;
+        }
+        self.streak = 0;
+        let hold_ms = (self.config.hold_secs * 1000.0) as u64;
+        let last = self.status.last_motion_ms.unwrap_or(now_ms);
+        if self.status.active && now_ms.saturating_sub(last) >= hold_ms {
+            return self.stop(now_ms);
+        }
+        None
+    }
+
+    fn stop(&mut self, now_ms: u64) -> Option<Transition> {
+        if !std::mem::take(&mut self.status.active) {
+            return None;
+        }
+        let since = self.status.since_ms.take().unwrap_or(now_ms);
+        Some(Transition::Stopped { duration_ms: now_ms.saturating_sub(since) })
+    }
+
+    /// Percent of the unmasked blocks that changed since the previous frame
+    fn changed_percent(&self) -> f32 {
+        let pairs = || {
+            self.current
+                .iter()
+                .zip(&self.previous)
+                .zip(&self.masked)
+                .filter(|(_, &masked)| !masked)
+                .map(|((&now, &before), _)| now as i32 - before as i32)
+        };
+        // Median difference: the exposure shift, which motion in part of
+        // the frame doesn't pull along the way it would a mean
+        let mut histogram = [0u32; 511];
+        for d in pairs() {
+            histogram[(d + 255) as usize] += 1;
+        }
+        let counted: u32 = histogram.iter().sum();
+        if counted == 0 {
+            return 0.0;
+        }
+        let mut below = 0;
+        let shift = histogram
+            .iter()
+            .position(|&n| {
+                below += n;
+                below * 2 >= counted
+            })
+            .unwrap_or(255) as i32
+            - 255;
+        let threshold = self.config.threshold();
+        let changed = pairs().filter(|d| (d - shift).abs() > threshold).count();
+        changed as f32 * 100.0 / counted as f32
+    }
+
+    fn build_mask(&mut self, columns: usize, rows: usize) {
+        self.mask_size = (columns, rows);
+        self.masked = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| {
+                let x = (column as f64 + 0.5) / columns as f64;
+                let y = (row as f64 + 0.5) / rows as f64;
+                self.config.masks.iter().any(|mask| zones::polygon_contains(mask, x, y))
+            })
+            .collect();
+        let masked = self.masked.iter().filter(|&&m| m).count();
+        self.status.masked_percent = masked as f32 * 100.0 / self.masked.len() as f32;
+    }
+}
+
+/// Mean of each `BLOCK`x`BLOCK` block, row by row
+fn block_means(pixels: &[u8], width: usize, columns: usize, rows: usize, out: &mut Vec<u8>) {
+    out.clear();
+    out.resize(columns * rows, 0);
+    let mut sums = vec![0u32; columns];
+    for row in 0..rows {
+        sums.fill(0);
+        for line in pixels[row * BLOCK * width..].chunks(width).take(BLOCK) {
+            for (sum, block) in sums.iter_mut().zip(line.chunks_exact(BLOCK)) {
+                *sum += block.iter().map(|&p| p as u32).sum::<u32>();
+            }
+        }
+        for (mean, sum) in out[row * columns..(row + 1) * columns].iter_mut().zip(&sums) {
+            *mean = (sum / (BLOCK * BLOCK) as u32) as u8;
+        }
+    }
+}
@@ -0,0 +1,505 @@
+//! Hardware JPEG and H.264 encoders on the Rockchip VPU through MPP
+//! (`mpp` feature)
+//!
+//! Each frame is converted to NV12 in an MPP buffer and handed to the
+//! encoder together with its own output packet; the packet is read back
+//! before the call returns, so encoding is synchronous like the software
+//! path. JPEG gets full-range BT.601 (as JFIF expects), H.264 limited-range
+//! BT.709 (what browsers assume for untagged HD video). Settings such as
+//! the JPEG quality factor are part of the encoder config and only re-sent
+//! when they change.
+
+use crate::capture::FrameEncoder;
+use anyhow::{Context, Result};
+use image::DynamicImage;
+use std::ffi::{c_char, c_int, c_void};
+use std::ptr;
+
+type MppRet = c_int;
+type MppCtx = *mut c_void;
+type MppEncCfg = *mut c_void;
+type MppBufferGroup = *mut c_void;
+type MppBuffer = *mut c_void;
+type MppFrame = *mut c_void;
+type MppPacket = *mut c_void;
+type MppMeta = *mut c_void;
+
+const MPP_OK: MppRet = 0;
+const MPP_CTX_ENC: c_int = 1;
+const MPP_VIDEO_CODING_AVC: c_int = 7;
+const MPP_VIDEO_CODING_MJPEG: c_int = 8;
+const MPP_FMT_YUV420SP: c_int = 0;
+const MPP_ENC_RC_MODE_CBR: i32 = 1;
+const MPP_ENC_RC_MODE_FIXQP: i32 = 2;
+const MPP_BUFFER_INTERNAL: c_int = 0;
+const MPP_BUFFER_TYPE_DRM: c_int = 3;
+// MpiCmd: MPP_ENC_CMD_BASE (0x0032_0000) + position in the encoder list
+const MPP_ENC_SET_CFG: c_int = 0x0032_0001;
+const MPP_ENC_GET_CFG: c_int = 0x0032_0002;
+const MPP_ENC_SET_IDR_FRAME: c_int = 0x0032_0009;
+const MPP_ENC_GET_HDR_SYNC: c_int = 0x0032_000d;
+/// `KEY_OUTPUT_PACKET`: FOURCC_META('o', 'p', 'k', 't')
+const KEY_OUTPUT_PACKET: c_int = i32::from_be_bytes(*b"opkt");
+/// Tag and caller names MPP records with each buffer
+const TAG: &[u8] = b"imx415_streamer\0";
+
+/// `MppApi`: entry points of an MPP context
+#[repr(C)]
+struct MppApi {
+    size: u32,
+    version: u32,
+    decode: *const c_void,
+    decode_put_packet: *const c_void,
+    decode_get_frame: *const c_void,
+    encode: *const c_void,
+    encode_put_frame: Option<unsafe extern "C" fn(MppCtx, MppFrame) -> MppRet>,
+    encode_get_packet: Option<unsafe extern "C" fn(MppCtx, *mut MppPacket) -> MppRet>,
+    isp: *const c_void,
+    isp_put_frame: *const c_void,
+    isp_get_frame: *const c_void,
+    poll: *const c_void,
+    dequeue: *const c_void,
+    enqueue: *const c_void,
+    reset: *const c_void,
+    control: Option<unsafe extern "C" fn(MppCtx, c_int, *mut c_void) -> MppRet>,
+    reserv: [u32; 16],
+}
+
+#[link(name = "rockchip_mpp")]
+extern "C" {
+    fn mpp_create(ctx: *mut MppCtx, mpi: *mut *mut MppApi) -> MppRet;
+    fn mpp_init(ctx: MppCtx, kind: c_int, coding: c_int) -> MppRet;
+    fn mpp_destroy(ctx: MppCtx) -> MppRet;
+    fn mpp_enc_cfg_init(cfg: *mut MppEncCfg) -> MppRet;
+    fn mpp_enc_cfg_deinit(cfg: MppEncCfg) -> MppRet;
+    fn mpp_enc_cfg_set_s32(cfg: MppEncCfg, name: *const c_char, value: i32) -> MppRet;
+    fn mpp_buffer_group_get(
+        group: *mut MppBufferGroup,
+        kind: c_int,
+        mode: c_int,
+        tag: *const c_char,
+        caller: *const c_char,
+    ) -> MppRet;
+    fn mpp_buffer_group_put(group: MppBufferGroup) -> MppRet;
+    fn mpp_buffer_get_with_tag(
+        group: MppBufferGroup,
+        buffer: *mut MppBuffer,
+        size: usize,
+        tag: *const c_char,
+        caller: *const c_char,
+    ) -> MppRet;
+    fn mpp_buffer_put_with_caller(buffer: MppBuffer, caller: *const c_char) -> MppRet;
+    fn mpp_buffer_get_ptr_with_caller(buffer: MppBuffer, caller: *const c_char) -> *mut c_void;
+    fn mpp_frame_init(frame: *mut MppFrame) -> MppRet;
+    fn mpp_frame_deinit(frame: *mut MppFrame) -> MppRet;
+    fn mpp_frame_set_width(frame: MppFrame, width: u32);
+    fn mpp_frame_set_height(frame: MppFrame, height: u32);
+    fn mpp_frame_set_hor_stride(frame: MppFrame, stride: u32);
+    fn mpp_frame_set_ver_stride(frame: MppFrame, stride: u32);
+    fn mpp_frame_set_fmt(frame: MppFrame, format: c_int);
+    fn mpp_frame_set_eos(frame: MppFrame, eos: u32);
+    fn mpp_frame_set_buffer(frame: MppFrame, buffer: MppBuffer);
+    fn mpp_frame_get_meta(frame: MppFrame) -> MppMeta;
+    fn mpp_meta_set_packet(meta: MppMeta, key: c_int, packet: MppPacket) -> MppRet;
+    fn mpp_packet_init_with_buffer(packet: *mut MppPacket, buffer: MppBuffer) -> MppRet;
+    fn mpp_packet_deinit(packet: *mut MppPacket) -> MppRet;
+    fn mpp_packet_set_length(packet: MppPacket, length: usize);
+    fn mpp_packet_get_pos(packet: MppPacket) -> *mut c_void;
+    fn mpp_packet_get_length(packet: MppPacket) -> usize;
+}
+
+fn check(ret: MppRet, what: &str) -> Result<()> {
+    if ret != MPP_OK {
+        anyhow::bail!("{} failed ({})", what, ret);
+    }
+    Ok(())
+}
+
+/// An encoder context with one NV12 input buffer and one output buffer
+struct Session {
+    ctx: MppCtx,
+    mpi: *mut MppApi,
+    cfg: MppEncCfg,
+    group: MppBufferGroup,
+    frame_buffer: MppBuffer,
+    packet_buffer: MppBuffer,
+    width: usize,
+    height: usize,
+    /// Rows allocated per plane (`height` rounded up as the codec needs)
+    ver_stride: usize,
+}
+
+// SAFETY: the MPP handles aren't tied to the thread that created them,
+// and every call into MPP takes `&mut self`, so a shared `&Session` can't
+// reach the library from two threads at once (encoders are `Sync` as
+// `FrameEncoder` requires, and used behind a lock)
+unsafe impl Send for Session {}
+unsafe impl Sync for Session {}
+
+impl Session {
+    /// Open an encoder for `coding` and `width` x `height` frames (width a
+    /// multiple of 16, height even) with planes of `ver_stride` rows and
+    /// the codec `settings` on top of the input format
+    fn open(
+        coding: c_int,
+        width: usize,
+        height: usize,
+        ver_stride: usize,
+        settings: &[(&[u8], i32)],
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            width.is_multiple_of(16) && height.is_multiple_of(2),
+            "Unsupported frame size {}x{}",
+            width,
+            height
+        );
+        let mut session = Self {
+            ctx: ptr::null_mut(),
+            mpi: ptr::null_mut(),
+            cfg: ptr::null_mut(),
+            group: ptr::null_mut(),
+            frame_buffer: ptr::null_mut(),
+            packet_buffer: ptr::null_mut(),
+            width,
+            height,
+            ver_stride,
+        };
+        // Whatever was set up is released by Drop if a step fails
+        // SAFETY: each handle is created before it is used, the names are
+        // NUL-terminated literals and the buffers are sized for NV12 at
+        // `ver_stride` rows
+        unsafe {
+            let tag = TAG.as_ptr() as *const c_char;
+            check(mpp_create(&mut session.ctx, &mut session.mpi), "mpp_create")?;
+            check(mpp_init(session.ctx, MPP_CTX_ENC, coding), "mpp_init")?;
+            check(
+                mpp_buffer_group_get(&mut session.group, MPP_BUFFER_TYPE_DRM, MPP_BUFFER_INTERNAL, tag, tag),
+                "mpp_buffer_group_get",
+            )?;
+            let frame_size = width * ver_stride * 3 / 2;
+            for buffer in [&mut session.frame_buffer, &mut session.packet_buffer] {
+                check(mpp_buffer_get_with_tag(session.group, buffer, frame_size, tag, tag), "mpp_buffer_get")?;
+            }
+            check(mpp_enc_cfg_init(&mut session.cfg), "mpp_enc_cfg_init")?;
+            session.command(MPP_ENC_GET_CFG, session.cfg)?;
+            let input = [
+                (&b"codec:type\0"[..], coding),
+                (b"prep:width\0", width as i32),
+                (b"prep:height\0", height as i32),
+                (b"prep:hor_stride\0", width as i32),
+                (b"prep:ver_stride\0", ver_stride as i32),
+                (b"prep:format\0", MPP_FMT_YUV420SP),
+            ];
+            for &(name, value) in input.iter().chain(settings) {
+                session.set_cfg(name, value)?;
+            }
+            session.command(MPP_ENC_SET_CFG, session.cfg)?;
+        }
+        Ok(session)
+    }
+
+    /// # Safety
+    /// `name` must be NUL-terminated and the config initialized
+    unsafe fn set_cfg(&mut self, name: &[u8], value: i32) -> Result<()> {
+        check(mpp_enc_cfg_set_s32(self.cfg, name.as_ptr() as *const c_char, value), "mpp_enc_cfg_set_s32")
+            .with_context(|| String::from_utf8_lossy(&name[..name.len() - 1]).into_owned())
+    }
+
+    /// # Safety
+    /// The context must be initialized and `param` what `cmd` expects
+    unsafe fn command(&mut self, cmd: c_int, param: *mut c_void) -> Result<()> {
+        let control = (*self.mpi).control.context("MPP has no control entry point")?;
+        check(control(self.ctx, cmd, param), "MPP encoder control")
+    }
+
+    /// Change config entries of the open encoder
+    fn update_cfg(&mut self, settings: &[(&[u8], i32)]) -> Result<()> {
+        // SAFETY: the session is open, so its config is initialized, and the
+        // callers' names are NUL-terminated literals
+        unsafe {
+            for &(name, value) in settings {
+                self.set_cfg(name, value)?;
+            }
+            self.command(MPP_ENC_SET_CFG, self.cfg)
+        }
+    }
+
+    /// Convert `image` into the input buffer
+    fn load_frame(&mut self, image: &DynamicImage, matrix: &YuvMatrix) -> Result<()> {
+        anyhow::ensure!(
+            (image.width() as usize, image.height() as usize) == (self.width, self.height),
+            "Frame is {}x{}, the encoder is set up for {}x{}",
+            image.width(),
+            image.height(),
+            self.width,
+            self.height
+        );
+        let size = self.width * self.ver_stride * 3 / 2;
+        // SAFETY: the frame buffer was allocated with `size` bytes in `open`
+        // and `&mut self` keeps the mapping exclusive while the slice lives
+        let nv12 = unsafe {
+            let data = mpp_buffer_get_ptr_with_caller(self.frame_buffer, TAG.as_ptr() as *const c_char) as *mut u8;
+            anyhow::ensure!(!data.is_null(), "MPP frame buffer has no mapping");
+            std::slice::from_raw_parts_mut(data, size)
+        };
+        let (luma, chroma) = nv12.split_at_mut(self.width * self.ver_stride);
+        match image {
+            DynamicImage::ImageLuma8(gray) => gray_to_nv12(gray.as_raw(), luma, chroma, matrix),
+            DynamicImage::ImageRgb8(rgb) => rgb_to_nv12(rgb.as_raw(), self.width, self.height, luma, chroma, matrix),
+            other => rgb_to_nv12(other.to_rgb8().as_raw(), self.width, self.height, luma, chroma, matrix),
+        }
+        Ok(())
+    }
+
+    /// Run `fill` on a packet over the output buffer and copy what it
+    /// wrote into `out`
+    fn with_packet(
+        &mut self,
+        out: &mut Vec<u8>,
+        fill: impl FnOnce(&mut Self, MppPacket) -> Result<MppPacket>,
+    ) -> Result<()> {
+        // SAFETY: the packet wraps the session's output buffer and is
+        // released here; a packet MPP hands back is read for the length it
+        // reports, then released unless it is the same packet
+        unsafe {
+            let mut packet: MppPacket = ptr::null_mut();
+            check(mpp_packet_init_with_buffer(&mut packet, self.packet_buffer), "mpp_packet_init")?;
+            // The length must start at zero for the encoder to fill the packet
+            mpp_packet_set_length(packet, 0);
+            let result = fill(self, packet).and_then(|mut filled| {
+                let copied = (|| {
+                    anyhow::ensure!(!filled.is_null(), "MPP returned no packet");
+                    let (pos, length) = (mpp_packet_get_pos(filled) as *const u8, mpp_packet_get_length(filled));
+                    anyhow::ensure!(!pos.is_null() && length > 0, "MPP returned an empty packet");
+                    out.clear();
+                    out.extend_from_slice(std::slice::from_raw_parts(pos, length));
+                    Ok(())
+                })();
+                if !filled.is_null() && filled != packet {
+                    mpp_packet_deinit(&mut filled);
+                }
+                copied
+            });
+            mpp_packet_deinit(&mut packet);
+            result
+        }
+    }
+
+    /// Encode the frame in the input buffer into `out`
+    fn encode(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        // SAFETY: the frame only borrows the input buffer and is released
+        // once queued; `packet` is valid for the duration of the callback
+        self.with_packet(out, |session, packet| unsafe {
+            let (put_frame, get_packet) = (
+                (*session.mpi).encode_put_frame.context("MPP has no encode_put_frame")?,
+                (*session.mpi).encode_get_packet.context("MPP has no encode_get_packet")?,
+            );
+            let mut frame: MppFrame = ptr::null_mut();
+            check(mpp_frame_init(&mut frame), "mpp_frame_init")?;
+            mpp_frame_set_width(frame, session.width as u32);
+            mpp_frame_set_height(frame, session.height as u32);
+            mpp_frame_set_hor_stride(frame, session.width as u32);
+            mpp_frame_set_ver_stride(frame, session.ver_stride as u32);
+            mpp_frame_set_fmt(frame, MPP_FMT_YUV420SP);
+            mpp_frame_set_eos(frame, 0);
+            mpp_frame_set_buffer(frame, session.frame_buffer);
+            let queued = check(
+                mpp_meta_set_packet(mpp_frame_get_meta(frame), KEY_OUTPUT_PACKET, packet),
+                "mpp_meta_set_packet",
+            )
+            .and_then(|()| check(put_frame(session.ctx, frame), "encode_put_frame"));
+            mpp_frame_deinit(&mut frame);
+            queued?;
+            // Comes back as the packet queued with the frame
+            let mut encoded: MppPacket = ptr::null_mut();
+            check(get_packet(session.ctx, &mut encoded), "encode_get_packet")?;
+            Ok(encoded)
+        })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // SAFETY: only handles that were created are released, each once
+        unsafe {
+            let tag = TAG.as_ptr() as *const c_char;
+            if !self.ctx.is_null() {
+                mpp_destroy(self.ctx);
+            }
+            if !self.cfg.is_null() {
+                mpp_enc_cfg_deinit(self.cfg);
+            }
+            for buffer in [self.frame_buffer, self.packet_buffer] {
+                if !buffer.is_null() {
+                    mpp_buffer_put_with_caller(buffer, tag);
+                }
+            }
+            if !self.group.is_null() {
+                mpp_buffer_group_put(self.group);
+            }
+        }
+    }
+}
+
+/// MJPEG encoder for one frame size
+pub struct MppEncoder {
+    session: Session,
+    quality: u8,
+}
+
+impl MppEncoder {
+    const INITIAL_QUALITY: u8 = 90;
+
+    pub fn new(width: usize, height: usize) -> Result<Self> {
+        let session = Session::open(
+            MPP_VIDEO_CODING_MJPEG,
+            width,
+            height,
+            height,
+            &[
+                (b"rc:mode\0", MPP_ENC_RC_MODE_FIXQP),
+                (b"jpeg:q_factor\0", Self::INITIAL_QUALITY as i32),
+                (b"jpeg:qf_min\0", 1),
+                (b"jpeg:qf_max\0", 99),
+            ],
+        )?;
+        tracing::info!("MPP JPEG encoder ready ({}x{})", width, height);
+        Ok(Self { session, quality: Self::INITIAL_QUALITY })
+    }
+}
+
+impl FrameEncoder for MppEncoder {
+    fn name(&self) -> &'static str {
+        "mpp"
+    }
+
+    fn encode(&mut self, image: &DynamicImage, quality: u8, out: &mut Vec<u8>) -> Result<()> {
+        let quality = quality.clamp(1, 99);
+        if quality != self.quality {
+            self.session.update_cfg(&[(b"jpeg:q_factor\0", quality as i32)])?;
+            self.quality = quality;
+        }
+        self.session.load_frame(image, &YuvMatrix::JPEG)?;
+        self.session.encode(out)
+    }
+}
+
+/// H.264 encoder (constant bitrate, High profile) for one frame size
+pub struct H264Encoder {
+    session: Session,
+}
+
+impl H264Encoder {
+    /// `gop` frames between IDR frames
+    pub fn new(width: usize, height: usize, bitrate_bps: u32, fps: u32, gop: u32) -> Result<Self> {
+        let bitrate = bitrate_bps.min(i32::MAX as u32) as i32;
+        let session = Session::open(
+            MPP_VIDEO_CODING_AVC,
+            width,
+            height,
+            // The encoder reads whole 16x16 macroblocks
+            height.next_multiple_of(16),
+            &[
+                (b"rc:mode\0", MPP_ENC_RC_MODE_CBR),
+                (b"rc:bps_target\0", bitrate),
+                (b"rc:bps_max\0", bitrate / 16 * 17),
+                (b"rc:bps_min\0", bitrate / 16 * 15),
+                (b"rc:fps_in_flex\0", 0),
+                (b"rc:fps_in_num\0", fps as i32),
+                (b"rc:fps_in_denorm\0", 1),
+                (b"rc:fps_out_flex\0", 0),
+                (b"rc:fps_out_num\0", fps as i32),
+                (b"rc:fps_out_denorm\0", 1),
+                (b"rc:gop\0", gop as i32),
+                // High profile, level 5.1 (4K at 30 fps)
+                (b"h264:profile\0", 100),
+                (b"h264:level\0", 51),
+                (b"h264:cabac_en\0", 1),
+                (b"h264:cabac_idc\0", 0),
+                (b"h264:trans8x8\0", 1),
+            ],
+        )?;
+        tracing::info!("MPP H.264 encoder ready ({}x{}, {} kbit/s)", width, height, bitrate_bps / 1000);
+        Ok(Self { session })
+    }
+
+    /// SPS and PPS (Annex B)
+    pub fn header(&mut self) -> Result<Vec<u8>> {
+        let mut header = Vec::new();
+        // SAFETY: MPP_ENC_GET_HDR_SYNC takes the packet to write into
+        self.session.with_packet(&mut header, |session, packet| unsafe {
+            session.command(MPP_ENC_GET_HDR_SYNC, packet)?;
+            Ok(packet)
+        })?;
+        Ok(header)
+    }
+
+    /// Make the next frame an IDR frame
+    pub fn request_idr(&mut self) -> Result<()> {
+        // SAFETY: MPP_ENC_SET_IDR_FRAME takes no parameter
+        unsafe { self.session.command(MPP_ENC_SET_IDR_FRAME, ptr::null_mut()) }
+    }
+
+    /// Encode one frame into an access unit (Annex B)
+    pub fn encode(&mut self, image: &DynamicImage, out: &mut Vec<u8>) -> Result<()> {
+        self.session.load_frame(image, &YuvMatrix::BT709_LIMITED)?;
+        self.session.encode(out)
+    }
+}
+
+/// RGB to YCbCr coefficients in 1/256 units; each chroma row sums to zero
+struct YuvMatrix {
+    y: [i32; 3],
+    y_offset: i32,
+    cb: [i32; 3],
+    cr: [i32; 3],
+}
+
+impl YuvMatrix {
+    /// Full-range BT.601 (JFIF)
+    const JPEG: YuvMatrix = YuvMatrix { y: [77, 150, 29], y_offset: 0, cb: [-43, -85, 128], cr: [128, -107, -21] };
+    /// Limited-range BT.709
+    const BT709_LIMITED: YuvMatrix =
+        YuvMatrix { y: [47, 157, 16], y_offset: 16, cb: [-26, -86, 112], cr: [112, -102, -10] };
+}
+
+/// Gray image as NV12: the luma plane scaled to the matrix's range,
+/// neutral chroma
+fn gray_to_nv12(gray: &[u8], luma: &mut [u8], chroma: &mut [u8], matrix: &YuvMatrix) {
+    let luma = &mut luma[..gray.len()];
+    let scale: i32 = matrix.y.iter().sum();
+    if scale == 256 && matrix.y_offset == 0 {
+        luma.copy_from_slice(gray);
+    } else {
+        for (out, &v) in luma.iter_mut().zip(gray) {
+            *out = (matrix.y_offset + ((scale * v as i32 + 128) >> 8)) as u8;
+        }
+    }
+    chroma.fill(128);
+}
+
+/// RGB image as NV12, chroma averaged over each 2x2 block
+fn rgb_to_nv12(rgb: &[u8], width: usize, height: usize, luma: &mut [u8], chroma: &mut [u8], matrix: &YuvMatrix) {
+    let dot = |k: &[i32; 3], r: i32, g: i32, b: i32| k[0] * r + k[1] * g + k[2] * b;
+    for (out, px) in luma.iter_mut().zip(rgb.chunks_exact(3)) {
+        let y = (dot(&matrix.y, px[0] as i32, px[1] as i32, px[2] as i32) + 128) >> 8;
+        *out = (matrix.y_offset + y).clamp(0, 255) as u8;
+    }
+    for y in (0..height).step_by(2) {
+        for x in (0..width).step_by(2) {
+            let (mut r, mut g, mut b) = (0, 0, 0);
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let i = ((y + dy) * width + x + dx) * 3;
+                r += rgb[i] as i32;
+                g += rgb[i + 1] as i32;
+                b += rgb[i + 2] as i32;
+            }
+            // Sums of four pixels: shift by 2 more to average (plus rounding)
+            let cb = (dot(&matrix.cb, r, g, b) + (128 << 10) + 512) >> 10;
+            let cr = (dot(&matrix.cr, r, g, b) + (128 << 10) + 512) >> 10;
+            let i = (y / 2) * width + x;
+            chroma[i] = cb.clamp(0, 255) as u8;
+            chroma[i + 1] = cr.clamp(0, 255) as u8;
+        }
+    }
+}
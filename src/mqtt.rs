@@ -0,0 +1,233 @@
+//! Minimal MQTT 3.1.1 client
+//!
+//! Just what the Home Assistant bridge needs: connect (with credentials and
+//! a last will), QoS 0 publish with the retain flag, QoS 0 subscribe and
+//! keep-alive pings. There is no session state and no QoS 1/2, so a
+//! reconnect starts over with a clean session and the caller republishes
+//! whatever it keeps retained.
+//!
+//! The connection is split: [`Connection::connect`] returns the writer and
+//! spawns a reader task that forwards decoded packets on a channel, which
+//! keeps `recv` cancel-safe inside `select!`.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Broker port when the address has none
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// How long the broker gets to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest packet accepted from the broker (we only subscribe to commands)
+const MAX_INCOMING_PACKET: usize = 64 * 1024;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+/// A message published on connection loss, as the broker will send it
+#[derive(Debug, Clone)]
+pub struct LastWill {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttOptions {
+    /// `host` or `host:port`
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive: Duration,
+    pub last_will: Option<LastWill>,
+}
+
+/// A packet from the broker
+#[derive(Debug)]
+pub enum Incoming {
+    Publish { topic: String, payload: Bytes },
+    /// Acks and ping responses: only show the link is alive
+    Other,
+}
+
+/// Writing half of a connection; incoming packets arrive on `incoming`
+pub struct Connection {
+    writer: OwnedWriteHalf,
+    pub incoming: mpsc::Receiver<Result<Incoming>>,
+    next_packet_id: u16,
+}
+
+impl Connection {
+    /// Connect and wait for the broker to accept the session
+    pub async fn connect(options: &MqttOptions) -> Result<Self> {
+        let address = if options.broker.contains(':') {
+            options.broker.clone()
+        } else {
+            format!("{}:{}", options.broker, DEFAULT_PORT)
+        };
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&address))
+            .await
+            .with_context(|| format!("Timed out connecting to {}", address))?
+            .with_context(|| format!("Failed to connect to {}", address))?;
+        stream.set_nodelay(true)?;
+        let (mut reader, mut writer) = stream.into_split();
+
+        writer.write_all(&encode_connect(options)).await?;
+        let (header, body) = tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut reader))
+            .await
+            .context("Timed out waiting for CONNACK")??;
+        anyhow::ensure!(header & 0xf0 == CONNACK && body.len() == 2, "Expected CONNACK, got packet {:#04x}", header);
+        match body[1] {
+            0 => {}
+            4 => anyhow::bail!("Broker refused the connection: bad user name or password"),
+            5 => anyhow::bail!("Broker refused the connection: not authorized"),
+            code => anyhow::bail!("Broker refused the connection (code {})", code),
+        }
+
+        let (tx, incoming) = mpsc::channel(32);
+        tokio::spawn(read_loop(reader, tx));
+        Ok(Self { writer, incoming, next_packet_id: 1 })
+    }
+
+    pub async fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        put_str(&mut body, topic);
+        body.extend_from_slice(payload);
+        self.write(PUBLISH | retain as u8, &body).await
+    }
+
+    /// Subscribe to `topics` at QoS 0 (the SUBACK arrives on `incoming`)
+    pub async fn subscribe(&mut self, topics: &[&str]) -> Result<()> {
+        let mut body = self.next_packet_id.to_be_bytes().to_vec();
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        for topic in topics {
+            put_str(&mut body, topic);
+            body.push(0);
+        }
+        self.write(SUBSCRIBE, &body).await
+    }
+
+    pub async fn ping(&mut self) -> Result<()> {
+        self.write(PINGREQ, &[]).await
+    }
+
+    /// Close cleanly (the broker then does not send the last will)
+    pub async fn disconnect(mut self) -> Result<()> {
+        self.write(DISCONNECT, &[]).await?;
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+
+    async fn write(&mut self, header: u8, body: &[u8]) -> Result<()> {
+        let mut packet = vec![header];
+        put_remaining_length(&mut packet, body.len());
+        packet.extend_from_slice(body);
+        self.writer.write_all(&packet).await.context("MQTT write failed")
+    }
+}
+
+fn encode_connect(options: &MqttOptions) -> Vec<u8> {
+    // Clean session
+    let mut flags = 0x02;
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    body.push(4);
+    if let Some(ref will) = options.last_will {
+        flags |= 0x04 | if will.retain { 0x20 } else { 0 };
+    }
+    if options.password.is_some() {
+        flags |= 0x40;
+    }
+    if options.username.is_some() {
+        flags |= 0x80;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(options.keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes());
+    put_str(&mut body, &options.client_id);
+    if let Some(ref will) = options.last_will {
+        put_str(&mut body, &will.topic);
+        put_str(&mut body, &will.payload);
+    }
+    if let Some(ref username) = options.username {
+        put_str(&mut body, username);
+    }
+    if let Some(ref password) = options.password {
+        put_str(&mut body, password);
+    }
+    let mut packet = vec![CONNECT];
+    put_remaining_length(&mut packet, body.len());
+    packet.extend_from_slice(&body);
+    packet
+}
+
+/// UTF-8 string with its u16 length
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Variable-length "remaining length": 7 bits per byte, low first
+fn put_remaining_length(buf: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// Read one packet: its first header byte and its body
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u8, Vec<u8>)> {
+    let header = reader.read_u8().await.context("Broker closed the connection")?;
+    let mut len = 0usize;
+    for shift in 0..4 {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << (7 * shift);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        anyhow::ensure!(shift < 3, "Malformed packet length");
+    }
+    anyhow::ensure!(len <= MAX_INCOMING_PACKET, "Incoming packet too large ({} bytes)", len);
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Ok((header, body))
+}
+
+fn decode(header: u8, body: Vec<u8>) -> Result<Incoming> {
+    if header & 0xf0 != PUBLISH {
+        return Ok(Incoming::Other);
+    }
+    anyhow::ensure!(body.len() >= 2, "Truncated PUBLISH");
+    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    // QoS 1/2 messages carry a packet id after the topic
+    let id_len = if header & 0x06 != 0 { 2 } else { 0 };
+    anyhow::ensure!(body.len() >= 2 + topic_len + id_len, "Truncated PUBLISH");
+    let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).context("PUBLISH topic is not UTF-8")?;
+    let payload = Bytes::from(body).slice(2 + topic_len + id_len..);
+    Ok(Incoming::Publish { topic, payload })
+}
+
+async fn read_loop(mut reader: OwnedReadHalf, tx: mpsc::Sender<Result<Incoming>>) {
+    loop {
+        let packet = read_packet(&mut reader).await.and_then(|(header, body)| decode(header, body));
+        let failed = packet.is_err();
+        if tx.send(packet).await.is_err() || failed {
+            return;
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! NEON paths for the color pipeline's hot loops (aarch64 only)
+//!
+//! NEON is part of the aarch64 baseline, so there is no runtime detection;
+//! other targets only have the scalar loops. Each function covers what its
+//! vectors can reach and reports it, leaving the rest of the row (frame
+//! edges, a short tail) to the scalar code, which it matches bit for bit.
+
+use std::arch::aarch64::*;
+use std::ops::Range;
+
+/// RAW10 lines to 10-bit samples (see `rawformat::unpack_packed10`), 16
+/// pixels from 20 bytes per step; returns the pixels written
+pub fn unpack_packed10(line: &[u8], out: &mut [u16]) -> usize {
+    // SAFETY: NEON is always available on aarch64, and the loop keeps
+    // every load within `line` and every store within `out`
+    unsafe { unpack_packed10_neon(line, out) }
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn unpack_packed10_neon(line: &[u8], out: &mut [u16]) -> usize {
+    // Within four 5-byte groups: each pixel's MSB byte, its group's LSB
+    // byte, and where its two bits sit in that byte
+    const MSB: [u8; 16] = [0, 1, 2, 3, 5, 6, 7, 8, 10, 11, 12, 13, 15, 16, 17, 18];
+    const LSB: [u8; 16] = [4, 4, 4, 4, 9, 9, 9, 9, 14, 14, 14, 14, 19, 19, 19, 19];
+    const LSB_SHIFT: [i8; 16] = [0, -2, -4, -6, 0, -2, -4, -6, 0, -2, -4, -6, 0, -2, -4, -6];
+    let msb_index = vld1q_u8(MSB.as_ptr());
+    let lsb_index = vld1q_u8(LSB.as_ptr());
+    let lsb_shift = vld1q_s8(LSB_SHIFT.as_ptr());
+    let low_bits = vdupq_n_u8(0x3);
+
+    let mut pixels = 0;
+    // The table is 32 bytes, 12 past the groups in use
+    while pixels * 5 / 4 + 32 <= line.len() && pixels + 16 <= out.len() {
+        let table = vld1q_u8_x2(line.as_ptr().add(pixels * 5 / 4));
+        let msb = vqtbl2q_u8(table, msb_index);
+        let lsb = vandq_u8(vshlq_u8(vqtbl2q_u8(table, lsb_index), lsb_shift), low_bits);
+        let first = vorrq_u16(vshll_n_u8::<2>(vget_low_u8(msb)), vmovl_u8(vget_low_u8(lsb)));
+        let second = vorrq_u16(vshll_high_n_u8::<2>(msb), vmovl_high_u8(lsb));
+        let dst = out.as_mut_ptr().add(pixels);
+        vst1q_u16(dst, first);
+        vst1q_u16(dst.add(8), second);
+        pixels += 16;
+    }
+    pixels
+}
+
+/// Bilinear demosaic of row `y` of a `width` x `height` 10-bit Bayer frame
+/// into 8-bit RGB (see `demosaic::bilinear_row`), 8 pixels per step;
+/// returns the columns written, which leave out the first and last 8
+pub fn demosaic_row(
+    bayer: &[u16],
+    width: usize,
+    height: usize,
+    y: usize,
+    (dy, dx): (usize, usize),
+    out: &mut [u8],
+) -> Range<usize> {
+    if width < 17 || bayer.len() < width * height || out.len() < width * 3 || y >= height {
+        return 0..0;
+    }
+    let row = |y: usize| &bayer[y * width..(y + 1) * width];
+    let rows = [row(y.saturating_sub(1)), row(y), row((y + 1).min(height - 1))];
+    // SAFETY: NEON is always available on aarch64; the rows are `width`
+    // long and the loop reads at most one column past each 8-pixel block,
+    // never past the row
+    unsafe { demosaic_row_neon(rows, (y + dy) & 1 == 0, dx & 1, out) }
+}
+
+#[target_feature(enable = "neon")]
+unsafe fn demosaic_row_neon([up, row, down]: [&[u16]; 3], gb_row: bool, dx: usize, out: &mut [u8]) -> Range<usize> {
+    // Blocks start on even columns, so lane parity is column parity
+    let mut even = [0u16; 8];
+    for (i, lane) in even.iter_mut().enumerate() {
+        if (i + dx) & 1 == 0 {
+            *lane = u16::MAX;
+        }
+    }
+    let even = vld1q_u16(even.as_ptr());
+
+    let width = row.len();
+    let mut x = 8;
+    while x + 9 <= width {
+        let (u, c, d) = (up.as_ptr().add(x), row.as_ptr().add(x), down.as_ptr().add(x));
+        let center = vld1q_u16(c);
+        let (left, right) = (vld1q_u16(c.sub(1)), vld1q_u16(c.add(1)));
+        let (above, below) = (vld1q_u16(u), vld1q_u16(d));
+        let horizontal = vhaddq_u16(left, right);
+        let vertical = vhaddq_u16(above, below);
+        let cross = vshrq_n_u16::<2>(vaddq_u16(vaddq_u16(left, right), vaddq_u16(above, below)));
+        let diagonal = vshrq_n_u16::<2>(vaddq_u16(
+            vaddq_u16(vld1q_u16(u.sub(1)), vld1q_u16(u.add(1))),
+            vaddq_u16(vld1q_u16(d.sub(1)), vld1q_u16(d.add(1))),
+        ));
+        let (r, g, b) = if gb_row {
+            // G, B, G, B...
+            (
+                vbslq_u16(even, vertical, diagonal),
+                vbslq_u16(even, center, cross),
+                vbslq_u16(even, horizontal, center),
+            )
+        } else {
+            // R, G, R, G...
+            (
+                vbslq_u16(even, center, horizontal),
+                vbslq_u16(even, cross, center),
+                vbslq_u16(even, diagonal, vertical),
+            )
+        };
+        let rgb = uint8x8x3_t(to_8bit(r), to_8bit(g), to_8bit(b));
+        vst3_u8(out.as_mut_ptr().add(x * 3), rgb);
+        x += 8;
+    }
+    8..x
+}
+
+/// Clamp to 10 bits and keep the top 8
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn to_8bit(v: uint16x8_t) -> uint8x8_t {
+    vshrn_n_u16::<2>(vminq_u16(v, vdupq_n_u16(1023)))
+}
@@ -0,0 +1,74 @@
+//! ONNX/CPU detector backend
+//!
+//! Runs a YOLOv8-style (objects) or SCRFD (faces) ONNX model in-process
+//! with tract. Slow compared to the NPU, but works on deployments without
+//! the RKNN runtime.
+
+use crate::detector::{Backend, DetectionResult, DetectionTask, FrameInput};
+use crate::{scrfd, yolo};
+use anyhow::{Context, Result};
+use tract_onnx::prelude::*;
+
+pub struct OnnxBackend {
+    model: std::sync::Arc<TypedRunnableModel>,
+    input_size: u32,
+    task: DetectionTask,
+}
+
+impl OnnxBackend {
+    /// Load and optimize a model for a fixed `input_size` x `input_size` input
+    pub fn load(path: &str, input_size: u32, task: DetectionTask) -> Result<Self> {
+        tracing::info!("Loading ONNX {} model {} ({}x{})", task.name(), path, input_size, input_size);
+        let size = input_size as usize;
+        let model = tract_onnx::onnx()
+            .model_for_path(path)
+            .with_context(|| format!("Failed to load ONNX model {}", path))?
+            .with_input_fact(0, f32::fact([1, 3, size, size]).into())?
+            .into_optimized()?
+            .into_runnable()?;
+        Ok(Self { model, input_size, task })
+    }
+}
+
+impl Backend for OnnxBackend {
+    fn infer(&mut self, frame: &FrameInput) -> Result<DetectionResult> {
+        let image = frame.image()?;
+        let (data, mapping) = match self.task {
+            DetectionTask::Objects => yolo::letterbox(&image, self.input_size),
+            DetectionTask::Faces => scrfd::prepare(&image, self.input_size),
+        };
+
+        let size = self.input_size as usize;
+        let input = Tensor::from_shape(&[1, 3, size, size], &data)?;
+        let outputs = self.model.run(tvec!(input.into()))?;
+
+        let detections = match self.task {
+            DetectionTask::Objects => {
+                let output = outputs[0].to_plain_array_view::<f32>()?;
+                // [1, 4 + classes, boxes]
+                let shape = output.shape();
+                anyhow::ensure!(shape.len() == 3, "Unexpected model output shape {:?}", shape);
+                let num_boxes = shape[2];
+                let values: Vec<f32> = output.iter().copied().collect();
+                yolo::decode_yolov8(&values, num_boxes, &mapping)
+            }
+            DetectionTask::Faces => {
+                // Nine [anchors, 1 | 4 | 10] tensors (a leading batch axis is fine)
+                let mut tensors = Vec::with_capacity(outputs.len());
+                for output in outputs.iter() {
+                    let view = output.to_plain_array_view::<f32>()?;
+                    let width = view.shape().last().copied().unwrap_or(1);
+                    tensors.push((width, view.iter().copied().collect()));
+                }
+                scrfd::decode(&tensors, self.input_size, &mapping)?
+            }
+        };
+
+        Ok(DetectionResult {
+            width: Some(mapping.src_width),
+            height: Some(mapping.src_height),
+            detections,
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,156 @@
+//! Frame orientation
+//!
+//! A camera mounted upside down or on its side gets its frames turned
+//! with an [`Orientation`]: mirror flips, then a clockwise rotation. A half
+//! turn is the same as both flips, so every orientation comes down to
+//! flips plus at most a quarter turn. The flips go to the sensor's
+//! `horizontal_flip` and `vertical_flip` controls when it has them, which
+//! costs nothing per frame; what the sensor can't do (a quarter turn, or a
+//! flip without a control) is done on the processed image before encoding.
+//!
+//! Flipping the readout moves the first pixel to another site of the color
+//! filter array on many sensors, so the Bayer order is read back from the
+//! driver after a sensor flip (`capture.bayer_order` overrides it for
+//! drivers that don't update it).
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+/// Rotations accepted, in degrees clockwise
+pub const ROTATIONS: [u16; 4] = [0, 90, 180, 270];
+
+/// Mirror flips of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct Flips {
+    /// Left and right swapped
+    pub horizontal: bool,
+    /// Top and bottom swapped
+    pub vertical: bool,
+}
+
+/// How frames are turned (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct Orientation {
+    /// Degrees clockwise, applied after the flips: 0, 90, 180 or 270
+    pub rotation: u16,
+    pub hflip: bool,
+    pub vflip: bool,
+}
+
+impl Orientation {
+    /// Error message if the rotation isn't a multiple of 90 degrees
+    pub fn validate(&self) -> Result<(), String> {
+        if !ROTATIONS.contains(&self.rotation) {
+            return Err(format!("Rotation must be one of 0, 90, 180, 270 (got {})", self.rotation));
+        }
+        Ok(())
+    }
+
+    /// The flips that, followed by a quarter turn when `quarter_turn` is
+    /// true, give this orientation
+    pub fn flips(&self) -> Flips {
+        let half_turn = self.rotation >= 180;
+        Flips { horizontal: self.hflip != half_turn, vertical: self.vflip != half_turn }
+    }
+
+    /// Whether a 90 degree turn remains after the flips (width and height
+    /// swap)
+    pub fn quarter_turn(&self) -> bool {
+        self.rotation % 180 == 90
+    }
+
+    /// The flips left for software when the sensor did `sensor`
+    fn remaining(&self, sensor: Flips) -> Flips {
+        let flips = self.flips();
+        Flips { horizontal: flips.horizontal != sensor.horizontal, vertical: flips.vertical != sensor.vertical }
+    }
+
+    /// Whether frames the sensor flipped by `sensor` need work in software
+    pub fn needs_software(&self, sensor: Flips) -> bool {
+        self.remaining(sensor) != Flips::default() || self.quarter_turn()
+    }
+
+    /// Turn a processed frame whose readout the sensor flipped by `sensor`
+    pub fn apply(&self, image: DynamicImage, sensor: Flips) -> DynamicImage {
+        let image = match self.remaining(sensor) {
+            Flips { horizontal: true, vertical: true } => image.rotate180(),
+            Flips { horizontal: true, vertical: false } => image.fliph(),
+            Flips { horizontal: false, vertical: true } => image.flipv(),
+            Flips { horizontal: false, vertical: false } => image,
+        };
+        if self.quarter_turn() {
+            image.rotate90()
+        } else {
+            image
+        }
+    }
+
+    /// Where pixel `(x, y)` of a `width`x`height` frame the sensor flipped
+    /// by `sensor` ends up after `apply`
+    pub fn map_point(&self, sensor: Flips, (x, y): (usize, usize), (width, height): (usize, usize)) -> (usize, usize) {
+        let Flips { horizontal, vertical } = self.remaining(sensor);
+        let x = if horizontal { width - 1 - x } else { x };
+        let y = if vertical { height - 1 - y } else { y };
+        // A clockwise quarter turn puts the left column on the top row
+        if self.quarter_turn() {
+            (height - 1 - y, x)
+        } else {
+            (x, y)
+        }
+    }
+
+    /// TIFF/EXIF Orientation value for raw frames the sensor flipped by
+    /// `sensor`: the part left to software, for a viewer to apply
+    pub fn exif_orientation(&self, sensor: Flips) -> u16 {
+        let Flips { horizontal, vertical } = self.remaining(sensor);
+        match (horizontal, vertical, self.quarter_turn()) {
+            (false, false, false) => 1,
+            (true, false, false) => 2,
+            (true, true, false) => 3,
+            (false, true, false) => 4,
+            // Mirror left-right, then 270 degrees clockwise
+            (false, true, true) => 5,
+            (false, false, true) => 6,
+            (true, false, true) => 7,
+            (true, true, true) => 8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_follow_the_image() {
+        let (width, height) = (6, 4);
+        // Every pixel its own value
+        let image = DynamicImage::ImageLuma16(image::ImageBuffer::from_fn(width, height, |x, y| {
+            image::Luma([(y * width + x) as u16])
+        }));
+        for rotation in ROTATIONS {
+            for (hflip, vflip) in [(false, false), (true, false), (false, true), (true, true)] {
+                for sensor in [Flips::default(), Flips { horizontal: true, vertical: false }] {
+                    let orientation = Orientation { rotation, hflip, vflip };
+                    let turned = orientation.apply(image.clone(), sensor).into_luma16();
+                    for (x, y) in (0..height).flat_map(|y| (0..width).map(move |x| (x, y))) {
+                        let (tx, ty) =
+                            orientation.map_point(sensor, (x as usize, y as usize), (width as usize, height as usize));
+                        assert_eq!(
+                            turned.get_pixel(tx as u32, ty as u32)[0],
+                            (y * width + x) as u16,
+                            "{:?} sensor {:?} at {}, {}",
+                            orientation,
+                            sensor,
+                            x,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
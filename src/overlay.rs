@@ -0,0 +1,139 @@
+//! Text overlay
+//!
+//! Burns a camera name, free-form text and the time into the output frame
+//! just before it is encoded, so the streams, snapshots and recordings made
+//! from the frame all carry them. The time is the local time the frame was
+//! processed. Lines use the 5x7 pixel font of the detection labels, which
+//! draws in lower case and leaves characters it has no glyph for blank.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use image::{DynamicImage, GenericImage, Luma, Rgb};
+use serde::{Deserialize, Serialize};
+
+use crate::detector::draw_text;
+
+/// Font scales accepted (1 = 5x7 pixel glyphs)
+pub const FONT_SCALES: std::ops::RangeInclusive<u32> = 1..=16;
+
+/// Longest camera name or text line
+const MAX_TEXT_CHARS: usize = 128;
+
+/// Frame corner the text block is drawn in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// What is drawn on frames and how (a pipeline setting)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Overlay {
+    /// Draw the time the frame was processed
+    pub timestamp: bool,
+    /// strftime format of the time
+    pub timestamp_format: String,
+    pub camera_name: Option<String>,
+    /// Free-form line under the camera name
+    pub text: Option<String>,
+    pub position: OverlayPosition,
+    /// Multiple of the 5x7 glyph size
+    pub font_scale: u32,
+    /// Text color; grayscale frames use its luma
+    pub color: [u8; 3],
+    /// Black box behind the text, so it stays legible on bright scenes
+    pub background: bool,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Self {
+            timestamp: false,
+            timestamp_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            camera_name: None,
+            text: None,
+            position: OverlayPosition::TopLeft,
+            font_scale: 4,
+            color: [255, 255, 255],
+            background: true,
+        }
+    }
+}
+
+impl Overlay {
+    pub fn validate(&self) -> Result<(), String> {
+        if !FONT_SCALES.contains(&self.font_scale) {
+            return Err(format!("font_scale must be {}-{}", FONT_SCALES.start(), FONT_SCALES.end()));
+        }
+        if StrftimeItems::new(&self.timestamp_format).any(|item| matches!(item, Item::Error)) {
+            return Err(format!("Invalid timestamp_format '{}'", self.timestamp_format));
+        }
+        for (name, line) in [("camera_name", &self.camera_name), ("text", &self.text)] {
+            let Some(line) = line else { continue };
+            if line.chars().count() > MAX_TEXT_CHARS || line.contains(['\n', '\r']) {
+                return Err(format!("{} must be a single line of at most {} characters", name, MAX_TEXT_CHARS));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether there's anything to draw
+    pub fn is_enabled(&self) -> bool {
+        self.timestamp || self.camera_name.is_some() || self.text.is_some()
+    }
+
+    /// The lines to draw at `now`, top to bottom
+    fn lines(&self, now: DateTime<Local>) -> Vec<String> {
+        let mut lines: Vec<String> = [&self.camera_name, &self.text].into_iter().flatten().cloned().collect();
+        if self.timestamp {
+            lines.push(now.format(&self.timestamp_format).to_string());
+        }
+        lines
+    }
+
+    /// Draw the overlay on a processed frame
+    pub fn draw(&self, image: &mut DynamicImage, now: DateTime<Local>) {
+        let lines = self.lines(now);
+        let [r, g, b] = self.color;
+        match image {
+            DynamicImage::ImageRgb8(img) => self.draw_lines(img, &lines, Rgb(self.color), Rgb([0, 0, 0])),
+            DynamicImage::ImageLuma8(img) => {
+                let luma = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).round() as u8;
+                self.draw_lines(img, &lines, Luma([luma]), Luma([0]))
+            }
+            _ => {}
+        }
+    }
+
+    fn draw_lines<I: GenericImage>(&self, img: &mut I, lines: &[String], color: I::Pixel, background: I::Pixel) {
+        let scale = self.font_scale;
+        let (char_width, line_height, margin) = (6 * scale, 10 * scale, 4 * scale);
+        let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) as u32;
+        // Text inset by two glyph pixels on every side
+        let block_width = (columns * char_width + 3 * scale).min(img.width());
+        let block_height = (lines.len() as u32 * line_height + scale).min(img.height());
+        let x = match self.position {
+            OverlayPosition::TopLeft | OverlayPosition::BottomLeft => margin,
+            OverlayPosition::TopRight | OverlayPosition::BottomRight => img.width().saturating_sub(block_width + margin),
+        };
+        let y = match self.position {
+            OverlayPosition::TopLeft | OverlayPosition::TopRight => margin,
+            OverlayPosition::BottomLeft | OverlayPosition::BottomRight => img.height().saturating_sub(block_height + margin),
+        };
+        if self.background {
+            for by in y..(y + block_height).min(img.height()) {
+                for bx in x..(x + block_width).min(img.width()) {
+                    img.put_pixel(bx, by, background);
+                }
+            }
+        }
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(img, line, x + 2 * scale, y + 2 * scale + i as u32 * line_height, color, scale);
+        }
+    }
+}
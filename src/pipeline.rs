@@ -0,0 +1,489 @@
+//! Versioned pipeline settings
+//!
+//! The processing settings (mode, gamma, black level, white balance, ...)
+//! live in one immutable [`SettingsSnapshot`] behind a watch channel.
+//! Endpoints never change the pipeline directly: they publish a modified
+//! copy under the next version number. The capture thread loads the
+//! current snapshot once at the start of each frame and uses only that for
+//! the whole frame, so no frame is built from a mix of old and new
+//! settings, and a change never waits for the frame in progress. Anything
+//! derived from the settings (LUTs, controllers, scene estimates) is
+//! rebuilt by the pipeline when it first sees a new version.
+
+use crate::capture::{self, AdaptiveQuality, AutoExposure, CaptureMode};
+use crate::ccm::ColorCorrection;
+use crate::contrast::{Contrast, ContrastMode};
+use crate::demosaic::DemosaicAlgorithm;
+use crate::denoise::{Denoise, SpatialDenoise, SpatialMethod};
+use crate::greenbalance::GreenBalance;
+use crate::hdr;
+use crate::masks::Mask;
+use crate::metering::MeteringConfig;
+use crate::orientation::Orientation;
+use crate::overlay::Overlay;
+use crate::sharpen::Sharpen;
+use crate::tone::Tone;
+use crate::whitebalance::{self, WbSmoothing, WhiteBalanceMode};
+use crate::zoom::Zoom;
+use std::ops::Deref;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Processing settings applied per frame
+#[derive(Debug, Clone)]
+pub struct PipelineSettings {
+    pub mode: CaptureMode,
+    /// Bayer interpolation in color mode
+    pub demosaic: DemosaicAlgorithm,
+    pub jpeg_quality: u8,
+    pub gamma: f32,
+    pub enable_white_balance: bool,
+    /// How the white balance gains are found
+    pub wb_mode: WhiteBalanceMode,
+    /// Temporal smoothing of the white balance gains
+    pub wb_smoothing: WbSmoothing,
+    /// Color correction matrix after white balance (color mode)
+    pub color_correction: ColorCorrection,
+    /// Gr/Gb green imbalance correction (color mode)
+    pub green_balance: GreenBalance,
+    /// Subtract per-row offset noise from the grayscale output
+    pub row_noise_correction: bool,
+    /// Fraction of the estimated row offset to remove (0.0 - 1.0)
+    pub row_noise_strength: f32,
+    /// Sensor black level in 10-bit units, subtracted after unpacking
+    pub black_level: u16,
+    /// Adaptive JPEG quality limits and targets (None = fixed `jpeg_quality`);
+    /// the controller state lives with the pipeline
+    pub adaptive_quality: Option<AdaptiveQuality>,
+    /// Software auto-exposure target and limits (None = exposure and gain
+    /// left as set); the controller state lives with the pipeline
+    pub auto_exposure: Option<AutoExposure>,
+    /// The part of the frame auto-exposure meters
+    pub metering: MeteringConfig,
+    /// Histogram-equalization tone mapping blend for color mode (0 = plain gamma)
+    pub tonemap_strength: f32,
+    /// Long/short exposure ratio in the HDR modes
+    pub hdr_ratio: f32,
+    /// Histogram equalization of the grayscale output
+    pub contrast: Contrast,
+    /// Temporal noise reduction
+    pub denoise: Denoise,
+    /// Median or bilateral noise reduction after demosaic
+    pub spatial_denoise: SpatialDenoise,
+    /// Unsharp mask on the processed frame
+    pub sharpen: Sharpen,
+    /// Brightness, contrast and saturation of the output
+    pub tone: Tone,
+    /// Flips and rotation of the output (sensor flips where possible)
+    pub orientation: Orientation,
+    /// Privacy masks on the oriented frame (`[[masks]]` in the config file)
+    pub masks: Vec<Mask>,
+    /// Digital zoom window on the oriented frame
+    pub zoom: Zoom,
+    /// Camera name, text and time drawn on the output
+    pub overlay: Overlay,
+}
+
+impl Default for PipelineSettings {
+    fn default() -> Self {
+        Self {
+            mode: CaptureMode::Color,
+            demosaic: DemosaicAlgorithm::default(),
+            jpeg_quality: 90,
+            gamma: 2.2,
+            enable_white_balance: true,
+            wb_mode: WhiteBalanceMode::default(),
+            wb_smoothing: WbSmoothing::default(),
+            color_correction: ColorCorrection::default(),
+            green_balance: GreenBalance::default(),
+            row_noise_correction: false,
+            row_noise_strength: 1.0,
+            black_level: 64,
+            adaptive_quality: None,
+            auto_exposure: None,
+            metering: MeteringConfig::default(),
+            tonemap_strength: 0.0,
+            hdr_ratio: hdr::DEFAULT_RATIO,
+            contrast: Contrast::default(),
+            denoise: Denoise::default(),
+            spatial_denoise: SpatialDenoise::default(),
+            sharpen: Sharpen::default(),
+            tone: Tone::default(),
+            orientation: Orientation::default(),
+            masks: Vec::new(),
+            zoom: Zoom::FULL,
+            overlay: Overlay::default(),
+        }
+    }
+}
+
+impl PipelineSettings {
+    pub fn set_mode(&mut self, mode: CaptureMode) {
+        self.mode = mode;
+        tracing::info!("Mode changed to {:?}", mode);
+    }
+
+    pub fn set_demosaic(&mut self, algorithm: DemosaicAlgorithm) {
+        self.demosaic = algorithm;
+        tracing::info!("Demosaic: {}", algorithm.name());
+    }
+
+    /// Set the HDR modes' long/short exposure ratio
+    pub fn set_hdr_ratio(&mut self, ratio: f32) {
+        self.hdr_ratio = ratio.clamp(hdr::MIN_RATIO, hdr::MAX_RATIO);
+        tracing::info!("Exposure fusion ratio {:.1}", self.hdr_ratio);
+    }
+
+    /// Enable/disable grayscale row-noise correction and set its strength
+    pub fn set_row_noise_correction(&mut self, enabled: bool, strength: f32) {
+        self.row_noise_correction = enabled;
+        self.row_noise_strength = strength.clamp(0.0, 1.0);
+        tracing::info!(
+            "Row-noise correction {} (strength {:.2})",
+            if enabled { "enabled" } else { "disabled" },
+            self.row_noise_strength
+        );
+    }
+
+    /// Set the sensor black level (10-bit units)
+    pub fn set_black_level(&mut self, black_level: u16) {
+        self.black_level = black_level.min(1022);
+        tracing::info!("Black level set to {}", self.black_level);
+    }
+
+    /// Enable adaptive JPEG quality, or return to fixed quality with `None`
+    pub fn set_adaptive_quality(&mut self, adaptive: Option<AdaptiveQuality>) {
+        match &adaptive {
+            Some(a) => tracing::info!(
+                "Adaptive JPEG quality: target {:?} bytes / {:?} ms, quality {}-{}",
+                a.target_bytes, a.target_encode_ms, a.min_quality, a.max_quality
+            ),
+            None => tracing::info!("Fixed JPEG quality {}", self.jpeg_quality),
+        }
+        self.adaptive_quality = adaptive;
+    }
+
+    /// Enable software auto-exposure, or leave exposure and gain as they are with `None`
+    pub fn set_auto_exposure(&mut self, auto_exposure: Option<AutoExposure>) {
+        match &auto_exposure {
+            Some(ae) => tracing::info!(
+                "Auto-exposure: target {:.0}, max gain {:.1} dB, speed {:.2}, anti-flicker {}",
+                ae.target, ae.max_gain_db, ae.speed, ae.anti_flicker.name()
+            ),
+            None => tracing::info!("Auto-exposure off"),
+        }
+        self.auto_exposure = auto_exposure;
+    }
+
+    /// Set the fixed JPEG quality (also the adaptive maximum's default)
+    pub fn set_jpeg_quality(&mut self, quality: u8) {
+        self.jpeg_quality = quality.clamp(1, 100);
+        tracing::info!("JPEG quality {}", self.jpeg_quality);
+    }
+
+    /// Quality for snapshots and stills: the configured maximum, never the adaptive value
+    pub fn snapshot_quality(&self) -> u8 {
+        match &self.adaptive_quality {
+            Some(adaptive) => adaptive.max_quality.max(self.jpeg_quality),
+            None => self.jpeg_quality,
+        }
+    }
+
+    /// Set the grayscale contrast enhancement
+    pub fn set_contrast(&mut self, contrast: Contrast) {
+        self.contrast = contrast;
+        match contrast.mode {
+            ContrastMode::Off => tracing::info!("Contrast enhancement off"),
+            ContrastMode::Global => tracing::info!("Contrast enhancement: global equalization"),
+            ContrastMode::Clahe => tracing::info!(
+                "Contrast enhancement: CLAHE, clip limit {:.1}, {} tiles across",
+                contrast.clip_limit, contrast.tiles
+            ),
+        }
+    }
+
+    /// Set the temporal noise reduction (strength 0 disables)
+    pub fn set_denoise(&mut self, denoise: Denoise) {
+        self.denoise = denoise;
+        if denoise.is_enabled() {
+            tracing::info!("Temporal denoise: strength {:.2}, threshold {}", denoise.strength, denoise.threshold);
+        } else {
+            tracing::info!("Temporal denoise off");
+        }
+    }
+
+    /// Set the spatial noise reduction
+    pub fn set_spatial_denoise(&mut self, spatial: SpatialDenoise) {
+        self.spatial_denoise = spatial;
+        match spatial.method {
+            SpatialMethod::Off => tracing::info!("Spatial denoise off"),
+            method => tracing::info!("Spatial denoise: {:?}, strength {:.2}", method, spatial.strength),
+        }
+    }
+
+    /// Set the unsharp mask (amount 0 disables)
+    pub fn set_sharpen(&mut self, sharpen: Sharpen) {
+        self.sharpen = sharpen;
+        if sharpen.is_enabled() {
+            tracing::info!(
+                "Sharpening: amount {:.2}, radius {}, threshold {}",
+                sharpen.amount, sharpen.radius, sharpen.threshold
+            );
+        } else {
+            tracing::info!("Sharpening off");
+        }
+    }
+
+    /// Set the brightness, contrast and saturation adjustment
+    pub fn set_tone(&mut self, tone: Tone) {
+        self.tone = tone;
+        tracing::info!(
+            "Tone: brightness {:+}, contrast {:.2}, saturation {:.2}",
+            tone.brightness, tone.contrast, tone.saturation
+        );
+    }
+
+    /// Set the color-mode tone mapping strength (0 disables)
+    pub fn set_tonemap_strength(&mut self, strength: f32) {
+        self.tonemap_strength = strength.clamp(0.0, 1.0);
+        tracing::info!("Tone mapping strength {:.2}", self.tonemap_strength);
+    }
+
+    /// Set the output gamma (color mode and the tone-mapping histogram)
+    pub fn set_gamma(&mut self, gamma: f32) {
+        self.gamma = gamma.clamp(capture::MIN_GAMMA, capture::MAX_GAMMA);
+        tracing::info!("Gamma set to {:.2}", self.gamma);
+    }
+
+    /// Enable/disable gray-world white balance in color mode
+    pub fn set_white_balance(&mut self, enabled: bool) {
+        self.enable_white_balance = enabled;
+        tracing::info!("White balance {}", if enabled { "enabled" } else { "disabled" });
+    }
+
+    /// Select the white balance estimate, a preset or manual gains
+    pub fn set_wb_mode(&mut self, mode: WhiteBalanceMode) {
+        self.wb_mode = match mode {
+            WhiteBalanceMode::Manual(gains) => WhiteBalanceMode::Manual(
+                gains.map(|gain| gain.clamp(whitebalance::MIN_MANUAL_GAIN, whitebalance::MAX_MANUAL_GAIN)),
+            ),
+            mode => mode,
+        };
+        match self.wb_mode {
+            WhiteBalanceMode::Manual([r, g, b]) => {
+                tracing::info!("Manual white balance: R {:.3}, G {:.3}, B {:.3}", r, g, b)
+            }
+            mode => tracing::info!("White balance: {}", mode.name()),
+        }
+    }
+
+    /// Set the white balance gain smoothing
+    pub fn set_wb_smoothing(&mut self, smoothing: WbSmoothing) {
+        self.wb_smoothing = smoothing;
+        tracing::info!(
+            "White balance smoothing: time constant {:.2} s, max rate {:.2}/s, scene change {:?}",
+            smoothing.time_constant_s, smoothing.max_rate_per_s, smoothing.scene_change
+        );
+    }
+
+    /// Set the color correction matrix and whether it is applied
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+        if correction.enabled {
+            tracing::info!("Color correction matrix: {:?}", correction.matrix.0);
+        } else {
+            tracing::info!("Color correction off");
+        }
+    }
+
+    /// Set the flips and rotation of the output
+    pub fn set_orientation(&mut self, orientation: Orientation) {
+        self.orientation = orientation;
+        tracing::info!(
+            "Orientation: rotation {}, hflip {}, vflip {}",
+            orientation.rotation, orientation.hflip, orientation.vflip
+        );
+    }
+
+    /// Set the privacy masks
+    pub fn set_masks(&mut self, masks: Vec<Mask>) {
+        tracing::info!("Privacy masks: {}", masks.iter().map(|m| m.name.as_str()).collect::<Vec<_>>().join(", "));
+        self.masks = masks;
+    }
+
+    /// Set the digital zoom window
+    pub fn set_metering(&mut self, metering: MeteringConfig) {
+        if metering.mode != self.metering.mode {
+            tracing::info!("Exposure metering: {:?}", metering.mode);
+        }
+        self.metering = metering;
+    }
+
+    pub fn set_zoom(&mut self, zoom: Zoom) {
+        self.zoom = zoom;
+        tracing::info!(
+            "Zoom: {:.2}x{:.2} at {:.2}, {:.2} ({:.1}x)",
+            zoom.width, zoom.height, zoom.x, zoom.y, zoom.factor()
+        );
+    }
+
+    /// Set the text overlay
+    pub fn set_overlay(&mut self, overlay: Overlay) {
+        tracing::info!(
+            "Overlay: timestamp {}, camera name {:?}, text {:?} ({:?}, scale {})",
+            overlay.timestamp, overlay.camera_name, overlay.text, overlay.position, overlay.font_scale
+        );
+        self.overlay = overlay;
+    }
+
+    /// Set the Gr/Gb green balance correction
+    pub fn set_green_balance(&mut self, green_balance: GreenBalance) {
+        self.green_balance = green_balance;
+        tracing::info!(
+            "Green balance: {:?} (strength {:.2})",
+            green_balance.mode, green_balance.strength
+        );
+    }
+}
+
+/// One published settings version
+#[derive(Debug)]
+pub struct SettingsSnapshot {
+    /// Increasing from 1 per settings cell
+    pub version: u64,
+    pub settings: PipelineSettings,
+}
+
+impl Deref for SettingsSnapshot {
+    type Target = PipelineSettings;
+
+    fn deref(&self) -> &PipelineSettings {
+        &self.settings
+    }
+}
+
+/// The current settings snapshot, shared by the pipeline and the endpoints
+pub struct SettingsCell {
+    tx: watch::Sender<Arc<SettingsSnapshot>>,
+}
+
+impl Default for SettingsCell {
+    fn default() -> Self {
+        Self::new(PipelineSettings::default())
+    }
+}
+
+impl SettingsCell {
+    pub fn new(settings: PipelineSettings) -> Self {
+        Self { tx: watch::Sender::new(Arc::new(SettingsSnapshot { version: 1, settings })) }
+    }
+
+    /// The current snapshot (never changes once loaded)
+    pub fn load(&self) -> Arc<SettingsSnapshot> {
+        self.tx.borrow().clone()
+    }
+
+    /// Publish a copy of the current settings modified by `change` as the
+    /// next version. Concurrent updates are applied one after the other,
+    /// each to the result of the previous one; `change` must not use the
+    /// cell itself.
+    pub fn update(&self, change: impl FnOnce(&mut PipelineSettings)) -> Arc<SettingsSnapshot> {
+        let mut published = None;
+        self.tx.send_modify(|current| {
+            let mut settings = current.settings.clone();
+            change(&mut settings);
+            *current = Arc::new(SettingsSnapshot { version: current.version + 1, settings });
+            published = Some(current.clone());
+        });
+        published.unwrap_or_else(|| self.load())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{CaptureConfig, FrameCapture, FrameSize};
+    use crate::rawformat::RawFormat;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn concurrent_updates_are_applied_in_turn() {
+        let cell = SettingsCell::new(PipelineSettings { black_level: 0, ..Default::default() });
+        let first = cell.load();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..250 {
+                        cell.update(|s| s.black_level += 1);
+                    }
+                });
+            }
+        });
+        let last = cell.load();
+        assert_eq!((last.version, last.black_level), (1001, 1000));
+        // A loaded snapshot never changes
+        assert_eq!((first.version, first.black_level), (1, 0));
+    }
+
+    /// Settings of update `n`: output geometry, mode and the LUT inputs
+    /// all change together, so a frame mixing two versions has the size or
+    /// pixel type of neither
+    fn variant(settings: &mut PipelineSettings, n: u32) {
+        settings.orientation.rotation = if n.is_multiple_of(2) { 0 } else { 90 };
+        settings.zoom = if n.is_multiple_of(3) { Zoom::FULL } else { Zoom::from_factor(2.0, 0.25, 0.75).unwrap() };
+        settings.mode = if n % 5 < 2 { CaptureMode::Grayscale } else { CaptureMode::Color };
+        settings.gamma = 1.8 + (n % 7) as f32 * 0.1;
+        settings.black_level = (n % 11) as u16 * 8;
+    }
+
+    #[test]
+    fn frames_use_one_settings_version_under_rapid_changes() {
+        let size = FrameSize { width: 640, height: 480 };
+        let cell = Arc::new(SettingsCell::default());
+        let mut capture = FrameCapture::with_config(CaptureConfig::default(), cell.clone()).unwrap();
+        let format = RawFormat::from_fourcc("RG10", "10-bit Bayer RGRG/GBGB").unwrap();
+        capture.set_raw_format(format, size, size.width * 2).unwrap();
+        let raw: Vec<u8> = (0..size.width * size.height)
+            .flat_map(|i| ((i * 7 % 1024) as u16).to_le_bytes())
+            .collect();
+
+        let published = Mutex::new(HashMap::from([(1, cell.load())]));
+        let done = AtomicBool::new(false);
+        let frames = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for n in 0.. {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let snapshot = cell.update(|s| variant(s, n));
+                    published.lock().insert(snapshot.version, snapshot);
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            });
+            let mut frames = Vec::new();
+            for _ in 0..24 {
+                let image = capture.process_raw_image(&raw).unwrap();
+                frames.push((capture.applied_version(), image.width() as usize, image.height() as usize, image.color()));
+            }
+            done.store(true, Ordering::Relaxed);
+            frames
+        });
+
+        let published = published.into_inner();
+        let versions: Vec<u64> = frames.iter().map(|f| f.0).collect();
+        assert!(versions.windows(2).all(|w| w[0] <= w[1]), "{:?}", versions);
+        assert!(versions.windows(2).filter(|w| w[0] != w[1]).count() >= 10, "settings barely changed: {:?}", versions);
+        for (version, width, height, color) in frames {
+            let settings = &published[&version];
+            let expected = size.output(settings);
+            assert_eq!((width, height), (expected.width, expected.height), "version {}", version);
+            let expected_color = match settings.mode {
+                CaptureMode::Color | CaptureMode::ColorHdr => image::ColorType::Rgb8,
+                CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => image::ColorType::L8,
+            };
+            assert_eq!(color, expected_color, "version {}", version);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Pre-event buffer for recordings
+//!
+//! Keeps the last few seconds of encoded frames in memory so that a
+//! recording, typically one started by a motion or detection event, begins
+//! before the trigger: [`Recorder::start`](crate::recorder::Recorder::start)
+//! writes what is buffered ahead of the live frames.
+//!
+//! Published JPEGs are kept for AVI recordings. When the H.264 encoder is
+//! built in, its access units are kept too for MP4 recordings, which keeps
+//! the encoder running. Those are trimmed a whole GOP at a time so the
+//! buffer always starts at an IDR frame; it therefore holds between the
+//! configured duration and one GOP more.
+
+use crate::h264::{AccessUnit, Track};
+use crate::push::PushFrame;
+use crate::spool::SpoolFrame;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Longest pre-event buffer (`--record-pre-secs`)
+pub const MAX_PRE_SECS: u64 = 30;
+
+#[derive(Default)]
+struct Units {
+    track: Option<Track>,
+    units: VecDeque<Arc<AccessUnit>>,
+    bytes: usize,
+}
+
+#[derive(Default)]
+struct Frames {
+    frames: VecDeque<SpoolFrame>,
+    bytes: usize,
+}
+
+/// Pre-event section of /record/status
+#[derive(Debug, Clone, Serialize)]
+pub struct PreBufferStats {
+    pub seconds: u64,
+    /// Buffered JPEG frames and their span
+    pub frames: usize,
+    pub frames_ms: u64,
+    /// Buffered H.264 access units and their span
+    pub units: usize,
+    pub units_ms: u64,
+    pub bytes: usize,
+}
+
+pub struct PreBuffer {
+    duration_ms: u64,
+    frames: Mutex<Frames>,
+    units: Mutex<Units>,
+}
+
+impl PreBuffer {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration_ms: duration.as_millis() as u64,
+            frames: Mutex::new(Frames::default()),
+            units: Mutex::new(Units::default()),
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// Add a published frame, dropping those older than the duration
+    pub fn push_frame(&self, frame: SpoolFrame) {
+        let mut frames = self.frames.lock();
+        let newest = frame.timestamp_ms;
+        frames.bytes += frame.jpeg.len();
+        frames.frames.push_back(frame);
+        while frames.frames.front().is_some_and(|f| newest.saturating_sub(f.timestamp_ms) > self.duration_ms) {
+            let dropped = frames.frames.pop_front().map_or(0, |f| f.jpeg.len());
+            frames.bytes -= dropped;
+        }
+    }
+
+    /// Add an access unit of `track` (the first must be an IDR frame); a
+    /// new track starts the buffer over
+    pub fn push_unit(&self, track: &Track, unit: Arc<AccessUnit>) {
+        let mut units = self.units.lock();
+        if units.track.as_ref() != Some(track) {
+            *units = Units { track: Some(track.clone()), ..Default::default() };
+        }
+        if units.units.is_empty() && !unit.keyframe {
+            return;
+        }
+        let newest = unit.timestamp_ms;
+        units.bytes += unit.sample.len();
+        units.units.push_back(unit);
+        // Start at the latest IDR frame that still covers the duration
+        let start = units
+            .units
+            .iter()
+            .rposition(|u| u.keyframe && newest.saturating_sub(u.timestamp_ms) >= self.duration_ms)
+            .unwrap_or(0);
+        let dropped: usize = units.units.drain(..start).map(|u| u.sample.len()).sum();
+        units.bytes -= dropped;
+    }
+
+    /// The buffered frames, oldest first
+    pub fn frames(&self) -> Vec<SpoolFrame> {
+        self.frames.lock().frames.iter().cloned().collect()
+    }
+
+    /// The buffered access units if they are of `track`, oldest (an IDR
+    /// frame) first
+    pub fn units(&self, track: &Track) -> Vec<Arc<AccessUnit>> {
+        let units = self.units.lock();
+        if units.track.as_ref() != Some(track) {
+            return Vec::new();
+        }
+        units.units.iter().cloned().collect()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.frames.lock().bytes + self.units.lock().bytes
+    }
+
+    /// Drop everything buffered (to save memory)
+    pub fn clear(&self) {
+        *self.frames.lock() = Frames::default();
+        *self.units.lock() = Units::default();
+    }
+
+    pub fn stats(&self) -> PreBufferStats {
+        let frames = self.frames.lock();
+        let units = self.units.lock();
+        let span = |first: Option<u64>, last: Option<u64>| last.zip(first).map_or(0, |(l, f)| l.saturating_sub(f));
+        PreBufferStats {
+            seconds: self.duration_ms / 1000,
+            frames: frames.frames.len(),
+            frames_ms: span(
+                frames.frames.front().map(|f| f.timestamp_ms),
+                frames.frames.back().map(|f| f.timestamp_ms),
+            ),
+            units: units.units.len(),
+            units_ms: span(
+                units.units.front().map(|u| u.timestamp_ms),
+                units.units.back().map(|u| u.timestamp_ms),
+            ),
+            bytes: frames.bytes + units.bytes,
+        }
+    }
+}
+
+/// Feed published frames into `buffer` until the watch closes
+pub async fn run_frames(buffer: Arc<PreBuffer>, mut frames: watch::Receiver<Option<PushFrame>>) {
+    while frames.changed().await.is_ok() {
+        let Some(frame) = frames.borrow_and_update().clone() else {
+            continue;
+        };
+        buffer.push_frame(SpoolFrame { seq: frame.seq, timestamp_ms: frame.timestamp_ms, jpeg: frame.jpeg });
+    }
+}
@@ -0,0 +1,256 @@
+//! Named settings profiles and the settings state file
+//!
+//! A profile is a snapshot of the runtime knobs (capture mode, sensor
+//! exposure/gain, image pipeline, metering, detection) saved under a name
+//! and applied again in one go. Profiles live under the `profiles` key of
+//! the settings state file, which is rewritten atomically on every change;
+//! other keys in that file (e.g. `schedule`) are left untouched.
+//!
+//! Every field is optional so hand-written profiles can set only a few
+//! knobs; fields that are absent are left as they are when applied. The
+//! schedule (see [`crate::schedule`]) can apply profiles at set times of
+//! day.
+
+use crate::capture::{self, CaptureMode};
+use crate::demosaic::DemosaicAlgorithm;
+use crate::hdr;
+use crate::metering::MeteringConfig;
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Settings state file before the versioned data layout ([`crate::storage`]);
+/// moved into the layout at startup
+pub const LEGACY_STATE_FILE: &str = "/home/angelo/imx415_streamer/settings.json";
+
+/// Sensor controls captured into profiles (when the sensor has them)
+pub const PROFILE_CONTROLS: [&str; 3] = ["exposure", "analogue_gain", "digital_gain"];
+
+/// Serializes read-modify-write cycles on the state file
+static STATE_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// One top-level key of the state file (None if the file or key is missing)
+pub fn read_state_key(path: &Path, key: &str) -> Result<Option<serde_json::Value>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => {
+            let mut state: serde_json::Value =
+                serde_json::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))?;
+            Ok(state.get_mut(key).map(serde_json::Value::take))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Rewrite one top-level key of the state file, keeping the others
+/// (temp file + rename)
+pub fn write_state_key(path: &Path, key: &str, value: serde_json::Value) -> Result<()> {
+    let _guard = STATE_FILE_LOCK.lock();
+    let mut state = match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|_| serde_json::json!({})),
+        Err(_) => serde_json::json!({}),
+    };
+    if !state.is_object() {
+        state = serde_json::json!({});
+    }
+    state[key] = value;
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_vec_pretty(&state)?)
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+    std::fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// JPEG quality mode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum QualitySetting {
+    /// Profiles saved before the quality was recorded keep the current one
+    Fixed {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quality: Option<u8>,
+    },
+    Auto {
+        min: u8,
+        max: u8,
+        target_kb: Option<usize>,
+        target_ms: Option<f32>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RowNoiseSetting {
+    pub enabled: bool,
+    pub strength: f32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DetectionSetting {
+    pub enabled: bool,
+    /// Feed every Nth frame (0 = on demand)
+    pub interval: u32,
+}
+
+/// The settings a profile carries
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Settings {
+    /// `grayscale`, `grayscale-hdr`, `color` or `color-hdr`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// Color mode demosaic, `bilinear` or `malvar`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub demosaic: Option<String>,
+    /// HDR modes' long/short exposure ratio
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hdr_ratio: Option<f32>,
+    /// Sensor subdevice controls by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub controls: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualitySetting>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gamma: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub white_balance: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tonemap_strength: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub black_level: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub row_noise: Option<RowNoiseSetting>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metering: Option<MeteringConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detection: Option<DetectionSetting>,
+}
+
+impl Settings {
+    /// Check the values a profile written by hand carries; sensor controls
+    /// and metering are checked against the hardware when applied
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(ref mode) = self.mode {
+            CaptureMode::parse(mode).ok_or_else(|| format!("Invalid mode '{}'", mode))?;
+        }
+        if let Some(ref name) = self.demosaic {
+            DemosaicAlgorithm::parse(name).ok_or_else(|| format!("Invalid demosaic '{}'", name))?;
+        }
+        match self.quality {
+            Some(QualitySetting::Fixed { quality: Some(quality) }) if !(1..=100).contains(&quality) => {
+                return Err(format!("Quality {} must be within 1-100", quality));
+            }
+            Some(QualitySetting::Auto { min, max, target_kb, target_ms }) => {
+                if target_kb.is_none() && target_ms.is_none() {
+                    return Err("Auto quality needs target_kb and/or target_ms".to_string());
+                }
+                if !(1..=100).contains(&min) || !(min..=100).contains(&max) {
+                    return Err(format!("Quality range {}-{} must be within 1-100", min, max));
+                }
+            }
+            _ => {}
+        }
+        if self.gamma.is_some_and(|g| !(capture::MIN_GAMMA..=capture::MAX_GAMMA).contains(&g)) {
+            return Err(format!("gamma must be between {} and {}", capture::MIN_GAMMA, capture::MAX_GAMMA));
+        }
+        if self.hdr_ratio.is_some_and(|r| !(hdr::MIN_RATIO..=hdr::MAX_RATIO).contains(&r)) {
+            return Err(format!("hdr_ratio must be between {} and {}", hdr::MIN_RATIO, hdr::MAX_RATIO));
+        }
+        if self.tonemap_strength.is_some_and(|s| !(0.0..=1.0).contains(&s)) {
+            return Err("tonemap_strength must be between 0.0 and 1.0".to_string());
+        }
+        if self.black_level.is_some_and(|b| b > 1022) {
+            return Err("black_level must be at most 1022".to_string());
+        }
+        if self.row_noise.is_some_and(|r| !(0.0..=1.0).contains(&r.strength)) {
+            return Err("row_noise strength must be between 0.0 and 1.0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A saved profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub saved_ms: u64,
+    #[serde(flatten)]
+    pub settings: Settings,
+}
+
+/// A setting left alone when a profile was applied, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    pub setting: String,
+    pub reason: String,
+}
+
+/// Profiles and the state file they are persisted to
+pub struct ProfileStore {
+    path: PathBuf,
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl ProfileStore {
+    /// Load profiles from `path`; a missing file means no profiles yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let profiles = match read_state_key(path, "profiles")? {
+            Some(profiles) => {
+                serde_json::from_value(profiles).with_context(|| format!("Invalid profiles in {}", path.display()))?
+            }
+            None => BTreeMap::new(),
+        };
+        Ok(Self { path: path.to_path_buf(), profiles })
+    }
+
+    /// An empty store that will be written to `path`
+    pub fn empty(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), profiles: BTreeMap::new() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    pub fn list(&self) -> &BTreeMap<String, Profile> {
+        &self.profiles
+    }
+
+    /// Save a profile (replacing one of the same name) and persist;
+    /// returns whether one was replaced
+    pub fn insert(&mut self, name: &str, profile: Profile) -> Result<bool> {
+        let previous = self.profiles.insert(name.to_string(), profile);
+        if let Err(e) = self.persist() {
+            match previous {
+                Some(previous) => self.profiles.insert(name.to_string(), previous),
+                None => self.profiles.remove(name),
+            };
+            return Err(e);
+        }
+        Ok(previous.is_some())
+    }
+
+    /// Remove a profile and persist; false if there was none
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        let Some(previous) = self.profiles.remove(name) else {
+            return Ok(false);
+        };
+        if let Err(e) = self.persist() {
+            self.profiles.insert(name.to_string(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Rewrite the `profiles` key of the state file
+    fn persist(&self) -> Result<()> {
+        write_state_key(&self.path, "profiles", serde_json::to_value(&self.profiles)?)
+    }
+}
@@ -0,0 +1,278 @@
+//! Raw frame push for non-HTTP consumers
+//!
+//! A TCP server sends every connected client a simple length-prefixed JPEG
+//! stream, and an optional UDP sender pushes downscaled thumbnails (one per
+//! datagram) to a fixed address. Both follow the MJPEG policy: a client that
+//! can't keep up skips frames rather than buffering them.
+
+use crate::latency::FrameTiming;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::watch;
+
+/// Wire format of one pushed frame (TCP message or UDP datagram).
+///
+/// All integers are little-endian:
+///
+/// | offset | size | field                          |
+/// |--------|------|--------------------------------|
+/// | 0      | 4    | `u32` JPEG length in bytes     |
+/// | 4      | 4    | `u32` frame sequence (low bits)|
+/// | 8      | 8    | `u64` capture time, Unix ms    |
+/// | 16     | len  | JPEG data                      |
+pub const WIRE_FORMAT: &str = "[u32 length][u32 frame_seq][u64 timestamp_ms][jpeg bytes], little-endian";
+
+/// Size of the header preceding the JPEG data
+pub const HEADER_LEN: usize = 16;
+
+/// Largest UDP payload we send (fits a single IPv4 datagram)
+pub const MAX_DATAGRAM: usize = 65_507;
+
+/// Thumbnail width for UDP pushes
+const THUMBNAIL_WIDTH: u32 = 320;
+
+/// JPEG qualities tried, in order, until a thumbnail fits in one datagram
+const THUMBNAIL_QUALITIES: [u8; 4] = [70, 50, 35, 20];
+
+/// A frame published by the capture loop
+#[derive(Clone)]
+pub struct PushFrame {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub jpeg: Bytes,
+    /// Content hash of the published frame `jpeg` shows (see `framehash`);
+    /// per-client re-encodes keep it
+    pub hash: u64,
+    pub timing: FrameTiming,
+}
+
+/// Encode the wire header for a frame
+pub fn encode_header(jpeg_len: usize, seq: u64, timestamp_ms: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0..4].copy_from_slice(&(jpeg_len as u32).to_le_bytes());
+    header[4..8].copy_from_slice(&(seq as u32).to_le_bytes());
+    header[8..16].copy_from_slice(&timestamp_ms.to_le_bytes());
+    header
+}
+
+/// Push counters
+#[derive(Default)]
+pub struct PushStats {
+    clients: AtomicUsize,
+    frames_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    udp_datagrams: AtomicU64,
+    udp_bytes: AtomicU64,
+}
+
+/// Snapshot of push counters
+#[derive(Debug, Clone, Serialize)]
+pub struct PushStatsSnapshot {
+    pub clients: usize,
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub udp_datagrams: u64,
+    pub udp_bytes: u64,
+}
+
+impl PushStats {
+    pub fn snapshot(&self) -> PushStatsSnapshot {
+        PushStatsSnapshot {
+            clients: self.clients.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            udp_datagrams: self.udp_datagrams.load(Ordering::Relaxed),
+            udp_bytes: self.udp_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Accept TCP clients and stream frames to each until it disconnects
+pub async fn run_tcp(
+    listener: TcpListener,
+    frames: watch::Receiver<Option<PushFrame>>,
+    stats: Arc<PushStats>,
+) {
+    loop {
+        let (socket, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Typically EMFILE; back off instead of spinning
+                tracing::warn!("Raw push accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let _ = socket.set_nodelay(true);
+        tracing::info!("Raw push client connected: {}", remote);
+
+        let frames = frames.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            stats.clients.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = serve_client(socket, frames, &stats).await {
+                tracing::debug!("Raw push client {}: {}", remote, e);
+            }
+            stats.clients.fetch_sub(1, Ordering::Relaxed);
+            tracing::info!("Raw push client disconnected: {}", remote);
+        });
+    }
+}
+
+async fn serve_client(
+    mut socket: TcpStream,
+    mut frames: watch::Receiver<Option<PushFrame>>,
+    stats: &PushStats,
+) -> Result<()> {
+    // The watch channel only holds the newest frame, so frames published
+    // while we're still writing are skipped rather than queued
+    frames.mark_changed();
+    loop {
+        frames.changed().await.context("Capture stopped")?;
+        let Some(frame) = frames.borrow_and_update().clone() else {
+            continue;
+        };
+        let header = encode_header(frame.jpeg.len(), frame.seq, frame.timestamp_ms);
+        socket.write_all(&header).await?;
+        socket.write_all(&frame.jpeg).await?;
+        stats.frames_sent.fetch_add(1, Ordering::Relaxed);
+        stats
+            .bytes_sent
+            .fetch_add((HEADER_LEN + frame.jpeg.len()) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Downscale a frame to a thumbnail that fits in one datagram
+fn encode_thumbnail(jpeg: &[u8]) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(jpeg).context("Failed to decode frame")?;
+    let height = image.height() * THUMBNAIL_WIDTH / image.width().max(1);
+    let thumbnail = image.thumbnail(THUMBNAIL_WIDTH, height.max(1));
+    for quality in THUMBNAIL_QUALITIES {
+        let encoded = crate::capture::encode_image_jpeg(&thumbnail, quality)?;
+        if encoded.len() + HEADER_LEN <= MAX_DATAGRAM {
+            return Ok(encoded);
+        }
+    }
+    anyhow::bail!("Thumbnail does not fit in a datagram")
+}
+
+/// Send a thumbnail of the latest frame to `target` at `fps`
+pub async fn run_udp(
+    target: SocketAddr,
+    fps: u32,
+    frames: watch::Receiver<Option<PushFrame>>,
+    stats: Arc<PushStats>,
+) -> Result<()> {
+    let bind_addr: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    if matches!(target.ip(), IpAddr::V4(ip) if ip.is_broadcast()) {
+        socket.set_broadcast(true)?;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / fps.max(1) as f64));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut last_seq = None;
+    loop {
+        interval.tick().await;
+        let Some(frame) = frames.borrow().clone() else {
+            continue;
+        };
+        if last_seq == Some(frame.seq) {
+            continue;
+        }
+        last_seq = Some(frame.seq);
+
+        let thumbnail = match tokio::task::spawn_blocking(move || encode_thumbnail(&frame.jpeg)).await? {
+            Ok(thumbnail) => thumbnail,
+            Err(e) => {
+                tracing::warn!("UDP thumbnail failed: {}", e);
+                continue;
+            }
+        };
+        let mut datagram = Vec::with_capacity(HEADER_LEN + thumbnail.len());
+        datagram.extend_from_slice(&encode_header(thumbnail.len(), frame.seq, frame.timestamp_ms));
+        datagram.extend_from_slice(&thumbnail);
+        match socket.send_to(&datagram, target).await {
+            Ok(sent) => {
+                stats.udp_datagrams.fetch_add(1, Ordering::Relaxed);
+                stats.udp_bytes.fetch_add(sent as u64, Ordering::Relaxed);
+            }
+            Err(e) => tracing::warn!("UDP push to {} failed: {}", target, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    fn frame(seq: u64, jpeg: &'static [u8]) -> Option<PushFrame> {
+        Some(PushFrame {
+            seq,
+            timestamp_ms: 1_700_000_000_000 + seq,
+            jpeg: Bytes::from_static(jpeg),
+            hash: seq,
+            timing: FrameTiming::default(),
+        })
+    }
+
+    /// Read one wire message: (seq, timestamp_ms, jpeg)
+    async fn read_message(socket: &mut TcpStream) -> (u32, u64, Vec<u8>) {
+        let mut header = [0u8; HEADER_LEN];
+        socket.read_exact(&mut header).await.unwrap();
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let seq = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let timestamp_ms = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let mut jpeg = vec![0u8; len as usize];
+        socket.read_exact(&mut jpeg).await.unwrap();
+        (seq, timestamp_ms, jpeg)
+    }
+
+    #[tokio::test]
+    async fn tcp_clients_get_length_prefixed_frames_over_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = watch::channel(frame(1, b"first"));
+        let stats = Arc::new(PushStats::default());
+        let server = tokio::spawn(run_tcp(listener, rx, stats.clone()));
+
+        // A new client starts with the current frame
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(read_message(&mut client).await, (1, 1_700_000_000_001, b"first".to_vec()));
+        tx.send_replace(frame(2, b"second frame"));
+        assert_eq!(read_message(&mut client).await, (2, 1_700_000_000_002, b"second frame".to_vec()));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.clients, 1);
+        assert_eq!(snapshot.frames_sent, 2);
+        assert_eq!(snapshot.bytes_sent, (2 * HEADER_LEN + 5 + 12) as u64);
+
+        // A disconnect is noticed by a failed write (the first one after
+        // the close can still succeed)
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for seq in 3.. {
+                if stats.snapshot().clients == 0 {
+                    break;
+                }
+                tx.send_replace(frame(seq, b"later"));
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("client still counted after disconnecting");
+        server.abort();
+    }
+}
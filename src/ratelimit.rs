@@ -0,0 +1,446 @@
+//! Per-client rate limiting for internet-facing deployments
+//!
+//! Image and API routes each get a token bucket per client IP, and
+//! streaming routes a cap on simultaneous connections per IP. Requests over
+//! the limit get 429 with a Retry-After header. The client IP is the TCP
+//! peer, or the X-Forwarded-For address when the peer is a trusted proxy.
+
+use crate::error::{ApiError, ErrorCode};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Burst size as a multiple of the sustained per-second rate
+const BURST_SECONDS: f64 = 2.0;
+
+/// Buckets tracked before idle (full) ones are pruned
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// Buckets left after pruning; if dropping the idle ones isn't enough, the
+/// least recently used go too. The slack means a full scan once per this
+/// many new clients rather than per request.
+const PRUNED_CLIENTS: usize = MAX_TRACKED_CLIENTS * 3 / 4;
+
+/// Retry-After sent when a client is over its stream cap
+const STREAM_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Token bucket: `capacity` tokens, refilled at `refill_per_sec`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(refill_per_sec: f64, capacity: f64, now: Instant) -> Self {
+        Self { capacity, refill_per_sec, tokens: capacity, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Take one token, or return how long until one is available
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = 1.0 - self.tokens;
+        Err(Duration::from_secs_f64(missing / self.refill_per_sec.max(f64::MIN_POSITIVE)))
+    }
+
+    /// Whether the bucket has refilled completely (the client is idle)
+    pub fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * self.refill_per_sec >= self.capacity
+    }
+}
+
+/// An address range (`192.168.0.0/16`) or single address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn parse(text: &str) -> Option<Self> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (text.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = |bits: u32| if self.prefix == 0 { 0 } else { u128::MAX << (bits - self.prefix as u32) };
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(32) as u32;
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(128);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Treat IPv4-mapped IPv6 peers (dual-stack sockets) as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// Parse a comma-separated list of addresses/ranges
+pub fn parse_ranges(list: &str) -> Result<Vec<IpRange>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| IpRange::parse(s).ok_or_else(|| format!("Invalid address or range: {}", s)))
+        .collect()
+}
+
+/// Client address for rate limiting
+///
+/// X-Forwarded-For is only honoured when the TCP peer is a trusted proxy;
+/// the rightmost entry is the address that proxy saw, so a client can't
+/// spoof its way past the limit by sending its own header.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpRange]) -> IpAddr {
+    let peer = canonical(peer);
+    if !trusted_proxies.iter().any(|p| p.contains(peer)) {
+        return peer;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|s| s.trim().parse::<IpAddr>().ok())
+        .next_back()
+        .map(canonical)
+        .unwrap_or(peer)
+}
+
+/// Limits from the command line; `None` means unlimited
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitConfig {
+    /// Image requests per second per client
+    pub images_per_sec: Option<f64>,
+    /// API requests per second per client
+    pub api_per_sec: Option<f64>,
+    /// Simultaneous streaming connections per client
+    pub max_streams_per_ip: Option<usize>,
+    pub trusted_proxies: Vec<IpRange>,
+    /// Clients never limited (e.g. the local network)
+    pub exempt: Vec<IpRange>,
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.images_per_sec.is_some() || self.api_per_sec.is_some() || self.max_streams_per_ip.is_some()
+    }
+}
+
+/// Route class a limit applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    Image,
+    Api,
+    Stream,
+}
+
+impl RouteClass {
+    pub const ALL: [RouteClass; 3] = [RouteClass::Image, RouteClass::Api, RouteClass::Stream];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RouteClass::Image => "image",
+            RouteClass::Api => "api",
+            RouteClass::Stream => "stream",
+        }
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    image_buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    api_buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+    streams: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    rejected: [AtomicU64; RouteClass::ALL.len()],
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            image_buckets: Mutex::new(HashMap::new()),
+            api_buckets: Mutex::new(HashMap::new()),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            rejected: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Requests rejected with 429, per route class
+    pub fn rejected(&self, class: RouteClass) -> u64 {
+        self.rejected[class as usize].load(Ordering::Relaxed)
+    }
+
+    fn reject(&self, class: RouteClass, retry_after: Duration) -> Response {
+        self.rejected[class as usize].fetch_add(1, Ordering::Relaxed);
+        let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        ApiError::new(ErrorCode::RateLimited, format!("Too many {} requests", class.name()))
+            .with_details(serde_json::json!({ "retry_after_secs": secs }))
+            .with_retry_after(secs)
+            .into_response()
+    }
+
+    fn take_token(&self, class: RouteClass, ip: IpAddr) -> Result<(), Duration> {
+        let (buckets, rate) = match class {
+            RouteClass::Image => (&self.image_buckets, self.config.images_per_sec),
+            RouteClass::Api => (&self.api_buckets, self.config.api_per_sec),
+            RouteClass::Stream => return Ok(()),
+        };
+        let Some(rate) = rate else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut buckets = buckets.lock();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            prune(&mut buckets, now);
+        }
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(rate, (rate * BURST_SECONDS).max(1.0), now))
+            .try_take(now)
+    }
+
+    /// Count a new stream for `ip`; the guard releases it when dropped
+    fn open_stream(&self, ip: IpAddr) -> Option<StreamGuard> {
+        let mut streams = self.streams.lock();
+        let count = streams.entry(ip).or_insert(0);
+        if self.config.max_streams_per_ip.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(StreamGuard { ip, streams: self.streams.clone() })
+    }
+
+    fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        let ConnectInfo(peer) = request.extensions().get::<ConnectInfo<SocketAddr>>()?;
+        let ip = client_ip(peer.ip(), request.headers(), &self.config.trusted_proxies);
+        (!self.config.exempt.iter().any(|range| range.contains(ip))).then_some(ip)
+    }
+}
+
+/// Drop idle buckets, then the least recently used ones, down to
+/// `PRUNED_CLIENTS`
+fn prune(buckets: &mut HashMap<IpAddr, TokenBucket>, now: Instant) {
+    buckets.retain(|_, bucket| !bucket.is_full(now));
+    let Some(excess) = buckets.len().checked_sub(PRUNED_CLIENTS).filter(|&n| n > 0) else {
+        return;
+    };
+    // `last` is the bucket's last take (`is_full` doesn't touch it)
+    let mut used: Vec<Instant> = buckets.values().map(|bucket| bucket.last).collect();
+    let (_, &mut cutoff, _) = used.select_nth_unstable(excess - 1);
+    buckets.retain(|_, bucket| bucket.last > cutoff);
+}
+
+/// Releases a stream slot when the response body is dropped
+struct StreamGuard {
+    ip: IpAddr,
+    streams: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut streams = self.streams.lock();
+        if let Some(count) = streams.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                streams.remove(&self.ip);
+            }
+        }
+    }
+}
+
+async fn limit(limiter: &RateLimiter, class: RouteClass, request: Request, next: Next) -> Response {
+    let Some(ip) = limiter.client_ip(&request) else {
+        return next.run(request).await;
+    };
+    if let Err(retry_after) = limiter.take_token(class, ip) {
+        return limiter.reject(class, retry_after);
+    }
+    next.run(request).await
+}
+
+/// Middleware for single-image routes
+pub async fn limit_images(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    limit(&limiter, RouteClass::Image, request, next).await
+}
+
+/// Middleware for API routes
+pub async fn limit_api(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    limit(&limiter, RouteClass::Api, request, next).await
+}
+
+/// Middleware for streaming routes: caps simultaneous streams per client
+pub async fn limit_streams(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    let Some(ip) = limiter.client_ip(&request) else {
+        return next.run(request).await;
+    };
+    let Some(guard) = limiter.open_stream(ip) else {
+        return limiter.reject(RouteClass::Stream, STREAM_RETRY_AFTER);
+    };
+
+    // Hold the slot for as long as the body is being streamed
+    let (parts, body) = next.run(request).await.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn forwarded(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &value in values {
+            headers.append("x-forwarded-for", HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 4.0, start);
+        for _ in 0..4 {
+            assert_eq!(bucket.try_take(start), Ok(()));
+        }
+        // Empty: the next token is half a second away
+        assert_eq!(bucket.try_take(start), Err(Duration::from_millis(500)));
+        let retry = bucket.try_take(start + Duration::from_millis(250)).unwrap_err();
+        assert!(retry.abs_diff(Duration::from_millis(250)) < Duration::from_micros(1), "{:?}", retry);
+        assert_eq!(bucket.try_take(start + Duration::from_millis(500)), Ok(()));
+        assert!(!bucket.is_full(start + Duration::from_millis(500)));
+        
+        // Refilling stops at the capacity
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.is_full(later));
+        for _ in 0..4 {
+            assert_eq!(bucket.try_take(later), Ok(()));
+        }
+        assert!(bucket.try_take(later).is_err());
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let headers = forwarded(&["203.0.113.7"]);
+        assert_eq!(client_ip(ip("198.51.100.1"), &headers, &[]), ip("198.51.100.1"));
+        let proxies = parse_ranges("10.0.0.0/8").unwrap();
+        assert_eq!(client_ip(ip("198.51.100.1"), &headers, &proxies), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn trusted_proxies_give_the_rightmost_forwarded_address() {
+        let proxies = parse_ranges("10.0.0.0/8, ::1").unwrap();
+        let peer = ip("10.1.2.3");
+        // The client may prepend anything; the proxy appends what it saw
+        let headers = forwarded(&["1.2.3.4, 203.0.113.7"]);
+        assert_eq!(client_ip(peer, &headers, &proxies), ip("203.0.113.7"));
+        // Across repeated headers too
+        let headers = forwarded(&["1.2.3.4", "203.0.113.8"]);
+        assert_eq!(client_ip(peer, &headers, &proxies), ip("203.0.113.8"));
+        // Unparseable entries are skipped, and no header means the peer
+        let headers = forwarded(&["203.0.113.9, unknown"]);
+        assert_eq!(client_ip(peer, &headers, &proxies), ip("203.0.113.9"));
+        assert_eq!(client_ip(peer, &HeaderMap::new(), &proxies), peer);
+        assert_eq!(client_ip(ip("::1"), &forwarded(&["2001:db8::1"]), &proxies), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn mapped_ipv4_peers_match_ipv4_ranges() {
+        let proxies = parse_ranges("10.0.0.0/8").unwrap();
+        let headers = forwarded(&["::ffff:203.0.113.7"]);
+        assert_eq!(client_ip(ip("::ffff:10.0.0.1"), &headers, &proxies), ip("203.0.113.7"));
+        assert_eq!(client_ip(ip("::ffff:192.0.2.1"), &headers, &proxies), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn ranges_parse_and_match() {
+        let range = IpRange::parse("192.168.0.0/16").unwrap();
+        assert!(range.contains(ip("192.168.44.1")));
+        assert!(!range.contains(ip("192.169.0.1")));
+        assert!(!range.contains(ip("::1")));
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(IpRange::parse("10.0.0.0/33").is_none());
+        assert!(parse_ranges("10.0.0.1, nonsense").is_err());
+        assert_eq!(parse_ranges(" , ").unwrap(), []);
+    }
+
+    #[test]
+    fn pruning_drops_idle_then_least_recently_used_buckets() {
+        let start = Instant::now();
+        let mut buckets = HashMap::new();
+        // Busy clients, client i last seen at start + i ms, none full
+        for i in 0..MAX_TRACKED_CLIENTS {
+            let client = IpAddr::from([10, 0, (i >> 8) as u8, i as u8]);
+            let mut bucket = TokenBucket::new(0.001, 1.0, start);
+            bucket.try_take(start + Duration::from_millis(i as u64)).unwrap();
+            buckets.insert(client, bucket);
+        }
+        // and one idle one
+        buckets.insert(ip("192.0.2.1"), TokenBucket::new(1.0, 1.0, start));
+        
+        let now = start + Duration::from_secs(10);
+        prune(&mut buckets, now);
+        assert_eq!(buckets.len(), PRUNED_CLIENTS);
+        assert!(!buckets.contains_key(&ip("192.0.2.1")));
+        // The most recently used survive
+        let evicted = MAX_TRACKED_CLIENTS - PRUNED_CLIENTS;
+        let oldest_kept = IpAddr::from([10, 0, (evicted >> 8) as u8, evicted as u8]);
+        assert!(buckets.contains_key(&oldest_kept));
+        assert!(buckets.values().all(|bucket| bucket.last >= start + Duration::from_millis(evicted as u64)));
+        
+        // Under the limit, only idle buckets go
+        buckets.insert(ip("192.0.2.2"), TokenBucket::new(1.0, 1.0, start));
+        prune(&mut buckets, now);
+        assert_eq!(buckets.len(), PRUNED_CLIENTS);
+    }
+}
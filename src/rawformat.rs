@@ -0,0 +1,300 @@
+//! Raw Bayer formats negotiated by the capture driver
+//!
+//! Depending on the kernel and driver, the sensor comes up as 10-bit CSI-2
+//! packed (5 bytes per 4 pixels), 10/12-bit unpacked (one little-endian
+//! 16-bit word per sample) or 12-bit CSI-2 packed (3 bytes per 2 pixels),
+//! in any of the four CFA orders. Everything is unpacked into the 10-bit
+//! Bayer buffer the pipeline works on; 12-bit samples lose their two least
+//! significant bits, which the 8-bit output never shows anyway.
+
+use serde::Serialize;
+
+/// Color filter array order, named by the top-left 2x2 block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Cfa {
+    Rggb,
+    Grbg,
+    Gbrg,
+    Bggr,
+}
+
+impl Cfa {
+    pub const ALL: [Cfa; 4] = [Cfa::Rggb, Cfa::Grbg, Cfa::Gbrg, Cfa::Bggr];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|cfa| cfa.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cfa::Rggb => "RGGB",
+            Cfa::Grbg => "GRBG",
+            Cfa::Gbrg => "GBRG",
+            Cfa::Bggr => "BGGR",
+        }
+    }
+
+    /// Row and column shift that maps this order onto GBRG, the layout the
+    /// demosaic is written for
+    pub fn gbrg_offset(self) -> (usize, usize) {
+        match self {
+            Cfa::Gbrg => (0, 0),
+            Cfa::Bggr => (0, 1),
+            Cfa::Rggb => (1, 0),
+            Cfa::Grbg => (1, 1),
+        }
+    }
+}
+
+/// Sample packing in the raw buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Packing {
+    /// MIPI CSI-2 RAW10: 4 MSB bytes, then one byte of 2-bit LSBs
+    Packed10,
+    /// One little-endian u16 per sample, right-aligned
+    Unpacked16,
+    /// MIPI CSI-2 RAW12: 2 MSB bytes, then one byte of 4-bit LSBs
+    Packed12,
+}
+
+/// A negotiated raw format
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RawFormat {
+    pub fourcc: String,
+    pub packing: Packing,
+    /// Significant bits per sample
+    pub bits: u8,
+    pub cfa: Cfa,
+}
+
+impl Default for RawFormat {
+    /// SGBRG10P, what the IMX415 driver negotiates on the stock image
+    fn default() -> Self {
+        Self {
+            fourcc: "pGAA".to_string(),
+            packing: Packing::Packed10,
+            bits: 10,
+            cfa: Cfa::Gbrg,
+        }
+    }
+}
+
+impl RawFormat {
+    /// Interpret a fourcc and the driver's description of it
+    ///
+    /// Some drivers (rkcif among them) report the unpacked fourcc (`GB10`)
+    /// for CSI-2 packed data and only say "Packed" in the description, so
+    /// the description counts as much as the `p` prefix.
+    pub fn from_fourcc(fourcc: &str, description: &str) -> Option<Self> {
+        let (cfa, bits, packed) = match fourcc {
+            "RG10" => (Cfa::Rggb, 10, false),
+            "BA10" => (Cfa::Grbg, 10, false),
+            "GB10" => (Cfa::Gbrg, 10, false),
+            "BG10" => (Cfa::Bggr, 10, false),
+            "pRAA" => (Cfa::Rggb, 10, true),
+            "pgAA" => (Cfa::Grbg, 10, true),
+            "pGAA" => (Cfa::Gbrg, 10, true),
+            "pBAA" => (Cfa::Bggr, 10, true),
+            "RG12" => (Cfa::Rggb, 12, false),
+            "BA12" => (Cfa::Grbg, 12, false),
+            "GB12" => (Cfa::Gbrg, 12, false),
+            "BG12" => (Cfa::Bggr, 12, false),
+            "pRCC" => (Cfa::Rggb, 12, true),
+            "pgCC" => (Cfa::Grbg, 12, true),
+            "pGCC" => (Cfa::Gbrg, 12, true),
+            "pBCC" => (Cfa::Bggr, 12, true),
+            _ => return None,
+        };
+        let packed = packed || description.contains("Packed");
+        let packing = match (bits, packed) {
+            (10, true) => Packing::Packed10,
+            (12, true) => Packing::Packed12,
+            _ => Packing::Unpacked16,
+        };
+        Some(Self { fourcc: fourcc.to_string(), packing, bits, cfa })
+    }
+
+    /// Parse the `Pixel Format` line of `v4l2-ctl --get-fmt-video`, e.g.
+    /// `'GB10' (10-bit Bayer GBGB/RGRG Packed)`
+    pub fn from_v4l2(pixel_format: &str) -> Option<Self> {
+        let fourcc = pixel_format.split('\'').nth(1)?;
+        let description = pixel_format
+            .split_once('(')
+            .map_or("", |(_, rest)| rest.trim_end_matches(')'));
+        Self::from_fourcc(fourcc, description)
+    }
+
+    /// Smallest line length (bytes) that holds `width` samples
+    pub fn min_stride(&self, width: usize) -> usize {
+        match self.packing {
+            Packing::Packed10 => width * 5 / 4,
+            Packing::Unpacked16 => width * 2,
+            Packing::Packed12 => width * 3 / 2,
+        }
+    }
+
+    /// Unpack `raw` (lines of `stride` bytes) into 10-bit samples in `out`
+    /// (`width` per row); short frames leave the missing tail untouched
+    pub fn unpack(&self, raw: &[u8], stride: usize, width: usize, out: &mut [u16]) {
+        let shift = self.bits.saturating_sub(10);
+        for (y, out_row) in out.chunks_exact_mut(width).enumerate() {
+            let Some(line) = raw.get(y * stride..) else {
+                break;
+            };
+            let line = &line[..line.len().min(stride)];
+            match self.packing {
+                Packing::Packed10 => unpack_packed10(line, out_row),
+                Packing::Unpacked16 => unpack_16(line, out_row, self.bits, shift),
+                Packing::Packed12 => unpack_packed12(line, out_row),
+            }
+        }
+    }
+
+    /// Pack one line of 10-bit samples into `line`, the inverse of
+    /// [`unpack`](Self::unpack) (12-bit formats get two zero LSBs)
+    pub fn pack_line(&self, samples: &[u16], line: &mut [u8]) {
+        match self.packing {
+            Packing::Packed10 => {
+                for (group, pixels) in line.chunks_exact_mut(5).zip(samples.chunks_exact(4)) {
+                    group[4] = 0;
+                    for (i, &v) in pixels.iter().enumerate() {
+                        group[i] = (v >> 2) as u8;
+                        group[4] |= ((v & 0x3) << (2 * i)) as u8;
+                    }
+                }
+            }
+            Packing::Unpacked16 => {
+                let shift = self.bits.saturating_sub(10);
+                for (bytes, &v) in line.chunks_exact_mut(2).zip(samples) {
+                    bytes.copy_from_slice(&(v << shift).to_le_bytes());
+                }
+            }
+            Packing::Packed12 => {
+                for (group, pixels) in line.chunks_exact_mut(3).zip(samples.chunks_exact(2)) {
+                    let [a, b] = [pixels[0] << 2, pixels[1] << 2];
+                    group[0] = (a >> 4) as u8;
+                    group[1] = (b >> 4) as u8;
+                    group[2] = ((a & 0xF) | ((b & 0xF) << 4)) as u8;
+                }
+            }
+        }
+    }
+}
+
+fn unpack_packed10(line: &[u8], out: &mut [u16]) {
+    #[cfg(target_arch = "aarch64")]
+    let done = crate::neon::unpack_packed10(line, out);
+    #[cfg(not(target_arch = "aarch64"))]
+    let done = 0;
+    let (line, out) = (&line[done * 5 / 4..], &mut out[done..]);
+    for (group, pixels) in line.chunks_exact(5).zip(out.chunks_exact_mut(4)) {
+        let lsbs = group[4] as u16;
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = ((group[i] as u16) << 2) | ((lsbs >> (2 * i)) & 0x3);
+        }
+    }
+}
+
+fn unpack_16(line: &[u8], out: &mut [u16], bits: u8, shift: u8) {
+    let mask = (1u16 << bits) - 1;
+    for (bytes, pixel) in line.chunks_exact(2).zip(out.iter_mut()) {
+        *pixel = (u16::from_le_bytes([bytes[0], bytes[1]]) & mask) >> shift;
+    }
+}
+
+/// RAW12 to 10-bit: the LSB nibbles are below the kept precision, so only
+/// the top two bits of each nibble are used
+fn unpack_packed12(line: &[u8], out: &mut [u16]) {
+    for (group, pixels) in line.chunks_exact(3).zip(out.chunks_exact_mut(2)) {
+        let lsbs = group[2] as u16;
+        pixels[0] = ((group[0] as u16) << 2) | ((lsbs >> 2) & 0x3);
+        pixels[1] = ((group[1] as u16) << 2) | ((lsbs >> 6) & 0x3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 10-bit ramp covering every sample value
+    fn ramp(len: usize) -> Vec<u16> {
+        (0..len).map(|i| (i * 37 % 1024) as u16).collect()
+    }
+
+    fn round_trip(fourcc: &str, description: &str) {
+        let format = RawFormat::from_fourcc(fourcc, description).unwrap();
+        let (width, height) = (16, 4);
+        // Padded lines, as the stock sensor mode has
+        let stride = format.min_stride(width) + 8;
+        let samples = ramp(width * height);
+        let mut raw = vec![0xAAu8; stride * height];
+        for (row, line) in samples.chunks_exact(width).zip(raw.chunks_exact_mut(stride)) {
+            format.pack_line(row, line);
+        }
+        let mut out = vec![0u16; width * height];
+        format.unpack(&raw, stride, width, &mut out);
+        assert_eq!(out, samples, "{} ({:?})", fourcc, format.packing);
+    }
+
+    #[test]
+    fn packed10_round_trips() {
+        round_trip("pGAA", "");
+        // rkcif reports packed data under the unpacked fourcc
+        round_trip("GB10", "10-bit Bayer GBGB/RGRG Packed");
+    }
+
+    #[test]
+    fn unpacked16_round_trips() {
+        round_trip("GB10", "10-bit Bayer GBGB/RGRG");
+        round_trip("RG12", "12-bit Bayer RGRG/GBGB");
+    }
+
+    #[test]
+    fn packed12_round_trips() {
+        round_trip("pGCC", "");
+    }
+
+    #[test]
+    fn packed10_matches_the_csi2_layout() {
+        let format = RawFormat::from_fourcc("pGAA", "").unwrap();
+        // MSBs 0x01..0x04, LSBs 0, 1, 2, 3 from the low bits up
+        let raw = [0x01, 0x02, 0x03, 0x04, 0b11_10_01_00];
+        let mut out = [0u16; 4];
+        format.unpack(&raw, 5, 4, &mut out);
+        assert_eq!(out, [0x04, 0x09, 0x0E, 0x13]);
+    }
+
+    #[test]
+    fn unpacked12_drops_the_two_lsbs() {
+        let format = RawFormat::from_fourcc("GB12", "").unwrap();
+        let raw = 0x0FFFu16.to_le_bytes().into_iter().chain(0x0803u16.to_le_bytes()).collect::<Vec<_>>();
+        let mut out = [0u16; 2];
+        format.unpack(&raw, 4, 2, &mut out);
+        assert_eq!(out, [0x3FF, 0x200]);
+    }
+
+    #[test]
+    fn packed12_drops_the_two_lsbs() {
+        let format = RawFormat::from_fourcc("pGCC", "").unwrap();
+        // 12-bit samples 0xABC and 0x123
+        let raw = [0xAB, 0x12, 0x3C];
+        let mut out = [0u16; 2];
+        format.unpack(&raw, 3, 2, &mut out);
+        assert_eq!(out, [0xABC >> 2, 0x123 >> 2]);
+    }
+
+    #[test]
+    fn short_frames_leave_the_tail_untouched() {
+        let format = RawFormat::default();
+        let (width, stride) = (8, 10);
+        let samples = ramp(width);
+        let mut raw = vec![0u8; stride];
+        format.pack_line(&samples, &mut raw);
+        let mut out = vec![0xFFFFu16; width * 3];
+        format.unpack(&raw, stride, width, &mut out);
+        assert_eq!(&out[..width], &samples[..]);
+        assert!(out[width..].iter().all(|&v| v == 0xFFFF));
+    }
+}
@@ -0,0 +1,623 @@
+//! Recording to H.264 MP4 or MJPEG AVI segments
+//!
+//! A recording is a series of segment files named after the time each
+//! segment starts (`recording-<unix_ms>.mp4` or `.avi`); a new segment
+//! begins once the current one reaches the [`Rollover`] duration or size.
+//!
+//! MP4 recordings take access units from the shared H.264 encoder
+//! ([`crate::h264`]), as one more client of it. Each segment is a
+//! fragmented MP4 starting at an IDR frame, so segments roll over at the
+//! first keyframe past the limit. The encoder output is a few Mbit/s;
+//! should the card still fall behind, the encoder's client policy applies
+//! and the recording skips to the next IDR frame.
+//!
+//! AVI recordings (the fallback without a hardware encoder) store the
+//! published JPEG frames as they are, through a [`Spool`]: a writer task
+//! appends every frame to the spool, the muxer reads it in order into the
+//! segment files. Capture therefore never waits for the output file; when
+//! the card is slower than capture the spool grows (bounded, oldest chunks
+//! evicted) and drains once the load drops. AVI segments also roll over
+//! before [`avi::MAX_FILE_BYTES`] and when the frame size changes.
+//!
+//! With a [`PreBuffer`], a recording starts with the frames it holds, the
+//! seconds before the recording was asked for.
+//!
+//! Stopping a recording ends the current segment (AVI: after the spool has
+//! drained). After a crash, AVI segments left without their header are
+//! completed ([`avi::repair`]) and frames left in the spool are written to
+//! `recovered-<unix_ms>.avi` at startup. An MP4 segment is playable up to
+//! its last complete fragment as it is.
+
+use crate::avi;
+use crate::events::{DomainEvent, EventBus};
+use crate::h264::{self, H264Stream};
+use crate::prebuffer::{PreBuffer, PreBufferStats};
+use crate::push::PushFrame;
+use crate::spool::{Spool, SpoolFrame, SpoolStats};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::sync::watch;
+
+/// Output directory before the versioned data layout ([`crate::storage`]);
+/// moved into the layout at startup
+pub const LEGACY_RECORD_DIR: &str = "/home/angelo/imx415_streamer/recordings";
+
+/// Spool subdirectory the output directory had before the versioned layout
+pub const LEGACY_SPOOL_DIR: &str = ".spool";
+
+/// Default segment length (seconds)
+pub const DEFAULT_SEGMENT_SECS: u64 = 600;
+
+/// Partially filled batches are written at least this often, so the muxer
+/// never lags by more than this at low frame rates
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// AVI frame interval until two frames give a real one
+const DEFAULT_FRAME_MICROS: u32 = 1_000_000 / h264::FPS;
+
+/// What the recorder is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Idle,
+    Recording,
+    /// Stopped; the muxer is draining the spool
+    Finishing,
+    /// Writing frames recovered from the spool at startup
+    Recovering,
+}
+
+/// Container (and codec) of a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    /// H.264 in fragmented MP4
+    Mp4,
+    /// The published JPEGs in AVI
+    Avi,
+}
+
+impl RecordFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "mp4" => Some(Self::Mp4),
+            "avi" => Some(Self::Avi),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Avi => "avi",
+        }
+    }
+}
+
+/// When a segment ends and the next one starts (either limit; None: no limit)
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Rollover {
+    pub max_secs: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Rollover {
+    /// Whether a segment spanning `elapsed_ms` of frames, `bytes` long, is complete
+    fn due(&self, elapsed_ms: u64, bytes: u64) -> bool {
+        self.max_secs.is_some_and(|secs| elapsed_ms >= secs.saturating_mul(1000))
+            || self.max_bytes.is_some_and(|max| bytes >= max)
+    }
+}
+
+/// What a recording is made from
+pub enum Source {
+    /// Published frames, recorded as AVI
+    Frames(watch::Receiver<Option<PushFrame>>),
+    /// The shared H.264 encoder (already started), recorded as MP4
+    H264 { subscription: h264::Subscription, track: h264::Track, stream: Arc<H264Stream> },
+}
+
+impl Source {
+    fn format(&self) -> RecordFormat {
+        match self {
+            Self::Frames(_) => RecordFormat::Avi,
+            Self::H264 { .. } => RecordFormat::Mp4,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    phase: Phase,
+    format: Option<RecordFormat>,
+    /// Segment being written
+    file: Option<PathBuf>,
+    segments: u64,
+    started_ms: Option<u64>,
+    stop: Option<watch::Sender<bool>>,
+    last_error: Option<String>,
+}
+
+/// Body of GET /record/status
+#[derive(Debug, Serialize)]
+pub struct RecordStatus {
+    pub state: Phase,
+    pub format: Option<RecordFormat>,
+    /// Current (or last) segment
+    pub file: Option<PathBuf>,
+    pub segments: u64,
+    pub rollover: Rollover,
+    pub started_ms: Option<u64>,
+    pub frames_spooled: u64,
+    pub frames_written: u64,
+    pub bytes_written: u64,
+    pub last_error: Option<String>,
+    pub spool: SpoolStats,
+    /// None without a pre-event buffer
+    pub pre_event: Option<PreBufferStats>,
+}
+
+pub struct Recorder {
+    dir: PathBuf,
+    spool: Spool,
+    rollover: Rollover,
+    prebuffer: Option<Arc<PreBuffer>>,
+    session: Mutex<Session>,
+    events: Arc<EventBus>,
+    frames_spooled: AtomicU64,
+    frames_written: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Recorder {
+    /// Open the output directory and the spool (recovering leftover chunks)
+    pub fn open(
+        dir: &Path,
+        spool_dir: &Path,
+        spool_bytes: u64,
+        rollover: Rollover,
+        prebuffer: Option<Arc<PreBuffer>>,
+        events: Arc<EventBus>,
+    ) -> Result<Arc<Self>> {
+        let spool = Spool::open(spool_dir, spool_bytes)?;
+        Ok(Arc::new(Self {
+            dir: dir.to_path_buf(),
+            spool,
+            rollover,
+            prebuffer,
+            session: Mutex::new(Session {
+                phase: Phase::Idle,
+                format: None,
+                file: None,
+                segments: 0,
+                started_ms: None,
+                stop: None,
+                last_error: None,
+            }),
+            events,
+            frames_spooled: AtomicU64::new(0),
+            frames_written: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        }))
+    }
+
+    /// Complete AVI segments a crash left without a header (blocking)
+    pub fn repair_segments(&self) -> Vec<(PathBuf, u32)> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut repaired = Vec::new();
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_none_or(|ext| ext != RecordFormat::Avi.extension()) {
+                continue;
+            }
+            match avi::repair(&path) {
+                Ok(Some(frames)) => repaired.push((path, frames)),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to repair {}: {}", path.display(), e),
+            }
+        }
+        repaired
+    }
+
+    /// Write frames recovered from the spool, if any, in the background
+    pub fn recover(self: &Arc<Self>) -> Option<u64> {
+        let frames = self.spool.stats().depth_frames;
+        if frames == 0 {
+            return None;
+        }
+        let file = self.segment_path("recovered", RecordFormat::Avi, crate::unix_millis());
+        self.begin(Phase::Recovering, RecordFormat::Avi, file.clone(), None);
+        tokio::spawn(self.clone().mux("recovered", file));
+        Some(frames)
+    }
+
+    pub fn prebuffer(&self) -> Option<&Arc<PreBuffer>> {
+        self.prebuffer.as_ref()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.session.lock().phase == Phase::Idle
+    }
+
+    /// Start recording `source`, from the pre-event buffer on if there is
+    /// one; fails unless idle. Returns the first segment's path.
+    pub fn start(self: &Arc<Self>, source: Source) -> Result<PathBuf, Phase> {
+        let started_ms = crate::unix_millis();
+        let format = source.format();
+        let (stop_tx, stop_rx) = watch::channel(false);
+        {
+            let mut session = self.session.lock();
+            if session.phase != Phase::Idle {
+                return Err(session.phase);
+            }
+            session.stop = Some(stop_tx);
+        }
+        let file = match source {
+            Source::Frames(frames) => {
+                let backlog = self.prebuffer.as_ref().map(|p| p.frames()).unwrap_or_default();
+                let file = self.segment_path("recording", format, backlog.first().map_or(started_ms, |f| f.timestamp_ms));
+                self.begin(Phase::Recording, format, file.clone(), Some(started_ms));
+                self.spool.resume();
+                tokio::spawn(self.clone().spool_frames(backlog, frames, stop_rx));
+                tokio::spawn(self.clone().mux("recording", file.clone()));
+                file
+            }
+            Source::H264 { subscription, track, stream } => {
+                let backlog = self.prebuffer.as_ref().map(|p| p.units(&track)).unwrap_or_default();
+                let file = self.segment_path("recording", format, backlog.first().map_or(started_ms, |u| u.timestamp_ms));
+                self.begin(Phase::Recording, format, file.clone(), Some(started_ms));
+                // The live units start at the IDR frame subscribing asked for
+                let last_ms = backlog.last().map(|u| u.timestamp_ms);
+                let live = subscription
+                    .access_units(stream, track.clone())
+                    .filter(move |u| futures::future::ready(last_ms.is_none_or(|last| u.timestamp_ms > last)));
+                let units = futures::stream::iter(backlog).chain(live);
+                tokio::spawn(self.clone().record_h264(units, track, file.clone(), stop_rx));
+                file
+            }
+        };
+        self.events.publish(DomainEvent::RecordingStarted { file: file.clone() });
+        Ok(file)
+    }
+
+    /// Stop recording; the last segment is complete once the state
+    /// returns to idle
+    pub fn stop(&self) -> bool {
+        let mut session = self.session.lock();
+        match session.stop.take() {
+            Some(stop) => {
+                session.phase = Phase::Finishing;
+                let _ = stop.send(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> RecordStatus {
+        let session = self.session.lock();
+        RecordStatus {
+            state: session.phase,
+            format: session.format,
+            file: session.file.clone(),
+            segments: session.segments,
+            rollover: self.rollover,
+            started_ms: session.started_ms,
+            frames_spooled: self.frames_spooled.load(Ordering::Relaxed),
+            frames_written: self.frames_written.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            last_error: session.last_error.clone(),
+            spool: self.spool.stats(),
+            pre_event: self.prebuffer.as_ref().map(|p| p.stats()),
+        }
+    }
+
+    fn segment_path(&self, prefix: &str, format: RecordFormat, start_ms: u64) -> PathBuf {
+        self.dir.join(format!("{}-{}.{}", prefix, start_ms, format.extension()))
+    }
+
+    fn begin(&self, phase: Phase, format: RecordFormat, file: PathBuf, started_ms: Option<u64>) {
+        let mut session = self.session.lock();
+        session.phase = phase;
+        session.format = Some(format);
+        session.file = Some(file);
+        session.segments = 0;
+        session.started_ms = started_ms;
+        session.last_error = None;
+        self.frames_spooled.store(0, Ordering::Relaxed);
+        self.frames_written.store(0, Ordering::Relaxed);
+        self.bytes_written.store(0, Ordering::Relaxed);
+    }
+
+    /// A segment file was created
+    fn segment_started(&self, file: &Path) {
+        let mut session = self.session.lock();
+        session.file = Some(file.to_path_buf());
+        session.segments += 1;
+    }
+
+    fn fail(&self, context: &str, e: anyhow::Error) {
+        tracing::error!("Recording {}: {:#}", context, e);
+        let mut session = self.session.lock();
+        session.last_error = Some(format!("{}: {:#}", context, e));
+        if let Some(stop) = session.stop.take() {
+            session.phase = Phase::Finishing;
+            let _ = stop.send(true);
+        }
+    }
+
+    /// Back to idle; announces the end of a recording
+    fn finished(&self) {
+        let (file, recording) = {
+            let mut session = self.session.lock();
+            session.phase = Phase::Idle;
+            session.stop = None;
+            (session.file.clone().unwrap_or_default(), session.started_ms.is_some())
+        };
+        let frames = self.frames_written.load(Ordering::Relaxed);
+        tracing::info!("Recording {} finished ({} frames)", file.display(), frames);
+        // Recoveries aren't recordings anyone started
+        if recording {
+            let bytes = self.bytes_written.load(Ordering::Relaxed);
+            self.events.publish(DomainEvent::RecordingStopped { file, frames, bytes });
+        }
+    }
+
+    fn count_written(&self, bytes: usize) {
+        self.frames_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Writer: the `backlog`, then published frames into the spool until
+    /// stopped
+    async fn spool_frames(
+        self: Arc<Self>,
+        backlog: Vec<SpoolFrame>,
+        mut frames: watch::Receiver<Option<PushFrame>>,
+        mut stop: watch::Receiver<bool>,
+    ) {
+        let mut last_seq = None;
+        for frame in backlog {
+            last_seq = Some(frame.seq);
+            if let Err(e) = tokio::task::block_in_place(|| self.spool.push(&frame)) {
+                self.fail("spool write failed", e);
+                break;
+            }
+            self.frames_spooled.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                changed = frames.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let Some(frame) = frames.borrow_and_update().clone() else {
+                        continue;
+                    };
+                    // Already in the backlog
+                    if last_seq.is_some_and(|seq| frame.seq <= seq) {
+                        continue;
+                    }
+                    let frame = SpoolFrame { seq: frame.seq, timestamp_ms: frame.timestamp_ms, jpeg: frame.jpeg };
+                    if let Err(e) = tokio::task::block_in_place(|| self.spool.push(&frame)) {
+                        self.fail("spool write failed", e);
+                        break;
+                    }
+                    self.frames_spooled.fetch_add(1, Ordering::Relaxed);
+                }
+                _ = flush.tick() => {
+                    if let Err(e) = tokio::task::block_in_place(|| self.spool.flush()) {
+                        self.fail("spool write failed", e);
+                        break;
+                    }
+                }
+                _ = stop.changed() => break,
+            }
+        }
+        if let Err(e) = tokio::task::block_in_place(|| self.spool.finish()) {
+            self.fail("spool write failed", e);
+        }
+    }
+
+    /// Muxer: spooled frames into AVI segments until the spool is finished
+    /// and drained
+    async fn mux(self: Arc<Self>, prefix: &'static str, first: PathBuf) {
+        if let Err(e) = self.mux_into(prefix, first).await {
+            self.fail("output write failed", e);
+            // Nothing can consume the spool now; drop what's left
+            while let Ok(Some(_)) = self.spool.next().await {}
+        }
+        self.finished();
+    }
+
+    async fn mux_into(&self, prefix: &str, first: PathBuf) -> Result<()> {
+        create_dir(&self.dir).await?;
+        let mut path = Some(first);
+        let mut segment: Option<AviSegment> = None;
+        while let Some(frame) = self.spool.next().await? {
+            let dimensions = avi::jpeg_dimensions(&frame.jpeg).unwrap_or_default();
+            if let Some(current) = segment.take_if(|s| {
+                !s.fits(frame.jpeg.len())
+                    || s.dimensions() != dimensions
+                    || self.rollover.due(frame.timestamp_ms.saturating_sub(s.first_ms), s.bytes())
+            }) {
+                current.finish().await?;
+            }
+            let current = match segment {
+                Some(ref mut current) => current,
+                None => {
+                    let path = path.take().unwrap_or_else(|| self.segment_path(prefix, RecordFormat::Avi, frame.timestamp_ms));
+                    self.segment_started(&path);
+                    segment.insert(AviSegment::create(path, dimensions, frame.timestamp_ms).await?)
+                }
+            };
+            current.write(&frame).await?;
+            self.count_written(frame.jpeg.len());
+        }
+        if let Some(current) = segment {
+            current.finish().await?;
+        }
+        Ok(())
+    }
+
+    /// Access units into MP4 segments until stopped
+    async fn record_h264(
+        self: Arc<Self>,
+        units: impl futures::Stream<Item = Arc<h264::AccessUnit>> + Send + 'static,
+        track: h264::Track,
+        first: PathBuf,
+        stop: watch::Receiver<bool>,
+    ) {
+        if let Err(e) = self.record_h264_into(units, &track, first, stop).await {
+            self.fail("output write failed", e);
+        }
+        self.finished();
+    }
+
+    async fn record_h264_into(
+        &self,
+        units: impl futures::Stream<Item = Arc<h264::AccessUnit>>,
+        track: &h264::Track,
+        first: PathBuf,
+        mut stop: watch::Receiver<bool>,
+    ) -> Result<()> {
+        create_dir(&self.dir).await?;
+        let init = track.init_segment();
+        let mut units = std::pin::pin!(units);
+        let mut path = Some(first);
+        let mut segment: Option<Mp4Segment> = None;
+        loop {
+            let unit = tokio::select! {
+                unit = units.next() => unit.context("H.264 stream ended (encoder stopped or frame size changed)")?,
+                _ = stop.changed() => break,
+            };
+            // Units start at an IDR frame; later segments do too
+            if let Some(current) = segment.take_if(|s| {
+                unit.keyframe && self.rollover.due(unit.timestamp_ms.saturating_sub(s.first_ms), s.bytes)
+            }) {
+                current.finish().await?;
+            }
+            let current = match segment {
+                Some(ref mut current) => current,
+                None => {
+                    let path = path.take().unwrap_or_else(|| self.segment_path("recording", RecordFormat::Mp4, unit.timestamp_ms));
+                    self.segment_started(&path);
+                    segment.insert(Mp4Segment::create(path, &init, unit.timestamp_ms).await?)
+                }
+            };
+            let fragment = current.muxer.mux(&unit);
+            current.out.write_all(&fragment).await?;
+            current.bytes += fragment.len() as u64;
+            self.count_written(fragment.len());
+        }
+        if let Some(current) = segment {
+            current.finish().await?;
+        }
+        Ok(())
+    }
+}
+
+async fn create_dir(dir: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))
+}
+
+async fn create_file(path: &Path) -> Result<BufWriter<tokio::fs::File>> {
+    let file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+/// An MP4 segment being written
+struct Mp4Segment {
+    out: BufWriter<tokio::fs::File>,
+    muxer: h264::Muxer,
+    first_ms: u64,
+    bytes: u64,
+}
+
+impl Mp4Segment {
+    async fn create(path: PathBuf, init: &[u8], first_ms: u64) -> Result<Self> {
+        let mut out = create_file(&path).await?;
+        out.write_all(init).await?;
+        Ok(Self { out, muxer: h264::Muxer::default(), first_ms, bytes: init.len() as u64 })
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        self.out.flush().await?;
+        Ok(())
+    }
+}
+
+/// An AVI segment being written: a placeholder header, the frames, and on
+/// finish the index and the real header
+struct AviSegment {
+    out: BufWriter<tokio::fs::File>,
+    summary: avi::Summary,
+    index: Vec<avi::IndexEntry>,
+    first_ms: u64,
+    last_ms: u64,
+}
+
+impl AviSegment {
+    async fn create(path: PathBuf, (width, height): (u32, u32), first_ms: u64) -> Result<Self> {
+        let mut out = create_file(&path).await?;
+        out.write_all(&[0; avi::HEADER_LEN]).await?;
+        let summary = avi::Summary { width, height, ..Default::default() };
+        Ok(Self { out, summary, index: Vec::new(), first_ms, last_ms: first_ms })
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        (self.summary.width, self.summary.height)
+    }
+
+    /// File size once finished
+    fn bytes(&self) -> u64 {
+        avi::HEADER_LEN as u64 + self.summary.movi_bytes as u64 + 8 + avi::INDEX_ENTRY_LEN * self.index.len() as u64
+    }
+
+    /// Whether a `jpeg_len` frame still fits the size limit
+    fn fits(&self, jpeg_len: usize) -> bool {
+        self.bytes() + 8 + jpeg_len as u64 + 1 + avi::INDEX_ENTRY_LEN <= avi::MAX_FILE_BYTES
+    }
+
+    async fn write(&mut self, frame: &SpoolFrame) -> Result<()> {
+        let (header, padding) = avi::frame_chunk(frame.jpeg.len());
+        self.out.write_all(&header).await?;
+        self.out.write_all(&frame.jpeg).await?;
+        self.out.write_all(padding).await?;
+        self.index.push(avi::IndexEntry { offset: 4 + self.summary.movi_bytes, size: frame.jpeg.len() as u32 });
+        self.summary.movi_bytes += (header.len() + frame.jpeg.len() + padding.len()) as u32;
+        self.summary.largest_frame = self.summary.largest_frame.max(frame.jpeg.len() as u32);
+        self.last_ms = frame.timestamp_ms;
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        let index = avi::index(&self.index);
+        self.out.write_all(&index).await?;
+        let frames = self.index.len() as u32;
+        self.summary.frames = frames;
+        self.summary.index_bytes = index.len() as u32;
+        self.summary.micros_per_frame = if frames > 1 {
+            (self.last_ms.saturating_sub(self.first_ms) * 1000 / (frames as u64 - 1)).clamp(1, u32::MAX as u64) as u32
+        } else {
+            DEFAULT_FRAME_MICROS
+        };
+        self.out.seek(std::io::SeekFrom::Start(0)).await?;
+        self.out.write_all(&avi::header(&self.summary)).await?;
+        self.out.flush().await?;
+        Ok(())
+    }
+}
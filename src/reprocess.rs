@@ -0,0 +1,277 @@
+//! Offline re-processing of saved raw frames
+//!
+//! `reprocess <dir>` runs every `*.raw` dump in a directory through the
+//! same `FrameCapture` pipeline live capture uses (unpack, black level,
+//! Gr/Gb balance, demosaic or grayscale extraction, white balance, gamma),
+//! with the settings of a saved profile, and writes the result next to the
+//! dump. Detection annotations are never drawn. Neither the camera nor the
+//! web server is needed.
+//!
+//! Files are spread over worker threads, each with its own pipeline. The
+//! scene state a live pipeline carries between frames (white balance and
+//! Gr/Gb estimates, tone curve) is reset for every file, so each output
+//! depends only on its own dump and the settings.
+
+use crate::capture::{self, CaptureConfig, CaptureMode, FrameCapture, FrameSize};
+use crate::demosaic::DemosaicAlgorithm;
+use crate::pipeline::{PipelineSettings, SettingsCell};
+use crate::greenbalance::GreenBalanceMode;
+use crate::profiles::{QualitySetting, Settings};
+use crate::rawformat::RawFormat;
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat};
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Worker threads unless `--jobs` is given (each holds ~100 MB of buffers)
+pub const DEFAULT_JOBS: usize = 4;
+
+/// Output image format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Tiff,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "png" => Some(OutputFormat::Png),
+            "tiff" | "tif" => Some(OutputFormat::Tiff),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Tiff => "tiff",
+        }
+    }
+}
+
+/// What to process and how
+#[derive(Clone)]
+pub struct Options {
+    pub dir: PathBuf,
+    /// Pipeline settings (each worker publishes its own copy)
+    pub settings: PipelineSettings,
+    /// Layout of the dumps
+    pub raw_format: RawFormat,
+    pub size: FrameSize,
+    pub stride: usize,
+    pub output: OutputFormat,
+    pub jobs: usize,
+}
+
+impl Options {
+    /// Size of a complete dump
+    pub fn frame_bytes(&self) -> usize {
+        self.stride * self.size.height
+    }
+}
+
+/// Outcome for one dump
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileStatus {
+    Ok,
+    /// Not a full frame in the expected raw format; skipped
+    SizeMismatch,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub status: FileStatus,
+    /// Output file, or why there is none
+    pub detail: String,
+    pub duration_ms: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Sorted by path
+    pub files: Vec<FileReport>,
+    pub duration_ms: f64,
+}
+
+impl Report {
+    /// True when every file was processed
+    pub fn passed(&self) -> bool {
+        self.files.iter().all(|f| f.status == FileStatus::Ok)
+    }
+}
+
+/// Pipeline configuration for a profile's settings; also returns the
+/// settings that only matter live (sensor controls, metering, detection)
+pub fn pipeline_config(settings: &Settings) -> Result<(PipelineSettings, Vec<String>), String> {
+    let mut config = PipelineSettings::default();
+    let mut ignored: Vec<String> = settings.controls.keys().cloned().collect();
+    if let Some(ref mode) = settings.mode {
+        config.mode = CaptureMode::parse(mode).ok_or_else(|| format!("Invalid mode '{}'", mode))?;
+    }
+    if let Some(ref name) = settings.demosaic {
+        config.demosaic = DemosaicAlgorithm::parse(name).ok_or_else(|| format!("Invalid demosaic '{}'", name))?;
+    }
+    if let Some(QualitySetting::Auto { max, .. }) = settings.quality {
+        // Stills use the top of the adaptive range
+        config.jpeg_quality = max.clamp(1, 100);
+    }
+    if let Some(gamma) = settings.gamma {
+        if !(capture::MIN_GAMMA..=capture::MAX_GAMMA).contains(&gamma) {
+            return Err(format!("gamma must be between {} and {}", capture::MIN_GAMMA, capture::MAX_GAMMA));
+        }
+        config.gamma = gamma;
+    }
+    if let Some(enabled) = settings.white_balance {
+        config.enable_white_balance = enabled;
+    }
+    if let Some(strength) = settings.tonemap_strength {
+        if !(0.0..=1.0).contains(&strength) {
+            return Err("tonemap_strength must be between 0.0 and 1.0".to_string());
+        }
+        config.tonemap_strength = strength;
+    }
+    if let Some(black_level) = settings.black_level {
+        if black_level > 1022 {
+            return Err("black_level must be at most 1022".to_string());
+        }
+        config.black_level = black_level;
+    }
+    if let Some(row_noise) = settings.row_noise {
+        config.row_noise_correction = row_noise.enabled;
+        config.row_noise_strength = row_noise.strength.clamp(0.0, 1.0);
+    }
+    if settings.metering.is_some() {
+        ignored.push("metering".to_string());
+    }
+    if settings.detection.is_some() {
+        ignored.push("detection".to_string());
+    }
+    Ok((config, ignored))
+}
+
+/// Output file name part describing the settings, e.g.
+/// `color_g2.20_bl64_wb_tm0.30_gbauto` (`_malvar` when not bilinear)
+pub fn settings_tag(config: &PipelineSettings) -> String {
+    let mut parts = Vec::new();
+    match config.mode {
+        CaptureMode::Color | CaptureMode::ColorHdr => {
+            parts.push("color".to_string());
+            parts.push(format!("g{:.2}", config.gamma));
+            parts.push(format!("bl{}", config.black_level));
+            parts.push(if config.enable_white_balance { "wb" } else { "nowb" }.to_string());
+            if config.tonemap_strength > 0.0 {
+                parts.push(format!("tm{:.2}", config.tonemap_strength));
+            }
+            match config.green_balance.mode {
+                GreenBalanceMode::Off => {}
+                GreenBalanceMode::Auto => parts.push("gbauto".to_string()),
+                GreenBalanceMode::Fixed(ratio) => parts.push(format!("gb{:.4}", ratio)),
+            }
+            if config.demosaic != DemosaicAlgorithm::Bilinear {
+                parts.push(config.demosaic.name().to_string());
+            }
+        }
+        CaptureMode::Grayscale | CaptureMode::GrayscaleHdr => {
+            parts.push("gray".to_string());
+            parts.push(format!("bl{}", config.black_level));
+            if config.row_noise_correction {
+                parts.push(format!("rn{:.2}", config.row_noise_strength));
+            }
+        }
+    }
+    parts.join("_")
+}
+
+/// The `*.raw` files in `dir`, sorted
+fn raw_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "raw") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Process every dump in `options.dir`; `on_file` sees each file as it
+/// finishes (from the worker threads, in completion order)
+pub fn run(options: &Options, on_file: impl Fn(&FileReport) + Sync) -> Result<Report> {
+    let start = Instant::now();
+    let files = raw_files(&options.dir)?;
+    let tag = settings_tag(&options.settings);
+    let next = AtomicUsize::new(0);
+    let reports = Mutex::new(Vec::with_capacity(files.len()));
+
+    let worker = || -> Result<()> {
+        let settings = Arc::new(SettingsCell::new(options.settings.clone()));
+        let mut capture = FrameCapture::with_config(CaptureConfig::default(), settings)?;
+        capture.set_raw_format(options.raw_format.clone(), options.size, options.stride)?;
+        while let Some(path) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let report = process_file(&mut capture, path, &tag, options);
+            on_file(&report);
+            reports.lock().push(report);
+        }
+        Ok(())
+    };
+    let workers = options.jobs.clamp(1, files.len().max(1));
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers).map(|_| scope.spawn(worker)).collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap_or_else(|_| Err(anyhow::anyhow!("Worker panicked"))))
+    })?;
+
+    let mut files = reports.into_inner();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(Report { files, duration_ms: start.elapsed().as_secs_f64() * 1000.0 })
+}
+
+fn process_file(capture: &mut FrameCapture, path: &Path, tag: &str, options: &Options) -> FileReport {
+    let start = Instant::now();
+    let outcome = (|| -> Result<(FileStatus, String)> {
+        let raw = std::fs::read(path).context("Failed to read")?;
+        if raw.len() != options.frame_bytes() {
+            return Ok((
+                FileStatus::SizeMismatch,
+                format!("{} bytes, expected {}", raw.len(), options.frame_bytes()),
+            ));
+        }
+        capture.reset_scene_state();
+        let image = capture.process_raw_image(&raw)?;
+        let output = path.with_extension(format!("{}.{}", tag, options.output.extension()));
+        write_image(&image, &output, options.output, capture.snapshot_quality())?;
+        Ok((FileStatus::Ok, output.display().to_string()))
+    })();
+    let (status, detail) = outcome.unwrap_or_else(|e| (FileStatus::Failed, format!("{:#}", e)));
+    FileReport {
+        path: path.to_path_buf(),
+        status,
+        detail,
+        duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }
+}
+
+/// Write `image` to `path` in `format` (JPEG at `quality`)
+pub fn write_image(image: &DynamicImage, path: &Path, format: OutputFormat, quality: u8) -> Result<()> {
+    match format {
+        OutputFormat::Jpeg => std::fs::write(path, capture::encode_image_jpeg(image, quality)?)
+            .with_context(|| format!("Failed to write {}", path.display())),
+        OutputFormat::Png => image
+            .save_with_format(path, ImageFormat::Png)
+            .with_context(|| format!("Failed to write {}", path.display())),
+        OutputFormat::Tiff => image
+            .save_with_format(path, ImageFormat::Tiff)
+            .with_context(|| format!("Failed to write {}", path.display())),
+    }
+}
@@ -0,0 +1,120 @@
+//! Scaling on the Rockchip RGA 2D accelerator through librga's im2d API
+//! (`rga` feature)
+//!
+//! Buffers are passed by virtual address, so librga maps (or, where the
+//! RGA can't reach the memory, copies) them for each call; calls are
+//! synchronous. Interpolation is bilinear, like the CPU loops it replaces,
+//! but the hardware rounds differently, so the output is not bit-identical
+//! to them.
+
+use anyhow::Result;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::ptr;
+
+type ImStatus = c_int;
+
+/// `RK_FORMAT_RGB_888`
+const RK_FORMAT_RGB_888: c_int = 0x2 << 8;
+/// `RK_FORMAT_YCbCr_400`: 8-bit luma only
+const RK_FORMAT_YCBCR_400: c_int = 0x15 << 8;
+/// `IM_INTERP_LINEAR`
+const IM_INTERP_LINEAR: c_int = 1;
+
+/// `im_colorkey_range`
+#[repr(C)]
+struct ColorKeyRange {
+    max: c_int,
+    min: c_int,
+}
+
+/// `im_nn_t`
+#[repr(C)]
+struct NnParams {
+    scale_r: c_int,
+    scale_g: c_int,
+    scale_b: c_int,
+    offset_r: c_int,
+    offset_g: c_int,
+    offset_b: c_int,
+}
+
+/// `rga_buffer_t` (im2d_type.h)
+#[repr(C)]
+struct RgaBuffer {
+    vir_addr: *mut c_void,
+    phy_addr: *mut c_void,
+    fd: c_int,
+    width: c_int,
+    height: c_int,
+    wstride: c_int,
+    hstride: c_int,
+    format: c_int,
+    color_space_mode: c_int,
+    global_alpha: c_int,
+    rd_mode: c_int,
+    color: c_int,
+    colorkey_range: ColorKeyRange,
+    nn: NnParams,
+    rop_code: c_int,
+    handle: c_int,
+}
+
+impl RgaBuffer {
+    /// A packed buffer in CPU memory
+    fn virtual_address(data: *mut u8, width: usize, height: usize, format: c_int) -> Self {
+        Self {
+            vir_addr: data as *mut c_void,
+            phy_addr: ptr::null_mut(),
+            fd: -1,
+            width: width as c_int,
+            height: height as c_int,
+            wstride: width as c_int,
+            hstride: height as c_int,
+            format,
+            color_space_mode: 0,
+            global_alpha: 0xff,
+            rd_mode: 0,
+            color: 0,
+            colorkey_range: ColorKeyRange { max: 0, min: 0 },
+            nn: NnParams { scale_r: 0, scale_g: 0, scale_b: 0, offset_r: 0, offset_g: 0, offset_b: 0 },
+            rop_code: 0,
+            handle: 0,
+        }
+    }
+}
+
+#[link(name = "rga")]
+extern "C" {
+    fn imresize_t(src: RgaBuffer, dst: RgaBuffer, fx: f64, fy: f64, interpolation: c_int, sync: c_int) -> ImStatus;
+    fn imStrError_t(status: ImStatus) -> *const c_char;
+}
+
+/// Scale packed 8-bit pixels (`channels` 1 for gray, 3 for RGB) from
+/// `src_width` x `src_height` to `dst_width` x `dst_height`
+pub fn resize(
+    src: &[u8],
+    (src_width, src_height): (usize, usize),
+    dst: &mut [u8],
+    (dst_width, dst_height): (usize, usize),
+    channels: usize,
+) -> Result<()> {
+    let format = match channels {
+        1 => RK_FORMAT_YCBCR_400,
+        3 => RK_FORMAT_RGB_888,
+        _ => anyhow::bail!("RGA: unsupported pixel layout ({} channels)", channels),
+    };
+    anyhow::ensure!(src.len() >= src_width * src_height * channels, "RGA: source buffer too small");
+    anyhow::ensure!(dst.len() >= dst_width * dst_height * channels, "RGA: destination buffer too small");
+    // librga only reads through the source pointer
+    let src = RgaBuffer::virtual_address(src.as_ptr() as *mut u8, src_width, src_height, format);
+    let dst = RgaBuffer::virtual_address(dst.as_mut_ptr(), dst_width, dst_height, format);
+    // SAFETY: both buffers hold their whole image and outlive the
+    // synchronous call; fx/fy of 0 take the size from `dst`
+    let status = unsafe { imresize_t(src, dst, 0.0, 0.0, IM_INTERP_LINEAR, 1) };
+    if status <= 0 {
+        // SAFETY: librga returns a static string for any status
+        let message = unsafe { CStr::from_ptr(imStrError_t(status)) };
+        anyhow::bail!("RGA resize failed: {}", message.to_string_lossy());
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! RKNN/NPU detector backend (`rknn` feature)
+//!
+//! Runs a YOLOv5 (objects) or SCRFD (faces) `.rknn` model in-process
+//! through the RKNN runtime's C API (librknnrt), on the NPU core the Python
+//! script used. Frames go in as 8-bit RGB taken straight from the
+//! pipeline's pixels when the capture loop has them (no JPEG round trip);
+//! the runtime dequantizes the outputs, and the decoding and NMS are the
+//! shared ones in `yolo` and `scrfd`. Mean/std normalization is part of the
+//! exported models.
+
+use crate::detector::{Backend, DetectionResult, DetectionTask, FrameInput};
+use crate::{scrfd, yolo};
+use anyhow::{Context, Result};
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::ptr;
+
+type RknnContext = u64;
+
+const RKNN_SUCC: c_int = 0;
+const RKNN_QUERY_IN_OUT_NUM: c_int = 0;
+const RKNN_QUERY_INPUT_ATTR: c_int = 1;
+const RKNN_QUERY_OUTPUT_ATTR: c_int = 2;
+const RKNN_TENSOR_NCHW: c_int = 0;
+const RKNN_TENSOR_NHWC: c_int = 1;
+const RKNN_TENSOR_UINT8: c_int = 3;
+const RKNN_NPU_CORE_0: c_int = 1;
+const RKNN_MAX_DIMS: usize = 16;
+const RKNN_MAX_NAME_LEN: usize = 256;
+
+/// `rknn_input_output_num`
+#[repr(C)]
+#[derive(Default)]
+struct InputOutputNum {
+    n_input: u32,
+    n_output: u32,
+}
+
+/// `rknn_tensor_attr`
+#[repr(C)]
+struct TensorAttr {
+    index: u32,
+    n_dims: u32,
+    dims: [u32; RKNN_MAX_DIMS],
+    name: [c_char; RKNN_MAX_NAME_LEN],
+    n_elems: u32,
+    size: u32,
+    fmt: c_int,
+    kind: c_int,
+    qnt_type: c_int,
+    fl: i8,
+    zp: i32,
+    scale: f32,
+    w_stride: u32,
+    size_with_stride: u32,
+    pass_through: u8,
+    h_stride: u32,
+}
+
+impl TensorAttr {
+    fn new(index: u32) -> Self {
+        // SAFETY: all-zero is a valid value for this plain C struct
+        let mut attr: Self = unsafe { std::mem::zeroed() };
+        attr.index = index;
+        attr
+    }
+
+    fn dims(&self) -> &[u32] {
+        &self.dims[..(self.n_dims as usize).min(RKNN_MAX_DIMS)]
+    }
+}
+
+/// `rknn_input`
+#[repr(C)]
+struct Input {
+    index: u32,
+    buf: *mut c_void,
+    size: u32,
+    pass_through: u8,
+    kind: c_int,
+    fmt: c_int,
+}
+
+/// `rknn_output`
+#[repr(C)]
+struct Output {
+    want_float: u8,
+    is_prealloc: u8,
+    index: u32,
+    buf: *mut c_void,
+    size: u32,
+}
+
+#[link(name = "rknnrt")]
+extern "C" {
+    fn rknn_init(context: *mut RknnContext, model: *mut c_void, size: u32, flag: u32, extend: *mut c_void) -> c_int;
+    fn rknn_destroy(context: RknnContext) -> c_int;
+    fn rknn_query(context: RknnContext, cmd: c_int, info: *mut c_void, size: u32) -> c_int;
+    fn rknn_set_core_mask(context: RknnContext, core_mask: c_int) -> c_int;
+    fn rknn_inputs_set(context: RknnContext, n_inputs: u32, inputs: *mut Input) -> c_int;
+    fn rknn_run(context: RknnContext, extend: *mut c_void) -> c_int;
+    fn rknn_outputs_get(context: RknnContext, n_outputs: u32, outputs: *mut Output, extend: *mut c_void) -> c_int;
+    fn rknn_outputs_release(context: RknnContext, n_outputs: u32, outputs: *mut Output) -> c_int;
+}
+
+fn check(ret: c_int, what: &str) -> Result<()> {
+    if ret != RKNN_SUCC {
+        anyhow::bail!("{} failed ({})", what, ret);
+    }
+    Ok(())
+}
+
+pub struct RknnBackend {
+    context: RknnContext,
+    task: DetectionTask,
+    /// Square input side
+    input_size: u32,
+    /// Shape of each output (as the runtime reports it)
+    outputs: Vec<Vec<u32>>,
+}
+
+// The context is only used from the detector thread, through `&mut self`
+unsafe impl Send for RknnBackend {}
+
+impl RknnBackend {
+    /// Load a model whose input is one square 8-bit RGB image
+    pub fn load(path: &str, task: DetectionTask) -> Result<Self> {
+        tracing::info!("Loading RKNN {} model {}", task.name(), path);
+        let path_c = CString::new(path).context("Invalid model path")?;
+        let mut backend = Self { context: 0, task, input_size: 0, outputs: Vec::new() };
+        // The context is released by Drop if a step fails
+        unsafe {
+            // A size of 0 makes the model argument a path
+            check(
+                rknn_init(&mut backend.context, path_c.as_ptr() as *mut c_void, 0, 0, ptr::null_mut()),
+                "rknn_init",
+            )
+            .with_context(|| format!("Failed to load RKNN model {}", path))?;
+            check(rknn_set_core_mask(backend.context, RKNN_NPU_CORE_0), "rknn_set_core_mask")?;
+
+            let mut io = InputOutputNum::default();
+            backend.query(RKNN_QUERY_IN_OUT_NUM, &mut io)?;
+            anyhow::ensure!(io.n_input == 1, "Expected a model with one input, got {}", io.n_input);
+
+            let mut input = TensorAttr::new(0);
+            backend.query(RKNN_QUERY_INPUT_ATTR, &mut input)?;
+            backend.input_size = match (input.fmt, input.dims()) {
+                (RKNN_TENSOR_NHWC, &[1, h, w, 3]) | (RKNN_TENSOR_NCHW, &[1, 3, h, w]) if h == w => h,
+                (_, dims) => anyhow::bail!("Unsupported model input {:?} (format {})", dims, input.fmt),
+            };
+
+            for index in 0..io.n_output {
+                let mut output = TensorAttr::new(index);
+                backend.query(RKNN_QUERY_OUTPUT_ATTR, &mut output)?;
+                backend.outputs.push(output.dims().to_vec());
+            }
+        }
+        tracing::info!(
+            "RKNN model input {}x{}, {} outputs",
+            backend.input_size,
+            backend.input_size,
+            backend.outputs.len()
+        );
+        Ok(backend)
+    }
+
+    /// # Safety
+    /// `T` must be the struct the runtime fills for `cmd`
+    unsafe fn query<T>(&self, cmd: c_int, info: &mut T) -> Result<()> {
+        check(
+            rknn_query(self.context, cmd, info as *mut T as *mut c_void, std::mem::size_of::<T>() as u32),
+            "rknn_query",
+        )
+    }
+
+    /// Run the model on packed RGB input; returns each output as floats
+    fn run(&mut self, mut data: Vec<u8>) -> Result<Vec<Vec<f32>>> {
+        let mut input = Input {
+            index: 0,
+            buf: data.as_mut_ptr() as *mut c_void,
+            size: data.len() as u32,
+            pass_through: 0,
+            kind: RKNN_TENSOR_UINT8,
+            fmt: RKNN_TENSOR_NHWC,
+        };
+        let mut outputs: Vec<Output> = (0..self.outputs.len() as u32)
+            .map(|index| Output { want_float: 1, is_prealloc: 0, index, buf: ptr::null_mut(), size: 0 })
+            .collect();
+        let count = outputs.len() as u32;
+        // SAFETY: `data` outlives the synchronous run; the runtime owns the
+        // output buffers until they are released below
+        unsafe {
+            check(rknn_inputs_set(self.context, 1, &mut input), "rknn_inputs_set")?;
+            check(rknn_run(self.context, ptr::null_mut()), "rknn_run")?;
+            check(rknn_outputs_get(self.context, count, outputs.as_mut_ptr(), ptr::null_mut()), "rknn_outputs_get")?;
+            let values = outputs
+                .iter()
+                .map(|o| std::slice::from_raw_parts(o.buf as *const f32, o.size as usize / 4).to_vec())
+                .collect();
+            rknn_outputs_release(self.context, count, outputs.as_mut_ptr());
+            Ok(values)
+        }
+    }
+}
+
+impl Backend for RknnBackend {
+    fn infer(&mut self, frame: &FrameInput) -> Result<DetectionResult> {
+        let image = frame.image()?;
+        let (data, mapping) = match self.task {
+            DetectionTask::Objects => yolo::fit_rgb8(&image, self.input_size, true, yolo::LETTERBOX_PAD_RGB8),
+            // SCRFD is anchored top-left on black
+            DetectionTask::Faces => yolo::fit_rgb8(&image, self.input_size, false, 0),
+        };
+        let values = self.run(data)?;
+
+        let detections = match self.task {
+            DetectionTask::Objects => {
+                // [1, 3 * (5 + classes), h, w] per stride
+                let tensors: Vec<_> = self
+                    .outputs
+                    .iter()
+                    .zip(values)
+                    .filter_map(|(dims, values)| match *dims.as_slice() {
+                        [.., h, w] => Some((h as usize, w as usize, values)),
+                        _ => None,
+                    })
+                    .collect();
+                yolo::decode_yolov5(&tensors, &mapping)
+            }
+            DetectionTask::Faces => {
+                let tensors: Vec<_> = self
+                    .outputs
+                    .iter()
+                    .zip(values)
+                    .map(|(dims, values)| (dims.last().copied().unwrap_or(1) as usize, values))
+                    .collect();
+                scrfd::decode(&tensors, self.input_size, &mapping)?
+            }
+        };
+
+        Ok(DetectionResult {
+            width: Some(mapping.src_width),
+            height: Some(mapping.src_height),
+            detections,
+            ..Default::default()
+        })
+    }
+}
+
+impl Drop for RknnBackend {
+    fn drop(&mut self) {
+        if self.context != 0 {
+            // SAFETY: the context came from rknn_init and is not used again
+            unsafe { rknn_destroy(self.context) };
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Bilinear image scaling with precomputed taps
+//!
+//! Source positions are computed once per resolution pair in 64-bit fixed
+//! point, `dst * (src - 1) / (dst - 1)` rounded to the nearest 1/65536, so
+//! the first and last destination pixels land exactly on the first and last
+//! source pixels and no tap reads past the edge. Pixels are blended in one
+//! step and rounded once, rather than truncated after each axis (which
+//! darkens the image by up to a level and shows at the edges).
+
+/// Fractional bits of a tap weight
+const FRAC_BITS: u32 = 16;
+const ONE: u64 = 1 << FRAC_BITS;
+
+/// Source samples and weight for one destination coordinate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tap {
+    pub index0: usize,
+    /// `index0 + 1`, or `index0` at the last source sample
+    pub index1: usize,
+    /// Weight of `index1`, in 1/65536
+    pub frac: u32,
+}
+
+/// Taps mapping `dst` coordinates onto `src` samples, endpoints aligned
+pub fn axis_taps(src: usize, dst: usize) -> Vec<Tap> {
+    let last = src.saturating_sub(1);
+    if dst <= 1 || last == 0 {
+        return vec![Tap { index0: 0, index1: 0, frac: 0 }; dst];
+    }
+    let span = (dst - 1) as u64;
+    (0..dst as u64)
+        .map(|i| {
+            let position = (i * last as u64 * ONE + span / 2) / span;
+            let index0 = ((position >> FRAC_BITS) as usize).min(last);
+            Tap {
+                index0,
+                index1: (index0 + 1).min(last),
+                frac: (position & (ONE - 1)) as u32,
+            }
+        })
+        .collect()
+}
+
+/// Bilinear scaler for one source and destination size
+#[derive(Debug, Clone)]
+pub struct BilinearScaler {
+    src_width: usize,
+    src_height: usize,
+    columns: Vec<Tap>,
+    rows: Vec<Tap>,
+}
+
+impl BilinearScaler {
+    pub fn new(src_width: usize, src_height: usize, dst_width: usize, dst_height: usize) -> Self {
+        Self {
+            src_width,
+            src_height,
+            columns: axis_taps(src_width, dst_width),
+            rows: axis_taps(src_height, dst_height),
+        }
+    }
+
+    pub fn dst_width(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn dst_height(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Scale interleaved 8-bit pixels with `channels` samples each
+    ///
+    /// Panics if either buffer is smaller than its size calls for.
+    pub fn scale(&self, src: &[u8], dst: &mut [u8], channels: usize) {
+        let src_stride = self.src_width * channels;
+        let dst_stride = self.dst_width() * channels;
+        assert!(src.len() >= src_stride * self.src_height, "source buffer too small");
+        assert!(dst.len() >= dst_stride * self.dst_height(), "destination buffer too small");
+
+        // Horizontally interpolated source rows (weights still applied),
+        // kept while consecutive destination rows share them
+        let mut upper = vec![0u32; dst_stride];
+        let mut lower = vec![0u32; dst_stride];
+        let mut loaded = None;
+        let source_row = |index: usize| &src[index * src_stride..][..src_stride];
+        for (row, tap_y) in dst.chunks_exact_mut(dst_stride).zip(&self.rows) {
+            if loaded != Some((tap_y.index0, tap_y.index1)) {
+                match loaded {
+                    Some((_, previous1)) if previous1 == tap_y.index0 => std::mem::swap(&mut upper, &mut lower),
+                    _ => self.interpolate_row(source_row(tap_y.index0), &mut upper, channels),
+                }
+                self.interpolate_row(source_row(tap_y.index1), &mut lower, channels);
+                loaded = Some((tap_y.index0, tap_y.index1));
+            }
+            let wy1 = tap_y.frac as u64;
+            let wy0 = ONE - wy1;
+            for (out, (&top, &bottom)) in row.iter_mut().zip(upper.iter().zip(&lower)) {
+                // Both weights are 16-bit: one rounding at the end
+                *out = ((top as u64 * wy0 + bottom as u64 * wy1 + ONE * ONE / 2) >> (2 * FRAC_BITS)) as u8;
+            }
+        }
+    }
+
+    /// Blend one source row along x, scaled by 65536
+    fn interpolate_row(&self, src_row: &[u8], out: &mut [u32], channels: usize) {
+        for (pixel, tap) in out.chunks_exact_mut(channels).zip(&self.columns) {
+            let wx1 = tap.frac;
+            let wx0 = ONE as u32 - wx1;
+            let a = &src_row[tap.index0 * channels..][..channels];
+            let b = &src_row[tap.index1 * channels..][..channels];
+            for ((out, &a), &b) in pixel.iter_mut().zip(a).zip(b) {
+                *out = a as u32 * wx0 + b as u32 * wx1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_hit_the_endpoints_exactly_and_stay_in_bounds() {
+        // 150 x 75 = 11250 size pairs, down- and upscaling
+        for src in 1..=150 {
+            for dst in 1..=75 {
+                let taps = axis_taps(src, dst);
+                assert_eq!(taps.len(), dst);
+                assert_eq!((taps[0].index0, taps[0].frac), (0, 0), "{} -> {}", src, dst);
+                if dst > 1 {
+                    let last = taps[dst - 1];
+                    assert_eq!((last.index0, last.frac), (src - 1, 0), "{} -> {}", src, dst);
+                }
+                for tap in &taps {
+                    assert!(tap.index0 < src && tap.index1 < src, "{} -> {}: {:?}", src, dst, tap);
+                    let degenerate = tap.index1 == tap.index0 && tap.frac == 0;
+                    assert!(tap.index1 == tap.index0 + 1 || degenerate, "{} -> {}: {:?}", src, dst, tap);
+                    assert!((tap.frac as u64) < ONE);
+                }
+                let positions: Vec<u64> =
+                    taps.iter().map(|t| ((t.index0 as u64) << FRAC_BITS) + t.frac as u64).collect();
+                assert!(positions.windows(2).all(|w| w[0] <= w[1]), "{} -> {}", src, dst);
+            }
+        }
+    }
+
+    #[test]
+    fn scaling_keeps_edges_and_flat_fields() {
+        let (w, h) = (37, 23);
+        let src: Vec<u8> = (0..w * h).map(|i| ((i % w) * 255 / (w - 1)) as u8).collect();
+        for (dw, dh) in [(10, 7), (64, 40), (37, 23), (2, 2)] {
+            let scaler = BilinearScaler::new(w, h, dw, dh);
+            let mut dst = vec![0u8; dw * dh];
+            scaler.scale(&src, &mut dst, 1);
+            for row in dst.chunks_exact(dw) {
+                assert_eq!((row[0], row[dw - 1]), (0, 255), "{}x{}", dw, dh);
+            }
+        }
+        let flat = vec![77u8; w * h * 3];
+        let scaler = BilinearScaler::new(w, h, 50, 11);
+        let mut dst = vec![0u8; 50 * 11 * 3];
+        scaler.scale(&flat, &mut dst, 3);
+        assert!(dst.iter().all(|&v| v == 77));
+    }
+
+    #[test]
+    fn same_size_is_the_identity() {
+        let (w, h) = (19, 13);
+        let src: Vec<u8> = (0..w * h * 3).map(|i| (i * 31 % 256) as u8).collect();
+        let mut dst = vec![0u8; src.len()];
+        BilinearScaler::new(w, h, w, h).scale(&src, &mut dst, 3);
+        assert_eq!(dst, src);
+    }
+}
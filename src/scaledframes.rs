@@ -0,0 +1,176 @@
+//! Downscaled and re-encoded encodings of published frames
+//!
+//! Stream clients that ask for a lower resolution (`/stream/1080p`,
+//! `?res=720p`) or another quality (`?quality=50`) would otherwise each
+//! scale and re-encode every 4K frame. Encodings are shared instead, keyed
+//! by the source frame's hash, the output width (None for full resolution)
+//! and the quality: the first client to want a frame at a resolution
+//! encodes it from the frame's pixels (see `DecodeCache`, which has the
+//! pipeline's pre-encode pixels of the current frame) and the rest wait for
+//! that encode.
+
+use crate::capture::ScalerKind;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use image::DynamicImage;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+/// Encodings kept per output width: the annotated and the clean view of
+/// the current frame
+pub const ENTRIES_PER_WIDTH: usize = 2;
+
+/// An encode, finished or in flight
+type Slot = Arc<OnceCell<Bytes>>;
+
+/// Encodings at one output width, oldest first
+type Encoded = VecDeque<(Key, Slot)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    hash: u64,
+    quality: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScaledFramesStats {
+    /// Output widths with encodings
+    pub widths: Vec<u32>,
+    /// Full-resolution re-encodes are held
+    pub full_resolution: bool,
+    /// JPEG bytes held
+    pub bytes: usize,
+    /// Encodes started
+    pub encodes: u64,
+    /// Requests served by an earlier or in-flight encode
+    pub hits: u64,
+}
+
+pub struct ScaledFrames {
+    scaler: ScalerKind,
+    /// Latest encodings by output width (None: full resolution)
+    entries: Mutex<Vec<(Option<u32>, Encoded)>>,
+    encodes: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl ScaledFrames {
+    pub fn new(scaler: ScalerKind) -> Self {
+        Self { scaler, entries: Mutex::default(), encodes: AtomicU64::new(0), hits: AtomicU64::new(0) }
+    }
+
+    /// The frame whose hash is `hash` at `max_width` (None: full
+    /// resolution) and `quality`; `pixels` is only called by the request
+    /// that encodes it
+    pub async fn get<F, P>(&self, hash: u64, max_width: Option<u32>, quality: u8, pixels: F) -> Result<Bytes>
+    where
+        F: FnOnce() -> P,
+        P: std::future::Future<Output = Result<Arc<DynamicImage>>>,
+    {
+        let key = Key { hash, quality };
+        let slot = {
+            let mut entries = self.entries.lock();
+            let index = match entries.iter().position(|(width, _)| *width == max_width) {
+                Some(index) => index,
+                None => {
+                    entries.push((max_width, VecDeque::new()));
+                    entries.len() - 1
+                }
+            };
+            let encoded = &mut entries[index].1;
+            match encoded.iter().find(|(k, _)| *k == key) {
+                Some((_, slot)) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    slot.clone()
+                }
+                None => {
+                    if encoded.len() >= ENTRIES_PER_WIDTH {
+                        encoded.pop_front();
+                    }
+                    let slot = Slot::default();
+                    encoded.push_back((key, slot.clone()));
+                    slot
+                }
+            }
+        };
+        // If the encoding request goes away, a waiting one takes over
+        let jpeg = slot
+            .get_or_try_init(|| async {
+                self.encodes.fetch_add(1, Ordering::Relaxed);
+                let pixels = pixels().await?;
+                let scaler = self.scaler;
+                let jpeg = tokio::task::spawn_blocking(move || {
+                    crate::capture::scaled_jpeg(&pixels, max_width, quality, scaler)
+                })
+                .await
+                .context("Encode task failed")??;
+                Ok::<_, anyhow::Error>(Bytes::from(jpeg))
+            })
+            .await?;
+        Ok(jpeg.clone())
+    }
+
+    /// Bytes held by finished encodings
+    pub fn bytes(&self) -> usize {
+        self.entries
+            .lock()
+            .iter()
+            .flat_map(|(_, encoded)| encoded.iter())
+            .filter_map(|(_, slot)| slot.get())
+            .map(Bytes::len)
+            .sum()
+    }
+
+    pub fn stats(&self) -> ScaledFramesStats {
+        let entries = self.entries.lock();
+        let widths = entries.iter().filter_map(|(width, _)| *width).collect();
+        let full_resolution = entries.iter().any(|(width, _)| width.is_none());
+        drop(entries);
+        ScaledFramesStats {
+            widths,
+            full_resolution,
+            bytes: self.bytes(),
+            encodes: self.encodes.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixels() -> Arc<DynamicImage> {
+        Arc::new(DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, 128])
+        })))
+    }
+
+    #[tokio::test]
+    async fn full_resolution_re_encodes_are_shared() {
+        let frames = ScaledFrames::new(ScalerKind::Software);
+        let first = frames.get(7, None, 40, || async { Ok(pixels()) }).await.unwrap();
+        let second = frames
+            .get(7, None, 40, || async { unreachable!("encoded again") as Result<Arc<DynamicImage>> })
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(image::load_from_memory(&first).unwrap().width(), 64);
+
+        // Another quality, and the same quality at a lower width, are encodes of their own
+        let other = frames.get(7, None, 90, || async { Ok(pixels()) }).await.unwrap();
+        assert_ne!(first, other);
+        let scaled = frames.get(7, Some(32), 40, || async { Ok(pixels()) }).await.unwrap();
+        assert_eq!(image::load_from_memory(&scaled).unwrap().width(), 32);
+
+        let stats = frames.stats();
+        assert_eq!((stats.encodes, stats.hits), (3, 1));
+        assert_eq!(stats.widths, [32]);
+        assert!(stats.full_resolution);
+        assert_eq!(stats.bytes, first.len() + other.len() + scaled.len());
+    }
+}
@@ -0,0 +1,584 @@
+//! Weekly time-of-day schedules for runtime features and settings profiles
+//!
+//! A schedule lists weekly windows per feature ("detection 22:00-06:00 every
+//! day") and times at which a saved settings profile is applied ("night at
+//! 18:00 on weekdays"), in an explicitly configured IANA time zone: the
+//! board often boots with its clock in UTC, so the host's local time is
+//! never used.
+//!
+//! Windows are wall-clock times, resolved to real instants per day before
+//! they are compared with the clock, so DST changes can't make a feature
+//! flap:
+//! - an end before the start spans midnight (the window belongs to the day
+//!   it starts on); equal start and end mean the whole day
+//! - a time skipped by a spring-forward change happens at the end of the gap
+//! - a time repeated by a fall-back change happens the first time the clock
+//!   shows it
+//!
+//! A manual change of a scheduled feature (e.g. `/detect/off`) overrides the
+//! schedule until the feature's next boundary.
+//!
+//! A scheduled profile stays in effect until the next profile time; settings
+//! changed by hand meanwhile are kept until then. When the schedule is
+//! installed (at startup, or replaced) the latest profile time of the past
+//! week is applied straight away, so a restart ends up with the profile the
+//! clock calls for. Applying a profile counts as a manual change of the
+//! features it sets.
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+/// How far ahead transitions are computed
+const LOOKAHEAD_DAYS: i64 = 8;
+
+/// Longest DST gap searched past a skipped wall-clock time (minutes)
+const MAX_GAP_MINUTES: i64 = 180;
+
+/// Features the scheduler can switch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    Detection,
+}
+
+impl Feature {
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Detection => "detection",
+        }
+    }
+}
+
+/// One weekly window as configured
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct WindowConfig {
+    /// Days the window starts on (`mon` ... `sun`); empty = every day
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+    /// `HH:MM`, local time
+    pub start: String,
+    /// `HH:MM`, local time; before `start` = ends the next day
+    pub end: String,
+}
+
+/// A time of day at which a settings profile is applied
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ProfileTimeConfig {
+    /// Days to apply it on (`mon` ... `sun`); empty = every day
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<String>,
+    /// `HH:MM`, local time
+    pub at: String,
+    /// Name of a saved profile
+    pub profile: String,
+}
+
+/// The schedule as stored under `schedule` in the settings state file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ScheduleConfig {
+    /// IANA zone name, e.g. `Europe/Berlin`
+    pub timezone: String,
+    #[serde(default)]
+    pub features: BTreeMap<Feature, Vec<WindowConfig>>,
+    /// Profiles to apply; of several due at the same time the last listed
+    /// one wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<ProfileTimeConfig>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self { timezone: "UTC".to_string(), features: BTreeMap::new(), profiles: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Window {
+    /// Indexed by `Weekday::num_days_from_monday`
+    days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+#[derive(Debug, Clone)]
+struct ProfileTime {
+    /// Indexed by `Weekday::num_days_from_monday`
+    days: [bool; 7],
+    at: NaiveTime,
+    profile: String,
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value.trim(), "%H:%M").map_err(|_| format!("Invalid time '{}' (use HH:MM)", value))
+}
+
+/// Day flags from `mon` ... `sun` names; none = every day
+fn parse_days(names: &[String]) -> Result<[bool; 7], String> {
+    let mut days = [names.is_empty(); 7];
+    for day in names {
+        let day: Weekday = day.parse().map_err(|_| format!("Invalid day '{}' (use mon ... sun)", day))?;
+        days[day.num_days_from_monday() as usize] = true;
+    }
+    Ok(days)
+}
+
+impl Window {
+    fn parse(config: &WindowConfig) -> Result<Self, String> {
+        Ok(Self { days: parse_days(&config.days)?, start: parse_time(&config.start)?, end: parse_time(&config.end)? })
+    }
+}
+
+impl ProfileTime {
+    fn parse(config: &ProfileTimeConfig) -> Result<Self, String> {
+        if !crate::valid_name(&config.profile) {
+            return Err(format!("Invalid profile name '{}'", config.profile));
+        }
+        Ok(Self { days: parse_days(&config.days)?, at: parse_time(&config.at)?, profile: config.profile.clone() })
+    }
+}
+
+/// A scheduled on-period of a feature, as real instants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Period {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+/// An upcoming change of a feature's scheduled state
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub feature: Feature,
+    /// RFC 3339 in the schedule's zone
+    pub at: String,
+    pub at_ms: i64,
+    pub enabled: bool,
+}
+
+/// An upcoming scheduled profile application
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileTransition {
+    pub profile: String,
+    /// RFC 3339 in the schedule's zone
+    pub at: String,
+    pub at_ms: i64,
+}
+
+/// A validated schedule
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    config: ScheduleConfig,
+    tz: Tz,
+    windows: BTreeMap<Feature, Vec<Window>>,
+    profile_times: Vec<ProfileTime>,
+}
+
+impl Schedule {
+    pub fn parse(config: ScheduleConfig) -> Result<Self, String> {
+        let tz: Tz = config
+            .timezone
+            .parse()
+            .map_err(|_| format!("Unknown time zone '{}' (use an IANA name like Europe/Berlin)", config.timezone))?;
+        let windows = config
+            .features
+            .iter()
+            .map(|(&feature, windows)| {
+                let windows = windows
+                    .iter()
+                    .map(Window::parse)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("{}: {}", feature.name(), e))?;
+                Ok((feature, windows))
+            })
+            .collect::<Result<_, String>>()?;
+        let profile_times = config
+            .profiles
+            .iter()
+            .map(ProfileTime::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("profiles: {}", e))?;
+        Ok(Self { config, tz, windows, profile_times })
+    }
+
+    pub fn config(&self) -> &ScheduleConfig {
+        &self.config
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.tz
+    }
+
+    /// Features with at least one window
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        self.windows.iter().filter(|(_, w)| !w.is_empty()).map(|(&f, _)| f)
+    }
+
+    /// Names of the profiles the schedule applies
+    pub fn profiles(&self) -> impl Iterator<Item = &str> + '_ {
+        self.profile_times.iter().map(|t| t.profile.as_str())
+    }
+
+    /// First instant the wall clock shows `date time`
+    fn resolve(&self, date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+        let local = NaiveDateTime::new(date, time);
+        for minutes in 0..=MAX_GAP_MINUTES {
+            match self.tz.from_local_datetime(&(local + Duration::minutes(minutes))) {
+                LocalResult::Single(t) => return t.with_timezone(&Utc),
+                LocalResult::Ambiguous(earliest, _) => return earliest.with_timezone(&Utc),
+                LocalResult::None => continue,
+            }
+        }
+        // No zone has a gap this long; treat the time as UTC
+        Utc.from_utc_datetime(&local)
+    }
+
+    /// On-periods of `feature` starting from two days before `from` to
+    /// LOOKAHEAD_DAYS after, sorted and merged
+    fn periods(&self, feature: Feature, from: DateTime<Utc>) -> Vec<Period> {
+        let Some(windows) = self.windows.get(&feature) else {
+            return Vec::new();
+        };
+        let today = from.with_timezone(&self.tz).date_naive();
+        let mut periods: Vec<Period> = (-2..=LOOKAHEAD_DAYS)
+            .filter_map(|offset| today.checked_add_signed(Duration::days(offset)))
+            .flat_map(|date| {
+                windows
+                    .iter()
+                    .filter(move |w| w.days[date.weekday().num_days_from_monday() as usize])
+                    .map(move |w| {
+                        let end_date = if w.end <= w.start { date.succ_opt().unwrap_or(date) } else { date };
+                        Period { start: self.resolve(date, w.start), end: self.resolve(end_date, w.end) }
+                    })
+            })
+            .filter(|p| p.end > p.start)
+            .collect();
+        periods.sort_by_key(|p| p.start);
+
+        let mut merged: Vec<Period> = Vec::with_capacity(periods.len());
+        for period in periods {
+            match merged.last_mut() {
+                Some(last) if period.start <= last.end => last.end = last.end.max(period.end),
+                _ => merged.push(period),
+            }
+        }
+        merged
+    }
+
+    /// Profile applications from a week before `from` to LOOKAHEAD_DAYS
+    /// after, in order (the later listed of simultaneous ones last)
+    fn profile_changes(&self, from: DateTime<Utc>) -> Vec<(DateTime<Utc>, &str)> {
+        let today = from.with_timezone(&self.tz).date_naive();
+        let mut changes: Vec<(DateTime<Utc>, usize)> = (-7..=LOOKAHEAD_DAYS)
+            .filter_map(|offset| today.checked_add_signed(Duration::days(offset)))
+            .flat_map(|date| {
+                self.profile_times
+                    .iter()
+                    .enumerate()
+                    .filter(move |(_, t)| t.days[date.weekday().num_days_from_monday() as usize])
+                    .map(move |(i, t)| (self.resolve(date, t.at), i))
+            })
+            .collect();
+        changes.sort();
+        changes.into_iter().map(|(t, i)| (t, self.profile_times[i].profile.as_str())).collect()
+    }
+
+    /// The profile applied last at or before `now`, and when
+    pub fn current_profile(&self, now: DateTime<Utc>) -> Option<(DateTime<Utc>, &str)> {
+        self.profile_changes(now).into_iter().rev().find(|&(t, _)| t <= now)
+    }
+
+    /// Upcoming profile applications, soonest first
+    pub fn profile_transitions(&self, now: DateTime<Utc>) -> Vec<ProfileTransition> {
+        self.profile_changes(now)
+            .into_iter()
+            .filter(|&(t, _)| t > now)
+            .map(|(t, profile)| ProfileTransition {
+                profile: profile.to_string(),
+                at: t.with_timezone(&self.tz).to_rfc3339(),
+                at_ms: t.timestamp_millis(),
+            })
+            .collect()
+    }
+
+    /// Whether `feature` is scheduled on at `now` (None = not scheduled)
+    pub fn is_active(&self, feature: Feature, now: DateTime<Utc>) -> Option<bool> {
+        if self.windows.get(&feature).is_none_or(|w| w.is_empty()) {
+            return None;
+        }
+        Some(self.periods(feature, now).iter().any(|p| p.start <= now && now < p.end))
+    }
+
+    /// Next instant after `now` at which `feature`'s scheduled state changes
+    pub fn next_boundary(&self, feature: Feature, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.periods(feature, now)
+            .iter()
+            .flat_map(|p| [p.start, p.end])
+            .find(|&t| t > now)
+    }
+
+    /// Upcoming state changes of all features, soonest first
+    pub fn transitions(&self, now: DateTime<Utc>) -> Vec<Transition> {
+        let mut transitions: Vec<Transition> = self
+            .features()
+            .flat_map(|feature| {
+                self.periods(feature, now)
+                    .into_iter()
+                    .flat_map(|p| [(p.start, true), (p.end, false)])
+                    .filter(|&(t, _)| t > now)
+                    .map(move |(t, enabled)| Transition {
+                        feature,
+                        at: t.with_timezone(&self.tz).to_rfc3339(),
+                        at_ms: t.timestamp_millis(),
+                        enabled,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        transitions.sort_by_key(|t| t.at_ms);
+        transitions
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self { config: ScheduleConfig::default(), tz: Tz::UTC, windows: BTreeMap::new(), profile_times: Vec::new() }
+    }
+}
+
+/// A manual change that holds until the feature's next boundary
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Override {
+    pub enabled: bool,
+    /// None when the schedule has no further boundary
+    pub until_ms: Option<i64>,
+}
+
+/// The active schedule plus what the scheduler has applied
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    schedule: Schedule,
+    overrides: BTreeMap<Feature, Override>,
+    /// Last scheduled state applied per feature
+    applied: BTreeMap<Feature, bool>,
+    /// Scheduled time of the last profile applied (Unix ms)
+    applied_profile_ms: Option<i64>,
+}
+
+/// Per-feature section of /status and /schedule
+#[derive(Debug, Clone, Serialize)]
+pub struct FeatureState {
+    pub scheduled: bool,
+    #[serde(rename = "override")]
+    pub override_: Option<Override>,
+}
+
+/// The profile in effect by the schedule, for /schedule
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileState {
+    pub profile: String,
+    /// When it was due, RFC 3339 in the schedule's zone
+    pub since: String,
+    pub since_ms: i64,
+}
+
+impl Scheduler {
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    /// Install a new schedule; overrides are dropped and every scheduled
+    /// feature is applied on the next tick
+    pub fn set(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+        self.overrides.clear();
+        self.applied.clear();
+        self.applied_profile_ms = None;
+    }
+
+    /// Record a manual change; ignored for unscheduled features
+    pub fn manual_change(&mut self, feature: Feature, enabled: bool, now: DateTime<Utc>) {
+        if self.schedule.is_active(feature, now).is_none() {
+            return;
+        }
+        let until_ms = self.schedule.next_boundary(feature, now).map(|t| t.timestamp_millis());
+        self.overrides.insert(feature, Override { enabled, until_ms });
+    }
+
+    /// Expire overrides and return the feature states to apply now
+    pub fn tick(&mut self, now: DateTime<Utc>) -> Vec<(Feature, bool)> {
+        let now_ms = now.timestamp_millis();
+        let mut changes = Vec::new();
+        for feature in self.schedule.features().collect::<Vec<_>>() {
+            if let Some(o) = self.overrides.get(&feature) {
+                if o.until_ms.is_none_or(|until| now_ms < until) {
+                    continue;
+                }
+                self.overrides.remove(&feature);
+                self.applied.remove(&feature);
+            }
+            let Some(desired) = self.schedule.is_active(feature, now) else {
+                continue;
+            };
+            if self.applied.insert(feature, desired) != Some(desired) {
+                changes.push((feature, desired));
+            }
+        }
+        changes
+    }
+
+    /// The profile to apply now, if one is due that hasn't been applied
+    pub fn due_profile(&mut self, now: DateTime<Utc>) -> Option<String> {
+        let (at, profile) = self.schedule.current_profile(now)?;
+        let at_ms = at.timestamp_millis();
+        if self.applied_profile_ms == Some(at_ms) {
+            return None;
+        }
+        self.applied_profile_ms = Some(at_ms);
+        Some(profile.to_string())
+    }
+
+    /// When the scheduler next needs to run
+    pub fn next_wake(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let next_profile = self.schedule.profile_changes(now).into_iter().map(|(t, _)| t).find(|&t| t > now);
+        self.schedule.features().filter_map(|f| self.schedule.next_boundary(f, now)).chain(next_profile).min()
+    }
+
+    pub fn profile_state(&self, now: DateTime<Utc>) -> Option<ProfileState> {
+        let (at, profile) = self.schedule.current_profile(now)?;
+        Some(ProfileState {
+            profile: profile.to_string(),
+            since: at.with_timezone(&self.schedule.tz).to_rfc3339(),
+            since_ms: at.timestamp_millis(),
+        })
+    }
+
+    pub fn states(&self, now: DateTime<Utc>) -> BTreeMap<Feature, FeatureState> {
+        self.schedule
+            .features()
+            .map(|feature| {
+                let state = FeatureState {
+                    scheduled: self.schedule.is_active(feature, now).unwrap_or(false),
+                    override_: self.overrides.get(&feature).copied(),
+                };
+                (feature, state)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(timezone: &str, days: &[&str], start: &str, end: &str) -> Schedule {
+        let window = WindowConfig {
+            days: days.iter().map(|d| d.to_string()).collect(),
+            start: start.to_string(),
+            end: end.to_string(),
+        };
+        Schedule::parse(ScheduleConfig {
+            timezone: timezone.to_string(),
+            features: BTreeMap::from([(Feature::Detection, vec![window])]),
+            profiles: Vec::new(),
+        })
+        .unwrap()
+    }
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn windows_span_midnight() {
+        let s = schedule("UTC", &[], "22:00", "06:00");
+        let active = |t| s.is_active(Feature::Detection, utc(t));
+        assert_eq!(active("2026-06-10T21:59:59Z"), Some(false));
+        assert_eq!(active("2026-06-10T22:00:00Z"), Some(true));
+        assert_eq!(active("2026-06-11T00:00:00Z"), Some(true));
+        assert_eq!(active("2026-06-11T05:59:59Z"), Some(true));
+        assert_eq!(active("2026-06-11T06:00:00Z"), Some(false));
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-06-10T12:00:00Z")), Some(utc("2026-06-10T22:00:00Z")));
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-06-10T23:00:00Z")), Some(utc("2026-06-11T06:00:00Z")));
+    }
+
+    #[test]
+    fn midnight_windows_belong_to_their_start_day() {
+        // 2026-06-12 is a Friday
+        let s = schedule("UTC", &["fri"], "22:00", "06:00");
+        let active = |t| s.is_active(Feature::Detection, utc(t));
+        assert_eq!(active("2026-06-12T03:00:00Z"), Some(false));
+        assert_eq!(active("2026-06-12T23:00:00Z"), Some(true));
+        assert_eq!(active("2026-06-13T03:00:00Z"), Some(true));
+        assert_eq!(active("2026-06-13T23:00:00Z"), Some(false));
+    }
+
+    #[test]
+    fn equal_start_and_end_is_the_whole_day() {
+        let s = schedule("UTC", &[], "00:00", "00:00");
+        assert_eq!(s.is_active(Feature::Detection, utc("2026-06-10T00:00:00Z")), Some(true));
+        assert_eq!(s.is_active(Feature::Detection, utc("2026-06-10T13:37:00Z")), Some(true));
+        // Only the end of the computed lookahead, never a change within it
+        let next = s.next_boundary(Feature::Detection, utc("2026-06-10T12:00:00Z"));
+        assert!(next.is_none_or(|t| t >= utc("2026-06-17T12:00:00Z")), "{:?}", next);
+    }
+
+    #[test]
+    fn unscheduled_features_are_none() {
+        let s = Schedule::default();
+        assert_eq!(s.is_active(Feature::Detection, utc("2026-06-10T12:00:00Z")), None);
+        // Only the end of the computed lookahead, never a change within it
+        let next = s.next_boundary(Feature::Detection, utc("2026-06-10T12:00:00Z"));
+        assert!(next.is_none_or(|t| t >= utc("2026-06-17T12:00:00Z")), "{:?}", next);
+    }
+
+    #[test]
+    fn spring_forward_starts_skipped_times_at_the_end_of_the_gap() {
+        // Europe/Berlin skips 02:00-03:00 on 2026-03-29 (01:00 UTC)
+        let s = schedule("Europe/Berlin", &[], "02:30", "05:00");
+        let active = |t| s.is_active(Feature::Detection, utc(t));
+        assert_eq!(active("2026-03-29T00:59:59Z"), Some(false));
+        assert_eq!(active("2026-03-29T01:00:00Z"), Some(true));
+        // 05:00 CEST
+        assert_eq!(active("2026-03-29T02:59:59Z"), Some(true));
+        assert_eq!(active("2026-03-29T03:00:00Z"), Some(false));
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-03-29T00:00:00Z")), Some(utc("2026-03-29T01:00:00Z")));
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-03-29T01:00:00Z")), Some(utc("2026-03-29T03:00:00Z")));
+    }
+
+    #[test]
+    fn spring_forward_shortens_a_night_window() {
+        // 22:00 CET to 06:00 CEST: seven hours
+        let s = schedule("Europe/Berlin", &[], "22:00", "06:00");
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-03-28T20:00:00Z")), Some(utc("2026-03-28T21:00:00Z")));
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-03-28T21:00:00Z")), Some(utc("2026-03-29T04:00:00Z")));
+        assert_eq!(s.is_active(Feature::Detection, utc("2026-03-29T03:59:59Z")), Some(true));
+        assert_eq!(s.is_active(Feature::Detection, utc("2026-03-29T04:00:00Z")), Some(false));
+    }
+
+    #[test]
+    fn fall_back_uses_the_first_of_repeated_times_without_flapping() {
+        // Europe/Berlin shows 02:00-03:00 twice on 2026-10-25 (CEST, then CET)
+        let s = schedule("Europe/Berlin", &[], "02:30", "04:00");
+        let active = |t| s.is_active(Feature::Detection, utc(t));
+        // 02:30 CEST
+        assert_eq!(active("2026-10-25T00:29:59Z"), Some(false));
+        assert_eq!(active("2026-10-25T00:30:00Z"), Some(true));
+        // 02:30 CET, shown a second time: still on
+        assert_eq!(active("2026-10-25T01:30:00Z"), Some(true));
+        // 04:00 CET
+        assert_eq!(active("2026-10-25T02:59:59Z"), Some(true));
+        assert_eq!(active("2026-10-25T03:00:00Z"), Some(false));
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-10-25T00:45:00Z")), Some(utc("2026-10-25T03:00:00Z")));
+    }
+
+    #[test]
+    fn fall_back_ends_repeated_times_the_first_time() {
+        // Ends at 02:30 CEST; the CET 02:30 an hour later isn't an end
+        let s = schedule("Europe/Berlin", &[], "01:00", "02:30");
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-10-24T23:00:00Z")), Some(utc("2026-10-25T00:30:00Z")));
+        assert_eq!(s.is_active(Feature::Detection, utc("2026-10-25T01:00:00Z")), Some(false));
+        assert_eq!(s.next_boundary(Feature::Detection, utc("2026-10-25T00:30:00Z")), Some(utc("2026-10-26T00:00:00Z")));
+    }
+}
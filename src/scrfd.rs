@@ -0,0 +1,123 @@
+//! SCRFD face detector pre- and post-processing
+//!
+//! SCRFD (InsightFace) models predict, for every anchor of three stride
+//! levels (8, 16, 32), a face score, distances from the anchor centre to
+//! the four box edges and five landmarks (eyes, nose tip, mouth corners),
+//! all in units of the stride. The model outputs come as nine tensors:
+//! scores `[N, 1]`, boxes `[N, 4]` and landmarks `[N, 10]` per level, where
+//! levels are told apart by their anchor count.
+
+use crate::detector::Detection;
+use crate::faces::FACE_CLASS;
+use crate::yolo::Letterbox;
+use anyhow::Result;
+#[cfg(feature = "onnx")]
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+/// Minimum face score kept after decoding
+pub const SCORE_THRESHOLD: f32 = 0.5;
+
+/// IoU above which overlapping faces are suppressed
+pub const NMS_IOU_THRESHOLD: f32 = 0.4;
+
+/// Anchor strides, finest first
+const STRIDES: [u32; 3] = [8, 16, 32];
+
+/// Input normalization: (pixel - MEAN) / STD
+#[cfg(feature = "onnx")]
+const MEAN: f32 = 127.5;
+#[cfg(feature = "onnx")]
+const STD: f32 = 128.0;
+
+/// Resize to fit `size`x`size` preserving aspect ratio, anchored top-left
+/// and padded with black (as SCRFD was trained), and return the normalized
+/// planar RGB tensor data (CHW) plus the coordinate mapping
+#[cfg(feature = "onnx")]
+pub fn prepare(image: &DynamicImage, size: u32) -> (Vec<f32>, Letterbox) {
+    let (src_width, src_height) = image.dimensions();
+    let scale = (size as f32 / src_width as f32).min(size as f32 / src_height as f32);
+    let new_width = ((src_width as f32 * scale).round() as u32).clamp(1, size);
+    let new_height = ((src_height as f32 * scale).round() as u32).clamp(1, size);
+
+    let resized = image.resize_exact(new_width, new_height, FilterType::Triangle).to_rgb8();
+    let plane = (size * size) as usize;
+    let mut data = vec![-MEAN / STD; 3 * plane];
+    for (x, y, pixel) in resized.enumerate_pixels() {
+        let idx = (y * size + x) as usize;
+        for c in 0..3 {
+            data[c * plane + idx] = (pixel[c] as f32 - MEAN) / STD;
+        }
+    }
+
+    let mapping = Letterbox { scale, pad_x: 0.0, pad_y: 0.0, src_width, src_height };
+    (data, mapping)
+}
+
+/// Decode the model outputs, given as (last dimension, values) pairs in any
+/// order, apply the score threshold and NMS
+pub fn decode(outputs: &[(usize, Vec<f32>)], input_size: u32, mapping: &Letterbox) -> Result<Vec<Detection>> {
+    // Per kind, finest level (most anchors) first
+    let kind = |width: usize| {
+        let mut tensors: Vec<&[f32]> = outputs
+            .iter()
+            .filter(|(w, _)| *w == width)
+            .map(|(_, values)| values.as_slice())
+            .collect();
+        tensors.sort_by_key(|t| std::cmp::Reverse(t.len()));
+        tensors
+    };
+    let (scores, boxes, landmarks) = (kind(1), kind(4), kind(10));
+    anyhow::ensure!(
+        scores.len() == STRIDES.len() && boxes.len() == STRIDES.len(),
+        "Expected {} score and box outputs, got {} and {}",
+        STRIDES.len(),
+        scores.len(),
+        boxes.len()
+    );
+
+    let mut candidates = Vec::new();
+    for (level, &stride) in STRIDES.iter().enumerate() {
+        let grid = (input_size / stride).max(1) as usize;
+        let anchors = scores[level].len();
+        let per_cell = (anchors / (grid * grid)).max(1);
+        anyhow::ensure!(
+            boxes[level].len() == anchors * 4,
+            "Stride {} box output doesn't match its {} anchors",
+            stride,
+            anchors
+        );
+        let kps = landmarks.get(level).filter(|k| k.len() == anchors * 10);
+        let stride = stride as f32;
+
+        for (anchor, &score) in scores[level].iter().enumerate() {
+            if score < SCORE_THRESHOLD {
+                continue;
+            }
+            let cell = anchor / per_cell;
+            let cx = (cell % grid) as f32 * stride;
+            let cy = (cell / grid) as f32 * stride;
+            let d = &boxes[level][anchor * 4..anchor * 4 + 4];
+            candidates.push(Detection {
+                class: FACE_CLASS.to_string(),
+                confidence: score,
+                bbox: mapping.map_to_frame(
+                    cx - d[0] * stride,
+                    cy - d[1] * stride,
+                    cx + d[2] * stride,
+                    cy + d[3] * stride,
+                ),
+                label: None,
+                landmarks: kps
+                    .map(|k| {
+                        k[anchor * 10..anchor * 10 + 10]
+                            .chunks_exact(2)
+                            .map(|p| mapping.map_point(cx + p[0] * stride, cy + p[1] * stride))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                zones: Vec::new(),
+            });
+        }
+    }
+    Ok(crate::detector::nms(candidates, NMS_IOU_THRESHOLD))
+}
@@ -0,0 +1,336 @@
+//! Deployment self-test
+//!
+//! Runs a fixed set of independent hardware/environment checks and reports
+//! pass/fail/skip with timing for each. Used by GET /selftest and the
+//! `self-test` subcommand.
+
+use crate::capture::{v4l2_field, FrameCapture, FrameSize, SETTLE_FRAMES};
+use crate::rawformat::RawFormat;
+use crate::controls;
+use crate::detector::DETECTOR_SCRIPT;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+use utoipa::ToSchema;
+
+/// NPU devfreq node on the RK3588
+const NPU_DEVFREQ: &str = "/sys/class/devfreq/fdab0000.npu";
+
+/// Free space required for recordings (MB)
+const MIN_FREE_SPACE_MB: u64 = 1024;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    Skip,
+}
+
+/// Result of a single check
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub required: bool,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub duration_ms: f64,
+}
+
+/// Full self-test report
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SelfTestReport {
+    /// True when no required check failed
+    pub passed: bool,
+    pub checks: Vec<CheckResult>,
+    pub duration_ms: f64,
+}
+
+/// Successful check outcome
+enum Outcome {
+    Pass(String),
+    Skip(String),
+}
+
+/// Run a check, catching its error and timing it
+fn run_check(name: &'static str, required: bool, check: impl FnOnce() -> Result<Outcome>) -> CheckResult {
+    let start = Instant::now();
+    let (status, detail) = match check() {
+        Ok(Outcome::Pass(detail)) => (CheckStatus::Pass, detail),
+        Ok(Outcome::Skip(detail)) => (CheckStatus::Skip, detail),
+        Err(e) => (CheckStatus::Fail, format!("{:#}", e)),
+    };
+    CheckResult {
+        name,
+        required,
+        status,
+        detail,
+        duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }
+}
+
+/// Run a command and return its stdout, failing on nonzero exit
+fn command_output(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        anyhow::bail!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Value of a `Key : value` line in v4l2-ctl output
+fn check_device(path: &str) -> Result<Outcome> {
+    let metadata = std::fs::metadata(path).with_context(|| format!("{} not found", path))?;
+    if metadata.is_dir() {
+        anyhow::bail!("{} is a directory", path);
+    }
+    Ok(Outcome::Pass(path.to_string()))
+}
+
+/// The negotiated format must be one the pipeline handles, and the
+/// configured frame size if one is set
+fn check_format(device: &str, expected: Option<FrameSize>) -> Result<Outcome> {
+    let output = command_output("v4l2-ctl", &["-d", device, "--get-fmt-video"])?;
+    let size = v4l2_field(&output, "Width/Height")
+        .and_then(FrameSize::from_v4l2)
+        .context("No Width/Height in format")?;
+    if let Some(expected) = expected.filter(|&expected| expected != size) {
+        anyhow::bail!("Negotiated {} but expected {}", size, expected);
+    }
+    size.validate().map_err(anyhow::Error::msg)?;
+    let pixel_format = v4l2_field(&output, "Pixel Format").context("No Pixel Format in format")?;
+    let format = RawFormat::from_v4l2(pixel_format)
+        .with_context(|| format!("Unsupported pixel format {}", pixel_format))?;
+    Ok(Outcome::Pass(format!(
+        "{} {} ({:?}, {}-bit {:?})",
+        size, format.fourcc, format.packing, format.bits, format.cfa
+    )))
+}
+
+fn check_stride(device: &str) -> Result<Outcome> {
+    let output = command_output("v4l2-ctl", &["-d", device, "--get-fmt-video"])?;
+    let stride: usize = v4l2_field(&output, "Bytes per Line")
+        .context("No Bytes per Line in format")?
+        .parse()
+        .context("Invalid Bytes per Line")?;
+    let format = v4l2_field(&output, "Pixel Format")
+        .and_then(RawFormat::from_v4l2)
+        .unwrap_or_default();
+    let width = v4l2_field(&output, "Width/Height")
+        .and_then(FrameSize::from_v4l2)
+        .unwrap_or(FrameSize::FULL)
+        .width;
+    let min_stride = format.min_stride(width);
+    if stride < min_stride {
+        anyhow::bail!("Stride is {} but {} needs at least {}", stride, format.fourcc, min_stride);
+    }
+    Ok(Outcome::Pass(format!("{} bytes per line", stride)))
+}
+
+fn check_raw_frame(capture: Option<&FrameCapture>) -> Result<Outcome> {
+    let Some(capture) = capture else {
+        anyhow::bail!("Capture not initialized");
+    };
+    let raw = capture.capture_raw_frame()?;
+    let expected = capture.stride() * capture.frame_size().height;
+    if raw.len() < expected {
+        anyhow::bail!("Raw frame is {} bytes, expected {}", raw.len(), expected);
+    }
+    Ok(Outcome::Pass(format!("{} bytes", raw.len())))
+}
+
+fn check_decoded_frame(capture: Option<&mut FrameCapture>) -> Result<Outcome> {
+    let Some(capture) = capture else {
+        anyhow::bail!("Capture not initialized");
+    };
+    let jpeg = capture.capture_jpeg_frame()?;
+    let image = image::load_from_memory_with_format(&jpeg, image::ImageFormat::Jpeg)
+        .context("Captured JPEG does not decode")?;
+    Ok(Outcome::Pass(format!(
+        "{}x{} {:?} ({} bytes)",
+        image.width(),
+        image.height(),
+        capture.mode(),
+        jpeg.len()
+    )))
+}
+
+fn check_sensor_controls(subdev: &str) -> Result<Outcome> {
+    // Write back the current gain so the check has no side effects
+    let output = command_output("v4l2-ctl", &["-d", subdev, "--get-ctrl", "analogue_gain"])?;
+    let gain = v4l2_field(&output, "analogue_gain").context("analogue_gain not reported")?;
+    command_output("v4l2-ctl", &["-d", subdev, "--set-ctrl", &format!("analogue_gain={}", gain)])?;
+    Ok(Outcome::Pass(format!("analogue_gain={}", gain)))
+}
+
+/// Fraction of ramp steps allowed to go the wrong way (sensor noise)
+const RAMP_TOLERANCE: f64 = 0.01;
+
+/// Whether the same-colour samples along rows or columns form a monotonic ramp
+fn is_monotonic_ramp(bayer: &[u16], size: FrameSize) -> bool {
+    let FrameSize { width, height } = size;
+    let count_decreasing = |samples: &mut dyn Iterator<Item = (u16, u16)>| {
+        let (mut down, mut total) = (0usize, 0usize);
+        for (a, b) in samples {
+            total += 1;
+            if b < a {
+                down += 1;
+            }
+        }
+        (down, total.max(1))
+    };
+    // Step by two to compare samples of the same CFA colour
+    let row = (height / 2) * width;
+    let (h_down, h_total) = count_decreasing(
+        &mut (0..width - 2).step_by(2).map(|x| (bayer[row + x], bayer[row + x + 2])),
+    );
+    let col = width / 2;
+    let (v_down, v_total) = count_decreasing(
+        &mut (0..height - 2).step_by(2).map(|y| (bayer[y * width + col], bayer[(y + 2) * width + col])),
+    );
+    let ratio = |down: usize, total: usize| down as f64 / total as f64;
+    let flat = |samples: &mut dyn Iterator<Item = u16>| {
+        let values: Vec<u16> = samples.collect();
+        values.iter().max() == values.iter().min()
+    };
+    let h_flat = flat(&mut (0..width).step_by(2).map(|x| bayer[row + x]));
+    let v_flat = flat(&mut (0..height).step_by(2).map(|y| bayer[y * width + col]));
+    (!h_flat && ratio(h_down, h_total) <= RAMP_TOLERANCE)
+        || (!v_flat && ratio(v_down, v_total) <= RAMP_TOLERANCE)
+}
+
+/// Enable the sensor's gradient test pattern and verify the unpacked ramp
+fn check_test_pattern_ramp(capture: Option<&mut FrameCapture>, subdev: &str) -> Result<Outcome> {
+    let Some(capture) = capture else {
+        anyhow::bail!("Capture not initialized");
+    };
+    let control = match controls::find_control(subdev, "test_pattern") {
+        Ok(control) => control,
+        Err(e) => return Ok(Outcome::Skip(format!("{:#}", e))),
+    };
+    let Some(gradient) = control.menu.iter().find(|e| {
+        let label = e.label.to_lowercase();
+        label.contains("gradient") || label.contains("ramp")
+    }) else {
+        return Ok(Outcome::Skip("No gradient test pattern in the driver's menu".to_string()));
+    };
+
+    let previous = control.value.unwrap_or(0);
+    controls::set_control(subdev, "test_pattern", gradient.index)?;
+    capture.skip_frames(SETTLE_FRAMES);
+    let raw = capture.capture_raw_frame();
+    // Restore before inspecting so a failure doesn't leave the pattern on
+    controls::set_control(subdev, "test_pattern", previous)?;
+
+    let size = capture.frame_size();
+    if !is_monotonic_ramp(capture.unpack_raw(&raw?), size) {
+        anyhow::bail!("'{}' did not unpack to a monotonic ramp (stride/packing bug?)", gradient.label);
+    }
+    Ok(Outcome::Pass(format!("'{}' unpacks to a monotonic ramp", gradient.label)))
+}
+
+fn check_detector_script() -> Result<Outcome> {
+    if !Path::new(DETECTOR_SCRIPT).is_file() {
+        anyhow::bail!("{} not found", DETECTOR_SCRIPT);
+    }
+    Ok(Outcome::Pass(DETECTOR_SCRIPT.to_string()))
+}
+
+fn check_python_env() -> Result<Outcome> {
+    command_output(
+        "python3",
+        &["-c", "import numpy, cv2; from rknnlite.api import RKNNLite"],
+    )?;
+    Ok(Outcome::Pass("numpy, cv2, rknnlite importable".to_string()))
+}
+
+fn check_npu() -> Result<Outcome> {
+    let freq_path = Path::new(NPU_DEVFREQ).join("cur_freq");
+    if !Path::new(NPU_DEVFREQ).exists() {
+        return Ok(Outcome::Skip(format!("{} not present (not an RK3588?)", NPU_DEVFREQ)));
+    }
+    let freq = std::fs::read_to_string(&freq_path)
+        .with_context(|| format!("Failed to read {}", freq_path.display()))?;
+    let mhz = freq.trim().parse::<u64>().map(|hz| hz / 1_000_000).unwrap_or(0);
+    Ok(Outcome::Pass(format!("NPU at {} MHz", mhz)))
+}
+
+fn check_disk_space(dir: &Path) -> Result<Outcome> {
+    // The directory is created with the first recording; until then its
+    // nearest existing parent is on the same filesystem
+    let existing = dir.ancestors().find(|d| d.exists()).unwrap_or(Path::new("/"));
+    let output = command_output("df", &["-Pk", &existing.to_string_lossy()])?;
+    let dir = dir.display();
+    let available_kb: u64 = output
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|v| v.parse().ok())
+        .context("Could not parse df output")?;
+    let available_mb = available_kb / 1024;
+    if available_mb < MIN_FREE_SPACE_MB {
+        anyhow::bail!("Only {} MB free in {} (need {} MB)", available_mb, dir, MIN_FREE_SPACE_MB);
+    }
+    Ok(Outcome::Pass(format!("{} MB free in {}", available_mb, dir)))
+}
+
+/// Run all checks. Every check runs regardless of earlier failures.
+///
+/// `capture` is borrowed for the frame checks; pass `None` when no capture
+/// instance could be created so those checks are reported as failed.
+/// Free space is checked where recordings are written.
+pub fn run(
+    mut capture: Option<&mut FrameCapture>,
+    device_path: &str,
+    sensor_subdev: &str,
+    recordings_dir: &Path,
+) -> SelfTestReport {
+    let start = Instant::now();
+    let frame_size = capture.as_ref().and_then(|c| c.config().frame_size);
+
+    let checks = vec![
+        run_check("camera_device", true, || check_device(device_path)),
+        run_check("sensor_subdev", true, || check_device(sensor_subdev)),
+        run_check("format", true, || check_format(device_path, frame_size)),
+        run_check("stride", true, || check_stride(device_path)),
+        run_check("raw_frame", true, || check_raw_frame(capture.as_deref())),
+        run_check("decoded_frame", true, || check_decoded_frame(capture.as_deref_mut())),
+        run_check("test_pattern_ramp", false, || check_test_pattern_ramp(capture, sensor_subdev)),
+        run_check("sensor_controls", true, || check_sensor_controls(sensor_subdev)),
+        run_check("detector_script", false, check_detector_script),
+        run_check("python_env", false, check_python_env),
+        run_check("npu", false, check_npu),
+        run_check("disk_space", false, || check_disk_space(recordings_dir)),
+    ];
+
+    let passed = !checks.iter().any(|c| c.required && c.status == CheckStatus::Fail);
+    SelfTestReport {
+        passed,
+        checks,
+        duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_space_is_checked_in_the_given_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        // Not created until the first recording
+        let recordings = dir.path().join("data/recordings");
+        match check_disk_space(&recordings).unwrap() {
+            Outcome::Pass(message) => {
+                assert!(message.ends_with(&format!("free in {}", recordings.display())), "{}", message)
+            }
+            _ => panic!("expected a pass"),
+        }
+    }
+}
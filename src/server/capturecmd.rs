@@ -0,0 +1,200 @@
+//! One-shot capture without the web server
+//!
+//! `imx415_streamer capture -o <file> [--count <n>] ...` configures the
+//! sensor, captures one frame (or `n`) through the same `FrameCapture`
+//! pipeline the server uses, writes each as JPEG, PNG, TIFF or the packed
+//! raw dump (JPEGs with EXIF), prints one JSON metadata line per frame to
+//! stdout and exits.
+//! The pipeline gets a temp dir of its own, so a running server's capture
+//! is left alone.
+
+use crate::capture::{self, CaptureConfig, FrameCapture};
+use crate::controls;
+use crate::exif::{self, ExifFields};
+use crate::pipeline::{PipelineSettings, SettingsCell};
+use crate::reprocess::{self, OutputFormat};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Per-frame capture timeout unless `--timeout` is given
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most frames one command captures
+pub const MAX_COUNT: usize = 10_000;
+
+/// What to write for each frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileFormat {
+    Image(OutputFormat),
+    /// The packed frame as read from the device (input for `reprocess`)
+    Raw,
+}
+
+impl FileFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "raw" => Some(FileFormat::Raw),
+            other => OutputFormat::parse(other).map(FileFormat::Image),
+        }
+    }
+
+    /// Format implied by the output file name
+    pub fn from_path(path: &str) -> Option<Self> {
+        Path::new(path).extension().and_then(|e| Self::parse(&e.to_string_lossy()))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FileFormat::Image(OutputFormat::Jpeg) => "jpeg",
+            FileFormat::Image(OutputFormat::Png) => "png",
+            FileFormat::Image(OutputFormat::Tiff) => "tiff",
+            FileFormat::Raw => "raw",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Output file name; with several frames it holds a `%d` (or `%0Nd`)
+    /// frame number
+    pub output: String,
+    pub count: usize,
+    pub format: FileFormat,
+    pub settings: PipelineSettings,
+    /// Sensor subdevice controls to set before capturing
+    pub controls: BTreeMap<String, i64>,
+    pub timeout: Duration,
+    /// Video node and sensor subdevice (the timeout is set from `timeout`)
+    pub devices: CaptureConfig,
+}
+
+/// Metadata of one written frame
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameRecord {
+    pub file: String,
+    /// Position in this run, from 0
+    pub index: usize,
+    /// Capture time, milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub format: &'static str,
+    pub bytes: u64,
+    pub width: usize,
+    pub height: usize,
+    pub mode: &'static str,
+    /// Pipeline settings, as in `reprocess` output names
+    pub settings: String,
+    pub raw_format: String,
+    pub stride: usize,
+    pub controls: BTreeMap<String, i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub white_balance_gains: Option<[f32; 3]>,
+    pub capture_ms: f64,
+    pub process_ms: f64,
+}
+
+/// File name of frame `index`: the first `%d`/`%0Nd` in `pattern` replaced
+/// by the number; `None` if the pattern has none
+pub fn frame_path(pattern: &str, index: usize) -> Option<String> {
+    let start = pattern.find('%')?;
+    let rest = &pattern[start + 1..];
+    let end = rest.find('d')?;
+    let spec = &rest[..end];
+    if !spec.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let width: usize = if spec.is_empty() { 0 } else { spec.parse().ok()? };
+    Some(format!("{}{:0width$}{}", &pattern[..start], index, &rest[end + 1..], width = width))
+}
+
+/// Check the options before the device is touched
+pub fn validate(options: &Options) -> Result<()> {
+    anyhow::ensure!((1..=MAX_COUNT).contains(&options.count), "--count must be between 1 and {}", MAX_COUNT);
+    if options.count > 1 {
+        anyhow::ensure!(
+            frame_path(&options.output, 0).is_some(),
+            "With --count above 1 the output name needs a frame number, e.g. frame_%03d.jpg"
+        );
+    }
+    Ok(())
+}
+
+/// Configure the sensor and capture `options.count` frames; `on_frame`
+/// sees each record once its file is written. Stops at the first failure.
+pub fn run(options: &Options, on_frame: impl Fn(&FrameRecord)) -> Result<Vec<FrameRecord>> {
+    let config = CaptureConfig {
+        capture_timeout: Some(options.timeout),
+        ..options.devices.clone()
+    };
+    let settings = Arc::new(SettingsCell::new(options.settings.clone()));
+    let mut capture = FrameCapture::with_config(config, settings)?;
+    capture.setup_sensor()?;
+    capture.start_streaming()?;
+    let subdev = capture.config().sensor_subdev.clone();
+    for (name, value) in &options.controls {
+        controls::set_control(&subdev, name, *value)
+            .with_context(|| format!("Failed to set {} to {}", name, value))?;
+    }
+    if !options.controls.is_empty() {
+        capture.skip_frames(capture::SETTLE_FRAMES);
+    }
+    let tag = reprocess::settings_tag(&options.settings);
+    let sensor_controls = controls::list_controls(&subdev).unwrap_or_else(|e| {
+        tracing::warn!("No sensor controls for EXIF: {:#}", e);
+        Vec::new()
+    });
+
+    let mut records = Vec::with_capacity(options.count);
+    for index in 0..options.count {
+        let file = frame_path(&options.output, index).unwrap_or_else(|| options.output.clone());
+
+        let start = Instant::now();
+        let raw = capture.capture_raw_frame().with_context(|| format!("Frame {} capture failed", index))?;
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        let capture_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let start = Instant::now();
+        match options.format {
+            FileFormat::Raw => {
+                std::fs::write(&file, &raw).with_context(|| format!("Failed to write {}", file))?;
+            }
+            FileFormat::Image(OutputFormat::Jpeg) => {
+                let image = capture.process_raw_image(&raw)?;
+                let jpeg = capture::encode_image_jpeg(&image, capture.snapshot_quality())?;
+                let fields =
+                    ExifFields::new(timestamp_ms).with_sensor_controls(&sensor_controls, capture.frame_size().width);
+                std::fs::write(&file, exif::insert(&jpeg, &fields)?)
+                    .with_context(|| format!("Failed to write {}", file))?;
+            }
+            FileFormat::Image(format) => {
+                let image = capture.process_raw_image(&raw)?;
+                reprocess::write_image(&image, Path::new(&file), format, capture.snapshot_quality())?;
+            }
+        }
+        let process_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let record = FrameRecord {
+            bytes: std::fs::metadata(&file).map_or(0, |m| m.len()),
+            file,
+            index,
+            timestamp_ms,
+            format: options.format.name(),
+            width: capture.frame_size().width,
+            height: capture.frame_size().height,
+            mode: capture.mode().name(),
+            settings: tag.clone(),
+            raw_format: capture.raw_format().fourcc.clone(),
+            stride: capture.stride(),
+            controls: options.controls.clone(),
+            white_balance_gains: capture.white_balance_gains(),
+            capture_ms,
+            process_ms,
+        };
+        on_frame(&record);
+        records.push(record);
+    }
+    Ok(records)
+}
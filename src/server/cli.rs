@@ -0,0 +1,422 @@
+//! Command line
+//!
+//! Without a subcommand the streamer serves HTTP, configured by
+//! [`ServeArgs`]; `capture`, `self-test` and `reprocess` run once without
+//! the server and exit. Flags that are also config file settings override
+//! the file, which `--config` names.
+
+use clap::{Args, Parser, Subcommand};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::{h264, memory, recorder, snapshots, spool, thermal, watchdog};
+
+/// IMX415 camera streamer for the Rock 5C
+#[derive(Debug, Parser)]
+#[command(name = "imx415_streamer", version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Capture frames to files through the processing pipeline and exit
+    Capture(Box<CaptureArgs>),
+    /// Check the camera, the sensor and the tools, print a report and exit
+    /// (nonzero if a required check failed)
+    SelfTest(SelfTestArgs),
+    /// Run saved raw frames through the pipeline, print a report and exit
+    Reprocess(ReprocessArgs),
+}
+
+/// The server
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Config file [default: /home/angelo/imx415_streamer/imx415_streamer.toml
+    /// if it exists]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long, value_name = "IP")]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on [default: 8080]
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Processing mode to start in [default: grayscale]
+    #[arg(long)]
+    pub mode: Option<String>,
+    /// JPEG quality to start with (1-100)
+    #[arg(long)]
+    pub quality: Option<u8>,
+    #[command(flatten)]
+    pub device: DeviceArgs,
+    #[command(flatten)]
+    pub storage: StorageArgs,
+    #[command(flatten)]
+    pub detector: DetectorArgs,
+    #[command(flatten)]
+    pub limits: LimitArgs,
+    #[command(flatten)]
+    pub streaming: StreamingArgs,
+    #[command(flatten)]
+    pub recording: RecordingArgs,
+    #[command(flatten)]
+    pub watchdog: WatchdogArgs,
+    #[command(flatten)]
+    pub gpio: GpioArgs,
+    #[command(flatten)]
+    pub home_assistant: HomeAssistantArgs,
+}
+
+/// Capture device and frame source, shared by the server, `capture` and
+/// `self-test`
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Capture device")]
+pub struct DeviceArgs {
+    /// Video capture node
+    #[arg(long, value_name = "PATH")]
+    pub device: Option<String>,
+    /// Sensor subdevice
+    #[arg(long, value_name = "PATH")]
+    pub subdev: Option<String>,
+    /// Sensor link frequency menu index
+    #[arg(long, value_name = "INDEX")]
+    pub link_frequency: Option<u32>,
+    /// JPEG encoder: mpp or software
+    #[arg(long)]
+    pub encoder: Option<String>,
+    /// Scaler: rga or software
+    #[arg(long)]
+    pub scaler: Option<String>,
+    /// Threads for the processing pipeline
+    #[arg(long, value_name = "N")]
+    pub capture_threads: Option<usize>,
+    /// Bayer order override: RGGB, GRBG, GBRG or BGGR
+    #[arg(long, value_name = "ORDER")]
+    pub bayer_order: Option<String>,
+    /// Frame source: v4l2, file or synthetic [default: v4l2]
+    #[arg(long)]
+    pub source: Option<String>,
+    /// Raw dump or directory of dumps for `--source file`
+    #[arg(long, value_name = "PATH")]
+    pub source_path: Option<PathBuf>,
+    #[command(flatten)]
+    pub raw: RawArgs,
+}
+
+/// Size and layout of raw frames
+#[derive(Debug, Args)]
+pub struct RawArgs {
+    /// Sensor mode to ask for, or the size of raw frames that don't come
+    /// from the capture device: WxH or full
+    #[arg(long, value_name = "WxH")]
+    pub frame_size: Option<String>,
+    /// Fourcc of raw frames that don't come from the capture device
+    #[arg(long, value_name = "FOURCC")]
+    pub raw_format: Option<String>,
+    /// Line stride of raw frames that don't come from the capture device
+    #[arg(long, value_name = "BYTES")]
+    pub stride: Option<usize>,
+}
+
+/// Where the streamer keeps its files
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Storage")]
+pub struct StorageArgs {
+    #[command(flatten)]
+    pub state: StateArgs,
+    /// Recordings directory [default: in the data directory]
+    #[arg(long, value_name = "PATH")]
+    pub record_dir: Option<PathBuf>,
+    /// Snapshot and job output directory [default: in the data directory]
+    #[arg(long, value_name = "PATH")]
+    pub job_output_dir: Option<PathBuf>,
+}
+
+/// The data directory and the settings state file in it
+#[derive(Debug, Args)]
+pub struct StateArgs {
+    /// Data directory [default: /home/angelo/imx415_streamer/data]
+    #[arg(long, value_name = "PATH")]
+    pub data_dir: Option<PathBuf>,
+    /// Settings state file (profiles, schedule, ...) [default: in the data
+    /// directory]
+    #[arg(long, value_name = "PATH")]
+    pub state_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Detector")]
+pub struct DetectorArgs {
+    /// Backends to try in order: rknn, subprocess, onnx-cpu (`none` ends
+    /// the list)
+    #[arg(long, value_name = "LIST", value_delimiter = ',')]
+    pub detector_backends: Option<Vec<String>>,
+    /// What to detect: objects or faces
+    #[arg(long, value_name = "TASK")]
+    pub detector_task: Option<String>,
+    /// ONNX model for the CPU backend
+    #[arg(long, value_name = "PATH")]
+    pub onnx_model: Option<String>,
+    /// Input size of the ONNX model
+    #[arg(long, value_name = "PX")]
+    pub onnx_input_size: Option<u32>,
+    /// RKNN model for the NPU backend
+    #[arg(long, value_name = "PATH")]
+    pub rknn_model: Option<String>,
+    /// Model directory
+    #[arg(long, value_name = "PATH")]
+    pub models_dir: Option<PathBuf>,
+    /// Model upload size limit
+    #[arg(long, value_name = "MB")]
+    pub max_model_mb: Option<u64>,
+    /// Face crop size (16-1024)
+    #[arg(long, value_name = "PX")]
+    pub face_crop_size: Option<u32>,
+    /// Face crop margin around the box (1.0-4.0)
+    #[arg(long, value_name = "FACTOR")]
+    pub face_crop_expand: Option<f32>,
+}
+
+/// Memory, request, bandwidth and temperature limits
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Limits")]
+pub struct LimitArgs {
+    /// Memory for retained frames
+    #[arg(long, value_name = "MB", default_value_t = memory::DEFAULT_MEMORY_BUDGET_MB)]
+    pub memory_budget_mb: usize,
+    /// Full-size image requests per second per client
+    #[arg(long, value_name = "PER_SEC")]
+    pub rate_limit_images: Option<f64>,
+    /// API requests per second per client
+    #[arg(long, value_name = "PER_SEC")]
+    pub rate_limit_api: Option<f64>,
+    /// Open streams per client
+    #[arg(long, value_name = "N")]
+    pub max_streams_per_ip: Option<usize>,
+    /// Proxies whose X-Forwarded-For is trusted
+    #[arg(long, value_name = "IP,...")]
+    pub trusted_proxy: Option<String>,
+    /// Clients exempt from the rate limits
+    #[arg(long, value_name = "CIDR,...")]
+    pub rate_limit_exempt: Option<String>,
+    /// Daily outbound bandwidth budget
+    #[arg(long, value_name = "MB")]
+    pub bandwidth_budget_mb: Option<u64>,
+    /// Hour of the day the bandwidth budget resets (0-23)
+    #[arg(long, value_name = "HOUR", default_value_t = 0)]
+    pub bandwidth_reset_hour: u32,
+    /// What to degrade, in order, once the budget is spent
+    #[arg(long, value_name = "LIST", default_value = "quality,fps,streams")]
+    pub bandwidth_degrade: String,
+    /// Sensor temperature to warn at (°C)
+    #[arg(long, value_name = "C", default_value_t = thermal::DEFAULT_WARN_C)]
+    pub thermal_warn_c: f32,
+    /// Sensor temperature to throttle the frame rate at (°C)
+    #[arg(long, value_name = "C")]
+    pub thermal_throttle_c: Option<f32>,
+    /// Frame rate while throttled
+    #[arg(long, value_name = "FPS", default_value_t = thermal::DEFAULT_THROTTLED_FPS)]
+    pub thermal_throttle_fps: u32,
+}
+
+/// Streams and raw push
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Streaming")]
+pub struct StreamingArgs {
+    /// H.264 target bitrate (100-100000)
+    #[arg(long, value_name = "KBPS", default_value_t = h264::DEFAULT_BITRATE_KBPS)]
+    pub h264_kbps: u32,
+    /// STUN/TURN servers for WebRTC peers outside the LAN
+    #[arg(long, value_name = "URL,...", value_delimiter = ',')]
+    pub webrtc_ice_servers: Vec<String>,
+    /// Identical frames in a row before /status reports a static scene
+    #[arg(long, value_name = "N")]
+    pub static_scene_frames: Option<u64>,
+    /// TCP port serving raw frames
+    #[arg(long, value_name = "PORT")]
+    pub raw_push_port: Option<u16>,
+    /// Address to push frames to over UDP
+    #[arg(long, value_name = "ADDR:PORT")]
+    pub udp_push: Option<SocketAddr>,
+    /// Frame rate of the UDP push
+    #[arg(long, value_name = "FPS", default_value_t = super::DEFAULT_UDP_PUSH_FPS)]
+    pub udp_push_fps: u32,
+}
+
+/// Recordings and the snapshot archive
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Recording")]
+pub struct RecordingArgs {
+    /// Spool for frames on their way to the recording
+    #[arg(long, value_name = "MB", default_value_t = spool::DEFAULT_SPOOL_MB)]
+    pub spool_mb: u64,
+    /// Record while detections are confirmed
+    #[arg(long)]
+    pub record_on_detection: bool,
+    /// Seconds of frames kept for the start of a recording
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub record_pre_secs: u64,
+    /// Recording segment length (0: no limit)
+    #[arg(long, value_name = "SECS", default_value_t = recorder::DEFAULT_SEGMENT_SECS)]
+    pub record_segment_secs: u64,
+    /// Recording segment size
+    #[arg(long, value_name = "MB")]
+    pub record_segment_mb: Option<u64>,
+    /// Age at which archived snapshots are pruned (0: never)
+    #[arg(long, value_name = "HOURS", default_value_t = snapshots::DEFAULT_MAX_AGE_HOURS)]
+    pub snapshot_max_age_hours: u64,
+    /// Size of the snapshot archive (0: no limit)
+    #[arg(long, value_name = "MB", default_value_t = snapshots::DEFAULT_MAX_MB)]
+    pub snapshot_max_mb: u64,
+}
+
+/// The capture watchdog
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Watchdog")]
+pub struct WatchdogArgs {
+    /// Failed captures in a row before the camera is reopened (0: off)
+    #[arg(long, value_name = "N", default_value_t = watchdog::DEFAULT_MAX_FAILURES)]
+    pub watchdog_failures: u32,
+    /// Time without a frame before the camera is reopened (0: off)
+    #[arg(long, value_name = "SECS", default_value_t = watchdog::DEFAULT_MAX_FRAME_AGE_SECS)]
+    pub watchdog_frame_age_secs: u64,
+}
+
+/// Strobe output and trigger input
+#[derive(Debug, Args)]
+#[command(next_help_heading = "GPIO")]
+pub struct GpioArgs {
+    /// Strobe output line
+    #[arg(long, value_name = "N")]
+    pub strobe_gpio: Option<u32>,
+    /// The strobe is active low
+    #[arg(long)]
+    pub strobe_active_low: bool,
+    /// Strobe on before the exposure
+    #[arg(long, value_name = "US", default_value_t = 0)]
+    pub strobe_lead_us: u64,
+    /// Strobe on after the exposure
+    #[arg(long, value_name = "US", default_value_t = 0)]
+    pub strobe_lag_us: u64,
+    /// Trigger input line
+    #[arg(long, value_name = "N")]
+    pub trigger_gpio: Option<u32>,
+    /// Trigger edge: rising, falling or both [default: rising]
+    #[arg(long, value_name = "EDGE")]
+    pub trigger_edge: Option<String>,
+    /// Time to wait for a trigger [default: 1000]
+    #[arg(long, value_name = "MS")]
+    pub trigger_timeout_ms: Option<u64>,
+}
+
+/// MQTT and Home Assistant discovery
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Home Assistant")]
+pub struct HomeAssistantArgs {
+    /// MQTT broker (turns Home Assistant integration on)
+    #[arg(long, value_name = "HOST[:PORT]")]
+    pub mqtt_broker: Option<String>,
+    /// MQTT user name
+    #[arg(long, value_name = "NAME")]
+    pub mqtt_user: Option<String>,
+    /// MQTT password (or MQTT_PASSWORD)
+    #[arg(long)]
+    pub mqtt_password: Option<String>,
+    /// Node id in topics and entity ids
+    #[arg(long, value_name = "ID")]
+    pub mqtt_node_id: Option<String>,
+    /// Discovery topic prefix
+    #[arg(long, value_name = "PREFIX")]
+    pub ha_discovery_prefix: Option<String>,
+    /// Detection classes to publish as sensors [default: person]
+    #[arg(long, value_name = "CLASS,...", value_delimiter = ',')]
+    pub ha_classes: Option<Vec<String>>,
+    /// Camera image interval (0: no camera) [default: 10]
+    #[arg(long, value_name = "SECS")]
+    pub ha_camera_interval: Option<f64>,
+    /// URL of the streamer for the device page
+    #[arg(long, value_name = "URL")]
+    pub ha_url: Option<String>,
+}
+
+/// `capture -o <file>`
+#[derive(Debug, Args)]
+pub struct CaptureArgs {
+    /// Output file; with --count above 1, numbered per frame
+    #[arg(short, long, value_name = "FILE")]
+    pub output: String,
+    /// Frames to capture
+    #[arg(long, default_value_t = 1)]
+    pub count: usize,
+    /// jpeg, png, tiff or raw [default: from the file name]
+    #[arg(long)]
+    pub format: Option<String>,
+    /// Exposure to set (lines)
+    #[arg(long, value_name = "LINES")]
+    pub exposure: Option<i64>,
+    /// Analogue gain to set
+    #[arg(long, value_name = "VALUE")]
+    pub analogue_gain: Option<i64>,
+    /// Time to wait for each frame [default: 10]
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<f32>,
+    /// Config file for the capture device settings
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub pipeline: OfflineArgs,
+    #[command(flatten)]
+    pub device: DeviceArgs,
+}
+
+/// `self-test`
+#[derive(Debug, Args)]
+pub struct SelfTestArgs {
+    /// Config file for the capture device settings
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub device: DeviceArgs,
+    /// Locations as the server is started with; the disk space check looks
+    /// at the recordings directory
+    #[command(flatten)]
+    pub storage: StorageArgs,
+}
+
+/// `reprocess <dir>`
+#[derive(Debug, Args)]
+pub struct ReprocessArgs {
+    /// Directory of .raw dumps
+    pub dir: String,
+    /// jpeg, png or tiff
+    #[arg(long, default_value = "jpeg")]
+    pub format: String,
+    /// Worker threads [default: the cores, at most 4]
+    #[arg(long, value_name = "N")]
+    pub jobs: Option<usize>,
+    #[command(flatten)]
+    pub pipeline: OfflineArgs,
+    #[command(flatten)]
+    pub raw: RawArgs,
+}
+
+/// Processing settings of `capture` and `reprocess`
+#[derive(Debug, Args)]
+#[command(next_help_heading = "Processing")]
+pub struct OfflineArgs {
+    /// Saved profile whose settings to use
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Processing mode, over the profile's
+    #[arg(long)]
+    pub mode: Option<String>,
+    /// Gr/Gb balance: off, auto or a ratio, over the profile's
+    #[arg(long, value_name = "VALUE")]
+    pub gbgr: Option<String>,
+    #[command(flatten)]
+    pub state: StateArgs,
+}
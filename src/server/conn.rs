@@ -0,0 +1,89 @@
+//! HTTP server plumbing
+//!
+//! Owns the listening socket and the hyper connection loop so that
+//! connection-level limits (header read timeout, TCP keepalive) can be set,
+//! which `axum::serve` does not expose.
+
+use anyhow::{Context, Result};
+use axum::{extract::ConnectInfo, Router};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+/// Time a client has to send the complete request head
+pub const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Idle time before the first keepalive probe on a connection
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(30);
+/// Interval between keepalive probes
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Unanswered probes before the kernel drops the connection
+const KEEPALIVE_RETRIES: u32 = 3;
+
+/// Bind a listener with TCP keepalive enabled
+///
+/// Accepted sockets inherit the keepalive settings, so a vanished MJPEG
+/// client is detected within idle + interval * retries and its stream task
+/// is dropped instead of lingering until a write finally fails.
+pub fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_tcp_keepalive(
+        &TcpKeepalive::new()
+            .with_time(KEEPALIVE_IDLE)
+            .with_interval(KEEPALIVE_INTERVAL)
+            .with_retries(KEEPALIVE_RETRIES),
+    )?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to bind {}", addr))?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into()).context("Failed to register listener")
+}
+
+/// Accept connections forever, serving each with `app`
+///
+/// Requests carry `ConnectInfo<SocketAddr>` like `axum::serve` with
+/// `into_make_service_with_connect_info` would provide.
+pub async fn serve(listener: TcpListener, app: Router) -> Result<()> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(HEADER_READ_TIMEOUT);
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Typically EMFILE; back off instead of spinning
+                tracing::warn!("Accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+
+        let app = app.clone();
+        let service = hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(remote));
+            app.clone().oneshot(request)
+        });
+        let builder = builder.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Connection from {} ended: {}", remote, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,8318 @@
+//! The streamer's HTTP server (feature `server`, on by default)
+//!
+//! [`run`] is the whole streamer: given the parsed [`Cli`] it sets up the
+//! camera and detector, starts the background tasks (recording, events,
+//! schedules, Home Assistant, ...) and serves the HTTP API, whose handlers
+//! and router live here. The `capture`, `self-test` and `reprocess`
+//! subcommands run without the server and exit.
+//!
+//! [`bind`] and [`serve`] are the connection plumbing underneath.
+
+mod capturecmd;
+mod cli;
+mod conn;
+mod openapi;
+#[cfg(test)]
+mod tests;
+
+pub use cli::{
+    CaptureArgs, Cli, Command, DetectorArgs, DeviceArgs, GpioArgs, HomeAssistantArgs, LimitArgs, OfflineArgs,
+    RawArgs, RecordingArgs, ReprocessArgs, SelfTestArgs, ServeArgs, StateArgs, StorageArgs, StreamingArgs,
+    WatchdogArgs,
+};
+pub use conn::{bind, serve, HEADER_READ_TIMEOUT};
+
+use crate::{
+    annotation, bandwidth, capture, ccm, clip, compare, config, contrast, controls, daynight, decodecache, demosaic,
+    denoise, detector, dng, error, events, exif, faces, focus, framehash, gpio, greenbalance, h264, hdr, history,
+    homeassistant, jobs, latency, masks, memory, metering, metrics, models, motion, mqtt, orientation, pipeline,
+    prebuffer, profiles, push, ratelimit, rawformat, recorder, reprocess, scaledframes, schedule, selftest, sharpen,
+    snapshots, source, stack, storage, supervisor, thermal, tone, unix_millis, version, watchdog, webrtc_peer,
+    whitebalance, zones, zoom,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    middleware,
+    response::{sse, Html, IntoResponse, Response},
+    routing::{get, post, put},
+    Router,
+};
+use annotation::{AnnotationSettings, AnnotationStyle};
+use bandwidth::{Bandwidth, BudgetConfig, Degradation, FrameThrottle};
+use bytes::Bytes;
+use capture::{
+    AdaptiveQuality, AntiFlicker, AutoExposure, CaptureConfig, CaptureMode, EncoderKind, FrameCapture, FrameSize,
+    Histogram, ScalerKind, StageTimings,
+};
+use ccm::{ColorCorrection, ColorMatrix};
+use contrast::{Contrast, ContrastMode};
+use daynight::{DayNight, DayNightConfig};
+use denoise::{Denoise, SpatialDenoise, SpatialMethod};
+use decodecache::DecodeCache;
+use demosaic::DemosaicAlgorithm;
+use detector::{BackendKind, DetectionFilter, DetectionResult, DetectionTask, DetectorConfig, YoloDetector};
+use error::{ApiError, ApiResult, ErrorCode};
+use events::{DetectionTracker, DomainEvent, EventBus, EventCounters, EventLog};
+use faces::{CropRequest, FaceCrop, FaceCropConfig, FaceStore};
+use focus::{FocusMeter, FocusReading};
+use framehash::FrameHashes;
+use gpio::{FrameGpio, GpioConfig, GpioStats};
+use greenbalance::{GreenBalance, GreenBalanceMode};
+use history::FrameHistory;
+use homeassistant::{HaCommand, HaConfig, HaState, HaStats};
+use jobs::{JobState, JobTable, Step, StepState};
+use latency::{FrameTiming, LatencyTracker};
+use memory::{Component, MemoryPressure, MemoryTracker};
+use masks::Mask;
+use metering::{MeteringConfig, MeteringMode, Roi};
+use motion::{MotionConfig, MotionDetector, MotionStatus, Transition};
+use orientation::Orientation;
+use pipeline::{PipelineSettings, SettingsCell, SettingsSnapshot};
+use prebuffer::PreBuffer;
+use profiles::{ProfileStore, Settings, Skipped};
+use push::{PushFrame, PushStats};
+use ratelimit::{RateLimitConfig, RateLimiter};
+use scaledframes::ScaledFrames;
+use snapshots::{Retention, SnapshotArchive, SnapshotInfo};
+use source::SourceConfig;
+use stack::{FrameStack, RawLayout, StackMode, StackState, StackStatus};
+use tone::Tone;
+use recorder::{RecordFormat, Recorder, Rollover};
+use schedule::{Feature, Schedule, ScheduleConfig, Scheduler};
+use image::DynamicImage;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{sync::Arc, time::Duration};
+use supervisor::{Backoff, Subsystem};
+use zones::{Zone, ZoneTracker};
+use zoom::Zoom;
+use thermal::{ThermalConfig, ThermalStatus};
+use watchdog::{CaptureWatchdog, WatchdogConfig};
+use whitebalance::{WbSmoothing, WhiteBalanceMode};
+use webrtc_peer::WebRtc;
+use tokio::sync::{oneshot, watch, Notify, Semaphore};
+use tokio::time::interval;
+use tower_http::{limit::RequestBodyLimitLayer, timeout::TimeoutLayer};
+use futures::StreamExt;
+use tracing::{info, error, Level};
+use tracing_subscriber::FmtSubscriber;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+/// Shared application state
+struct AppState {
+    /// Latest frame without detection annotations
+    clean_frame: RwLock<Option<Bytes>>,
+    /// Content hashes of the clean and the published frame; the capture
+    /// loop swaps the clean frame and publishes on `frame_watch` while
+    /// holding this lock, so take it first to read a frame together with
+    /// its hash
+    frame_hashes: RwLock<Option<FrameHashes>>,
+    /// Identical frames in a row before /status reports a static scene
+    static_scene_frames: RwLock<u64>,
+    /// Full-resolution pixels of the latest frame (pre-encode, unannotated)
+    current_image: RwLock<Option<Arc<DynamicImage>>>,
+    /// Pixels of published JPEGs for endpoints that re-encode or draw on them
+    decode_cache: DecodeCache,
+    /// Downscaled and re-encoded encodings of published frames (per output
+    /// width and quality), shared by stream clients
+    scaled_frames: ScaledFrames,
+    capture: RwLock<Option<FrameCapture>>,
+    /// Time spent in each pipeline stage of the latest frame
+    stage_timings: RwLock<Option<StageTimings>>,
+    /// Histogram of the latest frame and its sequence number
+    histogram: RwLock<Option<(u64, Arc<Histogram>)>>,
+    frame_count: RwLock<u64>,
+    /// Processing settings, published per version and picked up by the
+    /// capture pipeline at the next frame
+    pipeline: Arc<SettingsCell>,
+    /// Recent frames and detection records, keyed by frame sequence
+    history: RwLock<FrameHistory>,
+    /// A/B comparison reference (outside the history ring)
+    compare_reference: RwLock<Option<compare::Reference>>,
+    /// Byte counts of everything above that retains frame data
+    memory: MemoryTracker,
+    /// Per-client request and stream limits
+    rate_limiter: Arc<RateLimiter>,
+    /// Outbound bytes per route and stream, and the daily budget
+    bandwidth: Arc<Bandwidth>,
+    /// Open MJPEG stream responses
+    stream_clients: Arc<AtomicUsize>,
+    /// Per-frame pipeline timings and per-client send timings
+    latency: Arc<LatencyTracker>,
+    /// Pending /latency/blink request, answered with the frame's sequence
+    /// and timestamp once the marker has been published
+    blink: parking_lot::Mutex<Option<oneshot::Sender<(u64, u64)>>>,
+    /// One animated clip encode at a time
+    clip_encoder: Arc<Semaphore>,
+    /// Frame bus: every published frame (annotated) with its sequence and
+    /// timing. Streams, push, the recorder and job waits await changes on
+    /// it; a receiver that falls behind sees only the newest frame.
+    frame_watch: watch::Sender<Option<PushFrame>>,
+    /// Shared H.264 encoder for /video.mp4 and WebRTC (runs while clients
+    /// are connected)
+    h264: Arc<h264::H264Stream>,
+    /// WebRTC peers of the H.264 stream
+    webrtc: WebRtc,
+    /// Raw TCP/UDP push: configured endpoints and counters
+    push_config: RwLock<Option<PushConfig>>,
+    push_stats: Arc<PushStats>,
+    /// Home Assistant MQTT bridge: configuration and counters
+    home_assistant: RwLock<Option<HaConfig>>,
+    ha_stats: Arc<HaStats>,
+    /// MJPEG recorder and its disk spool (None if the directory is unusable)
+    recorder: RwLock<Option<Arc<Recorder>>>,
+    /// Stills saved with POST /snapshots (None until the data layout is set up)
+    snapshot_archive: RwLock<Option<Arc<SnapshotArchive>>>,
+    /// Raw frame stack (/stack/start), fed by the capture loop; kept after
+    /// it finishes for download
+    stack: parking_lot::Mutex<Option<FrameStack>>,
+    /// Domain events and the built-in subscribers' state
+    events: Arc<EventBus>,
+    event_log: Arc<EventLog>,
+    event_counters: Arc<EventCounters>,
+    /// Detection state as last published on the event bus
+    detection_events: parking_lot::Mutex<DetectionTracker>,
+    /// Zone+class pairs as last published on the event bus
+    zone_events: parking_lot::Mutex<ZoneTracker>,
+    /// Frame-differencing motion detection (config persisted in the settings state file)
+    motion: parking_lot::Mutex<MotionDetector>,
+    /// Sharpness score of the published frames, for manual focusing
+    focus: parking_lot::Mutex<FocusMeter>,
+    /// Day/night mode switching (config persisted in the settings state file)
+    day_night: parking_lot::Mutex<DayNight>,
+    // Detection state
+    detector: RwLock<Option<YoloDetector>>,
+    detection_enabled: RwLock<bool>,
+    /// Record while detections are confirmed (`--record-on-detection`)
+    record_on_detection: RwLock<bool>,
+    /// Feed every Nth captured frame to the detector (0 = on demand only)
+    detection_interval: RwLock<u32>,
+    /// Interval actually used, raised when a CPU backend can't keep up
+    effective_detection_interval: RwLock<u32>,
+    detector_config: RwLock<DetectorConfig>,
+    last_detections: RwLock<DetectionResult>,
+    /// Face sightings and the latest face crop
+    faces: RwLock<FaceStore>,
+    /// Class label map and box label style (persisted in the settings state file)
+    annotation: RwLock<AnnotationSettings>,
+    /// Active sensor test pattern (menu label), if any
+    test_pattern: RwLock<Option<String>>,
+    /// Scripted capture sequences (POST /jobs) and where their snapshots go
+    jobs: RwLock<JobTable>,
+    job_output_dir: RwLock<std::path::PathBuf>,
+    /// Data directory layout (state file, recordings, spool, snapshots)
+    storage: RwLock<storage::Layout>,
+    /// Config file path and its contents as of startup
+    config: RwLock<(std::path::PathBuf, config::ConfigFile)>,
+    /// Named settings profiles (persisted in the settings state file)
+    profiles: RwLock<ProfileStore>,
+    /// Time-of-day feature schedule (persisted in the settings state file)
+    scheduler: RwLock<Scheduler>,
+    /// Wakes the scheduler task after the schedule was replaced
+    schedule_changed: Notify,
+    /// Strobe output / trigger input lines (used only by the capture loop)
+    gpio_config: RwLock<GpioConfig>,
+    gpio: parking_lot::Mutex<Option<FrameGpio>>,
+    gpio_stats: GpioStats,
+    /// SoC/sensor temperatures and the thermal throttling policy
+    thermal_config: RwLock<ThermalConfig>,
+    thermal: RwLock<ThermalStatus>,
+    // Supervised background subsystems
+    camera_supervisor: Subsystem,
+    /// Failed captures and frame age, for reopening a stalled camera
+    capture_watchdog: parking_lot::Mutex<CaptureWatchdog>,
+    detector_supervisor: Subsystem,
+    push_supervisor: Subsystem,
+    mqtt_supervisor: Subsystem,
+}
+
+impl AppState {
+    fn new(
+        memory_budget: usize,
+        rate_limits: RateLimitConfig,
+        budget: Option<BudgetConfig>,
+        pipeline: Arc<SettingsCell>,
+        h264_bitrate_kbps: u32,
+        ice_servers: Vec<String>,
+        scaler: ScalerKind,
+    ) -> Self {
+        let bandwidth = Arc::new(Bandwidth::new(budget, rate_limits.trusted_proxies.clone()));
+        Self {
+            clean_frame: RwLock::new(None),
+            frame_hashes: RwLock::new(None),
+            static_scene_frames: RwLock::new(framehash::DEFAULT_STATIC_SCENE_FRAMES),
+            current_image: RwLock::new(None),
+            decode_cache: DecodeCache::default(),
+            scaled_frames: ScaledFrames::new(scaler),
+            capture: RwLock::new(None),
+            stage_timings: RwLock::new(None),
+            histogram: RwLock::new(None),
+            frame_count: RwLock::new(0),
+            pipeline,
+            history: RwLock::new(FrameHistory::new()),
+            compare_reference: RwLock::new(None),
+            memory: MemoryTracker::new(memory_budget),
+            rate_limiter: Arc::new(RateLimiter::new(rate_limits)),
+            bandwidth,
+            stream_clients: Arc::new(AtomicUsize::new(0)),
+            latency: Arc::new(LatencyTracker::new()),
+            blink: parking_lot::Mutex::new(None),
+            clip_encoder: Arc::new(Semaphore::new(1)),
+            frame_watch: watch::Sender::new(None),
+            h264: Arc::new(h264::H264Stream::new(h264_bitrate_kbps)),
+            webrtc: WebRtc::new(ice_servers),
+            push_config: RwLock::new(None),
+            push_stats: Arc::new(PushStats::default()),
+            home_assistant: RwLock::new(None),
+            ha_stats: Arc::new(HaStats::default()),
+            recorder: RwLock::new(None),
+            snapshot_archive: RwLock::new(None),
+            stack: parking_lot::Mutex::new(None),
+            events: Arc::new(EventBus::new()),
+            event_log: Arc::new(EventLog::default()),
+            event_counters: Arc::new(EventCounters::default()),
+            detection_events: parking_lot::Mutex::new(DetectionTracker::default()),
+            zone_events: parking_lot::Mutex::new(ZoneTracker::default()),
+            motion: parking_lot::Mutex::new(MotionDetector::new(MotionConfig::default())),
+            focus: parking_lot::Mutex::new(FocusMeter::default()),
+            day_night: parking_lot::Mutex::new(DayNight::new(DayNightConfig::default())),
+            detector: RwLock::new(None),
+            detection_enabled: RwLock::new(false),
+            record_on_detection: RwLock::new(false),
+            detection_interval: RwLock::new(DEFAULT_DETECTION_INTERVAL),
+            effective_detection_interval: RwLock::new(DEFAULT_DETECTION_INTERVAL),
+            detector_config: RwLock::new(DetectorConfig::default()),
+            last_detections: RwLock::new(DetectionResult::default()),
+            faces: RwLock::new(FaceStore::default()),
+            annotation: RwLock::new(AnnotationSettings::default()),
+            test_pattern: RwLock::new(None),
+            jobs: RwLock::new(JobTable::new()),
+            job_output_dir: RwLock::new(storage::Layout::default().snapshots_dir),
+            storage: RwLock::new(storage::Layout::default()),
+            config: RwLock::new((config::DEFAULT_CONFIG_FILE.into(), config::ConfigFile::default())),
+            profiles: RwLock::new(ProfileStore::empty(&storage::Layout::default().state_file)),
+            scheduler: RwLock::new(Scheduler::default()),
+            schedule_changed: Notify::new(),
+            gpio_config: RwLock::new(GpioConfig::default()),
+            gpio: parking_lot::Mutex::new(None),
+            gpio_stats: GpioStats::default(),
+            thermal_config: RwLock::new(ThermalConfig::default()),
+            thermal: RwLock::new(ThermalStatus::default()),
+            camera_supervisor: Subsystem::new("camera"),
+            capture_watchdog: parking_lot::Mutex::new(CaptureWatchdog::new(WatchdogConfig::default())),
+            detector_supervisor: Subsystem::new("detector"),
+            push_supervisor: Subsystem::new("push"),
+            mqtt_supervisor: Subsystem::new("mqtt"),
+        }
+    }
+}
+
+type SharedState = Arc<AppState>;
+
+/// `--rate-limit-images <per_sec>`, `--rate-limit-api <per_sec>`,
+/// `--max-streams-per-ip <n>`, `--trusted-proxy <ip,...>`,
+/// `--rate-limit-exempt <cidr,...>`
+fn rate_limit_config_from_args(args: &LimitArgs) -> Result<RateLimitConfig> {
+    let ranges = |list: &Option<String>| {
+        list.as_deref()
+            .map(|v| ratelimit::parse_ranges(v).map_err(anyhow::Error::msg))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    };
+    Ok(RateLimitConfig {
+        images_per_sec: args.rate_limit_images,
+        api_per_sec: args.rate_limit_api,
+        max_streams_per_ip: args.max_streams_per_ip,
+        trusted_proxies: ranges(&args.trusted_proxy).context("Invalid --trusted-proxy")?,
+        exempt: ranges(&args.rate_limit_exempt).context("Invalid --rate-limit-exempt")?,
+    })
+}
+
+/// `--bandwidth-budget-mb <mb>`, `--bandwidth-reset-hour <0-23>`,
+/// `--bandwidth-degrade <quality,fps,streams>`
+fn bandwidth_config_from_args(args: &LimitArgs) -> Result<Option<BudgetConfig>> {
+    let Some(mb) = args.bandwidth_budget_mb else {
+        return Ok(None);
+    };
+    if mb == 0 {
+        anyhow::bail!("--bandwidth-budget-mb must be positive");
+    }
+    if args.bandwidth_reset_hour > 23 {
+        anyhow::bail!("--bandwidth-reset-hour must be 0-23");
+    }
+    let degrade = BudgetConfig::parse_order(&args.bandwidth_degrade).map_err(anyhow::Error::msg)?;
+    Ok(Some(BudgetConfig { bytes_per_day: mb * 1024 * 1024, reset_hour: args.bandwidth_reset_hour, degrade }))
+}
+
+/// `--thermal-warn-c <t>`, `--thermal-throttle-c <t>`, `--thermal-throttle-fps <n>`
+fn thermal_config_from_args(args: &LimitArgs) -> ThermalConfig {
+    ThermalConfig {
+        warn_c: args.thermal_warn_c,
+        throttle_c: args.thermal_throttle_c,
+        throttled_fps: args.thermal_throttle_fps,
+    }
+}
+
+/// `--watchdog-failures <n>`, `--watchdog-frame-age-secs <secs>` (0 turns
+/// that check off)
+fn watchdog_config_from_args(args: &WatchdogArgs) -> WatchdogConfig {
+    WatchdogConfig {
+        max_failures: (args.watchdog_failures > 0).then_some(args.watchdog_failures),
+        max_frame_age: (args.watchdog_frame_age_secs > 0).then(|| Duration::from_secs(args.watchdog_frame_age_secs)),
+    }
+}
+
+/// `--strobe-gpio <n>`, `--strobe-active-low`, `--strobe-lead-us <us>`,
+/// `--strobe-lag-us <us>`, `--trigger-gpio <n>`,
+/// `--trigger-edge rising|falling|both`, `--trigger-timeout-ms <ms>`
+fn gpio_config_from_args(args: &GpioArgs) -> Result<GpioConfig> {
+    let defaults = GpioConfig::default();
+    let trigger_edge = match &args.trigger_edge {
+        Some(edge) => gpio::Edge::parse(edge)
+            .ok_or_else(|| anyhow::anyhow!("Invalid --trigger-edge {} (rising, falling or both)", edge))?,
+        None => defaults.trigger_edge,
+    };
+    Ok(GpioConfig {
+        strobe_pin: args.strobe_gpio,
+        strobe_active_low: args.strobe_active_low,
+        strobe_lead_us: args.strobe_lead_us,
+        strobe_lag_us: args.strobe_lag_us,
+        trigger_pin: args.trigger_gpio,
+        trigger_edge,
+        trigger_timeout_ms: args.trigger_timeout_ms.unwrap_or(defaults.trigger_timeout_ms),
+    })
+}
+
+/// Raw push endpoints from the command line
+#[derive(Debug, Clone)]
+struct PushConfig {
+    tcp_port: Option<u16>,
+    udp_target: Option<std::net::SocketAddr>,
+    udp_fps: u32,
+}
+
+impl PushConfig {
+    /// `--raw-push-port <port>`, `--udp-push <addr:port>`, `--udp-push-fps <n>`
+    fn from_args(args: &StreamingArgs) -> Option<Self> {
+        if args.raw_push_port.is_none() && args.udp_push.is_none() {
+            return None;
+        }
+        Some(Self { tcp_port: args.raw_push_port, udp_target: args.udp_push, udp_fps: args.udp_push_fps })
+    }
+}
+
+/// `--mqtt-broker <host[:port]>`, `--mqtt-user <name>`, `--mqtt-password
+/// <password>` (or `MQTT_PASSWORD`), `--mqtt-node-id <id>`,
+/// `--ha-discovery-prefix <prefix>`, `--ha-classes <class,...>`,
+/// `--ha-camera-interval <secs>` (0: no camera), `--ha-url <url>`
+fn ha_config_from_args(args: &HomeAssistantArgs) -> Result<Option<HaConfig>> {
+    let Some(broker) = args.mqtt_broker.clone() else {
+        return Ok(None);
+    };
+    let node_id = args.mqtt_node_id.clone().unwrap_or_else(|| homeassistant::DEFAULT_NODE_ID.to_string());
+    anyhow::ensure!(
+        crate::valid_name(&node_id),
+        "Invalid --mqtt-node-id {} (letters, digits, '_' and '-')",
+        node_id
+    );
+    let classes = args
+        .ha_classes
+        .as_ref()
+        .map(|classes| classes.iter().map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect())
+        .unwrap_or_else(|| vec!["person".to_string()]);
+    let camera_interval = match args.ha_camera_interval {
+        Some(secs) => Some(Duration::try_from_secs_f64(secs).context("Invalid --ha-camera-interval")?)
+            .filter(|d| !d.is_zero()),
+        None => Some(DEFAULT_HA_CAMERA_INTERVAL),
+    };
+    Ok(Some(HaConfig {
+        mqtt: mqtt::MqttOptions {
+            broker,
+            client_id: format!("imx415_streamer_{}", node_id),
+            username: args.mqtt_user.clone(),
+            password: args.mqtt_password.clone().or_else(|| std::env::var("MQTT_PASSWORD").ok()),
+            keep_alive: homeassistant::KEEP_ALIVE,
+            last_will: None,
+        },
+        discovery_prefix: args
+            .ha_discovery_prefix
+            .clone()
+            .unwrap_or_else(|| homeassistant::DEFAULT_DISCOVERY_PREFIX.to_string()),
+        node_id,
+        classes,
+        camera_interval,
+        url: args.ha_url.clone(),
+    }))
+}
+
+/// `--detector-backends rknn,subprocess,onnx-cpu`, `--detector-task objects|faces`,
+/// `--onnx-model <path>`, `--onnx-input-size <n>`, `--rknn-model <path>`,
+/// `--models-dir <path>`, `--max-model-mb <n>`; `[detector]` in the config
+/// file otherwise
+fn detector_config_from_args(args: &DetectorArgs, file: &config::DetectorFileConfig) -> Result<DetectorConfig> {
+    let mut config = DetectorConfig::default();
+    if let Some(task) = args.detector_task.clone().or_else(|| file.task.clone()) {
+        config.task = DetectionTask::parse(&task)
+            .ok_or_else(|| anyhow::anyhow!("Invalid --detector-task {} (objects or faces)", task))?;
+        if config.task == DetectionTask::Faces {
+            config.onnx_model = detector::DEFAULT_FACE_ONNX_MODEL.to_string();
+        }
+    }
+    if let Some(order) = args.detector_backends.as_ref().or(file.backends.as_ref()) {
+        config.backends = Vec::new();
+        for name in order {
+            match BackendKind::parse(name) {
+                Some(kind) => config.backends.push(kind),
+                // "none" ends the list: later entries are never tried
+                None if name.trim() == "none" => break,
+                None => tracing::warn!("Unknown detector backend '{}' (ignored)", name.trim()),
+            }
+        }
+    }
+    if let Some(model) = args.onnx_model.clone().or_else(|| file.onnx_model.clone()) {
+        config.onnx_model = model;
+    }
+    if let Some(size) = args.onnx_input_size {
+        config.onnx_input_size = size;
+    }
+    config.rknn_model = args.rknn_model.clone().or_else(|| file.rknn_model.clone());
+    if let Some(dir) = args.models_dir.clone().or_else(|| file.models_dir.clone()) {
+        config.models_dir = dir;
+    }
+    if let Some(mb) = args.max_model_mb {
+        config.max_model_bytes = mb * 1024 * 1024;
+    }
+    Ok(config)
+}
+
+/// `--face-crop-size <px>`, `--face-crop-expand <factor>`
+fn face_crop_config_from_args(args: &DetectorArgs) -> Result<FaceCropConfig> {
+    let defaults = FaceCropConfig::default();
+    let config = FaceCropConfig {
+        size: args.face_crop_size.unwrap_or(defaults.size),
+        expand: args.face_crop_expand.unwrap_or(defaults.expand),
+    };
+    anyhow::ensure!((16..=1024).contains(&config.size), "--face-crop-size must be between 16 and 1024");
+    anyhow::ensure!((1.0..=4.0).contains(&config.expand), "--face-crop-expand must be between 1.0 and 4.0");
+    Ok(config)
+}
+
+/// `--config <path>`, else the default config file if there is one
+fn config_file_from_args(path: Option<&std::path::Path>) -> Result<(std::path::PathBuf, config::ConfigFile)> {
+    let Some(path) = path else {
+        let path = std::path::PathBuf::from(config::DEFAULT_CONFIG_FILE);
+        if !path.exists() {
+            return Ok((path, config::ConfigFile::default()));
+        }
+        let file = config::ConfigFile::load(&path)?;
+        info!("Config file: {}", path.display());
+        return Ok((path, file));
+    };
+    let file = config::ConfigFile::load(path)?;
+    info!("Config file: {}", path.display());
+    Ok((path.to_path_buf(), file))
+}
+
+/// `--device <path>` (video node), `--subdev <path>` (sensor subdevice),
+/// `--link-frequency <index>`, `--encoder <mpp|software>`,
+/// `--scaler <rga|software>`, `--capture-threads <n>`,
+/// `--bayer-order <RGGB|GRBG|GBRG|BGGR>`, `--frame-size <WxH|full>`;
+/// `[capture]` in the config file otherwise. The frame source: see
+/// `source_config_from_args`.
+fn capture_config_from_args(args: &DeviceArgs, file: &config::CaptureFileConfig) -> Result<CaptureConfig> {
+    let mut config = CaptureConfig::default();
+    if let Some(device) = args.device.clone().or_else(|| file.device.clone()) {
+        config.device_path = device;
+    }
+    if let Some(subdev) = args.subdev.clone().or_else(|| file.subdev.clone()) {
+        config.sensor_subdev = subdev;
+    }
+    if let Some(index) = args.link_frequency.or(file.link_frequency) {
+        config.link_frequency = index;
+    }
+    if let Some(name) = args.encoder.clone().or_else(|| file.encoder.clone()) {
+        config.encoder = EncoderKind::parse(&name).with_context(|| format!("Invalid encoder '{}'", name))?;
+    }
+    if let Some(name) = args.scaler.clone().or_else(|| file.scaler.clone()) {
+        config.scaler = ScalerKind::parse(&name).with_context(|| format!("Invalid scaler '{}'", name))?;
+    }
+    config.threads = args.capture_threads.or(file.threads);
+    anyhow::ensure!(config.threads != Some(0), "--capture-threads must be at least 1");
+    if let Some(order) = args.bayer_order.clone().or_else(|| file.bayer_order.clone()) {
+        config.bayer_order = Some(rawformat::Cfa::parse(&order).with_context(|| format!("Invalid Bayer order '{}'", order))?);
+    }
+    config.frame_size = frame_size_from_args(&args.raw, file.frame_size.clone())?;
+    config.source = source_config_from_args(args, config.frame_size)?;
+    Ok(config)
+}
+
+/// `--mode <mode>` (default grayscale), `--quality <1-100>`: processing
+/// settings the server starts with, over those in the config file
+fn initial_pipeline_from_args(args: &ServeArgs, file: &config::ConfigFile) -> Result<PipelineSettings> {
+    // Grayscale is the stable default
+    let mut settings = PipelineSettings { mode: CaptureMode::Grayscale, ..PipelineSettings::default() };
+    file.apply_pipeline(&mut settings);
+    if let Some(mode) = &args.mode {
+        settings.mode = CaptureMode::parse(mode).with_context(|| format!("Invalid --mode '{}'", mode))?;
+    }
+    if let Some(quality) = args.quality {
+        anyhow::ensure!((1..=100).contains(&quality), "Invalid --quality '{}' (1-100)", quality);
+        settings.jpeg_quality = quality;
+    }
+    Ok(settings)
+}
+
+/// `--bind <ip>` (default 0.0.0.0), `--port <n>` (default 8080); `[server]`
+/// in the config file otherwise
+fn listen_addr_from_args(args: &ServeArgs, file: &config::ServerConfig) -> std::net::SocketAddr {
+    let ip = args.bind.or(file.bind).unwrap_or(DEFAULT_BIND_ADDR);
+    let port = args.port.or(file.port).unwrap_or(DEFAULT_PORT);
+    std::net::SocketAddr::new(ip, port)
+}
+
+/// Default UDP thumbnail rate
+const DEFAULT_UDP_PUSH_FPS: u32 = 2;
+
+/// Home Assistant camera image period, width and JPEG quality
+const DEFAULT_HA_CAMERA_INTERVAL: Duration = Duration::from_secs(10);
+const HA_CAMERA_WIDTH: u32 = 640;
+const HA_CAMERA_QUALITY: u8 = 75;
+
+/// How often detection state is sampled for Home Assistant
+const HA_STATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Response timeout for ordinary (non-streaming) requests
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Response timeout for slow diagnostic requests
+const SLOW_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Response timeout for model uploads (hundreds of MB over Wi-Fi)
+const MODEL_UPLOAD_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Largest accepted request body; our JSON payloads are tiny
+const MAX_REQUEST_BODY_BYTES: usize = 8 * 1024;
+
+/// Maximum frame rate a stream client may request
+const MAX_STREAM_FPS: u32 = 30;
+
+/// Default detection cadence: every 3rd frame keeps the stream framerate up
+const DEFAULT_DETECTION_INTERVAL: u32 = 3;
+
+/// Listen address unless `--bind`/`--port` are given
+const DEFAULT_BIND_ADDR: std::net::IpAddr = std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+const DEFAULT_PORT: u16 = 8080;
+
+/// Capture tick period (~30 fps target)
+const CAPTURE_PERIOD: Duration = Duration::from_millis(33);
+
+/// How often temperatures are polled
+const THERMAL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Job snapshot directory (under the temp dir) before the versioned data
+/// layout; moved into the layout at startup
+const LEGACY_JOB_OUTPUT_DIR: &str = "imx415_jobs";
+
+/// A job's frame wait fails if no frame arrives for this long
+const JOB_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A blink request fails if no frame is published for this long
+const BLINK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long POST /detect/once waits for the detector
+const DETECT_ONCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest scheduler sleep; catches wall-clock steps (NTP after boot)
+const SCHEDULE_RECHECK: Duration = Duration::from_secs(60);
+
+/// How often the detector thread is checked for a crashed backend
+const DETECTOR_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Delay before the first automatic detector restart, doubling up to the max
+const DETECTOR_RESTART_MIN_DELAY: Duration = Duration::from_secs(1);
+const DETECTOR_RESTART_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A restarted detector that stays up this long resets the backoff
+const DETECTOR_STABLE_PERIOD: Duration = Duration::from_secs(120);
+
+/// How often the capture watchdog checks for a stall
+const WATCHDOG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before reopening a stalled camera, doubling up to the max
+const CAMERA_RESTART_MIN_DELAY: Duration = Duration::from_secs(1);
+const CAMERA_RESTART_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// A reopened camera that stays up this long resets the backoff
+const CAMERA_STABLE_PERIOD: Duration = Duration::from_secs(120);
+
+/// Which frame variant a client wants
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum StreamView {
+    /// Frame with detection boxes drawn (when detection is enabled)
+    #[default]
+    Annotated,
+    /// Frame exactly as captured
+    Clean,
+}
+
+impl StreamView {
+    /// The frame for this view and its content hash
+    fn select_hashed_frame(self, state: &AppState) -> Option<(Bytes, u64)> {
+        // Held while reading the frame so it can't be replaced in between
+        let hashes = state.frame_hashes.read();
+        let hashes = hashes.as_ref()?;
+        match self {
+            StreamView::Annotated => state.frame_watch.borrow().as_ref().map(|frame| (frame.jpeg.clone(), frame.hash)),
+            StreamView::Clean => Some((state.clean_frame.read().clone()?, hashes.clean)),
+        }
+    }
+}
+
+/// Output resolution preset for stream clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+enum ResolutionPreset {
+    #[default]
+    #[serde(rename = "full")]
+    Full,
+    #[serde(rename = "1080p")]
+    P1080,
+    #[serde(rename = "720p")]
+    P720,
+    #[serde(rename = "480p")]
+    P480,
+}
+
+impl ResolutionPreset {
+    const NAMES: [&'static str; 4] = ["full", "1080p", "720p", "480p"];
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "full" => Some(ResolutionPreset::Full),
+            "1080p" => Some(ResolutionPreset::P1080),
+            "720p" => Some(ResolutionPreset::P720),
+            "480p" => Some(ResolutionPreset::P480),
+            _ => None,
+        }
+    }
+
+    fn max_width(self) -> Option<u32> {
+        match self {
+            ResolutionPreset::Full => None,
+            ResolutionPreset::P1080 => Some(1920),
+            ResolutionPreset::P720 => Some(1280),
+            ResolutionPreset::P480 => Some(854),
+        }
+    }
+}
+
+/// Per-client stream parameters (`?fps=&quality=&view=&res=`)
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StreamParams {
+    /// Frame rate cap (default and maximum: the capture rate)
+    fps: Option<u32>,
+    /// JPEG quality; frames are re-encoded when set
+    quality: Option<u8>,
+    #[serde(default)]
+    view: StreamView,
+    #[serde(default)]
+    res: ResolutionPreset,
+}
+
+impl StreamParams {
+    fn frame_interval(&self) -> Duration {
+        let fps = self.fps.unwrap_or(MAX_STREAM_FPS).clamp(1, MAX_STREAM_FPS);
+        Duration::from_millis(1000 / fps as u64)
+    }
+
+    /// The selected frame and its content hash
+    fn select_hashed_frame(&self, state: &AppState) -> Option<(Bytes, u64)> {
+        self.view.select_hashed_frame(state)
+    }
+
+    /// Single frames drop to 480p at degraded quality while the bandwidth
+    /// budget refuses new streams
+    fn budget_limited(self, bandwidth: &Bandwidth) -> Self {
+        if !bandwidth.is_engaged(Degradation::Streams) {
+            return self;
+        }
+        let quality = self.quality.unwrap_or(u8::MAX).min(bandwidth::DEGRADED_QUALITY);
+        Self { quality: Some(quality), res: ResolutionPreset::P480, ..self }
+    }
+
+    /// Re-encode the frame (whose hash is `hash`) if a quality or
+    /// resolution was requested
+    async fn apply(&self, state: &AppState, frame: Bytes, hash: u64) -> Bytes {
+        self.apply_capped(state, frame, hash, None).await
+    }
+
+    /// `apply`, with the JPEG quality held at or below `quality_cap`
+    async fn apply_capped(&self, state: &AppState, frame: Bytes, hash: u64, quality_cap: Option<u8>) -> Bytes {
+        let max_width = self.res.max_width();
+        if self.quality.is_none() && quality_cap.is_none() && max_width.is_none() {
+            return frame;
+        }
+        let quality = self.quality.unwrap_or(90).min(quality_cap.unwrap_or(u8::MAX));
+        // Encodes are shared between clients
+        let pixels = || state.decode_cache.get(hash, frame.clone());
+        match state.scaled_frames.get(hash, max_width, quality, pixels).await {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!("Failed to transcode frame: {:#}", e);
+                frame
+            }
+        }
+    }
+}
+
+/// Run the streamer, or the subcommand the command line names
+pub async fn run(cli: Cli) -> Result<()> {
+    if let Some(Command::Capture(args)) = &cli.command {
+        // Stdout carries the frame metadata
+        let subscriber = FmtSubscriber::builder()
+            .with_max_level(Level::INFO)
+            .with_writer(std::io::stderr)
+            .finish();
+        tracing::subscriber::set_global_default(subscriber)?;
+        run_capture_command(args).await;
+    }
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    match &cli.command {
+        Some(Command::SelfTest(args)) => run_self_test(args),
+        Some(Command::Reprocess(args)) => run_reprocess(args),
+        _ => {}
+    }
+    let args = &cli.serve;
+
+    info!(
+        "IMX415 Streamer {} ({}) starting...",
+        version::VERSION,
+        version::describe()
+    );
+
+    let (config_path, config_file) = config_file_from_args(args.config.as_deref())?;
+    let addr = listen_addr_from_args(args, &config_file.server);
+    let layout = storage_layout_from_args(&args.storage);
+    let mut detector_config = detector_config_from_args(&args.detector, &config_file.detector)?;
+    prepare_storage(&args.storage, &layout, &detector_config.models_dir)?;
+    detector_config.filter = load_detection_filter(&layout.state_file)?;
+    detector_config.zones = config_file.zones.clone();
+
+    let pipeline = Arc::new(SettingsCell::new(initial_pipeline_from_args(args, &config_file)?));
+    let mut capture = FrameCapture::with_config(capture_config_from_args(&args.device, &config_file.capture)?, pipeline.clone())?;
+    capture.setup_sensor()?;
+    capture.start_streaming()?;
+    
+    info!("Camera initialized");
+
+    let memory_budget_mb = args.limits.memory_budget_mb;
+    info!("Frame memory budget: {} MB", memory_budget_mb);
+    let rate_limits = rate_limit_config_from_args(&args.limits)?;
+    if rate_limits.is_enabled() {
+        info!(
+            "Rate limits: images {:?}/s, API {:?}/s, streams per IP {:?} ({} exempt ranges, {} trusted proxies)",
+            rate_limits.images_per_sec,
+            rate_limits.api_per_sec,
+            rate_limits.max_streams_per_ip,
+            rate_limits.exempt.len(),
+            rate_limits.trusted_proxies.len()
+        );
+    }
+    let budget = bandwidth_config_from_args(&args.limits)?;
+    if let Some(ref budget) = budget {
+        info!(
+            "Bandwidth budget: {} MB/day from {:02}:00, then degrade {:?}",
+            budget.bytes_per_day / (1024 * 1024),
+            budget.reset_hour,
+            budget.degrade
+        );
+    }
+    let h264_bitrate_kbps = args.streaming.h264_kbps;
+    anyhow::ensure!((100..=100_000).contains(&h264_bitrate_kbps), "--h264-kbps must be between 100 and 100000");
+    // STUN/TURN for WebRTC peers outside the LAN
+    let ice_servers = args
+        .streaming
+        .webrtc_ice_servers
+        .iter()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect();
+    let state = Arc::new(AppState::new(
+        memory_budget_mb * 1024 * 1024,
+        rate_limits,
+        budget,
+        pipeline,
+        h264_bitrate_kbps,
+        ice_servers,
+        capture.config().scaler,
+    ));
+    *state.capture.write() = Some(capture);
+
+    // Try to initialize YOLO detector (optional - will work without it)
+    *state.detector_config.write() = detector_config;
+    *state.faces.write() = FaceStore::new(face_crop_config_from_args(&args.detector)?);
+    start_detector(&state);
+    let (_, skipped) = apply_config_live(&state, &config_file);
+    for skip in skipped {
+        tracing::warn!("Config file: {} not applied: {}", skip.setting, skip.reason);
+    }
+    *state.config.write() = (config_path, config_file);
+    *state.thermal_config.write() = thermal_config_from_args(&args.limits);
+    let watchdog_config = watchdog_config_from_args(&args.watchdog);
+    info!(
+        "Capture watchdog: reopen after {} failed captures in a row, {} without a frame",
+        watchdog_config.max_failures.map_or("(off)".to_string(), |n| n.to_string()),
+        watchdog_config.max_frame_age.map_or("(off)".to_string(), |d| format!("{} s", d.as_secs()))
+    );
+    *state.capture_watchdog.lock() = CaptureWatchdog::new(watchdog_config);
+    let gpio_config = gpio_config_from_args(&args.gpio)?;
+    if gpio_config.is_enabled() {
+        let config = gpio_config.clone();
+        let gpio = tokio::task::spawn_blocking(move || FrameGpio::open(&config)).await??;
+        info!(
+            "GPIO: strobe {:?}, trigger {:?} ({})",
+            gpio_config.strobe_pin,
+            gpio_config.trigger_pin,
+            if gpio.trigger_mode() { "trigger mode" } else { "timer mode" }
+        );
+        *state.gpio.lock() = Some(gpio);
+    }
+    *state.gpio_config.write() = gpio_config;
+    if let Some(frames) = args.streaming.static_scene_frames {
+        *state.static_scene_frames.write() = frames.max(1);
+    }
+    *state.job_output_dir.write() = layout.snapshots_dir.clone();
+    let state_file = layout.state_file.clone();
+    let profiles = ProfileStore::load(&state_file)?;
+    info!("Settings profiles: {} saved in {}", profiles.list().len(), state_file.display());
+    *state.profiles.write() = profiles;
+    let schedule = load_schedule(&state_file)?;
+    info!(
+        "Schedule: {} feature(s), {} profile time(s), time zone {}",
+        schedule.features().count(),
+        schedule.config().profiles.len(),
+        schedule.config().timezone
+    );
+    state.scheduler.write().set(schedule);
+    let annotation = load_annotation(&state_file)?;
+    info!(
+        "Annotation: {} class label(s), font scale {}",
+        annotation.labels.len(),
+        annotation.style.font_scale
+    );
+    *state.annotation.write() = annotation;
+    let motion = load_motion_config(&state_file)?;
+    log_motion_config(&motion);
+    *state.motion.lock() = MotionDetector::new(motion);
+    let day_night = load_day_night_config(&state_file)?;
+    log_day_night_config(&day_night);
+    *state.day_night.lock() = DayNight::new(day_night);
+    start_event_subscribers(&state);
+    start_capture_loop(&state);
+    tokio::spawn(thermal_monitor(state.clone()));
+    tokio::spawn(detector_monitor(state.clone()));
+    tokio::spawn(camera_watchdog(state.clone()));
+    tokio::spawn(schedule_loop(state.clone()));
+    tokio::spawn(day_night_loop(state.clone()));
+    if let Some(push_config) = PushConfig::from_args(&args.streaming) {
+        start_push(&state, push_config).await?;
+    }
+    if let Some(ha_config) = ha_config_from_args(&args.home_assistant)? {
+        start_home_assistant(&state, ha_config);
+    }
+    *state.storage.write() = layout;
+    start_recorder(&state, &args.recording)?;
+    start_snapshot_archive(&state, &args.recording)?;
+
+    let (app, routed) = router(&state);
+    for problem in openapi::check_routes(&openapi::ApiDoc::openapi(), &routed) {
+        error!("API documentation: {}", problem);
+    }
+
+    info!("Starting web server on http://{}", addr);
+    info!("  - Live view: http://<ip>:{}/", addr.port());
+    info!("  - API reference: http://<ip>:{}/docs (OpenAPI document: /openapi.json)", addr.port());
+    info!("  - Single frame: http://<ip>:{}/frame.jpg (full quality: /snapshot, metadata: /frame.json)", addr.port());
+    info!("  - Snapshot archive: http://<ip>:{}/snapshots (POST saves the current frame, /snapshots/<id> fetches)", addr.port());
+    info!("  - Changed frame: http://<ip>:{}/frame/changed?since_hash=<X-Frame-Hash> (204 if unchanged)", addr.port());
+    info!("  - MJPEG stream: http://<ip>:{}/stream (downscaled: /stream/1080p, /stream/720p)", addr.port());
+    info!("  - H.264 stream: http://<ip>:{}/video.mp4 (fragmented MP4, `mpp` builds; WebRTC: POST /webrtc/offer)", addr.port());
+    info!("  - Native tile: http://<ip>:{}/tile.jpg?x=0&y=0&w=640&h=480 (or /stream_tile)", addr.port());
+    info!("  - Set mode: http://<ip>:{}/mode/grayscale or /mode/color", addr.port());
+    info!("  - Toggle detection: http://<ip>:{}/detect/on or /detect/off", addr.port());
+    info!("  - Detection cadence: http://<ip>:{}/detect/interval/3 (0 = on demand, POST /detect/once)", addr.port());
+    info!("  - Detection filter: http://<ip>:{}/detect/config (POST confidence/IoU thresholds, class allow-list)", addr.port());
+    info!("  - Replay history: http://<ip>:{}/history/replay?from_seq=&to_seq=&speed=1.0 (MJPEG, ends after to_seq)", addr.port());
+    info!("  - Share clip: http://<ip>:{}/clip.gif?seconds=3&fps=5&width=480 (or /clip.webp)", addr.port());
+    info!("  - Faces (--detector-task faces): http://<ip>:{}/faces (sightings), /faces/last.jpg (latest crop)", addr.port());
+    info!("  - Detector models: http://<ip>:{}/detect/models (PUT/DELETE /detect/models/<name>, POST .../activate)", addr.port());
+    info!(
+        "  - Profiles: http://<ip>:{}/profiles (POST /profiles/<name> saves, PUT sets, POST .../apply applies)",
+        addr.port()
+    );
+    info!("  - Schedule: http://<ip>:{}/schedule (POST a new schedule as JSON)", addr.port());
+    info!("  - Zones: http://<ip>:{}/zones (PUT/DELETE /zones/<name>; zone_entered/zone_cleared events)", addr.port());
+    info!("  - Privacy masks: http://<ip>:{}/masks (PUT/DELETE /masks/<name>)", addr.port());
+    info!("  - Annotation: http://<ip>:{}/annotation (POST label style), /detect/labels (POST class -> label map)", addr.port());
+    info!("  - Motion: http://<ip>:{}/motion (POST /motion/config; motion_started/motion_stopped events)", addr.port());
+    info!("  - Recording: POST http://<ip>:{}/record/start, /record/stop (progress: /record/status)", addr.port());
+    info!("  - Events: http://<ip>:{}/events (recent), /events/stream (server-sent events)", addr.port());
+    info!("  - Capture jobs: POST http://<ip>:{}/jobs (steps as JSON), GET/DELETE /jobs/<id>", addr.port());
+    info!("  - A/B compare: http://<ip>:{}/compare/set_reference, then /compare/diff.jpg or /compare/stats", addr.port());
+    info!("  - Self-test: http://<ip>:{}/selftest", addr.port());
+    info!("  - Latency: http://<ip>:{}/stats/latency (POST /latency/blink marks one frame)", addr.port());
+    info!("  - Bandwidth: http://<ip>:{}/stats/bandwidth (per route, open streams, daily budget)", addr.port());
+    info!("  - Build info: http://<ip>:{}/version (Prometheus: /metrics)", addr.port());
+    info!("  - Subsystems: http://<ip>:{}/admin/subsystems (POST /admin/restart/<name>)", addr.port());
+    info!("  - Gr/Gb balance: http://<ip>:{}/control/gbgr/auto?strength=1.0 (or off, or a fixed ratio)", addr.port());
+    info!("  - Row-noise correction: http://<ip>:{}/control/rownoise/on?strength=1.0", addr.port());
+    info!("  - Sensor controls: http://<ip>:{}/controls (POST /controls/<name>?value= sets one)", addr.port());
+
+    let listener = bind(addr)?;
+    tokio::select! {
+        result = serve(listener, app) => result?,
+        _ = shutdown_signal() => info!("Shutting down"),
+    }
+    
+    // Release GPIO lines (strobe off, unexport) before exiting; the capture
+    // loop may hold the lock while it waits for a trigger edge
+    let gpio_state = state.clone();
+    tokio::task::spawn_blocking(move || drop(gpio_state.gpio.lock().take())).await?;
+
+    Ok(())
+}
+
+/// The HTTP API on `state`, and the paths it routes (for checking against
+/// the OpenAPI document)
+fn router(state: &SharedState) -> (Router, Vec<&'static str>) {
+    let limiter = state.rate_limiter.clone();
+    let mut routed = Vec::new();
+    
+    // Long-lived streaming routes are exempt from the response timeout
+    let streaming_routes = openapi::ApiRouter::new()
+        .route("/stream", get(mjpeg_stream_handler))
+        .route("/stream/:res", get(scaled_stream_handler))
+        .route("/video.mp4", get(video_mp4_handler))
+        .route("/stream_tile", get(tile_stream_handler))
+        .route("/events/stream", get(events_stream_handler))
+        .route("/history/replay", get(history_replay_handler))
+        .finish(&mut routed)
+        .layer(middleware::from_fn_with_state(limiter.clone(), ratelimit::limit_streams));
+    
+    // Full-size encodes, rate limited separately from the API
+    let image_routes = openapi::ApiRouter::new()
+        .route("/frame.jpg", get(frame_handler))
+        .route("/frame.dng", get(frame_dng_handler))
+        .route("/frame/changed", get(frame_changed_handler))
+        .route("/snapshot", get(snapshot_handler))
+        .route("/snapshots", get(snapshots_list_handler).post(snapshot_save_handler))
+        .route("/snapshots/:id", get(snapshot_get_handler).delete(snapshot_delete_handler))
+        .route("/tile.jpg", get(tile_handler))
+        .route("/history/:seq/annotated.jpg", get(history_annotated_handler))
+        .route("/compare/diff.jpg", get(compare_diff_handler))
+        .route("/faces/last.jpg", get(face_crop_handler))
+        .finish(&mut routed)
+        .layer(TimeoutLayer::new(REQUEST_TIMEOUT))
+        .layer(middleware::from_fn_with_state(limiter.clone(), ratelimit::limit_images));
+    
+    let api_routes = openapi::ApiRouter::new()
+        .route("/", get(index_handler))
+        .route("/status", get(status_handler))
+        .route("/frame.json", get(frame_json_handler))
+        .route("/mode/:mode", get(set_mode_handler))
+        .route("/detect/:enabled", get(set_detection_handler))
+        .route("/detect/interval/:n", get(set_detection_interval_handler))
+        .route("/detect/once", post(detect_once_handler))
+        .route("/detect/models", get(models_list_handler))
+        .route("/detect/labels", get(labels_handler).post(labels_set_handler))
+        .route("/detect/config", get(detect_config_handler).post(detect_config_set_handler))
+        .route("/annotation", get(annotation_handler).post(annotation_set_handler))
+        .route("/jobs", get(jobs_list_handler).post(jobs_create_handler))
+        .route("/profiles", get(profiles_list_handler))
+        .route(
+            "/profiles/:name",
+            get(profile_handler).post(profile_save_handler).put(profile_put_handler).delete(profile_delete_handler),
+        )
+        .route("/profiles/:name/apply", post(profile_apply_handler))
+        .route("/schedule", get(schedule_handler).post(schedule_set_handler))
+        .route("/motion", get(motion_handler))
+        .route("/motion/config", get(motion_config_handler).post(motion_config_set_handler))
+        .route("/record/start", post(record_start_handler))
+        .route("/record/stop", post(record_stop_handler))
+        .route("/record/status", get(record_status_handler))
+        .route("/stack/start", post(stack_start_handler))
+        .route("/stack/cancel", post(stack_cancel_handler))
+        .route("/stack/status", get(stack_status_handler))
+        .route("/stack/result.dng", get(stack_dng_handler))
+        .route("/stack/result.png", get(stack_png_handler))
+        .route("/events", get(events_handler))
+        .route("/jobs/:id", get(job_handler).delete(job_cancel_handler))
+        .route("/control/rownoise/:enabled", get(set_row_noise_handler))
+        .route("/control/black_level/:value", get(set_black_level_handler))
+        .route("/control/quality/:value", get(set_quality_handler))
+        .route("/control/test_pattern/:mode", get(set_test_pattern_handler))
+        .route("/controls", get(controls_list_handler))
+        .route("/controls/:name", get(control_handler).post(control_set_handler))
+        .route("/control/gamma/:value", get(set_gamma_handler))
+        .route("/control/tonemap/:strength", get(set_tonemap_handler))
+        .route("/control/contrast/:mode", get(set_contrast_handler))
+        .route("/control/denoise/:strength", get(set_denoise_handler))
+        .route("/control/denoise/spatial/:method", get(set_spatial_denoise_handler))
+        .route("/control/sharpen/:amount", get(set_sharpen_handler))
+        .route("/control/tone", get(tone_handler).post(tone_set_handler))
+        .route("/control/wb", get(set_wb_handler))
+        .route("/control/wb/:mode", get(set_wb_mode_handler))
+        .route("/control/wb_smoothing/:time_constant", get(set_wb_smoothing_handler))
+        .route("/control/ccm", get(ccm_handler).post(ccm_set_handler))
+        .route("/control/orientation", get(orientation_handler).post(orientation_set_handler))
+        .route("/control/zoom", get(zoom_handler).post(zoom_set_handler))
+        .route("/control/gbgr/:value", get(set_green_balance_handler))
+        .route("/control/metering/:mode", get(set_metering_handler))
+        .route("/control/auto_exposure/:mode", get(set_auto_exposure_handler))
+        .route("/detections", get(detections_handler))
+        .route("/zones", get(zones_list_handler))
+        .route("/zones/:name", get(zone_handler).put(zone_put_handler).delete(zone_delete_handler))
+        .route("/masks", get(masks_list_handler))
+        .route("/masks/:name", get(mask_handler).put(mask_put_handler).delete(mask_delete_handler))
+        .route("/detector/health", get(detector_health_handler))
+        .route("/faces", get(faces_handler))
+        .route("/history", get(history_handler))
+        .route("/history/:seq/detections", get(history_detections_handler))
+        .route("/compare/set_reference", get(compare_set_reference_handler))
+        .route("/compare/clear", get(compare_clear_handler))
+        .route("/compare/stats", get(compare_stats_handler))
+        .route("/stats", get(stats_handler))
+        .route("/stats/image", get(image_stats_handler))
+        .route("/histogram", get(histogram_handler))
+        .route("/focus", get(focus_handler).post(focus_set_handler))
+        .route("/stats/latency", get(latency_stats_handler))
+        .route("/stats/bandwidth", get(bandwidth_stats_handler))
+        .route("/latency/blink", post(latency_blink_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/version", get(version_handler))
+        .route("/storage", get(storage_handler))
+        .route("/config/reload", post(config_reload_handler))
+        .route("/webrtc/offer", post(webrtc_offer_handler))
+        .route("/ui/config", get(ui_config_handler))
+        .finish(&mut routed)
+        .merge(SwaggerUi::new("/docs").url("/openapi.json", openapi::ApiDoc::openapi()))
+        .layer(TimeoutLayer::new(REQUEST_TIMEOUT))
+        .layer(middleware::from_fn_with_state(limiter.clone(), ratelimit::limit_api));
+    
+    // Clip encodes decode and re-encode up to a few dozen frames
+    let clip_routes = openapi::ApiRouter::new()
+        .route("/clip.gif", get(clip_gif_handler))
+        .route("/clip.webp", get(clip_webp_handler))
+        .finish(&mut routed)
+        .layer(TimeoutLayer::new(SLOW_REQUEST_TIMEOUT))
+        .layer(middleware::from_fn_with_state(limiter.clone(), ratelimit::limit_images));
+    
+    // The self-test captures several frames and runs external tools;
+    // restarting the camera reconfigures the sensor
+    let slow_routes = openapi::ApiRouter::new()
+        .route("/selftest", get(selftest_handler))
+        .route("/admin/restart/:subsystem", post(admin_restart_handler))
+        .route("/admin/subsystems", get(admin_subsystems_handler))
+        .route("/detect/models/:name/activate", post(model_activate_handler))
+        .finish(&mut routed)
+        .layer(TimeoutLayer::new(SLOW_REQUEST_TIMEOUT))
+        .layer(middleware::from_fn_with_state(limiter.clone(), ratelimit::limit_api));
+    
+    // Model uploads stream large bodies to disk (size checked as they
+    // arrive), so they are merged in after the small-body limit
+    let model_routes = openapi::ApiRouter::new()
+        .route("/detect/models/:name", put(model_upload_handler).delete(model_delete_handler))
+        .finish(&mut routed)
+        .layer(TimeoutLayer::new(MODEL_UPLOAD_TIMEOUT))
+        .layer(middleware::from_fn_with_state(limiter, ratelimit::limit_api));
+    
+    let app = api_routes
+        .merge(image_routes)
+        .merge(clip_routes)
+        .merge(slow_routes)
+        .merge(streaming_routes)
+        .layer(RequestBodyLimitLayer::new(MAX_REQUEST_BODY_BYTES))
+        .merge(model_routes)
+        .fallback(not_found_handler)
+        .layer(middleware::map_response(json_error_body))
+        .layer(middleware::from_fn_with_state(state.bandwidth.clone(), bandwidth::account))
+        .with_state(state.clone());
+    (app, routed)
+}
+
+/// Resolve on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+    let mut sigterm = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            tracing::warn!("Failed to install SIGTERM handler: {}", e);
+            let _ = ctrl_c.await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// Run the self-test, print the report and exit (nonzero if a required check failed)
+fn run_self_test(args: &SelfTestArgs) -> ! {
+    let config = match config_file_from_args(args.config.as_deref())
+        .and_then(|(_, file)| capture_config_from_args(&args.device, &file.capture))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let device_path = config.device_path.clone();
+    let sensor_subdev = config.sensor_subdev.clone();
+    let mut capture = match FrameCapture::with_config(config, Arc::default()) {
+        Ok(mut capture) => {
+            if let Err(e) = capture.detect_format() {
+                error!("Could not detect raw format: {:#}", e);
+            }
+            Some(capture)
+        }
+        Err(e) => {
+            error!("Could not create capture: {}", e);
+            None
+        }
+    };
+    
+    let recordings_dir = storage_layout_from_args(&args.storage).recordings_dir;
+    let report = selftest::run(capture.as_mut(), &device_path, &sensor_subdev, &recordings_dir);
+    drop(capture); // stops the capture stream
+    
+    for check in &report.checks {
+        println!(
+            "{:<5} {:<16} {:>9.1} ms  {}{}",
+            format!("{:?}", check.status).to_uppercase(),
+            check.name,
+            check.duration_ms,
+            check.detail,
+            if check.required { "" } else { " (optional)" }
+        );
+    }
+    println!(
+        "Self-test {} in {:.1} ms",
+        if report.passed { "PASSED" } else { "FAILED" },
+        report.duration_ms
+    );
+    
+    std::process::exit(if report.passed { 0 } else { 1 });
+}
+
+/// Re-process saved raw frames offline, print the per-file report and exit
+/// (nonzero unless every file was processed)
+fn run_reprocess(args: &ReprocessArgs) -> ! {
+    match reprocess_dir(args) {
+        Ok(passed) => std::process::exit(if passed { 0 } else { 1 }),
+        Err(e) => {
+            error!("Reprocessing failed: {:#}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Settings of the `--profile <name>` saved in the state file (defaults
+/// without a profile)
+fn profile_settings_from_args(args: &OfflineArgs) -> Result<Settings> {
+    let Some(name) = &args.profile else {
+        return Ok(Settings::default());
+    };
+    let mut state_file = state_file_from_args(&args.state);
+    // The server moves the legacy file into the layout on its next start
+    if !state_file.exists() && args.state.state_file.is_none() {
+        state_file = profiles::LEGACY_STATE_FILE.into();
+    }
+    let profiles = ProfileStore::load(&state_file)?;
+    let profile = profiles
+        .get(name)
+        .with_context(|| format!("No profile '{}' in {}", name, state_file.display()))?;
+    Ok(profile.settings.clone())
+}
+
+/// Pipeline settings for offline use: the profile's, then `--mode <mode>`
+/// and `--gbgr <value>`; also returns the profile's live-only settings
+fn offline_pipeline_from_args(args: &OfflineArgs, settings: &Settings) -> Result<(PipelineSettings, Vec<String>)> {
+    let (mut config, ignored) =
+        reprocess::pipeline_config(settings).map_err(|e| anyhow::anyhow!("Invalid profile: {}", e))?;
+    if let Some(mode) = &args.mode {
+        config.mode = CaptureMode::parse(mode).with_context(|| format!("Invalid mode '{}'", mode))?;
+    }
+    if let Some(value) = &args.gbgr {
+        config.green_balance.mode = GreenBalanceMode::parse(value)
+            .with_context(|| format!("Invalid --gbgr '{}'. Use off, auto or a Gr/Gb ratio", value))?;
+    }
+    Ok((config, ignored))
+}
+
+/// Run the `capture` subcommand and exit: 0 when every frame was written,
+/// 1 if the capture failed, 2 for invalid options, 130 when interrupted
+async fn run_capture_command(args: &CaptureArgs) -> ! {
+    let options = match capture_options_from_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let count = options.count;
+    let capture = tokio::task::spawn_blocking(move || {
+        capturecmd::run(&options, |record| match serde_json::to_string(record) {
+            Ok(line) => println!("{}", line),
+            Err(e) => error!("Failed to serialize frame metadata: {}", e),
+        })
+    });
+    tokio::select! {
+        result = capture => match result {
+            Ok(Ok(records)) => {
+                info!("Captured {} of {} frame(s)", records.len(), count);
+                std::process::exit(0);
+            }
+            Ok(Err(e)) => {
+                error!("Capture failed: {:#}", e);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("Capture failed: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ = shutdown_signal() => {
+            // The pipeline is still capturing on the blocking thread; its
+            // v4l2-ctl stream ends with our end of the pipe
+            error!("Interrupted");
+            std::process::exit(130);
+        }
+    }
+}
+
+/// `capture -o <file> [--count <n>] [--format jpeg|png|tiff|raw] [--profile <name>]
+/// [--mode <mode>] [--gbgr <value>] [--exposure <lines>] [--analogue-gain <value>]
+/// [--timeout <seconds>] [--device <path>] [--subdev <path>]`
+fn capture_options_from_args(args: &CaptureArgs) -> Result<capturecmd::Options> {
+    let output = args.output.clone();
+    let settings = profile_settings_from_args(&args.pipeline)?;
+    let (config, ignored) = offline_pipeline_from_args(&args.pipeline, &settings)?;
+    // Sensor controls are set for real here; the rest only matter live
+    let ignored: Vec<_> = ignored.into_iter().filter(|name| !settings.controls.contains_key(name)).collect();
+    if !ignored.is_empty() {
+        info!("Ignoring live-only settings: {}", ignored.join(", "));
+    }
+    let mut controls = settings.controls.clone();
+    for (value, control) in [(args.exposure, "exposure"), (args.analogue_gain, "analogue_gain")] {
+        if let Some(value) = value {
+            controls.insert(control.to_string(), value);
+        }
+    }
+    let format = match &args.format {
+        Some(name) => capturecmd::FileFormat::parse(name)
+            .with_context(|| format!("Invalid format '{}'. Use jpeg, png, tiff or raw", name))?,
+        None => capturecmd::FileFormat::from_path(&output)
+            .with_context(|| format!("Can't tell the format from '{}'; use --format", output))?,
+    };
+    let count = args.count;
+    let timeout = match args.timeout {
+        Some(secs) => {
+            anyhow::ensure!(secs.is_finite() && secs > 0.0, "--timeout must be positive");
+            Duration::from_secs_f32(secs)
+        }
+        None => capturecmd::DEFAULT_TIMEOUT,
+    };
+    let options = capturecmd::Options {
+        output,
+        count,
+        format,
+        settings: config,
+        controls,
+        timeout,
+        devices: capture_config_from_args(&args.device, &config_file_from_args(args.config.as_deref())?.1.capture)?,
+    };
+    capturecmd::validate(&options)?;
+    Ok(options)
+}
+
+/// `--frame-size <WxH|full>`, over `file` (the config file's): the sensor
+/// mode to ask for, or the size of raw frames that don't come from the
+/// capture device
+fn frame_size_from_args(args: &RawArgs, file: Option<String>) -> Result<Option<FrameSize>> {
+    let Some(text) = args.frame_size.clone().or(file) else {
+        return Ok(None);
+    };
+    let size = FrameSize::parse(&text).with_context(|| format!("Invalid frame size '{}' (WxH or full)", text))?;
+    size.validate().map_err(anyhow::Error::msg)?;
+    Ok(Some(size))
+}
+
+/// `--raw-format <fourcc>`, `--stride <bytes>`: layout of raw frames of
+/// `size` that don't come from the capture device (the stock sensor mode
+/// by default)
+fn raw_format_from_args(args: &RawArgs, size: FrameSize) -> Result<(rawformat::RawFormat, usize)> {
+    let raw_format = match &args.raw_format {
+        Some(fourcc) => rawformat::RawFormat::from_fourcc(fourcc, "")
+            .with_context(|| format!("Unsupported raw format '{}'", fourcc))?,
+        None => rawformat::RawFormat::default(),
+    };
+    let stride = match args.stride {
+        Some(stride) => stride,
+        // The stock sensor mode pads its lines
+        None if raw_format == rawformat::RawFormat::default() && size == FrameSize::FULL => capture::STRIDE,
+        None => raw_format.min_stride(size.width),
+    };
+    Ok((raw_format, stride))
+}
+
+/// `--source v4l2|file|synthetic` (default v4l2), `--source-path <file|dir>`
+/// (raw dumps for `file`), plus the raw format flags for the latter two,
+/// whose frames are `frame_size` (full size when unset)
+fn source_config_from_args(args: &DeviceArgs, frame_size: Option<FrameSize>) -> Result<SourceConfig> {
+    let source = args.source.as_deref().unwrap_or("v4l2");
+    let size = frame_size.unwrap_or(FrameSize::FULL);
+    Ok(match source {
+        "v4l2" => SourceConfig::V4l2,
+        "file" => {
+            let path = args.source_path.clone().context("--source file needs --source-path <file|dir>")?;
+            let (format, stride) = raw_format_from_args(&args.raw, size)?;
+            SourceConfig::Files { path, format, size, stride }
+        }
+        "synthetic" => {
+            let (format, stride) = raw_format_from_args(&args.raw, size)?;
+            SourceConfig::Synthetic { format, size, stride }
+        }
+        _ => anyhow::bail!("Invalid --source '{}'. Use v4l2, file or synthetic", source),
+    })
+}
+
+/// `reprocess <dir> [--profile <name>] [--mode <mode>] [--gbgr <value>]
+/// [--format jpeg|png|tiff] [--jobs <n>] [--raw-format <fourcc>] [--stride <bytes>]
+/// [--frame-size <WxH>]`
+fn reprocess_dir(args: &ReprocessArgs) -> Result<bool> {
+    let dir = &args.dir;
+    let (config, ignored) = offline_pipeline_from_args(&args.pipeline, &profile_settings_from_args(&args.pipeline)?)?;
+    if !ignored.is_empty() {
+        info!("Ignoring live-only settings: {}", ignored.join(", "));
+    }
+    let size = frame_size_from_args(&args.raw, None)?.unwrap_or(FrameSize::FULL);
+    let (raw_format, stride) = raw_format_from_args(&args.raw, size)?;
+    let output = reprocess::OutputFormat::parse(&args.format)
+        .with_context(|| format!("Invalid format '{}'. Use jpeg, png or tiff", args.format))?;
+    let jobs = match args.jobs {
+        Some(jobs) => jobs,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()).min(reprocess::DEFAULT_JOBS),
+    };
+    let options = reprocess::Options {
+        dir: dir.into(),
+        settings: config,
+        raw_format,
+        size,
+        stride,
+        output,
+        jobs,
+    };
+    info!(
+        "Reprocessing {} ({} {}, {} bytes per frame) as {} with {} worker(s)",
+        dir,
+        options.size,
+        options.raw_format.fourcc,
+        options.frame_bytes(),
+        reprocess::settings_tag(&options.settings),
+        jobs
+    );
+    
+    let report = reprocess::run(&options, |file| {
+        let status = match file.status {
+            reprocess::FileStatus::Ok => "OK",
+            reprocess::FileStatus::SizeMismatch => "SIZE",
+            reprocess::FileStatus::Failed => "FAIL",
+        };
+        let name = file.path.file_name().unwrap_or_default().to_string_lossy();
+        println!("{:<5} {:<32} {:>9.1} ms  {}", status, name, file.duration_ms, file.detail);
+    })?;
+    let count = |status| report.files.iter().filter(|f| f.status == status).count();
+    if report.files.is_empty() {
+        println!("No .raw files in {}", dir);
+        return Ok(false);
+    }
+    println!(
+        "Reprocessed {}/{} files in {:.1} ms ({} size mismatch, {} failed)",
+        count(reprocess::FileStatus::Ok),
+        report.files.len(),
+        report.duration_ms,
+        count(reprocess::FileStatus::SizeMismatch),
+        count(reprocess::FileStatus::Failed)
+    );
+    Ok(report.passed())
+}
+
+/// Give error responses that don't come from our handlers (timeout and
+/// body-limit rejections from the tower layers, extractor rejections,
+/// unknown methods) the `ApiError` shape, keeping their text as the message
+async fn json_error_body(response: Response) -> Response {
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if is_json {
+        return response;
+    }
+    
+    let (mut parts, body) = response.into_parts();
+    let text = axum::body::to_bytes(body, 4096)
+        .await
+        .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+        .unwrap_or_default();
+    let code = ErrorCode::from_status(status);
+    let message = if !text.is_empty() {
+        text
+    } else {
+        match code {
+            ErrorCode::Timeout => "Request timed out".to_string(),
+            ErrorCode::PayloadTooLarge => "Request body too large".to_string(),
+            _ => status.canonical_reason().unwrap_or("Request failed").to_string(),
+        }
+    };
+    // Keep the original headers (Allow, Retry-After, ...) apart from the body's
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let mut response = ApiError::new(code, message).into_response();
+    response.headers_mut().extend(parts.headers);
+    response
+}
+
+/// Unknown routes
+async fn not_found_handler() -> ApiError {
+    ApiError::not_found("No such endpoint")
+}
+
+/// Subsystems that can be listed and restarted via /admin
+const SUBSYSTEM_NAMES: [&str; 2] = ["camera", "detector"];
+
+/// Start the detector backend, warm it up and register it with its
+/// supervisor; frames are only fed to a detector that passed its warm-up
+fn start_detector(state: &AppState) {
+    let config = state.detector_config.read().clone();
+    // Backends load their model before returning; keep other tasks running meanwhile
+    let started = tokio::task::block_in_place(|| {
+        let detector = YoloDetector::new(&config)?;
+        let latency = detector
+            .warm_up(detector::WARM_UP_TIMEOUT)
+            .with_context(|| format!("{} backend failed its warm-up", detector.backend().name()))?;
+        Ok::<_, anyhow::Error>((detector, latency))
+    });
+    match started {
+        Ok((detector, latency)) => {
+            info!(
+                "YOLO detector initialized ({}, warm-up {:.0} ms)",
+                detector.backend().name(),
+                latency.as_secs_f64() * 1000.0
+            );
+            *state.detector.write() = Some(detector);
+            state.detector_supervisor.started(None);
+        }
+        Err(e) => {
+            info!("YOLO detector not available: {:#} (detection disabled)", e);
+            state.detector_supervisor.failed(format!("{:#}", e));
+        }
+    }
+}
+
+/// Spawn the capture loop as the camera subsystem's task
+fn start_capture_loop(state: &SharedState) {
+    let capture_state = state.clone();
+    let task = tokio::spawn(async move {
+        capture_loop(capture_state).await;
+    });
+    state.camera_supervisor.started(Some(task));
+}
+
+/// Start the raw TCP push server and/or the UDP thumbnail sender
+async fn start_push(state: &SharedState, config: PushConfig) -> Result<()> {
+    let tcp = match config.tcp_port {
+        Some(port) => {
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+            info!("Raw frame push on tcp://0.0.0.0:{} ({})", port, push::WIRE_FORMAT);
+            Some(tokio::spawn(push::run_tcp(
+                listener,
+                state.frame_watch.subscribe(),
+                state.push_stats.clone(),
+            )))
+        }
+        None => None,
+    };
+    let udp = config.udp_target.map(|target| {
+        info!("UDP thumbnail push to {} at {} fps", target, config.udp_fps);
+        let frames = state.frame_watch.subscribe();
+        let stats = state.push_stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = push::run_udp(target, config.udp_fps, frames, stats).await {
+                error!("UDP push stopped: {:#}", e);
+            }
+        })
+    });
+    
+    // One supervised task covering both senders
+    let task = tokio::spawn(async move {
+        for handle in [tcp, udp].into_iter().flatten() {
+            let _ = handle.await;
+        }
+    });
+    state.push_supervisor.started(Some(task));
+    *state.push_config.write() = Some(config);
+    Ok(())
+}
+
+/// Start the Home Assistant MQTT bridge and the tasks feeding it
+fn start_home_assistant(state: &SharedState, config: HaConfig) {
+    info!(
+        "Home Assistant: MQTT broker {}, node {}, occupancy for {}",
+        config.mqtt.broker,
+        config.node_id,
+        config.classes.join(", ")
+    );
+    let (state_tx, state_rx) = watch::channel(home_assistant_state(state));
+    let (image_tx, image_rx) = watch::channel(None);
+    let (command_tx, command_rx) = tokio::sync::mpsc::channel(8);
+    let channels = homeassistant::HaChannels { state: state_rx, images: image_rx, commands: command_tx };
+    let bridge = tokio::spawn(homeassistant::run(config.clone(), channels, state.ha_stats.clone()));
+    let feed = tokio::spawn(home_assistant_feed(state.clone(), state_tx, image_tx, config.camera_interval));
+    let commands = tokio::spawn(home_assistant_commands(state.clone(), command_rx));
+
+    // One supervised task covering the bridge and its feeders
+    let task = tokio::spawn(async move {
+        let _ = bridge.await;
+        feed.abort();
+        commands.abort();
+    });
+    state.mqtt_supervisor.started(Some(task));
+    *state.home_assistant.write() = Some(config);
+}
+
+/// What Home Assistant mirrors
+fn home_assistant_state(state: &AppState) -> HaState {
+    HaState {
+        detection_enabled: *state.detection_enabled.read(),
+        classes: state.last_detections.read().detections.iter().map(|d| d.class.clone()).collect(),
+    }
+}
+
+/// Sample the mirrored state and, every `camera_interval`, a downscaled
+/// camera image for the bridge
+async fn home_assistant_feed(
+    state: SharedState,
+    state_tx: watch::Sender<HaState>,
+    image_tx: watch::Sender<Option<Bytes>>,
+    camera_interval: Option<Duration>,
+) {
+    let mut ticker = tokio::time::interval(HA_STATE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut last_image: Option<(std::time::Instant, u64)> = None;
+    loop {
+        ticker.tick().await;
+        let current = home_assistant_state(&state);
+        state_tx.send_if_modified(|sent| {
+            let changed = *sent != current;
+            *sent = current;
+            changed
+        });
+
+        let Some(interval) = camera_interval else {
+            continue;
+        };
+        if last_image.is_some_and(|(at, _)| at.elapsed() < interval) {
+            continue;
+        }
+        let Some(frame) = state.frame_watch.borrow().clone() else {
+            continue;
+        };
+        // A still scene isn't sent again
+        if last_image.is_some_and(|(_, hash)| hash == frame.hash) {
+            continue;
+        }
+        let pixels = || state.decode_cache.get(frame.hash, frame.jpeg.clone());
+        match state.scaled_frames.get(frame.hash, Some(HA_CAMERA_WIDTH), HA_CAMERA_QUALITY, pixels).await {
+            Ok(jpeg) => {
+                image_tx.send_replace(Some(jpeg));
+                last_image = Some((std::time::Instant::now(), frame.hash));
+            }
+            Err(e) => tracing::warn!("Home Assistant: failed to scale camera image: {:#}", e),
+        }
+    }
+}
+
+/// Apply commands from Home Assistant
+async fn home_assistant_commands(state: SharedState, mut commands: tokio::sync::mpsc::Receiver<HaCommand>) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            HaCommand::SetDetection(enable) => {
+                if enable {
+                    // The switch flips back to OFF on the next state sample
+                    if let Err(e) = warm_up_detector(&state).await {
+                        tracing::warn!("Home Assistant: detector warm-up failed: {}", e);
+                        continue;
+                    }
+                }
+                set_detection_enabled(&state, enable);
+                state.scheduler.write().manual_change(Feature::Detection, enable, chrono::Utc::now());
+                tracing::info!("Detection {} from Home Assistant", if enable { "ENABLED" } else { "DISABLED" });
+            }
+        }
+    }
+}
+
+/// Built-in event bus subscribers, each its own task
+fn start_event_subscribers(state: &SharedState) {
+    tokio::spawn(events::run_event_log(state.events.subscribe("event_log"), state.event_log.clone()));
+    tokio::spawn(events::run_counters(state.events.subscribe("counters"), state.event_counters.clone()));
+    tokio::spawn(event_recording(state.clone()));
+}
+
+/// Store a detection result (latest + history), publish state changes and
+/// crop faces in the background
+fn record_detections(state: &SharedState, mut result: DetectionResult) {
+    state.annotation.read().apply_labels(&mut result);
+    state.history.write().record_detections(&result);
+    let event = state.detection_events.lock().update(&result);
+    let zone_events = state.zone_events.lock().update(&state.detector_config.read().zones, &result);
+    let frame = result.frame_seq.and_then(|seq| state.history.read().frame(seq).cloned());
+    let crop = state
+        .faces
+        .write()
+        .observe(&result, frame.as_ref().map_or_else(unix_millis, |f| f.timestamp_ms));
+    *state.last_detections.write() = result;
+    for event in event.into_iter().chain(zone_events) {
+        state.events.publish(event);
+    }
+    if let Some(crop) = crop {
+        tokio::spawn(take_face_crop(state.clone(), crop, frame));
+    }
+}
+
+/// Cut and store the crop of a face sighting from its frame's pixels, then
+/// publish `FaceAppeared` if faces just came into view
+async fn take_face_crop(state: SharedState, request: CropRequest, frame: Option<history::HistoryFrame>) {
+    let face = request.face;
+    let crop = async {
+        let frame = frame.ok_or_else(|| anyhow::anyhow!("frame no longer in history"))?;
+        // The pipeline's own pixels while the frame is current, else one decode
+        let pixels = state.decode_cache.get(framehash::hash(&frame.jpeg), frame.jpeg).await?;
+        let config = state.faces.read().config();
+        let bbox = face.bbox.clone();
+        tokio::task::spawn_blocking(move || faces::crop_face(&pixels, &bbox, config)).await?
+    }
+    .await;
+    let (frame_seq, confidence) = (face.frame_seq, face.confidence);
+    match crop {
+        Ok(jpeg) => state.faces.write().store_crop(FaceCrop { face, jpeg: jpeg.into() }),
+        Err(e) => tracing::warn!("Failed to crop face in frame {}: {:#}", frame_seq, e),
+    }
+    if request.appeared {
+        state.events.publish(DomainEvent::FaceAppeared { frame_seq, count: request.count, confidence });
+    }
+}
+
+/// Drop the last detection result, publishing a clear if objects were seen
+fn clear_detections(state: &AppState) {
+    *state.last_detections.write() = DetectionResult::default();
+    state.faces.write().reset();
+    if let Some(event) = state.detection_events.lock().reset() {
+        state.events.publish(event);
+    }
+    for event in state.zone_events.lock().reset() {
+        state.events.publish(event);
+    }
+}
+
+/// `--data-dir <path>`; `--state-file <path>`, `--record-dir <path>` and
+/// `--job-output-dir <path>` move single locations out of it
+fn storage_layout_from_args(args: &StorageArgs) -> storage::Layout {
+    let data_dir = args.state.data_dir.clone().unwrap_or_else(|| storage::DEFAULT_DATA_DIR.into());
+    let mut layout = storage::Layout::new(&data_dir);
+    layout.state_file = state_file_from_args(&args.state);
+    if let Some(dir) = &args.record_dir {
+        layout.recordings_dir = dir.clone();
+    }
+    if let Some(dir) = &args.job_output_dir {
+        layout.snapshots_dir = dir.clone();
+    }
+    layout
+}
+
+/// `--state-file <path>`, else the state file in `--data-dir <path>`
+fn state_file_from_args(args: &StateArgs) -> std::path::PathBuf {
+    match &args.state_file {
+        Some(path) => path.clone(),
+        None => {
+            let data_dir = args.data_dir.clone().unwrap_or_else(|| storage::DEFAULT_DATA_DIR.into());
+            storage::Layout::new(&data_dir).state_file
+        }
+    }
+}
+
+/// Legacy locations and where they go in `layout`. The spool comes first
+/// so it isn't moved along with the recordings; locations given on the
+/// command line are left alone.
+fn legacy_storage_moves(args: &StorageArgs, layout: &storage::Layout) -> Vec<(std::path::PathBuf, std::path::PathBuf)> {
+    let record_dir = args.record_dir.clone().unwrap_or_else(|| recorder::LEGACY_RECORD_DIR.into());
+    let mut moves = vec![(record_dir.join(recorder::LEGACY_SPOOL_DIR), layout.spool_dir.clone())];
+    if args.record_dir.is_none() {
+        moves.push((recorder::LEGACY_RECORD_DIR.into(), layout.recordings_dir.clone()));
+    }
+    if args.state.state_file.is_none() {
+        moves.push((profiles::LEGACY_STATE_FILE.into(), layout.state_file.clone()));
+    }
+    if args.job_output_dir.is_none() {
+        moves.push((std::env::temp_dir().join(LEGACY_JOB_OUTPUT_DIR), layout.snapshots_dir.clone()));
+    }
+    moves
+}
+
+/// Move legacy files into the data directory layout, then remove what
+/// earlier runs left behind: the raw frame temp dirs of versions that
+/// captured through files, interrupted model uploads and unfinished state
+/// file writes
+fn prepare_storage(args: &StorageArgs, layout: &storage::Layout, models_dir: &std::path::Path) -> Result<()> {
+    let report = storage::migrate(layout, &legacy_storage_moves(args, layout))
+        .with_context(|| format!("Failed to prepare data directory {}", layout.data_dir.display()))?;
+    for moved in &report.migrated {
+        info!("Migrated {} ({} files) to {}", moved.from.display(), moved.files, moved.to.display());
+    }
+    for path in &report.conflicts {
+        tracing::warn!("Not migrated, already present in the data directory: {}", path.display());
+    }
+    info!("Data directory: {} (layout v{})", layout.data_dir.display(), storage::LAYOUT_VERSION);
+
+    let temp_dir = std::env::temp_dir();
+    let state_temp = layout.state_file.with_extension("json.tmp");
+    let state_temp_name = state_temp.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+    let cleanups = [
+        storage::remove_matching(&temp_dir, |name| name == "imx415_capture"),
+        storage::remove_dead_pid_dirs(&temp_dir, "imx415_capturecmd_"),
+        storage::remove_dead_pid_dirs(&temp_dir, "imx415_reprocess_"),
+        storage::remove_matching(models_dir, |name| name.starts_with('.') && name.ends_with(".partial")),
+        storage::remove_matching(state_temp.parent().unwrap_or(std::path::Path::new(".")), |name| {
+            name == state_temp_name
+        }),
+    ];
+    let mut reclaimed = storage::Reclaimed::default();
+    for cleanup in cleanups {
+        match cleanup {
+            Ok(r) => reclaimed += r,
+            Err(e) => tracing::warn!("Cleanup failed: {:#}", e),
+        }
+    }
+    if reclaimed.files > 0 {
+        info!(
+            "Cleanup: removed {} orphaned temp files, reclaimed {:.1} MB",
+            reclaimed.files,
+            reclaimed.bytes as f64 / (1024.0 * 1024.0)
+        );
+    }
+    Ok(())
+}
+
+/// `--spool-mb <n>`: open the recorder and write out frames a crash left
+/// in the spool; `--record-on-detection` records while detections are
+/// confirmed
+fn start_recorder(state: &SharedState, args: &RecordingArgs) -> Result<()> {
+    let (dir, spool_dir) = {
+        let layout = state.storage.read();
+        (layout.recordings_dir.clone(), layout.spool_dir.clone())
+    };
+    let spool_mb = args.spool_mb;
+    let rollover = rollover_from_args(args)?;
+    let prebuffer = prebuffer_from_args(args)?;
+    *state.record_on_detection.write() = args.record_on_detection;
+    match Recorder::open(&dir, &spool_dir, spool_mb * 1024 * 1024, rollover, prebuffer.clone(), state.events.clone()) {
+        Ok(recorder) => {
+            info!(
+                "Recordings in {} (segments of {}, {}; spool up to {} MB)",
+                dir.display(),
+                rollover.max_secs.map_or("any length".to_string(), |secs| format!("{} s", secs)),
+                rollover.max_bytes.map_or("any size".to_string(), |bytes| format!("{} MB", bytes / 1024 / 1024)),
+                spool_mb
+            );
+            if let Some(prebuffer) = prebuffer {
+                start_prebuffer(state, prebuffer);
+            }
+            if *state.record_on_detection.read() {
+                info!("Recording while detections are confirmed");
+            }
+            for (file, frames) in recorder.repair_segments() {
+                info!("Completed {} left by an interrupted recording ({} frames)", file.display(), frames);
+            }
+            if let Some(frames) = recorder.recover() {
+                info!("Recovering {} spooled frames from an interrupted recording", frames);
+            }
+            *state.recorder.write() = Some(recorder);
+        }
+        Err(e) => error!("Recording not available: {:#}", e),
+    }
+    Ok(())
+}
+
+/// `--record-pre-secs <n>`: seconds of frames to keep for the start of a
+/// recording (0, the default: none)
+fn prebuffer_from_args(args: &RecordingArgs) -> Result<Option<Arc<PreBuffer>>> {
+    let secs = args.record_pre_secs;
+    anyhow::ensure!(
+        secs <= prebuffer::MAX_PRE_SECS,
+        "--record-pre-secs must be at most {}",
+        prebuffer::MAX_PRE_SECS
+    );
+    Ok((secs > 0).then(|| Arc::new(PreBuffer::new(Duration::from_secs(secs)))))
+}
+
+/// Feed the pre-event buffer: the published frames, and the H.264 encoder's
+/// output if there is an encoder (keeping it running)
+fn start_prebuffer(state: &SharedState, prebuffer: Arc<PreBuffer>) {
+    let h264 = state.h264.status().available;
+    info!(
+        "Pre-event buffer: {} s of {}",
+        prebuffer.duration().as_secs(),
+        if h264 { "JPEG frames and H.264" } else { "JPEG frames" }
+    );
+    tokio::spawn(prebuffer::run_frames(prebuffer.clone(), state.frame_watch.subscribe()));
+    if h264 {
+        tokio::spawn(prebuffer_h264(state.clone(), prebuffer));
+    }
+}
+
+/// Keep the H.264 encoder's access units in the pre-event buffer,
+/// resubscribing whenever the stream ends (encoder failure, new frame size)
+async fn prebuffer_h264(state: SharedState, prebuffer: Arc<PreBuffer>) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        match h264_subscription(&state).await {
+            Ok((subscription, track)) => {
+                let units = subscription.access_units(state.h264.clone(), track.clone());
+                let mut units = std::pin::pin!(units);
+                while let Some(unit) = units.next().await {
+                    backoff.reset();
+                    prebuffer.push_unit(&track, unit);
+                }
+            }
+            Err(e) => tracing::debug!("Pre-event buffer: {}", e.message),
+        }
+        tokio::time::sleep(backoff.next_delay()).await;
+    }
+}
+
+/// `--record-segment-secs <n>` (0: no limit), `--record-segment-mb <n>`
+fn rollover_from_args(args: &RecordingArgs) -> Result<Rollover> {
+    let max_secs = args.record_segment_secs;
+    let max_mb = args.record_segment_mb;
+    anyhow::ensure!(max_mb != Some(0), "--record-segment-mb must be at least 1");
+    Ok(Rollover {
+        max_secs: Some(max_secs).filter(|&secs| secs > 0),
+        max_bytes: max_mb.map(|mb| mb * 1024 * 1024),
+    })
+}
+
+/// `--snapshot-max-age-hours <n>`, `--snapshot-max-mb <n>` (0: no limit):
+/// open the snapshot archive and prune it now and every `PRUNE_INTERVAL`
+fn start_snapshot_archive(state: &SharedState, args: &RecordingArgs) -> Result<()> {
+    let retention = Retention {
+        max_age_hours: Some(args.snapshot_max_age_hours).filter(|&hours| hours > 0),
+        max_bytes: Some(args.snapshot_max_mb).filter(|&mb| mb > 0).map(|mb| mb * 1024 * 1024),
+    };
+    let dir = state.storage.read().snapshots_dir.clone();
+    info!(
+        "Snapshot archive in {} (kept {}, {})",
+        dir.display(),
+        retention.max_age_hours.map_or("indefinitely".to_string(), |hours| format!("{} h", hours)),
+        retention.max_bytes.map_or("any size".to_string(), |bytes| format!("up to {} MB", bytes / 1024 / 1024))
+    );
+    let archive = Arc::new(SnapshotArchive::new(&dir, retention));
+    *state.snapshot_archive.write() = Some(archive.clone());
+    tokio::spawn(async move {
+        let mut interval = interval(snapshots::PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let archive = archive.clone();
+            match tokio::task::spawn_blocking(move || archive.prune(unix_millis())).await {
+                Ok(Ok(pruned)) if pruned.files > 0 => {
+                    info!("Pruned {} snapshot(s) ({} bytes)", pruned.files, pruned.bytes)
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::warn!("Snapshot pruning failed: {:#}", e),
+                Err(e) => tracing::warn!("Snapshot pruning failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Stop the capture loop, reopen the camera with the current settings and resume
+async fn restart_camera(state: &SharedState) -> Result<()> {
+    state.camera_supervisor.stop();
+    
+    let previous = state.capture.write().take();
+    let config = previous.as_ref().map(|c| c.config().clone()).unwrap_or_default();
+    let settings = state.pipeline.clone();
+    let test_pattern_active = state.test_pattern.read().is_some();
+    
+    let reopened = tokio::task::spawn_blocking(move || -> Result<FrameCapture> {
+        drop(previous); // stops the old stream before the new instance opens the device
+        let mut capture = FrameCapture::with_config(config, settings)?;
+        capture.setup_sensor()?;
+        capture.start_streaming()?;
+        capture.set_test_pattern_active(test_pattern_active);
+        Ok(capture)
+    })
+    .await?;
+    
+    match reopened {
+        Ok(capture) => {
+            *state.capture.write() = Some(capture);
+            state.capture_watchdog.lock().frame_ok();
+            start_capture_loop(state);
+            Ok(())
+        }
+        Err(e) => {
+            state.camera_supervisor.failed(format!("{:#}", e));
+            state.events.publish(DomainEvent::CameraDegraded { error: format!("{:#}", e) });
+            Err(e)
+        }
+    }
+}
+
+/// Shut down the detector backend and start a fresh one
+fn restart_detector(state: &AppState) {
+    state.detector_supervisor.stop();
+    // Dropping the detector shuts down its thread and kills any subprocess
+    drop(state.detector.write().take());
+    clear_detections(state);
+    start_detector(state);
+    if state.detector.read().is_none() && *state.detection_enabled.read() {
+        tracing::warn!("Detection disabled: the restarted detector is not available");
+        set_detection_enabled(state, false);
+    }
+}
+
+fn detector_exit_reason(detector: &YoloDetector) -> String {
+    match detector.exit_error() {
+        Some(error) => format!("Detector stopped: {}", error),
+        None => "Detector stopped".to_string(),
+    }
+}
+
+/// Restart the detector when its backend dies (e.g. the Python script
+/// crashing mid-read), with exponential backoff between attempts. Frames
+/// are not fed while it is down; detection stays switched on and resumes
+/// with the restarted detector.
+async fn detector_monitor(state: SharedState) {
+    let mut ticker = interval(DETECTOR_CHECK_INTERVAL);
+    let mut backoff = Backoff::new(DETECTOR_RESTART_MIN_DELAY, DETECTOR_RESTART_MAX_DELAY);
+    // Why the detector is down, while it is being restarted
+    let mut down: Option<String> = None;
+    let mut restarted_at: Option<std::time::Instant> = None;
+    loop {
+        ticker.tick().await;
+        let exited = match *state.detector.read() {
+            Some(ref detector) if !detector.is_running() => Some(detector_exit_reason(detector)),
+            _ => None,
+        };
+        if let Some(reason) = exited {
+            tracing::error!("{}", reason);
+            drop(state.detector.write().take());
+            clear_detections(&state);
+            down = Some(reason);
+        } else if state.detector.read().is_some() {
+            // Up, possibly started by hand (/admin/restart, model activation)
+            down = None;
+            if restarted_at.is_some_and(|t| t.elapsed() >= DETECTOR_STABLE_PERIOD) {
+                backoff.reset();
+                restarted_at = None;
+            }
+            continue;
+        }
+        // Never started, or stopped on purpose
+        let Some(ref reason) = down else {
+            continue;
+        };
+
+        let delay = backoff.next_delay();
+        state.detector_supervisor.restart_scheduled(reason.clone(), delay);
+        tracing::warn!("Restarting the detector in {:.0} s", delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+        if state.detector.read().is_some() {
+            continue;
+        }
+        state.detector_supervisor.record_restart();
+        start_detector(&state);
+        if state.detector.read().is_some() {
+            info!("Detector restarted");
+            down = None;
+            restarted_at = Some(std::time::Instant::now());
+        } else {
+            let error = state.detector_supervisor.status().last_error;
+            down = Some(error.unwrap_or_else(|| "Detector restart failed".to_string()));
+        }
+    }
+}
+
+/// Reopen the camera (see `restart_camera`) when the capture pipeline
+/// stalls, with exponential backoff between attempts; a failed reopen is
+/// retried the same way
+async fn camera_watchdog(state: SharedState) {
+    let mut ticker = interval(WATCHDOG_CHECK_INTERVAL);
+    let mut backoff = Backoff::new(CAMERA_RESTART_MIN_DELAY, CAMERA_RESTART_MAX_DELAY);
+    let mut restarted_at: Option<std::time::Instant> = None;
+    loop {
+        ticker.tick().await;
+        // Frames only come with the external trigger in trigger mode
+        let trigger_mode = state.gpio_config.read().trigger_pin.is_some();
+        let Some(reason) = state.capture_watchdog.lock().stalled(!trigger_mode) else {
+            if restarted_at.is_some_and(|t| t.elapsed() >= CAMERA_STABLE_PERIOD) {
+                backoff.reset();
+                restarted_at = None;
+            }
+            continue;
+        };
+
+        let delay = backoff.next_delay();
+        tracing::warn!("Capture stalled ({}), reopening the camera in {:.0} s", reason, delay.as_secs_f64());
+        state.camera_supervisor.restart_scheduled(reason.clone(), delay);
+        tokio::time::sleep(delay).await;
+        state.camera_supervisor.record_restart();
+        let result = restart_camera(&state).await;
+        match result {
+            Ok(()) => info!("Camera reopened after a stall"),
+            Err(ref e) => error!("Reopening the stalled camera failed: {:#}", e),
+        }
+        state.capture_watchdog.lock().recovered(reason, result.is_ok(), unix_millis());
+        restarted_at = Some(std::time::Instant::now());
+    }
+}
+
+/// Supervisor snapshots, refreshed with subsystem-specific health checks
+fn subsystem_statuses(state: &AppState) -> Vec<supervisor::SubsystemStatus> {
+    if let Some(ref detector) = *state.detector.read() {
+        if !detector.is_running() {
+            state.detector_supervisor.degraded(detector_exit_reason(detector));
+        }
+    }
+    let mut statuses = vec![state.camera_supervisor.status(), state.detector_supervisor.status()];
+    if state.push_config.read().is_some() {
+        statuses.push(state.push_supervisor.status());
+    }
+    if state.home_assistant.read().is_some() {
+        // The task keeps retrying; it is degraded while disconnected
+        let mut status = state.mqtt_supervisor.status();
+        let stats = state.ha_stats.snapshot();
+        if status.state == supervisor::SubsystemState::Running && !stats.connected {
+            status.state = supervisor::SubsystemState::Degraded;
+            status.last_error = stats.last_error.or_else(|| Some("Not connected".to_string()));
+        }
+        statuses.push(status);
+    }
+    statuses
+}
+
+/// List supervised subsystems with state, uptime and restart count
+#[utoipa::path(
+    get,
+    path = "/admin/subsystems",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Supervised subsystems with state, uptime and restart count", body = Object),
+    )
+)]
+async fn admin_subsystems_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    axum::Json(serde_json::json!({
+        "subsystems": subsystem_statuses(&state)
+    }))
+}
+
+/// Restart a single subsystem without restarting the process
+#[utoipa::path(
+    post,
+    path = "/admin/restart/{subsystem}",
+    tag = "admin",
+    params(("subsystem" = String, Path, description = "`camera` or `detector`")),
+    responses(
+        (status = 200, description = "Subsystem restarted", body = Object),
+        (status = 404, description = "Unknown subsystem", body = ApiError),
+        (status = 503, description = "Restart failed", body = ApiError),
+    )
+)]
+async fn admin_restart_handler(
+    State(state): State<SharedState>,
+    Path(subsystem): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let result = match subsystem.as_str() {
+        "camera" => {
+            state.camera_supervisor.record_restart();
+            restart_camera(&state).await
+        }
+        "detector" => {
+            state.detector_supervisor.record_restart();
+            restart_detector(&state);
+            Ok(())
+        }
+        _ => {
+            return Err(ApiError::not_found(format!("Unknown subsystem '{}'", subsystem))
+                .with_details(serde_json::json!({ "available": SUBSYSTEM_NAMES })));
+        }
+    };
+    
+    let status = subsystem_statuses(&state)
+        .into_iter()
+        .find(|s| s.name == subsystem);
+    match result {
+        Ok(()) => {
+            info!("Subsystem '{}' restarted", subsystem);
+            Ok(axum::Json(serde_json::json!({
+                "subsystem": subsystem,
+                "status": status,
+                "success": true
+            })))
+        }
+        Err(e) => {
+            error!("Restarting '{}' failed: {:#}", subsystem, e);
+            Err(ApiError::unavailable(format!("Restarting '{}' failed: {:#}", subsystem, e))
+                .with_details(serde_json::json!({ "status": status })))
+        }
+    }
+}
+
+/// Capture tick period, longer while the thermal policy is throttling
+fn capture_period(state: &AppState) -> Duration {
+    if state.thermal.read().throttled {
+        Duration::from_millis(1000 / state.thermal_config.read().throttled_fps.max(1) as u64)
+    } else {
+        CAPTURE_PERIOD
+    }
+}
+
+/// Capture and process one frame, with the strobe asserted around the raw capture
+fn capture_frame(state: &AppState, capture: &mut FrameCapture, timing: &mut FrameTiming) -> Result<Vec<u8>> {
+    timing.capture_start_us = latency::now_us();
+    let raw = match state.gpio.lock().as_mut() {
+        Some(gpio) => {
+            if let Err(e) = gpio.strobe_on(&state.gpio_stats) {
+                tracing::warn!("Strobe assert failed: {:#}", e);
+            }
+            // The frame in flight started before the strobe (or trigger)
+            capture.skip_frames(0);
+            let raw = capture.capture_next();
+            if let Err(e) = gpio.strobe_off(&state.gpio_stats) {
+                tracing::warn!("Strobe deassert failed: {:#}", e);
+            }
+            raw
+        }
+        None => capture.capture_next(),
+    };
+    timing.capture_done_us = latency::now_us();
+    let raw = raw?;
+    add_to_stack(state, capture, &raw);
+    capture.process_capture(&raw)
+}
+
+/// Add a raw frame to the running stack, if any (the long exposure of an
+/// HDR pair)
+fn add_to_stack(state: &AppState, capture: &mut FrameCapture, raw: &capture::RawCapture) {
+    let mut stack = state.stack.lock();
+    let Some(stack) = stack.as_mut().filter(|s| s.state() == StackState::Running) else {
+        return;
+    };
+    let frame = match raw {
+        capture::RawCapture::Frame(frame) => frame,
+        capture::RawCapture::Pair(pair) => &pair.long,
+    };
+    let size = capture.frame_size();
+    let layout = RawLayout {
+        width: size.width,
+        height: size.height,
+        cfa: capture.raw_format().cfa,
+        orientation: capture.raw_orientation(),
+    };
+    stack.add(capture.unpack_raw(frame), layout, unix_millis());
+    match stack.state() {
+        StackState::Running => {}
+        StackState::Done => info!("Stack of {} frames done", stack.frames()),
+        StackState::Failed => tracing::warn!("Stack failed: {}", stack.status().error.unwrap_or_default()),
+    }
+}
+
+async fn capture_loop(state: SharedState) {
+    let mut interval = interval(CAPTURE_PERIOD);
+    let mut detection_frame_counter = 0u64;
+    let mut last_frame_at = std::time::Instant::now();
+    // Capture failing since the last good frame (reported once per outage)
+    let mut camera_failing = false;
+    
+    loop {
+        let trigger_mode = state.gpio.lock().as_ref().is_some_and(|gpio| gpio.trigger_mode());
+        if trigger_mode {
+            // Wait for the external trigger instead of the timer
+            let fired = tokio::task::block_in_place(|| match state.gpio.lock().as_mut() {
+                Some(gpio) => gpio.wait_trigger(&state.gpio_stats),
+                None => Ok(true),
+            });
+            match fired {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    error!("Trigger wait failed: {:#}", e);
+                    interval.tick().await;
+                    continue;
+                }
+            }
+        } else {
+            let period = capture_period(&state);
+            if interval.period() != period {
+                interval = tokio::time::interval(period);
+            }
+            interval.tick().await;
+        }
+        let frame_period = last_frame_at.elapsed();
+        last_frame_at = std::time::Instant::now();
+        
+        // Capture blocks for longer than the tick period, so `tick()` is usually
+        // ready immediately; hand this worker's other tasks off while we block
+        let mut timing = FrameTiming::default();
+        let captured = tokio::task::block_in_place(|| {
+            let mut capture_guard = state.capture.write();
+            capture_guard
+                .as_mut()
+                .map(|capture| {
+                    let motion_enabled = state.motion.lock().enabled();
+                    capture.set_native_gray(motion_enabled);
+                    let frame = capture_frame(&state, capture, &mut timing);
+                    let transition = match capture.native_gray() {
+                        Some((width, height, gray)) if frame.is_ok() && motion_enabled => {
+                            state.motion.lock().update(width, height, gray, unix_millis())
+                        }
+                        _ => None,
+                    };
+                    let stages = StageTimings {
+                        capture: Some(timing.capture_done_us.saturating_sub(timing.capture_start_us) as f32 / 1000.0),
+                        ..capture.stage_timings()
+                    };
+                    let histogram = capture.histogram();
+                    (frame, capture.last_image(), histogram, capture.applied_version(), transition, stages)
+                })
+        });
+        let Some((frame_result, image, histogram, settings_version, motion, mut stages)) = captured else {
+            continue;
+        };
+        
+        match frame_result {
+            Ok(mut jpeg_data) => {
+                state.capture_watchdog.lock().frame_ok();
+                if std::mem::take(&mut camera_failing) {
+                    info!("Capture recovered");
+                    state.events.publish(DomainEvent::CameraRecovered);
+                }
+                let detection_enabled = *state.detection_enabled.read();
+                let clean = Bytes::from(jpeg_data.clone());
+                let frame_seq = *state.frame_count.read() + 1;
+                let timestamp_ms = unix_millis();
+                
+                // Run detection every Nth frame to maintain framerate (never when on demand);
+                // a CPU backend that can't keep up gets fed less often
+                let mut detection_interval = *state.detection_interval.read();
+                if detection_interval > 0 {
+                    if let Some(ref detector) = *state.detector.read() {
+                        detection_interval = detection_interval.max(detector.min_interval(frame_period));
+                    }
+                }
+                *state.effective_detection_interval.write() = detection_interval;
+                if detection_enabled {
+                    detection_frame_counter += 1;
+                    
+                    if detection_interval > 0 && detection_frame_counter.is_multiple_of(detection_interval as u64) {
+                        // Send frame to detector (replaces any frame it hasn't picked up yet)
+                        if let Some(ref detector) = *state.detector.read() {
+                            if detection_frame_counter.is_multiple_of(30) {
+                                tracing::info!("Sending frame {} to detector ({} bytes)", frame_seq, jpeg_data.len());
+                            }
+                            let _ = detector.detect(frame_seq, jpeg_data.clone(), image.clone());
+                        }
+                    }
+                    
+                    // Get latest detection results
+                    let result = state.detector.read().as_ref().map(|d| d.get_last_result());
+                    if let Some(result) = result {
+                        record_detections(&state, result);
+                    }
+                    
+                    // Draw detection boxes on frame
+                    let draw_start = std::time::Instant::now();
+                    let style = state.annotation.read().style;
+                    let detections = state.last_detections.read();
+                    if !detections.detections.is_empty() {
+                        // The frame is still the clean encoding of `image`
+                        let annotated = match image.as_deref() {
+                            Some(pixels) => detector::annotate_image(pixels, &detections.detections, &style),
+                            None => detector::draw_detections(&jpeg_data, &detections.detections, &style),
+                        };
+                        match annotated {
+                            Ok(annotated) => jpeg_data = annotated,
+                            Err(e) => tracing::warn!("Failed to draw detections: {}", e),
+                        }
+                        stages.draw = capture::elapsed_ms(draw_start);
+                    }
+                }
+                
+                if let Some(ref pixels) = image {
+                    let focus_start = std::time::Instant::now();
+                    state.focus.lock().update(frame_seq, pixels);
+                    stages.focus = capture::elapsed_ms(focus_start);
+                }
+                
+                let metering = state.pipeline.load().metering;
+                if let Some(roi) = metering.overlay_region().filter(|_| metering.overlay) {
+                    match metering::draw_region(&jpeg_data, roi) {
+                        Ok(annotated) => jpeg_data = annotated,
+                        Err(e) => tracing::warn!("Failed to draw metering region: {}", e),
+                    }
+                }
+                
+                let blink = state.blink.lock().take();
+                if blink.is_some() {
+                    match latency::draw_marker(&jpeg_data) {
+                        Ok(marked) => jpeg_data = marked,
+                        Err(e) => tracing::warn!("Failed to draw blink marker: {}", e),
+                    }
+                }
+                timing.processed_us = latency::now_us();
+                *state.stage_timings.write() = Some(stages);
+                *state.histogram.write() = histogram.map(|histogram| (frame_seq, histogram));
+                
+                state.history.write().push(frame_seq, timestamp_ms, clean.clone());
+                let jpeg_data = Bytes::from(jpeg_data);
+                *state.current_image.write() = image.clone();
+                *state.frame_count.write() += 1;
+                {
+                    let mut frame_hashes = state.frame_hashes.write();
+                    let hashes = FrameHashes::next(
+                        frame_hashes.as_ref(),
+                        frame_seq,
+                        timestamp_ms,
+                        settings_version,
+                        &jpeg_data,
+                        &clean,
+                    );
+                    *state.clean_frame.write() = Some(clean);
+                    *frame_hashes = Some(hashes);
+                    if let Some(ref image) = image {
+                        state.decode_cache.seed(hashes.clean, image.clone());
+                    }
+                    // Published last, so watchers (streams, push, job frame
+                    // waits) see a frame that is already fully stored
+                    timing.published_us = latency::now_us();
+                    state.frame_watch.send_replace(Some(PushFrame {
+                        seq: frame_seq,
+                        timestamp_ms,
+                        jpeg: jpeg_data,
+                        hash: hashes.annotated,
+                        timing,
+                    }));
+                }
+                state.latency.record_frame(&timing);
+                if let Some(transition) = motion {
+                    publish_motion(&state, frame_seq, transition);
+                }
+                if let Some(blink) = blink {
+                    let _ = blink.send((frame_seq, timestamp_ms));
+                }
+                account_memory(&state);
+            }
+            Err(e) => {
+                error!("Capture error: {}", e);
+                state.capture_watchdog.lock().frame_failed();
+                if !std::mem::replace(&mut camera_failing, true) {
+                    state.events.publish(DomainEvent::CameraDegraded { error: format!("{:#}", e) });
+                }
+            }
+        }
+    }
+}
+
+/// Update per-component memory usage and degrade features in priority
+/// order (see `memory`) if the budget is approached
+fn account_memory(state: &AppState) {
+    let memory = &state.memory;
+    let current_frame = state.frame_watch.borrow().as_ref().map_or(0, |f| f.jpeg.len())
+        + state.clean_frame.read().as_ref().map_or(0, |f| f.len())
+        + state.current_image.read().as_ref().map_or(0, |i| i.as_bytes().len())
+        + state.scaled_frames.bytes();
+    memory.set(Component::CurrentFrame, current_frame);
+    let detector_input = state.detector.read().as_ref().map_or(0, |d| d.input_bytes());
+    memory.set(Component::DetectorInput, detector_input);
+    let reference = state
+        .compare_reference
+        .read()
+        .as_ref()
+        .map_or(0, |r| r.image.as_bytes().len());
+    memory.set(Component::CompareReference, reference);
+    memory.set(Component::DecodeCache, state.decode_cache.bytes());
+    let prebuffer = state.recorder.read().as_ref().and_then(|r| r.prebuffer().cloned());
+    memory.set(Component::PreBuffer, prebuffer.as_ref().map_or(0, |p| p.bytes()));
+    memory.set(Component::Stack, state.stack.lock().as_ref().map_or(0, FrameStack::bytes));
+    
+    // 1. Shrink the history ring to fit under the soft limit
+    let mut pressure = MemoryPressure::Normal;
+    {
+        let mut history = state.history.write();
+        if history.set_byte_limit(memory.allowance(Component::History)) {
+            pressure = MemoryPressure::Shrinking;
+        }
+        memory.set(Component::History, history.bytes());
+    }
+    
+    // 2. Drop non-essential retention
+    if memory.over_budget() {
+        if state.compare_reference.write().take().is_some() {
+            tracing::warn!("Memory budget exceeded, dropped the comparison reference");
+        }
+        memory.set(Component::CompareReference, 0);
+        state.decode_cache.clear();
+        memory.set(Component::DecodeCache, 0);
+        if let Some(prebuffer) = prebuffer {
+            prebuffer.clear();
+        }
+        memory.set(Component::PreBuffer, 0);
+        if let Some(stack) = state.stack.lock().as_mut().filter(|s| s.bytes() > 0) {
+            stack.fail("Dropped to stay within the memory budget".into(), unix_millis());
+            tracing::warn!("Memory budget exceeded, dropped the frame stack");
+        }
+        memory.set(Component::Stack, 0);
+        pressure = MemoryPressure::Shedding;
+    }
+    
+    // 3. Essential buffers alone don't fit
+    if memory.essential_total() > memory.budget() {
+        pressure = MemoryPressure::Critical;
+    }
+    
+    let previous = memory.set_pressure(pressure);
+    if pressure != previous {
+        tracing::warn!(
+            "Memory pressure {:?} -> {:?} ({} of {} bytes)",
+            previous,
+            pressure,
+            memory.total(),
+            memory.budget()
+        );
+    }
+}
+
+/// Read the sensor temperature control, if the driver exposes one
+fn read_sensor_temperature(state: &AppState, control: &mut Option<Option<String>>) -> Option<f32> {
+    let device = state.capture.read().as_ref()?.config().sensor_subdev.clone();
+    // Look the control up once; most IMX415 drivers don't have one
+    let name = control.get_or_insert_with(|| {
+        let name = controls::list_controls(&device)
+            .ok()?
+            .into_iter()
+            .find(|c| c.name.contains("temperature"))
+            .map(|c| c.name);
+        match &name {
+            Some(name) => info!("Sensor temperature control: {}", name),
+            None => info!("Sensor exposes no temperature control"),
+        }
+        name
+    });
+    let value = controls::find_control(&device, name.as_ref()?).ok()?.value?;
+    Some(thermal::sensor_value_to_c(value))
+}
+
+/// Poll temperatures, warn on crossings and apply the throttling policy
+async fn thermal_monitor(state: SharedState) {
+    let mut ticker = interval(THERMAL_POLL_INTERVAL);
+    let mut sensor_control = None;
+    loop {
+        ticker.tick().await;
+        let poll_state = state.clone();
+        let mut control = sensor_control.take();
+        let Ok((zones, sensor_c, control)) = tokio::task::spawn_blocking(move || {
+            let zones = thermal::read_zones(std::path::Path::new(thermal::THERMAL_ROOT));
+            let sensor_c = read_sensor_temperature(&poll_state, &mut control);
+            (zones, sensor_c, control)
+        })
+        .await
+        else {
+            continue;
+        };
+        sensor_control = control;
+        
+        let config = state.thermal_config.read().clone();
+        let max_c = ThermalStatus::hottest(&zones, sensor_c);
+        let mut status = state.thermal.write();
+        let warning = max_c.is_some_and(|t| t >= config.warn_c);
+        if warning && !status.warning {
+            tracing::warn!(
+                "Temperature {:.1}°C reached the warning threshold ({:.1}°C)",
+                max_c.unwrap_or_default(),
+                config.warn_c
+            );
+        } else if !warning && status.warning {
+            info!("Temperature back below the warning threshold ({:.1}°C)", config.warn_c);
+        }
+        
+        let throttled = ThermalStatus::should_throttle(&config, max_c, status.throttled);
+        if throttled && !status.throttled {
+            tracing::warn!("Thermal throttling: capture reduced to {} fps", config.throttled_fps);
+            status.throttled_since_ms = Some(unix_millis());
+        } else if !throttled && status.throttled {
+            info!("Thermal throttling ended, capture rate restored");
+            status.throttled_since_ms = None;
+        }
+        
+        status.zones = zones;
+        status.sensor_c = sensor_c;
+        status.max_c = max_c;
+        status.warning = warning;
+        status.throttled = throttled;
+    }
+}
+
+/// Turn detection on or off (clearing the last result when off)
+fn set_detection_enabled(state: &AppState, enable: bool) {
+    *state.detection_enabled.write() = enable;
+    if !enable {
+        clear_detections(state);
+    }
+}
+
+/// The schedule saved in the settings state file (empty if none)
+fn load_schedule(state_file: &std::path::Path) -> Result<Schedule> {
+    let config = match profiles::read_state_key(state_file, "schedule")? {
+        Some(config) => serde_json::from_value(config)
+            .with_context(|| format!("Invalid schedule in {}", state_file.display()))?,
+        None => ScheduleConfig::default(),
+    };
+    Schedule::parse(config).map_err(|e| anyhow::anyhow!("Invalid schedule in {}: {}", state_file.display(), e))
+}
+
+/// Switch scheduled features at their window boundaries and apply
+/// scheduled profiles when they are due
+async fn schedule_loop(state: SharedState) {
+    loop {
+        let now = chrono::Utc::now();
+        let due = state.scheduler.write().due_profile(now);
+        if let Some(name) = due {
+            apply_scheduled_profile(&state, &name).await;
+        }
+        let (changes, next_wake) = {
+            let mut scheduler = state.scheduler.write();
+            (scheduler.tick(now), scheduler.next_wake(now))
+        };
+        for (feature, enabled) in changes {
+            apply_scheduled(&state, feature, enabled);
+        }
+        
+        let wait = next_wake
+            .and_then(|t| (t - now).to_std().ok())
+            .map_or(SCHEDULE_RECHECK, |d| d.min(SCHEDULE_RECHECK));
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = state.schedule_changed.notified() => {}
+        }
+    }
+}
+
+fn apply_scheduled(state: &AppState, feature: Feature, enabled: bool) {
+    match feature {
+        Feature::Detection => {
+            if enabled && state.detector.read().is_none() {
+                tracing::warn!("Schedule: detection window started, but the detector is not available");
+                return;
+            }
+            set_detection_enabled(state, enabled);
+        }
+    }
+    info!("Schedule: {} {}", feature.name(), if enabled { "on" } else { "off" });
+}
+
+async fn apply_scheduled_profile(state: &SharedState, name: &str) {
+    let Some(profile) = state.profiles.read().get(name).cloned() else {
+        tracing::warn!("Schedule: profile '{}' is due, but no longer saved", name);
+        return;
+    };
+    info!("Schedule: applying profile '{}'", name);
+    let apply_state = state.clone();
+    match tokio::task::spawn_blocking(move || apply_settings(&apply_state, &profile.settings)).await {
+        Ok((applied, skipped)) => profile_applied(state, name, &applied, &skipped),
+        Err(e) => tracing::error!("Schedule: applying profile '{}' failed: {}", name, e),
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ModeParams {
+    /// HDR modes' long/short exposure ratio, 2-16 (default: unchanged)
+    ratio: Option<f32>,
+    /// Color mode demosaic, `bilinear` or `malvar` (default: unchanged)
+    demosaic: Option<String>,
+    /// `auto`: scene brightness (EV) below which night starts (default: unchanged)
+    night_below: Option<f32>,
+    /// `auto`: scene brightness (EV) above which day starts (default: unchanged)
+    day_above: Option<f32>,
+    /// `auto`: seconds the brightness must stay past a threshold (default: unchanged)
+    hold_secs: Option<f32>,
+}
+
+/// Set capture mode endpoint (`?ratio=` sets the HDR modes' exposure ratio,
+/// `?demosaic=` the color demosaic). `auto` switches between color and
+/// grayscale by scene brightness (`?night_below=&day_above=&hold_secs=`);
+/// any other mode turns that off.
+#[utoipa::path(
+    get,
+    path = "/mode/{mode}",
+    tag = "camera",
+    params(("mode" = String, Path, description = "`grayscale`, `grayscale-hdr`, `color`, `color-hdr` or `auto`"), ModeParams),
+    responses(
+        (status = 200, description = "Mode set, applied from the next frame", body = Object),
+        (status = 400, description = "Unknown mode or demosaic, or ratio or thresholds out of range", body = ApiError),
+    )
+)]
+async fn set_mode_handler(
+    State(state): State<SharedState>,
+    Path(mode): Path<String>,
+    Query(params): Query<ModeParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if mode == "auto" {
+        let current = state.day_night.lock().config().clone();
+        let config = DayNightConfig {
+            enabled: true,
+            night_below: params.night_below.unwrap_or(current.night_below),
+            day_above: params.day_above.unwrap_or(current.day_above),
+            hold_secs: params.hold_secs.unwrap_or(current.hold_secs),
+        };
+        config.validate().map_err(ApiError::bad_request)?;
+        set_day_night_config(&state, config).await?;
+        return Ok(axum::Json(serde_json::json!({
+            "mode": "auto",
+            "day_night": state.day_night.lock().status(),
+            "success": true
+        })));
+    }
+    let new_mode = CaptureMode::parse(&mode).ok_or_else(|| {
+        ApiError::bad_request("Invalid mode. Use 'grayscale', 'grayscale-hdr', 'color', 'color-hdr' or 'auto'")
+    })?;
+    if params.ratio.is_some_and(|ratio| !(hdr::MIN_RATIO..=hdr::MAX_RATIO).contains(&ratio)) {
+        return Err(ApiError::bad_request(format!(
+            "Exposure ratio must be {}-{}",
+            hdr::MIN_RATIO,
+            hdr::MAX_RATIO
+        )));
+    }
+    let demosaic = params
+        .demosaic
+        .as_deref()
+        .map(|name| {
+            DemosaicAlgorithm::parse(name)
+                .ok_or_else(|| ApiError::bad_request("Invalid demosaic. Use 'bilinear' or 'malvar'"))
+        })
+        .transpose()?;
+    let day_night = state.day_night.lock().config().clone();
+    if day_night.enabled {
+        set_day_night_config(&state, DayNightConfig { enabled: false, ..day_night }).await?;
+    }
+    // Mode, ratio and demosaic take effect together
+    let published = state.pipeline.update(|s| {
+        if let Some(ratio) = params.ratio {
+            s.set_hdr_ratio(ratio);
+        }
+        if let Some(demosaic) = demosaic {
+            s.set_demosaic(demosaic);
+        }
+        s.set_mode(new_mode);
+    });
+    
+    Ok(axum::Json(serde_json::json!({
+        "mode": format!("{:?}", new_mode),
+        "hdr_ratio": published.hdr_ratio,
+        "demosaic": published.demosaic.name(),
+        "success": true
+    })))
+}
+
+/// Switch the capture pipeline mode (from the next frame on)
+fn apply_mode(state: &AppState, mode: CaptureMode) {
+    state.pipeline.update(|s| s.set_mode(mode));
+}
+
+fn load_day_night_config(state_file: &std::path::Path) -> Result<DayNightConfig> {
+    let config: DayNightConfig = match profiles::read_state_key(state_file, daynight::STATE_KEY)? {
+        Some(config) => serde_json::from_value(config)
+            .with_context(|| format!("Invalid day/night config in {}", state_file.display()))?,
+        None => DayNightConfig::default(),
+    };
+    config
+        .validate()
+        .map_err(|e| anyhow::anyhow!("Invalid day/night config in {}: {}", state_file.display(), e))?;
+    Ok(config)
+}
+
+fn log_day_night_config(config: &DayNightConfig) {
+    if !config.enabled {
+        info!("Day/night switching: off");
+        return;
+    }
+    info!(
+        "Day/night switching: night below {} EV, day above {} EV, hold {}s",
+        config.night_below, config.day_above, config.hold_secs
+    );
+}
+
+/// Save and apply a day/night config
+async fn set_day_night_config(state: &SharedState, config: DayNightConfig) -> ApiResult<()> {
+    let path = state.profiles.read().path().to_path_buf();
+    let value = serde_json::to_value(&config).map_err(|e| ApiError::internal(e.to_string()))?;
+    tokio::task::spawn_blocking(move || profiles::write_state_key(&path, daynight::STATE_KEY, value)).await??;
+    log_day_night_config(&config);
+    state.day_night.lock().set_config(config);
+    Ok(())
+}
+
+/// Measure the scene brightness every few seconds and switch between the
+/// day and night modes while day/night switching is on
+async fn day_night_loop(state: SharedState) {
+    let mut ticker = interval(Duration::from_secs(daynight::POLL_SECS));
+    loop {
+        ticker.tick().await;
+        if !state.day_night.lock().config().enabled || state.test_pattern.read().is_some() {
+            continue;
+        }
+        let Some(mean) = state.histogram.read().as_ref().and_then(|(_, histogram)| histogram.mean()) else {
+            continue;
+        };
+        let pipeline = state.pipeline.load();
+        let gamma = pipeline.mode.output_gamma(pipeline.gamma);
+        let sensor = state.capture.read().as_ref().and_then(|c| {
+            c.config().source.has_sensor().then(|| (c.config().sensor_subdev.clone(), c.frame_size().width))
+        });
+        // Exposure time and gain as the EXIF of a still would record them
+        let Ok(fields) = tokio::task::spawn_blocking(move || {
+            let mut fields = exif::ExifFields::new(unix_millis());
+            if let Some((subdev, width)) = sensor {
+                match controls::list_controls(&subdev) {
+                    Ok(controls) => fields = fields.with_sensor_controls(&controls, width),
+                    Err(e) => tracing::warn!("No sensor controls for day/night switching: {:#}", e),
+                }
+            }
+            fields
+        })
+        .await
+        else {
+            continue;
+        };
+        let gain = fields.iso.map_or(1.0, |iso| iso as f64 / exif::BASE_ISO);
+        let Some(brightness) = daynight::scene_brightness(mean, gamma, fields.exposure_s.unwrap_or(1.0), gain) else {
+            continue;
+        };
+        let Some(period) = state.day_night.lock().update(brightness, unix_millis()) else {
+            continue;
+        };
+        let mode = period.mode();
+        info!("Day/night: {} at {:.1} EV, switching to {}", period.name(), brightness, mode.name());
+        apply_mode(&state, mode);
+        state.events.publish(DomainEvent::DayNightSwitched {
+            period: period.name().to_string(),
+            mode: mode.name().to_string(),
+            brightness,
+        });
+    }
+}
+
+/// Parse an on/off path segment
+fn parse_on_off(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "on" | "true" | "1" | "enable" | "enabled" => Some(true),
+        "off" | "false" | "0" | "disable" | "disabled" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse an on/off value, or a 400 naming the parameter
+fn require_on_off(value: &str, what: &str) -> ApiResult<bool> {
+    parse_on_off(value).ok_or_else(|| ApiError::bad_request(format!("Invalid {}. Use 'on' or 'off'", what)))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RowNoiseParams {
+    /// Fraction of the row offset to remove, 0.0-1.0 (default: unchanged)
+    strength: Option<f32>,
+}
+
+/// Toggle grayscale row-noise correction endpoint (`?strength=0.0-1.0`)
+#[utoipa::path(
+    get,
+    path = "/control/rownoise/{enabled}",
+    tag = "camera",
+    params(("enabled" = String, Path, description = "`on` or `off`"), RowNoiseParams),
+    responses(
+        (status = 200, description = "Correction set", body = Object),
+        (status = 400, description = "Not on/off", body = ApiError),
+    )
+)]
+async fn set_row_noise_handler(
+    State(state): State<SharedState>,
+    Path(enabled): Path<String>,
+    Query(params): Query<RowNoiseParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let enable = require_on_off(&enabled, "value")?;
+    
+    let published = state.pipeline.update(|s| {
+        s.set_row_noise_correction(enable, params.strength.unwrap_or(s.row_noise_strength));
+    });
+    
+    Ok(axum::Json(serde_json::json!({
+        "row_noise_correction": enable,
+        "strength": published.row_noise_strength,
+        "settings_version": published.version,
+        "success": true
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WbParams {
+    /// Red gain (default: unchanged, or the gain applied last)
+    r: Option<f32>,
+    /// Green gain (default: unchanged, or the gain applied last)
+    g: Option<f32>,
+    /// Blue gain (default: unchanged, or the gain applied last)
+    b: Option<f32>,
+    /// `true` returns to gray-world (can't be combined with gains), `false`
+    /// keeps the gains applied now as manual ones
+    auto: Option<bool>,
+}
+
+/// White balance gains endpoint: `?r=&g=&b=` fixes the gains (channels
+/// left out keep their current gain), `?auto=false` locks the gains the
+/// estimate arrived at, `?auto=true` returns to gray-world; without
+/// parameters it reports the current state
+#[utoipa::path(
+    get,
+    path = "/control/wb",
+    tag = "camera",
+    params(WbParams),
+    responses(
+        (status = 200, description = "Current or updated white balance", body = Object),
+        (status = 400, description = "Gain out of range, or gains combined with `auto=true`", body = ApiError),
+    )
+)]
+async fn set_wb_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<WbParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let requested = [params.r, params.g, params.b];
+    let range = whitebalance::MIN_MANUAL_GAIN..=whitebalance::MAX_MANUAL_GAIN;
+    if requested.iter().flatten().any(|gain| !range.contains(gain)) {
+        return Err(ApiError::bad_request(format!(
+            "Gains must be between {} and {}",
+            whitebalance::MIN_MANUAL_GAIN,
+            whitebalance::MAX_MANUAL_GAIN
+        )));
+    }
+    let set_gains = requested.iter().any(Option::is_some);
+    // Channels not given keep the gains of the preset or manual setting,
+    // or those the estimate applied last
+    let set_manual = || {
+        let applied = state.capture.read().as_ref().and_then(FrameCapture::white_balance_gains);
+        state.pipeline.update(|s| {
+            let current = s.wb_mode.fixed_gains().or(applied).unwrap_or([1.0; 3]);
+            s.set_wb_mode(WhiteBalanceMode::Manual(std::array::from_fn(|c| requested[c].unwrap_or(current[c]))));
+        })
+    };
+    let published = match params.auto {
+        Some(true) if set_gains => {
+            return Err(ApiError::bad_request("Give either gains or auto=true, not both"));
+        }
+        Some(true) => state.pipeline.update(|s| s.set_wb_mode(WhiteBalanceMode::GrayWorld)),
+        Some(false) => set_manual(),
+        None if set_gains => set_manual(),
+        None => state.pipeline.load(),
+    };
+    Ok(wb_response(&state, &published))
+}
+
+/// White balance mode endpoint: `gray_world`, `white_patch` (alias
+/// `retinex`) or a preset (`daylight`, `cloudy`, `shade`, `tungsten`,
+/// `fluorescent`); manual gains are set with `/control/wb`
+#[utoipa::path(
+    get,
+    path = "/control/wb/{mode}",
+    tag = "camera",
+    params(("mode" = String, Path, description = "`gray_world`, `white_patch` or a preset name")),
+    responses(
+        (status = 200, description = "White balance mode set", body = Object),
+        (status = 400, description = "Unknown mode (details list the available ones)", body = ApiError),
+    )
+)]
+async fn set_wb_mode_handler(
+    State(state): State<SharedState>,
+    Path(mode): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let Some(mode) = WhiteBalanceMode::parse(&mode) else {
+        return Err(ApiError::bad_request(format!("Unknown white balance mode '{}'", mode))
+            .with_details(serde_json::json!({ "available": WhiteBalanceMode::names() })));
+    };
+    let published = state.pipeline.update(|s| s.set_wb_mode(mode));
+    Ok(wb_response(&state, &published))
+}
+
+/// Response of the white balance endpoints
+fn wb_response(state: &AppState, published: &SettingsSnapshot) -> axum::Json<serde_json::Value> {
+    let applied = state.capture.read().as_ref().and_then(FrameCapture::white_balance_gains);
+    axum::Json(serde_json::json!({
+        "mode": published.wb_mode.name(),
+        "fixed_gains": published.wb_mode.fixed_gains(),
+        "applied_gains": applied,
+        "enabled": published.enable_white_balance,
+        "settings_version": published.version,
+        "color_mode_only": true,
+        "success": true
+    }))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WbSmoothingParams {
+    /// Largest gain change per second, 0 = unlimited (default: unchanged)
+    max_rate: Option<f32>,
+    /// Luminance change fraction that resets the smoothing, 0 = off (default: unchanged)
+    scene_change: Option<f32>,
+}
+
+/// White balance smoothing endpoint: EMA time constant in seconds
+/// (`?max_rate=<gain/s>&scene_change=<fraction, 0 = off>`)
+#[utoipa::path(
+    get,
+    path = "/control/wb_smoothing/{time_constant}",
+    tag = "camera",
+    params(("time_constant" = f32, Path, description = "EMA time constant in seconds (0-60)"), WbSmoothingParams),
+    responses(
+        (status = 200, description = "Smoothing set", body = Object),
+        (status = 400, description = "Out of range", body = ApiError),
+    )
+)]
+async fn set_wb_smoothing_handler(
+    State(state): State<SharedState>,
+    Path(time_constant): Path<String>,
+    Query(params): Query<WbSmoothingParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let time_constant_s = match time_constant.parse::<f32>() {
+        Ok(t) if (0.0..=60.0).contains(&t) => t,
+        _ => return Err(ApiError::bad_request("Invalid time constant. Use seconds between 0 and 60")),
+    };
+    if params.max_rate.is_some_and(|r| !(r >= 0.0 && r.is_finite())) {
+        return Err(ApiError::bad_request("max_rate must be 0 (unlimited) or a positive gain change per second"));
+    }
+    if params.scene_change.is_some_and(|c| !(0.0..=10.0).contains(&c)) {
+        return Err(ApiError::bad_request("scene_change must be a luminance change fraction (0 = off)"));
+    }
+    
+    let published = state.pipeline.update(|s| {
+        let current = s.wb_smoothing;
+        s.set_wb_smoothing(WbSmoothing {
+            time_constant_s,
+            max_rate_per_s: params.max_rate.unwrap_or(current.max_rate_per_s),
+            scene_change: match params.scene_change {
+                Some(0.0) => None,
+                Some(c) => Some(c),
+                None => current.scene_change,
+            },
+        });
+    });
+    
+    Ok(axum::Json(serde_json::json!({
+        "wb_smoothing": published.wb_smoothing,
+        "settings_version": published.version,
+        "color_mode_only": true,
+        "success": true
+    })))
+}
+
+/// Color correction change; fields left out stay as they are
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct CcmRequest {
+    enabled: Option<bool>,
+    /// Rows R, G, B of the output, columns camera R, G, B
+    matrix: Option<ColorMatrix>,
+    /// `true` restores the built-in IMX415 matrix
+    #[serde(default)]
+    reset: bool,
+}
+
+/// Color correction matrix applied after white balance (color mode)
+#[utoipa::path(
+    get,
+    path = "/control/ccm",
+    tag = "camera",
+    responses(
+        (status = 200, description = "Whether correction is on, and the matrix", body = Object),
+    )
+)]
+async fn ccm_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let published = state.pipeline.load();
+    axum::Json(serde_json::json!({
+        "color_correction": published.color_correction,
+        "default_matrix": ColorMatrix::IMX415,
+        "settings_version": published.version,
+        "color_mode_only": true
+    }))
+}
+
+/// Turn color correction on or off, or replace its matrix
+#[utoipa::path(
+    post,
+    path = "/control/ccm",
+    tag = "camera",
+    request_body = CcmRequest,
+    responses(
+        (status = 200, description = "Color correction set", body = Object),
+        (status = 400, description = "Invalid matrix, or a matrix combined with `reset`", body = ApiError),
+    )
+)]
+async fn ccm_set_handler(
+    State(state): State<SharedState>,
+    request: Result<axum::Json<CcmRequest>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(request) = request?;
+    let matrix = match (request.matrix, request.reset) {
+        (Some(_), true) => return Err(ApiError::bad_request("Give either a matrix or reset, not both")),
+        (Some(matrix), false) => {
+            matrix.validate().map_err(ApiError::bad_request)?;
+            Some(matrix)
+        }
+        (None, true) => Some(ColorMatrix::IMX415),
+        (None, false) => None,
+    };
+    let published = state.pipeline.update(|s| {
+        let current = s.color_correction;
+        s.set_color_correction(ColorCorrection {
+            enabled: request.enabled.unwrap_or(current.enabled),
+            matrix: matrix.unwrap_or(current.matrix),
+        });
+    });
+    Ok(axum::Json(serde_json::json!({
+        "color_correction": published.color_correction,
+        "settings_version": published.version,
+        "color_mode_only": true,
+        "success": true
+    })))
+}
+
+/// Orientation change; fields left out stay as they are
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct OrientationRequest {
+    /// Degrees clockwise, after the flips: 0, 90, 180 or 270
+    rotation: Option<u16>,
+    hflip: Option<bool>,
+    vflip: Option<bool>,
+}
+
+/// The published orientation, and how much of it the sensor does
+fn orientation_response(state: &AppState, published: &SettingsSnapshot) -> serde_json::Value {
+    let capture = state.capture.read();
+    let sensor = capture.as_ref().map(FrameCapture::sensor_flips);
+    let output = capture.as_ref().map(|c| c.frame_size().output(published));
+    serde_json::json!({
+        "orientation": published.orientation,
+        "sensor_flips": sensor,
+        "software": sensor.map(|flips| published.orientation.needs_software(flips)),
+        "output": output,
+        "settings_version": published.version
+    })
+}
+
+/// Flips and rotation of the output; `sensor_flips` are done by the sensor
+/// readout (as of the last processed frame), the rest in software
+#[utoipa::path(
+    get,
+    path = "/control/orientation",
+    tag = "camera",
+    responses(
+        (status = 200, description = "Orientation, sensor flips and output size", body = Object),
+    )
+)]
+async fn orientation_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let published = state.pipeline.load();
+    axum::Json(orientation_response(&state, &published))
+}
+
+/// Flip or rotate the output, e.g. `{"rotation": 180}` for a camera
+/// mounted upside down
+#[utoipa::path(
+    post,
+    path = "/control/orientation",
+    tag = "camera",
+    request_body = OrientationRequest,
+    responses(
+        (status = 200, description = "Orientation set", body = Object),
+        (status = 400, description = "Rotation not a multiple of 90 degrees", body = ApiError),
+    )
+)]
+async fn orientation_set_handler(
+    State(state): State<SharedState>,
+    request: Result<axum::Json<OrientationRequest>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(request) = request?;
+    let current = state.pipeline.load().orientation;
+    let orientation = Orientation {
+        rotation: request.rotation.unwrap_or(current.rotation),
+        hflip: request.hflip.unwrap_or(current.hflip),
+        vflip: request.vflip.unwrap_or(current.vflip),
+    };
+    orientation.validate().map_err(|e| {
+        ApiError::bad_request(e).with_details(serde_json::json!({ "available": orientation::ROTATIONS }))
+    })?;
+    let published = state.pipeline.update(|s| s.set_orientation(orientation));
+    let mut response = orientation_response(&state, &published);
+    response["success"] = true.into();
+    Ok(axum::Json(response))
+}
+
+/// Digital zoom change: a window on the frame, or a factor and centre
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ZoomRequest {
+    /// Window in fractions of the oriented frame
+    crop: Option<Zoom>,
+    /// Magnification, 1 (the whole frame) to 8
+    factor: Option<f32>,
+    /// Centre of the `factor` window in fractions of the frame (default:
+    /// the centre of the current window)
+    center: Option<ZoomCenter>,
+}
+
+/// Point on the frame in fractions of its width and height
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ZoomCenter {
+    x: f32,
+    y: f32,
+}
+
+/// The published zoom window, in fractions and in pixels of the oriented
+/// frame
+fn zoom_response(state: &AppState, published: &SettingsSnapshot) -> serde_json::Value {
+    let size = state.capture.read().as_ref().map(FrameCapture::frame_size);
+    serde_json::json!({
+        "zoom": published.zoom,
+        "factor": published.zoom.factor(),
+        "crop": size.map(|size| published.zoom.crop(size.oriented(&published.orientation))),
+        "output": size.map(|size| size.output(published)),
+        "settings_version": published.version
+    })
+}
+
+/// Digital zoom window; `crop` and `output` are in pixels
+#[utoipa::path(
+    get,
+    path = "/control/zoom",
+    tag = "camera",
+    responses(
+        (status = 200, description = "Zoom window, factor and output size", body = Object),
+    )
+)]
+async fn zoom_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let published = state.pipeline.load();
+    axum::Json(zoom_response(&state, &published))
+}
+
+/// Zoom into the frame before encoding, e.g. `{"factor": 2, "center":
+/// {"x": 0.25, "y": 0.5}}` or `{"crop": {"x": 0.5, "y": 0.5, "width":
+/// 0.5, "height": 0.5}}`; `{"factor": 1}` shows the whole frame again
+#[utoipa::path(
+    post,
+    path = "/control/zoom",
+    tag = "camera",
+    request_body = ZoomRequest,
+    responses(
+        (status = 200, description = "Zoom set", body = Object),
+        (status = 400, description = "Window outside the frame, factor out of range, or neither or both given", body = ApiError),
+    )
+)]
+async fn zoom_set_handler(
+    State(state): State<SharedState>,
+    request: Result<axum::Json<ZoomRequest>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(request) = request?;
+    let zoom = match (request.crop, request.factor) {
+        (Some(crop), None) if request.center.is_none() => {
+            crop.validate().map_err(ApiError::bad_request)?;
+            crop
+        }
+        (None, Some(factor)) => {
+            let current = state.pipeline.load().zoom;
+            let center = request.center.unwrap_or(ZoomCenter {
+                x: current.x + current.width / 2.0,
+                y: current.y + current.height / 2.0,
+            });
+            Zoom::from_factor(factor, center.x, center.y).map_err(|e| {
+                ApiError::bad_request(e).with_details(serde_json::json!({ "max_factor": zoom::MAX_FACTOR }))
+            })?
+        }
+        _ => return Err(ApiError::bad_request("Give either `crop` or `factor` (with an optional `center`)")),
+    };
+    let published = state.pipeline.update(|s| s.set_zoom(zoom));
+    let mut response = zoom_response(&state, &published);
+    response["success"] = true.into();
+    Ok(axum::Json(response))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GreenBalanceParams {
+    /// Correction strength, 0.0-1.0 (default: unchanged)
+    strength: Option<f32>,
+}
+
+/// Gr/Gb balance endpoint: `off`, `auto` (estimated from flat regions) or a
+/// fixed Gr/Gb ratio, with `?strength=0.0-1.0`
+#[utoipa::path(
+    get,
+    path = "/control/gbgr/{value}",
+    tag = "camera",
+    params(("value" = String, Path, description = "`off`, `auto` or a fixed Gr/Gb ratio"), GreenBalanceParams),
+    responses(
+        (status = 200, description = "Green balance set", body = Object),
+        (status = 400, description = "Invalid value", body = ApiError),
+    )
+)]
+async fn set_green_balance_handler(
+    State(state): State<SharedState>,
+    Path(value): Path<String>,
+    Query(params): Query<GreenBalanceParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let mode = GreenBalanceMode::parse(&value).ok_or_else(|| {
+        ApiError::bad_request(format!(
+            "Invalid value. Use off, auto or a Gr/Gb ratio between {} and {}",
+            1.0 - greenbalance::MAX_IMBALANCE,
+            1.0 + greenbalance::MAX_IMBALANCE
+        ))
+    })?;
+    if params.strength.is_some_and(|s| !(0.0..=1.0).contains(&s)) {
+        return Err(ApiError::bad_request("strength must be between 0.0 and 1.0"));
+    }
+    
+    let published = state.pipeline.update(|s| {
+        s.set_green_balance(GreenBalance {
+            mode,
+            strength: params.strength.unwrap_or(s.green_balance.strength),
+        });
+    });
+    
+    Ok(axum::Json(serde_json::json!({
+        "green_balance": published.green_balance,
+        "settings_version": published.version,
+        "color_mode_only": true,
+        "success": true
+    })))
+}
+
+/// Set black level endpoint (10-bit value, or `auto` to calibrate from a dark frame)
+#[utoipa::path(
+    get,
+    path = "/control/black_level/{value}",
+    tag = "camera",
+    params(("value" = String, Path, description = "10-bit black level (0-1022), or `auto` to calibrate from a dark frame")),
+    responses(
+        (status = 200, description = "Black level set", body = Object),
+        (status = 400, description = "Invalid value", body = ApiError),
+        (status = 503, description = "Camera not available or calibration failed", body = ApiError),
+    )
+)]
+async fn set_black_level_handler(
+    State(state): State<SharedState>,
+    Path(value): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if value.eq_ignore_ascii_case("auto") {
+        // Captures a dark frame: off the runtime, like setting a control
+        let calibrate_state = state.clone();
+        let black_level = tokio::task::spawn_blocking(move || {
+            let mut capture_guard = calibrate_state.capture.write();
+            let capture = capture_guard.as_mut().ok_or_else(ApiError::camera_unavailable)?;
+            capture
+                .calibrate_black_level()
+                .map_err(|e| ApiError::unavailable(format!("Dark-frame calibration failed: {}", e)))
+        })
+        .await??;
+        return Ok(axum::Json(serde_json::json!({
+            "black_level": black_level,
+            "calibrated": true,
+            "success": true
+        })));
+    }
+    
+    match value.parse::<u16>() {
+        Ok(black_level) if black_level < 1023 => {
+            let published = state.pipeline.update(|s| s.set_black_level(black_level));
+            Ok(axum::Json(serde_json::json!({
+                "black_level": black_level,
+                "settings_version": published.version,
+                "success": true
+            })))
+        }
+        _ => Err(ApiError::bad_request(
+            "Invalid black level. Use a 10-bit value (0-1022) or 'auto'",
+        )),
+    }
+}
+
+/// Output gamma endpoint (color mode): 0.5-4.0, 2.2 for sRGB-like output
+#[utoipa::path(
+    get,
+    path = "/control/gamma/{value}",
+    tag = "camera",
+    params(("value" = f32, Path, description = "Gamma 0.5-4.0")),
+    responses(
+        (status = 200, description = "Gamma set", body = Object),
+        (status = 400, description = "Out of range", body = ApiError),
+    )
+)]
+async fn set_gamma_handler(
+    State(state): State<SharedState>,
+    Path(value): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let gamma = match value.parse::<f32>() {
+        Ok(g) if (capture::MIN_GAMMA..=capture::MAX_GAMMA).contains(&g) => g,
+        _ => {
+            return Err(ApiError::bad_request(format!(
+                "Invalid gamma. Use a value between {} and {}",
+                capture::MIN_GAMMA,
+                capture::MAX_GAMMA
+            )))
+        }
+    };
+    
+    let published = state.pipeline.update(|s| s.set_gamma(gamma));
+    
+    Ok(axum::Json(serde_json::json!({
+        "gamma": published.gamma,
+        "settings_version": published.version,
+        "color_mode_only": true,
+        "success": true
+    })))
+}
+
+/// Tone mapping endpoint (color mode): blend strength 0.0-1.0, 0 disables
+#[utoipa::path(
+    get,
+    path = "/control/tonemap/{strength}",
+    tag = "camera",
+    params(("strength" = f32, Path, description = "Blend 0.0-1.0, 0 disables")),
+    responses(
+        (status = 200, description = "Tone mapping set", body = Object),
+        (status = 400, description = "Out of range", body = ApiError),
+    )
+)]
+async fn set_tonemap_handler(
+    State(state): State<SharedState>,
+    Path(strength): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let strength = match strength.parse::<f32>() {
+        Ok(s) if (0.0..=1.0).contains(&s) => s,
+        _ => return Err(ApiError::bad_request("Invalid strength. Use a value between 0.0 and 1.0")),
+    };
+    
+    let published = state.pipeline.update(|s| s.set_tonemap_strength(strength));
+    
+    Ok(axum::Json(serde_json::json!({
+        "tonemap": strength,
+        "settings_version": published.version,
+        "color_mode_only": true,
+        "success": true
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ContrastParams {
+    /// CLAHE clip limit, 1.0-16.0 (default: unchanged)
+    clip_limit: Option<f32>,
+    /// CLAHE tiles across the frame, 2-32 (default: unchanged)
+    tiles: Option<u32>,
+}
+
+/// Contrast enhancement endpoint (grayscale modes): `off`, `global`
+/// equalization or `clahe`, with `?clip_limit=&tiles=`
+#[utoipa::path(
+    get,
+    path = "/control/contrast/{mode}",
+    tag = "camera",
+    params(("mode" = String, Path, description = "`off`, `global` or `clahe`"), ContrastParams),
+    responses(
+        (status = 200, description = "Contrast enhancement set", body = Object),
+        (status = 400, description = "Invalid mode or parameter", body = ApiError),
+    )
+)]
+async fn set_contrast_handler(
+    State(state): State<SharedState>,
+    Path(mode): Path<String>,
+    Query(params): Query<ContrastParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let mode = ContrastMode::parse(&mode)
+        .ok_or_else(|| ApiError::bad_request("Invalid mode. Use 'off', 'global' or 'clahe'"))?;
+    let current = state.pipeline.load().contrast;
+    let contrast = Contrast {
+        mode,
+        clip_limit: params.clip_limit.unwrap_or(current.clip_limit),
+        tiles: params.tiles.unwrap_or(current.tiles),
+    };
+    contrast.validate().map_err(ApiError::bad_request)?;
+    
+    let published = state.pipeline.update(|s| s.set_contrast(contrast));
+    
+    Ok(axum::Json(serde_json::json!({
+        "contrast": published.contrast,
+        "settings_version": published.version,
+        "grayscale_modes_only": true,
+        "success": true
+    })))
+}
+
+/// Tone adjustment change; fields left out keep their current value
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct ToneRequest {
+    /// Offset in 8-bit levels, -128 to 128
+    brightness: Option<i16>,
+    /// Multiplier of the distance from mid-gray, 0.0-4.0
+    contrast: Option<f32>,
+    /// Multiplier of the distance from gray, 0.0-4.0 (color mode)
+    saturation: Option<f32>,
+}
+
+/// Brightness, contrast and saturation of the output
+#[utoipa::path(
+    get,
+    path = "/control/tone",
+    tag = "camera",
+    responses(
+        (status = 200, description = "Tone adjustment", body = Object),
+    )
+)]
+async fn tone_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let published = state.pipeline.load();
+    axum::Json(serde_json::json!({
+        "tone": published.tone,
+        "settings_version": published.version
+    }))
+}
+
+/// Adjust brightness, contrast or saturation, e.g. `{"brightness": 10,
+/// "saturation": 1.2}`; `{"brightness": 0, "contrast": 1, "saturation":
+/// 1}` leaves the output unchanged
+#[utoipa::path(
+    post,
+    path = "/control/tone",
+    tag = "camera",
+    request_body = ToneRequest,
+    responses(
+        (status = 200, description = "Tone adjustment set", body = Object),
+        (status = 400, description = "Value out of range", body = ApiError),
+    )
+)]
+async fn tone_set_handler(
+    State(state): State<SharedState>,
+    request: Result<axum::Json<ToneRequest>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(request) = request?;
+    let current = state.pipeline.load().tone;
+    let tone = Tone {
+        brightness: request.brightness.unwrap_or(current.brightness),
+        contrast: request.contrast.unwrap_or(current.contrast),
+        saturation: request.saturation.unwrap_or(current.saturation),
+    };
+    tone.validate().map_err(ApiError::bad_request)?;
+    
+    let published = state.pipeline.update(|s| s.set_tone(tone));
+    
+    Ok(axum::Json(serde_json::json!({
+        "tone": published.tone,
+        "settings_version": published.version,
+        "success": true
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DenoiseParams {
+    /// Motion threshold in 8-bit levels, 1-128 (default: unchanged)
+    threshold: Option<u8>,
+}
+
+/// Temporal denoise endpoint: strength 0.0-0.95 (0 disables), with
+/// `?threshold=` for the difference treated as motion
+#[utoipa::path(
+    get,
+    path = "/control/denoise/{strength}",
+    tag = "camera",
+    params(("strength" = f32, Path, description = "Weight of the running average, 0.0-0.95, 0 disables"), DenoiseParams),
+    responses(
+        (status = 200, description = "Temporal denoise set", body = Object),
+        (status = 400, description = "Out of range", body = ApiError),
+    )
+)]
+async fn set_denoise_handler(
+    State(state): State<SharedState>,
+    Path(strength): Path<String>,
+    Query(params): Query<DenoiseParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let strength = strength
+        .parse::<f32>()
+        .map_err(|_| ApiError::bad_request("Invalid strength. Use a value between 0.0 and 0.95"))?;
+    let denoise = Denoise {
+        strength,
+        threshold: params.threshold.unwrap_or(state.pipeline.load().denoise.threshold),
+    };
+    denoise.validate().map_err(ApiError::bad_request)?;
+    
+    let published = state.pipeline.update(|s| s.set_denoise(denoise));
+    
+    Ok(axum::Json(serde_json::json!({
+        "denoise": published.denoise,
+        "settings_version": published.version,
+        "success": true
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SpatialDenoiseParams {
+    /// Filter strength, 0.0-1.0 (default: unchanged)
+    strength: Option<f32>,
+}
+
+/// Spatial denoise endpoint: `off`, `median` or `bilateral`, with
+/// `?strength=0.0-1.0`
+#[utoipa::path(
+    get,
+    path = "/control/denoise/spatial/{method}",
+    tag = "camera",
+    params(("method" = String, Path, description = "`off`, `median` or `bilateral`"), SpatialDenoiseParams),
+    responses(
+        (status = 200, description = "Spatial denoise set", body = Object),
+        (status = 400, description = "Invalid method or strength", body = ApiError),
+    )
+)]
+async fn set_spatial_denoise_handler(
+    State(state): State<SharedState>,
+    Path(method): Path<String>,
+    Query(params): Query<SpatialDenoiseParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let method = SpatialMethod::parse(&method)
+        .ok_or_else(|| ApiError::bad_request("Invalid method. Use 'off', 'median' or 'bilateral'"))?;
+    let spatial = SpatialDenoise {
+        method,
+        strength: params.strength.unwrap_or(state.pipeline.load().spatial_denoise.strength),
+    };
+    spatial.validate().map_err(ApiError::bad_request)?;
+    
+    let published = state.pipeline.update(|s| s.set_spatial_denoise(spatial));
+    
+    Ok(axum::Json(serde_json::json!({
+        "spatial_denoise": published.spatial_denoise,
+        "settings_version": published.version,
+        "success": true
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SharpenParams {
+    /// Blur radius in pixels, 1-4 (default: unchanged)
+    radius: Option<u8>,
+    /// Differences left alone as noise, in 8-bit levels (default: unchanged)
+    threshold: Option<u8>,
+}
+
+/// Sharpening endpoint: unsharp-mask amount 0.0-4.0 (0 disables), with
+/// `?radius=&threshold=`
+#[utoipa::path(
+    get,
+    path = "/control/sharpen/{amount}",
+    tag = "camera",
+    params(("amount" = f32, Path, description = "Share of the detail added back, 0.0-4.0, 0 disables"), SharpenParams),
+    responses(
+        (status = 200, description = "Sharpening set", body = Object),
+        (status = 400, description = "Out of range", body = ApiError),
+    )
+)]
+async fn set_sharpen_handler(
+    State(state): State<SharedState>,
+    Path(amount): Path<String>,
+    Query(params): Query<SharpenParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let amount = amount
+        .parse::<f32>()
+        .map_err(|_| ApiError::bad_request("Invalid amount. Use a value between 0.0 and 4.0"))?;
+    let current = state.pipeline.load().sharpen;
+    let sharpen = sharpen::Sharpen {
+        amount,
+        radius: params.radius.unwrap_or(current.radius),
+        threshold: params.threshold.unwrap_or(current.threshold),
+    };
+    sharpen.validate().map_err(ApiError::bad_request)?;
+    
+    let published = state.pipeline.update(|s| s.set_sharpen(sharpen));
+    
+    Ok(axum::Json(serde_json::json!({
+        "sharpen": published.sharpen,
+        "settings_version": published.version,
+        "success": true
+    })))
+}
+
+/// Sensor test pattern endpoint: menu label or index from the driver, or `off`
+#[utoipa::path(
+    get,
+    path = "/control/test_pattern/{mode}",
+    tag = "camera",
+    params(("mode" = String, Path, description = "Menu label or index from the driver, or `off`")),
+    responses(
+        (status = 200, description = "Test pattern set", body = Object),
+        (status = 400, description = "Unknown pattern (details list the available ones)", body = ApiError),
+        (status = 503, description = "Camera or control not available", body = ApiError),
+    )
+)]
+async fn set_test_pattern_handler(
+    State(state): State<SharedState>,
+    Path(mode): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let subdev = {
+        let capture = state.capture.read();
+        capture.as_ref().ok_or_else(ApiError::camera_unavailable)?.config().sensor_subdev.clone()
+    };
+    
+    // v4l2-ctl runs off the runtime, as for /controls
+    let find_subdev = subdev.clone();
+    let control = tokio::task::spawn_blocking(move || controls::find_control(&find_subdev, "test_pattern"))
+        .await?
+        .map_err(|e| ApiError::unavailable(format!("{:#}", e)))?;
+    let labels: Vec<&str> = control.menu.iter().map(|e| e.label.as_str()).collect();
+    
+    // Menu index 0 is "Disabled" on every sensor driver we've seen
+    let entry = if mode.eq_ignore_ascii_case("off") {
+        control.menu.iter().find(|e| e.index == 0)
+    } else {
+        control.find_menu_entry(&mode)
+    };
+    let Some(entry) = entry else {
+        return Err(ApiError::bad_request(format!("Unknown test pattern '{}'", mode))
+            .with_details(serde_json::json!({ "available": labels })));
+    };
+    
+    let entry = entry.clone();
+    let set_state = state.clone();
+    let set_entry = entry.clone();
+    tokio::task::spawn_blocking(move || {
+        // Held while setting, so the next frame is the first with the pattern
+        let mut capture_guard = set_state.capture.write();
+        let capture = capture_guard.as_mut().ok_or_else(ApiError::camera_unavailable)?;
+        controls::set_control(&subdev, "test_pattern", set_entry.index)?;
+        test_pattern_changed(&set_state, capture, &set_entry);
+        Ok::<_, ApiError>(())
+    })
+    .await??;
+    
+    let active = entry.index != 0;
+    Ok(axum::Json(serde_json::json!({
+        "test_pattern": if active { Some(&entry.label) } else { None },
+        "index": entry.index,
+        "available": labels,
+        "success": true
+    })))
+}
+
+/// Tell the pipeline the sensor now shows test pattern `entry` (index 0: none)
+fn test_pattern_changed(state: &AppState, capture: &mut FrameCapture, entry: &controls::MenuEntry) {
+    let active = entry.index != 0;
+    capture.set_test_pattern_active(active);
+    *state.test_pattern.write() = active.then(|| entry.label.clone());
+    info!("Sensor test pattern: {} ({})", entry.label, entry.index);
+}
+
+/// Sensor subdevice of a camera that has one
+fn sensor_subdev(state: &AppState) -> ApiResult<String> {
+    let capture = state.capture.read();
+    let capture = capture.as_ref().ok_or_else(ApiError::camera_unavailable)?;
+    if !capture.config().source.has_sensor() {
+        return Err(ApiError::unavailable("The frame source has no sensor controls"));
+    }
+    Ok(capture.config().sensor_subdev.clone())
+}
+
+/// Why a sensor control can't be set through `/controls` right now (None
+/// if it can): the pipeline drives it itself
+fn managed_control(state: &AppState, name: &str) -> Option<String> {
+    match name {
+        "horizontal_flip" | "vertical_flip" => {
+            Some("The sensor flips follow the orientation; use /control/orientation".to_string())
+        }
+        "exposure" | "analogue_gain" if state.pipeline.load().auto_exposure.is_some() => {
+            Some("Auto-exposure sets exposure and gain; turn it off with /control/auto_exposure/off".to_string())
+        }
+        _ => None,
+    }
+}
+
+/// All V4L2 controls of the sensor subdevice, with ranges, current values
+/// and menu entries
+#[utoipa::path(
+    get,
+    path = "/controls",
+    tag = "camera",
+    responses(
+        (status = 200, description = "The subdevice and its controls", body = Object),
+        (status = 503, description = "Camera or controls not available", body = ApiError),
+    )
+)]
+async fn controls_list_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let subdev = sensor_subdev(&state)?;
+    let device = subdev.clone();
+    let controls = tokio::task::spawn_blocking(move || controls::list_controls(&device))
+        .await?
+        .map_err(|e| ApiError::unavailable(format!("{:#}", e)))?;
+    Ok(axum::Json(serde_json::json!({
+        "device": subdev,
+        "controls": controls
+    })))
+}
+
+/// The sensor control `name`, looked up on the subdevice
+async fn find_sensor_control(state: &AppState, name: &str) -> ApiResult<(String, controls::Control)> {
+    let subdev = sensor_subdev(state)?;
+    let (device, wanted) = (subdev.clone(), name.to_string());
+    let available = tokio::task::spawn_blocking(move || controls::list_controls(&device))
+        .await?
+        .map_err(|e| ApiError::unavailable(format!("{:#}", e)))?;
+    let control = available
+        .into_iter()
+        .find(|c| c.name == wanted)
+        .ok_or_else(|| ApiError::not_found(format!("No control '{}' on {}", wanted, subdev)))?;
+    Ok((subdev, control))
+}
+
+/// One sensor control
+#[utoipa::path(
+    get,
+    path = "/controls/{name}",
+    tag = "camera",
+    params(("name" = String, Path, description = "Control name as listed by `/controls`")),
+    responses(
+        (status = 200, description = "The control", body = Object),
+        (status = 404, description = "No such control", body = ApiError),
+        (status = 503, description = "Camera or controls not available", body = ApiError),
+    )
+)]
+async fn control_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<controls::Control>> {
+    let (_, control) = find_sensor_control(&state, &name).await?;
+    Ok(axum::Json(control))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ControlParams {
+    /// A number; a menu index or label for menu controls; `on`/`off` for booleans
+    value: String,
+}
+
+/// Set a sensor control (`?value=`). Controls the pipeline drives itself
+/// (the flips, and exposure and gain under auto-exposure) are refused;
+/// `test_pattern` is taken over like `/control/test_pattern`.
+#[utoipa::path(
+    post,
+    path = "/controls/{name}",
+    tag = "camera",
+    params(("name" = String, Path, description = "Control name as listed by `/controls`"), ControlParams),
+    responses(
+        (status = 200, description = "Control set; the control as read back", body = Object),
+        (status = 400, description = "Invalid or out-of-range value, or a read-only control", body = ApiError),
+        (status = 404, description = "No such control", body = ApiError),
+        (status = 409, description = "The pipeline drives this control", body = ApiError),
+        (status = 503, description = "Camera or controls not available", body = ApiError),
+    )
+)]
+async fn control_set_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    Query(params): Query<ControlParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let (subdev, control) = find_sensor_control(&state, &name).await?;
+    if control.is_read_only() {
+        return Err(ApiError::bad_request(format!("{} is read-only", name)));
+    }
+    if let Some(reason) = managed_control(&state, &name) {
+        return Err(ApiError::conflict(reason));
+    }
+    let value = control.parse_value(&params.value).map_err(|e| {
+        let error = ApiError::bad_request(e);
+        if control.menu.is_empty() {
+            error
+        } else {
+            error.with_details(serde_json::json!({ "menu": control.menu }))
+        }
+    })?;
+    
+    let set_state = state.clone();
+    let control = tokio::task::spawn_blocking(move || {
+        // Held while setting, so the next frame is the first with the value
+        let mut capture_guard = set_state.capture.write();
+        controls::set_control(&subdev, &control.name, value)?;
+        if control.name == "test_pattern" {
+            if let (Some(capture), Some(entry)) = (capture_guard.as_mut(), control.find_menu_entry(&value.to_string())) {
+                test_pattern_changed(&set_state, capture, entry);
+            }
+        }
+        drop(capture_guard);
+        controls::find_control(&subdev, &control.name)
+    })
+    .await??;
+    info!("Sensor control {} = {}", name, value);
+    
+    Ok(axum::Json(serde_json::json!({
+        "control": control,
+        "success": true
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MeteringParams {
+    /// Spot region left edge, fraction of the frame width
+    x: Option<f32>,
+    /// Spot region top edge, fraction of the frame height
+    y: Option<f32>,
+    /// Spot region width, fraction of the frame width
+    w: Option<f32>,
+    /// Spot region height, fraction of the frame height
+    h: Option<f32>,
+    /// `on` outlines the metered region on the stream
+    overlay: Option<String>,
+}
+
+/// Exposure metering endpoint: `average`, `center` or `spot?x=&y=&w=&h=`
+/// (region as fractions of the streamed frame), the part of the frame
+/// auto-exposure meters; `?overlay=on` outlines the region
+#[utoipa::path(
+    get,
+    path = "/control/metering/{mode}",
+    tag = "camera",
+    params(("mode" = String, Path, description = "`average`, `center` or `spot`"), MeteringParams),
+    responses(
+        (status = 200, description = "Metering set", body = Object),
+        (status = 400, description = "Invalid mode or region", body = ApiError),
+    )
+)]
+async fn set_metering_handler(
+    State(state): State<SharedState>,
+    Path(mode): Path<String>,
+    Query(params): Query<MeteringParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let mode = MeteringMode::parse(&mode)
+        .ok_or_else(|| ApiError::bad_request("Invalid metering mode. Use 'average', 'center' or 'spot'"))?;
+    let overlay = params
+        .overlay
+        .as_deref()
+        .map(|overlay| require_on_off(overlay, "overlay value"))
+        .transpose()?;
+    
+    // A new region, or the current one
+    let roi = match (params.x, params.y, params.w, params.h) {
+        (None, None, None, None) => None,
+        (Some(x), Some(y), Some(w), Some(h)) => Some(Roi::new(x, y, w, h).map_err(ApiError::bad_request)?),
+        _ => return Err(ApiError::bad_request("A region needs all of x, y, w and h")),
+    };
+    let published = state.pipeline.update(|s| {
+        s.set_metering(MeteringConfig {
+            mode,
+            roi: roi.or(s.metering.roi),
+            overlay: overlay.unwrap_or(s.metering.overlay),
+        })
+    });
+    
+    Ok(axum::Json(serde_json::json!({
+        "metering": published.metering,
+        "settings_version": published.version,
+        "success": true
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AutoExposureParams {
+    /// Target mean luminance, 1-254 (default 110)
+    target: Option<f32>,
+    /// Highest analogue gain in dB, 0-72 (default 30)
+    max_gain_db: Option<f32>,
+    /// Fraction of the error corrected per adjustment, 0.01-1 (default 0.5)
+    speed: Option<f32>,
+    /// Keep exposure times to whole periods of 50 or 60 Hz lighting
+    /// flicker: `50`, `60` or `off` (default off)
+    anti_flicker: Option<String>,
+}
+
+/// Software auto-exposure endpoint: `on?target=110&max_gain_db=30&speed=0.5&anti_flicker=50`
+/// or `off` (exposure and gain stay where auto-exposure left them)
+#[utoipa::path(
+    get,
+    path = "/control/auto_exposure/{mode}",
+    tag = "camera",
+    params(("mode" = String, Path, description = "`on` or `off`"), AutoExposureParams),
+    responses(
+        (status = 200, description = "Auto-exposure set", body = Object),
+        (status = 400, description = "Invalid mode or limits", body = ApiError),
+    )
+)]
+async fn set_auto_exposure_handler(
+    State(state): State<SharedState>,
+    Path(mode): Path<String>,
+    Query(params): Query<AutoExposureParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if require_on_off(&mode, "auto-exposure mode")? {
+        let current = state.pipeline.load().auto_exposure.clone();
+        let target = params.target.or(current.as_ref().map(|ae| ae.target)).unwrap_or(AutoExposure::DEFAULT_TARGET);
+        let max_gain_db = params
+            .max_gain_db
+            .or(current.as_ref().map(|ae| ae.max_gain_db))
+            .unwrap_or(AutoExposure::DEFAULT_MAX_GAIN_DB);
+        let speed = params.speed.or(current.as_ref().map(|ae| ae.speed)).unwrap_or(AutoExposure::DEFAULT_SPEED);
+        let anti_flicker = match params.anti_flicker {
+            Some(ref name) => AntiFlicker::parse(name)
+                .ok_or_else(|| ApiError::bad_request("Anti-flicker must be '50', '60' or 'off'"))?,
+            None => current.as_ref().map(|ae| ae.anti_flicker).unwrap_or_default(),
+        };
+        if !(1.0..=254.0).contains(&target) {
+            return Err(ApiError::bad_request("Target must be between 1 and 254"));
+        }
+        if !(0.0..=72.0).contains(&max_gain_db) {
+            return Err(ApiError::bad_request("Max gain must be between 0 and 72 dB"));
+        }
+        if !(0.01..=1.0).contains(&speed) {
+            return Err(ApiError::bad_request("Speed must be between 0.01 and 1.0"));
+        }
+        let ae = AutoExposure::new(target, max_gain_db, speed, anti_flicker);
+        state.pipeline.update(|s| s.set_auto_exposure(Some(ae)));
+    } else {
+        state.pipeline.update(|s| s.set_auto_exposure(None));
+    }
+    
+    Ok(axum::Json(serde_json::json!({
+        "auto_exposure": auto_exposure_status_json(&state),
+        "success": true
+    })))
+}
+
+/// Auto-exposure settings and controller state as JSON (null while off)
+fn auto_exposure_status_json(state: &AppState) -> serde_json::Value {
+    let settings = state.pipeline.load();
+    let Some(ref ae) = settings.auto_exposure else {
+        return serde_json::Value::Null;
+    };
+    // The pipeline's controller, once it has caught up with these settings
+    let status = state
+        .capture
+        .read()
+        .as_ref()
+        .filter(|c| c.applied_version() == settings.version)
+        .and_then(FrameCapture::auto_exposure_status)
+        .unwrap_or_else(|| ae.status());
+    serde_json::json!(status)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AutoQualityParams {
+    /// Target frame size in KiB
+    target_kb: Option<usize>,
+    /// Target encode time in milliseconds
+    target_ms: Option<f32>,
+    /// Lowest quality (default 40)
+    min: Option<u8>,
+    /// Highest quality (default: the fixed quality)
+    max: Option<u8>,
+}
+
+/// Quality control endpoint: a fixed quality (`1`-`100`, which also ends
+/// auto quality), `auto?target_kb=600&target_ms=&min=&max=` or `fixed`
+#[utoipa::path(
+    get,
+    path = "/control/quality/{value}",
+    tag = "camera",
+    params(("value" = String, Path, description = "Quality 1-100, `auto` or `fixed`"), AutoQualityParams),
+    responses(
+        (status = 200, description = "Quality set", body = Object),
+        (status = 400, description = "Invalid quality or mode, or auto without a target", body = ApiError),
+    )
+)]
+async fn set_quality_handler(
+    State(state): State<SharedState>,
+    Path(value): Path<String>,
+    Query(params): Query<AutoQualityParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    match value.to_lowercase().as_str() {
+        "auto" => {
+            if params.target_kb.is_none() && params.target_ms.is_none() {
+                return Err(ApiError::bad_request("Auto quality needs target_kb and/or target_ms"));
+            }
+            state.pipeline.update(|s| {
+                let max = params.max.unwrap_or(s.jpeg_quality);
+                let min = params.min.unwrap_or(40).min(max);
+                s.set_adaptive_quality(Some(AdaptiveQuality::new(
+                    params.target_kb.map(|kb| kb * 1024),
+                    params.target_ms,
+                    min,
+                    max,
+                )));
+            });
+        }
+        "fixed" | "off" => {
+            state.pipeline.update(|s| s.set_adaptive_quality(None));
+        }
+        other => match other.parse::<u8>() {
+            Ok(quality) if (1..=100).contains(&quality) => {
+                state.pipeline.update(|s| {
+                    s.set_jpeg_quality(quality);
+                    s.set_adaptive_quality(None);
+                });
+            }
+            _ => return Err(ApiError::bad_request("Invalid quality. Use 1-100, 'auto' or 'fixed'")),
+        },
+    }
+    
+    Ok(axum::Json(serde_json::json!({
+        "quality": quality_status_json(&state),
+        "success": true
+    })))
+}
+
+/// Quality mode and effective quality as JSON
+fn quality_status_json(state: &AppState) -> serde_json::Value {
+    let settings = state.pipeline.load();
+    // The pipeline's controller, once it has caught up with these settings
+    let effective = state
+        .capture
+        .read()
+        .as_ref()
+        .filter(|c| c.applied_version() == settings.version)
+        .map(FrameCapture::effective_quality);
+    match &settings.adaptive_quality {
+        Some(adaptive) => serde_json::json!({
+            "mode": "auto",
+            "effective": effective.unwrap_or_else(|| adaptive.quality()),
+            "min": adaptive.min_quality,
+            "max": adaptive.max_quality,
+            "target_kb": adaptive.target_bytes.map(|b| b / 1024),
+            "target_ms": adaptive.target_encode_ms,
+        }),
+        None => serde_json::json!({
+            "mode": "fixed",
+            "effective": settings.jpeg_quality,
+        }),
+    }
+}
+
+/// Toggle detection endpoint; `on` first runs a warm-up inference and
+/// fails if the detector doesn't answer it properly
+#[utoipa::path(
+    get,
+    path = "/detect/{enabled}",
+    tag = "detection",
+    params(("enabled" = String, Path, description = "`on` or `off`")),
+    responses(
+        (status = 200, description = "Detection switched", body = Object),
+        (status = 400, description = "Not on/off", body = ApiError),
+        (status = 503, description = "Detector not available or failed its warm-up", body = ApiError),
+    )
+)]
+async fn set_detection_handler(
+    State(state): State<SharedState>,
+    Path(enabled): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let enable = require_on_off(&enabled, "value")?;
+    
+    // Only report detection as on once the backend has answered a warm-up
+    let mut warm_up_ms = None;
+    if enable {
+        match warm_up_detector(&state).await {
+            Ok(latency) => warm_up_ms = Some(latency.as_secs_f64() * 1000.0),
+            Err(e) => {
+                tracing::warn!("Detector warm-up failed: {}", e);
+                return Err(ApiError::unavailable(format!("Detector warm-up failed: {}", e)));
+            }
+        }
+    }
+    
+    set_detection_enabled(&state, enable);
+    state.scheduler.write().manual_change(Feature::Detection, enable, chrono::Utc::now());
+    tracing::info!("Detection {}!", if enable { "ENABLED" } else { "DISABLED" });
+    
+    Ok(axum::Json(serde_json::json!({
+        "detection_enabled": enable,
+        "warm_up_ms": warm_up_ms,
+        "success": true
+    })))
+}
+
+/// Run a warm-up inference; the latency, or why the detector can't run
+async fn warm_up_detector(state: &SharedState) -> Result<Duration, String> {
+    // Waited on without the detector lock, as in `detect_once_handler`
+    let Some(detector) = state.detector.read().as_ref().map(YoloDetector::handle) else {
+        return Err(state
+            .detector_supervisor
+            .status()
+            .last_error
+            .unwrap_or_else(|| "YOLO detector not available (RKNN runtime not installed)".to_string()));
+    };
+    tokio::task::spawn_blocking(move || detector.warm_up(detector::WARM_UP_TIMEOUT).map_err(|e| format!("{:#}", e)))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Detection cadence endpoint: feed every Nth frame, or `0` for on-demand only
+#[utoipa::path(
+    get,
+    path = "/detect/interval/{n}",
+    tag = "detection",
+    params(("n" = u32, Path, description = "Feed every Nth frame to the detector, 0 = on demand only")),
+    responses(
+        (status = 200, description = "Cadence set", body = Object),
+        (status = 400, description = "Not a number", body = ApiError),
+    )
+)]
+async fn set_detection_interval_handler(
+    State(state): State<SharedState>,
+    Path(n): Path<u32>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    *state.detection_interval.write() = n;
+    tracing::info!(
+        "Detection cadence: {}",
+        if n == 0 { "on demand".to_string() } else { format!("every {} frame(s)", n) }
+    );
+    
+    Ok(axum::Json(serde_json::json!({
+        "interval": n,
+        "on_demand": n == 0,
+        "success": true
+    })))
+}
+
+/// Run a single detection on the current frame and wait for the result
+#[utoipa::path(
+    post,
+    path = "/detect/once",
+    tag = "detection",
+    responses(
+        (status = 200, description = "Detections on the current frame", body = Object),
+        (status = 503, description = "No frame, detector not available or detection failed", body = ApiError),
+    )
+)]
+async fn detect_once_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let jpeg = state.clean_frame.read().clone().ok_or_else(ApiError::no_frame)?;
+    let frame_seq = *state.frame_count.read();
+    
+    // Waited on without the detector lock, which a restart needs
+    let detector = state.detector.read().as_ref().map(YoloDetector::handle);
+    let detector = detector.ok_or_else(|| ApiError::unavailable("YOLO detector not available"))?;
+    let result = tokio::task::spawn_blocking(move || {
+        detector
+            .detect_blocking(frame_seq, jpeg.to_vec(), DETECT_ONCE_TIMEOUT)
+            .map_err(|e| ApiError::unavailable(format!("{:#}", e)))
+    })
+    .await??;
+    
+    record_detections(&state, result.clone());
+    if let Some(error) = result.error {
+        return Err(ApiError::unavailable(format!("Detection failed: {}", error))
+            .with_details(serde_json::json!({ "frame_seq": result.frame_seq })));
+    }
+    Ok(axum::Json(serde_json::json!({
+        "detections": result.detections,
+        "count": result.detections.len(),
+        "frame_seq": result.frame_seq,
+        "success": true
+    })))
+}
+
+/// Detector queue statistics as JSON (drop count and lag behind the live frame)
+fn detector_stats_json(state: &AppState) -> serde_json::Value {
+    let current_seq = *state.frame_count.read();
+    match *state.detector.read() {
+        Some(ref detector) => {
+            let stats = detector.stats();
+            serde_json::json!({
+                "submitted": stats.submitted,
+                "dropped": stats.dropped,
+                "processed": stats.processed,
+                "last_processed_seq": stats.last_processed_seq,
+                "current_seq": current_seq,
+                "lag_frames": current_seq.saturating_sub(stats.last_processed_seq),
+                "detections_per_sec": stats.rate_per_sec,
+                "last_inference_ms": stats.last_inference_ms,
+                "task": state.detector_config.read().task,
+            })
+        }
+        None => serde_json::Value::Null,
+    }
+}
+
+/// Detector liveness: whether the backend is running, automatic restarts
+/// and when it last finished an inference
+#[utoipa::path(
+    get,
+    path = "/detector/health",
+    tag = "detection",
+    responses(
+        (status = 200, description = "Detector state, restarts and last inference", body = Object),
+    )
+)]
+async fn detector_health_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let status = subsystem_statuses(&state)
+        .into_iter()
+        .find(|s| s.name == "detector");
+    let (alive, backend, last_inference_ms, since_last_inference_secs) = match *state.detector.read() {
+        Some(ref detector) => (
+            detector.is_running(),
+            Some(detector.backend().name()),
+            Some(detector.stats().last_inference_ms),
+            detector.since_last_inference().map(|d| d.as_secs_f64()),
+        ),
+        None => (false, None, None, None),
+    };
+    axum::Json(serde_json::json!({
+        "alive": alive,
+        "backend": backend,
+        "detection_enabled": *state.detection_enabled.read(),
+        "state": status.as_ref().map(|s| s.state),
+        "restarts": status.as_ref().map_or(0, |s| s.restarts),
+        "restart_in_secs": status.as_ref().and_then(|s| s.restart_in_secs),
+        "last_error": status.as_ref().and_then(|s| s.last_error.clone()),
+        "uptime_secs": status.as_ref().and_then(|s| s.uptime_secs),
+        "last_inference_ms": last_inference_ms,
+        "since_last_inference_secs": since_last_inference_secs,
+    }))
+}
+
+/// Get current detections endpoint
+#[utoipa::path(
+    get,
+    path = "/detections",
+    tag = "detection",
+    responses(
+        (status = 200, description = "Latest detections, cadence and detector queue", body = Object),
+    )
+)]
+async fn detections_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let detections = state.last_detections.read().clone();
+    let enabled = *state.detection_enabled.read();
+    let interval = *state.detection_interval.read();
+    // The detector reports the size of the frame it ran on
+    let input_resolution = match (detections.width, detections.height) {
+        (Some(w), Some(h)) => Some(format!("{}x{}", w, h)),
+        _ => None,
+    };
+    
+    axum::Json(serde_json::json!({
+        "enabled": enabled,
+        "detections": detections.detections,
+        "count": detections.detections.len(),
+        "frame_seq": detections.frame_seq,
+        "source": if interval == 0 { "on-demand" } else { "stream" },
+        "cadence": {
+            "interval": interval,
+            "effective_interval": *state.effective_detection_interval.read(),
+            "on_demand": interval == 0,
+        },
+        "input_resolution": input_resolution,
+        "queue": detector_stats_json(&state)
+    }))
+}
+
+/// Frames in the history ring and sequence numbers with detection records
+#[utoipa::path(
+    get,
+    path = "/history",
+    tag = "detection",
+    responses(
+        (status = 200, description = "Frames in the history ring and those with detection records", body = Object),
+    )
+)]
+async fn history_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let history = state.history.read();
+    axum::Json(serde_json::json!({
+        "frames": history.entries(),
+        "detection_seqs": history.detection_seqs()
+    }))
+}
+
+/// Detections recorded for a specific frame sequence number
+#[utoipa::path(
+    get,
+    path = "/history/{seq}/detections",
+    tag = "detection",
+    params(("seq" = u64, Path, description = "Frame sequence number")),
+    responses(
+        (status = 200, description = "Detection result recorded for the frame", body = DetectionResult),
+        (status = 404, description = "No record for the frame", body = ApiError),
+    )
+)]
+async fn history_detections_handler(
+    State(state): State<SharedState>,
+    Path(seq): Path<u64>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let history = state.history.read();
+    let frame_available = history.frame(seq).is_some();
+    let result = history.detections(seq).ok_or_else(|| {
+        ApiError::not_found(format!("No detections recorded for frame {}", seq))
+            .with_details(serde_json::json!({ "frame_evicted": !frame_available }))
+    })?;
+    Ok(axum::Json(serde_json::json!({
+        "seq": seq,
+        "detections": result.detections,
+        "count": result.detections.len(),
+        "frame_evicted": !frame_available,
+        "annotated_url": frame_available.then(|| format!("/history/{}/annotated.jpg", seq))
+    })))
+}
+
+/// A history frame with that frame's own detections drawn on it
+#[utoipa::path(
+    get,
+    path = "/history/{seq}/annotated.jpg",
+    tag = "detection",
+    params(("seq" = u64, Path, description = "Frame sequence number")),
+    responses(
+        (status = 200, description = "History frame with its own detections drawn", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 404, description = "Frame no longer in the history", body = ApiError),
+        (status = 500, description = "Drawing failed", body = ApiError),
+    )
+)]
+async fn history_annotated_handler(
+    State(state): State<SharedState>,
+    Path(seq): Path<u64>,
+) -> ApiResult<Response> {
+    let (frame, detections) = {
+        let history = state.history.read();
+        (
+            history.frame(seq).map(|f| f.jpeg.clone()),
+            history.detections(seq).map(|r| r.detections.clone()),
+        )
+    };
+    let frame = frame.ok_or_else(|| ApiError::not_found(format!("Frame {} not in history", seq)))?;
+    
+    let detections = detections.unwrap_or_default();
+    if detections.is_empty() {
+        return Ok(jpeg_response(frame));
+    }
+    let style = state.annotation.read().style;
+    let pixels = state
+        .decode_cache
+        .get(framehash::hash(&frame), frame)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to decode frame {}: {:#}", seq, e)))?;
+    let jpeg = tokio::task::spawn_blocking(move || detector::annotate_image(&pixels, &detections, &style))
+        .await?
+        .map_err(|e| ApiError::internal(format!("Failed to annotate frame {}: {}", seq, e)))?;
+    Ok(jpeg_response(jpeg))
+}
+
+/// Replay speed limits (multiples of the original timing)
+const MIN_REPLAY_SPEED: f32 = 0.1;
+const MAX_REPLAY_SPEED: f32 = 16.0;
+
+/// Longest pause between replayed frames, before scaling by the speed;
+/// longer gaps in the history (capture stalls, restarts) are shortened to it
+const MAX_REPLAY_GAP_MS: u64 = 2000;
+
+/// `?from_seq=&to_seq=&speed=`
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReplayParams {
+    /// First frame (default: the oldest in the history)
+    from_seq: Option<u64>,
+    /// Last frame (default: the newest in the history)
+    to_seq: Option<u64>,
+    /// Playback speed relative to the original timing (0.1-16)
+    #[serde(default = "ReplayParams::default_speed")]
+    speed: f32,
+}
+
+impl ReplayParams {
+    fn default_speed() -> f32 { 1.0 }
+}
+
+/// Replay a range of the frame history as an MJPEG stream, paced by the
+/// frames' capture timestamps, ending after the last frame
+///
+/// The frames still in the ring when the request arrives are replayed;
+/// evicted ones in the range are skipped.
+#[utoipa::path(
+    get,
+    path = "/history/replay",
+    tag = "stream",
+    params(ReplayParams),
+    responses(
+        (status = 200, description = "Finite MJPEG stream of the range (`multipart/x-mixed-replace`), closed with the final boundary", body = openapi::Binary, content_type = "multipart/x-mixed-replace"),
+        (status = 400, description = "Invalid range or speed", body = ApiError),
+        (status = 404, description = "No frames of the range left in the history", body = ApiError),
+        (status = 503, description = "The bandwidth budget refuses new streams", body = ApiError),
+    )
+)]
+async fn history_replay_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<ReplayParams>,
+) -> ApiResult<Response> {
+    if !(MIN_REPLAY_SPEED..=MAX_REPLAY_SPEED).contains(&params.speed) {
+        return Err(ApiError::bad_request(format!(
+            "speed must be between {} and {}",
+            MIN_REPLAY_SPEED, MAX_REPLAY_SPEED
+        )));
+    }
+    if let (Some(from), Some(to)) = (params.from_seq, params.to_seq) {
+        if from > to {
+            return Err(ApiError::bad_request("from_seq is after to_seq"));
+        }
+    }
+    let (frames, available) = {
+        let history = state.history.read();
+        (history.frames_between(params.from_seq, params.to_seq), history.seq_range())
+    };
+    if frames.is_empty() {
+        return Err(ApiError::not_found("No frames of the range are in the history").with_details(
+            serde_json::json!({
+                "oldest_seq": available.map(|(oldest, _)| oldest),
+                "newest_seq": available.map(|(_, newest)| newest),
+            }),
+        ));
+    }
+    admit_stream(&state)?;
+    
+    let speed = params.speed;
+    let mut previous_ms: Option<u64> = None;
+    let frames = futures::stream::iter(frames).then(move |frame| {
+        let gap_ms = previous_ms.map_or(0, |p| frame.timestamp_ms.saturating_sub(p).min(MAX_REPLAY_GAP_MS));
+        previous_ms = Some(frame.timestamp_ms);
+        async move {
+            if gap_ms > 0 {
+                tokio::time::sleep(Duration::from_secs_f32(gap_ms as f32 / 1000.0 / speed)).await;
+            }
+            PushFrame {
+                seq: frame.seq,
+                timestamp_ms: frame.timestamp_ms,
+                hash: framehash::hash(&frame.jpeg),
+                jpeg: frame.jpeg,
+                timing: FrameTiming::default(),
+            }
+        }
+    });
+    
+    Ok(mjpeg_response(&state, "/history/replay", frames))
+}
+
+/// Face sightings (newest first), presence and the latest crop's face
+#[utoipa::path(
+    get,
+    path = "/faces",
+    tag = "faces",
+    responses(
+        (status = 200, description = "Face sightings (newest first), presence and the latest crop", body = Object),
+    )
+)]
+async fn faces_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let task = state.detector_config.read().task;
+    let faces = state.faces.read();
+    axum::Json(serde_json::json!({
+        "task": task,
+        "present": faces.present(),
+        "sightings": faces.sightings(),
+        "crop": faces.config(),
+        "last_crop": faces.last_crop().map(|c| &c.face),
+        "recent": faces.recent()
+    }))
+}
+
+/// Latest face crop (square, `--face-crop-size` pixels)
+#[utoipa::path(
+    get,
+    path = "/faces/last.jpg",
+    tag = "faces",
+    responses(
+        (status = 200, description = "Latest face crop (`X-Frame-Seq`, `X-Timestamp-Ms` identify its frame)", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 404, description = "No face seen yet", body = ApiError),
+    )
+)]
+async fn face_crop_handler(State(state): State<SharedState>) -> ApiResult<Response> {
+    let crop = state
+        .faces
+        .read()
+        .last_crop()
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("No face seen yet"))?;
+    let mut response = jpeg_response(crop.jpeg);
+    let headers = response.headers_mut();
+    headers.insert("x-frame-seq", crop.face.frame_seq.into());
+    headers.insert("x-timestamp-ms", crop.face.timestamp_ms.into());
+    Ok(response)
+}
+
+/// UI capability description, loaded by the index page at startup
+#[utoipa::path(
+    get,
+    path = "/ui/config",
+    tag = "ui",
+    responses(
+        (status = 200, description = "UI capabilities", body = Object),
+    )
+)]
+async fn ui_config_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let detector_available = state.detector.read().is_some();
+    let default_quality = state.pipeline.load().jpeg_quality;
+    let frame = state.capture.read().as_ref().map_or(FrameSize::FULL, FrameCapture::output_size);
+    
+    axum::Json(serde_json::json!({
+        "h264": state.h264.status().available,
+        "webrtc": state.h264.status().available && WebRtc::check_available().is_ok(),
+        "detector": detector_available,
+        "modes": ["grayscale", "color"],
+        "views": if detector_available { vec!["annotated", "clean"] } else { vec!["clean"] },
+        "resolutions": ResolutionPreset::NAMES,
+        "quality": { "min": 10, "max": 100, "default": default_quality },
+        "max_fps": MAX_STREAM_FPS,
+        "frame": frame,
+        "zoom": { "max_factor": zoom::MAX_FACTOR },
+        "tone": {
+            "brightness": { "min": tone::BRIGHTNESS.start(), "max": tone::BRIGHTNESS.end() },
+            "contrast": { "min": tone::CONTRASTS.start(), "max": tone::CONTRASTS.end() },
+            "saturation": { "min": tone::SATURATIONS.start(), "max": tone::SATURATIONS.end() }
+        },
+        "max_tile_area": MAX_TILE_AREA
+    }))
+}
+
+/// Run the self-test against the live capture instance; a failed run is a
+/// 503 with the report as details
+#[utoipa::path(
+    get,
+    path = "/selftest",
+    tag = "admin",
+    responses(
+        (status = 200, description = "All required checks passed", body = selftest::SelfTestReport),
+        (status = 503, description = "A required check failed (details hold the report)", body = ApiError),
+    )
+)]
+async fn selftest_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<selftest::SelfTestReport>> {
+    let report = tokio::task::spawn_blocking(move || {
+        let recordings_dir = state.storage.read().recordings_dir.clone();
+        // Hold the capture lock so the frame checks don't race the capture loop
+        let mut capture_guard = state.capture.write();
+        let defaults = CaptureConfig::default();
+        let (device_path, sensor_subdev) = match *capture_guard {
+            Some(ref c) => (c.config().device_path.clone(), c.config().sensor_subdev.clone()),
+            None => (defaults.device_path.clone(), defaults.sensor_subdev.clone()),
+        };
+        selftest::run(capture_guard.as_mut(), &device_path, &sensor_subdev, &recordings_dir)
+    })
+    .await?;
+    
+    if !report.passed {
+        return Err(ApiError::unavailable("Self-test failed").with_details(report));
+    }
+    Ok(axum::Json(report))
+}
+
+/// Model files in the models directory
+#[utoipa::path(
+    get,
+    path = "/detect/models",
+    tag = "models",
+    responses(
+        (status = 200, description = "Model files, sizes, hashes and which are active", body = Object),
+    )
+)]
+async fn models_list_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let config = state.detector_config.read().clone();
+    let active = config.active_models();
+    let models_dir = config.models_dir.clone();
+    let models = tokio::task::spawn_blocking(move || models::list_models(&models_dir, &active)).await??;
+    Ok(axum::Json(serde_json::json!({
+        "dir": config.models_dir,
+        "max_upload_bytes": config.max_model_bytes,
+        "models": models
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ModelUploadParams {
+    /// Switch the detector to the model once stored
+    #[serde(default)]
+    activate: bool,
+    /// Model kind when activating (default: unchanged)
+    task: Option<DetectionTask>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ModelActivateParams {
+    /// Model kind (default: unchanged)
+    task: Option<DetectionTask>,
+}
+
+/// Stream a model file to disk (`PUT /detect/models/<name>[?activate=true]`),
+/// verified against `X-Content-SHA256` when given
+#[utoipa::path(
+    put,
+    path = "/detect/models/{name}",
+    tag = "models",
+    params(("name" = String, Path, description = "Model file name (`.rknn` or `.onnx`)"), ModelUploadParams, ("X-Content-SHA256" = Option<String>, Header, description = "Expected SHA-256 of the body (64 hex digits)")),
+    request_body(content = openapi::Binary, content_type = "application/octet-stream", description = "Model file"),
+    responses(
+        (status = 200, description = "Model stored", body = Object),
+        (status = 400, description = "Invalid name, hash or interrupted upload", body = ApiError),
+        (status = 409, description = "Hash mismatch or the model is active", body = ApiError),
+        (status = 413, description = "Model larger than the upload limit", body = ApiError),
+    )
+)]
+async fn model_upload_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    Query(params): Query<ModelUploadParams>,
+    headers: header::HeaderMap,
+    body: Body,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if !models::valid_model_name(&name) {
+        return Err(ApiError::bad_request(format!(
+            "Invalid model name. Use a plain file name ending in .{}",
+            models::MODEL_EXTENSIONS.join(" or .")
+        )));
+    }
+    let expected_sha256 = match headers.get("x-content-sha256").map(|v| v.to_str().ok().and_then(models::parse_sha256)) {
+        Some(None) => return Err(ApiError::bad_request("X-Content-SHA256 must be 64 hex digits")),
+        Some(Some(sha)) => Some(sha),
+        None => None,
+    };
+    let (models_dir, limit) = {
+        let config = state.detector_config.read();
+        (config.models_dir.clone(), config.max_model_bytes)
+    };
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(models::UploadError::TooLarge { limit }.into());
+    }
+    
+    let mut upload = models::ModelUpload::create(&models_dir, &name, limit).await?;
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| ApiError::bad_request(format!("Upload interrupted: {}", e)))?;
+        upload.write(&chunk).await?;
+    }
+    let (size, sha256) = upload.finish(expected_sha256.as_deref()).await?;
+    info!("Uploaded detector model {} ({} bytes, sha256 {})", name, size, sha256);
+    
+    if params.activate {
+        activate_model(&state, &name, params.task)?;
+    }
+    Ok(axum::Json(serde_json::json!({
+        "name": name,
+        "size": size,
+        "sha256": sha256,
+        "activated": params.activate,
+        "detector": detector_stats_json(&state),
+        "success": true
+    })))
+}
+
+/// Delete a model file (never the active one)
+#[utoipa::path(
+    delete,
+    path = "/detect/models/{name}",
+    tag = "models",
+    params(("name" = String, Path, description = "Model file name")),
+    responses(
+        (status = 200, description = "Model deleted", body = Object),
+        (status = 400, description = "Invalid name", body = ApiError),
+        (status = 404, description = "No such model", body = ApiError),
+        (status = 409, description = "The model is active", body = ApiError),
+    )
+)]
+async fn model_delete_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if !models::valid_model_name(&name) {
+        return Err(ApiError::bad_request("Invalid model name"));
+    }
+    let (path, active) = {
+        let config = state.detector_config.read();
+        (config.models_dir.join(&name), config.active_models())
+    };
+    if active.contains(&name) {
+        return Err(ApiError::conflict(format!("{} is the active model", name)));
+    }
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => {
+            info!("Deleted detector model {}", name);
+            Ok(axum::Json(serde_json::json!({ "deleted": name, "success": true })))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(ApiError::not_found(format!("No model {}", name)))
+        }
+        Err(e) => Err(ApiError::internal(format!("Failed to delete {}: {}", name, e))),
+    }
+}
+
+/// Switch the detector to a model from the models directory
+/// (`?task=faces` for a face model, `?task=objects` back)
+#[utoipa::path(
+    post,
+    path = "/detect/models/{name}/activate",
+    tag = "models",
+    params(("name" = String, Path, description = "Model file name"), ModelActivateParams),
+    responses(
+        (status = 200, description = "Detector restarted with the model", body = Object),
+        (status = 400, description = "Invalid name", body = ApiError),
+        (status = 404, description = "No such model", body = ApiError),
+        (status = 503, description = "Detector failed to start", body = ApiError),
+    )
+)]
+async fn model_activate_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    Query(params): Query<ModelActivateParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    activate_model(&state, &name, params.task)?;
+    Ok(axum::Json(serde_json::json!({
+        "active": name,
+        "detector": detector_stats_json(&state),
+        "success": true
+    })))
+}
+
+/// Point the matching backend at `name` (and the task, if given) and
+/// restart the detector
+fn activate_model(state: &AppState, name: &str, task: Option<DetectionTask>) -> ApiResult<()> {
+    if !models::valid_model_name(name) {
+        return Err(ApiError::bad_request("Invalid model name"));
+    }
+    let path = state.detector_config.read().models_dir.join(name);
+    if !path.is_file() {
+        return Err(ApiError::not_found(format!("No model {}", name)));
+    }
+    let path = path.display().to_string();
+    {
+        let mut config = state.detector_config.write();
+        if name.ends_with(".onnx") {
+            config.onnx_model = path;
+        } else {
+            config.rknn_model = Some(path);
+        }
+        if let Some(task) = task {
+            config.task = task;
+        }
+    }
+    info!("Activating detector model {} ({})", name, state.detector_config.read().task.name());
+    restart_detector(state);
+    if state.detector.read().is_none() {
+        let error = state.detector_supervisor.status().last_error.unwrap_or_default();
+        return Err(ApiError::unavailable(format!("Detector failed to start with {}: {}", name, error)));
+    }
+    Ok(())
+}
+
+/// Saved settings profiles
+#[utoipa::path(
+    get,
+    path = "/profiles",
+    tag = "profiles",
+    responses(
+        (status = 200, description = "Saved profiles and their settings", body = Object),
+    )
+)]
+async fn profiles_list_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let profiles = state.profiles.read();
+    axum::Json(serde_json::json!({
+        "file": profiles.path(),
+        "profiles": profiles.list()
+    }))
+}
+
+fn no_profile(name: &str) -> ApiError {
+    ApiError::not_found(format!("No profile '{}'", name))
+}
+
+/// One saved profile
+#[utoipa::path(
+    get,
+    path = "/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path, description = "Profile name")),
+    responses(
+        (status = 200, description = "The profile's settings", body = Object),
+        (status = 404, description = "No such profile", body = ApiError),
+    )
+)]
+async fn profile_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<profiles::Profile>> {
+    let profile = state.profiles.read().get(&name).cloned().ok_or_else(|| no_profile(&name))?;
+    Ok(axum::Json(profile))
+}
+
+/// Create or replace a profile from the given settings (any subset of what
+/// `GET /profiles/{name}` shows); nothing is applied
+#[utoipa::path(
+    put,
+    path = "/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path, description = "Profile name (letters, digits, `_`, `-`)")),
+    request_body = Object,
+    responses(
+        (status = 200, description = "Profile saved", body = Object),
+        (status = 400, description = "Invalid name or settings", body = ApiError),
+        (status = 500, description = "State file not writable", body = ApiError),
+    )
+)]
+async fn profile_put_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    settings: Result<axum::Json<Settings>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if !crate::valid_name(&name) {
+        return Err(ApiError::bad_request("Profile names may only use letters, digits, '_' and '-'"));
+    }
+    let axum::Json(settings) = settings?;
+    settings.validate().map_err(ApiError::bad_request)?;
+    let profile = profiles::Profile { saved_ms: unix_millis(), settings };
+    let replaced = state.profiles.write().insert(&name, profile.clone())?;
+    info!("{} settings profile '{}'", if replaced { "Replaced" } else { "Created" }, name);
+    Ok(axum::Json(serde_json::json!({
+        "name": name,
+        "profile": profile,
+        "replaced": replaced,
+        "success": true
+    })))
+}
+
+/// Save the current runtime settings as a named profile
+#[utoipa::path(
+    post,
+    path = "/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path, description = "Profile name (letters, digits, `_`, `-`)")),
+    responses(
+        (status = 200, description = "Current settings saved", body = Object),
+        (status = 400, description = "Invalid name", body = ApiError),
+        (status = 500, description = "State file not writable", body = ApiError),
+    )
+)]
+async fn profile_save_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if !crate::valid_name(&name) {
+        return Err(ApiError::bad_request("Profile names may only use letters, digits, '_' and '-'"));
+    }
+    let capture_state = state.clone();
+    let settings = tokio::task::spawn_blocking(move || current_settings(&capture_state)).await?;
+    let profile = profiles::Profile { saved_ms: unix_millis(), settings };
+    let replaced = state.profiles.write().insert(&name, profile.clone())?;
+    info!("Saved settings profile '{}'", name);
+    Ok(axum::Json(serde_json::json!({
+        "name": name,
+        "profile": profile,
+        "replaced": replaced,
+        "success": true
+    })))
+}
+
+/// Apply a saved profile
+#[utoipa::path(
+    post,
+    path = "/profiles/{name}/apply",
+    tag = "profiles",
+    params(("name" = String, Path, description = "Profile name")),
+    responses(
+        (status = 200, description = "Applied and skipped settings", body = Object),
+        (status = 404, description = "No such profile", body = ApiError),
+    )
+)]
+async fn profile_apply_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let profile = state.profiles.read().get(&name).cloned().ok_or_else(|| no_profile(&name))?;
+    let apply_state = state.clone();
+    let (applied, skipped) = tokio::task::spawn_blocking(move || apply_settings(&apply_state, &profile.settings)).await?;
+    profile_applied(&state, &name, &applied, &skipped);
+    Ok(axum::Json(serde_json::json!({
+        "name": name,
+        "applied": applied,
+        "skipped": skipped,
+        "success": true
+    })))
+}
+
+/// Log an applied profile and publish the event
+fn profile_applied(state: &AppState, name: &str, applied: &[String], skipped: &[Skipped]) {
+    if skipped.is_empty() {
+        info!("Applied settings profile '{}' ({})", name, applied.join(", "));
+    } else {
+        tracing::warn!(
+            "Applied settings profile '{}' ({}); skipped {}",
+            name,
+            applied.join(", "),
+            skipped.iter().map(|s| format!("{} ({})", s.setting, s.reason)).collect::<Vec<_>>().join(", ")
+        );
+    }
+    state.events.publish(DomainEvent::ProfileApplied { name: name.to_string(), applied: applied.to_vec() });
+}
+
+/// Delete a saved profile
+#[utoipa::path(
+    delete,
+    path = "/profiles/{name}",
+    tag = "profiles",
+    params(("name" = String, Path, description = "Profile name")),
+    responses(
+        (status = 200, description = "Profile deleted", body = Object),
+        (status = 404, description = "No such profile", body = ApiError),
+        (status = 409, description = "The schedule applies the profile", body = ApiError),
+        (status = 500, description = "State file not writable", body = ApiError),
+    )
+)]
+async fn profile_delete_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    if state.scheduler.read().schedule().profiles().any(|p| p == name) {
+        return Err(ApiError::conflict(format!("Profile '{}' is used by the schedule", name)));
+    }
+    if !state.profiles.write().remove(&name)? {
+        return Err(no_profile(&name));
+    }
+    info!("Deleted settings profile '{}'", name);
+    Ok(axum::Json(serde_json::json!({ "deleted": name, "success": true })))
+}
+
+/// The schedule, feature states and upcoming transitions
+fn schedule_json(state: &AppState) -> serde_json::Value {
+    let now = chrono::Utc::now();
+    let scheduler = state.scheduler.read();
+    let schedule = scheduler.schedule();
+    serde_json::json!({
+        "timezone": schedule.config().timezone,
+        "now": now.with_timezone(&schedule.timezone()).to_rfc3339(),
+        "features": schedule.config().features,
+        "profiles": schedule.config().profiles,
+        "state": scheduler.states(now),
+        "profile": scheduler.profile_state(now),
+        "next_transitions": schedule.transitions(now),
+        "next_profiles": schedule.profile_transitions(now)
+    })
+}
+
+/// Annotation settings saved in the settings state file (defaults if none)
+fn load_annotation(state_file: &std::path::Path) -> Result<AnnotationSettings> {
+    let settings: AnnotationSettings = match profiles::read_state_key(state_file, annotation::STATE_KEY)? {
+        Some(settings) => serde_json::from_value(settings)
+            .with_context(|| format!("Invalid annotation settings in {}", state_file.display()))?,
+        None => AnnotationSettings::default(),
+    };
+    settings
+        .validate()
+        .map_err(|e| anyhow::anyhow!("Invalid annotation settings in {}: {}", state_file.display(), e))?;
+    Ok(settings)
+}
+
+/// Persist new annotation settings, then make them current
+async fn save_annotation(state: &AppState, settings: AnnotationSettings) -> ApiResult<()> {
+    let path = state.profiles.read().path().to_path_buf();
+    let value = serde_json::to_value(&settings).map_err(|e| ApiError::internal(e.to_string()))?;
+    tokio::task::spawn_blocking(move || profiles::write_state_key(&path, annotation::STATE_KEY, value)).await??;
+    // Relabel the current result so on-demand detection doesn't wait for a new run
+    settings.apply_labels(&mut state.last_detections.write());
+    *state.annotation.write() = settings;
+    Ok(())
+}
+
+/// The detection filter saved in the settings state file (the default,
+/// which keeps everything, if none)
+fn load_detection_filter(state_file: &std::path::Path) -> Result<DetectionFilter> {
+    let filter: DetectionFilter = match profiles::read_state_key(state_file, detector::FILTER_STATE_KEY)? {
+        Some(filter) => serde_json::from_value(filter)
+            .with_context(|| format!("Invalid detection filter in {}", state_file.display()))?,
+        None => DetectionFilter::default(),
+    };
+    filter
+        .validate()
+        .map_err(|e| anyhow::anyhow!("Invalid detection filter in {}: {}", state_file.display(), e))?;
+    Ok(filter)
+}
+
+/// Detection filter: confidence and NMS thresholds and class allow-list
+#[utoipa::path(
+    get,
+    path = "/detect/config",
+    tag = "detection",
+    responses(
+        (status = 200, description = "Current detection filter", body = DetectionFilter),
+    )
+)]
+async fn detect_config_handler(State(state): State<SharedState>) -> axum::Json<DetectionFilter> {
+    axum::Json(state.detector_config.read().filter.clone())
+}
+
+/// Replace the detection filter (fields left out take their defaults);
+/// applies from the next detection and is kept across restarts
+#[utoipa::path(
+    post,
+    path = "/detect/config",
+    tag = "detection",
+    request_body = DetectionFilter,
+    responses(
+        (status = 200, description = "Filter replaced", body = Object),
+        (status = 400, description = "Invalid filter", body = ApiError),
+    )
+)]
+async fn detect_config_set_handler(
+    State(state): State<SharedState>,
+    filter: Result<axum::Json<DetectionFilter>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(filter) = filter?;
+    filter.validate().map_err(ApiError::bad_request)?;
+    let path = state.profiles.read().path().to_path_buf();
+    let value = serde_json::to_value(&filter).map_err(|e| ApiError::internal(e.to_string()))?;
+    tokio::task::spawn_blocking(move || profiles::write_state_key(&path, detector::FILTER_STATE_KEY, value)).await??;
+
+    info!(
+        "Detection filter: confidence >= {}, IoU {}, classes {}",
+        filter.confidence_threshold,
+        filter.iou_threshold,
+        if filter.classes.is_empty() { "all".to_string() } else { filter.classes.join(",") }
+    );
+    if let Some(ref detector) = *state.detector.read() {
+        detector.set_filter(filter.clone());
+    }
+    // Filter the current result too, so it doesn't show dropped classes until the next run
+    filter.apply(&mut state.last_detections.write());
+    state.detector_config.write().filter = filter.clone();
+    Ok(axum::Json(serde_json::json!({
+        "filter": filter,
+        "success": true
+    })))
+}
+
+/// Class label map (model class -> drawn name)
+#[utoipa::path(
+    get,
+    path = "/detect/labels",
+    tag = "detection",
+    responses(
+        (status = 200, description = "Class label map", body = Object),
+    )
+)]
+async fn labels_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    axum::Json(serde_json::json!({ "labels": state.annotation.read().labels }))
+}
+
+/// Replace the class label map; unmapped classes are drawn as reported
+#[utoipa::path(
+    post,
+    path = "/detect/labels",
+    tag = "detection",
+    request_body(content = BTreeMap<String, String>, description = "Model class -> drawn name"),
+    responses(
+        (status = 200, description = "Label map replaced", body = Object),
+        (status = 400, description = "Not a JSON object of strings", body = ApiError),
+    )
+)]
+async fn labels_set_handler(
+    State(state): State<SharedState>,
+    labels: Result<axum::Json<std::collections::BTreeMap<String, String>>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(labels) = labels?;
+    annotation::validate_labels(&labels).map_err(ApiError::bad_request)?;
+    let settings = AnnotationSettings { labels, ..state.annotation.read().clone() };
+    info!("Class labels updated: {} mapped", settings.labels.len());
+    save_annotation(&state, settings).await?;
+    Ok(axum::Json(serde_json::json!({
+        "labels": state.annotation.read().labels,
+        "success": true
+    })))
+}
+
+/// Label map and box label style
+#[utoipa::path(
+    get,
+    path = "/annotation",
+    tag = "detection",
+    responses(
+        (status = 200, description = "Label map and box label style", body = Object),
+    )
+)]
+async fn annotation_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    axum::Json(serde_json::json!(*state.annotation.read()))
+}
+
+/// Replace the box label style (fields left out take their defaults)
+#[utoipa::path(
+    post,
+    path = "/annotation",
+    tag = "detection",
+    request_body = AnnotationStyle,
+    responses(
+        (status = 200, description = "Style replaced", body = Object),
+        (status = 400, description = "Invalid style", body = ApiError),
+    )
+)]
+async fn annotation_set_handler(
+    State(state): State<SharedState>,
+    style: Result<axum::Json<AnnotationStyle>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(style) = style?;
+    style.validate().map_err(ApiError::bad_request)?;
+    let settings = AnnotationSettings { style, ..state.annotation.read().clone() };
+    info!("Annotation style updated: {:?}", style);
+    save_annotation(&state, settings).await?;
+    Ok(axum::Json(serde_json::json!({
+        "annotation": *state.annotation.read(),
+        "success": true
+    })))
+}
+
+/// Parsed schedule and the next transitions
+#[utoipa::path(
+    get,
+    path = "/schedule",
+    tag = "schedule",
+    responses(
+        (status = 200, description = "Schedule, feature states and the next transitions", body = Object),
+    )
+)]
+async fn schedule_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    axum::Json(schedule_json(&state))
+}
+
+/// Replace the schedule (persisted to the settings state file); overrides
+/// are dropped and the new schedule applies immediately, including the
+/// profile it calls for now
+#[utoipa::path(
+    post,
+    path = "/schedule",
+    tag = "schedule",
+    request_body = ScheduleConfig,
+    responses(
+        (status = 200, description = "Schedule replaced", body = Object),
+        (status = 400, description = "Invalid schedule or unknown profile", body = ApiError),
+        (status = 500, description = "State file not writable", body = ApiError),
+    )
+)]
+async fn schedule_set_handler(
+    State(state): State<SharedState>,
+    config: Result<axum::Json<ScheduleConfig>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(config) = config?;
+    let schedule = Schedule::parse(config.clone()).map_err(ApiError::bad_request)?;
+    let missing: Vec<&str> = {
+        let profiles = state.profiles.read();
+        schedule.profiles().filter(|p| profiles.get(p).is_none()).collect()
+    };
+    if !missing.is_empty() {
+        return Err(ApiError::bad_request(format!("No profile '{}'", missing.join("', '"))));
+    }
+    let path = state.profiles.read().path().to_path_buf();
+    let value = serde_json::to_value(&config).map_err(|e| ApiError::internal(e.to_string()))?;
+    tokio::task::spawn_blocking(move || profiles::write_state_key(&path, "schedule", value)).await??;
+    
+    info!(
+        "Schedule updated: {} feature(s), {} profile time(s), time zone {}",
+        schedule.features().count(),
+        config.profiles.len(),
+        config.timezone
+    );
+    state.scheduler.write().set(schedule);
+    state.schedule_changed.notify_one();
+    Ok(axum::Json(schedule_json(&state)))
+}
+
+/// The motion config saved in the settings state file (the default,
+/// disabled, if none)
+fn load_motion_config(state_file: &std::path::Path) -> Result<MotionConfig> {
+    let config: MotionConfig = match profiles::read_state_key(state_file, motion::STATE_KEY)? {
+        Some(config) => serde_json::from_value(config)
+            .with_context(|| format!("Invalid motion config in {}", state_file.display()))?,
+        None => MotionConfig::default(),
+    };
+    config
+        .validate()
+        .map_err(|e| anyhow::anyhow!("Invalid motion config in {}: {}", state_file.display(), e))?;
+    Ok(config)
+}
+
+fn log_motion_config(config: &MotionConfig) {
+    if !config.enabled {
+        info!("Motion detection: off");
+        return;
+    }
+    info!(
+        "Motion detection: sensitivity {}, area >= {}%, hold {}s, {} mask(s){}",
+        config.sensitivity,
+        config.min_area_percent,
+        config.hold_secs,
+        config.masks.len(),
+        if config.record { ", recording on motion" } else { "" }
+    );
+}
+
+/// Publish a motion edge seen at frame `frame_seq`
+fn publish_motion(state: &AppState, frame_seq: u64, transition: Transition) {
+    state.events.publish(match transition {
+        Transition::Started { score } => DomainEvent::MotionStarted { frame_seq, score },
+        Transition::Stopped { duration_ms } => DomainEvent::MotionStopped { frame_seq, duration_ms },
+    });
+}
+
+/// Record while triggered: motion (if its config has `record`) and
+/// detections (`--record-on-detection`) start a recording when the recorder
+/// is idle, which stops once no trigger is active (a recording started some
+/// other way is left alone). A pre-event buffer puts the seconds before the
+/// trigger at its start.
+async fn event_recording(state: SharedState) {
+    let mut events = state.events.subscribe("event_recording");
+    let (mut motion, mut detection) = (false, false);
+    // Start time of the recording started for a trigger
+    let mut started: Option<u64> = None;
+    while let Some(event) = events.recv().await {
+        match event.event {
+            DomainEvent::MotionStarted { .. } => motion = state.motion.lock().config().record,
+            DomainEvent::MotionStopped { .. } => motion = false,
+            DomainEvent::DetectionConfirmed { .. } => detection = *state.record_on_detection.read(),
+            DomainEvent::DetectionCleared { .. } => detection = false,
+            _ => continue,
+        }
+        let Ok(recorder) = recorder(&state) else { continue };
+        if motion || detection {
+            if !recorder.is_idle() {
+                continue;
+            }
+            match start_recording(&state, &recorder, None).await {
+                Ok(_) => {
+                    info!("Recording on {}", event.event.kind());
+                    started = recorder.status().started_ms;
+                }
+                Err(e) => tracing::warn!("Failed to start recording on {}: {}", event.event.kind(), e.message),
+            }
+        } else if let Some(started_ms) = started.take() {
+            if recorder.status().started_ms == Some(started_ms) && recorder.stop() {
+                info!("Recording stopped, no trigger active");
+            }
+        }
+    }
+}
+
+/// Motion state: whether there is motion now, the last score and counters
+#[utoipa::path(
+    get,
+    path = "/motion",
+    tag = "motion",
+    responses(
+        (status = 200, description = "Motion state", body = MotionStatus),
+    )
+)]
+async fn motion_handler(State(state): State<SharedState>) -> axum::Json<MotionStatus> {
+    axum::Json(state.motion.lock().status())
+}
+
+/// Motion detection config: sensitivity, minimum area, hold time and masks
+#[utoipa::path(
+    get,
+    path = "/motion/config",
+    tag = "motion",
+    responses(
+        (status = 200, description = "Current motion config", body = MotionConfig),
+    )
+)]
+async fn motion_config_handler(State(state): State<SharedState>) -> axum::Json<MotionConfig> {
+    axum::Json(state.motion.lock().config().clone())
+}
+
+/// Replace the motion config (fields left out take their defaults); motion
+/// detection starts over, and the config is kept across restarts
+#[utoipa::path(
+    post,
+    path = "/motion/config",
+    tag = "motion",
+    request_body = MotionConfig,
+    responses(
+        (status = 200, description = "Config replaced", body = Object),
+        (status = 400, description = "Invalid config", body = ApiError),
+    )
+)]
+async fn motion_config_set_handler(
+    State(state): State<SharedState>,
+    config: Result<axum::Json<MotionConfig>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(config) = config?;
+    config.validate().map_err(ApiError::bad_request)?;
+    let path = state.profiles.read().path().to_path_buf();
+    let value = serde_json::to_value(&config).map_err(|e| ApiError::internal(e.to_string()))?;
+    tokio::task::spawn_blocking(move || profiles::write_state_key(&path, motion::STATE_KEY, value)).await??;
+
+    log_motion_config(&config);
+    let stopped = state.motion.lock().set_config(config.clone(), unix_millis());
+    if let Some(transition) = stopped {
+        publish_motion(&state, *state.frame_count.read(), transition);
+    }
+    Ok(axum::Json(serde_json::json!({
+        "config": config,
+        "success": true
+    })))
+}
+
+fn recorder(state: &AppState) -> ApiResult<Arc<Recorder>> {
+    state
+        .recorder
+        .read()
+        .clone()
+        .ok_or_else(|| ApiError::unavailable("Recording not available"))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RecordStartParams {
+    /// `mp4` (H.264) or `avi` (MJPEG); default: MP4 when the hardware
+    /// encoder starts, AVI otherwise
+    format: Option<String>,
+}
+
+/// Start recording to segment files: H.264 MP4, or the published JPEGs in
+/// AVI without a hardware encoder
+#[utoipa::path(
+    post,
+    path = "/record/start",
+    tag = "recording",
+    params(RecordStartParams),
+    responses(
+        (status = 200, description = "Recording started", body = Object),
+        (status = 400, description = "Unknown format", body = ApiError),
+        (status = 409, description = "Already recording", body = ApiError),
+        (status = 503, description = "Recording not available, or MP4 asked for without a working H.264 encoder", body = ApiError),
+    )
+)]
+async fn record_start_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<RecordStartParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let recorder = recorder(&state)?;
+    let format = params
+        .format
+        .as_deref()
+        .map(|name| RecordFormat::parse(name).ok_or_else(|| ApiError::bad_request("Invalid format. Use 'mp4' or 'avi'")))
+        .transpose()?;
+    start_recording(&state, &recorder, format).await?;
+    Ok(axum::Json(serde_json::json!(recorder.status())))
+}
+
+/// Start a recording; without a `format`, MP4 if the H.264 encoder works
+/// and AVI otherwise
+async fn start_recording(
+    state: &SharedState,
+    recorder: &Arc<Recorder>,
+    format: Option<RecordFormat>,
+) -> ApiResult<std::path::PathBuf> {
+    let busy = |phase| ApiError::conflict("Recorder is busy").with_details(serde_json::json!({ "state": phase }));
+    // Don't start the encoder for a recorder that can't take it
+    if !recorder.is_idle() {
+        return Err(busy(recorder.status().state));
+    }
+    let h264 = match format {
+        Some(RecordFormat::Mp4) => Some(h264_subscription(state).await?),
+        Some(RecordFormat::Avi) => None,
+        None if state.h264.status().available => match h264_subscription(state).await {
+            Ok(subscription) => Some(subscription),
+            Err(e) => {
+                tracing::warn!("Recording as AVI, the H.264 encoder is not available: {}", e.message);
+                None
+            }
+        },
+        None => None,
+    };
+    let source = match h264 {
+        Some((subscription, track)) => recorder::Source::H264 { subscription, track, stream: state.h264.clone() },
+        None => recorder::Source::Frames(state.frame_watch.subscribe()),
+    };
+    let file = recorder.start(source).map_err(busy)?;
+    info!("Recording to {}", file.display());
+    Ok(file)
+}
+
+/// Stop recording; an AVI recording's spool drains into the last segment
+/// in the background
+#[utoipa::path(
+    post,
+    path = "/record/stop",
+    tag = "recording",
+    responses(
+        (status = 200, description = "Recording stopped", body = Object),
+        (status = 409, description = "Not recording", body = ApiError),
+        (status = 503, description = "Recording not available", body = ApiError),
+    )
+)]
+async fn record_stop_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let recorder = recorder(&state)?;
+    if !recorder.stop() {
+        return Err(ApiError::conflict("Not recording"));
+    }
+    info!("Recording stopped, draining spool");
+    Ok(axum::Json(serde_json::json!(recorder.status())))
+}
+
+/// Recorder state, counters and spool depth
+#[utoipa::path(
+    get,
+    path = "/record/status",
+    tag = "recording",
+    responses(
+        (status = 200, description = "Recorder state, counters and spool depth", body = Object),
+        (status = 503, description = "Recording not available", body = ApiError),
+    )
+)]
+async fn record_status_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let recorder = recorder(&state)?;
+    Ok(axum::Json(serde_json::json!(recorder.status())))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StackStartParams {
+    /// Raw frames to combine (2-4096, default 64)
+    frames: Option<u32>,
+    /// `mean` (default) or `sum`
+    mode: Option<String>,
+}
+
+/// Start stacking the next raw frames the camera delivers into one
+/// high-SNR image; the stream keeps running. A finished stack replaces the
+/// previous result.
+#[utoipa::path(
+    post,
+    path = "/stack/start",
+    tag = "stack",
+    params(StackStartParams),
+    responses(
+        (status = 200, description = "Stack started", body = StackStatus),
+        (status = 400, description = "Invalid frame count or mode", body = ApiError),
+        (status = 409, description = "A stack is already running", body = ApiError),
+        (status = 503, description = "Camera not available, or not enough memory budget", body = ApiError),
+    )
+)]
+async fn stack_start_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<StackStartParams>,
+) -> ApiResult<axum::Json<StackStatus>> {
+    let frames = params.frames.unwrap_or(stack::DEFAULT_FRAMES);
+    if !(2..=stack::MAX_FRAMES).contains(&frames) {
+        return Err(ApiError::bad_request(format!("frames must be 2-{}", stack::MAX_FRAMES)));
+    }
+    let mode = params
+        .mode
+        .as_deref()
+        .map(|name| StackMode::parse(name).ok_or_else(|| ApiError::bad_request("Invalid mode. Use 'mean' or 'sum'")))
+        .transpose()?
+        .unwrap_or(StackMode::Mean);
+    let (size, subdev) = state
+        .capture
+        .read()
+        .as_ref()
+        .map(|c| (c.frame_size(), c.config().source.has_sensor().then(|| c.config().sensor_subdev.clone())))
+        .ok_or_else(ApiError::camera_unavailable)?;
+    if state.stack.lock().as_ref().is_some_and(|s| s.state() == StackState::Running) {
+        return Err(ApiError::conflict("A stack is already running"));
+    }
+    if !state.memory.fits(Component::Stack, size.width * size.height * std::mem::size_of::<u32>()) {
+        state.memory.set_pressure(MemoryPressure::Shedding);
+        return Err(ApiError::unavailable("Not enough memory budget for a frame stack")
+            .with_details(serde_json::json!({ "memory": state.memory.snapshot() })));
+    }
+    // Exposure and ISO as set when the stack starts, for the DNG
+    let fields = tokio::task::spawn_blocking(move || {
+        let mut fields = exif::ExifFields::new(unix_millis());
+        if let Some(subdev) = subdev {
+            match controls::list_controls(&subdev) {
+                Ok(controls) => fields = fields.with_sensor_controls(&controls, size.width),
+                Err(e) => tracing::warn!("No sensor controls for the stack: {:#}", e),
+            }
+        }
+        fields
+    })
+    .await?;
+    let black_level = state.pipeline.load().black_level;
+    let mut stack = state.stack.lock();
+    if stack.as_ref().is_some_and(|s| s.state() == StackState::Running) {
+        return Err(ApiError::conflict("A stack is already running"));
+    }
+    let started = FrameStack::new(mode, frames, black_level, fields);
+    let status = started.status();
+    *stack = Some(started);
+    info!("Stacking {} frames ({})", frames, mode.name());
+    Ok(axum::Json(status))
+}
+
+/// Abandon the running stack, or discard the finished one
+#[utoipa::path(
+    post,
+    path = "/stack/cancel",
+    tag = "stack",
+    responses(
+        (status = 200, description = "Stack discarded", body = StackStatus),
+        (status = 409, description = "No stack", body = ApiError),
+    )
+)]
+async fn stack_cancel_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<StackStatus>> {
+    let stack = state.stack.lock().take().ok_or_else(|| ApiError::conflict("No stack"))?;
+    state.memory.set(Component::Stack, 0);
+    info!("Stack discarded after {} frames", stack.frames());
+    Ok(axum::Json(stack.status()))
+}
+
+/// Progress of the running or last stack
+#[utoipa::path(
+    get,
+    path = "/stack/status",
+    tag = "stack",
+    responses(
+        (status = 200, description = "Stack state and frame counts", body = StackStatus),
+        (status = 404, description = "No stack started", body = ApiError),
+    )
+)]
+async fn stack_status_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<StackStatus>> {
+    let status = state.stack.lock().as_ref().map(FrameStack::status);
+    status.map(axum::Json).ok_or_else(|| ApiError::not_found("No stack started"))
+}
+
+/// The finished stack's samples and layout, and its EXIF
+fn stack_result(state: &AppState) -> ApiResult<(stack::Stacked, RawLayout, exif::ExifFields)> {
+    let stack = state.stack.lock();
+    let stack = stack.as_ref().ok_or_else(|| ApiError::not_found("No stack started"))?;
+    match (stack.stacked(), stack.layout()) {
+        (Some(stacked), Some(layout)) => Ok((stacked, layout, stack.exif())),
+        _ => Err(ApiError::conflict("The stack is not done").with_details(serde_json::json!({ "status": stack.status() }))),
+    }
+}
+
+fn stack_file_name(fields: &exif::ExifFields, extension: &str) -> String {
+    format!("attachment; filename=\"stack-{}.{}\"", fields.timestamp_ms, extension)
+}
+
+/// The finished stack as a 16-bit DNG of the Bayer mosaic
+#[utoipa::path(
+    get,
+    path = "/stack/result.dng",
+    tag = "stack",
+    responses(
+        (status = 200, description = "Stacked Bayer samples on the 16-bit scale with their black and white levels", body = openapi::Binary, content_type = "image/x-adobe-dng"),
+        (status = 404, description = "No stack started", body = ApiError),
+        (status = 409, description = "The stack is running or failed", body = ApiError),
+        (status = 500, description = "Encoding failed", body = ApiError),
+    )
+)]
+async fn stack_dng_handler(State(state): State<SharedState>) -> ApiResult<Response> {
+    let pipeline = state.pipeline.load();
+    let color_matrix = pipeline.color_correction.effective();
+    let white_balance = state.capture.read().as_ref().and_then(FrameCapture::white_balance_gains);
+    let (dng, fields) = tokio::task::spawn_blocking(move || -> ApiResult<(Vec<u8>, exif::ExifFields)> {
+        let (stacked, layout, fields) = stack_result(&state)?;
+        let image = dng::BayerImage {
+            samples: &stacked.samples,
+            width: layout.width,
+            height: layout.height,
+            orientation: layout.orientation,
+            cfa: layout.cfa,
+            black_level: stacked.black_level,
+            white_level: stacked.white_level,
+            white_balance,
+            color_matrix,
+        };
+        let dng = dng::encode(&image, &fields).map_err(|e| ApiError::internal(format!("Failed to encode DNG: {:#}", e)))?;
+        Ok((dng, fields))
+    })
+    .await??;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/x-adobe-dng")
+        .header(header::CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .header(header::CONTENT_DISPOSITION, stack_file_name(&fields, "dng"))
+        .body(Body::from(dng))
+        .unwrap())
+}
+
+/// The finished stack as a 16-bit PNG: linear RGB at half resolution, one
+/// pixel per 2x2 Bayer block, white balanced with the last color frame's
+/// gains
+#[utoipa::path(
+    get,
+    path = "/stack/result.png",
+    tag = "stack",
+    responses(
+        (status = 200, description = "Linear 16-bit RGB", body = openapi::Binary, content_type = "image/png"),
+        (status = 404, description = "No stack started", body = ApiError),
+        (status = 409, description = "The stack is running or failed", body = ApiError),
+        (status = 500, description = "Encoding failed", body = ApiError),
+    )
+)]
+async fn stack_png_handler(State(state): State<SharedState>) -> ApiResult<Response> {
+    let gains = state.capture.read().as_ref().and_then(FrameCapture::white_balance_gains).unwrap_or([1.0; 3]);
+    let (png, fields) = tokio::task::spawn_blocking(move || -> ApiResult<(Vec<u8>, exif::ExifFields)> {
+        let (stacked, layout, fields) = stack_result(&state)?;
+        let image = stack::develop(&stacked, layout, gains);
+        let mut png = std::io::Cursor::new(Vec::new());
+        image
+            .write_to(&mut png, image::ImageFormat::Png)
+            .map_err(|e| ApiError::internal(format!("Failed to encode PNG: {}", e)))?;
+        Ok((png.into_inner(), fields))
+    })
+    .await??;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/png")
+        .header(header::CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .header(header::CONTENT_DISPOSITION, stack_file_name(&fields, "png"))
+        .body(Body::from(png))
+        .unwrap())
+}
+
+/// Snapshot the runtime settings a profile covers (blocking: reads sensor controls)
+fn current_settings(state: &AppState) -> Settings {
+    let pipeline = state.pipeline.load();
+    let mut settings = Settings {
+        mode: Some(pipeline.mode.name().to_string()),
+        demosaic: Some(pipeline.demosaic.name().to_string()),
+        hdr_ratio: Some(pipeline.hdr_ratio),
+        quality: Some(match &pipeline.adaptive_quality {
+            Some(adaptive) => profiles::QualitySetting::Auto {
+                min: adaptive.min_quality,
+                max: adaptive.max_quality,
+                target_kb: adaptive.target_bytes.map(|b| b / 1024),
+                target_ms: adaptive.target_encode_ms,
+            },
+            None => profiles::QualitySetting::Fixed { quality: Some(pipeline.jpeg_quality) },
+        }),
+        gamma: Some(pipeline.gamma),
+        white_balance: Some(pipeline.enable_white_balance),
+        tonemap_strength: Some(pipeline.tonemap_strength),
+        black_level: Some(pipeline.black_level),
+        row_noise: Some(profiles::RowNoiseSetting {
+            enabled: pipeline.row_noise_correction,
+            strength: pipeline.row_noise_strength,
+        }),
+        metering: Some(pipeline.metering),
+        detection: Some(profiles::DetectionSetting {
+            enabled: *state.detection_enabled.read(),
+            interval: *state.detection_interval.read(),
+        }),
+        ..Default::default()
+    };
+    let Some(subdev) = state.capture.read().as_ref().map(|c| c.config().sensor_subdev.clone()) else {
+        return settings;
+    };
+    match controls::list_controls(&subdev) {
+        Ok(controls) => {
+            settings.controls = controls
+                .into_iter()
+                .filter(|c| profiles::PROFILE_CONTROLS.contains(&c.name.as_str()))
+                .filter_map(|c| Some((c.name, c.value?)))
+                .collect();
+        }
+        Err(e) => tracing::warn!("Profile saved without sensor controls: {:#}", e),
+    }
+    settings
+}
+
+/// Apply `settings`, skipping (and reporting) any the hardware or current
+/// state can't take. The pipeline settings are published as one version and
+/// the capture lock is held while the sensor controls are set, so the next
+/// frame is the first one with the new settings and none sees half of them.
+/// Returns the names of the applied settings and the skipped ones.
+fn apply_settings(state: &AppState, settings: &Settings) -> (Vec<String>, Vec<Skipped>) {
+    let mut applied = Vec::new();
+    let mut skipped = Vec::new();
+    let mut skip = |setting: &str, reason: String| skipped.push(Skipped { setting: setting.to_string(), reason });
+    
+    let capture_guard = state.capture.write();
+    // Sensor controls, checked against what the current sensor mode allows
+    if !settings.controls.is_empty() {
+        match capture_guard.as_ref() {
+            None => {
+                for name in settings.controls.keys() {
+                    skip(name, "Camera not available".to_string());
+                }
+            }
+            Some(capture) => {
+                let subdev = capture.config().sensor_subdev.clone();
+                match controls::list_controls(&subdev) {
+                    Ok(available) => {
+                        for (name, &value) in &settings.controls {
+                            let Some(control) = available.iter().find(|c| &c.name == name) else {
+                                skip(name, "Not supported by the sensor".to_string());
+                                continue;
+                            };
+                            let (min, max) = (control.min.unwrap_or(i64::MIN), control.max.unwrap_or(i64::MAX));
+                            if !(min..=max).contains(&value) {
+                                skip(name, format!("{} is outside {}..={} in the current sensor mode", value, min, max));
+                                continue;
+                            }
+                            match controls::set_control(&subdev, name, value) {
+                                Ok(()) => applied.push(name.clone()),
+                                Err(e) => skip(name, format!("{:#}", e)),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        for name in settings.controls.keys() {
+                            skip(name, format!("{:#}", e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    
+    let pipeline_changed = settings.mode.is_some()
+        || settings.demosaic.is_some()
+        || settings.quality.is_some()
+        || settings.gamma.is_some()
+        || settings.white_balance.is_some()
+        || settings.tonemap_strength.is_some()
+        || settings.black_level.is_some()
+        || settings.row_noise.is_some()
+        || settings.hdr_ratio.is_some();
+    if pipeline_changed {
+        state.pipeline.update(|pipeline| {
+            if let Some(ref mode) = settings.mode {
+                match CaptureMode::parse(mode) {
+                    Some(mode) => {
+                        pipeline.set_mode(mode);
+                        applied.push("mode".to_string());
+                    }
+                    None => skip("mode", format!("Invalid mode '{}'", mode)),
+                }
+            }
+            if let Some(ref name) = settings.demosaic {
+                match DemosaicAlgorithm::parse(name) {
+                    Some(algorithm) => {
+                        pipeline.set_demosaic(algorithm);
+                        applied.push("demosaic".to_string());
+                    }
+                    None => skip("demosaic", format!("Invalid demosaic '{}'", name)),
+                }
+            }
+            match settings.quality {
+                Some(profiles::QualitySetting::Fixed { quality }) => match quality {
+                    Some(quality) if !(1..=100).contains(&quality) => {
+                        skip("quality", format!("Quality {} must be within 1-100", quality));
+                    }
+                    _ => {
+                        if let Some(quality) = quality {
+                            pipeline.set_jpeg_quality(quality);
+                        }
+                        pipeline.set_adaptive_quality(None);
+                        applied.push("quality".to_string());
+                    }
+                },
+                Some(profiles::QualitySetting::Auto { min, max, target_kb, target_ms }) => {
+                    if target_kb.is_none() && target_ms.is_none() {
+                        skip("quality", "Auto quality needs target_kb and/or target_ms".to_string());
+                    } else if !(1..=100).contains(&min) || !(min..=100).contains(&max) {
+                        skip("quality", format!("Quality range {}-{} must be within 1-100", min, max));
+                    } else {
+                        pipeline.set_adaptive_quality(Some(AdaptiveQuality::new(
+                            target_kb.map(|kb| kb * 1024),
+                            target_ms,
+                            min,
+                            max,
+                        )));
+                        applied.push("quality".to_string());
+                    }
+                }
+                None => {}
+            }
+            if let Some(gamma) = settings.gamma {
+                if (capture::MIN_GAMMA..=capture::MAX_GAMMA).contains(&gamma) {
+                    pipeline.set_gamma(gamma);
+                    applied.push("gamma".to_string());
+                } else {
+                    skip("gamma", format!("Must be between {} and {}", capture::MIN_GAMMA, capture::MAX_GAMMA));
+                }
+            }
+            if let Some(ratio) = settings.hdr_ratio {
+                if (hdr::MIN_RATIO..=hdr::MAX_RATIO).contains(&ratio) {
+                    pipeline.set_hdr_ratio(ratio);
+                    applied.push("hdr_ratio".to_string());
+                } else {
+                    skip("hdr_ratio", format!("Must be between {} and {}", hdr::MIN_RATIO, hdr::MAX_RATIO));
+                }
+            }
+            if let Some(enabled) = settings.white_balance {
+                pipeline.set_white_balance(enabled);
+                applied.push("white_balance".to_string());
+            }
+            if let Some(strength) = settings.tonemap_strength {
+                if (0.0..=1.0).contains(&strength) {
+                    pipeline.set_tonemap_strength(strength);
+                    applied.push("tonemap_strength".to_string());
+                } else {
+                    skip("tonemap_strength", "Must be between 0.0 and 1.0".to_string());
+                }
+            }
+            if let Some(black_level) = settings.black_level {
+                if black_level <= 1022 {
+                    pipeline.set_black_level(black_level);
+                    applied.push("black_level".to_string());
+                } else {
+                    skip("black_level", "Must be at most 1022".to_string());
+                }
+            }
+            if let Some(row_noise) = settings.row_noise {
+                if (0.0..=1.0).contains(&row_noise.strength) {
+                    pipeline.set_row_noise_correction(row_noise.enabled, row_noise.strength);
+                    applied.push("row_noise".to_string());
+                } else {
+                    skip("row_noise", "Strength must be between 0.0 and 1.0".to_string());
+                }
+            }
+        });
+    }
+    drop(capture_guard);
+    
+    if let Some(metering) = settings.metering {
+        let roi = metering.roi.map(|r| Roi::new(r.x, r.y, r.w, r.h)).transpose();
+        match roi {
+            Ok(roi) => {
+                state.pipeline.update(|s| s.set_metering(MeteringConfig { roi, ..metering }));
+                applied.push("metering".to_string());
+            }
+            Err(e) => skip("metering", e),
+        }
+    }
+    if let Some(detection) = settings.detection {
+        if detection.enabled && state.detector.read().is_none() {
+            skip("detection", "Detector not available".to_string());
+        } else {
+            set_detection_enabled(state, detection.enabled);
+            state.scheduler.write().manual_change(Feature::Detection, detection.enabled, chrono::Utc::now());
+            *state.detection_interval.write() = detection.interval;
+            applied.push("detection".to_string());
+        }
+    }
+    (applied, skipped)
+}
+
+/// Apply the config file's sensor controls and detection settings (the
+/// processing settings go through `ConfigFile::apply_pipeline`)
+fn apply_config_live(state: &AppState, file: &config::ConfigFile) -> (Vec<String>, Vec<Skipped>) {
+    let controls = Settings { controls: file.capture.controls.clone(), ..Settings::default() };
+    let (mut applied, mut skipped) = apply_settings(state, &controls);
+    if let Some(interval) = file.detector.interval {
+        *state.detection_interval.write() = interval;
+        applied.push("interval".to_string());
+    }
+    if let Some(enabled) = file.detector.enabled {
+        if enabled && state.detector.read().is_none() {
+            skipped.push(Skipped { setting: "enabled".to_string(), reason: "Detector not available".to_string() });
+        } else {
+            set_detection_enabled(state, enabled);
+            state.scheduler.write().manual_change(Feature::Detection, enabled, chrono::Utc::now());
+            applied.push("enabled".to_string());
+        }
+    }
+    if file.zones != state.detector_config.read().zones {
+        state.config.write().1.zones = file.zones.clone();
+        set_zones(state, file.zones.clone());
+        applied.push("zones".to_string());
+    }
+    (applied, skipped)
+}
+
+/// Re-read the config file and apply what can change at runtime
+#[utoipa::path(
+    post,
+    path = "/config/reload",
+    tag = "config",
+    responses(
+        (status = 200, description = "Applied and skipped settings, and changes that need a restart", body = Object),
+        (status = 400, description = "The config file is invalid", body = ApiError),
+        (status = 404, description = "No config file", body = ApiError),
+    )
+)]
+async fn config_reload_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let path = state.config.read().0.clone();
+    let file = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            if !path.exists() {
+                return Err(ApiError::not_found(format!("No config file at {}", path.display())));
+            }
+            config::ConfigFile::load(&path).map_err(|e| ApiError::bad_request(format!("{:#}", e)))
+        })
+        .await??
+    };
+    let mut applied: Vec<String> = Vec::new();
+    state.pipeline.update(|pipeline| {
+        applied = file.apply_pipeline(pipeline).into_iter().map(str::to_string).collect();
+    });
+    let (live_applied, skipped) = {
+        let state = state.clone();
+        let file = file.clone();
+        tokio::task::spawn_blocking(move || apply_config_live(&state, &file)).await?
+    };
+    applied.extend(live_applied);
+    let restart_required = file.restart_required(&state.config.read().1);
+    info!(
+        "Reloaded {} ({}){}",
+        path.display(),
+        applied.join(", "),
+        if restart_required.is_empty() {
+            String::new()
+        } else {
+            format!("; restart to apply {}", restart_required.join(", "))
+        }
+    );
+    for skip in &skipped {
+        tracing::warn!("Config file: {} not applied: {}", skip.setting, skip.reason);
+    }
+    Ok(axum::Json(serde_json::json!({
+        "path": path,
+        "applied": applied,
+        "skipped": skipped,
+        "restart_required": restart_required,
+        "success": true
+    })))
+}
+
+/// Make `zones` the ones the detector tags, restarting zone events
+fn set_zones(state: &AppState, zones: Vec<Zone>) {
+    if let Some(ref detector) = *state.detector.read() {
+        detector.set_zones(zones.clone());
+    }
+    zones::tag(&zones, &mut state.last_detections.write());
+    for event in state.zone_events.lock().reset() {
+        state.events.publish(event);
+    }
+    info!("Zones: {}", zones.iter().map(|z| z.name.as_str()).collect::<Vec<_>>().join(", "));
+    state.detector_config.write().zones = zones;
+}
+
+/// Edit the zone list, write it to the config file, then make it current
+fn update_zones<T>(state: &AppState, edit: impl FnOnce(&mut Vec<Zone>) -> ApiResult<T>) -> ApiResult<T> {
+    let mut config = state.config.write();
+    let mut zones = config.1.zones.clone();
+    let result = edit(&mut zones)?;
+    zones::validate(&zones).map_err(ApiError::bad_request)?;
+    config::write_zones(&config.0, &zones)?;
+    config.1.zones = zones.clone();
+    drop(config);
+    set_zones(state, zones);
+    Ok(result)
+}
+
+fn no_zone(name: &str) -> ApiError {
+    ApiError::not_found(format!("No zone '{}'", name))
+}
+
+/// Configured zones (`[[zones]]` in the config file)
+#[utoipa::path(
+    get,
+    path = "/zones",
+    tag = "zones",
+    responses(
+        (status = 200, description = "Zones and the config file they are kept in", body = Object),
+    )
+)]
+async fn zones_list_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let config = state.config.read();
+    axum::Json(serde_json::json!({
+        "zones": config.1.zones,
+        "path": config.0,
+    }))
+}
+
+/// One zone
+#[utoipa::path(
+    get,
+    path = "/zones/{name}",
+    tag = "zones",
+    params(("name" = String, Path, description = "Zone name")),
+    responses(
+        (status = 200, description = "The zone", body = Zone),
+        (status = 404, description = "No such zone", body = ApiError),
+    )
+)]
+async fn zone_handler(State(state): State<SharedState>, Path(name): Path<String>) -> ApiResult<axum::Json<Zone>> {
+    let config = state.config.read();
+    let zone = config.1.zones.iter().find(|z| z.name == name).ok_or_else(|| no_zone(&name))?;
+    Ok(axum::Json(zone.clone()))
+}
+
+/// Create or replace a zone; saved to the config file and applied to the
+/// next detection
+#[utoipa::path(
+    put,
+    path = "/zones/{name}",
+    tag = "zones",
+    params(("name" = String, Path, description = "Zone name (letters, digits, `_`, `-`)")),
+    request_body = Zone,
+    responses(
+        (status = 200, description = "Zone saved", body = Object),
+        (status = 400, description = "Invalid zone", body = ApiError),
+        (status = 500, description = "Config file not writable", body = ApiError),
+    )
+)]
+async fn zone_put_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    zone: Result<axum::Json<Zone>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(zone) = zone?;
+    let zone = Zone { name, ..zone };
+    zone.validate().map_err(ApiError::bad_request)?;
+    let saved = zone.clone();
+    let created = tokio::task::spawn_blocking(move || {
+        update_zones(&state, |zones| {
+            Ok(match zones.iter_mut().find(|z| z.name == zone.name) {
+                Some(existing) => {
+                    *existing = zone;
+                    false
+                }
+                None => {
+                    zones.push(zone);
+                    true
+                }
+            })
+        })
+    })
+    .await??;
+    info!("{} zone '{}'", if created { "Created" } else { "Replaced" }, saved.name);
+    Ok(axum::Json(serde_json::json!({
+        "zone": saved,
+        "created": created,
+        "success": true
+    })))
+}
+
+/// Delete a zone from the config file
+#[utoipa::path(
+    delete,
+    path = "/zones/{name}",
+    tag = "zones",
+    params(("name" = String, Path, description = "Zone name")),
+    responses(
+        (status = 200, description = "Zone deleted", body = Object),
+        (status = 404, description = "No such zone", body = ApiError),
+        (status = 500, description = "Config file not writable", body = ApiError),
+    )
+)]
+async fn zone_delete_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let deleted = name.clone();
+    tokio::task::spawn_blocking(move || {
+        update_zones(&state, |zones| {
+            let before = zones.len();
+            zones.retain(|z| z.name != name);
+            if zones.len() == before {
+                return Err(no_zone(&name));
+            }
+            Ok(())
+        })
+    })
+    .await??;
+    info!("Deleted zone '{}'", deleted);
+    Ok(axum::Json(serde_json::json!({ "deleted": deleted, "success": true })))
+}
+
+/// Edit the privacy masks, write them to the config file, then publish
+/// them for the next frame
+fn update_masks<T>(state: &AppState, edit: impl FnOnce(&mut Vec<Mask>) -> ApiResult<T>) -> ApiResult<T> {
+    let mut config = state.config.write();
+    let mut masks = state.pipeline.load().masks.clone();
+    let result = edit(&mut masks)?;
+    masks::validate(&masks).map_err(ApiError::bad_request)?;
+    config::write_masks(&config.0, &masks)?;
+    config.1.masks = masks.clone();
+    drop(config);
+    state.pipeline.update(|s| s.set_masks(masks));
+    Ok(result)
+}
+
+fn no_mask(name: &str) -> ApiError {
+    ApiError::not_found(format!("No mask '{}'", name))
+}
+
+/// Privacy masks (`[[masks]]` in the config file)
+#[utoipa::path(
+    get,
+    path = "/masks",
+    tag = "masks",
+    responses(
+        (status = 200, description = "Masks and the config file they are kept in", body = Object),
+    )
+)]
+async fn masks_list_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    axum::Json(serde_json::json!({
+        "masks": state.pipeline.load().masks,
+        "path": state.config.read().0,
+    }))
+}
+
+/// One mask
+#[utoipa::path(
+    get,
+    path = "/masks/{name}",
+    tag = "masks",
+    params(("name" = String, Path, description = "Mask name")),
+    responses(
+        (status = 200, description = "The mask", body = Mask),
+        (status = 404, description = "No such mask", body = ApiError),
+    )
+)]
+async fn mask_handler(State(state): State<SharedState>, Path(name): Path<String>) -> ApiResult<axum::Json<Mask>> {
+    let published = state.pipeline.load();
+    let mask = published.masks.iter().find(|m| m.name == name).ok_or_else(|| no_mask(&name))?;
+    Ok(axum::Json(mask.clone()))
+}
+
+/// Create or replace a mask, e.g. `{"rect": [0.72, 0.1, 0.12, 0.2],
+/// "style": "pixelate"}`; saved to the config file and applied from the
+/// next frame
+#[utoipa::path(
+    put,
+    path = "/masks/{name}",
+    tag = "masks",
+    params(("name" = String, Path, description = "Mask name (letters, digits, `_`, `-`)")),
+    request_body = Mask,
+    responses(
+        (status = 200, description = "Mask saved", body = Object),
+        (status = 400, description = "Invalid mask", body = ApiError),
+        (status = 500, description = "Config file not writable", body = ApiError),
+    )
+)]
+async fn mask_put_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    mask: Result<axum::Json<Mask>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(mask) = mask?;
+    let mask = Mask { name, ..mask };
+    mask.validate().map_err(ApiError::bad_request)?;
+    let saved = mask.clone();
+    let created = tokio::task::spawn_blocking(move || {
+        update_masks(&state, |masks| {
+            Ok(match masks.iter_mut().find(|m| m.name == mask.name) {
+                Some(existing) => {
+                    *existing = mask;
+                    false
+                }
+                None => {
+                    masks.push(mask);
+                    true
+                }
+            })
+        })
+    })
+    .await??;
+    info!("{} mask '{}'", if created { "Created" } else { "Replaced" }, saved.name);
+    Ok(axum::Json(serde_json::json!({
+        "mask": saved,
+        "created": created,
+        "success": true
+    })))
+}
+
+/// Delete a mask from the config file
+#[utoipa::path(
+    delete,
+    path = "/masks/{name}",
+    tag = "masks",
+    params(("name" = String, Path, description = "Mask name")),
+    responses(
+        (status = 200, description = "Mask deleted", body = Object),
+        (status = 404, description = "No such mask", body = ApiError),
+        (status = 500, description = "Config file not writable", body = ApiError),
+    )
+)]
+async fn mask_delete_handler(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let deleted = name.clone();
+    tokio::task::spawn_blocking(move || {
+        update_masks(&state, |masks| {
+            let before = masks.len();
+            masks.retain(|m| m.name != name);
+            if masks.len() == before {
+                return Err(no_mask(&name));
+            }
+            Ok(())
+        })
+    })
+    .await??;
+    info!("Deleted mask '{}'", deleted);
+    Ok(axum::Json(serde_json::json!({ "deleted": deleted, "success": true })))
+}
+
+/// Validate and start a capture job (POST a JSON array of steps)
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    tag = "jobs",
+    request_body = Vec<jobs::Step>,
+    responses(
+        (status = 200, description = "Job started", body = Object),
+        (status = 400, description = "Invalid steps", body = ApiError),
+        (status = 409, description = "Another job is running", body = ApiError),
+        (status = 503, description = "Camera not available", body = ApiError),
+    )
+)]
+async fn jobs_create_handler(
+    State(state): State<SharedState>,
+    steps: Result<axum::Json<Vec<Step>>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(steps) = steps?;
+    let subdev = state.capture.read().as_ref().map(|c| c.config().sensor_subdev.clone());
+    let subdev = subdev.ok_or_else(ApiError::camera_unavailable)?;
+    let job_running = |running| ApiError::conflict(format!("Job {} is still running", running));
+    if let Some(running) = state.jobs.read().running() {
+        return Err(job_running(running));
+    }
+    
+    let needs_controls = steps.iter().any(|s| matches!(s, Step::SetControl { .. }));
+    let controls = if needs_controls {
+        tokio::task::spawn_blocking(move || controls::list_controls(&subdev))
+            .await?
+            .map_err(|e| ApiError::unavailable(format!("{:#}", e)))?
+    } else {
+        Vec::new()
+    };
+    jobs::validate(&steps, &controls, |mode| CaptureMode::parse(mode).is_some()).map_err(ApiError::bad_request)?;
+    
+    let step_count = steps.len();
+    let (id, cancelled) = state.jobs.write().start(steps, unix_millis()).map_err(job_running)?;
+    info!("Job {} started ({} steps)", id, step_count);
+    tokio::spawn(run_job(state.clone(), id, cancelled));
+    
+    Ok(axum::Json(serde_json::json!({
+        "id": id,
+        "state": JobState::Running,
+        "steps": step_count,
+        "success": true
+    })))
+}
+
+/// Recent jobs, newest last
+#[utoipa::path(
+    get,
+    path = "/jobs",
+    tag = "jobs",
+    responses(
+        (status = 200, description = "Recent jobs, newest last", body = Object),
+    )
+)]
+async fn jobs_list_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let jobs = state.jobs.read();
+    axum::Json(serde_json::json!({
+        "running": jobs.running(),
+        "jobs": jobs.list()
+    }))
+}
+
+fn no_job(id: u64) -> ApiError {
+    ApiError::not_found(format!("No job {}", id))
+}
+
+/// Per-step status, outputs and errors of one job
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    tag = "jobs",
+    params(("id" = u64, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "Per-step status, outputs and errors", body = jobs::Job),
+        (status = 404, description = "No such job", body = ApiError),
+    )
+)]
+async fn job_handler(State(state): State<SharedState>, Path(id): Path<u64>) -> ApiResult<Response> {
+    match state.jobs.read().get(id) {
+        Some(job) => Ok(axum::Json(job).into_response()),
+        None => Err(no_job(id)),
+    }
+}
+
+/// Cancel a running job (its control changes are rolled back)
+#[utoipa::path(
+    delete,
+    path = "/jobs/{id}",
+    tag = "jobs",
+    params(("id" = u64, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "Cancel requested (false if the job had finished)", body = Object),
+        (status = 404, description = "No such job", body = ApiError),
+    )
+)]
+async fn job_cancel_handler(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let cancelled = state.jobs.read().cancel(id).ok_or_else(|| no_job(id))?;
+    Ok(axum::Json(serde_json::json!({
+        "id": id,
+        "cancelled": cancelled,
+        "success": true
+    })))
+}
+
+/// Settings a job changed, with their values before the job
+#[derive(Default)]
+struct JobChanges {
+    controls: Vec<(String, i64)>,
+    mode: Option<CaptureMode>,
+}
+
+/// Execute a job's steps in order; on failure or cancel, put back what it changed
+async fn run_job(state: SharedState, id: u64, mut cancelled: watch::Receiver<bool>) {
+    let steps: Vec<Step> = match state.jobs.read().get(id) {
+        Some(job) => job.steps.iter().map(|s| s.step.clone()).collect(),
+        None => return,
+    };
+    let mut changes = JobChanges::default();
+    let mut outcome = JobState::Succeeded;
+    let mut error = None;
+    
+    for (index, step) in steps.iter().enumerate() {
+        if *cancelled.borrow() {
+            outcome = JobState::Cancelled;
+            break;
+        }
+        if let Some(job) = state.jobs.write().get_mut(id) {
+            job.steps[index].state = StepState::Running;
+        }
+        let result = run_job_step(&state, id, index, step, &mut changes, &mut cancelled).await;
+        let mut jobs = state.jobs.write();
+        let Some(status) = jobs.get_mut(id).map(|job| &mut job.steps[index]) else {
+            return;
+        };
+        match result {
+            Ok(output) => {
+                status.state = StepState::Done;
+                status.output = output;
+            }
+            Err(_) if *cancelled.borrow() => {
+                status.state = StepState::Skipped;
+                outcome = JobState::Cancelled;
+                break;
+            }
+            Err(e) => {
+                status.state = StepState::Failed;
+                status.error = Some(format!("{:#}", e));
+                error = Some(format!("Step {} failed: {:#}", index, e));
+                outcome = JobState::Failed;
+                break;
+            }
+        }
+    }
+    
+    let restored = if outcome == JobState::Succeeded {
+        Vec::new()
+    } else {
+        restore_job_changes(&state, changes).await
+    };
+    match outcome {
+        JobState::Failed => tracing::warn!("Job {} failed: {}", id, error.as_deref().unwrap_or("")),
+        _ => info!("Job {} {:?}", id, outcome),
+    }
+    if let Some(job) = state.jobs.write().get_mut(id) {
+        job.skip_remaining();
+        job.state = outcome;
+        job.error = error;
+        job.restored = restored;
+        job.finished_ms = Some(unix_millis());
+    }
+}
+
+/// Run one step; returns the snapshot path for snapshot steps
+async fn run_job_step(
+    state: &SharedState,
+    id: u64,
+    index: usize,
+    step: &Step,
+    changes: &mut JobChanges,
+    cancelled: &mut watch::Receiver<bool>,
+) -> Result<Option<String>> {
+    match step {
+        Step::SetControl { name, value } => {
+            let subdev = state
+                .capture
+                .read()
+                .as_ref()
+                .map(|c| c.config().sensor_subdev.clone())
+                .ok_or_else(|| anyhow::anyhow!("Camera not available"))?;
+            let (name, value) = (name.clone(), *value);
+            let first_change = !changes.controls.iter().any(|(n, _)| *n == name);
+            let previous = tokio::task::spawn_blocking(move || {
+                let previous = if first_change { controls::find_control(&subdev, &name)?.value } else { None };
+                controls::set_control(&subdev, &name, value)?;
+                anyhow::Ok((name, previous))
+            })
+            .await??;
+            if let (name, Some(previous)) = previous {
+                changes.controls.push((name, previous));
+            }
+            Ok(None)
+        }
+        Step::WaitFrames { frames } => {
+            wait_for_frames(state, *frames, cancelled).await?;
+            Ok(None)
+        }
+        Step::WaitMs { ms } => {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(*ms)) => Ok(None),
+                _ = cancelled.wait_for(|c| *c) => anyhow::bail!("Cancelled"),
+            }
+        }
+        Step::SetMode { mode } => {
+            let mode = CaptureMode::parse(mode).ok_or_else(|| anyhow::anyhow!("Invalid mode '{}'", mode))?;
+            changes.mode.get_or_insert(state.pipeline.load().mode);
+            apply_mode(state, mode);
+            Ok(None)
+        }
+        Step::Snapshot { .. } | Step::StillCapture { .. } => {
+            if matches!(step, Step::StillCapture { .. }) {
+                wait_for_frames(state, 1, cancelled).await?;
+            }
+            let name = step.output_name(id, index).unwrap_or_default();
+            save_job_snapshot(state, &name).await.map(Some)
+        }
+    }
+}
+
+/// Wait until `frames` more frames have been captured
+async fn wait_for_frames(state: &AppState, frames: u64, cancelled: &mut watch::Receiver<bool>) -> Result<()> {
+    let mut frame_rx = state.frame_watch.subscribe();
+    let start = frame_rx.borrow_and_update().as_ref().map_or(0, |f| f.seq);
+    let target = start + frames;
+    loop {
+        tokio::select! {
+            changed = tokio::time::timeout(JOB_FRAME_TIMEOUT, frame_rx.changed()) => match changed {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => anyhow::bail!("Frame source closed"),
+                Err(_) => anyhow::bail!("No frame captured for {:?}", JOB_FRAME_TIMEOUT),
+            },
+            _ = cancelled.wait_for(|c| *c) => anyhow::bail!("Cancelled"),
+        }
+        if frame_rx.borrow_and_update().as_ref().is_some_and(|f| f.seq >= target) {
+            return Ok(());
+        }
+    }
+}
+
+/// Add EXIF to a still of the latest frame: its capture time, the sensor's
+/// exposure and gain, and the latest detections while detection is on
+async fn with_still_exif(state: &AppState, jpeg: Vec<u8>) -> Result<Vec<u8>> {
+    let timestamp_ms = state.frame_watch.borrow().as_ref().map_or_else(unix_millis, |f| f.timestamp_ms);
+    let mut fields = exif::ExifFields::new(timestamp_ms);
+    if *state.detection_enabled.read() {
+        let result = state.last_detections.read();
+        if !result.detections.is_empty() {
+            fields.description = serde_json::to_string(&serde_json::json!({
+                "frame_seq": result.frame_seq,
+                "detections": result.detections,
+            }))
+            .ok();
+        }
+    }
+    let sensor = state.capture.read().as_ref().map(|c| (c.config().sensor_subdev.clone(), c.frame_size().width));
+    
+    let jpeg = tokio::task::spawn_blocking(move || {
+        if let Some((subdev, width)) = sensor {
+            match controls::list_controls(&subdev) {
+                Ok(controls) => fields = fields.with_sensor_controls(&controls, width),
+                Err(e) => tracing::warn!("No sensor controls for EXIF: {:#}", e),
+            }
+        }
+        match exif::insert(&jpeg, &fields) {
+            Ok(tagged) => tagged,
+            Err(e) => {
+                tracing::warn!("Failed to add EXIF: {:#}", e);
+                jpeg
+            }
+        }
+    })
+    .await?;
+    Ok(jpeg)
+}
+
+/// The current frame encoded at snapshot quality, with EXIF
+async fn encode_still(state: &AppState) -> Result<Vec<u8>> {
+    let image = state
+        .current_image
+        .read()
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("No frame available"))?;
+    let quality = state.pipeline.load().snapshot_quality();
+    let jpeg = tokio::task::spawn_blocking(move || capture::encode_image_jpeg(&image, quality)).await??;
+    with_still_exif(state, jpeg).await
+}
+
+/// Encode the current frame at snapshot quality into the job output directory
+async fn save_job_snapshot(state: &AppState, name: &str) -> Result<String> {
+    let jpeg = encode_still(state).await?;
+    let dir = state.job_output_dir.read().clone();
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(format!("{}.jpg", name));
+    tokio::fs::write(&path, jpeg)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path.display().to_string())
+}
+
+/// Put back controls and mode changed by an unfinished job; returns what was restored
+async fn restore_job_changes(state: &SharedState, changes: JobChanges) -> Vec<String> {
+    let mut restored = Vec::new();
+    if let Some(mode) = changes.mode {
+        apply_mode(state, mode);
+        restored.push("mode".to_string());
+    }
+    let subdev = state.capture.read().as_ref().map(|c| c.config().sensor_subdev.clone());
+    let Some(subdev) = subdev else {
+        return restored;
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let mut restored = Vec::new();
+        for (name, value) in changes.controls.into_iter().rev() {
+            match controls::set_control(&subdev, &name, value) {
+                Ok(()) => restored.push(name),
+                Err(e) => tracing::warn!("Failed to restore {}={}: {:#}", name, value, e),
+            }
+        }
+        restored
+    })
+    .await;
+    restored.extend(result.unwrap_or_default());
+    restored
+}
+
+/// Pipeline statistics endpoint
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "stats",
+    responses(
+        (status = 200, description = "Frame, detector, memory and push statistics", body = Object),
+    )
+)]
+async fn stats_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let frame_count = *state.frame_count.read();
+    
+    axum::Json(serde_json::json!({
+        "frame_count": frame_count,
+        "detector": detector_stats_json(&state),
+        "events": {
+            "published": state.events.published(),
+            "counts": state.event_counters.snapshot(),
+            "subscribers": state.events.subscriber_stats()
+        },
+        "memory": state.memory.snapshot(),
+        "decode_cache": state.decode_cache.stats(),
+        "scaled_frames": state.scaled_frames.stats()
+    }))
+}
+
+/// Luminance of the latest frame: full-frame mean and the metered value
+#[utoipa::path(
+    get,
+    path = "/stats/image",
+    tag = "stats",
+    responses(
+        (status = 200, description = "Mean and metered luminance of the latest frame", body = Object),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+    )
+)]
+async fn image_stats_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let image = state.current_image.read().clone().ok_or_else(ApiError::no_frame)?;
+    let metering = state.pipeline.load().metering;
+    let seq = *state.frame_count.read();
+    let luminance = tokio::task::spawn_blocking(move || metering::measure(&image, &metering)).await?;
+    Ok(axum::Json(serde_json::json!({
+        "seq": seq,
+        "luminance": luminance,
+        "metering": metering
+    })))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistogramParams {
+    /// Also return the red, green and blue histograms (color mode only)
+    rgb: Option<bool>,
+}
+
+/// Luminance histogram of the latest frame, as computed by the pipeline
+/// (the data auto-exposure meters)
+#[utoipa::path(
+    get,
+    path = "/histogram",
+    tag = "stats",
+    params(HistogramParams),
+    responses(
+        (status = 200, description = "256-bin histograms of the latest frame", body = Object),
+        (status = 409, description = "RGB histograms asked for in a grayscale mode", body = ApiError),
+        (status = 503, description = "No frame processed yet", body = ApiError),
+    )
+)]
+async fn histogram_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<HistogramParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let (seq, histogram) = state.histogram.read().clone().ok_or_else(ApiError::no_frame)?;
+    let mut body = serde_json::json!({
+        "seq": seq,
+        "step": capture::HISTOGRAM_STEP,
+        "samples": histogram.samples(),
+        "mean": histogram.mean(),
+        // Fractions crushed to black and clipped (as auto-exposure counts it)
+        "black": histogram.fraction(..1),
+        "clipped": histogram.fraction(AutoExposure::CLIP_BIN..),
+        "luma": &histogram.luma[..],
+    });
+    if params.rgb.unwrap_or(false) {
+        let [r, g, b] = histogram
+            .rgb
+            .as_deref()
+            .ok_or_else(|| ApiError::conflict("RGB histograms need color mode"))?;
+        body["rgb"] = serde_json::json!({ "r": &r[..], "g": &g[..], "b": &b[..] });
+    }
+    Ok(axum::Json(body))
+}
+
+/// Sharpness of the latest frame over the focus region, for turning the
+/// lens by the numbers
+#[utoipa::path(
+    get,
+    path = "/focus",
+    tag = "stats",
+    responses(
+        (status = 200, description = "Latest focus score", body = FocusReading),
+        (status = 503, description = "No frame scored yet", body = ApiError),
+    )
+)]
+async fn focus_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<FocusReading>> {
+    let reading = state.focus.lock().reading().ok_or_else(ApiError::no_frame)?;
+    Ok(axum::Json(reading))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+struct FocusRequest {
+    /// Region to score, in fractions of the published frame
+    roi: Option<Roi>,
+    /// Start a new peak
+    #[serde(default)]
+    reset_peak: bool,
+}
+
+/// Set the focus region (a new region starts a new peak) or reset the peak
+#[utoipa::path(
+    post,
+    path = "/focus",
+    tag = "stats",
+    request_body = FocusRequest,
+    responses(
+        (status = 200, description = "Region now scored", body = Roi),
+        (status = 400, description = "Invalid region", body = ApiError),
+    )
+)]
+async fn focus_set_handler(
+    State(state): State<SharedState>,
+    request: Result<axum::Json<FocusRequest>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<Roi>> {
+    let axum::Json(request) = request?;
+    let mut focus = state.focus.lock();
+    if let Some(roi) = request.roi {
+        let roi = Roi::new(roi.x, roi.y, roi.w, roi.h).map_err(ApiError::bad_request)?;
+        if roi != focus.roi() {
+            info!("Focus region: {:?}", roi);
+        }
+        focus.set_roi(roi);
+    }
+    if request.reset_peak {
+        focus.reset_peak();
+    }
+    Ok(axum::Json(focus.roi()))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LatencyParams {
+    /// Frames to summarize (default and maximum: the tracking window)
+    frames: Option<usize>,
+}
+
+/// Pipeline segment and per-client latency over the last `?frames=N`
+/// frames (at most `latency::WINDOW`)
+#[utoipa::path(
+    get,
+    path = "/stats/latency",
+    tag = "stats",
+    params(LatencyParams),
+    responses(
+        (status = 200, description = "Latency distributions", body = latency::LatencyReport),
+    )
+)]
+async fn latency_stats_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<LatencyParams>,
+) -> axum::Json<latency::LatencyReport> {
+    let frames = params.frames.unwrap_or(latency::WINDOW).clamp(1, latency::WINDOW);
+    axum::Json(state.latency.report(frames))
+}
+
+/// Bytes sent per route, open streams and the daily budget
+#[utoipa::path(
+    get,
+    path = "/stats/bandwidth",
+    tag = "stats",
+    responses(
+        (status = 200, description = "Outbound bytes, open streams and the daily budget", body = bandwidth::BandwidthStats),
+    )
+)]
+async fn bandwidth_stats_handler(State(state): State<SharedState>) -> axum::Json<bandwidth::BandwidthStats> {
+    axum::Json(state.bandwidth.stats())
+}
+
+/// Draw the blink marker on the next published frame and report which
+/// frame it appeared in, for correlating an external glass-to-glass
+/// measurement with the stream
+#[utoipa::path(
+    post,
+    path = "/latency/blink",
+    tag = "stats",
+    responses(
+        (status = 200, description = "Sequence and publish time of the marked frame", body = Object),
+        (status = 409, description = "A blink is already pending", body = ApiError),
+        (status = 503, description = "Camera not available or no frame published in time", body = ApiError),
+    )
+)]
+async fn latency_blink_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    if state.capture.read().is_none() {
+        return Err(ApiError::camera_unavailable());
+    }
+    let (marked, marked_rx) = oneshot::channel();
+    {
+        let mut pending = state.blink.lock();
+        if pending.as_ref().is_some_and(|p| !p.is_closed()) {
+            return Err(ApiError::conflict("A blink is already pending"));
+        }
+        *pending = Some(marked);
+    }
+    match tokio::time::timeout(BLINK_TIMEOUT, marked_rx).await {
+        Ok(Ok((seq, timestamp_ms))) => Ok(axum::Json(serde_json::json!({
+            "seq": seq,
+            "timestamp_ms": timestamp_ms,
+            "corner": "top_left",
+            "success": true
+        }))),
+        _ => {
+            state.blink.lock().take();
+            Err(ApiError::unavailable(format!("No frame published within {:?}", BLINK_TIMEOUT)))
+        }
+    }
+}
+
+/// Build metadata (version, commit, rustc, build time, features)
+#[utoipa::path(
+    get,
+    path = "/version",
+    tag = "status",
+    responses(
+        (status = 200, description = "Build metadata", body = version::BuildInfo),
+    )
+)]
+async fn version_handler() -> axum::Json<version::BuildInfo> {
+    axum::Json(version::build_info())
+}
+
+/// Data directory and per-category disk usage
+#[utoipa::path(
+    get,
+    path = "/storage",
+    tag = "status",
+    responses(
+        (status = 200, description = "Data directory, layout manifest and disk usage per category", body = Object),
+        (status = 500, description = "The manifest could not be read", body = ApiError),
+    )
+)]
+async fn storage_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let layout = state.storage.read().clone();
+    let models_dir = state.detector_config.read().models_dir.clone();
+    let value = tokio::task::spawn_blocking(move || -> Result<serde_json::Value> {
+        let manifest = storage::read_manifest(&layout)?;
+        let mut categories = serde_json::Map::new();
+        let mut total_bytes = 0;
+        let paths = layout.categories().into_iter().chain([("models", models_dir.as_path())]);
+        for (name, path) in paths {
+            let usage = storage::usage(path);
+            total_bytes += usage.bytes;
+            categories.insert(
+                name.to_string(),
+                serde_json::json!({ "path": path, "files": usage.files, "bytes": usage.bytes }),
+            );
+        }
+        Ok(serde_json::json!({
+            "data_dir": layout.data_dir,
+            "layout_version": storage::LAYOUT_VERSION,
+            "manifest": manifest,
+            "categories": categories,
+            "total_bytes": total_bytes
+        }))
+    })
+    .await??;
+    Ok(axum::Json(value))
+}
+
+/// Prometheus metrics
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "stats",
+    responses(
+        (status = 200, description = "Prometheus text exposition", body = String, content_type = "text/plain"),
+    )
+)]
+async fn metrics_handler(State(state): State<SharedState>) -> Response {
+    let build = version::build_info();
+    let features = build.features.join(",");
+    let dirty = build.git_dirty.map_or("unknown", |d| if d { "true" } else { "false" });
+    let mut metrics = metrics::MetricsWriter::new();
+    metrics.labelled_gauge(
+        "build_info",
+        "Build metadata; always 1",
+        &[(
+            vec![
+                ("version", build.version),
+                ("commit", build.git_commit),
+                ("dirty", dirty),
+                ("rustc", build.rustc_version),
+                ("build_timestamp", build.build_timestamp),
+                ("features", &features),
+            ],
+            1.0,
+        )],
+    );
+    metrics.counter("frames_total", "Frames captured", *state.frame_count.read() as f64);
+    metrics.gauge(
+        "stream_clients",
+        "Open MJPEG stream connections",
+        state.stream_clients.load(Ordering::Relaxed) as f64,
+    );
+    let thermal = state.thermal.read().clone();
+    let zone_temps: Vec<_> = thermal
+        .zones
+        .iter()
+        .map(|z| (vec![("zone", z.zone.as_str()), ("type", z.kind.as_str())], z.temp_c as f64))
+        .collect();
+    metrics.labelled_gauge("thermal_zone_celsius", "SoC thermal zone temperature", &zone_temps);
+    if let Some(sensor_c) = thermal.sensor_c {
+        metrics.gauge("sensor_temperature_celsius", "IMX415 sensor temperature", sensor_c as f64);
+    }
+    metrics.gauge(
+        "thermal_throttled",
+        "1 while the thermal policy has reduced the capture rate",
+        if thermal.throttled { 1.0 } else { 0.0 },
+    );
+    let rejected: Vec<_> = ratelimit::RouteClass::ALL
+        .iter()
+        .map(|&c| (vec![("class", c.name())], state.rate_limiter.rejected(c) as f64))
+        .collect();
+    metrics.labelled_counter("rate_limited_total", "Requests rejected with 429", &rejected);
+    
+    let bandwidth = state.bandwidth.stats();
+    let sent: Vec<_> = bandwidth
+        .endpoints
+        .iter()
+        .map(|(endpoint, e)| (vec![("endpoint", endpoint.as_str())], e.bytes as f64))
+        .collect();
+    metrics.labelled_counter("http_response_bytes_total", "Response body bytes sent, by route", &sent);
+    metrics.counter("bandwidth_sent_bytes_total", "Response body bytes sent, all routes", bandwidth.total_bytes as f64);
+    if let Some(ref budget) = bandwidth.budget {
+        metrics.gauge("bandwidth_budget_bytes", "Daily outbound budget", budget.config.bytes_per_day as f64);
+        metrics.gauge("bandwidth_budget_used_bytes", "Bytes sent in the current budget window", budget.used_bytes as f64);
+        metrics.gauge(
+            "bandwidth_degradation_steps",
+            "Degradation steps in effect for the budget",
+            budget.engaged.len() as f64,
+        );
+        metrics.counter(
+            "stream_rejected_budget_total",
+            "Stream requests refused with 503 over the bandwidth budget",
+            budget.rejected_streams as f64,
+        );
+    }
+    
+    let counts = state.event_counters.snapshot();
+    let events: Vec<_> = counts.iter().map(|(&kind, &n)| (vec![("type", kind)], n as f64)).collect();
+    metrics.labelled_counter("events_total", "Domain events published, by type", &events);
+    let subscribers = state.events.subscriber_stats();
+    let dropped: Vec<_> = subscribers
+        .iter()
+        .map(|s| (vec![("subscriber", s.name.as_str())], s.dropped as f64))
+        .collect();
+    metrics.labelled_counter("event_dropped_total", "Events skipped by lagging subscribers", &dropped);
+    
+    let memory = &state.memory;
+    metrics.gauge("memory_budget_bytes", "Budget for retained frame data", memory.budget() as f64);
+    let usage: Vec<_> = memory::Component::ALL
+        .iter()
+        .map(|&c| (vec![("component", c.name())], memory.usage(c) as f64))
+        .collect();
+    metrics.labelled_gauge("memory_bytes", "Retained frame data by component", &usage);
+    metrics.gauge(
+        "memory_pressure",
+        "Degradation level: 0 normal, 1 shrinking, 2 shedding, 3 critical",
+        memory.pressure() as u8 as f64,
+    );
+    
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(metrics.finish()))
+        .unwrap()
+}
+
+/// Index page template (`{{name}}` placeholders are filled by `render_template`)
+const INDEX_TEMPLATE: &str = include_str!("index.html");
+
+/// Substitute `{{name}}` placeholders in a template
+fn render_template(template: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter().fold(template.to_string(), |html, (name, value)| {
+        html.replace(&format!("{{{{{}}}}}", name), value)
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "ui",
+    responses(
+        (status = 200, description = "Live view page", body = String, content_type = "text/html"),
+    )
+)]
+async fn index_handler(State(state): State<SharedState>) -> Html<String> {
+    let current_mode = state.pipeline.load().mode;
+    let auto_mode = state.day_night.lock().config().enabled;
+    let detection_enabled = *state.detection_enabled.read();
+    let detector_available = state.detector.read().is_some();
+    
+    let mode_str = if auto_mode { "auto" } else { current_mode.name() };
+    let mode_active = |mode| if !auto_mode && current_mode == mode { "active" } else { "" };
+    
+    let (detect_checked, detect_status, detect_status_class) = if !detector_available {
+        ("disabled", "unavailable", "")
+    } else if detection_enabled {
+        ("checked", "active", "active")
+    } else {
+        ("", "off", "")
+    };
+    
+    let mode_info = match current_mode {
+        _ if auto_mode => "🌓 Day/night • Color in daylight, grayscale in the dark",
+        CaptureMode::Grayscale => "✓ Artifact-free • Byte-4 extraction with row averaging",
+        CaptureMode::GrayscaleHdr => "🌗 Exposure fusion • Short/long pairs at half frame rate",
+        CaptureMode::Color => "🧪 Experimental • 10-bit Bayer demosaicing",
+        CaptureMode::ColorHdr => "🌇 Exposure fusion • Color from short/long pairs at half frame rate",
+    };
+    
+    let html = render_template(INDEX_TEMPLATE, &[
+        ("grayscale_active", mode_active(CaptureMode::Grayscale)),
+        ("grayscale_hdr_active", mode_active(CaptureMode::GrayscaleHdr)),
+        ("color_active", mode_active(CaptureMode::Color)),
+        ("color_hdr_active", mode_active(CaptureMode::ColorHdr)),
+        ("auto_active", if auto_mode { "active" } else { "" }),
+        ("mode_str", mode_str),
+        ("mode_info", mode_info),
+        ("detect_checked", detect_checked),
+        ("detect_status", detect_status),
+        ("detect_status_class", detect_status_class),
+        ("version", version::VERSION),
+        ("build", &version::describe()),
+    ]);
+    
+    Html(html)
+}
+
+/// Uncached JPEG body
+fn jpeg_response(jpeg: impl Into<Bytes>) -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/jpeg")
+        .header(header::CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .body(Body::from(jpeg.into()))
+        .unwrap()
+}
+
+#[utoipa::path(
+    get,
+    path = "/frame.jpg",
+    tag = "frames",
+    params(StreamParams),
+    responses(
+        (status = 200, description = "Latest frame (`X-Frame-Hash` carries its content hash)", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+    )
+)]
+async fn frame_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<StreamParams>,
+) -> ApiResult<Response> {
+    let params = params.budget_limited(&state.bandwidth);
+    let (frame, hash) = params.select_hashed_frame(&state).ok_or_else(ApiError::no_frame)?;
+    Ok(hashed_jpeg_response(params.apply(&state, frame, hash).await, hash))
+}
+
+/// JPEG response tagged with the published frame's hash (a per-request
+/// re-encode keeps the source frame's hash)
+fn hashed_jpeg_response(jpeg: Bytes, hash: u64) -> Response {
+    let mut response = jpeg_response(jpeg);
+    if let Ok(value) = header::HeaderValue::from_str(&framehash::to_hex(hash)) {
+        response.headers_mut().insert(framehash::HEADER, value);
+    }
+    response
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FrameChangedParams {
+    /// `X-Frame-Hash` of the frame the client already has
+    since_hash: Option<String>,
+    #[serde(default)]
+    view: StreamView,
+}
+
+/// The current frame if its hash differs from `?since_hash=`, else 204
+#[utoipa::path(
+    get,
+    path = "/frame/changed",
+    tag = "frames",
+    params(FrameChangedParams),
+    responses(
+        (status = 200, description = "Latest frame, its hash differs from `since_hash`", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 204, description = "The frame is unchanged"),
+        (status = 400, description = "`since_hash` is not a hex hash", body = ApiError),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+    )
+)]
+async fn frame_changed_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<FrameChangedParams>,
+) -> ApiResult<Response> {
+    let since = match params.since_hash.as_deref().filter(|h| !h.is_empty()) {
+        Some(hash) => Some(
+            framehash::parse_hex(hash)
+                .ok_or_else(|| ApiError::bad_request("since_hash must be a hex frame hash (X-Frame-Hash)"))?,
+        ),
+        None => None,
+    };
+    let (frame, hash) = params.view.select_hashed_frame(&state).ok_or_else(ApiError::no_frame)?;
+    if since == Some(hash) {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        if let Ok(value) = header::HeaderValue::from_str(&framehash::to_hex(hash)) {
+            response.headers_mut().insert(framehash::HEADER, value);
+        }
+        return Ok(response);
+    }
+    let params = StreamParams { view: params.view, ..Default::default() }.budget_limited(&state.bandwidth);
+    Ok(hashed_jpeg_response(params.apply(&state, frame, hash).await, hash))
+}
+
+/// Latest frame metadata: sequence, capture time, sizes, hashes and the
+/// pipeline settings version it was processed with
+#[utoipa::path(
+    get,
+    path = "/frame.json",
+    tag = "frames",
+    responses(
+        (status = 200, description = "Sequence, capture time, sizes, hashes and settings version of the latest frame", body = Object),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+    )
+)]
+async fn frame_json_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    // Held so the sizes are of the frames the hashes describe
+    let frame_hashes = state.frame_hashes.read();
+    let hashes = (*frame_hashes).ok_or_else(ApiError::no_frame)?;
+    let size = state.frame_watch.borrow().as_ref().map(|f| f.jpeg.len());
+    let clean_size = state.clean_frame.read().as_ref().map(|f| f.len());
+    drop(frame_hashes);
+    Ok(axum::Json(serde_json::json!({
+        "seq": hashes.seq,
+        "timestamp_ms": hashes.timestamp_ms,
+        "age_ms": unix_millis().saturating_sub(hashes.timestamp_ms),
+        "size": size,
+        "clean_size": clean_size,
+        "hash": framehash::to_hex(hashes.annotated),
+        "clean_hash": framehash::to_hex(hashes.clean),
+        "unchanged_frames": hashes.unchanged_frames,
+        "static_scene": hashes.unchanged_frames >= *state.static_scene_frames.read(),
+        "settings_version": hashes.settings_version
+    })))
+}
+
+/// Full-quality still of the current frame, encoded from the retained pixels
+#[utoipa::path(
+    get,
+    path = "/snapshot",
+    tag = "frames",
+    responses(
+        (status = 200, description = "Full-quality still of the latest frame", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+        (status = 500, description = "Encoding failed", body = ApiError),
+    )
+)]
+async fn snapshot_handler(State(state): State<SharedState>) -> ApiResult<Response> {
+    let jpeg = if state.bandwidth.is_engaged(Degradation::Streams) {
+        // Over budget: the low-resolution clean frame instead of a full encode
+        let params = StreamParams { view: StreamView::Clean, ..Default::default() }.budget_limited(&state.bandwidth);
+        let (frame, hash) = params.select_hashed_frame(&state).ok_or_else(ApiError::no_frame)?;
+        params.apply(&state, frame, hash).await.to_vec()
+    } else {
+        let image = state.current_image.read().clone().ok_or_else(ApiError::no_frame)?;
+        let quality = state.pipeline.load().snapshot_quality();
+        tokio::task::spawn_blocking(move || capture::encode_image_jpeg(&image, quality))
+            .await?
+            .map_err(|e| ApiError::internal(format!("Failed to encode snapshot: {}", e)))?
+    };
+    let jpeg = with_still_exif(&state, jpeg).await?;
+    let mut response = jpeg_response(jpeg);
+    response.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        header::HeaderValue::from_static("inline; filename=\"snapshot.jpg\""),
+    );
+    Ok(response)
+}
+
+/// A newly captured raw frame as a DNG, for developing in a raw editor
+#[utoipa::path(
+    get,
+    path = "/frame.dng",
+    tag = "frames",
+    responses(
+        (status = 200, description = "Raw frame: 10-bit Bayer samples with CFA pattern, black and white levels", body = openapi::Binary, content_type = "image/x-adobe-dng"),
+        (status = 503, description = "Camera not available or capture failed", body = ApiError),
+        (status = 500, description = "Encoding failed", body = ApiError),
+    )
+)]
+async fn frame_dng_handler(State(state): State<SharedState>) -> ApiResult<Response> {
+    let pipeline = state.pipeline.load();
+    let (black_level, color_matrix) = (pipeline.black_level, pipeline.color_correction.effective());
+    let (dng, timestamp_ms) = tokio::task::spawn_blocking(move || -> ApiResult<(Vec<u8>, u64)> {
+        // A frame of its own: the capture loop keeps no raw data around
+        let (samples, size, orientation, cfa, white_balance, subdev, timestamp_ms) = {
+            let mut capture_guard = state.capture.write();
+            let capture = capture_guard.as_mut().ok_or_else(ApiError::camera_unavailable)?;
+            let raw = capture
+                .capture_raw_frame()
+                .map_err(|e| ApiError::unavailable(format!("Raw capture failed: {:#}", e)))?;
+            let timestamp_ms = unix_millis();
+            let subdev = capture.config().source.has_sensor().then(|| capture.config().sensor_subdev.clone());
+            let cfa = capture.raw_format().cfa;
+            let white_balance = capture.white_balance_gains();
+            let (size, orientation) = (capture.frame_size(), capture.raw_orientation());
+            (capture.unpack_raw(&raw).to_vec(), size, orientation, cfa, white_balance, subdev, timestamp_ms)
+        };
+        let mut fields = exif::ExifFields::new(timestamp_ms);
+        if let Some(subdev) = subdev {
+            match controls::list_controls(&subdev) {
+                Ok(controls) => fields = fields.with_sensor_controls(&controls, size.width),
+                Err(e) => tracing::warn!("No sensor controls for DNG: {:#}", e),
+            }
+        }
+        let image = dng::BayerImage {
+            samples: &samples,
+            width: size.width,
+            height: size.height,
+            orientation,
+            cfa,
+            black_level,
+            white_level: dng::WHITE_LEVEL,
+            white_balance,
+            color_matrix,
+        };
+        let dng = dng::encode(&image, &fields).map_err(|e| ApiError::internal(format!("Failed to encode DNG: {:#}", e)))?;
+        Ok((dng, timestamp_ms))
+    })
+    .await??;
+    let disposition = format!("attachment; filename=\"frame-{}.dng\"", timestamp_ms);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/x-adobe-dng")
+        .header(header::CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(Body::from(dng))
+        .unwrap())
+}
+
+fn snapshot_archive(state: &AppState) -> ApiResult<Arc<SnapshotArchive>> {
+    state
+        .snapshot_archive
+        .read()
+        .clone()
+        .ok_or_else(|| ApiError::unavailable("Snapshot archive not available"))
+}
+
+fn no_snapshot(id: u64) -> ApiError {
+    ApiError::not_found(format!("No snapshot {}", id))
+}
+
+/// Save the current frame in the snapshot archive (full quality, with
+/// EXIF), pruning old snapshots past the retention limits
+#[utoipa::path(
+    post,
+    path = "/snapshots",
+    tag = "snapshots",
+    responses(
+        (status = 200, description = "Snapshot saved", body = Object),
+        (status = 503, description = "No frame captured yet, or no archive", body = ApiError),
+        (status = 500, description = "Encoding or writing failed", body = ApiError),
+    )
+)]
+async fn snapshot_save_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let archive = snapshot_archive(&state)?;
+    if state.current_image.read().is_none() {
+        return Err(ApiError::no_frame());
+    }
+    let timestamp_ms = state.frame_watch.borrow().as_ref().map_or_else(unix_millis, |f| f.timestamp_ms);
+    let jpeg = encode_still(&state).await?;
+    let (snapshot, pruned) = tokio::task::spawn_blocking(move || archive.save(&jpeg, timestamp_ms)).await??;
+    info!("Saved snapshot {} ({} bytes)", snapshot.id, snapshot.bytes);
+    if pruned.files > 0 {
+        info!("Pruned {} snapshot(s) ({} bytes)", pruned.files, pruned.bytes);
+    }
+    Ok(axum::Json(serde_json::json!({
+        "snapshot": snapshot,
+        "url": format!("/snapshots/{}", snapshot.id),
+        "pruned": pruned.files,
+        "success": true
+    })))
+}
+
+/// Most snapshots one listing returns
+const MAX_SNAPSHOT_LIST: usize = 1000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SnapshotListParams {
+    /// Only snapshots older than this id (for paging)
+    before: Option<u64>,
+    /// Most snapshots to return (default and maximum 1000)
+    limit: Option<usize>,
+}
+
+/// Stored snapshots, newest first, with the archive totals and retention
+#[utoipa::path(
+    get,
+    path = "/snapshots",
+    tag = "snapshots",
+    params(SnapshotListParams),
+    responses(
+        (status = 200, description = "Snapshots, totals and retention limits", body = Object),
+        (status = 503, description = "No archive", body = ApiError),
+    )
+)]
+async fn snapshots_list_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<SnapshotListParams>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let archive = snapshot_archive(&state)?;
+    let retention = archive.retention();
+    let all = tokio::task::spawn_blocking(move || archive.list()).await??;
+    let total_bytes: u64 = all.iter().map(|s| s.bytes).sum();
+    let count = all.len();
+    let limit = params.limit.unwrap_or(MAX_SNAPSHOT_LIST).min(MAX_SNAPSHOT_LIST);
+    let snapshots: Vec<SnapshotInfo> = all
+        .into_iter()
+        .rev()
+        .filter(|s| params.before.is_none_or(|before| s.id < before))
+        .take(limit)
+        .collect();
+    Ok(axum::Json(serde_json::json!({
+        "snapshots": snapshots,
+        "count": count,
+        "total_bytes": total_bytes,
+        "retention": retention
+    })))
+}
+
+/// One stored snapshot
+#[utoipa::path(
+    get,
+    path = "/snapshots/{id}",
+    tag = "snapshots",
+    params(("id" = u64, Path, description = "Snapshot id (capture time, Unix ms)")),
+    responses(
+        (status = 200, description = "The snapshot", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 404, description = "No such snapshot", body = ApiError),
+        (status = 503, description = "No archive", body = ApiError),
+    )
+)]
+async fn snapshot_get_handler(State(state): State<SharedState>, Path(id): Path<u64>) -> ApiResult<Response> {
+    let archive = snapshot_archive(&state)?;
+    let jpeg = tokio::task::spawn_blocking(move || archive.read(id)).await??.ok_or_else(|| no_snapshot(id))?;
+    let mut response = jpeg_response(jpeg);
+    let headers = response.headers_mut();
+    // An id always names the same image
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("private, max-age=31536000, immutable"));
+    if let Ok(disposition) = header::HeaderValue::from_str(&format!("inline; filename=\"snapshot-{}.jpg\"", id)) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
+}
+
+/// Delete a stored snapshot
+#[utoipa::path(
+    delete,
+    path = "/snapshots/{id}",
+    tag = "snapshots",
+    params(("id" = u64, Path, description = "Snapshot id")),
+    responses(
+        (status = 200, description = "Snapshot deleted", body = Object),
+        (status = 404, description = "No such snapshot", body = ApiError),
+        (status = 503, description = "No archive", body = ApiError),
+    )
+)]
+async fn snapshot_delete_handler(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let archive = snapshot_archive(&state)?;
+    if !tokio::task::spawn_blocking(move || archive.delete(id)).await?? {
+        return Err(no_snapshot(id));
+    }
+    info!("Deleted snapshot {}", id);
+    Ok(axum::Json(serde_json::json!({ "deleted": id, "success": true })))
+}
+
+/// Store the current output as the A/B comparison reference
+#[utoipa::path(
+    get,
+    path = "/compare/set_reference",
+    tag = "compare",
+    responses(
+        (status = 200, description = "Reference stored", body = Object),
+        (status = 503, description = "No frame, or not enough memory budget", body = ApiError),
+    )
+)]
+async fn compare_set_reference_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let image = state.current_image.read().clone().ok_or_else(ApiError::no_frame)?;
+    if !state.memory.fits(Component::CompareReference, image.as_bytes().len()) {
+        state.memory.set_pressure(MemoryPressure::Shedding);
+        return Err(ApiError::unavailable("Not enough memory budget for a reference frame")
+            .with_details(serde_json::json!({ "memory": state.memory.snapshot() })));
+    }
+    let reference = compare::Reference {
+        mode: state.pipeline.load().mode,
+        seq: *state.frame_count.read(),
+        timestamp_ms: unix_millis(),
+        image,
+    };
+    let json = serde_json::json!({
+        "status": "ok",
+        "mode": reference.mode.name(),
+        "width": reference.image.width(),
+        "height": reference.image.height(),
+        "frame_seq": reference.seq,
+        "timestamp_ms": reference.timestamp_ms
+    });
+    state.memory.set(Component::CompareReference, reference.image.as_bytes().len());
+    *state.compare_reference.write() = Some(reference);
+    info!("Comparison reference set (frame {})", json["frame_seq"]);
+    Ok(axum::Json(json))
+}
+
+/// Drop the A/B comparison reference
+#[utoipa::path(
+    get,
+    path = "/compare/clear",
+    tag = "compare",
+    responses(
+        (status = 200, description = "Reference dropped", body = Object),
+    )
+)]
+async fn compare_clear_handler(State(state): State<SharedState>) -> axum::Json<serde_json::Value> {
+    let cleared = state.compare_reference.write().take().is_some();
+    state.memory.set(Component::CompareReference, 0);
+    axum::Json(serde_json::json!({ "status": "ok", "cleared": cleared }))
+}
+
+/// Reference and current frame, checked for compatibility
+fn compare_inputs(state: &AppState) -> ApiResult<(Arc<DynamicImage>, Arc<DynamicImage>)> {
+    let current = state.current_image.read().clone().ok_or_else(ApiError::no_frame)?;
+    let reference = state.compare_reference.read();
+    let reference = reference
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found("No reference set (GET /compare/set_reference)"))?;
+    reference.check(state.pipeline.load().mode, &current).map_err(ApiError::conflict)?;
+    Ok((reference.image.clone(), current))
+}
+
+/// Comparison image style for /compare/diff.jpg
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum CompareStyle {
+    /// Reference on the left, current frame on the right
+    #[default]
+    Side,
+    /// Amplified per-pixel difference
+    Heatmap,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CompareParams {
+    #[serde(default)]
+    style: CompareStyle,
+    /// Output width in pixels
+    width: Option<u32>,
+}
+
+/// Render the reference against the current frame (`?style=side|heatmap&width=`)
+#[utoipa::path(
+    get,
+    path = "/compare/diff.jpg",
+    tag = "compare",
+    params(CompareParams),
+    responses(
+        (status = 200, description = "Reference and current frame side by side, or a difference heatmap", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 404, description = "No reference set", body = ApiError),
+        (status = 409, description = "Reference and current frame differ in mode or size", body = ApiError),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+    )
+)]
+async fn compare_diff_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<CompareParams>,
+) -> ApiResult<Response> {
+    let (reference, current) = compare_inputs(&state)?;
+    let width = params.width.unwrap_or(compare::DEFAULT_COMPARE_WIDTH).clamp(64, current.width() * 2);
+    
+    let jpeg = tokio::task::spawn_blocking(move || {
+        let image = match params.style {
+            CompareStyle::Side => compare::side_by_side(&reference, &current, width),
+            CompareStyle::Heatmap => compare::heatmap(&reference, &current, width),
+        };
+        capture::encode_image_jpeg(&image, 90)
+    })
+    .await?
+    .map_err(|e| ApiError::internal(format!("Failed to render comparison: {}", e)))?;
+    Ok(jpeg_response(jpeg))
+}
+
+/// Per-channel mean absolute difference and PSNR against the reference
+#[utoipa::path(
+    get,
+    path = "/compare/stats",
+    tag = "compare",
+    responses(
+        (status = 200, description = "Per-channel mean absolute difference and PSNR", body = Object),
+        (status = 404, description = "No reference set", body = ApiError),
+        (status = 409, description = "Reference and current frame differ in mode or size", body = ApiError),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+    )
+)]
+async fn compare_stats_handler(State(state): State<SharedState>) -> ApiResult<axum::Json<serde_json::Value>> {
+    let (reference, current) = compare_inputs(&state)?;
+    let reference_seq = state.compare_reference.read().as_ref().map(|r| r.seq);
+    let current_seq = *state.frame_count.read();
+    
+    let channels = tokio::task::spawn_blocking(move || compare::channel_stats(&reference, &current)).await?;
+    Ok(axum::Json(serde_json::json!({
+        "reference_seq": reference_seq,
+        "current_seq": current_seq,
+        "channels": channels
+    })))
+}
+
+/// Retry-After sent while another clip is being encoded
+const CLIP_BUSY_RETRY_SECS: u64 = 5;
+
+/// Reserve the clip encoder and pick frames
+fn prepare_clip(
+    state: &AppState,
+    params: clip::ClipParams,
+) -> ApiResult<(tokio::sync::OwnedSemaphorePermit, Vec<clip::ClipFrame>)> {
+    let Ok(permit) = state.clip_encoder.clone().try_acquire_owned() else {
+        return Err(ApiError::new(ErrorCode::RateLimited, "Another clip is being encoded")
+            .with_retry_after(CLIP_BUSY_RETRY_SECS));
+    };
+    
+    let since = unix_millis().saturating_sub((params.seconds * 1000.0) as u64 + 1000);
+    let frames = state.history.read().frames_since(since);
+    let selected = clip::select_frames(&frames, params.seconds, params.fps);
+    if selected.is_empty() {
+        return Err(ApiError::unavailable("No frames in history"));
+    }
+    Ok((permit, selected))
+}
+
+/// Animated GIF of the last few seconds, streamed as it is encoded
+#[utoipa::path(
+    get,
+    path = "/clip.gif",
+    tag = "frames",
+    params(clip::ClipParams),
+    responses(
+        (status = 200, description = "Animated GIF, streamed as it is encoded (`X-Clip-Frames`)", body = openapi::Binary, content_type = "image/gif"),
+        (status = 429, description = "Another clip is being encoded", body = ApiError),
+        (status = 503, description = "No frames in history", body = ApiError),
+    )
+)]
+async fn clip_gif_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<clip::ClipParams>,
+) -> ApiResult<Response> {
+    let params = params.clamped();
+    let (permit, frames) = prepare_clip(&state, params)?;
+    
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<Bytes>>(4);
+    let frame_count = frames.len();
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let writer = std::io::BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        if let Err(e) = clip::encode_gif(&frames, params.width, writer) {
+            tracing::warn!("GIF clip failed: {:#}", e);
+            let _ = tx.blocking_send(Err(std::io::Error::other(e.to_string())));
+        }
+    });
+    
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/gif")
+        .header(header::CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .header("X-Clip-Frames", frame_count)
+        .body(Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx)))
+        .unwrap())
+}
+
+/// Animated WebP of the last few seconds
+///
+/// The RIFF header holds the total size, so unlike the GIF this is
+/// encoded completely before it is sent.
+#[utoipa::path(
+    get,
+    path = "/clip.webp",
+    tag = "frames",
+    params(clip::ClipParams),
+    responses(
+        (status = 200, description = "Animated WebP (`X-Clip-Frames`)", body = openapi::Binary, content_type = "image/webp"),
+        (status = 429, description = "Another clip is being encoded", body = ApiError),
+        (status = 503, description = "No frames in history", body = ApiError),
+        (status = 500, description = "Encoding failed", body = ApiError),
+    )
+)]
+async fn clip_webp_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<clip::ClipParams>,
+) -> ApiResult<Response> {
+    let params = params.clamped();
+    let (permit, frames) = prepare_clip(&state, params)?;
+    
+    let frame_count = frames.len();
+    let webp = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        clip::encode_webp(&frames, params.width)
+    })
+    .await?
+    .map_err(|e| ApiError::internal(format!("Failed to encode clip: {:#}", e)))?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/webp")
+        .header(header::CACHE_CONTROL, "no-cache, no-store, must-revalidate")
+        .header("X-Clip-Frames", frame_count)
+        .body(Body::from(webp))
+        .unwrap())
+}
+
+/// Forwards writes from a blocking encoder to a streaming response body
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<Bytes>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Multipart boundary used by the MJPEG endpoints
+const MJPEG_BOUNDARY: &str = "frame";
+
+/// One multipart part, with timing headers for recorders: `X-Timestamp` is
+/// the capture time in seconds since the Unix epoch (millisecond precision)
+fn mjpeg_part(frame: &PushFrame) -> Bytes {
+    let header = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nX-Timestamp: {}.{:03}\r\nX-Frame-Seq: {}\r\n\r\n",
+        MJPEG_BOUNDARY,
+        frame.jpeg.len(),
+        frame.timestamp_ms / 1000,
+        frame.timestamp_ms % 1000,
+        frame.seq
+    );
+    let mut data = header.into_bytes();
+    data.extend_from_slice(&frame.jpeg);
+    data.extend_from_slice(b"\r\n");
+    Bytes::from(data)
+}
+
+/// Build an MJPEG response from a stream of frames
+///
+/// The client slot lives in the body stream, so it is released as soon as
+/// hyper drops the body on disconnect; each part's send time is recorded
+/// for /stats/latency as it is handed to the connection. When `frames`
+/// ends the closing boundary is sent, so browsers keep the last part.
+fn mjpeg_response<S>(state: &AppState, path: &'static str, frames: S) -> Response
+where
+    S: futures::Stream<Item = PushFrame> + Send + 'static,
+{
+    let client = StreamClient::new(state, path);
+    let stream = frames.map(move |frame| {
+        client.latency.record_send(&frame.timing);
+        Ok::<_, std::convert::Infallible>(mjpeg_part(&frame))
+    });
+    let closing = Bytes::from(format!("--{}--\r\n", MJPEG_BOUNDARY));
+    let stream = stream.chain(futures::stream::once(async move { Ok(closing) }));
+    
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/x-mixed-replace; boundary={}", MJPEG_BOUNDARY),
+        )
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
+
+/// Counts (and tracks the latency of) a connected stream client until the
+/// response body is dropped
+struct StreamClient {
+    clients: Arc<AtomicUsize>,
+    latency: latency::ClientHandle,
+}
+
+impl StreamClient {
+    fn new(state: &AppState, path: &'static str) -> Self {
+        state.stream_clients.fetch_add(1, Ordering::Relaxed);
+        Self {
+            clients: state.stream_clients.clone(),
+            latency: state.latency.register(path, unix_millis()),
+        }
+    }
+}
+
+impl Drop for StreamClient {
+    fn drop(&mut self) {
+        self.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Frames as the capture loop publishes them, at most one per `period`
+///
+/// Each frame is yielded once, when it is published, so a client never
+/// gets a frame twice; it misses frames only while it is still busy with
+/// the previous one, or to keep to `period` (below the capture rate). The
+/// rate follows the frames' capture times, not a timer of its own.
+///
+/// The stream only advances when the body asks for the next part, and the
+/// subscription lives in the stream itself, so it is released as soon as
+/// hyper drops the body on disconnect.
+fn published_frames(state: &AppState, period: Duration) -> impl futures::Stream<Item = PushFrame> + Send {
+    let mut frames = state.frame_watch.subscribe();
+    // Send the current frame straight away
+    frames.mark_changed();
+    let period_ms = period.as_millis() as u64;
+    // Capture times jitter by a few milliseconds around the frame period
+    let slack_ms = period_ms / 4;
+    
+    futures::stream::unfold((frames, 0u64), move |(mut frames, mut due_ms)| async move {
+        loop {
+            frames.changed().await.ok()?;
+            let Some(frame) = frames.borrow_and_update().clone() else {
+                continue;
+            };
+            if frame.timestamp_ms + slack_ms < due_ms {
+                continue;
+            }
+            // Keep the cadence of the requested rate; after a gap it restarts
+            // from this frame
+            due_ms = due_ms.max(frame.timestamp_ms.saturating_sub(slack_ms)) + period_ms;
+            return Some((frame, (frames, due_ms)));
+        }
+    })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EventsParams {
+    /// Most recent events to return (default and maximum: the log capacity)
+    limit: Option<usize>,
+}
+
+/// Recent domain events, oldest first (`?limit=`)
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "events",
+    params(EventsParams),
+    responses(
+        (status = 200, description = "Recent events and the published count", body = Object),
+    )
+)]
+async fn events_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<EventsParams>,
+) -> axum::Json<serde_json::Value> {
+    let limit = params.limit.unwrap_or(events::EVENT_LOG_CAPACITY).min(events::EVENT_LOG_CAPACITY);
+    axum::Json(serde_json::json!({
+        "events": state.event_log.recent(limit),
+        "published": state.events.published()
+    }))
+}
+
+/// Domain events as server-sent events (event name = type, id = event id)
+#[utoipa::path(
+    get,
+    path = "/events/stream",
+    tag = "events",
+    responses(
+        (status = 200, description = "Server-sent events; the event name is the event type, the id its sequence number", body = String, content_type = "text/event-stream"),
+    )
+)]
+async fn events_stream_handler(
+    State(state): State<SharedState>,
+) -> sse::Sse<impl futures::Stream<Item = Result<sse::Event, std::convert::Infallible>>> {
+    let subscription = state.events.subscribe("sse");
+    let stream = futures::stream::unfold(subscription, |mut subscription| async move {
+        let event = subscription.recv().await?;
+        let message = sse::Event::default()
+            .event(event.event.kind())
+            .id(event.id.to_string())
+            .json_data(&*event)
+            .unwrap_or_else(|_| sse::Event::default().comment("unserializable event"));
+        Some((Ok(message), subscription))
+    });
+    sse::Sse::new(stream).keep_alive(sse::KeepAlive::default())
+}
+
+/// Refuse a new stream while the bandwidth budget is used up
+fn admit_stream(state: &AppState) -> ApiResult<()> {
+    if state.bandwidth.admit_stream() {
+        return Ok(());
+    }
+    let stats = state.bandwidth.stats();
+    Err(ApiError::unavailable(
+        "Daily bandwidth budget used up; new streams are refused until the window resets (single frames are served at low resolution)",
+    )
+    .with_details(serde_json::json!({ "budget": stats.budget }))
+    .with_retry_after(state.bandwidth.until_reset().as_secs().max(1)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stream",
+    tag = "stream",
+    params(StreamParams),
+    responses(
+        (status = 200, description = "MJPEG stream (`multipart/x-mixed-replace`), one part per published frame", body = openapi::Binary, content_type = "multipart/x-mixed-replace"),
+        (status = 503, description = "Too many stream clients, or the bandwidth budget refuses new streams", body = ApiError),
+    )
+)]
+async fn mjpeg_stream_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<StreamParams>,
+) -> ApiResult<Response> {
+    admit_stream(&state)?;
+    Ok(mjpeg_stream(&state, params, "/stream"))
+}
+
+/// MJPEG stream at a lower resolution; each frame is scaled and encoded
+/// once for all clients of the same resolution and quality
+#[utoipa::path(
+    get,
+    path = "/stream/{res}",
+    tag = "stream",
+    params(("res" = String, Path, description = "`1080p`, `720p`, `480p` or `full`"), StreamParams),
+    responses(
+        (status = 200, description = "MJPEG stream (`multipart/x-mixed-replace`), one part per published frame", body = openapi::Binary, content_type = "multipart/x-mixed-replace"),
+        (status = 400, description = "Unknown resolution", body = ApiError),
+        (status = 503, description = "Too many stream clients, or the bandwidth budget refuses new streams", body = ApiError),
+    )
+)]
+async fn scaled_stream_handler(
+    State(state): State<SharedState>,
+    Path(res): Path<String>,
+    Query(params): Query<StreamParams>,
+) -> ApiResult<Response> {
+    let res = ResolutionPreset::parse(&res).ok_or_else(|| {
+        ApiError::bad_request(format!("Invalid resolution. Use one of: {}", ResolutionPreset::NAMES.join(", ")))
+    })?;
+    admit_stream(&state)?;
+    let path = match res {
+        ResolutionPreset::Full => "/stream/full",
+        ResolutionPreset::P1080 => "/stream/1080p",
+        ResolutionPreset::P720 => "/stream/720p",
+        ResolutionPreset::P480 => "/stream/480p",
+    };
+    Ok(mjpeg_stream(&state, StreamParams { res, ..params }, path))
+}
+
+/// The published frames as an MJPEG response, per `params`
+fn mjpeg_stream(state: &SharedState, params: StreamParams, path: &'static str) -> Response {
+    let params = Arc::new(params);
+    let mut throttle = FrameThrottle::default();
+    
+    let stream_state = state.clone();
+    let frames = published_frames(state, params.frame_interval()).filter_map(move |published| {
+        let state = stream_state.clone();
+        let params = params.clone();
+        let admitted = throttle.admit(&state.bandwidth);
+        async move {
+            if !admitted {
+                return None;
+            }
+            // The annotated variant is the published frame itself
+            let (jpeg, hash) = match params.view {
+                StreamView::Annotated => (published.jpeg, published.hash),
+                StreamView::Clean => params.select_hashed_frame(&state)?,
+            };
+            let jpeg = params.apply_capped(&state, jpeg, hash, state.bandwidth.quality_cap()).await;
+            Some(PushFrame { jpeg, hash, ..published })
+        }
+    });
+    
+    mjpeg_response(state, path, frames)
+}
+
+/// Live H.264 stream as fragmented MP4 for a `<video>` element
+///
+/// All clients share one hardware encoder, started by the first client;
+/// a new client starts at the next IDR frame. Frames are the clean view
+/// (no detection boxes) at full resolution and `--h264-kbps`.
+#[utoipa::path(
+    get,
+    path = "/video.mp4",
+    tag = "stream",
+    responses(
+        (status = 200, description = "Fragmented MP4 (H.264), one fragment per frame", body = openapi::Binary, content_type = "video/mp4"),
+        (status = 503, description = "No H.264 encoder (built without `mpp`, or it failed to start), no frame yet, or the bandwidth budget refuses new streams", body = ApiError),
+    )
+)]
+async fn video_mp4_handler(State(state): State<SharedState>) -> ApiResult<Response> {
+    admit_stream(&state)?;
+    let (subscription, track) = h264_subscription(&state).await?;
+    let client = StreamClient::new(&state, "/video.mp4");
+    let init = track.init_segment();
+    let fragments = subscription.fragments(state.h264.clone(), track).map(move |(fragment, unit)| {
+        client.latency.record_send(&unit.timing);
+        Ok::<_, std::convert::Infallible>(fragment)
+    });
+    let stream = futures::stream::once(async move { Ok(init) }).chain(fragments);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "video/mp4")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from_stream(stream))
+        .unwrap())
+}
+
+/// Join the shared H.264 stream, starting the encoder if this is its
+/// first client, and wait for its track
+async fn h264_subscription(state: &SharedState) -> ApiResult<(h264::Subscription, h264::Track)> {
+    let (mut subscription, start) = state.h264.subscribe();
+    if start {
+        start_h264_encoder(state);
+    }
+    let track = tokio::time::timeout(h264::START_TIMEOUT, subscription.track(&state.h264))
+        .await
+        .ok()
+        .flatten();
+    let Some(track) = track else {
+        let reason = state.h264.last_error().unwrap_or_else(|| "no frame to encode yet".to_string());
+        return Err(ApiError::unavailable(format!("H.264 stream unavailable: {}", reason)));
+    };
+    Ok((subscription, track))
+}
+
+/// SDP offer from `RTCPeerConnection.createOffer()`
+#[derive(Debug, Deserialize, ToSchema)]
+struct WebRtcOffer {
+    sdp: String,
+}
+
+/// Answer a WebRTC offer with a live H.264 track
+///
+/// ICE is not trickled: the answer carries all candidates. The peer shares
+/// the /video.mp4 encoder and closes with its connection.
+#[utoipa::path(
+    post,
+    path = "/webrtc/offer",
+    tag = "stream",
+    request_body = WebRtcOffer,
+    responses(
+        (status = 200, description = "SDP answer (`{type, sdp}`)", body = Object),
+        (status = 400, description = "Invalid or unusable offer", body = ApiError),
+        (status = 503, description = "Built without `webrtc`, no H.264 encoder, no frame yet, or the bandwidth budget refuses new streams", body = ApiError),
+    )
+)]
+async fn webrtc_offer_handler(
+    State(state): State<SharedState>,
+    offer: Result<axum::Json<WebRtcOffer>, axum::extract::rejection::JsonRejection>,
+) -> ApiResult<axum::Json<serde_json::Value>> {
+    let axum::Json(offer) = offer?;
+    WebRtc::check_available().map_err(|e| ApiError::unavailable(e.to_string()))?;
+    admit_stream(&state)?;
+    let (subscription, track) = h264_subscription(&state).await?;
+    let client = StreamClient::new(&state, "/webrtc");
+    let answer = state
+        .webrtc
+        .answer(offer.sdp, state.h264.clone(), subscription, track, move |timing| {
+            client.latency.record_send(timing)
+        })
+        .await
+        .map_err(|e| ApiError::bad_request(format!("WebRTC negotiation failed: {:#}", e)))?;
+    Ok(axum::Json(serde_json::json!({ "type": "answer", "sdp": answer })))
+}
+
+/// Run the shared H.264 encoder on the published frames' clean pixels
+/// until its last client has gone
+fn start_h264_encoder(state: &SharedState) {
+    let source = state.clone();
+    let frames = published_frames(state, Duration::from_millis(1000 / h264::FPS as u64)).filter_map(move |published| {
+        let image = source.current_image.read().clone();
+        futures::future::ready(image.map(|image| h264::SourceFrame {
+            image,
+            timestamp_ms: published.timestamp_ms,
+            timing: published.timing,
+        }))
+    });
+    tokio::spawn(h264::run_encoder(state.h264.clone(), frames));
+}
+
+/// Largest tile served by /tile.jpg and /stream_tile (pixels)
+const MAX_TILE_AREA: u32 = 1920 * 1080;
+
+/// Native-resolution crop rectangle (`?x=&y=&w=&h=`)
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TileParams {
+    /// Left edge in sensor pixels
+    #[serde(default)]
+    x: u32,
+    /// Top edge in sensor pixels
+    #[serde(default)]
+    y: u32,
+    /// Width in sensor pixels
+    #[serde(default = "TileParams::default_width")]
+    w: u32,
+    /// Height in sensor pixels
+    #[serde(default = "TileParams::default_height")]
+    h: u32,
+    /// Frame rate of /stream_tile
+    fps: Option<u32>,
+}
+
+impl TileParams {
+    fn default_width() -> u32 { 640 }
+    fn default_height() -> u32 { 480 }
+
+    /// Clamp the rectangle to the frame and to MAX_TILE_AREA
+    fn clamped(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let x = self.x.min(width.saturating_sub(1));
+        let y = self.y.min(height.saturating_sub(1));
+        let w = self.w.clamp(1, width - x);
+        let h = self.h.clamp(1, height - y).min((MAX_TILE_AREA / w).max(1));
+        (x, y, w, h)
+    }
+}
+
+/// Crop the tile from the latest frame's pixels, at the stream quality or
+/// `quality_cap` if lower
+async fn render_tile(state: &AppState, params: &TileParams, quality_cap: Option<u8>) -> Option<Bytes> {
+    let image = state.current_image.read().clone()?;
+    let quality = state.pipeline.load().jpeg_quality.min(quality_cap.unwrap_or(u8::MAX));
+    let (x, y, w, h) = params.clamped(image.width(), image.height());
+    
+    match tokio::task::spawn_blocking(move || capture::crop_jpeg(&image, x, y, w, h, quality)).await {
+        Ok(Ok(data)) => Some(Bytes::from(data)),
+        Ok(Err(e)) => {
+            tracing::warn!("Failed to render tile: {}", e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Native-resolution crop of the current frame
+#[utoipa::path(
+    get,
+    path = "/tile.jpg",
+    tag = "frames",
+    params(TileParams),
+    responses(
+        (status = 200, description = "Native-resolution crop of the latest frame", body = openapi::Binary, content_type = "image/jpeg"),
+        (status = 503, description = "No frame captured yet", body = ApiError),
+    )
+)]
+async fn tile_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<TileParams>,
+) -> ApiResult<Response> {
+    let tile = render_tile(&state, &params, None).await.ok_or_else(ApiError::no_frame)?;
+    Ok(jpeg_response(tile))
+}
+
+/// MJPEG stream of a fixed native-resolution tile
+#[utoipa::path(
+    get,
+    path = "/stream_tile",
+    tag = "stream",
+    params(TileParams),
+    responses(
+        (status = 200, description = "MJPEG stream of the tile", body = openapi::Binary, content_type = "multipart/x-mixed-replace"),
+        (status = 503, description = "Too many stream clients, or the bandwidth budget refuses new streams", body = ApiError),
+    )
+)]
+async fn tile_stream_handler(
+    State(state): State<SharedState>,
+    Query(params): Query<TileParams>,
+) -> ApiResult<Response> {
+    admit_stream(&state)?;
+    let fps = params.fps.unwrap_or(MAX_STREAM_FPS).clamp(1, MAX_STREAM_FPS);
+    let params = Arc::new(params);
+    let mut throttle = FrameThrottle::default();
+    
+    let stream_state = state.clone();
+    let frames = published_frames(&state, Duration::from_millis(1000 / fps as u64)).filter_map(move |published| {
+        let state = stream_state.clone();
+        let params = params.clone();
+        let admitted = throttle.admit(&state.bandwidth);
+        async move {
+            if !admitted {
+                return None;
+            }
+            let jpeg = render_tile(&state, &params, state.bandwidth.quality_cap()).await?;
+            Some(PushFrame { jpeg, ..published })
+        }
+    });
+    
+    Ok(mjpeg_response(&state, "/stream_tile", frames))
+}
+
+fn thermal_status_json(state: &AppState) -> serde_json::Value {
+    let config = state.thermal_config.read();
+    serde_json::json!({
+        "status": *state.thermal.read(),
+        "warn_c": config.warn_c,
+        "policy": config.throttle_c.map(|t| serde_json::json!({
+            "throttle_c": t,
+            "throttled_fps": config.throttled_fps,
+            "hysteresis_c": thermal::THROTTLE_HYSTERESIS_C
+        }))
+    })
+}
+
+fn rate_limit_status_json(limiter: &RateLimiter) -> serde_json::Value {
+    let config = limiter.config();
+    if !config.is_enabled() {
+        return serde_json::Value::Null;
+    }
+    let rejected: serde_json::Map<_, _> = ratelimit::RouteClass::ALL
+        .iter()
+        .map(|&c| (c.name().to_string(), limiter.rejected(c).into()))
+        .collect();
+    serde_json::json!({
+        "images_per_sec": config.images_per_sec,
+        "api_per_sec": config.api_per_sec,
+        "max_streams_per_ip": config.max_streams_per_ip,
+        "rejected": rejected
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "status",
+    responses(
+        (status = 200, description = "Capture, pipeline, detection and subsystem state", body = Object, example = openapi::status_example),
+    )
+)]
+async fn status_handler(State(state): State<SharedState>) -> impl IntoResponse {
+    let frame_count = *state.frame_count.read();
+    let has_frame = state.frame_watch.borrow().is_some();
+    let unchanged_frames = state.frame_hashes.read().map(|h| h.unchanged_frames);
+    let static_scene = unchanged_frames.map(|n| n >= *state.static_scene_frames.read());
+    let mode = state.pipeline.load().mode;
+    let detection_enabled = *state.detection_enabled.read();
+    let detection_count = state.last_detections.read().detections.len();
+    let (detector_available, detector_backend) = match *state.detector.read() {
+        Some(ref detector) => (true, Some(detector.backend().name())),
+        None => (false, None),
+    };
+    // Degraded while a crashed detector waits for its restart
+    let detector_status = subsystem_statuses(&state).into_iter().find(|s| s.name == "detector");
+    let test_pattern = state.test_pattern.read().clone();
+    let raw_push = state.push_config.read().as_ref().map(|config| {
+        serde_json::json!({
+            "tcp_port": config.tcp_port,
+            "udp_target": config.udp_target.map(|a| a.to_string()),
+            "udp_fps": config.udp_fps,
+            "stats": state.push_stats.snapshot(),
+        })
+    });
+    let home_assistant = state.home_assistant.read().as_ref().map(|config| {
+        serde_json::json!({
+            "broker": config.mqtt.broker,
+            "node_id": config.node_id,
+            "classes": config.classes,
+            "stats": state.ha_stats.snapshot(),
+        })
+    });
+    let raw_format = state.capture.read().as_ref().map(|c| {
+        serde_json::json!({
+            "format": c.raw_format(),
+            "stride": c.stride(),
+            "source": c.source_name(),
+        })
+    });
+    let resolution = state.capture.read().as_ref().map_or(FrameSize::FULL, FrameCapture::frame_size).to_string();
+    let encoder = state.capture.read().as_ref().and_then(FrameCapture::encoder_name);
+    let scaler = state.capture.read().as_ref().map(FrameCapture::scaler_name);
+    let pipeline = state.pipeline.load();
+    // Scene-dependent state is only known while the camera runs
+    let (applied_version, wb_gains, green_state) = match *state.capture.read() {
+        Some(ref c) => (Some(c.applied_version()), c.white_balance_gains(), Some(c.green_balance_state().clone())),
+        None => (None, None, None),
+    };
+    let row_noise = serde_json::json!({
+        "enabled": pipeline.row_noise_correction,
+        "strength": pipeline.row_noise_strength,
+    });
+    let white_balance = serde_json::json!({
+        "enabled": pipeline.enable_white_balance,
+        "mode": pipeline.wb_mode.name(),
+        "fixed_gains": pipeline.wb_mode.fixed_gains(),
+        "gains": wb_gains,
+        "smoothing": pipeline.wb_smoothing,
+    });
+    let green_balance = serde_json::json!({
+        "config": pipeline.green_balance,
+        "ratio": green_state.as_ref().and_then(|g| g.ratio()),
+        "gain": green_state.as_ref().and_then(|g| g.gain()),
+        "flat_tiles": green_state.as_ref().map(|g| g.flat_tiles()),
+    });
+    
+    axum::Json(serde_json::json!({
+        "frame_count": frame_count,
+        "settings_version": pipeline.version,
+        "settings_applied_version": applied_version,
+        "row_noise_correction": row_noise,
+        "black_level": pipeline.black_level,
+        "quality": quality_status_json(&state),
+        "auto_exposure": auto_exposure_status_json(&state),
+        "gamma": pipeline.gamma,
+        "tonemap": pipeline.tonemap_strength,
+        "hdr_ratio": pipeline.hdr_ratio,
+        "contrast": pipeline.contrast,
+        "denoise": pipeline.denoise,
+        "spatial_denoise": pipeline.spatial_denoise,
+        "sharpen": pipeline.sharpen,
+        "tone": pipeline.tone,
+        "white_balance": white_balance,
+        "color_correction": pipeline.color_correction,
+        "orientation": pipeline.orientation,
+        "zoom": pipeline.zoom,
+        "overlay": pipeline.overlay,
+        "green_balance": green_balance,
+        "test_pattern": test_pattern,
+        "metering": pipeline.metering,
+        "raw_push": raw_push,
+        "home_assistant": home_assistant,
+        "has_frame": has_frame,
+        "static_scene": static_scene,
+        "unchanged_frames": unchanged_frames,
+        "stream_clients": state.stream_clients.load(Ordering::Relaxed),
+        "resolution": resolution,
+        "raw_format": raw_format,
+        "encoder": encoder,
+        "scaler": scaler,
+        "stage_ms": *state.stage_timings.read(),
+        "h264": state.h264.status(),
+        "webrtc_peers": state.webrtc.peers(),
+        "mode": mode.name(),
+        "demosaic": pipeline.demosaic.name(),
+        "day_night": state.day_night.lock().status(),
+        "detection_enabled": detection_enabled,
+        "detection_count": detection_count,
+        "detector_available": detector_available,
+        "detector_backend": detector_backend,
+        "detector_state": detector_status.as_ref().map(|s| s.state),
+        "detector_restarts": detector_status.as_ref().map_or(0, |s| s.restarts),
+        "rate_limit": rate_limit_status_json(&state.rate_limiter),
+        "bandwidth_budget": state.bandwidth.stats().budget,
+        "thermal": thermal_status_json(&state),
+        "gpio": state.gpio_config.read().is_enabled().then(|| serde_json::json!({
+            "config": *state.gpio_config.read(),
+            "trigger_mode": state.gpio_config.read().trigger_pin.is_some(),
+            "stats": state.gpio_stats.snapshot()
+        })),
+        "schedule": state.scheduler.read().states(chrono::Utc::now()),
+        "recording": state.recorder.read().as_ref().map(|r| r.status().state),
+        "capture_watchdog": state.capture_watchdog.lock().status(),
+        "memory_pressure": state.memory.pressure(),
+        "memory": state.memory.snapshot()
+    }))
+}
@@ -0,0 +1,422 @@
+//! OpenAPI description of the HTTP API
+//!
+//! Every handler carries a `#[utoipa::path]` annotation next to its code;
+//! [`ApiDoc`] collects them into the document served at `/openapi.json`
+//! (browsable at `/docs`). Routes are registered through [`ApiRouter`],
+//! which remembers their paths so [`check_routes`] can report any route
+//! the document doesn't describe when the server starts.
+
+use crate::annotation::{AnnotationStyle, LabelPosition};
+use crate::ccm::ColorMatrix;
+use crate::contrast::{Contrast, ContrastMode};
+use crate::daynight::{DayNightConfig, DayNightStatus, Period};
+use crate::denoise::{Denoise, SpatialDenoise, SpatialMethod};
+use crate::masks::{Mask, MaskStyle};
+use crate::orientation::{Flips, Orientation};
+use crate::sharpen::Sharpen;
+use crate::tone::Tone;
+use crate::zoom::{Crop, Zoom};
+use crate::bandwidth::{BandwidthStats, BudgetConfig, BudgetStats, ConnectionStats, Degradation, EndpointStats};
+use crate::detector::{BBox, Detection, DetectionFilter, DetectionResult, DetectionTask, Point};
+use crate::error::{ApiError, ErrorCode};
+use crate::faces::{FaceCropConfig, FaceRecord};
+use crate::focus::FocusReading;
+use crate::jobs::{Job, JobState, Step, StepState, StepStatus};
+use crate::latency::{ClientReport, Distribution, LatencyReport};
+use crate::metering::Roi;
+use crate::motion::{MotionConfig, MotionStatus};
+use crate::schedule::{Feature, ProfileTimeConfig, ScheduleConfig, WindowConfig};
+use crate::selftest::{CheckResult, CheckStatus, SelfTestReport};
+use crate::snapshots::SnapshotInfo;
+use crate::stack::{StackMode, StackState, StackStatus};
+use crate::version::BuildInfo;
+use crate::zones::Zone;
+use axum::routing::MethodRouter;
+use axum::Router;
+use std::collections::BTreeSet;
+use utoipa::{OpenApi, ToSchema};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "IMX415 Streamer",
+        description = "Camera streaming, control and detection API.\n\n\
+            Every error response carries a JSON body `{\"code\", \"message\", \"details\"}` \
+            (see `ApiError`). Requests are rate limited per client in three classes \
+            (API, full-size images, streams); over the limit the server answers \
+            429 with a `Retry-After` header."
+    ),
+    paths(
+        super::index_handler,
+        super::status_handler,
+        super::version_handler,
+        super::storage_handler,
+        super::config_reload_handler,
+        super::ui_config_handler,
+        super::mjpeg_stream_handler,
+        super::scaled_stream_handler,
+        super::video_mp4_handler,
+        super::webrtc_offer_handler,
+        super::tile_stream_handler,
+        super::history_replay_handler,
+        super::frame_handler,
+        super::frame_changed_handler,
+        super::frame_json_handler,
+        super::snapshot_handler,
+        super::frame_dng_handler,
+        super::snapshot_save_handler,
+        super::snapshots_list_handler,
+        super::snapshot_get_handler,
+        super::snapshot_delete_handler,
+        super::tile_handler,
+        super::clip_gif_handler,
+        super::clip_webp_handler,
+        super::set_mode_handler,
+        super::set_row_noise_handler,
+        super::set_black_level_handler,
+        super::set_quality_handler,
+        super::set_test_pattern_handler,
+        super::controls_list_handler,
+        super::control_handler,
+        super::control_set_handler,
+        super::set_gamma_handler,
+        super::set_tonemap_handler,
+        super::set_wb_handler,
+        super::set_wb_mode_handler,
+        super::set_wb_smoothing_handler,
+        super::ccm_handler,
+        super::ccm_set_handler,
+        super::orientation_handler,
+        super::orientation_set_handler,
+        super::zoom_handler,
+        super::zoom_set_handler,
+        super::set_green_balance_handler,
+        super::set_contrast_handler,
+        super::set_denoise_handler,
+        super::set_spatial_denoise_handler,
+        super::set_sharpen_handler,
+        super::tone_handler,
+        super::tone_set_handler,
+        super::set_metering_handler,
+        super::set_auto_exposure_handler,
+        super::set_detection_handler,
+        super::set_detection_interval_handler,
+        super::detect_once_handler,
+        super::detections_handler,
+        super::detector_health_handler,
+        super::detect_config_handler,
+        super::detect_config_set_handler,
+        super::labels_handler,
+        super::labels_set_handler,
+        super::annotation_handler,
+        super::annotation_set_handler,
+        super::history_handler,
+        super::history_detections_handler,
+        super::history_annotated_handler,
+        super::faces_handler,
+        super::face_crop_handler,
+        super::models_list_handler,
+        super::model_upload_handler,
+        super::model_delete_handler,
+        super::model_activate_handler,
+        super::profiles_list_handler,
+        super::profile_handler,
+        super::profile_put_handler,
+        super::profile_save_handler,
+        super::profile_delete_handler,
+        super::zones_list_handler,
+        super::zone_handler,
+        super::zone_put_handler,
+        super::zone_delete_handler,
+        super::masks_list_handler,
+        super::mask_handler,
+        super::mask_put_handler,
+        super::mask_delete_handler,
+        super::profile_apply_handler,
+        super::schedule_handler,
+        super::schedule_set_handler,
+        super::motion_handler,
+        super::motion_config_handler,
+        super::motion_config_set_handler,
+        super::record_start_handler,
+        super::record_stop_handler,
+        super::record_status_handler,
+        super::stack_start_handler,
+        super::stack_cancel_handler,
+        super::stack_status_handler,
+        super::stack_dng_handler,
+        super::stack_png_handler,
+        super::events_handler,
+        super::events_stream_handler,
+        super::jobs_list_handler,
+        super::jobs_create_handler,
+        super::job_handler,
+        super::job_cancel_handler,
+        super::compare_set_reference_handler,
+        super::compare_clear_handler,
+        super::compare_diff_handler,
+        super::compare_stats_handler,
+        super::stats_handler,
+        super::image_stats_handler,
+        super::histogram_handler,
+        super::focus_handler,
+        super::focus_set_handler,
+        super::latency_stats_handler,
+        super::bandwidth_stats_handler,
+        super::latency_blink_handler,
+        super::metrics_handler,
+        super::selftest_handler,
+        super::admin_restart_handler,
+        super::admin_subsystems_handler,
+    ),
+    components(schemas(
+        ApiError, ErrorCode, Binary,
+        super::StreamView, super::ResolutionPreset, super::CompareStyle, super::WebRtcOffer,
+        DetectionTask, BBox, Point, Detection, DetectionResult, DetectionFilter, Zone,
+        Mask, MaskStyle,
+        MotionConfig, MotionStatus, SnapshotInfo,
+        DayNightConfig, DayNightStatus, Period,
+        StackStatus, StackState, StackMode,
+        FaceCropConfig, FaceRecord,
+        AnnotationStyle, LabelPosition,
+        ScheduleConfig, WindowConfig, ProfileTimeConfig, Feature,
+        Step, StepState, StepStatus, Job, JobState,
+        BandwidthStats, BudgetConfig, BudgetStats, ConnectionStats, Degradation, EndpointStats,
+        LatencyReport, ClientReport, Distribution,
+        SelfTestReport, CheckResult, CheckStatus,
+        BuildInfo,
+        super::CcmRequest, ColorMatrix,
+        Contrast, ContrastMode, Denoise, SpatialDenoise, SpatialMethod, Sharpen,
+        super::ToneRequest, Tone,
+        super::OrientationRequest, Orientation, Flips,
+        super::ZoomRequest, super::ZoomCenter, Zoom, Crop,
+        super::FocusRequest, FocusReading, Roi,
+    )),
+    tags(
+        (name = "stream", description = "MJPEG and H.264 streams"),
+        (name = "frames", description = "Single frames, stills, tiles and clips"),
+        (name = "snapshots", description = "Server-side snapshot archive"),
+        (name = "camera", description = "Pipeline and sensor controls; changes apply from the next frame"),
+        (name = "detection", description = "Object detection, labels and the frame history"),
+        (name = "faces", description = "Face sightings and crops (`--detector-task faces`)"),
+        (name = "models", description = "Detector model files"),
+        (name = "profiles", description = "Saved settings profiles"),
+        (name = "zones", description = "Polygon zones for detection tagging and alerts"),
+        (name = "masks", description = "Privacy masks blacked out of the processed frames"),
+        (name = "schedule", description = "Time-of-day feature and profile schedule"),
+        (name = "motion", description = "Frame-differencing motion detection"),
+        (name = "recording", description = "Continuous recording"),
+        (name = "stack", description = "Raw frame stacking for long exposures"),
+        (name = "events", description = "Event log and live event stream"),
+        (name = "jobs", description = "Multi-step capture jobs"),
+        (name = "compare", description = "A/B comparison against a reference frame"),
+        (name = "stats", description = "Frame, latency and bandwidth statistics"),
+        (name = "status", description = "Server state and build information"),
+        (name = "config", description = "Configuration file (`--config`)"),
+        (name = "admin", description = "Self-test and subsystem supervision"),
+        (name = "ui", description = "Browser live view"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Binary body (images, streams, model files)
+#[allow(dead_code)]
+#[derive(ToSchema)]
+#[schema(value_type = String, format = Binary)]
+pub struct Binary(Vec<u8>);
+
+/// Representative GET /status document
+pub fn status_example() -> serde_json::Value {
+    serde_json::json!({
+        "frame_count": 1824,
+        "settings_version": 7,
+        "settings_applied_version": 7,
+        "row_noise_correction": { "enabled": false, "strength": 1.0 },
+        "black_level": 64,
+        "quality": { "mode": "fixed", "effective": 90 },
+        "auto_exposure": null,
+        "gamma": 2.2,
+        "tonemap": 0.0,
+        "hdr_ratio": 4.0,
+        "contrast": { "mode": "off", "clip_limit": 2.0, "tiles": 8 },
+        "denoise": { "strength": 0.8, "threshold": 24 },
+        "spatial_denoise": { "method": "off", "strength": 0.5 },
+        "sharpen": { "amount": 0.8, "radius": 1, "threshold": 2 },
+        "tone": { "brightness": 0, "contrast": 1.0, "saturation": 1.0 },
+        "white_balance": {
+            "enabled": true,
+            "mode": "gray_world",
+            "fixed_gains": null,
+            "gains": [1.62, 1.0, 1.48],
+            "smoothing": { "time_constant_s": 1.0, "max_rate_per_s": 0.5, "scene_change": null }
+        },
+        "color_correction": {
+            "enabled": true,
+            "matrix": [[1.62, -0.45, -0.17], [-0.28, 1.52, -0.24], [-0.05, -0.52, 1.57]]
+        },
+        "orientation": { "rotation": 0, "hflip": false, "vflip": false },
+        "zoom": { "x": 0.0, "y": 0.0, "width": 1.0, "height": 1.0 },
+        "overlay": {
+            "timestamp": true,
+            "timestamp_format": "%Y-%m-%d %H:%M:%S",
+            "camera_name": "driveway",
+            "text": null,
+            "position": "bottom_right",
+            "font_scale": 4,
+            "color": [255, 255, 0],
+            "background": true
+        },
+        "green_balance": {
+            "config": { "mode": "off", "strength": 1.0 },
+            "ratio": null,
+            "gain": null,
+            "flat_tiles": 0
+        },
+        "test_pattern": null,
+        "metering": { "mode": "average", "roi": null, "overlay": false },
+        "raw_push": null,
+        "home_assistant": null,
+        "has_frame": true,
+        "static_scene": false,
+        "unchanged_frames": 0,
+        "stream_clients": 1,
+        "resolution": "3840x2160",
+        "raw_format": {
+            "format": { "fourcc": "GB10", "cfa": "GBRG", "bits": 10, "packing": "packed10" },
+            "stride": 4864,
+            "source": "v4l2"
+        },
+        "encoder": "software",
+        "scaler": "software",
+        "stage_ms": {
+            "capture": 21.4, "unpack": 18.2, "levels": 6.1, "demosaic": 41.7, "extract": null,
+            "spatial_denoise": null, "denoise": 14.2, "upscale": null, "white_balance": 9.3,
+            "color_matrix": 11.2, "gamma": 8.8, "sharpen": 22.6, "histogram": 0.3,
+            "contrast": null, "tone": null, "orientation": null, "masks": 0.9, "zoom": null, "overlay": 1.4,
+            "encode": 96.5, "auto_exposure": null, "draw": 12.0, "focus": 2.1
+        },
+        "h264": { "available": false, "running": false, "clients": 0, "bitrate_kbps": 8000, "error": null },
+        "webrtc_peers": 0,
+        "mode": "color",
+        "demosaic": "bilinear",
+        "day_night": {
+            "enabled": true,
+            "night_below": -4.0,
+            "day_above": -1.0,
+            "hold_secs": 30.0,
+            "period": "day",
+            "brightness": 3.2,
+            "pending_since_ms": null
+        },
+        "detection_enabled": true,
+        "detection_count": 2,
+        "detector_available": true,
+        "detector_backend": "subprocess",
+        "detector_state": "running",
+        "detector_restarts": 0,
+        "rate_limit": null,
+        "bandwidth_budget": null,
+        "thermal": {
+            "policy": null,
+            "warn_c": 85.0,
+            "status": {
+                "sensor_c": 52.5,
+                "max_c": 61.0,
+                "throttled": false,
+                "throttled_since_ms": null,
+                "warning": false,
+                "zones": []
+            }
+        },
+        "gpio": null,
+        "schedule": {},
+        "recording": "idle",
+        "capture_watchdog": {
+            "config": { "max_failures": 10, "max_frame_age": 30.0 },
+            "consecutive_failures": 0,
+            "frame_age_secs": 0.2,
+            "recoveries": 1,
+            "failed_recoveries": 0,
+            "last_stall": "10 failed captures in a row (limit 10)",
+            "last_recovery_ms": 1760000000000u64
+        },
+        "memory_pressure": "normal",
+        "memory": {
+            "budget_bytes": 536870912,
+            "used_bytes": 11200240,
+            "pressure": "normal",
+            "components": {
+                "current_frame": 8875568,
+                "history": 2324672,
+                "decode_cache": 0,
+                "detector_input": 0,
+                "compare_reference": 0
+            }
+        }
+    })
+}
+
+/// A router that remembers the paths registered on it
+pub struct ApiRouter<S> {
+    router: Router<S>,
+    paths: Vec<&'static str>,
+}
+
+impl<S: Clone + Send + Sync + 'static> ApiRouter<S> {
+    pub fn new() -> Self {
+        Self { router: Router::new(), paths: Vec::new() }
+    }
+
+    pub fn route(mut self, path: &'static str, method_router: MethodRouter<S>) -> Self {
+        self.paths.push(path);
+        self.router = self.router.route(path, method_router);
+        self
+    }
+
+    /// The plain router; its paths are appended to `registered`
+    pub fn finish(self, registered: &mut Vec<&'static str>) -> Router<S> {
+        registered.extend(self.paths);
+        self.router
+    }
+}
+
+/// Axum path (`/jobs/:id`) in OpenAPI form (`/jobs/{id}`)
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Problems with the document against the registered routes: routes it
+/// doesn't describe, paths it describes that aren't routed, and operations
+/// without responses
+pub fn check_routes(doc: &utoipa::openapi::OpenApi, registered: &[&'static str]) -> Vec<String> {
+    let routed: BTreeSet<String> = registered.iter().map(|p| openapi_path(p)).collect();
+    let mut problems = Vec::new();
+    for path in &routed {
+        if !doc.paths.paths.contains_key(path) {
+            problems.push(format!("route {} is missing from the OpenAPI document", path));
+        }
+    }
+    for (path, item) in &doc.paths.paths {
+        if !routed.contains(path) {
+            problems.push(format!("OpenAPI path {} is not routed", path));
+        }
+        let operations = [
+            ("GET", &item.get),
+            ("POST", &item.post),
+            ("PUT", &item.put),
+            ("DELETE", &item.delete),
+        ];
+        for (method, operation) in operations {
+            if operation.as_ref().is_some_and(|op| op.responses.responses.is_empty()) {
+                problems.push(format!("{} {} documents no responses", method, path));
+            }
+        }
+    }
+    problems
+}
@@ -0,0 +1,461 @@
+//! Router-level tests: requests go through the same middleware stack as
+//! in `run`, against an `AppState` with no camera
+
+use super::*;
+use tower::ServiceExt;
+use utoipa::OpenApi;
+
+fn test_state() -> SharedState {
+    test_state_with(RateLimitConfig::default())
+}
+
+fn test_state_with(limits: RateLimitConfig) -> SharedState {
+    Arc::new(AppState::new(
+        64 * 1024 * 1024,
+        limits,
+        None,
+        Arc::default(),
+        h264::DEFAULT_BITRATE_KBPS,
+        Vec::new(),
+        ScalerKind::Software,
+    ))
+}
+
+#[tokio::test]
+async fn every_route_is_documented() {
+    let (_, routed) = router(&test_state());
+    let problems = openapi::check_routes(&openapi::ApiDoc::openapi(), &routed);
+    assert!(problems.is_empty(), "{:#?}", problems);
+}
+
+/// A request as `serve` hands it to the router, from `peer`
+fn request_from(peer: &str, method: &str, uri: &str, body: Body) -> axum::http::Request<Body> {
+    let mut request = axum::http::Request::builder().method(method).uri(uri).body(body).unwrap();
+    let peer: std::net::SocketAddr = peer.parse().unwrap();
+    request.extensions_mut().insert(axum::extract::ConnectInfo(peer));
+    request
+}
+
+fn request(method: &str, uri: &str) -> axum::http::Request<Body> {
+    request_from("192.0.2.10:50000", method, uri, Body::empty())
+}
+
+async fn json_body(response: Response) -> serde_json::Value {
+    let bytes = axum::body::to_bytes(response.into_body(), 1 << 20).await.unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+/// Publish a frame as the capture loop does
+fn publish_frame(state: &AppState, seq: u64, jpeg: &'static [u8]) {
+    state.frame_watch.send_replace(Some(PushFrame {
+        seq,
+        timestamp_ms: unix_millis(),
+        jpeg: Bytes::from_static(jpeg),
+        hash: seq,
+        timing: Default::default(),
+    }));
+}
+
+/// A JSON body of `len` bytes for POST `uri`, with or without a
+/// `Content-Length` (chunked uploads have none)
+fn json_request(uri: &str, len: usize, content_length: bool) -> axum::http::Request<Body> {
+    let mut request = request_from("192.0.2.10:50000", "POST", uri, Body::from(vec![b' '; len]));
+    let headers = request.headers_mut();
+    headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    if content_length {
+        headers.insert(header::CONTENT_LENGTH, header::HeaderValue::from(len));
+    }
+    request
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_rejected_with_413() {
+    let (app, _) = router(&test_state());
+    for content_length in [true, false] {
+        let request = json_request("/schedule", MAX_REQUEST_BODY_BYTES + 1, content_length);
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = json_body(response).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE, "Content-Length {}: {}", content_length, body);
+        assert_eq!(body["code"], "payload_too_large");
+    }
+    // At the limit the body is read (and is no schedule)
+    let response = app.oneshot(json_request("/schedule", MAX_REQUEST_BODY_BYTES, true)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+// The held capture lock is the slow request; it is released once the
+// timeout has fired
+#[allow(clippy::await_holding_lock)]
+#[tokio::test(start_paused = true)]
+async fn slow_requests_time_out_with_408() {
+    let state = test_state();
+    let (app, _) = router(&state);
+    // Dark-frame calibration waits for the capture lock on a blocking thread
+    let capture = state.capture.write();
+    let response = tokio::spawn(app.oneshot(request("GET", "/control/black_level/auto")));
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+    tokio::time::advance(REQUEST_TIMEOUT + Duration::from_secs(1)).await;
+    let response = response.await.unwrap().unwrap();
+    drop(capture);
+    assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    assert_eq!(json_body(response).await["code"], "timeout");
+}
+
+#[tokio::test(start_paused = true)]
+async fn streams_outlive_the_request_timeout() {
+    use futures::StreamExt;
+
+    let state = test_state();
+    let (app, _) = router(&state);
+    let response = app.oneshot(request("GET", "/stream")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = response.into_body().into_data_stream();
+
+    tokio::time::sleep(REQUEST_TIMEOUT * 3).await;
+    publish_frame(&state, 1, b"\xFF\xD8first frame\xFF\xD9");
+    let mut received = Vec::new();
+    while !received.windows(11).any(|w| w == b"first frame") {
+        let chunk = tokio::time::timeout(Duration::from_secs(1), body.next())
+            .await
+            .expect("stream stalled")
+            .expect("stream ended")
+            .unwrap();
+        received.extend_from_slice(&chunk);
+    }
+}
+
+#[tokio::test]
+async fn version_reports_the_build() {
+    let (app, _) = router(&test_state());
+    let response = app.oneshot(request("GET", "/version")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = json_body(response).await;
+    
+    let mut keys: Vec<&str> = body.as_object().unwrap().keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    assert_eq!(
+        keys,
+        ["build_timestamp", "features", "git_commit", "git_commit_short", "git_dirty", "rustc_version", "version"]
+    );
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    let commit = body["git_commit"].as_str().unwrap();
+    assert!(commit.starts_with(body["git_commit_short"].as_str().unwrap()));
+    assert!(body["git_dirty"].is_boolean() || body["git_dirty"].is_null());
+    assert!(body["rustc_version"].as_str().unwrap().starts_with("rustc "));
+    assert!(chrono::DateTime::parse_from_rfc3339(body["build_timestamp"].as_str().unwrap()).is_ok());
+    assert!(body["features"].as_array().unwrap().iter().any(|f| f == "server"));
+}
+
+#[tokio::test]
+async fn disconnected_stream_clients_release_their_slot() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+    let state = test_state();
+    let (app, _) = router(&state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(conn::serve(listener, app));
+    let subscribers = state.frame_watch.receiver_count();
+
+    // The capture loop, publishing at 30 fps
+    let publisher = tokio::spawn({
+        let state = state.clone();
+        async move {
+            for seq in 1.. {
+                publish_frame(&state, seq, b"\xFF\xD8frame\xFF\xD9");
+                tokio::time::sleep(FRAME_INTERVAL).await;
+            }
+        }
+    });
+
+    let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"GET /stream HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 4096];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while received.windows(12).filter(|w| w == b"X-Frame-Seq:").count() < 2 {
+            let n = client.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "stream ended");
+            received.extend_from_slice(&buf[..n]);
+        }
+    })
+    .await
+    .expect("two parts not received");
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.1 200 OK"), "{}", received);
+    assert!(received.contains("X-Timestamp: "), "{}", received);
+    assert_eq!(state.stream_clients.load(Ordering::Relaxed), 1);
+
+    // A dropped connection is noticed by the next write or two (the first
+    // after the close can still be buffered), not by a TCP timeout; the
+    // bound leaves room for a loaded test machine
+    drop(client);
+    let closed = std::time::Instant::now();
+    while state.stream_clients.load(Ordering::Relaxed) != 0 {
+        assert!(closed.elapsed() < 10 * FRAME_INTERVAL, "client slot still held");
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert_eq!(state.frame_watch.receiver_count(), subscribers);
+    publisher.abort();
+    server.abort();
+}
+
+/// Send `request` and check the error status and the `{code, message,
+/// details}` body every failure has; returns the details
+async fn expect_error(
+    app: &Router,
+    request: axum::http::Request<Body>,
+    status: StatusCode,
+    code: &str,
+) -> serde_json::Value {
+    let uri = request.uri().clone();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), status, "{}", uri);
+    let content_type = response.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap();
+    assert!(content_type.starts_with("application/json"), "{}: {}", uri, content_type);
+    let mut body = json_body(response).await;
+    let object = body.as_object_mut().unwrap();
+    let mut keys: Vec<&str> = object.keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    assert_eq!(keys, ["code", "details", "message"], "{}", uri);
+    assert_eq!(object["code"], code, "{}", uri);
+    assert!(!object["message"].as_str().unwrap().is_empty(), "{}", uri);
+    object.remove("details").unwrap()
+}
+
+#[tokio::test]
+async fn errors_have_their_status_and_body_shape() {
+    let state = test_state();
+    let (app, _) = router(&state);
+    let cases = [
+        // Bad input, in the path, the query or the body
+        ("GET", "/mode/sepia", StatusCode::BAD_REQUEST, "bad_request"),
+        ("GET", "/mode/color-hdr?ratio=1000", StatusCode::BAD_REQUEST, "bad_request"),
+        ("GET", "/detect/maybe", StatusCode::BAD_REQUEST, "bad_request"),
+        ("GET", "/jobs/first", StatusCode::BAD_REQUEST, "bad_request"),
+        // Unknown resources and routes
+        ("GET", "/jobs/999", StatusCode::NOT_FOUND, "not_found"),
+        ("GET", "/stack/status", StatusCode::NOT_FOUND, "not_found"),
+        ("GET", "/no/such/endpoint", StatusCode::NOT_FOUND, "not_found"),
+        ("DELETE", "/status", StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"),
+        // Conflicting state
+        ("POST", "/stack/cancel", StatusCode::CONFLICT, "conflict"),
+        // No camera, frames or detector
+        ("GET", "/frame.jpg", StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+        ("POST", "/detect/once", StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+        ("GET", "/detect/on", StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+    ];
+    for (method, uri, status, code) in cases {
+        expect_error(&app, request(method, uri), status, code).await;
+    }
+
+    // A malformed JSON body
+    let mut malformed = request_from("192.0.2.10:50000", "POST", "/schedule", Body::from("{"));
+    malformed
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    expect_error(&app, malformed, StatusCode::BAD_REQUEST, "bad_request").await;
+
+    // With a frame but no detector
+    *state.clean_frame.write() = Some(Bytes::from_static(b"\xFF\xD8\xFF\xD9"));
+    expect_error(&app, request("POST", "/detect/once"), StatusCode::SERVICE_UNAVAILABLE, "unavailable").await;
+}
+
+#[tokio::test]
+async fn rate_limited_requests_get_429_with_retry_after() {
+    let state = test_state_with(RateLimitConfig { api_per_sec: Some(0.5), ..Default::default() });
+    let (app, _) = router(&state);
+    // A burst of one request
+    let response = app.clone().oneshot(request("GET", "/version")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // then a token every two seconds
+    let response = app.clone().oneshot(request("GET", "/version")).await.unwrap();
+    assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+    let details = expect_error(&app, request("GET", "/version"), StatusCode::TOO_MANY_REQUESTS, "rate_limited").await;
+    assert_eq!(details["retry_after_secs"], 2);
+    assert_eq!(state.rate_limiter.rejected(ratelimit::RouteClass::Api), 2);
+    // Other clients have their own buckets
+    let other = request_from("192.0.2.11:50000", "GET", "/version", Body::empty());
+    assert_eq!(app.oneshot(other).await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn detection_events_reach_every_subscriber() {
+    use futures::StreamExt;
+
+    let state = test_state();
+    start_event_subscribers(&state);
+    let (app, _) = router(&state);
+    let response = app.clone().oneshot(request("GET", "/events/stream")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+    let mut sse = response.into_body().into_data_stream();
+
+    // A detection then an empty result: confirmed, then cleared
+    let detection = detector::Detection {
+        class: "person".to_string(),
+        confidence: 0.8,
+        bbox: detector::BBox { x1: 10, y1: 20, x2: 110, y2: 220 },
+        label: None,
+        landmarks: Vec::new(),
+        zones: Vec::new(),
+    };
+    record_detections(&state, DetectionResult { frame_seq: Some(1), detections: vec![detection], ..Default::default() });
+    record_detections(&state, DetectionResult { frame_seq: Some(2), ..Default::default() });
+
+    let mut received = String::new();
+    while !received.contains("event: detection_cleared") {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), sse.next())
+            .await
+            .expect("no event on the stream")
+            .expect("stream ended")
+            .unwrap();
+        received.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    let confirmed = received.split("\n\n").find(|m| m.contains("event: detection_confirmed")).unwrap();
+    assert!(confirmed.lines().any(|line| line == "id: 1"), "{}", confirmed);
+    let data = confirmed.lines().find_map(|line| line.strip_prefix("data: ")).unwrap();
+    let data: serde_json::Value = serde_json::from_str(data).unwrap();
+    assert_eq!(data["type"], "detection_confirmed");
+    assert_eq!(data["classes"], serde_json::json!(["person"]));
+
+    // The event log and the counters saw both
+    let mut events = serde_json::Value::Null;
+    for _ in 0..100 {
+        events = json_body(app.clone().oneshot(request("GET", "/events")).await.unwrap()).await;
+        if events["events"].as_array().unwrap().len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let kinds: Vec<&str> = events["events"].as_array().unwrap().iter().map(|e| e["type"].as_str().unwrap()).collect();
+    assert_eq!(kinds, ["detection_confirmed", "detection_cleared"]);
+    assert_eq!(events["published"], 2);
+
+    let stats = json_body(app.oneshot(request("GET", "/stats")).await.unwrap()).await;
+    assert_eq!(stats["events"]["counts"]["detection_confirmed"], 1);
+    assert_eq!(stats["events"]["counts"]["detection_cleared"], 1);
+    let sse_stats = stats["events"]["subscribers"].as_array().unwrap().iter().find(|s| s["name"] == "sse").unwrap();
+    assert_eq!((sse_stats["active"].as_u64(), sse_stats["dropped"].as_u64()), (Some(1), Some(0)));
+}
+
+#[tokio::test]
+async fn labels_are_drawn_while_the_api_keeps_the_class() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = test_state();
+    *state.profiles.write() = ProfileStore::empty(&dir.path().join("state.json"));
+    let (app, _) = router(&state);
+
+    let body = Body::from(r#"{"person": "mensch"}"#);
+    let mut set = request_from("192.0.2.10:50000", "POST", "/detect/labels", body);
+    set.headers_mut().insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    let response = app.clone().oneshot(set).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(json_body(response).await["labels"], serde_json::json!({ "person": "mensch" }));
+    // Persisted under the annotation key
+    let saved = profiles::read_state_key(&dir.path().join("state.json"), annotation::STATE_KEY).unwrap().unwrap();
+    assert_eq!(saved["labels"]["person"], "mensch");
+
+    let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(320, 240, image::Rgb([90, 90, 90])));
+    let jpeg = crate::capture::encode_image_jpeg(&image, 90).unwrap();
+    state.history.write().push(1, unix_millis(), Bytes::from(jpeg.clone()));
+    let detection = |class: &str, x1: i32| detector::Detection {
+        class: class.to_string(),
+        confidence: 0.9,
+        bbox: detector::BBox { x1, y1: 60, x2: x1 + 100, y2: 180 },
+        label: None,
+        landmarks: Vec::new(),
+        zones: Vec::new(),
+    };
+    let result = DetectionResult { frame_seq: Some(1), detections: vec![detection("person", 20), detection("car", 200)], ..Default::default() };
+    record_detections(&state, result);
+
+    let detections = json_body(app.clone().oneshot(request("GET", "/detections")).await.unwrap()).await;
+    assert_eq!(detections["detections"][0]["class"], "person");
+    assert_eq!(detections["detections"][0]["label"], "mensch");
+    assert_eq!(detections["detections"][1]["class"], "car");
+    assert!(detections["detections"][1].get("label").is_none());
+
+    let response = app.oneshot(request("GET", "/history/1/annotated.jpg")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let drawn = axum::body::to_bytes(response.into_body(), 1 << 20).await.unwrap();
+    let decoded = image::load_from_memory(&jpeg).unwrap();
+    let style = AnnotationStyle::default();
+    let expected = detector::annotate_image(&decoded, &[detection("mensch", 20), detection("car", 200)], &style).unwrap();
+    let unmapped = detector::annotate_image(&decoded, &[detection("person", 20), detection("car", 200)], &style).unwrap();
+    assert_eq!(drawn, expected);
+    assert_ne!(drawn, unmapped);
+}
+
+#[tokio::test]
+async fn streamed_bytes_are_counted_as_sent() {
+    use futures::StreamExt;
+
+    const FRAMES: [&[u8]; 5] = [
+        b"\xFF\xD8frame one\xFF\xD9",
+        b"\xFF\xD8frame two, a little longer\xFF\xD9",
+        b"\xFF\xD8frame three\xFF\xD9",
+        b"\xFF\xD8frame four, longer than the others\xFF\xD9",
+        b"\xFF\xD8frame five\xFF\xD9",
+    ];
+    let state = test_state();
+    let (app, _) = router(&state);
+    let response = app.clone().oneshot(request("GET", "/stream")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = response.into_body().into_data_stream();
+
+    // One frame at a time, so the watch channel doesn't skip any, a second
+    // apart so the stream's frame rate cap doesn't either
+    let mut received = Vec::new();
+    let start_ms = unix_millis();
+    for (seq, frame) in (1..).zip(FRAMES) {
+        state.frame_watch.send_replace(Some(PushFrame {
+            seq,
+            timestamp_ms: start_ms + seq * 1000,
+            jpeg: Bytes::from_static(frame),
+            hash: seq,
+            timing: Default::default(),
+        }));
+        while !received.windows(frame.len()).any(|w| w == frame) {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("stream stalled")
+                .expect("stream ended")
+                .unwrap();
+            received.extend_from_slice(&chunk);
+        }
+    }
+    let sent = received.len() as u64;
+    assert!(sent > FRAMES.iter().map(|f| f.len() as u64).sum::<u64>());
+
+    // Counted as the body was polled: exactly what the client has read
+    let stats = state.bandwidth.stats();
+    let stream = &stats.endpoints["/stream"];
+    assert_eq!((stream.bytes, stream.responses), (sent, 1));
+    assert_eq!(stats.connections.len(), 1);
+    let connection = &stats.connections[0];
+    assert_eq!(connection.endpoint, "/stream");
+    assert_eq!(connection.bytes, sent);
+    assert_eq!(connection.client, Some("192.0.2.10".parse().unwrap()));
+    assert_eq!(stats.total_bytes, sent);
+
+    // The connection leaves the list when the client goes, its bytes stay
+    drop(body);
+    let stats = json_body(app.clone().oneshot(request("GET", "/stats/bandwidth")).await.unwrap()).await;
+    assert_eq!(stats["connections"], serde_json::json!([]));
+    assert_eq!(stats["endpoints"]["/stream"]["bytes"], sent);
+
+    let response = app.oneshot(request("GET", "/metrics")).await.unwrap();
+    let metrics = axum::body::to_bytes(response.into_body(), 1 << 20).await.unwrap();
+    let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+    let sample = metrics
+        .lines()
+        .find(|line| line.contains("http_response_bytes_total{endpoint=\"/stream\"}"))
+        .unwrap();
+    assert_eq!(sample.rsplit(' ').next().unwrap().parse::<u64>().unwrap(), sent);
+}
@@ -0,0 +1,99 @@
+//! Unsharp-mask sharpening
+//!
+//! The raw pipeline has no edge enhancement, so its output looks soft next
+//! to an ISP's: bilinear demosaic and the grayscale upscale both smear
+//! detail over a couple of pixels. An unsharp mask subtracts a box-blurred
+//! copy of the frame from it and adds `amount` times the difference back,
+//! steepening every edge. Differences up to `threshold` levels are left
+//! alone so flat areas don't have their noise sharpened too.
+//!
+//! It runs last in the processing, on the 8-bit output: on color frames
+//! after gamma, on grayscale frames after the upscale.
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Amounts accepted (0 disables)
+pub const AMOUNTS: std::ops::RangeInclusive<f32> = 0.0..=4.0;
+
+/// Blur radii accepted, in pixels
+pub const RADII: std::ops::RangeInclusive<u8> = 1..=4;
+
+/// Sharpening settings (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct Sharpen {
+    /// Share of the detail added back (0 disables; 0.5-1.5 is typical)
+    pub amount: f32,
+    /// Box blur radius: 1 sharpens the finest detail, larger radii wider
+    /// edges
+    pub radius: u8,
+    /// Differences from the blur, in 8-bit levels, left alone as noise
+    pub threshold: u8,
+}
+
+impl Default for Sharpen {
+    fn default() -> Self {
+        Self { amount: 0.0, radius: 1, threshold: 2 }
+    }
+}
+
+impl Sharpen {
+    pub fn validate(&self) -> Result<(), String> {
+        if !AMOUNTS.contains(&self.amount) {
+            return Err(format!("amount must be {}-{}", AMOUNTS.start(), AMOUNTS.end()));
+        }
+        if !RADII.contains(&self.radius) {
+            return Err(format!("radius must be {}-{}", RADII.start(), RADII.end()));
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.amount > 0.0
+    }
+
+    /// Sharpen a frame of interleaved `channels`-channel pixels, `width`
+    /// pixels to a row, in place (rows in parallel on the current rayon
+    /// pool)
+    pub fn apply(&self, frame: &mut [u8], width: usize, channels: usize) {
+        let stride = width * channels;
+        if !self.is_enabled() || stride == 0 || frame.len() < stride {
+            return;
+        }
+        let height = frame.len() / stride;
+        let radius = self.radius as isize;
+        // Horizontal box sums, then vertical ones over the rows around each
+        let mut sums = vec![0u16; frame.len()];
+        sums.par_chunks_exact_mut(stride).zip(frame.par_chunks_exact(stride)).for_each(|(sums, row)| {
+            for x in 0..width {
+                for c in 0..channels {
+                    sums[x * channels + c] = (-radius..=radius)
+                        .map(|dx| {
+                            let nx = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+                            row[nx * channels + c] as u16
+                        })
+                        .sum();
+                }
+            }
+        });
+        let area = ((2 * radius + 1) * (2 * radius + 1)) as i32;
+        let amount = (self.amount * 256.0).round() as i32;
+        let threshold = self.threshold as i32;
+        frame.par_chunks_exact_mut(stride).enumerate().for_each(|(y, row)| {
+            for (i, v) in row.iter_mut().enumerate() {
+                let sum: i32 = (-radius..=radius)
+                    .map(|dy| {
+                        let ny = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+                        sums[ny * stride + i] as i32
+                    })
+                    .sum();
+                let detail = *v as i32 - (sum + area / 2) / area;
+                if detail.abs() > threshold {
+                    *v = (*v as i32 + (detail * amount + 128) / 256).clamp(0, 255) as u8;
+                }
+            }
+        });
+    }
+}
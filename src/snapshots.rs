@@ -0,0 +1,166 @@
+//! Server-side snapshot archive
+//!
+//! `POST /snapshots` stores the current frame as `snapshot-<id>.jpg` in the
+//! snapshots directory (next to job snapshots, which the archive leaves
+//! alone). The id is the frame's capture time in Unix milliseconds, moved
+//! on by a millisecond if that file already exists, so ids sort by time and
+//! the directory itself is the index: nothing else to keep in sync.
+//!
+//! Old snapshots are pruned by age and by the total size of the archive,
+//! oldest first, after every save and periodically.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Default age limit (`--snapshot-max-age-hours`)
+pub const DEFAULT_MAX_AGE_HOURS: u64 = 7 * 24;
+
+/// Default size limit (`--snapshot-max-mb`)
+pub const DEFAULT_MAX_MB: u64 = 1024;
+
+/// How often the age limit is applied between saves
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+const PREFIX: &str = "snapshot-";
+const EXTENSION: &str = ".jpg";
+
+/// Pruning limits (None: no limit)
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Retention {
+    pub max_age_hours: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// A stored snapshot
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SnapshotInfo {
+    pub id: u64,
+    /// Capture time of the frame (Unix ms)
+    pub timestamp_ms: u64,
+    pub bytes: u64,
+}
+
+/// What a prune removed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Pruned {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+pub struct SnapshotArchive {
+    dir: PathBuf,
+    retention: Retention,
+    /// Serializes id allocation and pruning
+    lock: parking_lot::Mutex<()>,
+}
+
+impl SnapshotArchive {
+    pub fn new(dir: &Path, retention: Retention) -> Self {
+        Self { dir: dir.to_path_buf(), retention, lock: parking_lot::Mutex::new(()) }
+    }
+
+    pub fn retention(&self) -> Retention {
+        self.retention
+    }
+
+    /// Store `jpeg`, captured at `timestamp_ms`, then prune (blocking)
+    pub fn save(&self, jpeg: &[u8], timestamp_ms: u64) -> Result<(SnapshotInfo, Pruned)> {
+        let _guard = self.lock.lock();
+        std::fs::create_dir_all(&self.dir).with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let mut id = timestamp_ms;
+        while self.path(id).exists() {
+            id += 1;
+        }
+        // Written under a temporary name so a listing never shows a partial file
+        let path = self.path(id);
+        let temp = path.with_extension("jpg.tmp");
+        std::fs::write(&temp, jpeg).with_context(|| format!("Failed to write {}", temp.display()))?;
+        std::fs::rename(&temp, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+        let info = SnapshotInfo { id, timestamp_ms: id, bytes: jpeg.len() as u64 };
+        let pruned = self.prune_locked(crate::unix_millis(), Some(id))?;
+        Ok((info, pruned))
+    }
+
+    /// Stored snapshots, oldest first (blocking)
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.dir.display())),
+        };
+        let mut snapshots: Vec<_> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let id = parse_id(name.to_str()?)?;
+                let bytes = entry.metadata().ok()?.len();
+                Some(SnapshotInfo { id, timestamp_ms: id, bytes })
+            })
+            .collect();
+        snapshots.sort_by_key(|s| s.id);
+        Ok(snapshots)
+    }
+
+    /// The snapshot's JPEG; None if there is no such snapshot (blocking)
+    pub fn read(&self, id: u64) -> Result<Option<Vec<u8>>> {
+        let path = self.path(id);
+        match std::fs::read(&path) {
+            Ok(jpeg) => Ok(Some(jpeg)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Remove a snapshot; false if there is no such snapshot (blocking)
+    pub fn delete(&self, id: u64) -> Result<bool> {
+        let path = self.path(id);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+        }
+    }
+
+    /// Remove snapshots past the age limit, then the oldest until the
+    /// archive fits the size limit (blocking)
+    pub fn prune(&self, now_ms: u64) -> Result<Pruned> {
+        let _guard = self.lock.lock();
+        self.prune_locked(now_ms, None)
+    }
+
+    /// `keep`: a snapshot just saved, never removed to make room for itself
+    fn prune_locked(&self, now_ms: u64, keep: Option<u64>) -> Result<Pruned> {
+        let snapshots = self.list()?;
+        let mut total: u64 = snapshots.iter().map(|s| s.bytes).sum();
+        let oldest_kept = self
+            .retention
+            .max_age_hours
+            .map(|hours| now_ms.saturating_sub(hours.saturating_mul(3_600_000)));
+        let mut pruned = Pruned::default();
+        for snapshot in snapshots {
+            let expired = oldest_kept.is_some_and(|oldest| snapshot.timestamp_ms < oldest);
+            let over_size = self.retention.max_bytes.is_some_and(|max| total > max);
+            if !(expired || over_size) || Some(snapshot.id) == keep {
+                continue;
+            }
+            if self.delete(snapshot.id)? {
+                total -= snapshot.bytes;
+                pruned.files += 1;
+                pruned.bytes += snapshot.bytes;
+            }
+        }
+        Ok(pruned)
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}{}{}", PREFIX, id, EXTENSION))
+    }
+}
+
+/// Id from a `snapshot-<id>.jpg` file name
+fn parse_id(name: &str) -> Option<u64> {
+    name.strip_prefix(PREFIX)?.strip_suffix(EXTENSION)?.parse().ok()
+}
@@ -0,0 +1,330 @@
+//! Where raw frames come from
+//!
+//! [`FrameCapture`](crate::capture::FrameCapture) takes its packed raw
+//! frames from a [`FrameSource`]: the sensor through V4L2
+//! ([`V4l2Source`]), raw dumps ([`FileSource`]) or a generated test pattern
+//! ([`SyntheticSource`]). The last two need no camera, so the processing
+//! pipeline and detection can be run off-device; they deliver frames at
+//! the sensor's rate and have no exposure to control, so auto-exposure and
+//! exposure fusion are left out for them.
+
+use crate::capture::FrameSize;
+use crate::rawformat::{Packing, RawFormat};
+use crate::stream::V4l2Stream;
+use anyhow::{Context, Result};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Frame rate of the stock sensor mode
+pub const FPS: u32 = 30;
+
+/// Frame interval of the sensor, kept by the sources that aren't one
+pub const FRAME_INTERVAL: Duration = Duration::from_micros(1_000_000 / FPS as u64);
+
+/// Which source a capture opens (`CaptureConfig::source`)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum SourceConfig {
+    /// The sensor, through the capture device
+    #[default]
+    V4l2,
+    /// A raw dump, or a directory of `*.raw` dumps, played in a loop
+    Files { path: PathBuf, format: RawFormat, size: FrameSize, stride: usize },
+    /// A generated test pattern
+    Synthetic { format: RawFormat, size: FrameSize, stride: usize },
+}
+
+impl SourceConfig {
+    /// Whether the source is a sensor whose controls can be set
+    pub fn has_sensor(&self) -> bool {
+        matches!(self, SourceConfig::V4l2)
+    }
+}
+
+/// A supplier of packed raw frames (of the capture's frame size and
+/// stride)
+pub trait FrameSource: Send {
+    /// Short name for logs and status reports
+    fn name(&self) -> &'static str;
+
+    /// Whether a sensor is behind the source: auto-exposure and exposure
+    /// fusion need one
+    fn has_sensor(&self) -> bool;
+
+    /// The next frame not returned before, waiting up to `timeout` for it
+    fn next_frame(&mut self, timeout: Duration) -> Result<Vec<u8>>;
+
+    /// Never return the frame in flight nor the `frames` after it
+    fn skip(&mut self, frames: u64);
+}
+
+/// The sensor through a V4L2 capture stream, restarted when it ends or
+/// stalls
+pub struct V4l2Source {
+    device: String,
+    frame_bytes: usize,
+    stream: Option<V4l2Stream>,
+}
+
+impl V4l2Source {
+    /// Start streaming `device`; every buffer is `frame_bytes` long (the
+    /// device's `sizeimage`)
+    pub fn start(device: &str, frame_bytes: usize) -> Result<Self> {
+        let stream = V4l2Stream::start(device, frame_bytes)?;
+        Ok(Self { device: device.to_string(), frame_bytes, stream: Some(stream) })
+    }
+}
+
+impl FrameSource for V4l2Source {
+    fn name(&self) -> &'static str {
+        "v4l2"
+    }
+
+    fn has_sensor(&self) -> bool {
+        true
+    }
+
+    fn next_frame(&mut self, timeout: Duration) -> Result<Vec<u8>> {
+        let running = match self.stream.take() {
+            Some(running) if running.is_running() => running,
+            previous => {
+                if previous.is_some() {
+                    tracing::debug!("Capture stream ended, restarting");
+                }
+                // The old process must release the device first
+                drop(previous);
+                V4l2Stream::start(&self.device, self.frame_bytes)?
+            }
+        };
+        let frame = running.next_frame(timeout);
+        // A stalled stream is killed here and restarted on the next call
+        if frame.is_ok() {
+            self.stream = Some(running);
+        }
+        frame
+    }
+
+    fn skip(&mut self, frames: u64) {
+        if let Some(ref stream) = self.stream {
+            stream.skip(frames);
+        }
+    }
+}
+
+/// Keeps a source that isn't a sensor at the sensor's frame rate
+struct Pacer {
+    next: Instant,
+}
+
+impl Pacer {
+    fn new() -> Self {
+        Self { next: Instant::now() }
+    }
+
+    /// Wait for the next frame time, without catching up on missed ones
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next > now {
+            std::thread::sleep(self.next - now);
+        }
+        self.next = self.next.max(now) + FRAME_INTERVAL;
+    }
+}
+
+/// Raw dumps played in a loop: one file, or every `*.raw` file of a
+/// directory in name order. A file may hold several frames back to back.
+pub struct FileSource {
+    /// Each frame's file and offset
+    frames: Vec<(PathBuf, u64)>,
+    frame_bytes: usize,
+    next: usize,
+    pacer: Pacer,
+}
+
+impl FileSource {
+    pub fn open(path: &Path, frame_bytes: usize) -> Result<Self> {
+        let files = if path.is_dir() {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("raw")))
+                .collect();
+            files.sort();
+            files
+        } else {
+            vec![path.to_path_buf()]
+        };
+        let mut frames = Vec::new();
+        for file in files {
+            let len = std::fs::metadata(&file).with_context(|| format!("Failed to read {}", file.display()))?.len();
+            let count = len / frame_bytes as u64;
+            if count == 0 {
+                tracing::warn!("{} is shorter than a frame ({} bytes), skipped", file.display(), frame_bytes);
+            }
+            frames.extend((0..count).map(|i| (file.clone(), i * frame_bytes as u64)));
+        }
+        anyhow::ensure!(!frames.is_empty(), "No raw frames in {}", path.display());
+        Ok(Self { frames, frame_bytes, next: 0, pacer: Pacer::new() })
+    }
+
+    /// Frames in one pass
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+impl FrameSource for FileSource {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn has_sensor(&self) -> bool {
+        false
+    }
+
+    fn next_frame(&mut self, _timeout: Duration) -> Result<Vec<u8>> {
+        self.pacer.wait();
+        let (ref path, offset) = self.frames[self.next];
+        self.next = (self.next + 1) % self.frames.len();
+        let mut file = std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut frame = vec![0u8; self.frame_bytes];
+        file.read_exact(&mut frame).with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(frame)
+    }
+
+    fn skip(&mut self, frames: u64) {
+        self.next = (self.next + frames as usize) % self.frames.len();
+    }
+}
+
+/// Side of the moving square (a multiple of 4, the packed group width)
+const SQUARE: usize = 256;
+
+/// Horizontal step of the square per frame
+const SQUARE_STEP: usize = 16;
+
+/// 75% color bars (white, yellow, cyan, green, magenta, red, blue, black)
+/// over a gray ramp, with a white square moving across the bars so that
+/// consecutive frames differ (motion detection, frame hashes). Samples are
+/// 10-bit linear.
+///
+/// In 10-bit packed formats the fifth byte of each group, which the
+/// grayscale mode reads, carries the group's 8-bit luma as the IMX415 puts
+/// it there, rather than the LSBs of the samples; color output only loses
+/// the two least significant bits to it.
+pub struct SyntheticSource {
+    format: RawFormat,
+    size: FrameSize,
+    stride: usize,
+    /// The frame without the square
+    background: Vec<u8>,
+    frame: u64,
+    pacer: Pacer,
+}
+
+impl SyntheticSource {
+    pub fn new(format: RawFormat, size: FrameSize, stride: usize) -> Result<Self> {
+        size.validate().map_err(anyhow::Error::msg)?;
+        anyhow::ensure!(
+            stride >= format.min_stride(size.width),
+            "{} bytes per line is too short for {} at {}",
+            stride,
+            format.fourcc,
+            size
+        );
+        let background = vec![0u8; stride * size.height];
+        let mut source = Self { format, size, stride, background, frame: 0, pacer: Pacer::new() };
+        let mut background = std::mem::take(&mut source.background);
+        for (y, line) in background.chunks_exact_mut(stride).enumerate() {
+            source.render_line(y, None, line);
+        }
+        source.background = background;
+        Ok(source)
+    }
+
+    /// Left edge of the square in `frame`, bouncing between the sides
+    fn square_x(&self, frame: u64) -> usize {
+        let span = (self.size.width - SQUARE) / SQUARE_STEP;
+        let step = (frame % (2 * span as u64)) as usize;
+        let step = if step < span { step } else { 2 * span - step };
+        step * SQUARE_STEP
+    }
+
+    /// Rows the square covers (the middle of the bars)
+    fn square_rows(&self) -> std::ops::Range<usize> {
+        let top = self.size.height * 3 / 8 - SQUARE / 2;
+        top..top + SQUARE
+    }
+
+    /// Linear 10-bit RGB of the pattern at (x, y)
+    fn color(&self, x: usize, y: usize, square_x: Option<usize>) -> [u16; 3] {
+        let FrameSize { width, height } = self.size;
+        const LEVEL: u16 = 767;
+        if square_x.is_some_and(|left| (left..left + SQUARE).contains(&x)) {
+            return [1023; 3];
+        }
+        if y >= height * 3 / 4 {
+            let v = (x * 1023 / (width - 1)) as u16;
+            return [v; 3];
+        }
+        // Bars in the order above: bit 2 red, bit 1 green, bit 0 blue
+        const BARS: [u8; 8] = [0b111, 0b110, 0b011, 0b010, 0b101, 0b100, 0b001, 0b000];
+        let bar = BARS[x * 8 / width];
+        [0b100, 0b010, 0b001].map(|bit| if bar & bit != 0 { LEVEL } else { 0 })
+    }
+
+    fn render_line(&self, y: usize, square_x: Option<usize>, line: &mut [u8]) {
+        let (dy, dx) = self.format.cfa.gbrg_offset();
+        let colors: Vec<[u16; 3]> = (0..self.size.width).map(|x| self.color(x, y, square_x)).collect();
+        // GBRG sites: G B on even rows, R G on odd ones
+        let samples: Vec<u16> = colors
+            .iter()
+            .enumerate()
+            .map(|(x, rgb)| match ((y + dy) % 2, (x + dx) % 2) {
+                (0, 1) => rgb[2],
+                (1, 0) => rgb[0],
+                _ => rgb[1],
+            })
+            .collect();
+        self.format.pack_line(&samples, line);
+        if self.format.packing == Packing::Packed10 {
+            for (group, pixels) in line.chunks_exact_mut(5).zip(colors.chunks_exact(4)) {
+                let luma: u32 =
+                    pixels.iter().map(|&[r, g, b]| (77 * r as u32 + 150 * g as u32 + 29 * b as u32) >> 8).sum();
+                // Mean of the four, 10 to 8 bits
+                group[4] = (luma / 16) as u8;
+            }
+        }
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn name(&self) -> &'static str {
+        "synthetic"
+    }
+
+    fn has_sensor(&self) -> bool {
+        false
+    }
+
+    fn next_frame(&mut self, _timeout: Duration) -> Result<Vec<u8>> {
+        self.pacer.wait();
+        let mut frame = self.background.clone();
+        let square_x = self.square_x(self.frame);
+        for y in self.square_rows() {
+            self.render_line(y, Some(square_x), &mut frame[y * self.stride..(y + 1) * self.stride]);
+        }
+        self.frame += 1;
+        Ok(frame)
+    }
+
+    fn skip(&mut self, frames: u64) {
+        self.frame += frames;
+    }
+}
@@ -0,0 +1,613 @@
+//! Bounded disk spool between capture and a slower consumer
+//!
+//! When the SD card can't keep up with sustained 4K MJPEG writes, frames
+//! are buffered on disk instead of being dropped at the source. The spool
+//! is a directory of sequentially numbered chunk files (`0000000042.chunk`)
+//! holding length-prefixed frames:
+//!
+//! ```text
+//! [len: u32 LE][seq: u64 LE][timestamp_ms: u64 LE][len bytes of JPEG]
+//! ```
+//!
+//! The writer batches frames in memory and appends whole batches to the
+//! newest chunk, starting a new chunk once it reaches the chunk size. Only
+//! flushed bytes are visible to the reader, which consumes chunks in order
+//! and deletes each one once it has moved past it. When the spool exceeds
+//! its size cap the oldest chunks are evicted, unread frames included;
+//! evictions are counted so a consumer that genuinely can't keep up shows
+//! in the stats.
+//!
+//! On open, existing chunks are re-indexed: a chunk cut short by a crash
+//! is truncated to its last complete frame, and the recovered frames are
+//! read back before anything new. The reader's position is kept in
+//! `read.pos` (chunk index and offset, two little-endian `u64`s) so
+//! recovery resumes after the last frame handed out.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use tokio::sync::Notify;
+
+/// Default size cap (MiB)
+pub const DEFAULT_SPOOL_MB: u64 = 512;
+
+/// Chunk size: large enough to keep file churn low, small enough that
+/// eviction frees space in useful steps
+const CHUNK_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Fewest chunks the size cap is split into
+const MIN_CHUNKS: u64 = 4;
+
+/// Batched bytes that trigger a write
+const BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Record header: length, sequence, timestamp
+const HEADER_BYTES: u64 = 20;
+
+/// Larger records are treated as corruption
+const MAX_FRAME_BYTES: u32 = 64 * 1024 * 1024;
+
+/// A spooled frame
+#[derive(Debug, Clone)]
+pub struct SpoolFrame {
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub jpeg: Bytes,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    index: u64,
+    /// Flushed (readable) bytes
+    bytes: u64,
+    frames: u64,
+}
+
+/// Reader position: chunk index, byte offset and frames read in it
+#[derive(Debug, Clone, Copy, Default)]
+struct ReadPos {
+    chunk: u64,
+    offset: u64,
+    frames: u64,
+}
+
+/// Spool depth and eviction counters
+#[derive(Debug, Clone, Serialize)]
+pub struct SpoolStats {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    pub chunks: usize,
+    /// Spooled frames not yet read
+    pub depth_frames: u64,
+    pub depth_bytes: u64,
+    pub evicted_chunks: u64,
+    /// Unread frames lost to eviction
+    pub evicted_frames: u64,
+    /// Frames found on disk when the spool was opened
+    pub recovered_frames: u64,
+}
+
+struct Inner {
+    chunks: VecDeque<Chunk>,
+    next_index: u64,
+    /// Open handle on the newest chunk, None until the next flush starts one
+    writer: Option<File>,
+    batch: Vec<u8>,
+    batch_frames: u64,
+    read: ReadPos,
+    /// No more frames until `resume`; the reader stops once caught up
+    closed: bool,
+    evicted_chunks: u64,
+    evicted_frames: u64,
+    recovered_frames: u64,
+}
+
+/// The reader's files, kept apart from `Inner` so reads don't hold up
+/// the writer
+struct ReadFiles {
+    /// Open chunk: index, byte offset the reader is at, reader
+    chunk: Option<(u64, u64, BufReader<File>)>,
+    /// `read.pos`, rewritten after every frame read
+    position: File,
+}
+
+/// A disk spool with one writer and one reader
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    chunk_bytes: u64,
+    inner: Mutex<Inner>,
+    files: Mutex<ReadFiles>,
+    readable: Notify,
+}
+
+fn chunk_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:010}.chunk", index))
+}
+
+/// End offsets of the complete records in a chunk
+fn scan_chunk(path: &Path) -> Result<Vec<u64>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut ends = Vec::new();
+    let mut offset = 0;
+    let mut header = [0u8; HEADER_BYTES as usize];
+    while offset + HEADER_BYTES <= len {
+        reader.read_exact(&mut header)?;
+        let frame_len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let end = offset + HEADER_BYTES + frame_len as u64;
+        if frame_len > MAX_FRAME_BYTES || end > len {
+            break;
+        }
+        reader.seek_relative(frame_len as i64)?;
+        ends.push(end);
+        offset = end;
+    }
+    Ok(ends)
+}
+
+/// Saved reader position, if readable
+fn read_position(file: &File) -> Option<(u64, u64)> {
+    let mut buf = [0u8; 16];
+    file.read_exact_at(&mut buf, 0).ok()?;
+    Some((
+        u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+        u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+    ))
+}
+
+impl Spool {
+    /// Open (or create) the spool in `dir`, re-indexing existing chunks
+    ///
+    /// The spool starts closed: recovered frames can be read, new ones are
+    /// accepted after `resume`.
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let mut indices: Vec<u64> = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".chunk")?.parse().ok())
+            .collect();
+        indices.sort_unstable();
+
+        let position_path = dir.join("read.pos");
+        let position = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&position_path)
+            .with_context(|| format!("Failed to open {}", position_path.display()))?;
+        let saved = read_position(&position);
+
+        let mut chunks = VecDeque::new();
+        let mut read = ReadPos::default();
+        for index in indices {
+            let path = chunk_path(dir, index);
+            let ends = scan_chunk(&path)?;
+            let len = std::fs::metadata(&path)?.len();
+            let valid = ends.last().copied().unwrap_or(0);
+            // Chunks before the saved position were fully read
+            let read_before = saved.is_some_and(|(chunk, _)| index < chunk);
+            if ends.is_empty() || read_before {
+                std::fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+                continue;
+            }
+            if chunks.is_empty() {
+                read.chunk = index;
+                if let Some((chunk, offset)) = saved.filter(|&(chunk, _)| chunk == index) {
+                    // Only trust a position on a record boundary
+                    if let Ok(frames) = ends.binary_search(&offset) {
+                        read = ReadPos { chunk, offset, frames: frames as u64 + 1 };
+                    }
+                }
+            }
+            if valid < len {
+                tracing::warn!("Spool chunk {} truncated to {} of {} bytes", path.display(), valid, len);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_len(valid))
+                    .with_context(|| format!("Failed to truncate {}", path.display()))?;
+            }
+            chunks.push_back(Chunk { index, bytes: valid, frames: ends.len() as u64 });
+        }
+
+        let recovered_frames = chunks.iter().map(|c| c.frames).sum::<u64>() - read.frames;
+        // Never reuse an index the saved position could refer to
+        let next_index = chunks
+            .back()
+            .map(|c| c.index + 1)
+            .max(saved.map(|(chunk, _)| chunk + 1))
+            .unwrap_or(0);
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            chunk_bytes: CHUNK_BYTES.min(max_bytes / MIN_CHUNKS).max(1),
+            inner: Mutex::new(Inner {
+                chunks,
+                next_index,
+                writer: None,
+                batch: Vec::with_capacity(BATCH_BYTES),
+                batch_frames: 0,
+                read,
+                closed: true,
+                evicted_chunks: 0,
+                evicted_frames: 0,
+                recovered_frames,
+            }),
+            files: Mutex::new(ReadFiles { chunk: None, position }),
+            readable: Notify::new(),
+        })
+    }
+
+    /// Accept frames again after `finish`
+    pub fn resume(&self) {
+        self.inner.lock().closed = false;
+    }
+
+    /// Queue a frame; writes the batch once it is large enough (blocking)
+    pub fn push(&self, frame: &SpoolFrame) -> Result<()> {
+        let mut inner = self.inner.lock();
+        inner.batch.extend_from_slice(&(frame.jpeg.len() as u32).to_le_bytes());
+        inner.batch.extend_from_slice(&frame.seq.to_le_bytes());
+        inner.batch.extend_from_slice(&frame.timestamp_ms.to_le_bytes());
+        inner.batch.extend_from_slice(&frame.jpeg);
+        inner.batch_frames += 1;
+        if inner.batch.len() >= BATCH_BYTES {
+            self.flush_locked(&mut inner)?;
+        }
+        Ok(())
+    }
+
+    /// Write queued frames (blocking)
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        self.flush_locked(&mut inner)
+    }
+
+    /// Write queued frames and let the reader stop once it has caught up
+    pub fn finish(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let flushed = self.flush_locked(&mut inner);
+        inner.closed = true;
+        inner.writer = None;
+        drop(inner);
+        self.readable.notify_one();
+        flushed
+    }
+
+    fn flush_locked(&self, inner: &mut Inner) -> Result<()> {
+        if inner.batch.is_empty() {
+            return Ok(());
+        }
+        let rotate = inner.chunks.back().is_none_or(|c| c.bytes >= self.chunk_bytes);
+        if inner.writer.is_none() || rotate {
+            let index = inner.next_index;
+            let path = chunk_path(&self.dir, index);
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to create {}", path.display()))?;
+            inner.writer = Some(file);
+            inner.next_index += 1;
+            inner.chunks.push_back(Chunk { index, bytes: 0, frames: 0 });
+        }
+
+        let mut batch = std::mem::take(&mut inner.batch);
+        let frames = std::mem::take(&mut inner.batch_frames);
+        let len = batch.len() as u64;
+        let written = match inner.writer.as_mut() {
+            Some(file) => file.write_all(&batch),
+            None => Ok(()),
+        };
+        // Keep the allocation for the next batch
+        batch.clear();
+        inner.batch = batch;
+        if let Err(e) = written {
+            // The chunk may now end in a partial record: drop the batch and
+            // start a new chunk (the reader stops at the last flushed byte,
+            // recovery truncates the tail on next open)
+            inner.writer = None;
+            return Err(e).context("Failed to write spool chunk");
+        }
+        if let Some(chunk) = inner.chunks.back_mut() {
+            chunk.bytes += len;
+            chunk.frames += frames;
+        }
+        self.evict(inner);
+        self.readable.notify_one();
+        Ok(())
+    }
+
+    /// Drop the oldest chunks (never the one being written) while over the cap
+    fn evict(&self, inner: &mut Inner) {
+        let mut total: u64 = inner.chunks.iter().map(|c| c.bytes).sum();
+        while total > self.max_bytes && inner.chunks.len() > 1 {
+            let Some(oldest) = inner.chunks.pop_front() else {
+                break;
+            };
+            total -= oldest.bytes;
+            let _ = std::fs::remove_file(chunk_path(&self.dir, oldest.index));
+            let unread = if inner.read.chunk == oldest.index {
+                oldest.frames - inner.read.frames
+            } else if inner.read.chunk < oldest.index {
+                oldest.frames
+            } else {
+                0
+            };
+            if inner.read.chunk <= oldest.index {
+                inner.read = ReadPos { chunk: oldest.index + 1, ..Default::default() };
+            }
+            inner.evicted_chunks += 1;
+            inner.evicted_frames += unread;
+        }
+    }
+
+    /// Read the next flushed frame, if any (blocking)
+    ///
+    /// `inner` is only held to pick the record and to advance past it; the
+    /// disk reads happen in between, so a concurrent eviction is noticed
+    /// by the read position having moved.
+    fn try_next(&self) -> Result<Option<SpoolFrame>> {
+        let mut files = self.files.lock();
+        loop {
+            let (index, offset) = {
+                let mut inner = self.inner.lock();
+                let Some(&front) = inner.chunks.front() else {
+                    return Ok(None);
+                };
+                if inner.read.chunk < front.index {
+                    inner.read = ReadPos { chunk: front.index, ..Default::default() };
+                }
+                if inner.read.offset < front.bytes {
+                    (front.index, inner.read.offset)
+                } else if inner.chunks.len() > 1 || (inner.closed && front.bytes > 0) {
+                    // Fully read; delete it once the writer has moved past it
+                    inner.chunks.pop_front();
+                    inner.read = ReadPos { chunk: front.index + 1, ..Default::default() };
+                    drop(inner);
+                    files.chunk = None;
+                    let _ = std::fs::remove_file(chunk_path(&self.dir, front.index));
+                    continue;
+                } else {
+                    return Ok(None);
+                }
+            };
+
+            let record = self.read_record(&mut files, index, offset);
+            let mut inner = self.inner.lock();
+            if inner.read.chunk != index || inner.read.offset != offset {
+                // Evicted while reading: those frames are already counted lost
+                files.chunk = None;
+                continue;
+            }
+            let (frame, end) = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    files.chunk = None;
+                    return Err(e);
+                }
+            };
+            inner.read.offset = end;
+            inner.read.frames += 1;
+            drop(inner);
+
+            let mut position = [0u8; 16];
+            position[0..8].copy_from_slice(&index.to_le_bytes());
+            position[8..16].copy_from_slice(&end.to_le_bytes());
+            // Best effort: a stale position only means frames written twice
+            let _ = files.position.write_all_at(&position, 0);
+            return Ok(Some(frame));
+        }
+    }
+
+    /// Read the record at `offset` in chunk `index`; returns it and its end
+    fn read_record(&self, files: &mut ReadFiles, index: u64, offset: u64) -> Result<(SpoolFrame, u64)> {
+        if files.chunk.as_ref().is_none_or(|&(i, at, _)| i != index || at != offset) {
+            let path = chunk_path(&self.dir, index);
+            let file = File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+            let mut reader = BufReader::new(file);
+            reader.seek(SeekFrom::Start(offset))?;
+            files.chunk = Some((index, offset, reader));
+        }
+        let (_, at, reader) = files.chunk.as_mut().unwrap();
+        let mut header = [0u8; HEADER_BYTES as usize];
+        reader.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let mut jpeg = vec![0u8; len as usize];
+        reader.read_exact(&mut jpeg)?;
+        *at = offset + HEADER_BYTES + len as u64;
+        let frame = SpoolFrame {
+            seq: u64::from_le_bytes(header[4..12].try_into().unwrap()),
+            timestamp_ms: u64::from_le_bytes(header[12..20].try_into().unwrap()),
+            jpeg: jpeg.into(),
+        };
+        Ok((frame, *at))
+    }
+
+    /// Next frame in spool order; waits for the writer, None once the
+    /// spool is finished and fully read
+    pub async fn next(&self) -> Result<Option<SpoolFrame>> {
+        loop {
+            let readable = self.readable.notified();
+            if let Some(frame) = tokio::task::block_in_place(|| self.try_next())? {
+                return Ok(Some(frame));
+            }
+            if self.inner.lock().closed {
+                // A finish may have raced the read; check once more
+                return tokio::task::block_in_place(|| self.try_next());
+            }
+            readable.await;
+        }
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        let inner = self.inner.lock();
+        let (depth_frames, depth_bytes) = inner.chunks.iter().fold((0, 0), |(frames, bytes), c| {
+            if c.index == inner.read.chunk {
+                (frames + c.frames - inner.read.frames, bytes + c.bytes - inner.read.offset)
+            } else {
+                (frames + c.frames, bytes + c.bytes)
+            }
+        });
+        SpoolStats {
+            dir: self.dir.clone(),
+            max_bytes: self.max_bytes,
+            chunks: inner.chunks.len(),
+            depth_frames,
+            depth_bytes,
+            evicted_chunks: inner.evicted_chunks,
+            evicted_frames: inner.evicted_frames,
+            recovered_frames: inner.recovered_frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(seq: u64) -> SpoolFrame {
+        SpoolFrame { seq, timestamp_ms: 1000 + seq, jpeg: Bytes::from(vec![seq as u8; 60]) }
+    }
+
+    /// Push and flush each frame on its own, so each is one write
+    fn write(spool: &Spool, seqs: std::ops::Range<u64>) {
+        for seq in seqs {
+            spool.push(&frame(seq)).unwrap();
+            spool.flush().unwrap();
+        }
+    }
+
+    fn read_all(spool: &Spool) -> Vec<u64> {
+        std::iter::from_fn(|| spool.try_next().unwrap()).map(|f| f.seq).collect()
+    }
+
+    fn chunk_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".chunk"))
+            .count()
+    }
+
+    #[test]
+    fn frames_come_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        spool.resume();
+        write(&spool, 0..5);
+        let frame = spool.try_next().unwrap().unwrap();
+        assert_eq!((frame.seq, frame.timestamp_ms, frame.jpeg.len()), (0, 1000, 60));
+        assert_eq!(read_all(&spool), [1, 2, 3, 4]);
+        assert_eq!(spool.stats().depth_frames, 0);
+    }
+
+    #[test]
+    fn unflushed_frames_are_not_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        spool.resume();
+        spool.push(&frame(0)).unwrap();
+        assert!(spool.try_next().unwrap().is_none());
+        spool.flush().unwrap();
+        assert_eq!(read_all(&spool), [0]);
+    }
+
+    #[test]
+    fn finished_chunks_are_deleted_once_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        spool.resume();
+        write(&spool, 0..3);
+        spool.finish().unwrap();
+        assert_eq!(chunk_files(dir.path()), 1);
+        assert_eq!(read_all(&spool), [0, 1, 2]);
+        assert_eq!(chunk_files(dir.path()), 0);
+    }
+
+    #[test]
+    fn eviction_drops_the_oldest_chunk_and_counts_unread_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        // 100-byte chunks; each 80-byte record is a write, two per chunk
+        let spool = Spool::open(dir.path(), 400).unwrap();
+        spool.resume();
+        write(&spool, 0..6);
+        let stats = spool.stats();
+        assert_eq!((stats.evicted_chunks, stats.evicted_frames), (1, 2));
+        assert_eq!((stats.chunks, stats.depth_frames), (2, 4));
+        assert_eq!(chunk_files(dir.path()), 2);
+        assert_eq!(read_all(&spool), [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn eviction_of_a_partly_read_chunk_counts_only_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(dir.path(), 400).unwrap();
+        spool.resume();
+        write(&spool, 0..2);
+        assert_eq!(spool.try_next().unwrap().unwrap().seq, 0);
+        write(&spool, 2..6);
+        let stats = spool.stats();
+        assert_eq!((stats.evicted_chunks, stats.evicted_frames), (1, 1));
+        assert_eq!(read_all(&spool), [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn open_recovers_frames_and_truncates_a_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+            spool.resume();
+            write(&spool, 0..3);
+        }
+        let path = chunk_path(dir.path(), 0);
+        let complete = std::fs::metadata(&path).unwrap().len();
+        // Half a header, as a crash mid-write leaves it
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0x50, 0, 0, 0, 7]).unwrap();
+
+        let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete);
+        assert_eq!(spool.stats().recovered_frames, 3);
+        assert_eq!(read_all(&spool), [0, 1, 2]);
+    }
+
+    #[test]
+    fn open_resumes_after_the_last_frame_read() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+            spool.resume();
+            write(&spool, 0..4);
+            assert_eq!(spool.try_next().unwrap().unwrap().seq, 0);
+            assert_eq!(spool.try_next().unwrap().unwrap().seq, 1);
+        }
+        let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        assert_eq!(spool.stats().recovered_frames, 2);
+        assert_eq!(read_all(&spool), [2, 3]);
+    }
+
+    #[test]
+    fn open_drops_empty_chunks_and_starts_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(chunk_path(dir.path(), 7), b"").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"not a chunk").unwrap();
+        let spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        assert_eq!(chunk_files(dir.path()), 0);
+        assert!(dir.path().join("notes.txt").exists());
+        assert!(spool.inner.lock().closed);
+        spool.resume();
+        write(&spool, 0..1);
+        assert!(chunk_path(dir.path(), 0).exists());
+        assert_eq!(read_all(&spool), [0]);
+    }
+}
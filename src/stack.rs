@@ -0,0 +1,288 @@
+//! Raw frame stacking for long exposures
+//!
+//! For faint scenes (the night sky) the per-frame pipeline throws away most
+//! of what the sensor collects: every output frame carries one exposure's
+//! read and shot noise, rounded to 8 bits. A stack adds up the 10-bit Bayer
+//! samples of N consecutive raw frames as the capture loop reads them,
+//! before any processing, and keeps the total in 32 bits. The result is
+//! written as a 16-bit DNG (the mosaic, for a raw developer or stacking
+//! tool) or a 16-bit PNG (linear, white balanced RGB at half resolution):
+//!
+//! - `mean` divides by N: one frame's brightness with the noise down by
+//!   √N, the extra precision kept in 6 fractional bits (the 10-bit scale
+//!   times 64).
+//! - `sum` keeps the total signal above black, as one exposure N times as
+//!   long would collect it, clipped at 65535 (64 frames at full scale).
+//!
+//! The stream keeps running meanwhile. Nothing holds the sensor controls
+//! still, so turn auto exposure off for the duration; a stack fails if the
+//! frame size or orientation changes under it. In the HDR modes the long
+//! exposure of each pair is added.
+
+use crate::exif::ExifFields;
+use crate::rawformat::Cfa;
+use image::{DynamicImage, ImageBuffer, Rgb};
+use serde::Serialize;
+
+/// Most frames in one stack
+pub const MAX_FRAMES: u32 = 4096;
+
+/// Default frame count of `/stack/start`
+pub const DEFAULT_FRAMES: u32 = 64;
+
+/// 10-bit samples to the 16-bit scale of the mean
+const SCALE: u64 = 64;
+
+/// How the frames are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum StackMode {
+    /// Average: single-frame brightness, less noise
+    Mean,
+    /// Total signal above black, as a longer exposure
+    Sum,
+}
+
+impl StackMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "mean" | "average" => Some(StackMode::Mean),
+            "sum" => Some(StackMode::Sum),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StackMode::Mean => "mean",
+            StackMode::Sum => "sum",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum StackState {
+    /// Adding frames as they are captured
+    Running,
+    /// All frames added; the result can be downloaded
+    Done,
+    /// Abandoned (see `error`)
+    Failed,
+}
+
+/// Geometry of the stacked frames, recorded from the first one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawLayout {
+    pub width: usize,
+    pub height: usize,
+    pub cfa: Cfa,
+    /// TIFF Orientation of the raw samples
+    pub orientation: u16,
+}
+
+/// Progress of a stack, for `/stack/status`
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct StackStatus {
+    pub state: StackState,
+    pub mode: StackMode,
+    /// Frames added so far
+    pub frames: u32,
+    /// Frames asked for
+    pub target: u32,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub started_ms: u64,
+    pub finished_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// The stacked samples on the 16-bit scale, with their levels
+pub struct Stacked {
+    pub samples: Vec<u16>,
+    pub black_level: u16,
+    pub white_level: u16,
+}
+
+/// Sums of N raw frames
+pub struct FrameStack {
+    mode: StackMode,
+    target: u32,
+    frames: u32,
+    /// Black level of the 10-bit samples
+    black_level: u16,
+    layout: Option<RawLayout>,
+    sums: Vec<u32>,
+    /// Start time and the sensor's exposure and ISO at the start
+    fields: ExifFields,
+    finished_ms: Option<u64>,
+    error: Option<String>,
+}
+
+impl FrameStack {
+    pub fn new(mode: StackMode, target: u32, black_level: u16, fields: ExifFields) -> Self {
+        Self {
+            mode,
+            target,
+            frames: 0,
+            black_level,
+            layout: None,
+            sums: Vec::new(),
+            fields,
+            finished_ms: None,
+            error: None,
+        }
+    }
+
+    pub fn mode(&self) -> StackMode {
+        self.mode
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// EXIF of the result: a sum is exposed for the frames' total time
+    pub fn exif(&self) -> ExifFields {
+        let mut fields = self.fields.clone();
+        if self.mode == StackMode::Sum {
+            fields.exposure_s = fields.exposure_s.map(|s| s * self.frames as f64);
+        }
+        fields
+    }
+
+    pub fn layout(&self) -> Option<RawLayout> {
+        self.layout
+    }
+
+    pub fn state(&self) -> StackState {
+        if self.error.is_some() {
+            StackState::Failed
+        } else if self.frames < self.target {
+            StackState::Running
+        } else {
+            StackState::Done
+        }
+    }
+
+    /// Bytes held by the sums
+    pub fn bytes(&self) -> usize {
+        self.sums.len() * std::mem::size_of::<u32>()
+    }
+
+    /// Add one frame's unpacked 10-bit samples, `now_ms` being its capture
+    /// time; a frame of another layout than the first fails the stack
+    pub fn add(&mut self, samples: &[u16], layout: RawLayout, now_ms: u64) {
+        if self.state() != StackState::Running {
+            return;
+        }
+        match self.layout {
+            None => {
+                self.layout = Some(layout);
+                self.sums = vec![0; layout.width * layout.height];
+            }
+            Some(first) if first != layout => {
+                self.fail("Frame size or orientation changed during the stack".into(), now_ms);
+                return;
+            }
+            Some(_) => {}
+        }
+        if samples.len() < self.sums.len() {
+            self.fail(format!("Raw frame shorter than {}x{}", layout.width, layout.height), now_ms);
+            return;
+        }
+        for (sum, &sample) in self.sums.iter_mut().zip(samples) {
+            *sum += sample as u32;
+        }
+        self.frames += 1;
+        if self.frames == self.target {
+            self.finished_ms = Some(now_ms);
+        }
+    }
+
+    /// Abandon the stack and free the sums
+    pub fn fail(&mut self, error: String, now_ms: u64) {
+        self.error = Some(error);
+        self.finished_ms = Some(now_ms);
+        self.sums = Vec::new();
+    }
+
+    pub fn status(&self) -> StackStatus {
+        StackStatus {
+            state: self.state(),
+            mode: self.mode,
+            frames: self.frames,
+            target: self.target,
+            width: self.layout.map(|l| l.width),
+            height: self.layout.map(|l| l.height),
+            started_ms: self.fields.timestamp_ms,
+            finished_ms: self.finished_ms,
+            error: self.error.clone(),
+        }
+    }
+
+    /// The result on the 16-bit scale (None until the stack is done)
+    pub fn stacked(&self) -> Option<Stacked> {
+        if self.state() != StackState::Done {
+            return None;
+        }
+        let n = self.frames as u64;
+        let black = self.black_level as u64;
+        Some(match self.mode {
+            StackMode::Mean => Stacked {
+                samples: self.sums.iter().map(|&sum| ((sum as u64 * SCALE + n / 2) / n) as u16).collect(),
+                black_level: (black * SCALE) as u16,
+                white_level: (crate::dng::WHITE_LEVEL as u64 * SCALE) as u16,
+            },
+            // One black level, the signal above it added up
+            StackMode::Sum => Stacked {
+                samples: self
+                    .sums
+                    .iter()
+                    .map(|&sum| (sum as u64).saturating_sub(black * (n - 1)).min(u16::MAX as u64) as u16)
+                    .collect(),
+                black_level: self.black_level,
+                white_level: u16::MAX,
+            },
+        })
+    }
+}
+
+/// Linear RGB at half resolution from a stacked mosaic, one pixel per 2x2
+/// block (greens averaged), black removed, scaled to the white level and
+/// balanced with `gains`, turned upright by its TIFF orientation
+pub fn develop(stacked: &Stacked, layout: RawLayout, gains: [f32; 3]) -> DynamicImage {
+    let (width, height) = (layout.width / 2, layout.height / 2);
+    let pattern = crate::dng::cfa_pattern(layout.cfa);
+    let scale = u16::MAX as f32 / (stacked.white_level - stacked.black_level).max(1) as f32;
+    let gains = gains.map(|g| g * scale);
+    let rgb = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let (x, y) = (x as usize * 2, y as usize * 2);
+        let mut totals = [0f32; 3];
+        let mut counts = [0f32; 3];
+        for (i, &channel) in pattern.iter().enumerate() {
+            let sample = stacked.samples[(y + i / 2) * layout.width + x + i % 2];
+            totals[channel as usize] += sample.saturating_sub(stacked.black_level) as f32;
+            counts[channel as usize] += 1.0;
+        }
+        Rgb(std::array::from_fn(|c| {
+            (totals[c] / counts[c] * gains[c]).round().clamp(0.0, u16::MAX as f32) as u16
+        }))
+    });
+    let image = DynamicImage::ImageRgb16(rgb);
+    // The inverse of how `Orientation::exif_orientation` numbers them:
+    // flips first, then a quarter turn
+    match layout.orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.flipv().rotate90(),
+        6 => image.rotate90(),
+        7 => image.fliph().rotate90(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
@@ -0,0 +1,482 @@
+//! Persistent data directory with a versioned layout
+//!
+//! Everything the server keeps across restarts lives under one data
+//! directory (`--data-dir`):
+//!
+//! ```text
+//! <data_dir>/manifest.json     layout version, migration log
+//! <data_dir>/v1/state.json     settings state (profiles, schedule, annotation)
+//! <data_dir>/v1/recordings/    MJPEG recordings
+//! <data_dir>/v1/spool/         recording spool chunks
+//! <data_dir>/v1/snapshots/     job snapshots and stills
+//! ```
+//!
+//! At startup, files from older locations are moved into place
+//! ([`migrate`]). A move never overwrites: directories are merged entry by
+//! entry and anything already present at the destination stays at the
+//! source and is reported, so running the migration again is a no-op. The
+//! manifest records the layout version; a data directory written by a
+//! newer layout is refused rather than guessed at.
+//!
+//! Detector models are not part of the layout: they stay next to the
+//! detector script, which loads its default model and labels from there.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Current layout version
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// Default data directory
+pub const DEFAULT_DATA_DIR: &str = "/home/angelo/imx415_streamer/data";
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Where each kind of data lives; paths default to the layout under
+/// `data_dir` and may be overridden individually
+#[derive(Debug, Clone, Serialize)]
+pub struct Layout {
+    pub data_dir: PathBuf,
+    pub state_file: PathBuf,
+    pub recordings_dir: PathBuf,
+    pub spool_dir: PathBuf,
+    pub snapshots_dir: PathBuf,
+}
+
+impl Layout {
+    pub fn new(data_dir: &Path) -> Self {
+        let root = data_dir.join(format!("v{}", LAYOUT_VERSION));
+        Self {
+            data_dir: data_dir.to_path_buf(),
+            state_file: root.join("state.json"),
+            recordings_dir: root.join("recordings"),
+            spool_dir: root.join("spool"),
+            snapshots_dir: root.join("snapshots"),
+        }
+    }
+
+    /// Category names and their paths, as reported by GET /storage
+    pub fn categories(&self) -> [(&'static str, &Path); 4] {
+        [
+            ("state", &self.state_file),
+            ("recordings", &self.recordings_dir),
+            ("spool", &self.spool_dir),
+            ("snapshots", &self.snapshots_dir),
+        ]
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.data_dir.join(MANIFEST_FILE)
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new(Path::new(DEFAULT_DATA_DIR))
+    }
+}
+
+/// One legacy path moved into the layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Migrated {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Files moved
+    pub files: u64,
+    pub migrated_ms: u64,
+}
+
+/// Contents of `manifest.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub layout_version: u32,
+    #[serde(default)]
+    pub created_ms: u64,
+    #[serde(default)]
+    pub migrated: Vec<Migrated>,
+}
+
+/// What a migration run did
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrated: Vec<Migrated>,
+    /// Source paths left behind because the destination already has them
+    pub conflicts: Vec<PathBuf>,
+}
+
+/// Read the manifest (None if the directory has none yet)
+pub fn read_manifest(layout: &Layout) -> Result<Option<Manifest>> {
+    let path = layout.manifest_path();
+    match std::fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map(Some)
+            .with_context(|| format!("Invalid manifest {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+fn write_manifest(layout: &Layout, manifest: &Manifest) -> Result<()> {
+    let path = layout.manifest_path();
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_vec_pretty(manifest)?)
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+    std::fs::rename(&temp, &path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Create the layout, move each `(legacy, destination)` pair that still
+/// exists into place and record it in the manifest
+pub fn migrate(layout: &Layout, moves: &[(PathBuf, PathBuf)]) -> Result<MigrationReport> {
+    let existing = read_manifest(layout)?;
+    if let Some(ref manifest) = existing {
+        if manifest.layout_version > LAYOUT_VERSION {
+            anyhow::bail!(
+                "{} uses data layout v{}, newer than this build's v{}",
+                layout.data_dir.display(),
+                manifest.layout_version,
+                LAYOUT_VERSION
+            );
+        }
+    }
+    let mut manifest = existing.clone().unwrap_or_else(|| Manifest {
+        layout_version: LAYOUT_VERSION,
+        created_ms: crate::unix_millis(),
+        migrated: Vec::new(),
+    });
+
+    for dir in [&layout.recordings_dir, &layout.spool_dir, &layout.snapshots_dir] {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    if let Some(dir) = layout.state_file.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    let mut report = MigrationReport::default();
+    for (from, to) in moves {
+        if from == to || !from.exists() {
+            continue;
+        }
+        let files = move_path(from, to, &mut report.conflicts)
+            .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))?;
+        if files > 0 {
+            report.migrated.push(Migrated { from: from.clone(), to: to.clone(), files, migrated_ms: crate::unix_millis() });
+        }
+    }
+
+    if existing.is_none() || !report.migrated.is_empty() || manifest.layout_version != LAYOUT_VERSION {
+        manifest.layout_version = LAYOUT_VERSION;
+        manifest.migrated.extend(report.migrated.iter().cloned());
+        write_manifest(layout, &manifest)?;
+    }
+    Ok(report)
+}
+
+/// Move a file or merge a directory into `to`; returns the files moved.
+/// Entries that already exist at the destination are left in place and
+/// listed in `conflicts`; emptied source directories are removed.
+fn move_path(from: &Path, to: &Path, conflicts: &mut Vec<PathBuf>) -> Result<u64> {
+    let metadata = std::fs::symlink_metadata(from)?;
+    if !metadata.is_dir() {
+        if to.exists() {
+            conflicts.push(from.to_path_buf());
+            return Ok(0);
+        }
+        if let Some(dir) = to.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        rename_or_copy(from, to)?;
+        return Ok(1);
+    }
+
+    std::fs::create_dir_all(to)?;
+    let mut files = 0;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        files += move_path(&entry.path(), &to.join(entry.file_name()), conflicts)?;
+    }
+    // Fails (and is left alone) if conflicts remain inside
+    let _ = std::fs::remove_dir(from);
+    Ok(files)
+}
+
+/// Rename, falling back to copy + remove across filesystems (/tmp is
+/// usually tmpfs)
+fn rename_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let temp = to.with_file_name(format!(
+        ".{}.migrating",
+        to.file_name().and_then(|n| n.to_str()).unwrap_or("file")
+    ));
+    std::fs::copy(from, &temp)?;
+    std::fs::rename(&temp, to)?;
+    std::fs::remove_file(from)?;
+    Ok(())
+}
+
+/// Files and bytes removed by a cleanup pass
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reclaimed {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl std::ops::AddAssign for Reclaimed {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Remove the entries of `dir` whose names match `orphaned` (missing is fine)
+pub fn remove_matching(dir: &Path, orphaned: impl Fn(&str) -> bool) -> Result<Reclaimed> {
+    let mut reclaimed = Reclaimed::default();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(reclaimed),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    for entry in entries.flatten() {
+        if !entry.file_name().to_str().is_some_and(&orphaned) {
+            continue;
+        }
+        let path = entry.path();
+        let usage = usage(&path);
+        let removed = if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match removed {
+            Ok(()) => reclaimed += Reclaimed { files: usage.files, bytes: usage.bytes },
+            Err(e) => tracing::warn!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+    Ok(reclaimed)
+}
+
+/// Remove `<prefix><pid>[_<suffix>]` entries in `dir` whose process is gone
+pub fn remove_dead_pid_dirs(dir: &Path, prefix: &str) -> Result<Reclaimed> {
+    remove_matching(dir, |name| {
+        name.strip_prefix(prefix)
+            .and_then(|rest| rest.split('_').next())
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid != std::process::id() && !Path::new("/proc").join(pid.to_string()).exists())
+    })
+}
+
+/// Disk usage of a file or directory tree
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Usage of `path` (zero if missing; unreadable entries are skipped)
+pub fn usage(path: &Path) -> Usage {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Usage::default();
+    };
+    if !metadata.is_dir() {
+        return Usage { files: 1, bytes: metadata.len() };
+    }
+    let mut total = Usage::default();
+    for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+        let inner = usage(&entry.path());
+        total.files += inner.files;
+        total.bytes += inner.bytes;
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    /// Relative paths of the files under `dir`, sorted
+    fn tree(dir: &Path) -> Vec<String> {
+        fn walk(root: &Path, dir: &Path, out: &mut Vec<String>) {
+            for entry in std::fs::read_dir(dir).unwrap().flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    walk(root, &path, out);
+                } else {
+                    out.push(path.strip_prefix(root).unwrap().display().to_string());
+                }
+            }
+        }
+        let mut files = Vec::new();
+        walk(dir, dir, &mut files);
+        files.sort();
+        files
+    }
+
+    /// The pre-layout locations under `root`: recordings with the spool
+    /// inside, the settings file next to them and job output in temp
+    struct Legacy {
+        record_dir: PathBuf,
+        state_file: PathBuf,
+        jobs_dir: PathBuf,
+    }
+
+    impl Legacy {
+        fn create(root: &Path) -> Self {
+            let legacy = Self {
+                record_dir: root.join("home/recordings"),
+                state_file: root.join("home/settings.json"),
+                jobs_dir: root.join("tmp/imx415_jobs"),
+            };
+            write(&legacy.record_dir.join("2024-05-01_120000.mjpeg"), "recording 1");
+            write(&legacy.record_dir.join("2024-05-02_120000.mjpeg"), "recording 2");
+            write(&legacy.record_dir.join(".spool/000001.chunk"), "chunk 1");
+            write(&legacy.record_dir.join(".spool/000002.chunk"), "chunk 2");
+            write(&legacy.state_file, r#"{"profiles": {}}"#);
+            write(&legacy.jobs_dir.join("job-1/still.jpg"), "still");
+            legacy
+        }
+
+        /// In the order the server lists them: the spool before the
+        /// recordings it sits in
+        fn moves(&self, layout: &Layout) -> Vec<(PathBuf, PathBuf)> {
+            vec![
+                (self.record_dir.join(".spool"), layout.spool_dir.clone()),
+                (self.record_dir.clone(), layout.recordings_dir.clone()),
+                (self.state_file.clone(), layout.state_file.clone()),
+                (self.jobs_dir.clone(), layout.snapshots_dir.clone()),
+            ]
+        }
+    }
+
+    #[test]
+    fn legacy_files_are_moved_into_the_layout() {
+        let root = tempfile::tempdir().unwrap();
+        let legacy = Legacy::create(root.path());
+        let layout = Layout::new(&root.path().join("data"));
+
+        let report = migrate(&layout, &legacy.moves(&layout)).unwrap();
+        assert!(report.conflicts.is_empty(), "{:?}", report.conflicts);
+        let files: Vec<u64> = report.migrated.iter().map(|m| m.files).collect();
+        assert_eq!(files, [2, 2, 1, 1]);
+
+        assert_eq!(
+            tree(&layout.data_dir),
+            [
+                "manifest.json",
+                "v1/recordings/2024-05-01_120000.mjpeg",
+                "v1/recordings/2024-05-02_120000.mjpeg",
+                "v1/snapshots/job-1/still.jpg",
+                "v1/spool/000001.chunk",
+                "v1/spool/000002.chunk",
+                "v1/state.json",
+            ]
+        );
+        assert_eq!(read(&layout.state_file), r#"{"profiles": {}}"#);
+        assert_eq!(read(&layout.spool_dir.join("000002.chunk")), "chunk 2");
+        // Emptied legacy directories are gone
+        assert!(!legacy.record_dir.exists());
+        assert!(!legacy.state_file.exists());
+        assert!(!legacy.jobs_dir.exists());
+
+        let manifest = read_manifest(&layout).unwrap().unwrap();
+        assert_eq!(manifest.layout_version, LAYOUT_VERSION);
+        assert_eq!(manifest.migrated.len(), 4);
+        assert_eq!(manifest.migrated[2].from, legacy.state_file);
+        assert_eq!(manifest.migrated[2].to, layout.state_file);
+    }
+
+    #[test]
+    fn migrating_again_changes_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let legacy = Legacy::create(root.path());
+        let layout = Layout::new(&root.path().join("data"));
+        migrate(&layout, &legacy.moves(&layout)).unwrap();
+        let files = tree(&layout.data_dir);
+        let manifest = read(&layout.data_dir.join(MANIFEST_FILE));
+
+        let report = migrate(&layout, &legacy.moves(&layout)).unwrap();
+        assert!(report.migrated.is_empty() && report.conflicts.is_empty());
+        assert_eq!(tree(&layout.data_dir), files);
+        assert_eq!(read(&layout.data_dir.join(MANIFEST_FILE)), manifest);
+    }
+
+    #[test]
+    fn files_already_in_the_layout_are_not_overwritten() {
+        let root = tempfile::tempdir().unwrap();
+        let legacy = Legacy::create(root.path());
+        let layout = Layout::new(&root.path().join("data"));
+        write(&layout.state_file, "current state");
+        write(&layout.recordings_dir.join("2024-05-01_120000.mjpeg"), "current recording");
+
+        let report = migrate(&layout, &legacy.moves(&layout)).unwrap();
+        let mut conflicts = report.conflicts.clone();
+        conflicts.sort();
+        assert_eq!(conflicts, [legacy.record_dir.join("2024-05-01_120000.mjpeg"), legacy.state_file.clone()]);
+        assert_eq!(read(&layout.state_file), "current state");
+        assert_eq!(read(&layout.recordings_dir.join("2024-05-01_120000.mjpeg")), "current recording");
+        // The rest of the directory is merged in; the conflicts stay behind
+        assert_eq!(read(&layout.recordings_dir.join("2024-05-02_120000.mjpeg")), "recording 2");
+        assert_eq!(tree(&legacy.record_dir), ["2024-05-01_120000.mjpeg"]);
+        assert_eq!(read(&legacy.state_file), r#"{"profiles": {}}"#);
+    }
+
+    #[test]
+    fn a_fresh_directory_gets_the_layout_and_a_manifest() {
+        let root = tempfile::tempdir().unwrap();
+        let layout = Layout::new(&root.path().join("data"));
+        let report = migrate(&layout, &[(root.path().join("missing"), layout.spool_dir.clone())]).unwrap();
+        assert!(report.migrated.is_empty());
+        for (_, path) in layout.categories().into_iter().skip(1) {
+            assert!(path.is_dir(), "{}", path.display());
+        }
+        let manifest = read_manifest(&layout).unwrap().unwrap();
+        assert_eq!((manifest.layout_version, manifest.migrated.len()), (LAYOUT_VERSION, 0));
+    }
+
+    #[test]
+    fn a_newer_layout_is_refused() {
+        let root = tempfile::tempdir().unwrap();
+        let legacy = Legacy::create(root.path());
+        let layout = Layout::new(&root.path().join("data"));
+        write(&layout.data_dir.join(MANIFEST_FILE), r#"{"layout_version": 2}"#);
+
+        let error = migrate(&layout, &legacy.moves(&layout)).unwrap_err().to_string();
+        assert!(error.contains("data layout v2"), "{}", error);
+        // Nothing was touched
+        assert!(legacy.state_file.exists());
+        assert!(!layout.state_file.exists());
+    }
+
+    #[test]
+    fn cleanup_removes_orphans_and_counts_them() {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join("imx415_capture/frame_0001.raw"), "0123456789");
+        write(&dir.path().join("imx415_capture/frame_0002.raw"), "01234");
+        write(&dir.path().join("unrelated.txt"), "keep");
+        let reclaimed = remove_matching(dir.path(), |name| name == "imx415_capture").unwrap();
+        assert_eq!((reclaimed.files, reclaimed.bytes), (2, 15));
+        assert_eq!(tree(dir.path()), ["unrelated.txt"]);
+
+        // Only directories of processes that are gone
+        let own = format!("imx415_reprocess_{}_1", std::process::id());
+        write(&dir.path().join(&own).join("out.jpg"), "live");
+        write(&dir.path().join("imx415_reprocess_4000000000_1/out.jpg"), "dead");
+        let reclaimed = remove_dead_pid_dirs(dir.path(), "imx415_reprocess_").unwrap();
+        assert_eq!((reclaimed.files, reclaimed.bytes), (1, 4));
+        assert_eq!(tree(dir.path()), [format!("{}/out.jpg", own), "unrelated.txt".to_string()]);
+
+        // A missing directory has nothing to clean
+        assert_eq!(remove_matching(&dir.path().join("missing"), |_| true).unwrap().files, 0);
+    }
+}
@@ -0,0 +1,146 @@
+//! Long-lived V4L2 streaming session
+//!
+//! One `v4l2-ctl --stream-mmap` process keeps the capture queue running and
+//! writes every dequeued buffer to a pipe. A reader thread cuts the pipe
+//! into frames and keeps only the newest, so a slow consumer never works
+//! on a stale frame and the queue depth stays at one.
+//!
+//! Frames are numbered in arrival order. A control change only shows a few
+//! frames later (the frames in flight were exposed with the old value), so
+//! [`V4l2Stream::skip`] makes the next frames returned ones that started
+//! after the change.
+
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Buffers the driver cycles through
+const MMAP_BUFFERS: u32 = 4;
+
+#[derive(Default)]
+struct Latest {
+    frame: Option<Vec<u8>>,
+    /// Number of the newest frame received (1-based; 0 before the first)
+    received: u64,
+    /// Frames up to this number are never returned
+    returned: u64,
+    /// Why the stream ended, once it has
+    ended: Option<String>,
+}
+
+#[derive(Default)]
+struct Shared {
+    latest: Mutex<Latest>,
+    ready: Condvar,
+}
+
+/// A running capture stream
+pub struct V4l2Stream {
+    child: Child,
+    shared: Arc<Shared>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl V4l2Stream {
+    /// Start streaming `device`; every buffer is `frame_bytes` long (the
+    /// device's `sizeimage`), which is how the pipe is cut into frames
+    pub fn start(device: &str, frame_bytes: usize) -> Result<Self> {
+        let mut child = Command::new("v4l2-ctl")
+            .args([
+                "-d", device,
+                &format!("--stream-mmap={}", MMAP_BUFFERS),
+                "--stream-to=-",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Failed to run v4l2-ctl stream")?;
+        let stdout = child.stdout.take().context("v4l2-ctl has no stdout")?;
+        let shared = Arc::new(Shared::default());
+        let reader = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("v4l2-stream".to_string())
+                .spawn(move || read_frames(stdout, frame_bytes, &shared))
+        };
+        let reader = match reader {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e).context("Failed to start the stream reader");
+            }
+        };
+        Ok(Self { child, shared, reader: Some(reader) })
+    }
+
+    /// The newest frame not returned before, waiting up to `timeout` for
+    /// one to arrive
+    pub fn next_frame(&self, timeout: Duration) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        let mut latest = self.shared.latest.lock().unwrap();
+        loop {
+            if latest.received > latest.returned {
+                if let Some(frame) = latest.frame.take() {
+                    latest.returned = latest.received;
+                    return Ok(frame);
+                }
+            }
+            if let Some(ref reason) = latest.ended {
+                anyhow::bail!("Capture stream ended: {}", reason);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                anyhow::bail!("No frame from the capture stream within {:.1} s", timeout.as_secs_f32());
+            }
+            latest = self.shared.ready.wait_timeout(latest, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Never return the frame in flight nor the `frames` after it
+    pub fn skip(&self, frames: u64) {
+        let mut latest = self.shared.latest.lock().unwrap();
+        latest.returned = latest.returned.max(latest.received + 1 + frames);
+        latest.frame = None;
+    }
+
+    /// Whether the stream is still delivering frames
+    pub fn is_running(&self) -> bool {
+        self.shared.latest.lock().unwrap().ended.is_none()
+    }
+}
+
+impl Drop for V4l2Stream {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        // The pipe closed with the process, so the reader is finishing
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// Reader thread: cut the pipe into frames and publish each
+fn read_frames(mut stdout: ChildStdout, frame_bytes: usize, shared: &Shared) {
+    let reason = loop {
+        let mut frame = vec![0u8; frame_bytes];
+        if let Err(e) = stdout.read_exact(&mut frame) {
+            break match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => "v4l2-ctl exited".to_string(),
+                _ => e.to_string(),
+            };
+        }
+        let mut latest = shared.latest.lock().unwrap();
+        latest.received += 1;
+        if latest.received > latest.returned {
+            latest.frame = Some(frame);
+            shared.ready.notify_all();
+        }
+    };
+    shared.latest.lock().unwrap().ended = Some(reason);
+    shared.ready.notify_all();
+}
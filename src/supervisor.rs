@@ -0,0 +1,159 @@
+//! Background subsystem supervision
+//!
+//! Every long-running piece of the streamer (capture loop, detector, ...)
+//! is tracked through a [`Subsystem`] handle instead of a fire-and-forget
+//! `tokio::spawn`, so it can be listed, restarted and stopped in order.
+
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Lifecycle state of a subsystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubsystemState {
+    Running,
+    /// Started, but its task exited or it reported an error
+    Degraded,
+    Stopped,
+}
+
+/// Serializable snapshot of a subsystem
+#[derive(Debug, Clone, Serialize)]
+pub struct SubsystemStatus {
+    pub name: &'static str,
+    pub state: SubsystemState,
+    pub uptime_secs: Option<f64>,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+    /// Seconds until a scheduled automatic restart
+    pub restart_in_secs: Option<f64>,
+}
+
+struct Inner {
+    state: SubsystemState,
+    started_at: Option<Instant>,
+    restarts: u64,
+    last_error: Option<String>,
+    restart_at: Option<Instant>,
+    task: Option<JoinHandle<()>>,
+}
+
+/// Handle for one supervised subsystem
+pub struct Subsystem {
+    name: &'static str,
+    inner: Mutex<Inner>,
+}
+
+impl Subsystem {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            inner: Mutex::new(Inner {
+                state: SubsystemState::Stopped,
+                started_at: None,
+                restarts: 0,
+                last_error: None,
+                restart_at: None,
+                task: None,
+            }),
+        }
+    }
+
+    /// Mark the subsystem as started (optionally owning its task)
+    pub fn started(&self, task: Option<JoinHandle<()>>) {
+        let mut inner = self.inner.lock();
+        if let Some(old) = inner.task.take() {
+            old.abort();
+        }
+        inner.state = SubsystemState::Running;
+        inner.started_at = Some(Instant::now());
+        inner.last_error = None;
+        inner.restart_at = None;
+        inner.task = task;
+    }
+
+    /// Record that the subsystem failed to start
+    pub fn failed(&self, error: impl Into<String>) {
+        self.stop();
+        self.inner.lock().last_error = Some(error.into());
+    }
+
+    /// Record a failure without stopping the subsystem
+    pub fn degraded(&self, error: impl Into<String>) {
+        let mut inner = self.inner.lock();
+        inner.state = SubsystemState::Degraded;
+        inner.last_error = Some(error.into());
+    }
+
+    /// Abort the owned task (if any) and mark the subsystem stopped
+    pub fn stop(&self) {
+        let mut inner = self.inner.lock();
+        if let Some(task) = inner.task.take() {
+            task.abort();
+        }
+        inner.state = SubsystemState::Stopped;
+        inner.started_at = None;
+        inner.restart_at = None;
+    }
+
+    /// Record a failure that will be handled by restarting after `delay`
+    pub fn restart_scheduled(&self, error: impl Into<String>, delay: Duration) {
+        let mut inner = self.inner.lock();
+        inner.state = SubsystemState::Degraded;
+        inner.last_error = Some(error.into());
+        inner.restart_at = Some(Instant::now() + delay);
+    }
+
+    /// Count a restart (call before starting the new instance)
+    pub fn record_restart(&self) {
+        self.inner.lock().restarts += 1;
+    }
+
+    pub fn status(&self) -> SubsystemStatus {
+        let mut inner = self.inner.lock();
+        // A task that exited on its own is no longer doing its job
+        if inner.state == SubsystemState::Running
+            && inner.task.as_ref().is_some_and(|t| t.is_finished())
+        {
+            inner.state = SubsystemState::Degraded;
+            inner.last_error.get_or_insert_with(|| "Task exited".to_string());
+        }
+        SubsystemStatus {
+            name: self.name,
+            state: inner.state,
+            uptime_secs: inner.started_at.map(|t| t.elapsed().as_secs_f64()),
+            restarts: inner.restarts,
+            last_error: inner.last_error.clone(),
+            restart_in_secs: inner
+                .restart_at
+                .map(|t| t.saturating_duration_since(Instant::now()).as_secs_f64()),
+        }
+    }
+}
+
+/// Exponential restart delay: doubles on each failure up to `max`
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, next: initial }
+    }
+
+    /// Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Start over from the initial delay
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
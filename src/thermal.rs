@@ -0,0 +1,208 @@
+//! SoC thermal zones and sensor temperature
+//!
+//! Long 4K color sessions heat the RK3588 until the governor throttles it.
+//! Zones are read from sysfs on every poll, so zones that appear or
+//! disappear (driver reloads, hotplug) are handled naturally; unreadable
+//! zones are skipped.
+
+use serde::Serialize;
+use std::path::Path;
+
+/// Where the kernel exposes thermal zones
+pub const THERMAL_ROOT: &str = "/sys/class/thermal";
+
+/// Warn when any zone reaches this temperature by default (°C)
+pub const DEFAULT_WARN_C: f32 = 85.0;
+
+/// Target capture rate while throttled
+pub const DEFAULT_THROTTLED_FPS: u32 = 10;
+
+/// Throttling ends once the hottest zone is this far below the threshold
+pub const THROTTLE_HYSTERESIS_C: f32 = 5.0;
+
+/// One thermal zone reading
+#[derive(Debug, Clone, Serialize)]
+pub struct ThermalZone {
+    /// Directory name, e.g. "thermal_zone0"
+    pub zone: String,
+    /// Zone type, e.g. "soc-thermal" (falls back to the directory name)
+    pub kind: String,
+    pub temp_c: f32,
+}
+
+/// Parse a sysfs `temp` value (millidegrees Celsius)
+pub fn parse_millidegrees(text: &str) -> Option<f32> {
+    text.trim().parse::<i64>().ok().map(|m| m as f32 / 1000.0)
+}
+
+/// Interpret a sensor temperature control value: whole degrees, or
+/// millidegrees for drivers that report those
+pub fn sensor_value_to_c(value: i64) -> f32 {
+    if value.abs() >= 1000 {
+        value as f32 / 1000.0
+    } else {
+        value as f32
+    }
+}
+
+/// Read every `thermal_zone*` under `root`, sorted by zone name
+pub fn read_zones(root: &Path) -> Vec<ThermalZone> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+    let mut zones: Vec<ThermalZone> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let zone = entry.file_name().to_str()?.to_string();
+            if !zone.starts_with("thermal_zone") {
+                return None;
+            }
+            // A zone can vanish between listing and reading
+            let temp_c = parse_millidegrees(&std::fs::read_to_string(entry.path().join("temp")).ok()?)?;
+            let kind = std::fs::read_to_string(entry.path().join("type"))
+                .ok()
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .unwrap_or_else(|| zone.clone());
+            Some(ThermalZone { zone, kind, temp_c })
+        })
+        .collect();
+    zones.sort_by_key(|z| natural_key(&z.zone));
+    zones
+}
+
+/// Sort "thermal_zone10" after "thermal_zone9"
+fn natural_key(zone: &str) -> (usize, String) {
+    let digits = zone.trim_start_matches(|c: char| !c.is_ascii_digit());
+    (digits.parse().unwrap_or(usize::MAX), zone.to_string())
+}
+
+/// Thermal limits from the command line
+#[derive(Debug, Clone)]
+pub struct ThermalConfig {
+    pub warn_c: f32,
+    /// Reduce the capture rate above this temperature (policy off if None)
+    pub throttle_c: Option<f32>,
+    pub throttled_fps: u32,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            warn_c: DEFAULT_WARN_C,
+            throttle_c: None,
+            throttled_fps: DEFAULT_THROTTLED_FPS,
+        }
+    }
+}
+
+/// Latest readings and policy state, reported in /status
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ThermalStatus {
+    pub zones: Vec<ThermalZone>,
+    pub sensor_c: Option<f32>,
+    pub max_c: Option<f32>,
+    pub warning: bool,
+    /// The capture rate is currently reduced by the thermal policy
+    pub throttled: bool,
+    pub throttled_since_ms: Option<u64>,
+}
+
+impl ThermalStatus {
+    /// Hottest of the SoC zones and the sensor
+    pub fn hottest(zones: &[ThermalZone], sensor_c: Option<f32>) -> Option<f32> {
+        zones
+            .iter()
+            .map(|z| z.temp_c)
+            .chain(sensor_c)
+            .fold(None, |max, t| Some(max.map_or(t, |m: f32| m.max(t))))
+    }
+
+    /// Whether the policy should be throttling at `max_c`, given whether it
+    /// is throttling now (hysteresis avoids flapping around the threshold)
+    pub fn should_throttle(config: &ThermalConfig, max_c: Option<f32>, throttled: bool) -> bool {
+        let (Some(threshold), Some(max_c)) = (config.throttle_c, max_c) else {
+            return false;
+        };
+        if throttled {
+            max_c > threshold - THROTTLE_HYSTERESIS_C
+        } else {
+            max_c >= threshold
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Create `root/zone` with the given `temp` and `type` files
+    fn zone(root: &Path, zone: &str, temp: Option<&str>, kind: Option<&str>) {
+        let dir = root.join(zone);
+        fs::create_dir(&dir).unwrap();
+        if let Some(temp) = temp {
+            fs::write(dir.join("temp"), temp).unwrap();
+        }
+        if let Some(kind) = kind {
+            fs::write(dir.join("type"), kind).unwrap();
+        }
+    }
+
+    #[test]
+    fn zones_are_read_from_sysfs_in_natural_order() {
+        let root = tempfile::tempdir().unwrap();
+        zone(root.path(), "thermal_zone10", Some("41000\n"), Some("npu-thermal\n"));
+        zone(root.path(), "thermal_zone2", Some("-5500\n"), Some("\n"));
+        zone(root.path(), "thermal_zone9", Some("52125\n"), Some("soc-thermal\n"));
+        zone(root.path(), "thermal_zone0", Some("38000"), None);
+        // Not zones, or unreadable ones
+        zone(root.path(), "cooling_device0", Some("1"), Some("fan"));
+        zone(root.path(), "thermal_zone3", None, Some("gpu-thermal"));
+        zone(root.path(), "thermal_zone4", Some("n/a"), Some("bad"));
+
+        let zones = read_zones(root.path());
+        let found: Vec<(&str, &str, f32)> = zones.iter().map(|z| (z.zone.as_str(), z.kind.as_str(), z.temp_c)).collect();
+        assert_eq!(
+            found,
+            [
+                ("thermal_zone0", "thermal_zone0", 38.0),
+                ("thermal_zone2", "thermal_zone2", -5.5),
+                ("thermal_zone9", "soc-thermal", 52.125),
+                ("thermal_zone10", "npu-thermal", 41.0),
+            ]
+        );
+        assert_eq!(ThermalStatus::hottest(&zones, None), Some(52.125));
+        assert_eq!(ThermalStatus::hottest(&zones, Some(60.0)), Some(60.0));
+    }
+
+    #[test]
+    fn missing_root_has_no_zones() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(read_zones(&root.path().join("absent")).is_empty());
+        assert!(read_zones(root.path()).is_empty());
+        assert_eq!(ThermalStatus::hottest(&[], None), None);
+    }
+
+    #[test]
+    fn sensor_values_in_degrees_or_millidegrees() {
+        assert_eq!(sensor_value_to_c(47), 47.0);
+        assert_eq!(sensor_value_to_c(47_500), 47.5);
+        assert_eq!(sensor_value_to_c(-20), -20.0);
+        assert_eq!(parse_millidegrees(" 85000\n"), Some(85.0));
+        assert_eq!(parse_millidegrees("85.0"), None);
+    }
+
+    #[test]
+    fn throttling_has_hysteresis() {
+        let config = ThermalConfig { throttle_c: Some(90.0), ..Default::default() };
+        assert!(!ThermalStatus::should_throttle(&config, Some(89.9), false));
+        assert!(ThermalStatus::should_throttle(&config, Some(90.0), false));
+        // Stays throttled until 5 °C below the threshold
+        assert!(ThermalStatus::should_throttle(&config, Some(86.0), true));
+        assert!(!ThermalStatus::should_throttle(&config, Some(85.0), true));
+        // No reading, or no policy, never throttles
+        assert!(!ThermalStatus::should_throttle(&config, None, true));
+        assert!(!ThermalStatus::should_throttle(&ThermalConfig::default(), Some(120.0), false));
+    }
+}
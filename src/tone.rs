@@ -0,0 +1,87 @@
+//! Brightness, contrast and saturation
+//!
+//! A final look adjustment on the 8-bit output, for tuning the picture
+//! without touching the gamma (which also feeds the tone mapping) or the
+//! sensor exposure. Brightness is an offset in levels; contrast scales each
+//! value's distance from mid-gray (128), so it stretches or flattens the
+//! picture without moving its middle; saturation scales each color pixel's
+//! distance from its luma (0 makes it gray). Brightness and contrast are a
+//! single lookup table per value.
+//!
+//! It runs after the histogram is taken and after the grayscale contrast
+//! enhancement, so auto-exposure keeps metering the scene and not the
+//! adjusted picture.
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Brightness offsets accepted, in 8-bit levels
+pub const BRIGHTNESS: std::ops::RangeInclusive<i16> = -128..=128;
+
+/// Contrast multipliers accepted
+pub const CONTRASTS: std::ops::RangeInclusive<f32> = 0.0..=4.0;
+
+/// Saturation multipliers accepted
+pub const SATURATIONS: std::ops::RangeInclusive<f32> = 0.0..=4.0;
+
+/// Tone adjustment settings (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct Tone {
+    /// Offset added to every value, in 8-bit levels (0 = unchanged)
+    pub brightness: i16,
+    /// Multiplier of the distance from mid-gray (1 = unchanged)
+    pub contrast: f32,
+    /// Multiplier of the distance from gray, color mode only (1 =
+    /// unchanged, 0 = grayscale)
+    pub saturation: f32,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self { brightness: 0, contrast: 1.0, saturation: 1.0 }
+    }
+}
+
+impl Tone {
+    pub fn validate(&self) -> Result<(), String> {
+        if !BRIGHTNESS.contains(&self.brightness) {
+            return Err(format!("brightness must be {} to {}", BRIGHTNESS.start(), BRIGHTNESS.end()));
+        }
+        if !CONTRASTS.contains(&self.contrast) {
+            return Err(format!("contrast must be {}-{}", CONTRASTS.start(), CONTRASTS.end()));
+        }
+        if !SATURATIONS.contains(&self.saturation) {
+            return Err(format!("saturation must be {}-{}", SATURATIONS.start(), SATURATIONS.end()));
+        }
+        Ok(())
+    }
+
+    /// Whether the adjustment changes a frame of `channels`-channel pixels
+    pub fn is_enabled(&self, channels: usize) -> bool {
+        self.brightness != 0 || self.contrast != 1.0 || (channels == 3 && self.saturation != 1.0)
+    }
+
+    /// Adjust a frame of interleaved `channels`-channel pixels (1 or 3) in
+    /// place, on the current rayon pool
+    pub fn apply(&self, frame: &mut [u8], channels: usize) {
+        let lut: [u8; 256] = std::array::from_fn(|v| {
+            ((v as f32 - 128.0) * self.contrast + 128.0 + self.brightness as f32).round().clamp(0.0, 255.0) as u8
+        });
+        if channels != 3 || self.saturation == 1.0 {
+            frame.par_iter_mut().for_each(|v| *v = lut[*v as usize]);
+            return;
+        }
+        let saturation = (self.saturation * 256.0).round() as i32;
+        frame.par_chunks_mut(3 * 4096).for_each(|chunk| {
+            for pixel in chunk.chunks_exact_mut(3) {
+                let [r, g, b] = [0, 1, 2].map(|c| lut[pixel[c] as usize] as i32);
+                let luma = (77 * r + 150 * g + 29 * b) >> 8;
+                for (out, v) in pixel.iter_mut().zip([r, g, b]) {
+                    *out = (luma + ((v - luma) * saturation + 128) / 256).clamp(0, 255) as u8;
+                }
+            }
+        });
+    }
+}
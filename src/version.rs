@@ -0,0 +1,51 @@
+//! Build metadata embedded by build.rs
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+/// "true", "false", or "unknown" outside a git checkout
+pub const GIT_DIRTY: &str = env!("BUILD_GIT_DIRTY");
+pub const RUSTC_VERSION: &str = env!("BUILD_RUSTC_VERSION");
+/// RFC 3339 UTC
+pub const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+/// Comma-separated enabled cargo features
+const FEATURES: &str = env!("BUILD_FEATURES");
+
+/// Build information served by /version
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub git_commit_short: &'static str,
+    pub git_dirty: Option<bool>,
+    pub rustc_version: &'static str,
+    pub build_timestamp: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+/// Abbreviated commit hash ("unknown" outside a git checkout)
+pub fn short_commit() -> &'static str {
+    GIT_COMMIT.get(..8).unwrap_or(GIT_COMMIT)
+}
+
+/// Commit for display, e.g. "1a2b3c4d" or "1a2b3c4d-dirty"
+pub fn describe() -> String {
+    match GIT_DIRTY {
+        "true" => format!("{}-dirty", short_commit()),
+        _ => short_commit().to_string(),
+    }
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        git_commit_short: short_commit(),
+        git_dirty: GIT_DIRTY.parse().ok(),
+        rustc_version: RUSTC_VERSION,
+        build_timestamp: BUILD_TIMESTAMP,
+        features: FEATURES.split(',').filter(|f| !f.is_empty()).collect(),
+    }
+}
@@ -0,0 +1,128 @@
+//! Capture watchdog
+//!
+//! The capture loop reports every frame and every failed capture here. The
+//! pipeline counts as stalled after `max_failures` failed captures in a row
+//! (sensor glitches, CSI errors and capture timeouts all show up as those)
+//! or when no frame has been published for `max_frame_age`; the caller then
+//! sets the sensor up again and reopens the device, see
+//! [`CaptureWatchdog::stalled`].
+
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Default failed captures in a row that count as a stall (`--watchdog-failures`)
+pub const DEFAULT_MAX_FAILURES: u32 = 10;
+
+/// Default frame age that counts as a stall (`--watchdog-frame-age-secs`)
+pub const DEFAULT_MAX_FRAME_AGE_SECS: u64 = 30;
+
+/// Stall limits (None: that check is off)
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WatchdogConfig {
+    pub max_failures: Option<u32>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub max_frame_age: Option<Duration>,
+}
+
+fn serialize_secs<S: serde::Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    value.map(|d| d.as_secs_f64()).serialize(serializer)
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: Some(DEFAULT_MAX_FAILURES),
+            max_frame_age: Some(Duration::from_secs(DEFAULT_MAX_FRAME_AGE_SECS)),
+        }
+    }
+}
+
+/// Watchdog section of /status
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchdogStatus {
+    pub config: WatchdogConfig,
+    /// Failed captures since the last frame
+    pub consecutive_failures: u32,
+    /// Seconds since the last frame (or since the camera was opened)
+    pub frame_age_secs: f64,
+    /// Reopens that brought the camera back, and those that failed
+    pub recoveries: u64,
+    pub failed_recoveries: u64,
+    /// Why the last recovery was started, and when (Unix ms)
+    pub last_stall: Option<String>,
+    pub last_recovery_ms: Option<u64>,
+}
+
+pub struct CaptureWatchdog {
+    config: WatchdogConfig,
+    failures: u32,
+    /// Last frame, or when the camera was (re)opened
+    last_frame: Instant,
+    recoveries: u64,
+    failed_recoveries: u64,
+    last_stall: Option<String>,
+    last_recovery_ms: Option<u64>,
+}
+
+impl CaptureWatchdog {
+    pub fn new(config: WatchdogConfig) -> Self {
+        Self {
+            config,
+            failures: 0,
+            last_frame: Instant::now(),
+            recoveries: 0,
+            failed_recoveries: 0,
+            last_stall: None,
+            last_recovery_ms: None,
+        }
+    }
+
+    /// A frame was captured, or the camera was opened again
+    pub fn frame_ok(&mut self) {
+        self.failures = 0;
+        self.last_frame = Instant::now();
+    }
+
+    pub fn frame_failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    /// Why the pipeline counts as stalled, if it does. `frame_age`: whether
+    /// frames are expected at all (not while waiting for an external trigger).
+    pub fn stalled(&self, frame_age: bool) -> Option<String> {
+        if let Some(max) = self.config.max_failures.filter(|&max| self.failures >= max) {
+            return Some(format!("{} failed captures in a row (limit {})", self.failures, max));
+        }
+        let age = self.last_frame.elapsed();
+        match self.config.max_frame_age {
+            Some(max) if frame_age && age >= max => {
+                Some(format!("No frame for {:.0} s (limit {:.0} s)", age.as_secs_f64(), max.as_secs_f64()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Count a recovery attempt for `reason` (after a failed one the
+    /// counts stand, so the stall is retried)
+    pub fn recovered(&mut self, reason: String, ok: bool, now_ms: u64) {
+        if ok {
+            self.recoveries += 1;
+        } else {
+            self.failed_recoveries += 1;
+        }
+        self.last_stall = Some(reason);
+        self.last_recovery_ms = Some(now_ms);
+    }
+
+    pub fn status(&self) -> WatchdogStatus {
+        WatchdogStatus {
+            config: self.config,
+            consecutive_failures: self.failures,
+            frame_age_secs: self.last_frame.elapsed().as_secs_f64(),
+            recoveries: self.recoveries,
+            failed_recoveries: self.failed_recoveries,
+            last_stall: self.last_stall.clone(),
+            last_recovery_ms: self.last_recovery_ms,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! WebRTC live view (`webrtc` feature)
+//!
+//! `POST /webrtc/offer` answers the browser's SDP offer with a send-only
+//! H.264 track fed from the shared encoder ([`crate::h264`]), so video
+//! needs an `mpp` build as well. ICE candidates are not trickled: the
+//! answer goes out once gathering is done, which only takes long with an
+//! unreachable STUN server. Each peer reads its own subscription from an
+//! IDR frame on; the browser's picture loss reports ask the encoder for
+//! another. A peer is dropped when its connection fails or closes, or when
+//! the encoder stops.
+
+use crate::h264::{H264Stream, Subscription, Track};
+use crate::latency::FrameTiming;
+use anyhow::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Peer connections and their ICE setup
+pub struct WebRtc {
+    /// STUN/TURN server URLs offered to ICE (none on a LAN)
+    ice_servers: Vec<String>,
+    peers: Arc<AtomicUsize>,
+}
+
+impl WebRtc {
+    pub fn new(ice_servers: Vec<String>) -> Self {
+        Self { ice_servers, peers: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Built with WebRTC support
+    pub fn check_available() -> Result<()> {
+        anyhow::ensure!(cfg!(feature = "webrtc"), "Built without the `webrtc` feature");
+        Ok(())
+    }
+
+    /// Connected (or connecting) peers
+    pub fn peers(&self) -> usize {
+        self.peers.load(Ordering::Relaxed)
+    }
+
+    /// Answer `offer` with a peer connection that streams `subscription`
+    /// until it closes; `on_send` runs for each frame sent and is dropped
+    /// with the peer
+    pub async fn answer(
+        &self,
+        offer: String,
+        stream: Arc<H264Stream>,
+        subscription: Subscription,
+        track: Track,
+        on_send: impl FnMut(&FrameTiming) + Send + 'static,
+    ) -> Result<String> {
+        #[cfg(feature = "webrtc")]
+        {
+            peer::connect(&self.ice_servers, offer, stream, subscription, track, on_send, self.peers.clone()).await
+        }
+        #[cfg(not(feature = "webrtc"))]
+        {
+            let _ = (&self.ice_servers, offer, stream, subscription, track, on_send);
+            Self::check_available().map(|()| String::new())
+        }
+    }
+}
+
+#[cfg(feature = "webrtc")]
+mod peer {
+    use super::*;
+    use crate::fmp4;
+    use anyhow::Context;
+    use futures::StreamExt;
+    use std::time::{Duration, SystemTime};
+    use tokio::sync::watch;
+    use webrtc::api::interceptor_registry::register_default_interceptors;
+    use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+    use webrtc::api::APIBuilder;
+    use webrtc::ice_transport::ice_server::RTCIceServer;
+    use webrtc::interceptor::registry::Registry;
+    use webrtc::media::Sample;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+    use webrtc::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
+    use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+    use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+    use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+    use webrtc::track::track_local::TrackLocal;
+
+    /// Longest wait for ICE candidate gathering before answering anyway
+    const GATHER_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub async fn connect(
+        ice_servers: &[String],
+        offer: String,
+        stream: Arc<H264Stream>,
+        subscription: Subscription,
+        track: Track,
+        on_send: impl FnMut(&FrameTiming) + Send + 'static,
+        peers: Arc<AtomicUsize>,
+    ) -> Result<String> {
+        let mut media = MediaEngine::default();
+        media.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media)?;
+        let api = APIBuilder::new().with_media_engine(media).with_interceptor_registry(registry).build();
+        let config = RTCConfiguration {
+            ice_servers: if ice_servers.is_empty() {
+                Vec::new()
+            } else {
+                vec![RTCIceServer { urls: ice_servers.to_vec(), ..Default::default() }]
+            },
+            ..Default::default()
+        };
+        let connection = Arc::new(api.new_peer_connection(config).await?);
+        let result = negotiate(&connection, offer, &stream).await;
+        let (video, sender, sdp, ended) = match result {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                let _ = connection.close().await;
+                return Err(e);
+            }
+        };
+        tokio::spawn(read_rtcp(sender, stream.clone()));
+        let units = subscription.access_units(stream, track.clone());
+        tokio::spawn(send_video(connection, video, units, track, ended, on_send, peers));
+        Ok(sdp)
+    }
+
+    type Negotiated = (Arc<TrackLocalStaticSample>, Arc<RTCRtpSender>, String, watch::Receiver<bool>);
+
+    async fn negotiate(connection: &Arc<RTCPeerConnection>, offer: String, stream: &Arc<H264Stream>) -> Result<Negotiated> {
+        let video = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability { mime_type: MIME_TYPE_H264.to_owned(), ..Default::default() },
+            "video".to_owned(),
+            "imx415_streamer".to_owned(),
+        ));
+        let sender = connection.add_track(video.clone() as Arc<dyn TrackLocal + Send + Sync>).await?;
+
+        let (ended_tx, ended) = watch::channel(false);
+        let stream = stream.clone();
+        connection.on_peer_connection_state_change(Box::new(move |state| {
+            match state {
+                // Frames sent before the connection was up went nowhere
+                RTCPeerConnectionState::Connected => stream.request_idr(),
+                RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed => {
+                    ended_tx.send_replace(true);
+                }
+                _ => {}
+            }
+            Box::pin(async {})
+        }));
+
+        connection.set_remote_description(RTCSessionDescription::offer(offer)?).await?;
+        let answer = connection.create_answer(None).await?;
+        let mut gathered = connection.gathering_complete_promise().await;
+        connection.set_local_description(answer).await?;
+        if tokio::time::timeout(GATHER_TIMEOUT, gathered.recv()).await.is_err() {
+            tracing::warn!("WebRTC: ICE gathering unfinished after {:?}, answering with the candidates so far", GATHER_TIMEOUT);
+        }
+        let local = connection.local_description().await.context("No local description")?;
+        Ok((video, sender, local.sdp, ended))
+    }
+
+    /// Ask the encoder for an IDR frame when the browser reports picture loss
+    async fn read_rtcp(sender: Arc<RTCRtpSender>, stream: Arc<H264Stream>) {
+        while let Ok((packets, _)) = sender.read_rtcp().await {
+            let loss = packets.iter().any(|packet| {
+                let packet = packet.as_any();
+                packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>()
+            });
+            if loss {
+                stream.request_idr();
+            }
+        }
+    }
+
+    async fn send_video(
+        connection: Arc<RTCPeerConnection>,
+        video: Arc<TrackLocalStaticSample>,
+        units: impl futures::Stream<Item = Arc<crate::h264::AccessUnit>> + Send,
+        track: Track,
+        mut ended: watch::Receiver<bool>,
+        mut on_send: impl FnMut(&FrameTiming) + Send,
+        peers: Arc<AtomicUsize>,
+    ) {
+        peers.fetch_add(1, Ordering::Relaxed);
+        let mut units = std::pin::pin!(units);
+        let mut last_ms = None;
+        loop {
+            let unit = tokio::select! {
+                unit = units.next() => unit,
+                _ = ended.wait_for(|ended| *ended) => None,
+            };
+            let Some(unit) = unit else {
+                break;
+            };
+            // The payloader wants Annex B, with the parameter sets before each IDR frame
+            let prefix: &[&[u8]] = if unit.keyframe { &[&track.sps[..], &track.pps[..]] } else { &[] };
+            let duration = last_ms.map_or(Duration::from_millis(1000 / crate::h264::FPS as u64), |last| {
+                Duration::from_millis(unit.timestamp_ms.saturating_sub(last).max(1))
+            });
+            last_ms = Some(unit.timestamp_ms);
+            let sample = Sample {
+                data: fmp4::avcc_to_annexb(&unit.sample, prefix).into(),
+                timestamp: SystemTime::now(),
+                duration,
+                ..Default::default()
+            };
+            if let Err(e) = video.write_sample(&sample).await {
+                tracing::debug!("WebRTC: write failed: {}", e);
+                break;
+            }
+            on_send(&unit.timing);
+        }
+        let _ = connection.close().await;
+        peers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
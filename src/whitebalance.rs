@@ -0,0 +1,347 @@
+//! Temporal smoothing of the gray-world white balance gains
+//!
+//! Gray-world gains computed from scratch every frame swing whenever
+//! something colorful enters the scene, which shows up as a hue flicker on
+//! the stream. The applied gains instead follow the per-frame estimate
+//! through an exponential moving average, with each gain's change per
+//! second clamped. An optional scene-change detector lets the gains jump
+//! straight to the estimate when the mean luminance shifts abruptly (lights
+//! switched on), where a slow re-converge would look wrong.
+//!
+//! Scenes dominated by one color throw gray-world off however it is
+//! smoothed. [`WhiteBalanceMode`] selects the estimate instead: gray-world,
+//! white-patch (the brightest unclipped tones taken as white, which copes
+//! better with one dominant color as long as something white or specular
+//! is in view) or fixed gains, from a preset for the kind of light or set
+//! by hand. Fixed gains are never smoothed.
+
+use serde::{Deserialize, Serialize};
+
+/// Gains are clamped to this range whatever the scene
+pub const MIN_GAIN: f32 = 0.5;
+pub const MAX_GAIN: f32 = 2.0;
+
+/// Range accepted for manual gains, wider than gray-world's so strongly
+/// colored light (tungsten, sodium) can be corrected
+pub const MIN_MANUAL_GAIN: f32 = 0.1;
+pub const MAX_MANUAL_GAIN: f32 = 8.0;
+
+/// Fraction of the unclipped pixels at or below the level white-patch
+/// takes as white, so a few hot pixels or highlights don't decide it
+const WHITE_PATCH_PERCENTILE: f64 = 0.99;
+
+/// Darkest white-patch level (8-bit) trusted; below it gray-world is used
+const WHITE_PATCH_MIN_LEVEL: f32 = 32.0;
+
+/// Longest frame gap fed to the filter (s); a stalled pipeline shouldn't
+/// turn the next frame into a jump
+const MAX_DT_S: f32 = 1.0;
+
+/// Fixed gains for a kind of light
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WbPreset {
+    /// Direct sun, around 5500 K
+    Daylight,
+    /// Overcast, around 6500 K
+    Cloudy,
+    /// Open shade, around 7500 K
+    Shade,
+    /// Incandescent and halogen, around 3200 K
+    Tungsten,
+    /// Cool white tubes and LEDs, around 4000 K
+    Fluorescent,
+}
+
+impl WbPreset {
+    pub const ALL: [WbPreset; 5] =
+        [WbPreset::Daylight, WbPreset::Cloudy, WbPreset::Shade, WbPreset::Tungsten, WbPreset::Fluorescent];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WbPreset::Daylight => "daylight",
+            WbPreset::Cloudy => "cloudy",
+            WbPreset::Shade => "shade",
+            WbPreset::Tungsten => "tungsten",
+            WbPreset::Fluorescent => "fluorescent",
+        }
+    }
+
+    /// R, G, B gains: approximate values for the IMX415, scaled from the
+    /// gains gray-world settles on in daylight by the light's color
+    /// temperature. Optics (IR-cut filters especially) shift them; manual
+    /// gains are there for a precise match.
+    pub fn gains(self) -> [f32; 3] {
+        match self {
+            WbPreset::Daylight => [1.62, 1.0, 1.48],
+            WbPreset::Cloudy => [1.78, 1.0, 1.32],
+            WbPreset::Shade => [1.92, 1.0, 1.22],
+            WbPreset::Tungsten => [1.10, 1.0, 2.45],
+            WbPreset::Fluorescent => [1.35, 1.0, 2.00],
+        }
+    }
+}
+
+/// How the white balance gains are found (a pipeline setting)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WhiteBalanceMode {
+    /// Each channel's mean made equal
+    #[default]
+    GrayWorld,
+    /// The brightest unclipped tones (per channel) made white
+    WhitePatch,
+    Preset(WbPreset),
+    /// R, G, B gains set by hand
+    Manual([f32; 3]),
+}
+
+impl WhiteBalanceMode {
+    /// A mode by name: `gray_world`, `white_patch` (or `retinex`) or a
+    /// preset; manual gains have no name to parse
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().replace('-', "_").as_str() {
+            "gray_world" | "grayworld" | "auto" => Some(WhiteBalanceMode::GrayWorld),
+            "white_patch" | "whitepatch" | "retinex" => Some(WhiteBalanceMode::WhitePatch),
+            name => WbPreset::ALL.into_iter().find(|p| p.name() == name).map(WhiteBalanceMode::Preset),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WhiteBalanceMode::GrayWorld => "gray_world",
+            WhiteBalanceMode::WhitePatch => "white_patch",
+            WhiteBalanceMode::Preset(preset) => preset.name(),
+            WhiteBalanceMode::Manual(_) => "manual",
+        }
+    }
+
+    /// Every name `parse` accepts, for error messages and listings
+    pub fn names() -> Vec<&'static str> {
+        let mut names = vec!["gray_world", "white_patch"];
+        names.extend(WbPreset::ALL.map(WbPreset::name));
+        names
+    }
+
+    /// Gains that don't depend on the scene (presets and manual)
+    pub fn fixed_gains(self) -> Option<[f32; 3]> {
+        match self {
+            WhiteBalanceMode::Preset(preset) => Some(preset.gains()),
+            WhiteBalanceMode::Manual(gains) => Some(gains),
+            WhiteBalanceMode::GrayWorld | WhiteBalanceMode::WhitePatch => None,
+        }
+    }
+}
+
+/// White-patch target gains from per-channel histograms of the unclipped
+/// pixels (8-bit); None when the scene is too dark to tell
+pub fn white_patch_target(histograms: &[[u32; 256]; 3]) -> Option<[f32; 3]> {
+    let mut levels = [0f32; 3];
+    for (level, histogram) in levels.iter_mut().zip(histograms) {
+        let total: u64 = histogram.iter().map(|&n| n as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let threshold = (total as f64 * WHITE_PATCH_PERCENTILE).ceil() as u64;
+        let mut count = 0u64;
+        let bin = histogram
+            .iter()
+            .position(|&n| {
+                count += n as u64;
+                count >= threshold
+            })
+            .unwrap_or(255);
+        *level = bin as f32 + 0.5;
+    }
+    if levels.iter().any(|&l| l < WHITE_PATCH_MIN_LEVEL) {
+        return None;
+    }
+    let white = levels.iter().sum::<f32>() / 3.0;
+    Some(levels.map(|l| white / l))
+}
+
+/// Smoothing parameters
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WbSmoothing {
+    /// EMA time constant in seconds (0 = follow the estimate directly)
+    pub time_constant_s: f32,
+    /// Largest change of any gain per second (0 = unlimited)
+    pub max_rate_per_s: f32,
+    /// Relative mean-luminance change between frames that counts as a
+    /// scene change and resets the gains to the estimate (None = off)
+    pub scene_change: Option<f32>,
+}
+
+impl Default for WbSmoothing {
+    fn default() -> Self {
+        Self { time_constant_s: 1.0, max_rate_per_s: 0.5, scene_change: None }
+    }
+}
+
+/// Applied gains and the luminance they were last updated with
+#[derive(Debug, Clone, Default)]
+pub struct WbSmoother {
+    gains: Option<[f32; 3]>,
+    luminance: f32,
+}
+
+impl WbSmoother {
+    /// Gains currently applied (None until the first frame after a reset)
+    pub fn gains(&self) -> Option<[f32; 3]> {
+        self.gains
+    }
+
+    /// Forget the gains; the next frame starts from its own estimate
+    pub fn reset(&mut self) {
+        self.gains = None;
+    }
+
+    /// Apply fixed (preset or manual) gains; an estimate continues
+    /// smoothly from them when it takes over again
+    pub fn hold(&mut self, gains: [f32; 3]) {
+        self.gains = Some(gains);
+    }
+
+    /// Move the gains towards this frame's estimate; `luminance` is the
+    /// frame's mean level before white balance, `dt_s` the time since the
+    /// previous frame
+    pub fn update(&mut self, config: &WbSmoothing, target: [f32; 3], luminance: f32, dt_s: f32) -> [f32; 3] {
+        let target = target.map(|g| g.clamp(MIN_GAIN, MAX_GAIN));
+        let previous_luminance = std::mem::replace(&mut self.luminance, luminance);
+        let scene_change = config
+            .scene_change
+            .is_some_and(|threshold| (luminance - previous_luminance).abs() > threshold * previous_luminance.max(1.0));
+        let gains = match self.gains {
+            Some(gains) if !scene_change => {
+                let dt = dt_s.clamp(0.0, MAX_DT_S);
+                let alpha = if config.time_constant_s > 0.0 { 1.0 - (-dt / config.time_constant_s).exp() } else { 1.0 };
+                let max_step = if config.max_rate_per_s > 0.0 { config.max_rate_per_s * dt } else { f32::INFINITY };
+                std::array::from_fn(|c| gains[c] + (alpha * (target[c] - gains[c])).clamp(-max_step, max_step))
+            }
+            _ => target,
+        };
+        self.gains = Some(gains);
+        gains
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT_S: f32 = 1.0 / 30.0;
+
+    /// Gray-world target and mean level of a flat frame of `rgb`, as the
+    /// capture computes them from channel means
+    fn gray_world(rgb: [f32; 3]) -> ([f32; 3], f32) {
+        let mean = rgb.iter().sum::<f32>() / 3.0;
+        (rgb.map(|c| mean / c), mean)
+    }
+
+    /// Feed `frames` at 30 fps, returning the applied gains after each
+    fn run(smoother: &mut WbSmoother, config: &WbSmoothing, frames: &[[f32; 3]]) -> Vec<[f32; 3]> {
+        frames
+            .iter()
+            .map(|&frame| {
+                let (target, luminance) = gray_world(frame);
+                smoother.update(config, target, luminance, DT_S)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_red_cast_moves_the_gains_no_faster_than_the_rate_limit() {
+        let config = WbSmoothing { time_constant_s: 0.1, max_rate_per_s: 0.3, scene_change: None };
+        let neutral = [110.0, 120.0, 100.0];
+        let red_cast = [220.0, 90.0, 80.0];
+        let mut frames = vec![neutral; 10];
+        frames.push(red_cast);
+        frames.extend([neutral; 10]);
+
+        let mut smoother = WbSmoother::default();
+        let gains = run(&mut smoother, &config, &frames);
+        // The first frame starts at its own estimate
+        assert_eq!(gains[0], gray_world(neutral).0);
+        let max_step = config.max_rate_per_s * DT_S + 1e-6;
+        for (i, pair) in gains.windows(2).enumerate() {
+            for (c, (after, before)) in pair[1].iter().zip(pair[0]).enumerate() {
+                let step = (after - before).abs();
+                assert!(step <= max_step, "frame {} channel {}: moved {}", i + 1, c, step);
+            }
+        }
+        // The cast frame pulled red down by exactly one clamped step
+        let red_step = gains[9][0] - gains[10][0];
+        assert!((red_step - config.max_rate_per_s * DT_S).abs() < 1e-5, "{}", red_step);
+        // and the gains recover afterwards
+        let settled = gray_world(neutral).0;
+        assert!(gains[20].iter().zip(settled).all(|(g, s)| (g - s).abs() < 1e-3), "{:?}", gains[20]);
+    }
+
+    #[test]
+    fn a_scene_change_jumps_to_the_estimate() {
+        let config = WbSmoothing { scene_change: Some(0.5), ..Default::default() };
+        let mut smoother = WbSmoother::default();
+        run(&mut smoother, &config, &[[100.0, 100.0, 100.0]; 5]);
+        // Same brightness, new cast: smoothed
+        let (target, _) = gray_world([60.0, 100.0, 140.0]);
+        let gains = run(&mut smoother, &config, &[[60.0, 100.0, 140.0]])[0];
+        assert_ne!(gains, target);
+        // Lights on: luminance up 3x, straight to the estimate
+        let (target, _) = gray_world([180.0, 300.0, 420.0]);
+        assert_eq!(run(&mut smoother, &config, &[[180.0, 300.0, 420.0]])[0], target);
+    }
+
+    #[test]
+    fn unlimited_smoothing_follows_the_clamped_estimate() {
+        let config = WbSmoothing { time_constant_s: 0.0, max_rate_per_s: 0.0, scene_change: None };
+        let mut smoother = WbSmoother::default();
+        let gains = run(&mut smoother, &config, &[[100.0, 100.0, 100.0], [20.0, 100.0, 300.0]]);
+        assert_eq!(gains[0], [1.0, 1.0, 1.0]);
+        let (target, _) = gray_world([20.0, 100.0, 300.0]);
+        assert_eq!(gains[1], target.map(|g| g.clamp(MIN_GAIN, MAX_GAIN)));
+        assert_eq!(gains[1][0], MAX_GAIN);
+        assert_eq!(gains[1][2], MIN_GAIN);
+    }
+
+    #[test]
+    fn held_gains_are_where_smoothing_resumes() {
+        let config = WbSmoothing::default();
+        let mut smoother = WbSmoother::default();
+        smoother.hold(WbPreset::Tungsten.gains());
+        let gains = smoother.update(&config, [1.0, 1.0, 1.0], 100.0, DT_S);
+        assert!((gains[2] - WbPreset::Tungsten.gains()[2]).abs() <= config.max_rate_per_s * DT_S + 1e-6);
+        smoother.reset();
+        assert_eq!(smoother.gains(), None);
+    }
+
+    #[test]
+    fn white_patch_takes_the_brightest_tones_as_white() {
+        // 2% highlights in each channel (over the 99th percentile), above
+        // a scene at 100/80/60
+        let mut histograms = [[0u32; 256]; 3];
+        for (histogram, (scene, white)) in histograms.iter_mut().zip([(100, 240), (80, 200), (60, 160)]) {
+            histogram[scene] = 980;
+            histogram[white] = 20;
+        }
+        let gains = white_patch_target(&histograms).unwrap();
+        let white = [240.5f32, 200.5, 160.5];
+        let mean = white.iter().sum::<f32>() / 3.0;
+        for (gain, level) in gains.iter().zip(white) {
+            assert!((gain - mean / level).abs() < 1e-5);
+        }
+        // Too dark to trust
+        let mut dark = [[0u32; 256]; 3];
+        for histogram in &mut dark {
+            histogram[10] = 100;
+        }
+        assert_eq!(white_patch_target(&dark), None);
+    }
+
+    #[test]
+    fn modes_parse_by_name() {
+        for name in WhiteBalanceMode::names() {
+            assert_eq!(WhiteBalanceMode::parse(name).unwrap().name(), name);
+        }
+        assert_eq!(WhiteBalanceMode::parse("Retinex"), Some(WhiteBalanceMode::WhitePatch));
+        assert_eq!(WhiteBalanceMode::parse("gray-world"), Some(WhiteBalanceMode::GrayWorld));
+        assert_eq!(WhiteBalanceMode::parse("sodium"), None);
+    }
+}
@@ -0,0 +1,363 @@
+//! Shared YOLO pre- and post-processing
+//!
+//! Letterboxing, output decoding and non-maximum suppression used by the
+//! in-process detector backends, so every backend produces the same
+//! `DetectionResult`s for the same model output.
+
+use crate::detector::{nms, BBox, Detection, Point};
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+/// COCO class names, in model output order
+pub const COCO_CLASSES: [&str; 80] = [
+    "person", "bicycle", "car", "motorcycle", "airplane", "bus", "train", "truck", "boat",
+    "traffic light", "fire hydrant", "stop sign", "parking meter", "bench", "bird", "cat", "dog",
+    "horse", "sheep", "cow", "elephant", "bear", "zebra", "giraffe", "backpack", "umbrella",
+    "handbag", "tie", "suitcase", "frisbee", "skis", "snowboard", "sports ball", "kite",
+    "baseball bat", "baseball glove", "skateboard", "surfboard", "tennis racket", "bottle",
+    "wine glass", "cup", "fork", "knife", "spoon", "bowl", "banana", "apple", "sandwich", "orange",
+    "broccoli", "carrot", "hot dog", "pizza", "donut", "cake", "chair", "couch", "potted plant",
+    "bed", "dining table", "toilet", "tv", "laptop", "mouse", "remote", "keyboard", "cell phone",
+    "microwave", "oven", "toaster", "sink", "refrigerator", "book", "clock", "vase", "scissors",
+    "teddy bear", "hair drier", "toothbrush",
+];
+
+/// Minimum class confidence kept after decoding
+pub const CONFIDENCE_THRESHOLD: f32 = 0.25;
+
+/// IoU above which overlapping boxes of the same class are suppressed
+pub const NMS_IOU_THRESHOLD: f32 = 0.45;
+
+/// Gray used to pad letterboxed input (Ultralytics convention)
+#[cfg(feature = "onnx")]
+const LETTERBOX_PAD: f32 = 114.0 / 255.0;
+/// The same for models that take 8-bit input
+#[cfg(feature = "rknn")]
+pub const LETTERBOX_PAD_RGB8: u8 = 114;
+
+/// Mapping from model input coordinates back to the source frame
+#[derive(Debug, Clone, Copy)]
+pub struct Letterbox {
+    pub scale: f32,
+    pub pad_x: f32,
+    pub pad_y: f32,
+    pub src_width: u32,
+    pub src_height: u32,
+}
+
+impl Letterbox {
+    /// Convert a box in model input pixels to clamped frame pixels
+    pub fn map_to_frame(&self, x1: f32, y1: f32, x2: f32, y2: f32) -> BBox {
+        let max_x = self.src_width.saturating_sub(1) as f32;
+        let max_y = self.src_height.saturating_sub(1) as f32;
+        let fx = |x: f32| ((x - self.pad_x) / self.scale).clamp(0.0, max_x) as i32;
+        let fy = |y: f32| ((y - self.pad_y) / self.scale).clamp(0.0, max_y) as i32;
+        BBox { x1: fx(x1), y1: fy(y1), x2: fx(x2), y2: fy(y2) }
+    }
+
+    /// Convert a point in model input pixels to clamped frame pixels
+    pub fn map_point(&self, x: f32, y: f32) -> Point {
+        let bbox = self.map_to_frame(x, y, x, y);
+        Point { x: bbox.x1, y: bbox.y1 }
+    }
+}
+
+/// Resize to fit `size`x`size` preserving aspect ratio, pad, and return
+/// the planar RGB tensor data (CHW, 0.0-1.0) plus the coordinate mapping
+#[cfg(feature = "onnx")]
+pub fn letterbox(image: &DynamicImage, size: u32) -> (Vec<f32>, Letterbox) {
+    let (src_width, src_height) = image.dimensions();
+    let scale = (size as f32 / src_width as f32).min(size as f32 / src_height as f32);
+    let new_width = ((src_width as f32 * scale).round() as u32).clamp(1, size);
+    let new_height = ((src_height as f32 * scale).round() as u32).clamp(1, size);
+    let pad_x = (size - new_width) / 2;
+    let pad_y = (size - new_height) / 2;
+
+    let resized = image.resize_exact(new_width, new_height, FilterType::Triangle).to_rgb8();
+    let plane = (size * size) as usize;
+    let mut data = vec![LETTERBOX_PAD; 3 * plane];
+    for (x, y, pixel) in resized.enumerate_pixels() {
+        let idx = ((y + pad_y) * size + (x + pad_x)) as usize;
+        for c in 0..3 {
+            data[c * plane + idx] = pixel[c] as f32 / 255.0;
+        }
+    }
+
+    let mapping = Letterbox {
+        scale,
+        pad_x: pad_x as f32,
+        pad_y: pad_y as f32,
+        src_width,
+        src_height,
+    };
+    (data, mapping)
+}
+
+/// Resize to fit `size`x`size` preserving aspect ratio onto a `pad`
+/// background, centered or anchored top-left, and return the packed RGB
+/// (HWC) input for models that take 8-bit pixels, plus the coordinate
+/// mapping
+#[cfg(feature = "rknn")]
+pub fn fit_rgb8(image: &DynamicImage, size: u32, centered: bool, pad: u8) -> (Vec<u8>, Letterbox) {
+    let (src_width, src_height) = image.dimensions();
+    let scale = (size as f32 / src_width as f32).min(size as f32 / src_height as f32);
+    let new_width = ((src_width as f32 * scale).round() as u32).clamp(1, size);
+    let new_height = ((src_height as f32 * scale).round() as u32).clamp(1, size);
+    let (pad_x, pad_y) = if centered { ((size - new_width) / 2, (size - new_height) / 2) } else { (0, 0) };
+
+    let resized = image.resize_exact(new_width, new_height, FilterType::Triangle).to_rgb8();
+    let stride = size as usize * 3;
+    let mut data = vec![pad; stride * size as usize];
+    for (y, row) in resized.rows().enumerate() {
+        let start = (y + pad_y as usize) * stride + pad_x as usize * 3;
+        for (x, pixel) in row.enumerate() {
+            data[start + x * 3..start + x * 3 + 3].copy_from_slice(&pixel.0);
+        }
+    }
+
+    let mapping = Letterbox {
+        scale,
+        pad_x: pad_x as f32,
+        pad_y: pad_y as f32,
+        src_width,
+        src_height,
+    };
+    (data, mapping)
+}
+
+fn class_name(class_id: usize) -> String {
+    COCO_CLASSES
+        .get(class_id)
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| format!("class{}", class_id))
+}
+
+/// Decode a YOLOv8-style output tensor of shape `[4 + classes, boxes]`
+/// (cx, cy, w, h in model input pixels, then per-class scores), apply the
+/// confidence threshold and per-class NMS
+#[cfg(feature = "onnx")]
+pub fn decode_yolov8(output: &[f32], num_boxes: usize, mapping: &Letterbox) -> Vec<Detection> {
+    let rows = output.len() / num_boxes.max(1);
+    if rows <= 4 {
+        return Vec::new();
+    }
+    let num_classes = rows - 4;
+    let at = |row: usize, i: usize| output[row * num_boxes + i];
+
+    let mut candidates = Vec::new();
+    for i in 0..num_boxes {
+        let (class_id, confidence) = (0..num_classes)
+            .map(|c| (c, at(4 + c, i)))
+            .fold((0, f32::MIN), |best, cur| if cur.1 > best.1 { cur } else { best });
+        if confidence < CONFIDENCE_THRESHOLD {
+            continue;
+        }
+        let (cx, cy, w, h) = (at(0, i), at(1, i), at(2, i), at(3, i));
+        candidates.push(Detection {
+            class: class_name(class_id),
+            confidence,
+            bbox: mapping.map_to_frame(cx - w / 2.0, cy - h / 2.0, cx + w / 2.0, cy + h / 2.0),
+            label: None,
+            landmarks: Vec::new(),
+            zones: Vec::new(),
+        });
+    }
+    nms(candidates, NMS_IOU_THRESHOLD)
+}
+
+/// YOLOv5 anchor sizes (input pixels) per output, finest stride first
+#[cfg(feature = "rknn")]
+const YOLOV5_ANCHORS: [[(f32, f32); 3]; 3] = [
+    [(10.0, 13.0), (16.0, 30.0), (33.0, 23.0)],
+    [(30.0, 61.0), (62.0, 45.0), (59.0, 119.0)],
+    [(116.0, 90.0), (156.0, 198.0), (373.0, 326.0)],
+];
+#[cfg(feature = "rknn")]
+const YOLOV5_STRIDES: [f32; 3] = [8.0, 16.0, 32.0];
+
+/// Decode YOLOv5 head outputs, given as (grid height, grid width, values)
+/// of `[3 anchors * (5 + classes), h, w]` tensors with the sigmoid already
+/// applied (as RKNN exports have it), apply the confidence threshold and
+/// per-class NMS
+#[cfg(feature = "rknn")]
+pub fn decode_yolov5(outputs: &[(usize, usize, Vec<f32>)], mapping: &Letterbox) -> Vec<Detection> {
+    // Finest grid first, to line up with the anchors
+    let mut outputs: Vec<_> = outputs.iter().filter(|(h, w, _)| h * w > 0).collect();
+    outputs.sort_by_key(|(h, w, _)| std::cmp::Reverse(h * w));
+
+    let mut candidates = Vec::new();
+    for ((&&(grid_h, grid_w, ref values), anchors), stride) in outputs.iter().zip(&YOLOV5_ANCHORS).zip(YOLOV5_STRIDES) {
+        let cells = grid_h * grid_w;
+        let per_anchor = values.len() / cells / anchors.len();
+        if per_anchor <= 5 {
+            continue;
+        }
+        let at = |anchor: usize, row: usize, cell: usize| values[(anchor * per_anchor + row) * cells + cell];
+        for (anchor, &(anchor_w, anchor_h)) in anchors.iter().enumerate() {
+            for cell in 0..cells {
+                let objectness = at(anchor, 4, cell);
+                if objectness < CONFIDENCE_THRESHOLD {
+                    continue;
+                }
+                let (class_id, class_score) = (0..per_anchor - 5)
+                    .map(|c| (c, at(anchor, 5 + c, cell)))
+                    .fold((0, f32::MIN), |best, cur| if cur.1 > best.1 { cur } else { best });
+                let confidence = objectness * class_score;
+                if confidence < CONFIDENCE_THRESHOLD {
+                    continue;
+                }
+                let (mut cx, mut cy, mut w, mut h) =
+                    (at(anchor, 0, cell), at(anchor, 1, cell), at(anchor, 2, cell), at(anchor, 3, cell));
+                // Some exports decode the boxes, others leave them relative
+                // to the cell and anchor (then all below 10)
+                if cx < 10.0 && cy < 10.0 {
+                    let (gx, gy) = ((cell % grid_w) as f32, (cell / grid_w) as f32);
+                    cx = (cx * 2.0 - 0.5 + gx) * stride;
+                    cy = (cy * 2.0 - 0.5 + gy) * stride;
+                    w = (w * 2.0).powi(2) * anchor_w;
+                    h = (h * 2.0).powi(2) * anchor_h;
+                }
+                candidates.push(Detection {
+                    class: class_name(class_id),
+                    confidence,
+                    bbox: mapping.map_to_frame(cx - w / 2.0, cy - h / 2.0, cx + w / 2.0, cy + h / 2.0),
+                    label: None,
+                    landmarks: Vec::new(),
+                    zones: Vec::new(),
+                });
+            }
+        }
+    }
+    nms(candidates, NMS_IOU_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fixture frame: 1280x720 letterboxed into 640x640 (scale 1/2, 140
+    /// rows of padding above and below)
+    const FRAME: (u32, u32) = (1280, 720);
+    #[cfg(any(feature = "onnx", feature = "rknn"))]
+    const INPUT_SIZE: u32 = 640;
+
+    /// Objects in the fixture frame: class id, confidence, frame box
+    const OBJECTS: [(usize, f32, [i32; 4]); 3] = [
+        (0, 0.9, [100, 200, 300, 600]),
+        (2, 0.8, [640, 360, 1200, 700]),
+        (16, 0.6, [0, 0, 80, 60]),
+    ];
+
+    #[cfg(any(feature = "onnx", feature = "rknn"))]
+    fn fixture_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(FRAME.0, FRAME.1, image::Rgb([200, 40, 10])))
+    }
+
+    fn fixture_mapping() -> Letterbox {
+        Letterbox { scale: 0.5, pad_x: 0.0, pad_y: 140.0, src_width: FRAME.0, src_height: FRAME.1 }
+    }
+
+    /// A box in model input pixels: (cx, cy, w, h)
+    type InputBox = (f32, f32, f32, f32);
+
+    /// A frame box in model input pixels
+    fn to_input(mapping: &Letterbox, [x1, y1, x2, y2]: [i32; 4]) -> InputBox {
+        let fx = |x: i32| x as f32 * mapping.scale + mapping.pad_x;
+        let fy = |y: i32| y as f32 * mapping.scale + mapping.pad_y;
+        ((fx(x1) + fx(x2)) / 2.0, (fy(y1) + fy(y2)) / 2.0, fx(x2) - fx(x1), fy(y2) - fy(y1))
+    }
+
+    /// Model candidates for the fixture: every object, a weaker duplicate
+    /// of the first (for NMS) and a box under the confidence threshold
+    #[cfg(any(feature = "onnx", feature = "rknn"))]
+    fn fixture_candidates(mapping: &Letterbox) -> Vec<(usize, f32, InputBox)> {
+        let mut candidates: Vec<_> = OBJECTS.iter().map(|&(class, score, bbox)| (class, score, to_input(mapping, bbox))).collect();
+        let (cx, cy, w, h) = candidates[0].2;
+        candidates.push((0, 0.7, (cx + 4.0, cy + 4.0, w, h)));
+        candidates.push((5, CONFIDENCE_THRESHOLD / 2.0, (320.0, 320.0, 50.0, 50.0)));
+        candidates
+    }
+
+    /// Decoded detections match the fixture objects exactly
+    #[cfg(any(feature = "onnx", feature = "rknn"))]
+    fn assert_fixture_detections(detections: &[Detection]) {
+        let found: Vec<_> = detections
+            .iter()
+            .map(|d| (d.class.as_str(), d.confidence, [d.bbox.x1, d.bbox.y1, d.bbox.x2, d.bbox.y2]))
+            .collect();
+        let expected: Vec<_> = OBJECTS.iter().map(|&(class, score, bbox)| (COCO_CLASSES[class], score, bbox)).collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn fixture_boxes_round_trip_through_the_mapping() {
+        let mapping = fixture_mapping();
+        for (_, _, bbox) in OBJECTS {
+            let (cx, cy, w, h) = to_input(&mapping, bbox);
+            let mapped = mapping.map_to_frame(cx - w / 2.0, cy - h / 2.0, cx + w / 2.0, cy + h / 2.0);
+            assert_eq!([mapped.x1, mapped.y1, mapped.x2, mapped.y2], bbox);
+        }
+        // Boxes reaching into the padding are clamped to the frame
+        let clamped = mapping.map_to_frame(-10.0, 0.0, 700.0, 640.0);
+        assert_eq!([clamped.x1, clamped.y1, clamped.x2, clamped.y2], [0, 0, 1279, 719]);
+    }
+
+    #[cfg(feature = "onnx")]
+    #[test]
+    fn onnx_path_decodes_the_fixture() {
+        let (data, mapping) = letterbox(&fixture_image(), INPUT_SIZE);
+        let expected = fixture_mapping();
+        assert_eq!(
+            (mapping.scale, mapping.pad_x, mapping.pad_y, mapping.src_width, mapping.src_height),
+            (expected.scale, expected.pad_x, expected.pad_y, expected.src_width, expected.src_height)
+        );
+        // Planar RGB: padding above the frame, frame pixels inside it
+        let size = INPUT_SIZE as usize;
+        assert_eq!(data.len(), 3 * size * size);
+        assert_eq!(data[0], LETTERBOX_PAD);
+        assert!((data[200 * size + 320] - 200.0 / 255.0).abs() < 1e-6);
+        assert!((data[size * size + 200 * size + 320] - 40.0 / 255.0).abs() < 1e-6);
+
+        // [4 + classes, boxes]
+        let candidates = fixture_candidates(&mapping);
+        let num_boxes = candidates.len();
+        let mut output = vec![0f32; (4 + COCO_CLASSES.len()) * num_boxes];
+        for (i, &(class, score, (cx, cy, w, h))) in candidates.iter().enumerate() {
+            for (row, value) in [cx, cy, w, h].into_iter().enumerate() {
+                output[row * num_boxes + i] = value;
+            }
+            output[(4 + class) * num_boxes + i] = score;
+        }
+        assert_fixture_detections(&decode_yolov8(&output, num_boxes, &mapping));
+    }
+
+    #[cfg(feature = "rknn")]
+    #[test]
+    fn rknn_path_decodes_the_fixture() {
+        let (data, mapping) = fit_rgb8(&fixture_image(), INPUT_SIZE, true, LETTERBOX_PAD_RGB8);
+        let expected = fixture_mapping();
+        assert_eq!(
+            (mapping.scale, mapping.pad_x, mapping.pad_y, mapping.src_width, mapping.src_height),
+            (expected.scale, expected.pad_x, expected.pad_y, expected.src_width, expected.src_height)
+        );
+        // Packed RGB: padding above the frame, frame pixels inside it
+        let stride = INPUT_SIZE as usize * 3;
+        assert_eq!(data.len(), stride * INPUT_SIZE as usize);
+        assert_eq!(data[..3], [LETTERBOX_PAD_RGB8; 3]);
+        assert_eq!(data[200 * stride + 320 * 3..][..3], [200, 40, 10]);
+
+        // One 2x2 head with decoded boxes, a candidate per anchor and cell
+        let (grid_h, grid_w) = (2, 2);
+        let cells = grid_h * grid_w;
+        let per_anchor = 5 + COCO_CLASSES.len();
+        let mut values = vec![0f32; 3 * per_anchor * cells];
+        for (slot, &(class, score, (cx, cy, w, h))) in fixture_candidates(&mapping).iter().enumerate() {
+            let (anchor, cell) = (slot / cells, slot % cells);
+            let mut set = |row: usize, value: f32| values[(anchor * per_anchor + row) * cells + cell] = value;
+            for (row, value) in [cx, cy, w, h, 1.0].into_iter().enumerate() {
+                set(row, value);
+            }
+            set(5 + class, score);
+        }
+        assert_fixture_detections(&decode_yolov5(&[(grid_h, grid_w, values)], &mapping));
+    }
+}
@@ -0,0 +1,179 @@
+//! Polygon zones
+//!
+//! Zones are named polygons in frame fractions (0-1, so they hold at any
+//! stream resolution), kept as `[[zones]]` in the config file. The detector
+//! tags each detection with the zones its bbox centre falls inside; with the
+//! `server` feature, a zone that lists `alert_classes` turns those tags into
+//! `zone_entered` / `zone_cleared` events for each zone+class pair, the way
+//! the server's detection tracker does for the frame as a whole.
+
+use crate::detector::DetectionResult;
+#[cfg(feature = "server")]
+use crate::events::DomainEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Vertex limit per zone
+pub const MAX_POINTS: usize = 64;
+
+/// Zones per config file
+pub const MAX_ZONES: usize = 32;
+
+/// `alert_classes` entry matching every class
+pub const ANY_CLASS: &str = "*";
+
+/// A named polygon
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Zone {
+    /// Letters, digits, '_' and '-' (taken from the path in `PUT /zones/{name}`)
+    #[serde(default)]
+    pub name: String,
+    /// Vertices as `[x, y]` fractions of the frame width and height, in
+    /// order around the polygon (at least 3)
+    pub points: Vec<[f64; 2]>,
+    /// Classes that raise events in this zone (`*`: any); empty to only tag
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alert_classes: Vec<String>,
+}
+
+impl Zone {
+    pub fn validate(&self) -> Result<(), String> {
+        if !crate::valid_name(&self.name) {
+            return Err(format!(
+                "Invalid zone name '{}' (letters, digits, '_' and '-', at most 64)",
+                self.name
+            ));
+        }
+        if !(3..=MAX_POINTS).contains(&self.points.len()) {
+            return Err(format!("Zone '{}' needs 3-{} points", self.name, MAX_POINTS));
+        }
+        if self.points.iter().flatten().any(|v| !(0.0..=1.0).contains(v)) {
+            return Err(format!("Zone '{}': points must be fractions of the frame (0-1)", self.name));
+        }
+        if self.alert_classes.iter().any(|class| class.trim().is_empty()) {
+            return Err(format!("Zone '{}': empty class name in alert_classes", self.name));
+        }
+        Ok(())
+    }
+
+    /// Whether `(x, y)` (frame fractions) lies inside the zone
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        polygon_contains(&self.points, x, y)
+    }
+
+    /// Whether detections of `class` inside the zone raise events
+    pub fn alerts_on(&self, class: &str) -> bool {
+        self.alert_classes.iter().any(|c| c == ANY_CLASS || c == class)
+    }
+}
+
+/// Whether `(x, y)` lies inside the polygon (even-odd rule; points on an
+/// edge may fall either way)
+pub fn polygon_contains(points: &[[f64; 2]], x: f64, y: f64) -> bool {
+    let Some(&last) = points.last() else { return false };
+    let mut inside = false;
+    let mut previous = last;
+    for &point in points {
+        let ([x1, y1], [x2, y2]) = (previous, point);
+        if (y1 > y) != (y2 > y) && x < x1 + (y - y1) * (x2 - x1) / (y2 - y1) {
+            inside = !inside;
+        }
+        previous = point;
+    }
+    inside
+}
+
+/// Check a whole zone list (names must be unique)
+pub fn validate(zones: &[Zone]) -> Result<(), String> {
+    if zones.len() > MAX_ZONES {
+        return Err(format!("At most {} zones", MAX_ZONES));
+    }
+    let mut names = BTreeSet::new();
+    for zone in zones {
+        zone.validate()?;
+        if !names.insert(zone.name.as_str()) {
+            return Err(format!("Duplicate zone '{}'", zone.name));
+        }
+    }
+    Ok(())
+}
+
+/// Set each detection's zones from its bbox centre; needs the frame size
+/// the backend reports
+pub fn tag(zones: &[Zone], result: &mut DetectionResult) {
+    let (Some(width), Some(height)) = (result.width, result.height) else {
+        return;
+    };
+    if width == 0 || height == 0 {
+        return;
+    }
+    for detection in &mut result.detections {
+        let bbox = &detection.bbox;
+        let x = (bbox.x1 + bbox.x2) as f64 / 2.0 / width as f64;
+        let y = (bbox.y1 + bbox.y2) as f64 / 2.0 / height as f64;
+        detection.zones = zones.iter().filter(|z| z.contains(x, y)).map(|z| z.name.clone()).collect();
+    }
+}
+
+/// Turns successive tagged results into events for the alerting zone+class
+/// pairs that appear or disappear
+#[cfg(feature = "server")]
+#[derive(Debug, Default)]
+pub struct ZoneTracker {
+    /// Active (zone, class) pairs
+    active: BTreeSet<(String, String)>,
+    last_seq: Option<u64>,
+}
+
+#[cfg(feature = "server")]
+impl ZoneTracker {
+    /// Events for a result; repeated results for the same frame and failed
+    /// runs are ignored
+    pub fn update(&mut self, zones: &[Zone], result: &DetectionResult) -> Vec<DomainEvent> {
+        if result.error.is_some() || (result.frame_seq.is_some() && result.frame_seq == self.last_seq) {
+            return Vec::new();
+        }
+        self.last_seq = result.frame_seq;
+
+        let mut counts: std::collections::BTreeMap<(String, String), usize> = Default::default();
+        for detection in &result.detections {
+            for name in &detection.zones {
+                if zones.iter().any(|z| &z.name == name && z.alerts_on(&detection.class)) {
+                    *counts.entry((name.clone(), detection.class.clone())).or_default() += 1;
+                }
+            }
+        }
+        let frame_seq = result.frame_seq;
+        let mut events: Vec<DomainEvent> = self
+            .active
+            .iter()
+            .filter(|pair| !counts.contains_key(*pair))
+            .map(|(zone, class)| DomainEvent::ZoneCleared { zone: zone.clone(), class: class.clone(), frame_seq })
+            .collect();
+        events.extend(
+            counts
+                .iter()
+                .filter(|(pair, _)| !self.active.contains(*pair))
+                .map(|((zone, class), &count)| DomainEvent::ZoneEntered {
+                    zone: zone.clone(),
+                    class: class.clone(),
+                    frame_seq,
+                    count,
+                }),
+        );
+        self.active = counts.into_keys().collect();
+        events
+    }
+
+    /// Forget the state (detector restarted, zones changed); clears every
+    /// active pair
+    pub fn reset(&mut self) -> Vec<DomainEvent> {
+        self.last_seq = None;
+        std::mem::take(&mut self.active)
+            .into_iter()
+            .map(|(zone, class)| DomainEvent::ZoneCleared { zone, class, frame_seq: None })
+            .collect()
+    }
+}
@@ -0,0 +1,128 @@
+//! Digital zoom
+//!
+//! A [`Zoom`] is a window on the output frame, in fractions of its width and
+//! height so it survives changes of frame size and orientation. The
+//! processed frame is cropped to the window just before encoding: a 2x zoom
+//! encodes a quarter of the pixels, so panning and zooming costs nothing
+//! over the full frame. There's no upscale back to the frame size; clients
+//! get the window at the sensor's resolution.
+
+use serde::{Deserialize, Serialize};
+
+use crate::capture::FrameSize;
+
+/// Largest zoom factor (the smallest window is 1/8 of the frame each way)
+pub const MAX_FACTOR: f32 = 8.0;
+
+// Slack for windows whose edges are sums of rounded fractions
+const EPSILON: f32 = 1e-4;
+
+/// Window on the output frame, in fractions of its size (a pipeline setting)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Zoom {
+    /// Left edge (0.0 - 1.0)
+    pub x: f32,
+    /// Top edge (0.0 - 1.0)
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Default for Zoom {
+    fn default() -> Self {
+        Self::FULL
+    }
+}
+
+/// Window of a zoom in pixels of the output frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+pub struct Crop {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Zoom {
+    /// The whole frame
+    pub const FULL: Zoom = Zoom { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+
+    /// Window `factor` times smaller than the frame, centred on (`center_x`,
+    /// `center_y`) in fractions of the frame and moved inside it if the
+    /// centre is too close to an edge
+    pub fn from_factor(factor: f32, center_x: f32, center_y: f32) -> Result<Self, String> {
+        if !factor.is_finite() || !(1.0..=MAX_FACTOR).contains(&factor) {
+            return Err(format!("Zoom factor must be between 1 and {} (got {})", MAX_FACTOR, factor));
+        }
+        if !(0.0..=1.0).contains(&center_x) || !(0.0..=1.0).contains(&center_y) {
+            return Err(format!("Zoom center must be within 0.0 - 1.0 (got {}, {})", center_x, center_y));
+        }
+        let size = 1.0 / factor;
+        Ok(Self {
+            x: (center_x - size / 2.0).clamp(0.0, 1.0 - size),
+            y: (center_y - size / 2.0).clamp(0.0, 1.0 - size),
+            width: size,
+            height: size,
+        })
+    }
+
+    /// Error message if the window isn't inside the frame or is smaller
+    /// than [`MAX_FACTOR`] allows
+    pub fn validate(&self) -> Result<(), String> {
+        let min = 1.0 / MAX_FACTOR;
+        if ![self.x, self.y, self.width, self.height].iter().all(|v| v.is_finite()) {
+            return Err("Zoom window must be finite".to_string());
+        }
+        if self.width < min - EPSILON || self.height < min - EPSILON {
+            return Err(format!(
+                "Zoom window must be at least 1/{} of the frame each way (got {}x{})",
+                MAX_FACTOR, self.width, self.height
+            ));
+        }
+        if self.x < 0.0 || self.y < 0.0 || self.x + self.width > 1.0 + EPSILON || self.y + self.height > 1.0 + EPSILON {
+            return Err(format!(
+                "Zoom window must be inside the frame (got {}x{} at {}, {})",
+                self.width, self.height, self.x, self.y
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether this is the whole frame (no crop)
+    pub fn is_full(&self) -> bool {
+        self.width >= 1.0 - EPSILON && self.height >= 1.0 - EPSILON
+    }
+
+    /// Magnification of the window's larger side
+    pub fn factor(&self) -> f32 {
+        1.0 / self.width.max(self.height)
+    }
+
+    /// The window in pixels of a `size` frame, on even coordinates with a
+    /// width that's a multiple of 4 (what the encoders take)
+    pub fn crop(&self, size: FrameSize) -> Crop {
+        if self.is_full() {
+            return Crop { x: 0, y: 0, width: size.width, height: size.height };
+        }
+        let scale = |fraction: f32, pixels: usize, align: usize| {
+            ((fraction * pixels as f32) as usize / align * align).min(pixels)
+        };
+        let width = scale(self.width, size.width, 4).max(4);
+        let height = scale(self.height, size.height, 2).max(2);
+        Crop {
+            x: scale(self.x, size.width, 2).min(size.width - width),
+            y: scale(self.y, size.height, 2).min(size.height - height),
+            width,
+            height,
+        }
+    }
+}
+
+impl Crop {
+    pub fn size(&self) -> FrameSize {
+        FrameSize { width: self.width, height: self.height }
+    }
+}
@@ -3,11 +3,15 @@
 YOLO Object Detection Service for IMX415 Streamer
 Uses RKNN-Lite to run YOLOv5s on Rock 5C NPU
 
+With DETECTOR_TASK=faces it runs an SCRFD face model instead and reports
+"face" detections with five landmarks (eyes, nose tip, mouth corners).
+
 Runs as a subprocess, communicates via stdin/stdout:
 - Input: Raw grayscale JPEG data (length prefix)
 - Output: JSON detection results
 """
 
+import os
 import sys
 import struct
 import json
@@ -22,8 +26,13 @@ except ImportError:
     print("ERROR: rknnlite not installed", file=sys.stderr)
     sys.exit(1)
 
-# Model configuration
-MODEL_PATH = "/home/angelo/imx415_streamer/models/yolov5s-640-640.rknn"
+# Model configuration (YOLO_MODEL and DETECTOR_TASK are set by the streamer)
+TASK = os.environ.get("DETECTOR_TASK", "objects")
+DEFAULT_MODELS = {
+    "objects": "/home/angelo/imx415_streamer/models/yolov5s-640-640.rknn",
+    "faces": "/home/angelo/imx415_streamer/models/scrfd_500m-640-640.rknn",
+}
+MODEL_PATH = os.environ.get("YOLO_MODEL") or DEFAULT_MODELS.get(TASK, DEFAULT_MODELS["objects"])
 LABELS_PATH = "/home/angelo/imx415_streamer/models/coco_80_labels_list.txt"
 INPUT_SIZE = 640
 CONF_THRESHOLD = 0.25
@@ -37,6 +46,11 @@ ANCHORS = [
 ]
 STRIDES = [8, 16, 32]
 
+# SCRFD: two anchors per cell, face score threshold and NMS IoU
+FACE_ANCHORS = 2
+FACE_THRESHOLD = 0.5
+FACE_NMS_THRESHOLD = 0.4
+
 
 def load_labels(path):
     """Load COCO class labels"""
@@ -124,14 +138,66 @@ def process_yolo_output(outputs, img_w, img_h):
     return boxes, scores, class_ids
 
 
+def process_scrfd_output(outputs, scale, img_w, img_h):
+    """
+    Process SCRFD output to face boxes and landmarks
+    Outputs: per stride, scores (N, 1), box distances (N, 4) and landmark
+    offsets (N, 10), in stride units from the anchor centre; N is the
+    anchor count (grid cells * 2). The input was resized by `scale` and
+    padded at the right and bottom.
+    """
+    by_width = {}
+    for output in outputs:
+        output = np.array(output)
+        output = output.reshape(-1, output.shape[-1])
+        by_width.setdefault(output.shape[1], []).append(output)
+    # Finest stride has the most anchors
+    for tensors in by_width.values():
+        tensors.sort(key=lambda t: -t.shape[0])
+    score_levels = by_width.get(1, [])
+    box_levels = by_width.get(4, [])
+    kps_levels = by_width.get(10, [])
+
+    boxes, scores, landmarks = [], [], []
+    for idx, stride in enumerate(STRIDES):
+        level_scores = score_levels[idx][:, 0]
+        grid = INPUT_SIZE // stride
+        keep = np.where(level_scores >= FACE_THRESHOLD)[0]
+        for i in keep:
+            cell = i // FACE_ANCHORS
+            cx = (cell % grid) * stride
+            cy = (cell // grid) * stride
+            d = box_levels[idx][i] * stride
+            boxes.append([(cx - d[0]) / scale, (cy - d[1]) / scale,
+                          (cx + d[2]) / scale, (cy + d[3]) / scale])
+            scores.append(float(level_scores[i]))
+            if idx < len(kps_levels):
+                k = kps_levels[idx][i] * stride
+                landmarks.append([{"x": int(min(max((cx + k[j]) / scale, 0), img_w - 1)),
+                                   "y": int(min(max((cy + k[j + 1]) / scale, 0), img_h - 1))}
+                                  for j in range(0, 10, 2)])
+            else:
+                landmarks.append([])
+
+    return boxes, scores, landmarks
+
+
 def nms(boxes, scores, class_ids, threshold):
     """Non-maximum suppression"""
     if len(boxes) == 0:
         return [], [], []
     
+    keep = nms_indices(boxes, scores, threshold)
     boxes = np.array(boxes)
     scores = np.array(scores)
     class_ids = np.array(class_ids)
+    return boxes[keep].tolist(), scores[keep].tolist(), class_ids[keep].tolist()
+
+
+def nms_indices(boxes, scores, threshold):
+    """Indices kept by non-maximum suppression, highest score first"""
+    boxes = np.array(boxes)
+    scores = np.array(scores)
     
     # Sort by score
     indices = np.argsort(scores)[::-1]
@@ -164,13 +230,13 @@ def nms(boxes, scores, class_ids, threshold):
         # Keep boxes with low IoU
         indices = rest[iou < threshold]
     
-    return boxes[keep].tolist(), scores[keep].tolist(), class_ids[keep].tolist()
+    return keep
 
 
 class YOLODetector:
     def __init__(self):
         self.rknn = RKNNLite()
-        self.labels = load_labels(LABELS_PATH)
+        self.labels = load_labels(LABELS_PATH) if TASK != "faces" else ["face"]
         
         # Load model
         print(f"Loading model: {MODEL_PATH}", file=sys.stderr)
@@ -206,6 +272,9 @@ class YOLODetector:
         elif img.shape[2] == 1:
             img = cv2.cvtColor(img, cv2.COLOR_GRAY2BGR)
         
+        if TASK == "faces":
+            return self.detect_faces(img, orig_w, orig_h)
+
         # Resize to model input size (letterbox)
         img_resized = cv2.resize(img, (INPUT_SIZE, INPUT_SIZE))
         
@@ -255,6 +324,46 @@ class YOLODetector:
             "detections": detections
         }
     
+    def detect_faces(self, img, orig_w, orig_h):
+        """
+        Detect faces with SCRFD: aspect-preserving resize anchored top-left,
+        zero padded (mean/std normalization is part of the RKNN model)
+        """
+        scale = min(INPUT_SIZE / orig_w, INPUT_SIZE / orig_h)
+        new_w, new_h = max(1, round(orig_w * scale)), max(1, round(orig_h * scale))
+        padded = np.zeros((INPUT_SIZE, INPUT_SIZE, 3), dtype=np.uint8)
+        padded[:new_h, :new_w] = cv2.resize(img, (new_w, new_h))
+        img_rgb = cv2.cvtColor(padded, cv2.COLOR_BGR2RGB)
+
+        outputs = self.rknn.inference(inputs=[np.expand_dims(img_rgb, axis=0)])
+        try:
+            boxes, scores, landmarks = process_scrfd_output(outputs, scale, orig_w, orig_h)
+        except Exception as e:
+            print(f"Post-process ERROR: {e}", file=sys.stderr)
+            return {"error": str(e), "detections": []}
+
+        keep = nms_indices(boxes, scores, FACE_NMS_THRESHOLD) if boxes else []
+        detections = []
+        for i in keep:
+            box, score = boxes[i], scores[i]
+            detections.append({
+                "class": "face",
+                "confidence": round(score, 3),
+                "bbox": {
+                    "x1": int(max(0, box[0])),
+                    "y1": int(max(0, box[1])),
+                    "x2": int(min(orig_w, box[2])),
+                    "y2": int(min(orig_h, box[3]))
+                },
+                "landmarks": landmarks[i]
+            })
+
+        return {
+            "width": orig_w,
+            "height": orig_h,
+            "detections": detections
+        }
+
     def __del__(self):
         if hasattr(self, 'rknn'):
             self.rknn.release()